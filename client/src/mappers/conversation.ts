@@ -17,6 +17,125 @@ export const mapLoadingSteps = (
           `${pa?.length > 20 ? '...' : ''}${pa?.slice(-20)}`,
       }));
     }
+    if (s.type === 'dependency_vulns') {
+      return {
+        ...s,
+        path: '',
+        displayText: t(`Checking dependencies`),
+      };
+    }
+    if (s.type === 'dead_code') {
+      return {
+        ...s,
+        path: '',
+        displayText: t(`Looking for unused code`),
+      };
+    }
+    if (s.type === 'deprecated_usage') {
+      return {
+        ...s,
+        path: s.content.package,
+        displayText: t(`Looking for deprecated APIs`),
+      };
+    }
+    if (s.type === 'config_audit') {
+      return {
+        ...s,
+        path: s.content.path,
+        displayText: t(`Reviewing configuration`),
+      };
+    }
+    if (s.type === 'todos') {
+      return {
+        ...s,
+        path: s.content.path,
+        displayText: t(`Finding TODOs`),
+      };
+    }
+    if (s.type === 'allocations') {
+      return {
+        ...s,
+        path: s.content.path,
+        displayText: t(`Finding allocations`),
+      };
+    }
+    if (s.type === 'symbol') {
+      return {
+        ...s,
+        path: s.content.query,
+        displayText: t(`Searching symbols`),
+      };
+    }
+    if (s.type === 'find_similar') {
+      return {
+        ...s,
+        path: s.content.path,
+        displayText: t(`Finding similar files`),
+      };
+    }
+    if (s.type === 'changelog') {
+      return {
+        ...s,
+        path: s.content.since || '',
+        displayText: t(`Reading the commit history`),
+      };
+    }
+    if (s.type === 'changelog_diff') {
+      return {
+        ...s,
+        path: `${s.content.v1}...${s.content.v2}`,
+        displayText: t(`Comparing release notes`),
+      };
+    }
+    if (s.type === 'weekly_digest') {
+      return {
+        ...s,
+        path: '',
+        displayText: t(`Writing the weekly digest`),
+      };
+    }
+    if (s.type === 'plan') {
+      return {
+        ...s,
+        path: '',
+        displayText: t(`Planning the next steps`),
+      };
+    }
+    if (s.type === 'upgrade_suggestions') {
+      return {
+        ...s,
+        path: s.content.dep_name,
+        displayText: t(`Checking upgrade`),
+      };
+    }
+    if (s.type === 'prs') {
+      return {
+        ...s,
+        path: s.content.query,
+        displayText: t(`Searching pull requests`),
+      };
+    }
+    if (s.type === 'format') {
+      return {
+        ...s,
+        path: s.content.path,
+        displayText: t(`Checking formatting`),
+      };
+    }
+    if (s.type === 'related_files') {
+      return {
+        ...s,
+        path: s.content.paths.join(', '),
+        displayText: t(`Finding related files`),
+      };
+    }
+    if (s.type === 'list_files') {
+      return {
+        ...s,
+        path: s.content.pattern,
+        displayText: s.content.pattern,
+      };
+    }
     return {
       ...s,
       path: s.content.query,
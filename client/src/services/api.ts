@@ -9,7 +9,7 @@ import {
   SuggestionsResponse,
   TokenInfoResponse,
 } from '../types/api';
-import { EnvConfig, RepoType } from '../types/general';
+import { BranchSettings, EnvConfig, RepoType } from '../types/general';
 
 const DB_API = 'https://api.bloop.ai';
 let http: AxiosInstance;
@@ -115,6 +115,15 @@ export const indexRepoBranch = async (repoRef: string, branch: string) => {
   );
 };
 
+export const setBranchSettings = async (
+  repoRef: string,
+  settings: BranchSettings,
+) => {
+  return http
+    .put('/repos/branch_settings', settings, { params: { repo: repoRef } })
+    .then((r) => r.data);
+};
+
 export const getAutocomplete = async (
   q: string,
 ): Promise<SuggestionsResponse> => {
@@ -218,10 +227,21 @@ export const getAllConversations = (
     .get('/answer/conversations', { params: { repo_ref } })
     .then((r) => r.data);
 
-export const getConversation = (
+export const getConversation = async (
   thread_id: string,
-): Promise<ConversationType[]> =>
-  http.get(`/answer/conversations/${thread_id}`).then((r) => r.data);
+): Promise<ConversationType[]> => {
+  const exchanges: ConversationType[] = [];
+  let cursor: string | null = null;
+  do {
+    const page: { exchanges: ConversationType[]; next_cursor: string | null } =
+      await http
+        .get(`/answer/conversations/${thread_id}`, { params: { cursor } })
+        .then((r) => r.data);
+    exchanges.push(...page.exchanges);
+    cursor = page.next_cursor;
+  } while (cursor);
+  return exchanges;
+};
 
 export const deleteConversation = (
   thread_id: string,
@@ -48,6 +48,7 @@ export interface SnippetItem {
   highlights: Range[];
   symbols: SymbolSnippetItem[];
   line_range: Range;
+  symbol_path?: string;
 }
 
 export interface Snippet {
@@ -223,7 +224,7 @@ export type AllConversationsResponse = {
 
 type ProcStep = {
   type: 'proc';
-  content: { query: string; paths: string[] };
+  content: { query: string; paths: string[]; timed_out?: string[] };
 };
 
 type CodeStep = {
@@ -236,7 +237,201 @@ type PathStep = {
   content: { query: string };
 };
 
-export type SearchStepType = ProcStep | CodeStep | PathStep;
+type ListFilesStep = {
+  type: 'list_files';
+  content: { pattern: string; paths: string[] };
+};
+
+type DependencyVulnsStep = {
+  type: 'dependency_vulns';
+  content: {
+    lockfiles: string[];
+    packages: { name: string; version: string; cve_ids: string[] }[];
+  };
+};
+
+type DeadCodeStep = {
+  type: 'dead_code';
+  content: {
+    dead_symbols: { path: string; name: string; line: number }[];
+  };
+};
+
+type DeprecatedUsageStep = {
+  type: 'deprecated_usage';
+  content: {
+    package: string;
+    apis: number;
+    calls: {
+      api: string;
+      path: string;
+      line: number;
+      replacement: string | null;
+    }[];
+  };
+};
+
+type ConfigAuditStep = {
+  type: 'config_audit';
+  content: {
+    path: string;
+    issues: {
+      field: string;
+      severity: 'critical' | 'high' | 'medium' | 'low';
+      description: string;
+    }[];
+  };
+};
+
+type TodosStep = {
+  type: 'todos';
+  content: {
+    path: string;
+    todos: { line: number; tag: string; message: string; context: string }[];
+  };
+};
+
+type AllocationsStep = {
+  type: 'allocations';
+  content: {
+    path: string;
+    language: string | null;
+    sites: {
+      line: number;
+      expression: string;
+      frequency: 'low' | 'medium' | 'high';
+    }[];
+  };
+};
+
+type SymbolStep = {
+  type: 'symbol';
+  content: {
+    query: string;
+    symbols: {
+      name: string;
+      kind: string;
+      path: string;
+      line: number;
+      public: boolean;
+    }[];
+  };
+};
+
+type FindSimilarStep = {
+  type: 'find_similar';
+  content: {
+    path: string;
+    similar: { path: string; similarity: number }[];
+  };
+};
+
+type ChangelogStep = {
+  type: 'changelog';
+  content: {
+    since: string | null;
+    breaking_changes: { short_desc: string; migration_hint: string | null }[];
+    response: string;
+  };
+};
+
+type ChangelogDiffStep = {
+  type: 'changelog_diff';
+  content: {
+    v1: string;
+    v2: string;
+    diff: string | null;
+    response: string;
+  };
+};
+
+type WeeklyDigestStep = {
+  type: 'weekly_digest';
+  content: {
+    since: string;
+    commits: number;
+    response: string;
+  };
+};
+
+type PlanStep = {
+  type: 'plan';
+  content: {
+    goal: string;
+    actions: Record<string, unknown>[];
+  };
+};
+
+type UpgradeSuggestionsStep = {
+  type: 'upgrade_suggestions';
+  content: {
+    dep_name: string;
+    current_version: string | null;
+    target_version: string | null;
+    breaking_changes: { short_desc: string; migration_hint: string | null }[];
+    response: string;
+  };
+};
+
+type PrsStep = {
+  type: 'prs';
+  content: {
+    query: string;
+    pull_requests: {
+      number: number;
+      title: string;
+      author: string;
+      url: string;
+      matched_files: string[];
+    }[];
+  };
+};
+
+type FormatStep = {
+  type: 'format';
+  content: { path: string; formatter: string | null; diff: string | null };
+};
+
+type RelatedFilesStep = {
+  type: 'related_files';
+  content: { paths: string[]; related: string[] };
+};
+
+export type SearchStepType =
+  | ProcStep
+  | CodeStep
+  | PathStep
+  | ListFilesStep
+  | DependencyVulnsStep
+  | DeadCodeStep
+  | DeprecatedUsageStep
+  | ConfigAuditStep
+  | TodosStep
+  | AllocationsStep
+  | SymbolStep
+  | FindSimilarStep
+  | ChangelogStep
+  | ChangelogDiffStep
+  | WeeklyDigestStep
+  | PlanStep
+  | UpgradeSuggestionsStep
+  | PrsStep
+  | FormatStep
+  | RelatedFilesStep;
+
+export type ContextFileType = {
+  path: string;
+  sources: (
+    | 'path_search'
+    | 'code_search'
+    | 'proc'
+    | 'related_files'
+    | 'call_graph'
+    | 'pinned'
+  )[];
+  lines: { start: number; end: number }[];
+  sent_to_llm: boolean;
+};
 
 export type ConversationType = {
   id: string;
@@ -245,8 +440,21 @@ export type ConversationType = {
   conclusion: string;
   answer: string;
   paths: string[];
+  context: ContextFileType[];
   response_timestamp: string;
+  last_updated_at: string;
+  tokenization_us?: number;
   focused_chunk: { file_path: string } | null;
+  source?: 'faq' | 'playbook';
+  suggestions?: { faq_id: number; question: string }[];
+  error?: string;
+  redactions?: { path: string; kinds: string[] }[];
+  pr_citations?: {
+    kind: 'pull_request';
+    number: number;
+    title: string;
+    url: string;
+  }[];
 };
 
 export interface SuggestionsResponse {
@@ -59,6 +59,7 @@ export enum SyncStatus {
 export enum RepoProvider {
   GitHub = 'github',
   Local = 'local',
+  LocalDir = 'local_dir',
 }
 
 export type RepoType = {
@@ -72,6 +73,18 @@ export type RepoType = {
   most_common_lang: string;
   branches: { name: string; last_commit_unix_secs: number }[];
   branch_filter: { select: string[] } | null;
+  branch_settings?: BranchSettings;
+  lfs?: LfsCounts;
+};
+
+export type LfsCounts = {
+  skipped: number;
+  unfetched: number;
+};
+
+export type BranchSettings = {
+  branches_to_index: string[];
+  default_query_branch: string | null;
 };
 
 export type RepoUi = RepoType & {
@@ -1,7 +1,7 @@
 use bleep::{
     indexes::{reader::ContentReader, DocumentRead, File},
     intelligence::TreeSitterFile,
-    semantic::Semantic,
+    semantic::{store::Qdrant, Semantic},
     symbol::SymbolLocations,
     Application, Configuration, Environment,
 };
@@ -57,9 +57,13 @@ pub fn criterion_benchmark(c: &mut Criterion) {
         let file = File::new(
             app.sql.clone(),
             Some(
-                Semantic::initialize(&model_dir, "http://127.0.0.1:6334", Arc::clone(&app.config))
-                    .await
-                    .unwrap(),
+                Semantic::initialize(
+                    &model_dir,
+                    Arc::new(Qdrant::connect("http://127.0.0.1:6334").await.unwrap()),
+                    Arc::clone(&app.config),
+                )
+                .await
+                .unwrap(),
             ),
         );
 
@@ -0,0 +1,96 @@
+use bleep::semantic::{self, Payload};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde::Deserialize;
+
+const FIXTURE: &str = include_str!("./rerank-fixture.json");
+
+#[derive(Deserialize)]
+struct Case {
+    query: String,
+    relevant: Vec<String>,
+    results: Vec<FixtureResult>,
+}
+
+#[derive(Deserialize)]
+struct FixtureResult {
+    path: String,
+    score: f32,
+    text: String,
+}
+
+impl Case {
+    fn payloads(&self) -> Vec<Payload> {
+        self.results
+            .iter()
+            .map(|r| Payload {
+                relative_path: r.path.clone(),
+                text: r.text.clone(),
+                score: Some(r.score),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// The reciprocal rank of the first relevant result.
+    fn reciprocal_rank(&self, ranked: &[Payload]) -> f32 {
+        ranked
+            .iter()
+            .position(|p| self.relevant.contains(&p.relative_path))
+            .map(|i| 1. / (i + 1) as f32)
+            .unwrap_or_default()
+    }
+
+    /// Normalized discounted cumulative gain, with binary relevance.
+    fn ndcg(&self, ranked: &[Payload]) -> f32 {
+        let gain = |i: usize| 1. / (i as f32 + 2.).log2();
+
+        let dcg = ranked
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| self.relevant.contains(&p.relative_path))
+            .map(|(i, _)| gain(i))
+            .sum::<f32>();
+        let ideal = (0..self.relevant.len()).map(gain).sum::<f32>();
+
+        dcg / ideal
+    }
+}
+
+fn quality(cases: &[Case], weight: f32) -> (f32, f32) {
+    let n = cases.len() as f32;
+    let (mrr, ndcg) = cases
+        .iter()
+        .map(|case| {
+            let ranked = semantic::rerank_weighted(&case.query, case.payloads(), weight);
+            (case.reciprocal_rank(&ranked), case.ndcg(&ranked))
+        })
+        .fold((0., 0.), |(a, b), (c, d)| (a + c, b + d));
+
+    (mrr / n, ndcg / n)
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let cases: Vec<Case> = serde_json::from_str(FIXTURE).unwrap();
+
+    // A weight of 0 keeps the vector similarity ranking.
+    for weight in [0., 0.1, semantic::DEFAULT_BM25_WEIGHT, 0.5, 1.] {
+        let (mrr, ndcg) = quality(&cases, weight);
+        println!("rerank quality, bm25 weight {weight:.2}: MRR {mrr:.3}, nDCG {ndcg:.3}");
+    }
+
+    c.bench_function("semantic::rerank - fixture", |b| {
+        let inputs = cases
+            .iter()
+            .map(|case| (case.query.as_str(), case.payloads()))
+            .collect::<Vec<_>>();
+
+        b.iter(|| {
+            for (query, payloads) in &inputs {
+                black_box(semantic::rerank(black_box(query), payloads.clone()));
+            }
+        });
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,16 @@
+CREATE TABLE query_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    user_id TEXT,
+    repo_ref TEXT NOT NULL,
+    thread_id TEXT NOT NULL,
+    query_id TEXT NOT NULL,
+    stage TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    latency_ms INTEGER NOT NULL
+);
+
+-- Usage reports always select a time range.
+CREATE INDEX query_usage_created_at ON query_usage (created_at);
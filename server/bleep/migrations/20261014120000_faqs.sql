@@ -0,0 +1,13 @@
+CREATE TABLE faqs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    question TEXT NOT NULL,
+    -- An optional regex, matched against the user query.
+    pattern TEXT,
+    -- Markdown text, returned verbatim.
+    answer TEXT NOT NULL,
+    -- If set, this FAQ only applies to queries against this repository.
+    repo_ref TEXT,
+    -- Little-endian `f32` embedding of `question`, if semantic search was available.
+    embedding BLOB
+);
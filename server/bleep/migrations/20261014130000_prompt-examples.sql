@@ -0,0 +1,16 @@
+-- Few-shot examples of tool call sequences, mined from positively rated exchanges.
+CREATE TABLE prompt_examples (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at INTEGER NOT NULL,
+    repo_ref TEXT NOT NULL,
+    query_id TEXT NOT NULL UNIQUE,
+    example TEXT NOT NULL,
+    -- The number of tool calls in the example.
+    steps INTEGER NOT NULL,
+    -- Pinned examples are always used, and are set by administrators.
+    pinned BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Whether the selection job picked this example as one of the best for its repository.
+    selected BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX prompt_examples_repo_ref ON prompt_examples (repo_ref);
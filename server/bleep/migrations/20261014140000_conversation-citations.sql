@@ -0,0 +1,5 @@
+-- Cited code chunks, shared by all exchanges of a thread.
+--
+-- This is NULL for threads stored before citations were registered. Those threads embed their code
+-- chunks in `exchanges`, and are backfilled on startup.
+ALTER TABLE conversations ADD COLUMN citations TEXT;
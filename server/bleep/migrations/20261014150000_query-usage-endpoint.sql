@@ -0,0 +1 @@
+ALTER TABLE query_usage ADD COLUMN endpoint INTEGER;
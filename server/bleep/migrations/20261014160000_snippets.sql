@@ -0,0 +1,13 @@
+-- Answers that users saved for later reference.
+CREATE TABLE snippets (
+    id TEXT PRIMARY KEY NOT NULL,
+    created_at INTEGER NOT NULL,
+    user_id TEXT NOT NULL,
+    repo_ref TEXT NOT NULL,
+    thread_id TEXT NOT NULL,
+    title TEXT NOT NULL,
+    query TEXT NOT NULL,
+    answer TEXT NOT NULL
+);
+
+CREATE INDEX snippets_user_id ON snippets (user_id);
@@ -0,0 +1,4 @@
+ALTER TABLE query_usage ADD COLUMN run_id TEXT;
+
+-- Run exports collect the LLM calls of each run.
+CREATE INDEX query_usage_run_id ON query_usage (run_id);
@@ -0,0 +1,22 @@
+-- Open pull requests of GitHub repositories, as of the last sync.
+CREATE TABLE pull_requests (
+    repo_ref TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    body TEXT NOT NULL,
+    author TEXT NOT NULL,
+    url TEXT NOT NULL,
+    -- A JSON array of the paths that the pull request changes.
+    changed_files TEXT NOT NULL,
+    -- The `updated_at` timestamp reported by GitHub, used to tell whether the changed files need
+    -- to be fetched again.
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (repo_ref, number)
+);
+
+-- The ETag of the last listing of each repository's open pull requests.
+CREATE TABLE pull_request_syncs (
+    repo_ref TEXT PRIMARY KEY NOT NULL,
+    etag TEXT,
+    synced_at INTEGER NOT NULL
+);
@@ -0,0 +1,18 @@
+-- Long threads keep only their most recent exchanges in `conversations.exchanges`, and archive the
+-- older ones here.
+--
+-- `archived` counts the archived exchanges of a thread, and `archived_paths` is a JSON array of
+-- their paths, which prefix the path aliases of the recent exchanges.
+ALTER TABLE conversations ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE conversations ADD COLUMN archived_paths TEXT NOT NULL DEFAULT '[]';
+
+CREATE TABLE conversation_archive (
+    user_id TEXT NOT NULL,
+    thread_id TEXT NOT NULL,
+    -- The index of the exchange in its thread.
+    position INTEGER NOT NULL,
+    exchange TEXT NOT NULL,
+    -- The citation registry of this exchange alone.
+    citations TEXT NOT NULL,
+    PRIMARY KEY (user_id, thread_id, position)
+);
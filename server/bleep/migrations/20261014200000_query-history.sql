@@ -0,0 +1,20 @@
+-- Every query that a user asked, across their threads.
+--
+-- A query is recorded as `pending` when answering it starts, and is then marked as `answered`,
+-- `errored` or `cancelled`.
+CREATE TABLE query_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Unix timestamp, in seconds.
+    created_at INTEGER NOT NULL DEFAULT (strftime('%s', 'now')),
+    user_id TEXT NOT NULL,
+    thread_id TEXT NOT NULL,
+    exchange_id TEXT NOT NULL UNIQUE,
+    repo_ref TEXT NOT NULL,
+    query TEXT NOT NULL,
+    status TEXT NOT NULL
+);
+
+-- Pages of history are read newest first, after a `(created_at, id)` cursor.
+CREATE INDEX query_history_user ON query_history (user_id, created_at, id);
+CREATE INDEX query_history_user_repo ON query_history (user_id, repo_ref, created_at, id);
+CREATE INDEX query_history_thread ON query_history (user_id, thread_id);
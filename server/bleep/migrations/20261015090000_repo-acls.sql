@@ -0,0 +1,11 @@
+-- Which users can query a repository. Repositories without an ACL can be queried by everyone.
+CREATE TABLE repo_acls (
+    repo_ref TEXT PRIMARY KEY NOT NULL,
+    -- Public repositories can be queried by everyone, whoever is listed below.
+    public BOOLEAN NOT NULL,
+    -- JSON array of the GitHub logins that can query the repository.
+    users TEXT NOT NULL,
+    -- JSON array of the groups, from the `acl_groups` configuration, that can query the repository.
+    groups TEXT NOT NULL,
+    updated_at INTEGER NOT NULL
+);
@@ -0,0 +1,2 @@
+-- How the query that a call was made for was answered, set once the query is answered or fails.
+ALTER TABLE query_usage ADD COLUMN outcome TEXT;
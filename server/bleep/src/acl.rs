@@ -0,0 +1,298 @@
+//! Access control of repositories, for deployments that are shared by several teams.
+//!
+//! A repository with an ACL can only be queried by the users and groups in it, unless it is
+//! public. Repositories without an ACL can be queried by everyone. Repositories that a user can't
+//! query are reported as not found, as if they had never been indexed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use anyhow::Result;
+
+use crate::{
+    db::{RepoAcl, RepoAcls, SqlDb},
+    query::parser::{Query, SemanticQuery},
+    repo::RepoRef,
+    webserver::middleware::User,
+    Configuration,
+};
+
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    /// The ACLs of repositories, by repo ref.
+    acls: RwLock<HashMap<String, RepoAcl>>,
+    /// The members of each group, by GitHub login.
+    groups: BTreeMap<String, Vec<String>>,
+    /// The users that can change ACLs. If there are none, every user can.
+    admins: Vec<String>,
+}
+
+impl AccessControl {
+    pub fn new(
+        acls: Vec<RepoAcl>,
+        groups: BTreeMap<String, Vec<String>>,
+        admins: Vec<String>,
+    ) -> Self {
+        Self {
+            acls: RwLock::new(
+                acls.into_iter()
+                    .map(|acl| (acl.repo_ref.clone(), acl))
+                    .collect(),
+            ),
+            groups,
+            admins,
+        }
+    }
+
+    /// Load the stored ACLs, with the groups and admins of `config`.
+    pub async fn load(db: &SqlDb, config: &Configuration) -> Result<Self> {
+        Ok(Self::new(
+            RepoAcls::new(db).list().await?,
+            config.acl_groups.clone(),
+            config.acl_admins.clone(),
+        ))
+    }
+
+    /// Whether `user` can query the repository `repo_ref`.
+    pub fn allows(&self, user: &User, repo_ref: &RepoRef) -> bool {
+        let acls = self.acls.read().unwrap();
+        self.allows_login(acls.get(&repo_ref.to_string()), user.login())
+    }
+
+    fn allows_login(&self, acl: Option<&RepoAcl>, login: Option<&str>) -> bool {
+        let Some(acl) = acl else {
+            return true;
+        };
+
+        if acl.public {
+            return true;
+        }
+
+        let Some(login) = login else {
+            return false;
+        };
+
+        acl.users.iter().any(|user| user == login)
+            || acl.groups.iter().any(|group| {
+                self.groups
+                    .get(group)
+                    .map_or(false, |members| members.iter().any(|m| m == login))
+            })
+    }
+
+    /// The repositories with an ACL that `user` is not in.
+    pub fn denied(&self, user: &User) -> Vec<RepoRef> {
+        let acls = self.acls.read().unwrap();
+        acls.values()
+            .filter(|acl| !self.allows_login(Some(acl), user.login()))
+            .filter_map(|acl| acl.repo_ref.parse().ok())
+            .collect()
+    }
+
+    /// Remove the repositories that `user` can't query from the `repo:` filters of `query`,
+    /// returning how many were removed.
+    pub fn strip_denied(&self, user: &User, query: &mut SemanticQuery<'_>) -> usize {
+        let denied = self.denied(user);
+        let before = query.repos.len();
+        query.repos.retain(|repo| {
+            let Some(name) = repo.as_plain() else {
+                return true;
+            };
+
+            !denied.iter().any(|repo_ref| names(&name, repo_ref))
+        });
+
+        before - query.repos.len()
+    }
+
+    /// Remove the queries whose `repo:` filter names a repository that `user` can't query,
+    /// returning how many were removed.
+    pub fn strip_denied_queries(&self, user: &User, queries: &mut Vec<Query<'_>>) -> usize {
+        let denied = self.denied(user);
+        let before = queries.len();
+        queries.retain(|query| {
+            let Some(name) = query.repo.as_ref().and_then(|repo| repo.as_plain()) else {
+                return true;
+            };
+
+            !denied.iter().any(|repo_ref| names(&name, repo_ref))
+        });
+
+        before - queries.len()
+    }
+
+    pub fn is_admin(&self, user: &User) -> bool {
+        match user.login() {
+            Some(login) => self.admins.is_empty() || self.admins.iter().any(|a| a == login),
+            None => self.admins.is_empty(),
+        }
+    }
+
+    /// Whether `user` is one of the configured admins.
+    ///
+    /// Unlike `is_admin`, this is false for everyone if there are no admins, for operations that
+    /// can't be left open to every user.
+    pub fn is_configured_admin(&self, user: &User) -> bool {
+        user.login()
+            .map_or(false, |login| self.admins.iter().any(|a| a == login))
+    }
+
+    /// Whether `group` is configured, and so can be listed in an ACL.
+    pub fn has_group(&self, group: &str) -> bool {
+        self.groups.contains_key(group)
+    }
+
+    pub fn get(&self, repo_ref: &RepoRef) -> Option<RepoAcl> {
+        self.acls
+            .read()
+            .unwrap()
+            .get(&repo_ref.to_string())
+            .cloned()
+    }
+
+    /// Store `acl`, which applies to every check from then on.
+    pub async fn set(&self, db: &SqlDb, acl: RepoAcl) -> Result<()> {
+        RepoAcls::new(db).set(&acl).await?;
+        self.acls.write().unwrap().insert(acl.repo_ref.clone(), acl);
+        Ok(())
+    }
+}
+
+/// Whether `name`, from a `repo:` filter, names `repo_ref`.
+fn names(name: &str, repo_ref: &RepoRef) -> bool {
+    name == repo_ref.display_name()
+        || name == repo_ref.indexed_name()
+        || name == repo_ref.to_string()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    pub(crate) fn user(login: &str) -> User {
+        User::Authenticated {
+            login: login.to_owned(),
+            crab: Arc::new(|| -> anyhow::Result<octocrab::Octocrab> {
+                anyhow::bail!("not connected to GitHub")
+            }),
+        }
+    }
+
+    #[test]
+    fn test_allows() {
+        let access = AccessControl::new(
+            vec![
+                RepoAcl {
+                    repo_ref: "github.com/acme/payments".to_owned(),
+                    public: false,
+                    users: vec!["alice".to_owned()],
+                    groups: vec!["platform".to_owned()],
+                },
+                RepoAcl {
+                    repo_ref: "github.com/acme/docs".to_owned(),
+                    public: true,
+                    users: vec![],
+                    groups: vec![],
+                },
+            ],
+            [("platform".to_owned(), vec!["bob".to_owned()])].into(),
+            vec!["alice".to_owned()],
+        );
+
+        let payments = RepoRef::from("github.com/acme/payments");
+        let docs = RepoRef::from("github.com/acme/docs");
+        let web = RepoRef::from("github.com/acme/web");
+
+        // Users are allowed directly, or through their groups.
+        assert!(access.allows(&user("alice"), &payments));
+        assert!(access.allows(&user("bob"), &payments));
+        assert!(!access.allows(&user("carol"), &payments));
+        assert!(!access.allows(&User::Unknown, &payments));
+
+        // Public repositories, and repositories without an ACL, can be queried by everyone.
+        for repo_ref in [&docs, &web] {
+            assert!(access.allows(&user("carol"), repo_ref));
+            assert!(access.allows(&User::Unknown, repo_ref));
+        }
+
+        assert_eq!(access.denied(&user("carol")), [payments]);
+        assert!(access.denied(&user("bob")).is_empty());
+
+        assert!(access.is_admin(&user("alice")));
+        assert!(!access.is_admin(&user("bob")));
+        assert!(AccessControl::default().is_admin(&user("bob")));
+
+        assert!(access.is_configured_admin(&user("alice")));
+        assert!(!access.is_configured_admin(&user("bob")));
+        assert!(!AccessControl::default().is_configured_admin(&user("bob")));
+        assert!(!AccessControl::default().is_configured_admin(&User::Unknown));
+    }
+
+    #[test]
+    fn test_strip_denied() {
+        let access = AccessControl::new(
+            vec![RepoAcl {
+                repo_ref: "github.com/acme/payments".to_owned(),
+                public: false,
+                users: vec!["alice".to_owned()],
+                groups: vec![],
+            }],
+            Default::default(),
+            Vec::new(),
+        );
+
+        let query = crate::query::parser::parse_nl(
+            "repo:acme/payments repo:acme/web repo:github.com/acme/payments how are refunds made",
+        )
+        .unwrap()
+        .into_semantic()
+        .unwrap()
+        .into_owned();
+
+        // Unauthorized repositories are dropped from queries across several repositories, and
+        // counted, whichever of their names are used.
+        let mut stripped = query.clone();
+        assert_eq!(access.strip_denied(&user("carol"), &mut stripped), 2);
+        assert_eq!(stripped.repos().collect::<Vec<_>>(), ["acme/web"]);
+        assert_eq!(stripped.target, query.target);
+
+        let mut kept = query.clone();
+        assert_eq!(access.strip_denied(&user("alice"), &mut kept), 0);
+        assert_eq!(kept, query);
+    }
+
+    #[test]
+    fn test_strip_denied_queries() {
+        let access = AccessControl::new(
+            vec![RepoAcl {
+                repo_ref: "github.com/acme/payments".to_owned(),
+                public: false,
+                users: vec!["alice".to_owned()],
+                groups: vec![],
+            }],
+            Default::default(),
+            Vec::new(),
+        );
+
+        let queries =
+            crate::query::parser::parse("repo:acme/payments refund or repo:acme/web refund or pay")
+                .unwrap();
+
+        // Queries filtered to an unauthorized repository are dropped before they run; queries
+        // across all repositories are kept.
+        let mut stripped = queries.clone();
+        assert_eq!(
+            access.strip_denied_queries(&user("carol"), &mut stripped),
+            1
+        );
+        assert_eq!(stripped, queries[1..]);
+
+        let mut kept = queries.clone();
+        assert_eq!(access.strip_denied_queries(&user("alice"), &mut kept), 0);
+        assert_eq!(kept, queries);
+    }
+}
@@ -1,25 +1,70 @@
-use std::time::Duration;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
 use futures::TryStreamExt;
+use once_cell::sync::OnceCell;
 use tokio::sync::mpsc::Sender;
-use tracing::debug;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
 
 use crate::{
-    analytics::{EventData, QueryEvent},
-    indexes::reader::{ContentDocument, FileDocument},
+    analytics::{self, EventData, QueryEvent},
+    db::{QueryHistory, SnippetId, SnippetStore, Usage, UsageRecord},
+    indexes::{
+        diagnostics::IndexWarnings,
+        lfs,
+        reader::{ContentDocument, FileDocument},
+    },
     llm_gateway::{self, api::FunctionCall},
-    query::parser,
-    repo::RepoRef,
+    query::{languages, parser},
+    repo::{Backend, RepoRef},
     semantic,
     webserver::middleware::User,
     Application,
 };
 
-use self::exchange::{Exchange, SearchStep, Update};
+use self::{
+    exchange::{
+        AnswerOutcome, CodeChunk, ContextSource, Exchange, InstrumentedExchange, Redaction,
+        SearchStep, Update,
+    },
+    file_budget::{FileBudget, FileBudgetExhausted},
+    relocation::Relocation,
+    tokens::{Stopwatch, Tokenizer},
+};
 
+pub mod builder;
+pub mod call_graph;
+pub mod canary;
+pub mod citations;
+pub mod context;
+pub mod deadline;
 pub mod exchange;
+pub mod external;
+pub mod few_shot;
+pub mod file_budget;
+pub mod flush;
+pub mod knowledge;
+pub mod line_map;
+pub mod loops;
+pub mod outcome;
+pub mod page;
+pub mod playbook;
 mod prompts;
+pub mod quick;
+pub mod relocation;
+pub mod secrets;
+pub mod snapshot;
+pub mod stack_trace;
+pub mod title;
+pub mod tokens;
 mod transcoder;
 
 /// A collection of modules that each add methods to `Agent`.
@@ -29,29 +74,200 @@ mod transcoder;
 /// tests.
 mod tools {
     pub mod answer;
+    pub mod changelog;
+    pub mod changelog_diff;
+    pub mod changelog_generator;
     pub mod code;
+    pub mod config;
+    pub mod dead_code;
+    pub mod dependency_check;
+    pub mod deprecated_usage;
+    pub mod format;
+    pub mod list_files;
     pub mod path;
+    pub mod perf;
+    pub mod plan;
     pub mod proc;
+    pub mod prs;
+    pub mod related_files;
+    pub mod similar;
+    pub mod symbol;
+    pub mod todos;
+    pub mod upgrade;
 }
 
 const ANSWER_MODEL: &str = "gpt-4-0613";
 
+/// The default for `AgentConfig::max_file_size_bytes`.
+pub const DEFAULT_MAX_FILE_SIZE_BYTES: usize = 100 * 1024;
+
+/// The error of reading a file that is larger than `AgentConfig::max_file_size_bytes`.
+#[derive(thiserror::Error, Debug)]
+#[error("file too large to read: {path} is {size} bytes, over the limit of {limit} bytes")]
+pub struct FileTooLarge {
+    pub path: String,
+    pub size: usize,
+    pub limit: usize,
+}
+
+/// The default for `AgentConfig::headroom_tokens`.
+pub const DEFAULT_HEADROOM_TOKENS: usize = 2048;
+
+/// How the answer to a query is written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Plain text, for clients that can't render markdown.
+    Prose,
+    /// A JSON object with the answer, its summary and its outcome, for clients like IDE plugins
+    /// that process answers rather than show them.
+    Json,
+    /// Markdown, with links to the code that the answer refers to.
+    #[default]
+    Markdown,
+}
+
 pub enum Error {
     Timeout(Duration),
     Processing(anyhow::Error),
 }
 
+/// The share of a repository that is written in a single language.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LanguageStat {
+    pub language: String,
+    pub file_count: usize,
+    pub line_count: usize,
+    /// The percentage of lines in the repository written in this language.
+    pub percentage: f32,
+}
+
+/// The settings of a query, which `AgentBuilder` sets up.
+///
+/// These stay the same while the query is answered, apart from the headroom that tools adjust.
+pub struct AgentConfig {
+    /// Few-shot examples used to refine code search queries.
+    ///
+    /// If this is `None`, the defaults in `prompts::CODE_SEARCH_EXAMPLES` are used.
+    pub search_examples: Option<Vec<String>>,
+
+    /// Few-shot examples of tool calls that answered past queries against this repository well.
+    ///
+    /// These are shown in the system prompt, and are loaded with `few_shot::load`.
+    pub tool_examples: Vec<String>,
+
+    /// The branch that searches are restricted to when the query names none.
+    ///
+    /// This is the `default_query_branch` of the repository's branch settings.
+    pub default_branch: Option<String>,
+
+    /// Files larger than this are left out of search results and file reads.
+    ///
+    /// These tend to be generated files or vendored assets, which waste context.
+    pub max_file_size_bytes: usize,
+
+    /// The tokens that `trim_history` leaves free for the model's response.
+    ///
+    /// Tools that generate long responses raise this with `Agent::adjust_headroom`.
+    pub headroom_tokens: usize,
+
+    /// The language that code searches are restricted to, set with `Agent::set_language_hint`.
+    pub language_hint: Option<String>,
+
+    /// When the answer is updated while it is streamed.
+    pub flush: flush::Flush,
+
+    /// The most tokens that the LLM can respond with, set with `Agent::set_max_tokens`.
+    pub max_response_tokens: Option<u32>,
+
+    /// Whether `proc` asks the model for a `ProcResult`, instead of its usual line ranges.
+    pub use_structured_proc_output: bool,
+
+    /// Whether queries are answered in full, or quickly from search snippets.
+    pub mode: quick::Mode,
+
+    /// How answers are written, set with `Agent::with_output_format`.
+    pub output_format: OutputFormat,
+
+    /// How long a single tool call can take, before it is given up on with `deadline::TimedOut`.
+    pub tool_timeout: Duration,
+
+    /// The most tool calls that a query can make before it is answered, if it is limited.
+    pub max_steps: Option<usize>,
+}
+
 pub struct Agent {
     pub app: Application,
     pub repo_ref: RepoRef,
     pub exchanges: Vec<Exchange>,
-    pub exchange_tx: Sender<Exchange>,
+    pub exchange_tx: ExchangeTx,
+
+    /// The paths of exchanges that were archived from this thread, and are not in `exchanges`.
+    ///
+    /// These keep their path aliases, which the paths of `exchanges` follow.
+    pub archived_paths: Vec<String>,
 
     pub llm_gateway: llm_gateway::Client,
     pub user: User,
     pub thread_id: uuid::Uuid,
     pub query_id: uuid::Uuid,
 
+    /// The browser session of the user, which groups the analytics events of their threads.
+    pub session_id: Option<String>,
+
+    /// The settings that this query is answered with.
+    pub config: AgentConfig,
+
+    /// Frames of a stack trace pasted into the query, rendered for the system prompt.
+    ///
+    /// Only frames that resolve to files in this repository are kept, innermost first.
+    pub stack_trace: Vec<String>,
+
+    /// Pages linked in the query, rendered for the system prompt.
+    pub external_context: Vec<String>,
+
+    /// The call graph of a single function that the query asks to explain.
+    ///
+    /// When this is set, the agent skips tool calls and answers with a dedicated prompt.
+    pub call_graph: Option<call_graph::CallGraph>,
+
+    /// The generated title of this thread, cached by `Agent::conversation_title`.
+    pub thread_title: Option<String>,
+
+    /// A summary of the files that the last index of the repository could not fully index, if
+    /// enough were affected for answers to carry a caveat. This is attached to every query.
+    pub index_warnings: Option<IndexWarnings>,
+
+    /// Files that secrets were redacted from, which are yet to be recorded on the exchange.
+    ///
+    /// Searches only borrow the agent, so they collect redactions here for `Agent::update`.
+    pub pending_redactions: Mutex<Vec<Redaction>>,
+
+    /// The time spent counting tokens, which is yet to be recorded on the exchange.
+    pub tokenization: Stopwatch,
+
+    /// What the agent can make use of in this repository, cached by `Agent::capabilities`.
+    pub capabilities: OnceCell<prompts::Capabilities>,
+
+    /// The files whose content was sent to the LLM for this query, set up by `Agent::file_budget`.
+    pub file_budget: OnceCell<Mutex<FileBudget>>,
+
+    /// Documents added with `Agent::add_knowledge_base_document`, for the system prompt.
+    pub knowledge_base: Vec<knowledge::KnowledgeDoc>,
+
+    /// The recent tool calls of this query, for breaking answer loops.
+    pub loop_guard: loops::LoopGuard,
+
+    /// The parent of the cancellation tokens of tool calls, which is cancelled when the agent is
+    /// dropped.
+    pub cancellation: CancellationToken,
+
+    /// The LLM usage of a canary query, like the ones that `POST /admin/selftest` runs.
+    ///
+    /// Canary queries send no analytics events, and their usage is collected here instead of
+    /// being recorded, so that they are not billed to anyone.
+    pub canary_usage: Option<Mutex<Vec<UsageRecord>>>,
+
     /// Indicate whether the request was answered.
     ///
     /// This is used in the `Drop` handler, in order to track cancelled answer queries.
@@ -68,11 +284,28 @@ pub struct Agent {
 /// `.complete()` will "diffuse" tracking, and disable the cancellation message from sending on drop.
 impl Drop for Agent {
     fn drop(&mut self) {
+        self.cancellation.cancel();
+
         if !self.complete {
             self.track_query(
                 EventData::output_stage("cancelled")
                     .with_payload("message", "request was cancelled"),
             );
+
+            // Queries that ended with an error are already marked as such.
+            let (sql, query_id) = (self.app.sql.clone(), self.query_id.to_string());
+
+            // Agents can be dropped outside of a runtime, where nothing can be spawned.
+            if tokio::runtime::Handle::try_current().is_err() {
+                warn!(query_id, "no runtime to mark query as cancelled");
+                return;
+            }
+
+            context::spawn_in_ctx(&self.request_context(), async move {
+                if let Err(err) = QueryHistory::new(&sql).cancel(&query_id).await {
+                    warn!(?err, query_id, "failed to mark query as cancelled");
+                }
+            });
         }
     }
 }
@@ -84,27 +317,266 @@ impl Agent {
         self.complete = true;
     }
 
+    /// Override the few-shot examples used to refine code search queries.
+    pub fn with_search_examples(mut self, examples: Vec<String>) -> Self {
+        self.config.search_examples = Some(examples);
+        self
+    }
+
+    /// Write answers in `format`, instead of markdown.
+    pub fn with_output_format(mut self, format: OutputFormat) -> Self {
+        self.config.output_format = format;
+        self
+    }
+
+    /// Save the answer of the last exchange as a snippet, for the user to refer back to later.
+    pub async fn save_answer_as_snippet(&self, title: &str) -> Result<SnippetId> {
+        let exchange = self.last_exchange();
+        let (answer, _) = exchange.answer().context("exchange has no answer")?;
+        let query = exchange.query().context("exchange has no query")?;
+        let user_id = self.user.login().context("didn't have user ID")?;
+
+        SnippetStore::new(&self.app.sql)
+            .insert(
+                user_id,
+                &self.repo_ref.to_string(),
+                &self.thread_id.to_string(),
+                title,
+                &query,
+                answer,
+            )
+            .await
+    }
+
     /// Update the last exchange
     async fn update(&mut self, update: Update) -> Result<()> {
-        self.last_exchange_mut().apply_update(update);
+        let budget_exhausted = self.file_budget().lock().unwrap().is_exhausted();
+        if budget_exhausted && !self.last_exchange().file_budget_exhausted {
+            self.last_exchange_mut().file_budget_exhausted = true;
+            self.track_query(
+                EventData::output_stage("file budget exhausted")
+                    .with_payload("max_files_sent", self.app.config.max_files_sent),
+            );
+        }
 
-        // Immutable reborrow of `self`
-        let self_ = &*self;
-        self_
-            .exchange_tx
-            .send(self.last_exchange().clone())
-            .await
-            .map_err(|_| anyhow!("exchange_tx was closed"))
+        let redactions = std::mem::take(&mut *self.pending_redactions.lock().unwrap());
+        let exchange = self.exchanges.last_mut().expect("exchange list was empty");
+        for redaction in redactions {
+            exchange.record_redaction(redaction);
+        }
+        exchange.tokenization_us += self.tokenization.take().as_micros() as u64;
+
+        send_update(exchange, &self.exchange_tx, update).await
+    }
+
+    /// The context window and pricing of the model that picks the agent's actions.
+    ///
+    /// Without a model set on the gateway client, this is the answer model.
+    pub fn llm_model_info(&self) -> llm_gateway::LlmModelInfo {
+        let model = self.llm_gateway.model.as_deref().unwrap_or(ANSWER_MODEL);
+        llm_gateway::LlmModelInfo::for_model(model)
+    }
+
+    /// The tokenizer of `model`, which records its time spent on the current exchange.
+    fn tokenizer(&self, model: &str) -> Result<Tokenizer> {
+        Tokenizer::with_stopwatch(model, self.tokenization.clone())
+    }
+
+    /// Replace secrets in `text` with markers, unless this repository is opted out.
+    ///
+    /// If `path` is given, it is recorded on the exchange as a file that secrets were found in.
+    fn redact_secrets<'a>(&self, path: Option<&str>, text: &'a str) -> Cow<'a, str> {
+        let repo = self.repo_ref.to_string();
+        if self
+            .app
+            .config
+            .disable_secret_redaction_repos
+            .contains(&repo)
+        {
+            return Cow::Borrowed(text);
+        }
+
+        let (redacted, kinds) = self.app.secret_scanner.redact(text);
+        if let Some(path) = path.filter(|_| !kinds.is_empty()) {
+            debug!(path, ?kinds, "redacted secrets");
+            self.pending_redactions.lock().unwrap().push(Redaction {
+                path: path.to_owned(),
+                kinds,
+            });
+        }
+
+        redacted
+    }
+
+    /// Replace matches of any of `redact_patterns` with `[REDACTED]`, throughout the exchanges of
+    /// this thread.
+    ///
+    /// This changes the exchanges in place, so that they are stored anonymized.
+    pub fn anonymize_exchanges(&mut self, redact_patterns: &[regex::Regex]) {
+        for exchange in &mut self.exchanges {
+            exchange.anonymize(redact_patterns);
+        }
     }
 
     pub fn track_query(&self, data: EventData) {
-        let event = QueryEvent {
+        if !analytics::enabled() || self.canary_usage.is_some() {
+            return;
+        }
+
+        self.app.track_query(&self.user, &self.query_event(data));
+    }
+
+    /// An analytics event of the query being answered.
+    fn query_event(&self, data: EventData) -> QueryEvent {
+        QueryEvent {
             query_id: self.query_id,
+            run_id: self.last_exchange().run_id,
             thread_id: self.thread_id,
+            session_id: self.session_id.clone(),
             repo_ref: Some(self.repo_ref.clone()),
             data,
+        }
+    }
+
+    /// Record the token usage and latency of an LLM call made while answering this query.
+    async fn track_usage(
+        &self,
+        stage: &str,
+        model: &str,
+        messages: &[llm_gateway::api::Message],
+        response: &str,
+        latency: Duration,
+    ) {
+        let tiktoken_msgs = messages.iter().map(|m| m.into()).collect::<Vec<_>>();
+        let (prompt_tokens, completion_tokens) = self
+            .tokenizer(model)
+            .map(|t| (t.count_messages(&tiktoken_msgs), t.count(response)))
+            .unwrap_or_default();
+
+        let record = UsageRecord {
+            created_at: Utc::now().timestamp(),
+            user_id: self.user.login().map(str::to_owned),
+            repo_ref: self.repo_ref.to_string(),
+            thread_id: self.thread_id.to_string(),
+            query_id: self.query_id.to_string(),
+            run_id: self.last_exchange().run_id.map(|id| id.to_string()),
+            stage: stage.to_owned(),
+            model: model.to_owned(),
+            prompt_tokens: prompt_tokens as i64,
+            completion_tokens: completion_tokens as i64,
+            latency_ms: latency.as_millis() as i64,
+            endpoint: self.llm_gateway.last_endpoint().map(|i| i as i64),
+            outcome: self.last_exchange().outcome.map(|o| o.as_str().to_owned()),
+        };
+
+        if let Some(usage) = &self.canary_usage {
+            usage.lock().unwrap().push(record);
+            return;
+        }
+
+        if let Err(err) = Usage::new(&self.app.sql).insert(&record).await {
+            warn!(?err, "failed to record LLM usage");
+        }
+    }
+
+    /// Record how the query was answered, on the exchange and on the usage records of its run.
+    async fn record_outcome(&mut self, outcome: AnswerOutcome) {
+        self.last_exchange_mut().outcome = Some(outcome);
+        self.track_query(
+            EventData::output_stage("answer outcome").with_payload("outcome", outcome),
+        );
+
+        let Some(run_id) = self.last_exchange().run_id else {
+            return;
         };
-        self.app.track_query(&self.user, &event);
+
+        if let Err(err) = Usage::new(&self.app.sql)
+            .set_outcome(&run_id.to_string(), outcome.as_str())
+            .await
+        {
+            warn!(?err, "failed to record answer outcome");
+        }
+    }
+
+    /// Leave `tokens` free for the model's response when trimming the history from now on.
+    pub fn adjust_headroom(&mut self, tokens: usize) {
+        self.config.headroom_tokens = tokens;
+    }
+
+    /// The ids of the query being answered, for the log events of its background tasks.
+    pub fn request_context(&self) -> context::RequestContext {
+        context::RequestContext {
+            user: self.user.login().map(str::to_owned),
+            thread_id: self.thread_id,
+            query_id: self.query_id,
+            run_id: self.exchanges.last().and_then(|e| e.run_id),
+        }
+    }
+
+    /// Restrict code searches to `language` from now on.
+    pub fn set_language_hint(&mut self, language: impl Into<String>) {
+        self.config.language_hint = Some(language.into());
+    }
+
+    /// Add a document to the context of every step, when it is relevant to the query.
+    ///
+    /// This is for documentation that isn't in an indexed repository. Of all added documents, the
+    /// ones that best match the query are shown, up to `knowledge::MAX_TOKENS`.
+    pub fn add_knowledge_base_document(&mut self, title: &str, content: &str) {
+        self.knowledge_base.push(knowledge::KnowledgeDoc {
+            title: title.to_owned(),
+            content: content.to_owned(),
+        });
+    }
+
+    /// Clamp every LLM response from now on to `n` tokens.
+    ///
+    /// Every LLM call goes through `llm_gateway`, so the limit is set on its requests.
+    pub fn set_max_tokens(&mut self, n: u32) -> &mut Self {
+        self.config.max_response_tokens = Some(n);
+        self.llm_gateway = self.llm_gateway.clone().max_tokens(n);
+        self
+    }
+
+    /// The page of this thread's exchanges that starts at `cursor`, signed with the app's key.
+    pub fn exchanges_page(
+        &self,
+        cursor: Option<&str>,
+        page_size: usize,
+    ) -> Result<page::ThreadPage> {
+        page::page(
+            &self.exchanges,
+            self.thread_id,
+            self.app.cookie_key.signing(),
+            cursor,
+            page_size,
+        )
+    }
+
+    /// The budget of files whose content can be sent to the LLM for this query.
+    ///
+    /// Files that the user pinned to the last exchange are taken out of the budget first.
+    fn file_budget(&self) -> &Mutex<FileBudget> {
+        self.file_budget.get_or_init(|| {
+            let pinned = self
+                .last_exchange()
+                .context
+                .iter()
+                .filter(|f| f.sources.contains(&ContextSource::Pinned))
+                .map(|f| f.path.as_str());
+
+            Mutex::new(FileBudget::new(self.app.config.max_files_sent, pinned))
+        })
+    }
+
+    /// Spend the file budget on `path`, returning whether its content can be sent to the LLM.
+    fn spend_file_budget(&self, path: &str) -> bool {
+        let spent = self.file_budget().lock().unwrap().spend(path);
+        if !spent {
+            debug!(path, "file budget exhausted");
+        }
+
+        spent
     }
 
     fn last_exchange(&self) -> &Exchange {
@@ -115,58 +587,265 @@ impl Agent {
         self.exchanges.last_mut().expect("exchange list was empty")
     }
 
+    /// Whether open pull requests are synced for this repository, which requires the GitHub
+    /// integration.
+    fn has_pull_requests(&self) -> bool {
+        self.repo_ref.backend() == Backend::Github && self.app.credentials.github().is_some()
+    }
+
+    /// What the agent can make use of in this repository, which decides the functions it is
+    /// offered.
+    fn capabilities(&self) -> &prompts::Capabilities {
+        self.capabilities.get_or_init(|| {
+            let repo = self.app.repo_pool.read(&self.repo_ref, |_, repo| {
+                (repo.disk_path.clone(), repo.most_common_lang.clone())
+            });
+
+            let (commit_history, docs, language) = match repo {
+                Some((disk_path, language)) => (
+                    disk_path.join(".git").exists(),
+                    has_docs(&disk_path),
+                    language,
+                ),
+                None => (false, false, None),
+            };
+
+            prompts::Capabilities {
+                semantic: self.app.semantic.is_some(),
+                commit_history,
+                docs,
+                pull_requests: self.has_pull_requests(),
+                path_count: self.last_exchange().query.paths.len(),
+                language,
+            }
+        })
+    }
+
     fn paths(&self) -> Vec<String> {
-        self.exchanges
+        self.archived_paths
             .iter()
-            .flat_map(|e| e.paths.iter().cloned())
+            .cloned()
+            .chain(self.exchanges.iter().flat_map(|e| e.paths.iter().cloned()))
             .collect::<Vec<_>>()
     }
 
+    /// The paths of `paths`, with the repository that each one is in if it is known.
+    fn alias_table(&self) -> Vec<(Option<&RepoRef>, &str)> {
+        alias_table(&self.archived_paths, &self.exchanges)
+    }
+
+    /// Whether a path in `repo` is in the repository that this agent searches.
+    ///
+    /// Paths in an unknown repository are taken to be in this one, unless the thread has searched
+    /// another repository.
+    fn is_this_repo(&self, repo: Option<&RepoRef>) -> bool {
+        match repo {
+            Some(repo) => *repo == self.repo_ref,
+            None => self
+                .exchanges
+                .iter()
+                .filter_map(|e| e.repo_ref.as_ref())
+                .all(|r| *r == self.repo_ref),
+        }
+    }
+
+    /// The aliases of the paths in the repository that this agent searches.
+    ///
+    /// Paths that earlier exchanges of the thread found in other repositories keep their aliases,
+    /// but can't be read from this one.
+    fn repo_aliases(&self) -> Vec<usize> {
+        self.alias_table()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (repo, _))| self.is_this_repo(*repo))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// The paths of `paths` as they are shown to the model, with the repository of the ones that
+    /// are not in this agent's repository.
+    fn alias_labels(&self) -> Vec<String> {
+        self.alias_table()
+            .into_iter()
+            .map(|(repo, path)| match repo {
+                _ if self.is_this_repo(repo) => path.to_owned(),
+                Some(repo) => format!("{path} (in {})", repo.display_name()),
+                None => format!("{path} (in another repository)"),
+            })
+            .collect()
+    }
+
+    /// The alias of `path` in this agent's repository, which is added to the alias table if it is
+    /// not in it yet.
+    ///
+    /// The same path in another repository has a different alias, so that aliases stay unique
+    /// across the thread.
     fn get_path_alias(&mut self, path: &str) -> usize {
-        if let Some(i) = self.paths().iter().position(|p| *p == path) {
+        let table = self.alias_table();
+        let found = table
+            .iter()
+            .position(|(repo, p)| *p == path && self.is_this_repo(*repo));
+        let len = table.len();
+
+        if let Some(i) = found {
             i
         } else {
-            let i = self.paths().len();
             self.last_exchange_mut().paths.push(path.to_owned());
-            i
+            len
         }
     }
 
+    /// Execute an action, returning the next action if there is one.
+    ///
+    /// If the action fails, the error is recorded on the last exchange and sent to `exchange_tx`
+    /// before it is returned, so that it can be shown to the user.
+    #[tracing::instrument(skip_all, fields(run_id = ?self.last_exchange().run_id))]
     pub async fn step(&mut self, action: Action) -> Result<Option<Action>> {
-        debug!(?action, %self.thread_id, "executing next action");
+        let result = self.try_step(action).await;
+        if result.is_err() {
+            self.record_outcome(AnswerOutcome::Error).await;
+        }
+
+        let exchange = self.exchanges.last_mut().expect("exchange list was empty");
+        report_error(exchange, &self.exchange_tx, result).await
+    }
 
-        match &action {
-            Action::Query(s) => {
-                self.track_query(EventData::input_stage("query").with_payload("q", s));
-                s.clone()
+    async fn try_step(&mut self, action: Action) -> Result<Option<Action>> {
+        // A batch runs one of its actions per step, without asking the model in between.
+        let (action, queued) = match action {
+            Action::Batch(mut actions) if !actions.is_empty() => {
+                let first = actions.remove(0);
+                (first, actions)
             }
+            Action::Batch(_) => bail!("a batch of actions was empty"),
+            action => (action, Vec::new()),
+        };
+
+        debug!(?action, queued = queued.len(), %self.thread_id, "executing next action");
+
+        let cache_key = action.cache_key();
+
+        if let Some((tool, args)) = &cache_key {
+            let thresholds = self.loop_thresholds();
+            match self.loop_guard.record(*tool, args, &thresholds) {
+                loops::Verdict::Continue => {}
+                loops::Verdict::Nudge => self.track_query(
+                    EventData::output_stage("loop nudge")
+                        .with_payload("tool", tool)
+                        .with_payload("repeats", self.loop_guard.run()),
+                ),
+                loops::Verdict::Answer => {
+                    debug!(?action, %self.thread_id, "breaking an answer loop");
+                    self.track_query(
+                        EventData::output_stage("loop break")
+                            .with_payload("tool", tool)
+                            .with_payload("repeats", self.loop_guard.run()),
+                    );
 
-            Action::Answer { paths } => {
-                self.answer(paths).await?;
-                return Ok(None);
+                    self.last_exchange_mut().forced_answer = true;
+                    let paths = self.repo_aliases();
+                    return Ok(Some(Action::Answer { paths }));
+                }
             }
+        }
 
-            Action::Path { query } => self.path_search(query).await?,
-            Action::Code { query } => self.code_search(query).await?,
-            Action::Proc { query, paths } => self.process_files(query, paths).await?,
-        };
+        let cached_step = cache_key
+            .as_ref()
+            .and_then(|key| self.last_exchange().cached_step(key));
 
-        let functions = serde_json::from_value::<Vec<llm_gateway::api::Function>>(
-            prompts::functions(!self.paths().is_empty()), // Only add proc if there are paths in context
-        )
-        .unwrap();
+        if let Some(step) = cached_step {
+            debug!(?action, %self.thread_id, "reusing the result of a repeated tool call");
+            self.update(Update::ReuseStep(step)).await?;
+        } else {
+            match &action {
+                Action::Query(s) => {
+                    self.track_query(EventData::input_stage("query").with_payload("q", s));
+                    self.last_exchange_mut().index_warnings = self.index_warnings.clone();
 
-        let paths = self.paths();
-        let mut history = vec![llm_gateway::api::Message::system(&prompts::system(
-            paths.iter().map(String::as_str),
-        ))];
-        history.extend(self.history()?);
+                    if let Some(paths) = self.seed_call_graph(s).await? {
+                        return Ok(Some(Action::Answer { paths }));
+                    }
+
+                    if self.config.mode == quick::Mode::Quick {
+                        self.quick_search(s).await?;
+                        let paths = self.repo_aliases();
+                        return Ok(Some(Action::Answer { paths }));
+                    }
+
+                    self.seed_stack_trace(s).await?;
+                    self.seed_external_context(s).await?;
+                    s.clone()
+                }
+
+                Action::Answer { paths } => {
+                    self.answer(paths).await?;
+                    return Ok(None);
+                }
+
+                Action::Path { query } => self.path_search(query).await?,
+                Action::ListFiles { pattern } => self.list_files(pattern).await?,
+                Action::DependencyVulns {} => self.dependency_vulns().await?,
+                Action::UpgradeSuggestions { dep_name } => {
+                    self.upgrade_suggestions(dep_name).await?
+                }
+                Action::DeadCode {} => self.dead_code().await?,
+                Action::DeprecatedUsage { package } => self.deprecated_usage(package).await?,
+                Action::RelatedFiles { paths } => self.related_files(paths).await?,
+                Action::ConfigAudit { path } => self.config_audit(path).await?,
+                Action::TODOs { path } => self.todos(path).await?,
+                Action::Allocations { path } => self.allocations(path).await?,
+                Action::FindSimilar { path } => self.find_similar(path).await?,
+                Action::Symbol { query } => self.symbol_search(query).await?,
+                Action::Changelog { since } => self.changelog(since.as_ref()).await?,
+                Action::ChangelogDiff { v1, v2 } => self.changelog_diff(v1, v2).await?,
+                Action::WeeklyDigest {} => self.weekly_digest().await?,
+                Action::Prs { query } => self.pr_search(query).await?,
+                Action::Format { path } => self.format_check(path).await?,
+                Action::Code { query } => self.code_search(query).await?,
+                Action::Proc { query, paths } => self.process_files(query, paths).await?,
+                Action::Plan { goal } => match self.plan(goal).await? {
+                    actions if actions.is_empty() => String::new(),
+                    actions => return Ok(Some(Action::Batch(actions))),
+                },
+                Action::Batch(_) => bail!("batches of actions can't be nested"),
+            };
+
+            // Failed tool calls return early above, so only successful results are cached.
+            if let Some(key) = cache_key {
+                self.last_exchange_mut().cache_last_step(key);
+            }
+        }
+
+        if quick::is_spent(self.last_exchange()) {
+            let paths = self.repo_aliases();
+            return Ok(Some(Action::Answer { paths }));
+        }
+
+        let steps = self.last_exchange().search_steps.len();
+        if self.config.max_steps.map_or(false, |max| steps >= max) {
+            self.last_exchange_mut().forced_answer = true;
+            let paths = self.repo_aliases();
+            return Ok(Some(Action::Answer { paths }));
+        }
+
+        if !queued.is_empty() {
+            return Ok(Some(Action::Batch(queued)));
+        }
 
-        let trimmed_history = trim_history(history.clone())?;
+        let functions = self.step_functions();
+        let (history, pinned): (Vec<_>, Vec<_>) = self.pinned_step_history()?.into_iter().unzip();
+        let trimmed_history = trim_history(
+            history.clone(),
+            &pinned,
+            &self.tokenizer(ANSWER_MODEL)?,
+            self.config.headroom_tokens,
+            self.app.config.token_safety_margin,
+        )?;
 
+        let start = Instant::now();
         let raw_response = self
             .llm_gateway
-            .chat(&trim_history(history.clone())?, Some(&functions))
+            .chat(&trimmed_history, Some(&functions))
             .await?
             .try_fold(
                 llm_gateway::api::FunctionCall::default(),
@@ -180,97 +859,164 @@ impl Agent {
             )
             .await?;
 
+        self.track_usage(
+            "step",
+            ANSWER_MODEL,
+            &trimmed_history,
+            &serde_json::to_string(&raw_response)?,
+            start.elapsed(),
+        )
+        .await;
+
         self.track_query(
             EventData::output_stage("llm_reply")
                 .with_payload("full_history", &history)
                 .with_payload("trimmed_history", &trimmed_history)
                 .with_payload("last_message", history.last())
                 .with_payload("functions", &functions)
-                .with_payload("raw_response", &raw_response),
+                .with_payload("raw_response", &raw_response)
+                .with_payload("llm_endpoint", self.llm_gateway.last_endpoint()),
+        );
+
+        debug!(
+            function_call = %raw_response.pretty_print(),
+            %self.thread_id,
+            "received next action"
         );
 
-        let action = Action::deserialize_gpt(&raw_response)?;
+        let action = Action::deserialize_offered(&raw_response, &functions)?;
         Ok(Some(action))
     }
 
+    /// The functions that the model can call in the next step.
+    fn step_functions(&self) -> Vec<llm_gateway::api::Function> {
+        // Only add proc if there are paths in context that it can read
+        let add_proc = !self.repo_aliases().is_empty();
+        let query_type = self.last_exchange().query_type();
+        let mut functions = serde_json::from_value::<Vec<llm_gateway::api::Function>>(
+            prompts::functions(add_proc, self.capabilities(), query_type),
+        )
+        .unwrap();
+
+        if self.last_exchange().quick {
+            quick::restrict_functions(&mut functions);
+        }
+
+        functions
+    }
+
+    /// The messages that the next action is picked with, starting with the system prompt.
+    fn step_history(&self) -> Result<Vec<llm_gateway::api::Message>> {
+        Ok(unpin(self.pinned_step_history()?))
+    }
+
+    /// As `step_history`, with whether each message belongs to a pinned exchange.
+    fn pinned_step_history(&self) -> Result<Vec<(llm_gateway::api::Message, bool)>> {
+        let system = llm_gateway::api::Message::system(&self.system_prompt()?);
+        let mut history = vec![(system, false)];
+        history.extend(self.pinned_history()?);
+
+        if self.loop_guard.is_nudging(&self.loop_thresholds()) {
+            let nudge = llm_gateway::api::Message::system(loops::NUDGE_MESSAGE);
+            history.push((nudge, false));
+        }
+
+        Ok(history)
+    }
+
+    /// The system prompt of the next step, with the knowledge base documents relevant to the last
+    /// query.
+    fn system_prompt(&self) -> Result<String> {
+        let query = self.last_exchange().query().unwrap_or_default();
+        let knowledge = knowledge::sections(
+            &self.knowledge_base,
+            &query,
+            &self.tokenizer(ANSWER_MODEL)?,
+            knowledge::MAX_TOKENS,
+        )?;
+
+        let paths = self.alias_labels();
+        Ok(prompts::system(
+            paths.iter().map(String::as_str),
+            &self.config.tool_examples,
+            &self.stack_trace,
+            &knowledge,
+            &self.external_context,
+            &self.last_exchange().query.filters(),
+        ))
+    }
+
+    fn loop_thresholds(&self) -> loops::Thresholds {
+        loops::Thresholds {
+            similarity: self.app.config.loop_similarity,
+            nudge_after: self.app.config.loop_nudge_after,
+            answer_after: self.app.config.loop_answer_after,
+        }
+    }
+
     /// The full history of messages, including intermediate function calls
     fn history(&self) -> Result<Vec<llm_gateway::api::Message>> {
+        Ok(unpin(self.pinned_history()?))
+    }
+
+    /// As `history`, with whether each message belongs to a pinned exchange.
+    ///
+    /// Only the last few exchanges are included, along with any earlier exchanges that were
+    /// pinned.
+    fn pinned_history(&self) -> Result<Vec<(llm_gateway::api::Message, bool)>> {
         const ANSWER_MAX_HISTORY_SIZE: usize = 3;
-        const FUNCTION_CALL_INSTRUCTION: &str = "Call a function. Do not answer";
 
-        let history = self
-            .exchanges
+        let first = self.exchanges.len().saturating_sub(ANSWER_MAX_HISTORY_SIZE);
+        let exchanges = self.exchanges[..first]
             .iter()
-            .rev()
-            .take(ANSWER_MAX_HISTORY_SIZE)
-            .rev()
-            .try_fold(Vec::new(), |mut acc, e| -> Result<_> {
-                let query = e
-                    .query()
-                    .map(|q| llm_gateway::api::Message::user(&q))
-                    .ok_or_else(|| anyhow!("query does not have target"))?;
-
-                let steps = e.search_steps.iter().flat_map(|s| {
-                    let (name, arguments) = match s {
-                        SearchStep::Path { query, .. } => (
-                            "path".to_owned(),
-                            format!("{{\n \"query\": \"{query}\"\n}}"),
-                        ),
-                        SearchStep::Code { query, .. } => (
-                            "code".to_owned(),
-                            format!("{{\n \"query\": \"{query}\"\n}}"),
-                        ),
-                        SearchStep::Proc { query, paths, .. } => (
-                            "proc".to_owned(),
-                            format!(
-                                "{{\n \"paths\": [{}],\n \"query\": \"{query}\"\n}}",
-                                paths
-                                    .iter()
-                                    .map(|path| self
-                                        .paths()
-                                        .iter()
-                                        .position(|p| p == path)
-                                        .unwrap()
-                                        .to_string())
-                                    .collect::<Vec<_>>()
-                                    .join(", ")
-                            ),
-                        ),
-                    };
-
-                    vec![
-                        llm_gateway::api::Message::function_call(&FunctionCall {
-                            name: Some(name.clone()),
-                            arguments,
-                        }),
-                        llm_gateway::api::Message::function_return(&name, &s.get_response()),
-                        llm_gateway::api::Message::user(FUNCTION_CALL_INSTRUCTION),
-                    ]
-                });
-
-                let answer = match e.answer() {
-                    // NB: We intentionally discard the summary as it is redundant.
-                    Some((answer, _conclusion)) => {
-                        let encoded = transcoder::encode_summarized(answer, None, "gpt-3.5-turbo")?;
-                        Some(llm_gateway::api::Message::assistant(&encoded))
-                    }
+            .filter(|e| e.pinned)
+            .chain(&self.exchanges[first..]);
+        let history =
+            build_pinned_history(exchanges, &self.alias_table(), self.instruction_framing())?;
 
-                    None => None,
-                };
+        // Encoding every message is only worth it when the counts are logged.
+        if tracing::enabled!(tracing::Level::TRACE) {
+            for (i, (message, _)) in history.iter().enumerate() {
+                let tokens = message.count_tokens(ANSWER_MODEL)?;
+                trace!(i, tokens, %self.thread_id, "history message");
+            }
+        }
 
-                acc.extend(
-                    std::iter::once(query)
-                        .chain(vec![llm_gateway::api::Message::user(
-                            FUNCTION_CALL_INSTRUCTION,
-                        )])
-                        .chain(steps)
-                        .chain(answer.into_iter()),
-                );
-                Ok(acc)
-            })?;
         Ok(history)
     }
 
+    fn instruction_framing(&self) -> InstructionFraming {
+        if self.app.config.legacy_function_call_framing {
+            InstructionFraming::UserTurns
+        } else {
+            InstructionFraming::System
+        }
+    }
+
+    /// The whole thread, as a single JSONL line of OpenAI's fine-tuning format.
+    ///
+    /// Messages are framed as in `history`, after the system prompt. Every record has to end with
+    /// an answer, so this fails if any exchange of the thread is unanswered.
+    pub fn export_openai_format(&self) -> Result<String> {
+        if let Some(i) = self.exchanges.iter().position(|e| e.answer().is_none()) {
+            bail!("exchange {i} of the thread has no answer");
+        }
+
+        let framing = self.instruction_framing();
+        let mut messages = vec![llm_gateway::api::Message::system(&self.system_prompt()?)];
+        let mut history = build_history(&self.exchanges, &self.alias_table(), framing)?;
+
+        // The instruction that follows the history of a step goes with the system prompt instead,
+        // as records end with the last answer.
+        if framing == InstructionFraming::System {
+            messages.extend(history.pop());
+        }
+        messages.extend(history);
+
+        let record = serde_json::json!({ "messages": messages });
+        Ok(serde_json::to_string(&record)?)
+    }
+
     async fn semantic_search(
         &self,
         query: parser::Literal<'_>,
@@ -279,19 +1025,81 @@ impl Agent {
         threshold: f32,
         retrieve_more: bool,
     ) -> Result<Vec<semantic::Payload>> {
-        let query = parser::SemanticQuery {
-            target: Some(query),
-            repos: [parser::Literal::Plain(self.repo_ref.display_name().into())].into(),
-            ..self.last_exchange().query.clone()
-        };
+        let query = self.semantic_query(query);
+        self.run_semantic_query(query, limit, offset, threshold, retrieve_more)
+            .await
+    }
 
-        debug!(?query, %self.thread_id, "executing semantic query");
-        self.app
-            .semantic
-            .as_ref()
+    /// Like `semantic_search`, but only returning code written in `language`.
+    ///
+    /// The language can be a name or a file extension, as in the `lang:` filter of a query.
+    async fn semantic_search_by_language(
+        &self,
+        query: parser::Literal<'_>,
+        language: &str,
+        limit: u64,
+        offset: u64,
+        threshold: f32,
+        retrieve_more: bool,
+    ) -> Result<Vec<semantic::Payload>> {
+        let query = with_language(self.semantic_query(query), language);
+        self.run_semantic_query(query, limit, offset, threshold, retrieve_more)
+            .await
+    }
+
+    /// A semantic query for `target` in this repository, with the filters of the last exchange.
+    fn semantic_query<'a>(&self, target: parser::Literal<'a>) -> parser::SemanticQuery<'a> {
+        let mut query = parser::SemanticQuery {
+            target: Some(target),
+            repos: [parser::Literal::Plain(self.repo_ref.display_name().into())].into(),
+            ..self.last_exchange().query.clone()
+        };
+
+        if !self.repo_ref.has_branches() {
+            query.branch.clear();
+        }
+
+        query
+    }
+
+    async fn run_semantic_query(
+        &self,
+        query: parser::SemanticQuery<'_>,
+        limit: u64,
+        offset: u64,
+        threshold: f32,
+        retrieve_more: bool,
+    ) -> Result<Vec<semantic::Payload>> {
+        let text = query
+            .target
+            .clone()
+            .context("semantic query has no target")?
             .unwrap()
+            .into_owned();
+
+        let index = self.app.semantic.as_ref().unwrap();
+        let query_embedding = index.embed(&text)?;
+
+        debug!(?query, %self.thread_id, "executing semantic query");
+        index
             .search(&query, limit, offset, threshold, retrieve_more)
             .await
+            .map(|payloads| filter_oversized(payloads, self.config.max_file_size_bytes))
+            .map(|payloads| semantic::retain_phrases(&query, payloads))
+            .map(|payloads| index.hybrid_rerank(&text, query_embedding, payloads))
+            .map(|payloads| {
+                payloads
+                    .into_iter()
+                    .map(|mut payload| {
+                        if let Cow::Owned(text) =
+                            self.redact_secrets(Some(&payload.relative_path), &payload.text)
+                        {
+                            payload.text = text;
+                        }
+                        payload
+                    })
+                    .collect()
+            })
     }
 
     #[allow(dead_code)]
@@ -321,25 +1129,237 @@ impl Agent {
             .unwrap()
             .batch_search(queries.as_slice(), limit, offset, threshold, retrieve_more)
             .await
+            .map(|payloads| filter_oversized(payloads, self.config.max_file_size_bytes))
     }
 
+    /// The branch that searches are restricted to.
+    ///
+    /// Plain directories have no branches, so branches in the query are ignored for them.
+    fn branch(&self) -> Option<Cow<'_, str>> {
+        if !self.repo_ref.has_branches() {
+            return None;
+        }
+
+        self.last_exchange()
+            .query
+            .first_branch()
+            .or_else(|| self.config.default_branch.as_deref().map(Cow::Borrowed))
+    }
+
+    /// Read a file to send its content to the LLM, which spends the file budget.
+    ///
+    /// This fails with `FileBudgetExhausted` once the budget is spent, unless the file was already
+    /// sent for this query.
     async fn get_file_content(&self, path: &str) -> Result<Option<ContentDocument>> {
-        let branch = self.last_exchange().query.first_branch();
+        let doc = self.read_file(path).await?;
+        if doc.is_some() && !self.spend_file_budget(path) {
+            return Err(FileBudgetExhausted {
+                path: path.to_owned(),
+            }
+            .into());
+        }
+
+        Ok(doc)
+    }
+
+    /// Read a file from the index, without spending the file budget.
+    ///
+    /// Files larger than `max_file_size_bytes` are not read, which fails with `FileTooLarge`.
+    async fn read_file(&self, path: &str) -> Result<Option<ContentDocument>> {
+        let branch = self.branch();
 
         debug!(%self.repo_ref, path, ?branch, %self.thread_id, "executing file search");
-        self.app
+        let Some(mut doc) = self
+            .app
             .indexes
             .file
             .by_path(&self.repo_ref, path, branch.as_deref())
             .await
-            .with_context(|| format!("failed to read path: {}", path))
+            .with_context(|| format!("failed to read path: {}", path))?
+        else {
+            return Ok(None);
+        };
+
+        if doc.content.len() > self.config.max_file_size_bytes {
+            debug!(path, size = doc.content.len(), "skipping oversized file");
+            return Err(FileTooLarge {
+                path: path.to_owned(),
+                size: doc.content.len(),
+                limit: self.config.max_file_size_bytes,
+            }
+            .into());
+        }
+
+        // The pointer text would only tell the model that the file is empty.
+        if doc.lfs_pointer {
+            doc.content = lfs::not_fetched_message(path, &doc.content) + "\n";
+            doc.line_end_indices = doc
+                .content
+                .match_indices('\n')
+                .map(|(i, _)| i as u32)
+                .collect();
+        }
+
+        if let Cow::Owned(content) = self.redact_secrets(Some(path), &doc.content) {
+            doc.content = content;
+        }
+
+        Ok(Some(doc))
+    }
+
+    /// If the query contains a stack trace, add the files of its frames to the context.
+    ///
+    /// Frames that point outside of the repository, or that can't be found in the index, are
+    /// skipped.
+    async fn seed_stack_trace(&mut self, query: &str) -> Result<()> {
+        const MAX_FRAMES: usize = 10;
+
+        let Some(trace) = stack_trace::parse(query) else {
+            return Ok(());
+        };
+
+        let mut resolved = Vec::new();
+        for frame in trace.frames.iter().filter(|f| !f.external) {
+            if resolved.len() == MAX_FRAMES {
+                break;
+            }
+
+            if let Some(path) = self.resolve_frame(&frame.path).await? {
+                resolved.push((path, frame));
+            }
+        }
+
+        let stack_trace = resolved
+            .iter()
+            .map(|(path, frame)| {
+                let alias = self.get_path_alias(path);
+                let symbol = frame.symbol.as_deref().unwrap_or("-");
+                format!("{alias}, {path}:{}, {symbol}", frame.line)
+            })
+            .collect();
+        self.stack_trace = stack_trace;
+
+        self.track_query(
+            EventData::input_stage("stack trace")
+                .with_payload("language", trace.language)
+                .with_payload("frames", trace.frames.len())
+                .with_payload("resolved", &self.stack_trace),
+        );
+
+        Ok(())
+    }
+
+    /// If the query asks to explain a single function, add the function, its callees and its
+    /// callers to the context.
+    ///
+    /// This returns the aliases of the added paths, or `None` if the query is not about a single
+    /// function, or if the function can't be found.
+    async fn seed_call_graph(&mut self, query: &str) -> Result<Option<Vec<usize>>> {
+        let Some(target) = call_graph::parse(query) else {
+            return Ok(None);
+        };
+
+        let branch = self.branch();
+        let docs = self
+            .app
+            .indexes
+            .file
+            .all_files(&self.repo_ref, branch.as_deref())
+            .await?;
+
+        let limits = call_graph::Limits {
+            depth: self.app.config.call_graph_depth,
+            fan_out: self.app.config.call_graph_fan_out,
+        };
+
+        let Some(graph) = call_graph::CallGraph::build(&target, &docs, limits) else {
+            debug!(?target, %self.thread_id, "no definition found for explained function");
+            return Ok(None);
+        };
+
+        let mut aliases = vec![];
+        for function in graph.functions() {
+            let alias = self.get_path_alias(&function.path);
+            aliases.push(alias);
+
+            let exchange = self.last_exchange_mut();
+            exchange.include_context(
+                &function.path,
+                ContextSource::CallGraph,
+                &[function.start_line..function.end_line + 1],
+                true,
+            );
+            exchange.code_chunks.push(CodeChunk {
+                path: function.path.clone(),
+                alias,
+                snippet: function.snippet.clone(),
+                start_line: function.start_line,
+                end_line: function.end_line,
+                moved_to: None,
+                deleted: false,
+                symbol_path: None,
+                blob: None,
+                revision: None,
+                mapped: None,
+            });
+        }
+
+        aliases.sort();
+        aliases.dedup();
+
+        self.track_query(
+            EventData::input_stage("call graph")
+                .with_payload("symbol", &target.symbol)
+                .with_payload("callees", graph.callees.len())
+                .with_payload("callers", graph.callers.len())
+                .with_payload("unresolved", &graph.unresolved),
+        );
+
+        self.call_graph = Some(graph);
+        Ok(Some(aliases))
+    }
+
+    /// Find the indexed path of a normalized stack frame path, by exact path first and then by
+    /// file name.
+    async fn resolve_frame(&self, frame_path: &str) -> Result<Option<String>> {
+        let relative = frame_path.trim_start_matches('/');
+
+        let exists = match self.read_file(relative).await {
+            Ok(doc) => doc.is_some(),
+            // Files that are too large to read are still in the index.
+            Err(err) if err.is::<FileTooLarge>() => true,
+            Err(err) => return Err(err),
+        };
+        if exists {
+            return Ok(Some(relative.to_owned()));
+        }
+
+        let file_name = relative.rsplit('/').next().unwrap_or(relative);
+        let candidates = self
+            .fuzzy_path_search(file_name)
+            .await
+            .map(|doc| doc.relative_path)
+            .collect::<Vec<_>>();
+
+        Ok(
+            stack_trace::best_match(frame_path, candidates.iter().map(String::as_str))
+                .map(str::to_owned),
+        )
+    }
+
+    /// Find out whether a path was renamed or deleted since it was added to the context.
+    ///
+    /// This returns `None` if the path still exists in the index.
+    async fn relocate_path(&self, path: &str) -> Result<Option<Relocation>> {
+        let branch = self.branch();
+        relocation::relocate(&self.app, &self.repo_ref, path, branch.as_deref(), None).await
     }
 
     async fn fuzzy_path_search<'a>(
         &'a self,
         query: &str,
     ) -> impl Iterator<Item = FileDocument> + 'a {
-        let branch = self.last_exchange().query.first_branch();
+        let branch = self.branch();
 
         debug!(%self.repo_ref, query, ?branch, %self.thread_id, "executing fuzzy search");
         self.app
@@ -348,43 +1368,421 @@ impl Agent {
             .fuzzy_path_match(&self.repo_ref, query, branch.as_deref(), 50)
             .await
     }
+
+    async fn glob_path_search(&self, pattern: &str) -> Vec<String> {
+        const LIST_FILES_LIMIT: usize = 200;
+
+        let branch = self.branch();
+
+        debug!(%self.repo_ref, pattern, ?branch, %self.thread_id, "executing glob search");
+        self.app
+            .indexes
+            .file
+            .glob_path_match(&self.repo_ref, pattern, branch.as_deref(), LIST_FILES_LIMIT)
+            .await
+    }
+
+    /// Break down the files of this repository by language, sorted by line count descending.
+    pub async fn language_breakdown(&self) -> Result<Vec<LanguageStat>> {
+        let branch = self.branch();
+
+        debug!(%self.repo_ref, ?branch, %self.thread_id, "computing language breakdown");
+        let files = self
+            .app
+            .indexes
+            .file
+            .all_files(&self.repo_ref, branch.as_deref())
+            .await?;
+
+        Ok(language_stats(files.iter().map(|doc| {
+            (doc.lang.as_deref(), doc.content.lines().count())
+        })))
+    }
+}
+
+/// Drop semantic search results that come from files larger than `max_file_size_bytes`.
+///
+/// Payloads only carry their own chunk, so a chunk that is itself larger than the limit, or that
+/// ends past it, is taken as evidence of an oversized file.
+fn filter_oversized(
+    payloads: Vec<semantic::Payload>,
+    max_file_size_bytes: usize,
+) -> Vec<semantic::Payload> {
+    payloads
+        .into_iter()
+        .filter(|payload| {
+            let oversized = payload.text.len() > max_file_size_bytes
+                || payload.end_byte as usize > max_file_size_bytes;
+
+            if oversized {
+                debug!(path = payload.relative_path, "skipping oversized file");
+            }
+
+            !oversized
+        })
+        .collect()
+}
+
+/// Restrict a semantic query to code written in `language`, which can be a name or a file
+/// extension.
+fn with_language<'a>(
+    query: parser::SemanticQuery<'a>,
+    language: &str,
+) -> parser::SemanticQuery<'a> {
+    let language = languages::parse_alias(language.into()).into_owned();
+
+    parser::SemanticQuery {
+        langs: [language.into()].into(),
+        ..query
+    }
+}
+
+/// Aggregate `(language, line count)` pairs of files into per-language statistics.
+///
+/// Percentages are of the total line count, or of the file count if no file has any lines.
+fn language_stats<'a>(
+    files: impl IntoIterator<Item = (Option<&'a str>, usize)>,
+) -> Vec<LanguageStat> {
+    let mut by_language = HashMap::<&str, (usize, usize)>::new();
+
+    for (language, lines) in files {
+        let (file_count, line_count) = by_language.entry(language.unwrap_or("other")).or_default();
+        *file_count += 1;
+        *line_count += lines;
+    }
+
+    let total_files = by_language.values().map(|(files, _)| files).sum::<usize>();
+    let total_lines = by_language.values().map(|(_, lines)| lines).sum::<usize>();
+
+    let mut stats = by_language
+        .into_iter()
+        .map(|(language, (file_count, line_count))| {
+            let (part, total) = if total_lines > 0 {
+                (line_count, total_lines)
+            } else {
+                (file_count, total_files)
+            };
+
+            LanguageStat {
+                language: language.to_owned(),
+                file_count,
+                line_count,
+                percentage: 100.0 * part as f32 / total as f32,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    stats.sort_by(|a, b| {
+        b.line_count
+            .cmp(&a.line_count)
+            .then_with(|| b.file_count.cmp(&a.file_count))
+            .then_with(|| a.language.cmp(&b.language))
+    });
+
+    stats
+}
+
+/// Whether a repository has documentation, in a `docs` directory or a top-level README.
+fn has_docs(disk_path: &Path) -> bool {
+    ["docs", "doc"]
+        .iter()
+        .any(|dir| disk_path.join(dir).is_dir())
+        || std::fs::read_dir(disk_path).map_or(false, |entries| {
+            entries.flatten().any(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .to_lowercase()
+                    .starts_with("readme")
+            })
+        })
+}
+
+/// How the instruction to call a function is placed in the agent history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InstructionFraming {
+    /// Interleave the instruction as user messages, after each query and function return.
+    ///
+    /// The model occasionally parrots these fake user turns back in its answers.
+    UserTurns,
+    /// Append the instruction once, as a trailing system message.
+    System,
+}
+
+/// The alias table of a thread: every path that was given an alias, with the repository that it
+/// is in if that is known. A path's alias is its position in the table.
+///
+/// Paths of archived exchanges come first, and their repository is not known.
+fn alias_table<'a>(
+    archived_paths: &'a [String],
+    exchanges: &'a [Exchange],
+) -> Vec<(Option<&'a RepoRef>, &'a str)> {
+    archived_paths
+        .iter()
+        .map(|p| (None, p.as_str()))
+        .chain(
+            exchanges
+                .iter()
+                .flat_map(|e| e.paths.iter().map(|p| (e.repo_ref.as_ref(), p.as_str()))),
+        )
+        .collect()
+}
+
+fn build_history(
+    exchanges: &[Exchange],
+    aliases: &[(Option<&RepoRef>, &str)],
+    framing: InstructionFraming,
+) -> Result<Vec<llm_gateway::api::Message>> {
+    Ok(unpin(build_pinned_history(exchanges, aliases, framing)?))
+}
+
+/// As `build_history`, with whether each message belongs to a pinned exchange.
+///
+/// If the exchanges searched more than one repository, function returns start with the name of
+/// the repository they came from, so that the model doesn't mix up their results.
+fn build_pinned_history<'a>(
+    exchanges: impl IntoIterator<Item = &'a Exchange>,
+    aliases: &[(Option<&RepoRef>, &str)],
+    framing: InstructionFraming,
+) -> Result<Vec<(llm_gateway::api::Message, bool)>> {
+    // With the legacy framing, this yields the instruction as a user message.
+    let user_turn = || {
+        (framing == InstructionFraming::UserTurns)
+            .then(|| llm_gateway::api::Message::user(prompts::FUNCTION_CALL_INSTRUCTION))
+    };
+
+    let exchanges = exchanges.into_iter().collect::<Vec<_>>();
+    let repos = exchanges
+        .iter()
+        .filter_map(|e| e.repo_ref.as_ref())
+        .collect::<HashSet<_>>();
+    let label_repos = repos.len() > 1;
+
+    let mut history = exchanges
+        .into_iter()
+        .try_fold(Vec::new(), |mut acc, e| -> Result<_> {
+            // Queries without a target only changed the search filters, which is all that the
+            // model is told about them.
+            let query = match e.query() {
+                Some(q) => llm_gateway::api::Message::user(&q),
+                None => llm_gateway::api::Message::system(&prompts::filter_change_note(
+                    &e.query.filters(),
+                )),
+            };
+
+            let responses = e.step_responses();
+            let steps = responses.zip(&e.search_steps).flat_map(|(response, s)| {
+                let (name, arguments) = match s {
+                    SearchStep::Path { query, .. } => (
+                        "path".to_owned(),
+                        format!("{{\n \"query\": \"{query}\"\n}}"),
+                    ),
+                    SearchStep::Code { query, .. } => (
+                        "code".to_owned(),
+                        format!("{{\n \"query\": \"{query}\"\n}}"),
+                    ),
+                    SearchStep::ListFiles { pattern, .. } => (
+                        "list_files".to_owned(),
+                        format!("{{\n \"pattern\": \"{pattern}\"\n}}"),
+                    ),
+                    SearchStep::DependencyVulns { .. } => {
+                        ("dependency_vulns".to_owned(), "{}".to_owned())
+                    }
+                    SearchStep::DeadCode { .. } => ("dead_code".to_owned(), "{}".to_owned()),
+                    SearchStep::DeprecatedUsage { package, .. } => (
+                        "deprecated_usage".to_owned(),
+                        format!("{{\n \"package\": \"{package}\"\n}}"),
+                    ),
+                    SearchStep::UpgradeSuggestions { dep_name, .. } => (
+                        "upgrade_suggestions".to_owned(),
+                        format!("{{\n \"dep_name\": \"{dep_name}\"\n}}"),
+                    ),
+                    SearchStep::ConfigAudit { path, .. } => (
+                        "config_audit".to_owned(),
+                        format!("{{\n \"path\": \"{path}\"\n}}"),
+                    ),
+                    SearchStep::TODOs { path, .. } => {
+                        ("todos".to_owned(), format!("{{\n \"path\": \"{path}\"\n}}"))
+                    }
+                    SearchStep::Allocations { path, .. } => (
+                        "allocations".to_owned(),
+                        format!("{{\n \"path\": \"{path}\"\n}}"),
+                    ),
+                    SearchStep::FindSimilar { path, .. } => (
+                        "find_similar".to_owned(),
+                        format!("{{\n \"path\": \"{path}\"\n}}"),
+                    ),
+                    SearchStep::Symbol { query, .. } => (
+                        "symbol".to_owned(),
+                        format!("{{\n \"query\": \"{query}\"\n}}"),
+                    ),
+                    SearchStep::Changelog { since, .. } => (
+                        "changelog".to_owned(),
+                        match since {
+                            Some(since) => format!("{{\n \"since\": \"{since}\"\n}}"),
+                            None => "{}".to_owned(),
+                        },
+                    ),
+                    SearchStep::ChangelogDiff { v1, v2, .. } => (
+                        "changelog_diff".to_owned(),
+                        format!("{{\n \"v1\": \"{v1}\",\n \"v2\": \"{v2}\"\n}}"),
+                    ),
+                    SearchStep::WeeklyDigest { .. } => {
+                        ("weekly_digest".to_owned(), "{}".to_owned())
+                    }
+                    SearchStep::Plan { goal, .. } => {
+                        ("plan".to_owned(), format!("{{\n \"goal\": \"{goal}\"\n}}"))
+                    }
+                    SearchStep::Prs { query, .. } => {
+                        ("prs".to_owned(), format!("{{\n \"query\": \"{query}\"\n}}"))
+                    }
+                    SearchStep::Format { path, .. } => (
+                        "format".to_owned(),
+                        format!("{{\n \"path\": \"{path}\"\n}}"),
+                    ),
+                    SearchStep::RelatedFiles { paths, .. } => (
+                        "related_files".to_owned(),
+                        format!(
+                            "{{\n \"paths\": {}\n}}",
+                            serde_json::to_string(paths).unwrap()
+                        ),
+                    ),
+                    SearchStep::Proc { query, paths, .. } => (
+                        "proc".to_owned(),
+                        format!(
+                            "{{\n \"paths\": [{}],\n \"query\": \"{query}\"\n}}",
+                            paths
+                                .iter()
+                                .map(|path| aliases
+                                    .iter()
+                                    .position(|(repo, p)| {
+                                        *p == path.as_str()
+                                            && (repo.is_none()
+                                                || e.repo_ref.is_none()
+                                                || *repo == e.repo_ref.as_ref())
+                                    })
+                                    .unwrap()
+                                    .to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    ),
+                };
+
+                let response = match &e.repo_ref {
+                    Some(repo) if label_repos => {
+                        format!("[repository: {}]\n{response}", repo.display_name())
+                    }
+                    _ => response,
+                };
+
+                [
+                    llm_gateway::api::Message::function_call(&FunctionCall {
+                        name: Some(name.clone()),
+                        arguments,
+                    }),
+                    llm_gateway::api::Message::function_return(&name, &response),
+                ]
+                .into_iter()
+                .chain(user_turn())
+            });
+
+            let answer = match e.answer() {
+                // NB: We intentionally discard the summary as it is redundant.
+                Some((answer, _conclusion)) => {
+                    let encoded = transcoder::encode_summarized(answer, None, "gpt-3.5-turbo")?;
+                    Some(llm_gateway::api::Message::assistant(&encoded))
+                }
+
+                None => None,
+            };
+
+            acc.extend(
+                std::iter::once(query)
+                    .chain(user_turn())
+                    .chain(steps)
+                    .chain(answer.into_iter())
+                    .map(|m| (m, e.pinned)),
+            );
+            Ok(acc)
+        })?;
+
+    if framing == InstructionFraming::System {
+        history.push((
+            llm_gateway::api::Message::system(prompts::FUNCTION_CALL_INSTRUCTION),
+            false,
+        ));
+    }
+
+    Ok(history)
+}
+
+/// Drop the pinned flags of a history.
+fn unpin(history: Vec<(llm_gateway::api::Message, bool)>) -> Vec<llm_gateway::api::Message> {
+    history.into_iter().map(|(m, _)| m).collect()
 }
 
+/// Hide old assistant messages and function returns, until the history leaves `headroom` tokens
+/// and a further `safety_margin` of the context window free.
+///
+/// Messages that are flagged in `pinned` are never hidden. Messages past the end of `pinned` are
+/// not pinned.
 fn trim_history(
     mut history: Vec<llm_gateway::api::Message>,
+    pinned: &[bool],
+    tokenizer: &Tokenizer,
+    headroom: usize,
+    safety_margin: usize,
 ) -> Result<Vec<llm_gateway::api::Message>> {
-    const HEADROOM: usize = 2048;
     const HIDDEN: &str = "[HIDDEN]";
 
     let mut tiktoken_msgs = history.iter().map(|m| m.into()).collect::<Vec<_>>();
 
-    while tiktoken_rs::get_chat_completion_max_tokens(ANSWER_MODEL, &tiktoken_msgs)? < HEADROOM {
+    // The total is kept up to date as messages are hidden, rather than encoding the whole history
+    // again.
+    let context_size = tokenizer.context_size();
+    let hidden_tokens = tokenizer.count(HIDDEN);
+    let mut total = tokenizer.count_messages(&tiktoken_msgs);
+
+    while total + headroom + safety_margin > context_size {
         let _ = history
             .iter_mut()
             .zip(tiktoken_msgs.iter_mut())
-            .position(|(m, tm)| match m {
-                llm_gateway::api::Message::PlainText {
-                    role,
-                    ref mut content,
-                } => {
-                    if role == "assistant" && content != HIDDEN {
+            .enumerate()
+            .position(|(i, (m, tm))| {
+                if pinned.get(i).copied().unwrap_or_default() {
+                    return false;
+                }
+
+                let hidden = match m {
+                    llm_gateway::api::Message::PlainText {
+                        role,
+                        ref mut content,
+                    } => {
+                        if role == "assistant" && content != HIDDEN {
+                            *content = HIDDEN.into();
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    llm_gateway::api::Message::FunctionReturn {
+                        role: _,
+                        name: _,
+                        ref mut content,
+                    } if content != HIDDEN => {
                         *content = HIDDEN.into();
-                        tm.content = HIDDEN.into();
                         true
-                    } else {
-                        false
                     }
-                }
-                llm_gateway::api::Message::FunctionReturn {
-                    role: _,
-                    name: _,
-                    ref mut content,
-                } if content != HIDDEN => {
-                    *content = HIDDEN.into();
+                    _ => false,
+                };
+
+                if hidden {
+                    total = total - tokenizer.count(&tm.content) + hidden_tokens;
                     tm.content = HIDDEN.into();
-                    true
                 }
-                _ => false,
+
+                hidden
             })
             .ok_or_else(|| anyhow!("could not find message to trim"))?;
     }
@@ -392,7 +1790,67 @@ fn trim_history(
     Ok(history)
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// The sending half of the channel that an agent sends updated exchanges to.
+#[derive(Clone)]
+pub struct ExchangeTx {
+    tx: Sender<InstrumentedExchange>,
+    /// Whether exchanges are sent with the current span, as set by `AgentBuilder::with_span`.
+    with_span: bool,
+}
+
+impl ExchangeTx {
+    pub fn new(tx: Sender<InstrumentedExchange>, with_span: bool) -> Self {
+        Self { tx, with_span }
+    }
+
+    async fn send(&self, exchange: Exchange) -> Result<()> {
+        let span = if self.with_span {
+            tracing::Span::current()
+        } else {
+            tracing::Span::none()
+        };
+
+        self.tx
+            .send(InstrumentedExchange { exchange, span })
+            .await
+            .map_err(|_| anyhow!("exchange_tx was closed"))
+    }
+}
+
+/// Apply `update` to `exchange`, and send the updated exchange.
+async fn send_update(
+    exchange: &mut Exchange,
+    exchange_tx: &ExchangeTx,
+    update: Update,
+) -> Result<()> {
+    send_updates(exchange, exchange_tx, vec![update]).await
+}
+
+/// Apply a batch of updates, and send the resulting state once.
+async fn send_updates(
+    exchange: &mut Exchange,
+    exchange_tx: &ExchangeTx,
+    updates: Vec<Update>,
+) -> Result<()> {
+    exchange.apply_updates(updates);
+    exchange_tx.send(exchange.clone()).await
+}
+
+/// Pass `result` through, first sending an `Update::Error` if it is an error.
+async fn report_error<T>(
+    exchange: &mut Exchange,
+    exchange_tx: &ExchangeTx,
+    result: Result<T>,
+) -> Result<T> {
+    if let Err(err) = &result {
+        // The channel may be the reason that the step failed, so a failure to send is ignored.
+        let _ = send_update(exchange, exchange_tx, Update::Error(err.to_string())).await;
+    }
+
+    result
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     /// A user-provided query.
@@ -401,6 +1859,61 @@ pub enum Action {
     Path {
         query: String,
     },
+    #[serde(rename = "list_files")]
+    ListFiles {
+        pattern: String,
+    },
+    #[serde(rename = "dependency_vulns")]
+    DependencyVulns {},
+    #[serde(rename = "dead_code")]
+    DeadCode {},
+    #[serde(rename = "deprecated_usage")]
+    DeprecatedUsage {
+        package: String,
+    },
+    #[serde(rename = "weekly_digest")]
+    WeeklyDigest {},
+    #[serde(rename = "upgrade_suggestions")]
+    UpgradeSuggestions {
+        dep_name: String,
+    },
+    #[serde(rename = "related_files")]
+    RelatedFiles {
+        paths: Vec<String>,
+    },
+    #[serde(rename = "config_audit")]
+    ConfigAudit {
+        path: String,
+    },
+    #[serde(rename = "todos")]
+    TODOs {
+        path: String,
+    },
+    Allocations {
+        path: String,
+    },
+    #[serde(rename = "find_similar")]
+    FindSimilar {
+        path: String,
+    },
+    Symbol {
+        query: String,
+    },
+    Changelog {
+        #[serde(default)]
+        since: Option<String>,
+    },
+    #[serde(rename = "changelog_diff")]
+    ChangelogDiff {
+        v1: String,
+        v2: String,
+    },
+    Prs {
+        query: String,
+    },
+    Format {
+        path: String,
+    },
     #[serde(rename = "none")]
     Answer {
         paths: Vec<usize>,
@@ -412,6 +1925,14 @@ pub enum Action {
         query: String,
         paths: Vec<usize>,
     },
+    /// Break a complex task down into the function calls that are made next.
+    Plan {
+        goal: String,
+    },
+    /// Actions that are run one after another, without asking the model in between.
+    ///
+    /// This is never offered to the model, but follows a `Plan`.
+    Batch(Vec<Action>),
 }
 
 impl Action {
@@ -441,6 +1962,110 @@ impl Action {
 
         Ok(serde_json::from_value(serde_json::Value::Object(map))?)
     }
+
+    /// Serialize this action as the function call that picks it, the inverse of
+    /// `deserialize_gpt`.
+    fn to_gpt(&self) -> Option<FunctionCall> {
+        let serde_json::Value::Object(map) = serde_json::to_value(self).ok()? else {
+            return None;
+        };
+        let (name, arguments) = map.into_iter().next()?;
+
+        Some(FunctionCall {
+            name: Some(name),
+            arguments: arguments.to_string(),
+        })
+    }
+
+    /// Deserialize an action that the model picked from `functions`, rejecting calls to
+    /// functions that it wasn't offered.
+    fn deserialize_offered(
+        call: &FunctionCall,
+        functions: &[llm_gateway::api::Function],
+    ) -> Result<Self> {
+        let name = call.name.as_deref().unwrap_or_default();
+
+        if !functions.iter().any(|f| f.name == name) {
+            let offered = functions
+                .iter()
+                .map(|f| format!("`{}`", f.name))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            return Err(anyhow!(
+                "the `{name}` function is not available for this repository, call one of {offered} instead"
+            ));
+        }
+
+        Self::deserialize_gpt(call)
+    }
+
+    /// A key identifying a search tool call, by tool name and normalized arguments.
+    ///
+    /// Two calls with the same key are expected to return the same result within an exchange.
+    /// This returns `None` for actions that are not searches.
+    fn cache_key(&self) -> Option<(&'static str, String)> {
+        let normalize = |s: &str| {
+            s.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase()
+        };
+
+        match self {
+            // Plans are not reused, as the searches they lead to are cached themselves.
+            Action::Query(_) | Action::Answer { .. } | Action::Plan { .. } | Action::Batch(_) => {
+                None
+            }
+            Action::Path { query } => Some(("path", normalize(query))),
+            Action::Code { query } => Some(("code", normalize(query))),
+            Action::Prs { query } => Some(("prs", normalize(query))),
+            // Glob patterns are case sensitive.
+            Action::ListFiles { pattern } => Some(("list_files", pattern.trim().to_owned())),
+            Action::DependencyVulns {} => Some(("dependency_vulns", String::new())),
+            Action::DeadCode {} => Some(("dead_code", String::new())),
+            // Packages are looked up regardless of case.
+            Action::DeprecatedUsage { package } => {
+                Some(("deprecated_usage", package.trim().to_lowercase()))
+            }
+            Action::WeeklyDigest {} => Some(("weekly_digest", String::new())),
+            // Dependency names are matched exactly in manifests.
+            Action::UpgradeSuggestions { dep_name } => {
+                Some(("upgrade_suggestions", dep_name.trim().to_owned()))
+            }
+            // Paths are case sensitive.
+            Action::ConfigAudit { path } => Some(("config_audit", path.trim().to_owned())),
+            Action::Format { path } => Some(("format", path.trim().to_owned())),
+            Action::TODOs { path } => Some(("todos", path.trim().to_owned())),
+            Action::Allocations { path } => Some(("allocations", path.trim().to_owned())),
+            Action::FindSimilar { path } => Some(("find_similar", path.trim().to_owned())),
+            // Symbols are matched by subword, so a name in any naming convention has one result.
+            Action::Symbol { query } => Some(("symbol", tools::symbol::subwords(query).join(" "))),
+            // Revisions are case sensitive.
+            Action::Changelog { since } => Some((
+                "changelog",
+                since.as_deref().unwrap_or_default().trim().to_owned(),
+            )),
+            // Versions are named as they are in the changelog.
+            Action::ChangelogDiff { v1, v2 } => {
+                Some(("changelog_diff", format!("{}\n{}", v1.trim(), v2.trim())))
+            }
+            Action::RelatedFiles { paths } => {
+                let mut paths = paths.iter().map(|p| p.trim()).collect::<Vec<_>>();
+                paths.sort_unstable();
+                paths.dedup();
+
+                Some(("related_files", paths.join("\n")))
+            }
+            Action::Proc { query, paths } => {
+                let mut paths = paths.clone();
+                paths.sort_unstable();
+                paths.dedup();
+
+                Some(("proc", format!("{paths:?} {}", normalize(query))))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -449,8 +2074,508 @@ mod tests {
 
     use super::*;
 
-    #[test]
-    fn test_trimming_history() {
+    #[derive(serde::Deserialize)]
+    struct RecordedExchange {
+        query: String,
+        paths: Vec<String>,
+        steps: Vec<RecordedStep>,
+        answer: Option<String>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct RecordedStep {
+        call: FunctionCall,
+        response: String,
+    }
+
+    /// The search step that results from running `action`.
+    fn search_step(action: Action, response: String, all_paths: &[String]) -> SearchStep {
+        match action {
+            Action::Path { query } => SearchStep::Path { query, response },
+            Action::Code { query } => SearchStep::Code { query, response },
+            Action::ListFiles { pattern } => SearchStep::ListFiles {
+                pattern,
+                paths: response.lines().map(str::to_owned).collect(),
+            },
+            Action::Proc { query, paths } => SearchStep::Proc {
+                query,
+                paths: paths.iter().map(|i| all_paths[*i].clone()).collect(),
+                response,
+                lines_read: Vec::new(),
+                timed_out: Vec::new(),
+            },
+            action => panic!("unexpected action: {action:?}"),
+        }
+    }
+
+    /// Rebuild the exchanges of a recorded thread, from the function calls the model made.
+    fn replay(recorded: &[RecordedExchange]) -> Vec<Exchange> {
+        let mut exchanges = Vec::<Exchange>::new();
+
+        for r in recorded {
+            let query = parser::SemanticQuery {
+                target: Some(parser::Literal::Plain(r.query.clone().into())),
+                ..Default::default()
+            };
+
+            let mut exchange = Exchange::new(uuid::Uuid::nil(), query);
+            exchange.paths = r.paths.clone();
+            exchanges.push(exchange);
+
+            let all_paths = exchanges
+                .iter()
+                .flat_map(|e| e.paths.clone())
+                .collect::<Vec<_>>();
+            let exchange = exchanges.last_mut().unwrap();
+
+            for step in &r.steps {
+                let action = Action::deserialize_gpt(&step.call).unwrap();
+                let step = search_step(action, step.response.clone(), &all_paths);
+                exchange.apply_update(Update::StartStep(step));
+            }
+
+            if let Some(answer) = &r.answer {
+                exchange.apply_update(Update::Article(answer.clone()));
+                exchange.apply_update(Update::Conclude("Anything else?".into()));
+            }
+        }
+
+        exchanges
+    }
+
+    /// The actions that the model sees itself taking in a history, in order.
+    fn actions(history: &[llm_gateway::api::Message]) -> Vec<serde_json::Value> {
+        history
+            .iter()
+            .filter_map(|m| match m {
+                llm_gateway::api::Message::FunctionCall { function_call, .. } => Some(
+                    serde_json::to_value(Action::deserialize_gpt(function_call).unwrap()).unwrap(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn messages_from<'a>(history: &'a [llm_gateway::api::Message], from: &str) -> Vec<&'a str> {
+        history
+            .iter()
+            .filter_map(|m| match m {
+                llm_gateway::api::Message::PlainText { role, content } if role == from => {
+                    Some(content.as_str())
+                }
+                llm_gateway::api::Message::FunctionReturn { content, .. } if from == "function" => {
+                    Some(content.as_str())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The history of the next step of an agent that continues a recorded thread, as it is sent
+    /// to the model, with the function call instruction in user turns if `legacy` is set.
+    async fn recorded_step_history(
+        recorded: &[RecordedExchange],
+        legacy: bool,
+    ) -> Vec<llm_gateway::api::Message> {
+        let index_dir = tempdir::TempDir::new("test-instruction-framing").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+            "legacy_function_call_framing": legacy,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref =
+            RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+        let agent = builder::builder(app)
+            .repo(repo_ref)
+            .exchanges(replay(recorded))
+            .build()
+            .unwrap()
+            .into_agent();
+
+        let history = agent.step_history().unwrap();
+        agent.complete();
+        history
+    }
+
+    #[tokio::test]
+    async fn test_instruction_framing_preserves_actions() {
+        let recorded = serde_json::from_str::<Vec<RecordedExchange>>(include_str!(
+            "agent/fixtures/recorded_thread.json"
+        ))
+        .unwrap();
+
+        let recorded_actions = recorded
+            .iter()
+            .flat_map(|r| &r.steps)
+            .map(|s| serde_json::to_value(Action::deserialize_gpt(&s.call).unwrap()).unwrap())
+            .collect::<Vec<_>>();
+
+        let legacy = recorded_step_history(&recorded, true).await;
+        let system = recorded_step_history(&recorded, false).await;
+
+        assert_eq!(actions(&legacy), recorded_actions);
+        assert_eq!(actions(&system), recorded_actions);
+        assert_eq!(
+            messages_from(&legacy, "function"),
+            messages_from(&system, "function")
+        );
+        assert_eq!(
+            messages_from(&legacy, "assistant"),
+            messages_from(&system, "assistant")
+        );
+
+        // Both start with the same system prompt.
+        assert_eq!(legacy[0], system[0]);
+
+        // The legacy framing interleaves the instruction after each query and function return.
+        let instruction = prompts::FUNCTION_CALL_INSTRUCTION;
+        assert_eq!(
+            messages_from(&legacy, "user")
+                .into_iter()
+                .filter(|m| *m == instruction)
+                .count(),
+            recorded.len() + recorded_actions.len()
+        );
+        assert!(messages_from(&legacy[1..], "system").is_empty());
+
+        // The system framing only sends the user's own queries as user messages, and ends with
+        // the instruction as a system message.
+        assert_eq!(
+            messages_from(&system, "user"),
+            recorded
+                .iter()
+                .map(|r| r.query.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            serde_json::to_value(system.last().unwrap()).unwrap(),
+            serde_json::json!({ "role": "system", "content": instruction })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_tool_calls_are_cached() {
+        use crate::{
+            llm_gateway::mock::{call, Gateway, Reply, Script},
+            repo::Repository,
+        };
+
+        let index_dir = tempdir::TempDir::new("test-tool-cache").unwrap();
+        let repo_dir = tempdir::TempDir::new("test-tool-cache-repo").unwrap();
+        std::fs::write(
+            repo_dir.path().join("CHANGELOG.md"),
+            include_str!("agent/fixtures/CHANGELOG.md"),
+        )
+        .unwrap();
+
+        // A mocked model that repeats a changelog comparison, with slightly different formatting,
+        // and then asks for the same comparison again in a follow-up.
+        let diff = |v1, v2| call("changelog_diff", serde_json::json!({ "v1": v1, "v2": v2 }));
+        let none = call("none", serde_json::json!({ "paths": [] }));
+        let calls = Script::new(vec![
+            diff("0.4.0", "0.5.0"),
+            call("path", serde_json::json!({ "query": "auth" })),
+            diff(" 0.4.0 ", "0.5.0 "),
+            none.clone(),
+            diff("0.4.0", "0.5.0"),
+            none,
+        ]);
+        let gateway = Gateway::serve(move |request| {
+            if request.is_function_call() {
+                calls.next()
+            } else {
+                Reply::text("Sharing links were added in 0.4.0.")
+            }
+        });
+        let comparisons = || {
+            gateway
+                .requests_where(|request| {
+                    request.system().contains("in the changelog of a codebase")
+                })
+                .len()
+        };
+
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": gateway.url,
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        app.repo_pool
+            .insert(repo_ref.clone(), Repository::local_from(&repo_ref))
+            .unwrap();
+
+        let mut driver = builder::builder(app).repo(repo_ref).build().unwrap();
+        let exchange = driver
+            .run("What changed between 0.4.0 and 0.5.0?")
+            .await
+            .unwrap();
+
+        // The repeated comparison is not explained by the model again.
+        assert_eq!(comparisons(), 1);
+        assert_eq!(exchange.search_steps.len(), 3);
+        assert_eq!(exchange.cached_steps, [2]);
+
+        let first = exchange.search_steps[0].get_response();
+        let repeated = exchange.step_response(2).unwrap();
+        assert!(repeated.starts_with("This function was already called"));
+        assert!(repeated.ends_with(&first));
+
+        // Follow-up exchanges start with an empty cache.
+        let exchange = driver.run("And what about the fixes?").await.unwrap();
+        assert_eq!(comparisons(), 2);
+        assert!(exchange.cached_steps.is_empty());
+
+        driver.into_agent().complete();
+    }
+
+    #[test]
+    fn test_calls_to_hidden_functions_are_rejected() {
+        let functions =
+            serde_json::from_value::<Vec<llm_gateway::api::Function>>(prompts::functions(
+                false,
+                &prompts::Capabilities::default(),
+                exchange::QueryType::Other,
+            ))
+            .unwrap();
+
+        let call =
+            serde_json::from_str(r#"{"name": "path", "arguments": "{\"query\": \"auth\"}"}"#)
+                .unwrap();
+        assert!(matches!(
+            Action::deserialize_offered(&call, &functions).unwrap(),
+            Action::Path { .. }
+        ));
+
+        // Without semantic search, `code` is hidden.
+        let call =
+            serde_json::from_str(r#"{"name": "code", "arguments": "{\"query\": \"auth\"}"}"#)
+                .unwrap();
+        let err = Action::deserialize_offered(&call, &functions)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("the `code` function is not available"));
+        let (_, offered) = err.split_once("call one of").unwrap();
+        assert!(offered.contains("`path`"));
+        assert!(!offered.contains("`code`"));
+    }
+
+    #[test]
+    fn test_failed_tool_calls_are_not_cached() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), parser::SemanticQuery::default());
+        let action = Action::Code {
+            query: "bearer token".into(),
+        };
+        let key = action.cache_key().unwrap();
+
+        // A tool call that fails after starting its step never gets cached.
+        exchange.apply_update(Update::StartStep(search_step(action, String::new(), &[])));
+        assert!(exchange.cached_step(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_batched_updates_are_sent_once() {
+        let (tx, mut exchange_rx) = tokio::sync::mpsc::channel(200);
+        let exchange_tx = ExchangeTx::new(tx, false);
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), parser::SemanticQuery::default());
+
+        let updates = (0..100)
+            .map(|i| {
+                Update::StartStep(SearchStep::Path {
+                    query: format!("query {i}"),
+                    response: String::new(),
+                })
+            })
+            .collect();
+
+        send_updates(&mut exchange, &exchange_tx, updates)
+            .await
+            .unwrap();
+        drop(exchange_tx);
+
+        let sent = exchange_rx.recv().await.unwrap().exchange;
+        assert_eq!(sent.search_steps.len(), 100);
+        assert!(matches!(
+            sent.search_steps.last(),
+            Some(SearchStep::Path { query, .. }) if query == "query 99"
+        ));
+        assert!(exchange_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_step_error_is_sent() {
+        let (tx, mut exchange_rx) = tokio::sync::mpsc::channel(10);
+        let exchange_tx = ExchangeTx::new(tx, false);
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), parser::SemanticQuery::default());
+
+        let proc = SearchStep::Proc {
+            query: "where is the config parsed".into(),
+            paths: vec!["src/config.rs".into()],
+            response: String::new(),
+            lines_read: Vec::new(),
+            timed_out: Vec::new(),
+        };
+        send_update(&mut exchange, &exchange_tx, Update::StartStep(proc))
+            .await
+            .unwrap();
+
+        let failed = Err::<(), _>(anyhow!("did not find requested file"));
+        let result = report_error(&mut exchange, &exchange_tx, failed).await;
+        assert!(result.is_err());
+
+        let started = exchange_rx.recv().await.unwrap().exchange;
+        assert_eq!(started.error, None);
+
+        let errored = exchange_rx.recv().await.unwrap().exchange;
+        assert_eq!(
+            errored.error.as_deref(),
+            Some("did not find requested file")
+        );
+        assert!(matches!(
+            errored.search_steps[..],
+            [SearchStep::Proc { .. }]
+        ));
+
+        // Successful steps are passed through without an update.
+        let result = report_error(&mut exchange, &exchange_tx, Ok(1)).await;
+        assert_eq!(result.unwrap(), 1);
+        assert!(exchange_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_filter_oversized() {
+        let payload = |path: &str, size: usize| semantic::Payload {
+            relative_path: path.to_owned(),
+            text: "x".repeat(size),
+            end_byte: size as u64,
+            ..Default::default()
+        };
+
+        let payloads = vec![
+            payload("small.rs", 50 * 1024),
+            payload("large.js", 200 * 1024),
+        ];
+
+        assert_eq!(
+            filter_oversized(payloads, DEFAULT_MAX_FILE_SIZE_BYTES)
+                .into_iter()
+                .map(|p| p.relative_path)
+                .collect::<Vec<_>>(),
+            vec!["small.rs"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_semantic_query_by_language() {
+        use semantic::store::{Embedded, Point, VectorStore};
+
+        let dir = tempdir::TempDir::new("test-semantic-language").unwrap();
+        let store = Embedded::open(dir.path().join("vectors.bin")).unwrap();
+
+        let point = |id: &str, path: &str, lang: &str| Point {
+            id: id.to_owned(),
+            vector: vec![1.0, 0.0],
+            payload: semantic::Payload {
+                lang: lang.to_owned(),
+                repo_name: "github.com/bloopai/bloop".to_owned(),
+                relative_path: path.to_owned(),
+                ..Default::default()
+            },
+        };
+        store
+            .upsert(vec![
+                point("a", "server/bleep/src/agent.rs", "rust"),
+                point("b", "client/src/App.tsx", "tsx"),
+                point("c", "server/bleep/build.rs", "rust"),
+                point("d", "scripts/release.py", "python"),
+            ])
+            .await
+            .unwrap();
+
+        let query = parser::SemanticQuery {
+            target: Some(parser::Literal::Plain("build scripts".into())),
+            repos: [parser::Literal::Plain("bloopai/bloop".into())].into(),
+            langs: ["tsx".into()].into(),
+            ..Default::default()
+        };
+
+        async fn found(store: &Embedded, query: parser::SemanticQuery<'_>) -> Vec<String> {
+            let mut paths = store
+                .search(&query, vec![1.0, 0.0], 10, 0, 0.0)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|p| p.relative_path)
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        }
+
+        // The language replaces any languages that the query was already filtered by.
+        assert_eq!(
+            found(&store, with_language(query.clone(), "rs")).await,
+            ["server/bleep/build.rs", "server/bleep/src/agent.rs"]
+        );
+        assert_eq!(
+            found(&store, with_language(query, "Python")).await,
+            ["scripts/release.py"]
+        );
+    }
+
+    #[test]
+    fn test_language_stats() {
+        let files = [
+            (Some("rust"), 120),
+            (Some("typescript"), 30),
+            (Some("rust"), 80),
+            (None, 7),
+            (Some("sql"), 0),
+            (Some("typescript"), 33),
+        ];
+
+        let stats = language_stats(files);
+
+        assert_eq!(
+            stats
+                .iter()
+                .map(|s| (s.language.as_str(), s.file_count, s.line_count))
+                .collect::<Vec<_>>(),
+            vec![
+                ("rust", 2, 200),
+                ("typescript", 2, 63),
+                ("other", 1, 7),
+                ("sql", 1, 0)
+            ]
+        );
+
+        let total = stats.iter().map(|s| s.percentage).sum::<f32>();
+        assert!((total - 100.0).abs() < 1e-3, "{total}");
+        assert!((stats[0].percentage - 200.0 / 2.7).abs() < 1e-3);
+
+        // Without any lines, files are counted instead.
+        let total = language_stats([(Some("rust"), 0), (Some("sql"), 0), (Some("sql"), 0)])
+            .iter()
+            .map(|s| s.percentage)
+            .sum::<f32>();
+        assert!((total - 100.0).abs() < 1e-3, "{total}");
+
+        assert!(language_stats([]).is_empty());
+    }
+
+    #[test]
+    fn test_trimming_history() {
         let long_string = "long string ".repeat(2000);
         let history = vec![
             llm_gateway::api::Message::system("foo"),
@@ -464,8 +2589,16 @@ mod tests {
             llm_gateway::api::Message::user("corge"),
         ];
 
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
         assert_eq!(
-            trim_history(history).unwrap(),
+            trim_history(
+                history,
+                &[],
+                &tokenizer,
+                DEFAULT_HEADROOM_TOKENS,
+                tokens::DEFAULT_SAFETY_MARGIN
+            )
+            .unwrap(),
             vec![
                 llm_gateway::api::Message::system("foo"),
                 llm_gateway::api::Message::user("bar"),
@@ -479,4 +2612,499 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_trimming_cjk_history() {
+        let cjk = include_str!("agent/fixtures/cjk_thread.txt");
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
+        let history = vec![
+            llm_gateway::api::Message::system("foo"),
+            llm_gateway::api::Message::user("認証トークンはどこで検証されていますか？"),
+            llm_gateway::api::Message::function_return("code", &cjk.repeat(8)),
+            llm_gateway::api::Message::assistant(cjk),
+            llm_gateway::api::Message::user("索引是如何更新的？"),
+            llm_gateway::api::Message::function_return("proc", &cjk.repeat(8)),
+            llm_gateway::api::Message::user(cjk),
+        ];
+
+        // Estimating a token per four characters, the whole history would seem to fit.
+        let chars = history
+            .iter()
+            .map(|m| {
+                tiktoken_rs::ChatCompletionRequestMessage::from(m)
+                    .content
+                    .chars()
+                    .count()
+            })
+            .sum::<usize>();
+        assert!(
+            chars / 4 + DEFAULT_HEADROOM_TOKENS + tokens::DEFAULT_SAFETY_MARGIN
+                < tokenizer.context_size()
+        );
+
+        let trimmed = trim_history(
+            history.clone(),
+            &[],
+            &tokenizer,
+            DEFAULT_HEADROOM_TOKENS,
+            tokens::DEFAULT_SAFETY_MARGIN,
+        )
+        .unwrap();
+        let tiktoken_msgs = trimmed.iter().map(|m| m.into()).collect::<Vec<_>>();
+        assert!(
+            tokenizer.count_messages(&tiktoken_msgs)
+                + DEFAULT_HEADROOM_TOKENS
+                + tokens::DEFAULT_SAFETY_MARGIN
+                <= tokenizer.context_size()
+        );
+
+        assert_eq!(
+            trimmed[2],
+            llm_gateway::api::Message::function_return("code", "[HIDDEN]")
+        );
+        assert_eq!(trimmed.last(), history.last());
+    }
+
+    #[test]
+    fn test_trimming_history_by_headroom() {
+        let long_string = "long string ".repeat(500);
+        let mut history = vec![llm_gateway::api::Message::system("foo")];
+        // Eight answers of about 1000 tokens each, which are just too many for the context window.
+        for i in 0..8 {
+            history.push(llm_gateway::api::Message::user(&format!("question {i}")));
+            history.push(llm_gateway::api::Message::assistant(&long_string));
+        }
+        history.push(llm_gateway::api::Message::user("corge"));
+
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
+        let hidden = |headroom| {
+            trim_history(
+                history.clone(),
+                &[],
+                &tokenizer,
+                headroom,
+                tokens::DEFAULT_SAFETY_MARGIN,
+            )
+            .unwrap()
+            .into_iter()
+            .filter(|m| *m == llm_gateway::api::Message::assistant("[HIDDEN]"))
+            .count()
+        };
+
+        assert!(hidden(512) > 0);
+        assert!(hidden(4096) > hidden(512));
+    }
+
+    #[test]
+    fn test_trimming_history_keeps_pinned_exchanges() {
+        let long_answer = "long answer ".repeat(1200);
+        let mut exchanges = (0..4)
+            .map(|i| {
+                let query = parser::SemanticQuery {
+                    target: Some(parser::Literal::Plain(format!("question {i}").into())),
+                    ..Default::default()
+                };
+                let mut exchange = Exchange::new(uuid::Uuid::new_v4(), query);
+                exchange.apply_update(Update::Article(long_answer.clone()));
+                exchange.apply_update(Update::Conclude("Anything else?".into()));
+                exchange
+            })
+            .collect::<Vec<_>>();
+        exchanges[1].mark_as_pinned();
+
+        let (history, pinned): (Vec<_>, Vec<_>) =
+            build_pinned_history(&exchanges, &[], InstructionFraming::System)
+                .unwrap()
+                .into_iter()
+                .unzip();
+        assert_eq!(pinned.iter().filter(|p| **p).count(), 2);
+
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
+        let trimmed = trim_history(
+            history.clone(),
+            &pinned,
+            &tokenizer,
+            DEFAULT_HEADROOM_TOKENS,
+            tokens::DEFAULT_SAFETY_MARGIN,
+        )
+        .unwrap();
+
+        let hidden = llm_gateway::api::Message::assistant("[HIDDEN]");
+        assert!(trimmed.contains(&hidden));
+        for (i, is_pinned) in pinned.into_iter().enumerate() {
+            if is_pinned {
+                assert_eq!(trimmed[i], history[i]);
+            }
+        }
+
+        // Without the pin, the answer of the second exchange would have been hidden too.
+        let unpinned = trim_history(
+            history.clone(),
+            &[],
+            &tokenizer,
+            DEFAULT_HEADROOM_TOKENS,
+            tokens::DEFAULT_SAFETY_MARGIN,
+        )
+        .unwrap();
+        assert_eq!(unpinned[3], hidden);
+    }
+
+    #[tokio::test]
+    async fn test_knowledge_base_in_history() {
+        let index_dir = tempdir::TempDir::new("test-knowledge-base").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref =
+            RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+        let query = parser::parse_nl("How does billing retry charges?")
+            .unwrap()
+            .into_semantic()
+            .unwrap()
+            .into_owned();
+        let mut agent = builder::builder(app)
+            .repo(repo_ref)
+            .exchanges(vec![Exchange::new(uuid::Uuid::new_v4(), query)])
+            .build()
+            .unwrap()
+            .into_agent();
+
+        agent.add_knowledge_base_document(
+            "Billing",
+            "The billing service retries failed charges three times.",
+        );
+        agent.add_knowledge_base_document(
+            "Deployments",
+            "Services are deployed with the internal release tool.",
+        );
+
+        let history = agent.step_history().unwrap();
+        let llm_gateway::api::Message::PlainText { content, .. } = &history[0] else {
+            panic!("history did not start with the system prompt");
+        };
+        assert!(content.contains(
+            "Additional context: Billing\nThe billing service retries failed charges three times.\n"
+        ));
+        assert!(!content.contains("Deployments"));
+
+        agent.complete();
+    }
+
+    #[tokio::test]
+    async fn test_queries_without_target() {
+        let index_dir = tempdir::TempDir::new("test-queries-without-target").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref =
+            RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+        let parse = |q: &str| {
+            parser::parse_nl(q)
+                .unwrap()
+                .into_semantic()
+                .unwrap()
+                .with_raw_target(q)
+                .into_owned()
+        };
+
+        // An exchange stored before filter-only queries fell back to their input as the target.
+        let mut stored = Exchange::new(
+            uuid::Uuid::new_v4(),
+            parser::parse_nl("lang:rust repo:foo")
+                .unwrap()
+                .into_semantic()
+                .unwrap()
+                .into_owned(),
+        );
+        stored.apply_update(Update::Article("Sure.".into()));
+        stored.apply_update(Update::Conclude("Anything else?".into()));
+
+        for (input, target) in [
+            ("lang:rust repo:foo", Some("lang:rust repo:foo")),
+            ("🦀", Some("🦀")),
+            ("   ", None),
+        ] {
+            let query = parse(input);
+            assert_eq!(query.target().as_deref(), target, "{input:?}");
+
+            let mut agent = builder::builder(app.clone())
+                .repo(repo_ref.clone())
+                .exchanges(vec![
+                    stored.clone(),
+                    Exchange::new(uuid::Uuid::new_v4(), query.clone()),
+                ])
+                .build()
+                .unwrap()
+                .into_agent();
+
+            let history = agent.history().unwrap();
+            assert_eq!(
+                history[0],
+                llm_gateway::api::Message::system(
+                    "The user changed the search filters to: lang:rust repo:foo"
+                )
+            );
+            let last_query = match target {
+                Some(target) => llm_gateway::api::Message::user(target),
+                None => llm_gateway::api::Message::system("The user sent an empty query."),
+            };
+            assert!(history.contains(&last_query), "{input:?}");
+
+            // Semantic searches keep the filters of the query, with the target they are given.
+            let semantic = agent.semantic_query(parser::Literal::Plain("retries".into()));
+            assert_eq!(semantic.target().as_deref(), Some("retries"));
+            assert_eq!(semantic.langs, query.langs);
+
+            agent.complete = true;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_thread_across_repositories() {
+        let index_dir = tempdir::TempDir::new("test-thread-across-repos").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let [client, server] = ["client", "server"].map(|name| {
+            let dir = index_dir.path().join(name);
+            RepoRef::new(Backend::LocalDir, &dir.to_string_lossy()).unwrap()
+        });
+        let parse = |q: &str| {
+            parser::parse_nl(q)
+                .unwrap()
+                .into_semantic()
+                .unwrap()
+                .into_owned()
+        };
+        let path_step = |response: &str| SearchStep::Path {
+            query: "config".into(),
+            response: response.into(),
+        };
+
+        // The first exchange of the thread searched the client.
+        let mut agent = builder::builder(app.clone())
+            .repo(client.clone())
+            .exchanges(vec![Exchange::new(
+                uuid::Uuid::new_v4(),
+                parse("Where is the config loaded?"),
+            )])
+            .build()
+            .unwrap()
+            .into_agent();
+        assert_eq!(agent.get_path_alias("src/config.rs"), 0);
+        agent
+            .last_exchange_mut()
+            .apply_update(Update::StartStep(path_step("0, src/config.rs")));
+        agent
+            .last_exchange_mut()
+            .apply_update(Update::Article("In `src/config.rs`.".into()));
+        agent
+            .last_exchange_mut()
+            .apply_update(Update::Conclude("Anything else?".into()));
+        let first = agent.exchanges.pop().unwrap();
+        agent.complete = true;
+
+        // The follow-up switches to the server, keeping the history of the thread.
+        let mut agent = builder::builder(app)
+            .repo(server.clone())
+            .exchanges(vec![
+                first,
+                Exchange::new(uuid::Uuid::new_v4(), parse("And on the server?")),
+            ])
+            .build()
+            .unwrap()
+            .into_agent();
+        assert_eq!(agent.exchanges[0].repo_ref.as_ref(), Some(&client));
+        assert_eq!(agent.exchanges[1].repo_ref.as_ref(), Some(&server));
+
+        // The same path in the server gets a new alias, and the client's can't be read.
+        assert_eq!(agent.get_path_alias("src/config.rs"), 1);
+        assert_eq!(agent.get_path_alias("src/main.rs"), 2);
+        assert_eq!(agent.get_path_alias("src/config.rs"), 1);
+        assert_eq!(agent.repo_aliases(), [1, 2]);
+        assert_eq!(
+            agent.alias_labels(),
+            [
+                format!("src/config.rs (in {})", client.display_name()),
+                "src/config.rs".to_owned(),
+                "src/main.rs".to_owned(),
+            ]
+        );
+
+        // Searches are scoped to the server.
+        let semantic = agent.semantic_query(parser::Literal::Plain("config".into()));
+        assert_eq!(
+            semantic.repos().collect::<Vec<_>>(),
+            [server.display_name()]
+        );
+
+        // Function results are labeled with the repository they came from.
+        agent
+            .last_exchange_mut()
+            .apply_update(Update::StartStep(path_step("1, src/config.rs")));
+        let history = agent.history().unwrap();
+        assert_eq!(
+            messages_from(&history, "function"),
+            [
+                format!("[repository: {}]\n0, src/config.rs", client.display_name()),
+                format!("[repository: {}]\n1, src/config.rs", server.display_name()),
+            ]
+        );
+        assert!(history.contains(&llm_gateway::api::Message::user(
+            "Where is the config loaded?"
+        )));
+
+        // Each exchange records its repository when it is serialized.
+        let serialized = serde_json::to_value(&agent.exchanges).unwrap();
+        assert_eq!(serialized[0]["repo_ref"], client.to_string());
+        assert_eq!(serialized[1]["repo_ref"], server.to_string());
+
+        agent.complete = true;
+    }
+
+    #[tokio::test]
+    async fn test_session_id_in_events() {
+        let index_dir = tempdir::TempDir::new("test-session-id").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref =
+            RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+        let agent = |query: &str| {
+            let query = parser::parse_nl(query)
+                .unwrap()
+                .into_semantic()
+                .unwrap()
+                .into_owned();
+
+            builder::builder(app.clone())
+                .repo(repo_ref.clone())
+                .session_id("session-1".to_owned())
+                .exchanges(vec![Exchange::new(uuid::Uuid::new_v4(), query)])
+                .build()
+                .unwrap()
+                .into_agent()
+        };
+
+        let first = agent("How does billing retry charges?");
+        let second = agent("Where are invoices rendered?");
+
+        let a = first.query_event(EventData::input_stage("query"));
+        let b = second.query_event(EventData::input_stage("query"));
+        assert_ne!(a.thread_id, b.thread_id);
+        assert_eq!(a.session_id.as_deref(), Some("session-1"));
+        assert_eq!(a.session_id, b.session_id);
+
+        first.complete();
+        second.complete();
+    }
+
+    #[test]
+    fn test_drop_outside_runtime() {
+        let index_dir = tempdir::TempDir::new("test-drop-outside-runtime").unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let agent = runtime.block_on(async {
+            let app = crate::webserver::tests::app(&index_dir, serde_json::json!({})).await;
+            let repo_ref =
+                RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+            let query = parser::parse_nl("How are charges retried?")
+                .unwrap()
+                .into_semantic()
+                .unwrap()
+                .into_owned();
+
+            builder::builder(app)
+                .repo(repo_ref)
+                .exchanges(vec![Exchange::new(uuid::Uuid::new_v4(), query)])
+                .build()
+                .unwrap()
+                .into_agent()
+        });
+        drop(runtime);
+
+        // The query never completed, so dropping the agent tries to mark it as cancelled.
+        drop(agent);
+    }
+
+    #[tokio::test]
+    async fn test_export_openai_format() {
+        let index_dir = tempdir::TempDir::new("test-export-openai-format").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref =
+            RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+        let query = parser::parse_nl("How does billing retry charges?")
+            .unwrap()
+            .into_semantic()
+            .unwrap()
+            .into_owned();
+        let mut agent = builder::builder(app)
+            .repo(repo_ref)
+            .exchanges(vec![Exchange::new(uuid::Uuid::new_v4(), query)])
+            .build()
+            .unwrap()
+            .into_agent();
+
+        assert!(agent.export_openai_format().is_err());
+
+        let exchange = agent.last_exchange_mut();
+        exchange.apply_update(Update::Article(
+            "Failed charges are retried three times.".into(),
+        ));
+        exchange.apply_update(Update::Conclude("Anything else?".into()));
+
+        let export = agent.export_openai_format().unwrap();
+        assert_eq!(export.lines().count(), 1);
+
+        let record = serde_json::from_str::<serde_json::Value>(&export).unwrap();
+        let messages = record["messages"].as_array().unwrap();
+        let roles = messages
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(roles.first(), Some(&"system"));
+        assert_eq!(roles.last(), Some(&"assistant"));
+        assert!(roles.contains(&"user"));
+        assert!(messages.iter().all(|m| m.get("content").is_some()));
+
+        agent.complete();
+    }
 }
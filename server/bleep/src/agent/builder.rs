@@ -0,0 +1,841 @@
+//! Construction of an `Agent`, and the loop that drives it through the steps of a query.
+//!
+//! The webserver and the editor integration both build their agents here, as can services that
+//! embed bleep without running its webserver.
+
+use std::{pin::pin, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{
+    future::{Either, FutureExt},
+    stream, Stream, StreamExt,
+};
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::Instrument;
+
+use crate::{
+    agent::{
+        deadline,
+        exchange::{Exchange, InstrumentedExchange},
+        flush, quick, Action, Agent, AgentConfig, Error, ExchangeTx, OutputFormat,
+    },
+    llm_gateway,
+    query::parser,
+    repo::RepoRef,
+    webserver::middleware::User,
+    Application,
+};
+
+/// How long a step can go without an update, before the query is abandoned.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Start building an agent that answers queries with `app`.
+pub fn builder(app: Application) -> AgentBuilder {
+    AgentBuilder::new(app)
+}
+
+/// The options of an agent, which `build` creates a `Driver` with.
+pub struct AgentBuilder {
+    app: Application,
+    repo_ref: Option<RepoRef>,
+    user: User,
+    thread_id: uuid::Uuid,
+    query_id: uuid::Uuid,
+    session_id: Option<String>,
+    exchanges: Vec<Exchange>,
+    archived_paths: Vec<String>,
+    llm_gateway: Option<llm_gateway::Client>,
+    model: Option<String>,
+    tool_examples: Vec<String>,
+    flush: flush::Flush,
+    language_hint: Option<String>,
+    structured_proc_output: bool,
+    mode: quick::Mode,
+    output_format: OutputFormat,
+    timeout: Duration,
+    max_steps: Option<usize>,
+    canary: bool,
+    on_update: Option<Sender<Exchange>>,
+    with_span: bool,
+}
+
+impl AgentBuilder {
+    pub fn new(app: Application) -> Self {
+        Self {
+            app,
+            repo_ref: None,
+            user: User::Unknown,
+            thread_id: uuid::Uuid::new_v4(),
+            query_id: uuid::Uuid::new_v4(),
+            session_id: None,
+            exchanges: Vec::new(),
+            archived_paths: Vec::new(),
+            llm_gateway: None,
+            model: None,
+            tool_examples: Vec::new(),
+            flush: Default::default(),
+            language_hint: None,
+            structured_proc_output: false,
+            mode: quick::Mode::Normal,
+            output_format: OutputFormat::default(),
+            timeout: DEFAULT_TIMEOUT,
+            max_steps: None,
+            canary: false,
+            on_update: None,
+            with_span: false,
+        }
+    }
+
+    /// The repository that queries are answered from. This is required.
+    pub fn repo(mut self, repo_ref: RepoRef) -> Self {
+        self.repo_ref = Some(repo_ref);
+        self
+    }
+
+    pub fn user(mut self, user: User) -> Self {
+        self.user = user;
+        self
+    }
+
+    /// The model that picks the tools to call, instead of the gateway's default.
+    pub fn model(mut self, model: &str) -> Self {
+        self.model = Some(model.to_owned());
+        self
+    }
+
+    /// Send every update to an exchange to `tx`, as `Driver::run` and `Driver::run_action` go.
+    pub fn on_update(mut self, tx: Sender<Exchange>) -> Self {
+        self.on_update = Some(tx);
+        self
+    }
+
+    /// Send each update with the span of the step that made it, for `Driver::drive_instrumented`
+    /// to pass on. Updates carry a disabled span otherwise.
+    pub fn with_span(mut self, with_span: bool) -> Self {
+        self.with_span = with_span;
+        self
+    }
+
+    pub fn thread_id(mut self, thread_id: uuid::Uuid) -> Self {
+        self.thread_id = thread_id;
+        self
+    }
+
+    /// The id of the query that the last of `exchanges` is for.
+    pub fn query_id(mut self, query_id: uuid::Uuid) -> Self {
+        self.query_id = query_id;
+        self
+    }
+
+    /// The browser session of the user, which is sent with every analytics event.
+    pub fn session_id(mut self, session_id: impl Into<Option<String>>) -> Self {
+        self.session_id = session_id.into();
+        self
+    }
+
+    /// The exchanges of the thread so far, continued by the agent.
+    pub fn exchanges(mut self, exchanges: Vec<Exchange>) -> Self {
+        self.exchanges = exchanges;
+        self
+    }
+
+    /// The paths of exchanges that were archived from the thread, and are not in `exchanges`.
+    pub fn archived_paths(mut self, archived_paths: Vec<String>) -> Self {
+        self.archived_paths = archived_paths;
+        self
+    }
+
+    /// The client that the agent talks to the LLM with.
+    ///
+    /// By default, this is a client of `answer_api_url` that uses the app's endpoints.
+    pub fn llm_gateway(mut self, llm_gateway: llm_gateway::Client) -> Self {
+        self.llm_gateway = Some(llm_gateway);
+        self
+    }
+
+    /// Few-shot examples of tool calls, as loaded with `few_shot::load`.
+    pub fn tool_examples(mut self, tool_examples: Vec<String>) -> Self {
+        self.tool_examples = tool_examples;
+        self
+    }
+
+    pub fn flush(mut self, flush: flush::Flush) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Restrict code searches to a language, as with `Agent::set_language_hint`.
+    pub fn language_hint(mut self, language: impl Into<Option<String>>) -> Self {
+        self.language_hint = language.into();
+        self
+    }
+
+    /// Read files with `prompts::proc_json`, as with `Agent::use_structured_proc_output`.
+    pub fn structured_proc_output(mut self, structured: bool) -> Self {
+        self.structured_proc_output = structured;
+        self
+    }
+
+    /// Whether queries are answered in full, or quickly from search snippets.
+    pub fn mode(mut self, mode: quick::Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// How answers are written, as with `Agent::with_output_format`.
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// How long a step can go without an update. This is `DEFAULT_TIMEOUT` by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Answer each query after at most `max_steps` tool calls. Queries are not limited by default.
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Run queries as canaries, which are left out of analytics and usage records.
+    ///
+    /// Their usage can be read from `Agent::canary_usage` instead.
+    pub fn canary(mut self, canary: bool) -> Self {
+        self.canary = canary;
+        self
+    }
+
+    pub fn build(self) -> Result<Driver> {
+        let repo_ref = self.repo_ref.context("an agent needs a repository")?;
+
+        // Repositories that the user can't query are reported like the ones that don't exist.
+        if !self.app.access.allows(&self.user, &repo_ref) {
+            bail!("repository was not found");
+        }
+
+        let llm_gateway = self.llm_gateway.unwrap_or_else(|| {
+            llm_gateway::Client::new(&self.app.config.answer_api_url)
+                .temperature(0.0)
+                .endpoints(self.app.llm_endpoints.clone())
+                .session_reference_id(self.thread_id.to_string())
+        });
+        let llm_gateway = match &self.model {
+            Some(model) => llm_gateway.model(model),
+            None => llm_gateway,
+        };
+
+        let threshold = self.app.config.index_warning_threshold;
+        let (default_branch, index_warnings) = self
+            .app
+            .repo_pool
+            .read(&repo_ref, |_, repo| {
+                (
+                    repo.branch_settings.default_query_branch.clone(),
+                    repo.index_diagnostics.warnings(threshold),
+                )
+            })
+            .unwrap_or_default();
+
+        // The last exchange is the one being answered, which searches this repository whatever
+        // the earlier exchanges of the thread searched.
+        let mut exchanges = self.exchanges;
+        if let Some(exchange) = exchanges.last_mut() {
+            exchange.repo_ref = Some(repo_ref.clone());
+        }
+
+        let (exchange_tx, exchange_rx) = mpsc::channel(10);
+
+        let mut agent = Agent {
+            app: self.app,
+            repo_ref,
+            exchanges,
+            exchange_tx: ExchangeTx::new(exchange_tx, self.with_span),
+            archived_paths: self.archived_paths,
+            llm_gateway,
+            user: self.user,
+            thread_id: self.thread_id,
+            query_id: self.query_id,
+            session_id: self.session_id,
+            config: AgentConfig {
+                search_examples: None,
+                tool_examples: self.tool_examples,
+                default_branch,
+                max_file_size_bytes: super::DEFAULT_MAX_FILE_SIZE_BYTES,
+                headroom_tokens: super::DEFAULT_HEADROOM_TOKENS,
+                language_hint: self.language_hint,
+                flush: self.flush,
+                max_response_tokens: None,
+                use_structured_proc_output: self.structured_proc_output,
+                mode: self.mode,
+                output_format: self.output_format,
+                tool_timeout: deadline::tool_timeout(self.timeout),
+                max_steps: self.max_steps,
+            },
+            stack_trace: Vec::new(),
+            external_context: Vec::new(),
+            call_graph: None,
+            thread_title: None,
+            index_warnings,
+            pending_redactions: Default::default(),
+            tokenization: Default::default(),
+            capabilities: Default::default(),
+            file_budget: Default::default(),
+            knowledge_base: Vec::new(),
+            loop_guard: Default::default(),
+            cancellation: Default::default(),
+            canary_usage: self.canary.then(Default::default),
+            complete: false,
+        };
+
+        for doc in agent.app.knowledge_base.clone().iter() {
+            agent.add_knowledge_base_document(&doc.title, &doc.content);
+        }
+        if let Some(n) = agent.app.config.max_response_tokens {
+            agent.set_max_tokens(n);
+        }
+
+        Ok(Driver {
+            agent,
+            updates: ReceiverStream::new(exchange_rx),
+            timeout: self.timeout,
+            on_update: self.on_update,
+        })
+    }
+}
+
+/// An agent, alongside the updates it sends while it runs.
+pub struct Driver {
+    agent: Agent,
+    updates: ReceiverStream<InstrumentedExchange>,
+    timeout: Duration,
+    on_update: Option<Sender<Exchange>>,
+}
+
+impl Driver {
+    /// Run `action`, and every action that follows it, streaming each update to the last exchange.
+    ///
+    /// If a step fails or times out, the stream ends with the error, after the updates that were
+    /// sent before it.
+    pub fn drive(&mut self, action: Action) -> impl Stream<Item = Result<Exchange, Error>> + '_ {
+        self.drive_instrumented(action)
+            .map(|update| update.map(|update| update.exchange))
+    }
+
+    /// Like `drive`, with the span that each update was sent from, if the agent was built with
+    /// `AgentBuilder::with_span`.
+    pub fn drive_instrumented(
+        &mut self,
+        mut action: Action,
+    ) -> impl Stream<Item = Result<InstrumentedExchange, Error>> + '_ {
+        // Log events of the agent, and of the tasks it spawns, carry the ids of this request.
+        let span = self.agent.request_context().span();
+        let timeout = self.timeout;
+
+        async_stream::stream! {
+            let result = 'outer: loop {
+                // Here, we create two streams that operate simultaneously; the update stream,
+                // which sends updates to the exchange as they happen, and the action stream, which
+                // returns a single item when there is a new action available to execute. Both of
+                // these operate together, and we repeat the process for every new action.
+                let left_stream = (&mut self.updates).map(Either::Left);
+                let right_stream = self
+                    .agent
+                    .step(action)
+                    .instrument(span.clone())
+                    .into_stream()
+                    .map(Either::Right);
+
+                let mut next = None;
+                for await item in tokio_stream::StreamExt::timeout(
+                    stream::select(left_stream, right_stream),
+                    timeout,
+                ) {
+                    match item {
+                        Ok(Either::Left(exchange)) => yield Ok(exchange),
+                        Ok(Either::Right(next_action)) => match next_action {
+                            Ok(n) => break next = n,
+                            Err(e) => break 'outer Err(Error::Processing(e)),
+                        },
+                        Err(_) => break 'outer Err(Error::Timeout(timeout)),
+                    }
+                }
+
+                // NB: Sending updates after all other `await` points in the final `step` call will
+                // likely not return a pending future due to the internal receiver queue. So, the
+                // call stack usually continues onwards, ultimately resulting in a `Poll::Ready`,
+                // backing out of the above loop without ever processing the final message. Here,
+                // we empty the queue.
+                while let Some(Some(exchange)) = self.updates.next().now_or_never() {
+                    yield Ok(exchange);
+                }
+
+                match next {
+                    Some(a) => action = a,
+                    None => break Ok(()),
+                }
+            };
+
+            // A failed step sends the error it failed with, which is forwarded before the stream
+            // ends.
+            if let Err(err) = result {
+                while let Some(Some(exchange)) = self.updates.next().now_or_never() {
+                    yield Ok(exchange);
+                }
+
+                yield Err(err);
+            }
+        }
+    }
+
+    /// Run `action` to completion, returning the exchange it was run for.
+    ///
+    /// Updates are sent to the channel given to `AgentBuilder::on_update`, if there is one.
+    pub async fn run_action(&mut self, action: Action) -> Result<Exchange> {
+        let on_update = self.on_update.clone();
+
+        {
+            let mut updates = pin!(self.drive(action));
+            while let Some(update) = updates.next().await {
+                match update {
+                    Ok(exchange) => {
+                        if let Some(tx) = &on_update {
+                            tx.send(exchange)
+                                .await
+                                .map_err(|_| anyhow!("update receiver was dropped"))?;
+                        }
+                    }
+                    Err(Error::Timeout(duration)) => bail!("reached timeout of {duration:?}"),
+                    Err(Error::Processing(err)) => return Err(err),
+                }
+            }
+        }
+
+        self.agent.complete = true;
+        Ok(self.agent.last_exchange().clone())
+    }
+
+    /// Ask `question` as a new query in the thread, returning its answered exchange.
+    pub async fn run(&mut self, question: &str) -> Result<Exchange> {
+        let query = parser::parse_nl(question)
+            .context("parse error")?
+            .into_semantic()
+            .context("got a 'Grep' query")?
+            .with_raw_target(question)
+            .into_owned();
+
+        self.agent.query_id = uuid::Uuid::new_v4();
+        self.agent.file_budget = Default::default();
+        self.agent.loop_guard = Default::default();
+        self.agent.complete = false;
+        self.agent
+            .exchanges
+            .push(Exchange::new(self.agent.query_id, query));
+
+        self.run_action(Action::Query(question.to_owned())).await
+    }
+
+    pub(crate) fn into_agent(self) -> Agent {
+        self.agent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::exchange::AnswerOutcome,
+        llm_gateway::mock::{call, Gateway, Reply, Request, Script},
+        repo::Backend,
+        Environment,
+    };
+
+    /// Serve a mock gateway, which makes the function calls in `calls` in order, and answers every
+    /// other request with `answer`. The last call is repeated once all have been made.
+    fn serve(calls: Vec<serde_json::Value>, answer: &'static str) -> Gateway {
+        let calls = Script::new(calls);
+        Gateway::serve(move |request| {
+            if request.is_function_call() {
+                calls.next()
+            } else {
+                Reply::text(answer)
+            }
+        })
+    }
+
+    /// An application without a semantic index, that talks to the gateway at `answer_api_url`.
+    async fn app(index_dir: &tempdir::TempDir, answer_api_url: &str) -> Application {
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": answer_api_url,
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+
+        Application::initialize(Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve(
+            vec![call("none", serde_json::json!({ "paths": [] }))],
+            "The license is MIT.",
+        );
+        let app = app(&index_dir, &gateway.url).await;
+
+        // Agents answer from a single repository, which must be given.
+        assert!(builder(app.clone()).build().is_err());
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let (tx, mut rx) = mpsc::channel(100);
+        let mut driver = builder(app).repo(repo_ref).on_update(tx).build().unwrap();
+
+        let exchange = driver
+            .run("What is the license of this project?")
+            .await
+            .unwrap();
+        assert_eq!(exchange.answer.as_deref(), Some("The license is MIT."));
+        assert_eq!(
+            exchange.query().as_deref(),
+            Some("What is the license of this project?")
+        );
+
+        // The updates end with the answered exchange.
+        let mut last = None;
+        while let Ok(update) = rx.try_recv() {
+            last = Some(update);
+        }
+        assert_eq!(last.unwrap().answer, exchange.answer);
+
+        // Follow-up queries continue the same thread.
+        let exchange = driver.run("And of its dependencies?").await.unwrap();
+        assert_eq!(exchange.answer.as_deref(), Some("The license is MIT."));
+        assert_eq!(driver.into_agent().exchanges.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_quick_mode() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve(
+            vec![
+                call("path", serde_json::json!({ "query": "retry" })),
+                call("none", serde_json::json!({ "paths": [] })),
+            ],
+            "Retries are scheduled with a timer.",
+        );
+        let app = app(&index_dir, &gateway.url).await;
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let question = "How are retries scheduled?";
+        let mut driver = builder(app.clone())
+            .repo(repo_ref.clone())
+            .mode(quick::Mode::Quick)
+            .build()
+            .unwrap();
+        let quick_exchange = driver.run(question).await.unwrap();
+
+        // The searches are run without asking the model, and the answer follows right after.
+        assert!(gateway.requests_where(Request::is_function_call).is_empty());
+        assert!(quick_exchange.search_steps.len() <= quick::MAX_STEPS);
+        assert!(quick_exchange.quick);
+        assert_eq!(
+            quick_exchange.answer.as_deref(),
+            Some("Retries are scheduled with a timer.")
+        );
+
+        // A full analysis of the same question replaces the quick exchange, in the normal mode.
+        let mut exchanges = driver.into_agent().exchanges.clone();
+        let full =
+            quick::full_analysis(&mut exchanges, quick_exchange.id, uuid::Uuid::new_v4()).unwrap();
+        exchanges.push(full);
+
+        let mut driver = builder(app)
+            .repo(repo_ref)
+            .exchanges(exchanges)
+            .build()
+            .unwrap();
+        let full_exchange = driver
+            .run_action(Action::Query(question.to_owned()))
+            .await
+            .unwrap();
+
+        assert!(!gateway.requests_where(Request::is_function_call).is_empty());
+        assert!(!full_exchange.quick);
+        assert_eq!(full_exchange.full_analysis_of, Some(quick_exchange.id));
+        assert_eq!(full_exchange.query().as_deref(), Some(question));
+        assert_eq!(driver.into_agent().exchanges.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_answer_loop() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        // The model keeps listing the same files, in slightly different ways.
+        let gateway = serve(
+            vec![
+                call(
+                    "list_files",
+                    serde_json::json!({ "pattern": "src/retry/**" }),
+                ),
+                call(
+                    "list_files",
+                    serde_json::json!({ "pattern": "src/retry/*.rs" }),
+                ),
+                call(
+                    "list_files",
+                    serde_json::json!({ "pattern": "src/retry/**" }),
+                ),
+                call("none", serde_json::json!({ "paths": [] })),
+            ],
+            "Retries are not implemented.",
+        );
+        let app = app(&index_dir, &gateway.url).await;
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let mut driver = builder(app).repo(repo_ref).build().unwrap();
+        let exchange = driver.run("How are retries implemented?").await.unwrap();
+
+        let nudged = |request: &Request| {
+            request.body["messages"]["messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|m| m["content"] == crate::agent::loops::NUDGE_MESSAGE)
+        };
+
+        // The second near-identical call is followed by a nudge, and the third is replaced by an
+        // answer, without the model being asked again.
+        let requests = gateway.requests_where(Request::is_function_call);
+        assert_eq!(requests.len(), 3);
+        assert!(!nudged(&requests[0]));
+        assert!(!nudged(&requests[1]));
+        assert!(nudged(&requests[2]));
+
+        assert_eq!(exchange.search_steps.len(), 2);
+        assert_eq!(
+            exchange.answer.as_deref(),
+            Some("Retries are not implemented.")
+        );
+    }
+    #[tokio::test]
+    async fn test_index_warnings() {
+        use crate::{
+            indexes::diagnostics::{Category, DiagnosticsTally},
+            repo::Repository,
+        };
+
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve(
+            vec![call("none", serde_json::json!({ "paths": [] }))],
+            "Releases are built by CI.",
+        );
+        let app = app(&index_dir, &gateway.url).await;
+
+        // 3 of the repository's 20 files could not be indexed, which is over the threshold.
+        let tally = DiagnosticsTally::default();
+        for _ in 0..20 {
+            tally.file();
+        }
+        tally.record(Category::Oversized, "dist/bundle.js", "too large");
+        tally.record(Category::Oversized, "data/vocab.txt", "too large");
+        tally.record(Category::Unreadable, "secrets/key.pem", "permission denied");
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let mut repo = Repository::local_from(&repo_ref);
+        repo.index_diagnostics = tally.finish();
+        app.repo_pool.insert(repo_ref.clone(), repo).unwrap();
+
+        let mut driver = builder(app).repo(repo_ref).build().unwrap();
+        let exchange = driver.run("How are releases built?").await.unwrap();
+
+        let warnings = exchange.index_warnings.unwrap();
+        assert_eq!((warnings.files, warnings.affected), (20, 3));
+        assert_eq!(
+            warnings.counts,
+            [(Category::Oversized, 2), (Category::Unreadable, 1)].into()
+        );
+        assert!(warnings.caveat().starts_with("3 of the 20 files"));
+    }
+
+    #[tokio::test]
+    async fn test_access_control() {
+        use crate::{acl::tests::user, db::RepoAcl};
+
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve(
+            vec![call("none", serde_json::json!({ "paths": [] }))],
+            "Payments are retried.",
+        );
+        let app = app(&index_dir, &gateway.url).await;
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let acl = RepoAcl {
+            repo_ref: repo_ref.to_string(),
+            public: false,
+            users: vec!["alice".to_owned()],
+            groups: vec![],
+        };
+        app.access.set(&app.sql, acl).await.unwrap();
+
+        // Direct queries to the repository are refused to other users, as if it didn't exist.
+        for user in [user("mallory"), User::Unknown] {
+            let err = builder(app.clone())
+                .repo(repo_ref.clone())
+                .user(user)
+                .build()
+                .err()
+                .unwrap();
+            assert_eq!(err.to_string(), "repository was not found");
+        }
+
+        let mut driver = builder(app)
+            .repo(repo_ref)
+            .user(user("alice"))
+            .build()
+            .unwrap();
+        let exchange = driver.run("Are payments retried?").await.unwrap();
+        assert_eq!(exchange.answer.as_deref(), Some("Payments are retried."));
+    }
+
+    #[tokio::test]
+    async fn test_output_formats() {
+        async fn answer(format: OutputFormat, answer: &'static str) -> Result<Exchange> {
+            let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+            let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+            let gateway = serve(
+                vec![call("none", serde_json::json!({ "paths": [] }))],
+                answer,
+            );
+            let repo_ref =
+                RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+            let mut driver = builder(app(&index_dir, &gateway.url).await)
+                .repo(repo_ref)
+                .output_format(format)
+                .build()
+                .unwrap();
+
+            driver.run("How are requests retried?").await
+        }
+
+        let markdown = "[outcome: answered]\n# Retries\n\nRequests are retried by \
+                        [`send`](src/client.rs#L10-L20).\n\n[^summary]: They are retried.";
+
+        let exchange = answer(OutputFormat::Markdown, markdown).await.unwrap();
+        assert_eq!(
+            exchange.answer.as_deref(),
+            Some("# Retries\n\nRequests are retried by [`send`](src/client.rs#L10-L20).")
+        );
+
+        let exchange = answer(OutputFormat::Prose, markdown).await.unwrap();
+        assert_eq!(
+            exchange.answer.as_deref(),
+            Some("Retries\n\nRequests are retried by send.")
+        );
+        assert_eq!(exchange.answer().unwrap().1, "They are retried.");
+
+        let json = concat!(
+            r#"{"outcome": "answered", "answer": "Requests are retried by `send`.", "#,
+            r#""summary": "They are retried.", "#,
+            r#""references": [{"path": "src/client.rs", "start_line": 10, "end_line": 20}]}"#,
+        );
+        let exchange = answer(OutputFormat::Json, json).await.unwrap();
+        let value =
+            serde_json::from_str::<serde_json::Value>(exchange.answer.as_deref().unwrap()).unwrap();
+        assert_eq!(value["answer"], "Requests are retried by `send`.");
+        assert_eq!(value["references"][0]["path"], "src/client.rs");
+        assert_eq!(exchange.answer().unwrap().1, "They are retried.");
+        assert_eq!(exchange.outcome, Some(AnswerOutcome::Answered));
+
+        // Answers that are not valid JSON fail the query, rather than being passed on.
+        assert!(answer(OutputFormat::Json, markdown).await.is_err());
+    }
+    #[tokio::test]
+    async fn test_knowledge_base() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+        let docs_dir = tempdir::TempDir::new("bleep-docs").unwrap();
+
+        let doc = docs_dir.path().join("deployments.md");
+        std::fs::write(&doc, "Deployments are rolled back with the release tool.").unwrap();
+
+        let gateway = serve(
+            vec![call("none", serde_json::json!({ "paths": [] }))],
+            "With the release tool.",
+        );
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": gateway.url,
+            "disable_background": true,
+            "disable_analytics": true,
+            "knowledge_base": [doc],
+        }))
+        .unwrap();
+        let app = Application::initialize(Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        // Every agent is given the configured documents, and shows them to the model when they
+        // are relevant to the query.
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let mut driver = builder(app).repo(repo_ref).build().unwrap();
+        driver
+            .run("How are deployments rolled back?")
+            .await
+            .unwrap();
+
+        let steps = gateway.requests_where(Request::is_function_call);
+        assert!(steps[0].system().contains(
+            "Additional context: deployments.md\nDeployments are rolled back with the release tool."
+        ));
+    }
+    #[tokio::test]
+    async fn test_max_response_tokens() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve(
+            vec![call("none", serde_json::json!({ "paths": [] }))],
+            "The license is MIT.",
+        );
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": gateway.url,
+            "disable_background": true,
+            "disable_analytics": true,
+            "max_response_tokens": 10,
+        }))
+        .unwrap();
+        let app = Application::initialize(Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let mut driver = builder(app).repo(repo_ref).build().unwrap();
+        driver
+            .run("What is the license of this project?")
+            .await
+            .unwrap();
+
+        // Every request of the agent, whether a step or the answer, is clamped.
+        let requests = gateway.requests();
+        assert!(requests.len() > 1);
+        assert!(requests.iter().all(|r| r.body["max_tokens"] == 10));
+    }
+}
@@ -0,0 +1,453 @@
+//! Symbol-centric context for queries that ask to explain a single function.
+//!
+//! For a query like "explain `Agent::step`", the best context is the function itself, alongside
+//! the functions it calls and the functions that call it, rather than its semantic neighbours.
+//! Definitions are found through the symbols of each indexed file, and calls through a tree-sitter
+//! query over function bodies.
+
+use std::{collections::HashSet, ops::Range};
+
+use lazy_regex::regex;
+
+use crate::{indexes::reader::ContentDocument, intelligence::TreeSitterFile};
+
+/// A single function that a query asks to explain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// The symbol as written in the query, e.g. `Agent::step`.
+    pub symbol: String,
+    /// The name of the function, e.g. `step`.
+    pub name: String,
+    /// The type or module that the query qualified the function with, e.g. `Agent`.
+    pub qualifier: Option<String>,
+}
+
+/// Detect a query that asks to explain a single function.
+///
+/// The symbol must look like code, by being quoted in backticks, being qualified or called, using
+/// `snake_case` or `camelCase`, or by being explicitly called a function or method. Otherwise,
+/// "explain authentication" would be mistaken for a request to explain an `authentication`
+/// function.
+pub fn parse(query: &str) -> Option<Target> {
+    let captures = regex!(
+        r"(?i)^\s*(?:please\s+)?(?:explain|describe|what\s+does|how\s+does)\s+(?:the\s+)?((?:function|method|fn)\s+)?(`[^`\s]+`|[a-z_$][\w$:.#]*(?:\(\))?)(\s+(?:function|method|fn))?(?:\s+(?:do|does|work|works))?\s*[?.!]?\s*$"
+    )
+    .captures(query)?;
+
+    let raw = &captures[2];
+    let symbol = raw.trim_matches('`').trim_end_matches("()");
+    let (qualifier, name) = match symbol.rfind(|c| c == ':' || c == '.' || c == '#') {
+        Some(i) => (
+            Some(symbol[..i].trim_end_matches(':')).filter(|q| !q.is_empty()),
+            &symbol[i + 1..],
+        ),
+        None => (None, symbol),
+    };
+
+    if !regex!(r"^[A-Za-z_$][\w$]*$").is_match(name) {
+        return None;
+    }
+
+    let looks_like_code = raw.starts_with('`')
+        || raw.ends_with("()")
+        || qualifier.is_some()
+        || name.contains('_')
+        || regex!(r"[a-z][A-Z]").is_match(name)
+        || captures.get(1).is_some()
+        || captures.get(3).is_some();
+
+    looks_like_code.then(|| Target {
+        symbol: symbol.to_owned(),
+        name: name.to_owned(),
+        qualifier: qualifier.map(str::to_owned),
+    })
+}
+
+/// Limits on how much of the call graph is explored.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// How many levels of callees to follow. Callers are only followed one level.
+    pub depth: usize,
+    /// The maximum number of callees of each function, and the maximum number of callers.
+    pub fan_out: usize,
+}
+
+/// A function in the call graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub path: String,
+    pub name: String,
+    /// The 1-based, inclusive line range of the function.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub snippet: String,
+    bytes: Range<usize>,
+}
+
+impl Function {
+    fn new(doc: &ContentDocument, name: &str, bytes: Range<usize>) -> Self {
+        let start_line = doc.content[..bytes.start].matches('\n').count() + 1;
+        let end_line = start_line + doc.content[bytes.clone()].trim_end().matches('\n').count();
+
+        let snippet = doc
+            .content
+            .lines()
+            .skip(start_line - 1)
+            .take(end_line - start_line + 1)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Self {
+            path: doc.relative_path.clone(),
+            name: name.to_owned(),
+            start_line,
+            end_line,
+            snippet,
+            bytes,
+        }
+    }
+
+    fn is(&self, other: &Function) -> bool {
+        self.path == other.path && self.bytes == other.bytes
+    }
+}
+
+/// A function alongside its direct callers, and its callees up to some depth.
+#[derive(Debug)]
+pub struct CallGraph {
+    pub target: Target,
+    pub definition: Function,
+    /// Callees, breadth first.
+    pub callees: Vec<Function>,
+    pub callers: Vec<Function>,
+    /// Names of called functions that could not be found in the repository.
+    pub unresolved: Vec<String>,
+}
+
+impl CallGraph {
+    /// Build the call graph of a target out of the documents of a repository.
+    ///
+    /// This returns `None` if the target is not defined in any of the documents.
+    pub fn build(target: &Target, docs: &[ContentDocument], limits: Limits) -> Option<Self> {
+        let mut definitions = definitions(docs, &target.name).collect::<Vec<_>>();
+
+        // Prefer definitions in files that mention the qualifier, e.g. `impl Agent`.
+        if let Some(qualifier) = &target.qualifier {
+            definitions.sort_by_key(|(doc, _)| !doc.content.contains(qualifier.as_str()));
+        }
+
+        let definition = definitions.into_iter().next()?.1;
+
+        let mut seen = HashSet::from([target.name.clone()]);
+        let mut callees = vec![];
+        let mut unresolved = vec![];
+        let mut frontier = vec![definition.clone()];
+
+        for _ in 0..limits.depth {
+            let mut next = vec![];
+
+            for function in &frontier {
+                let mut added = 0;
+
+                for name in calls(docs, function) {
+                    if added == limits.fan_out {
+                        break;
+                    }
+
+                    if !seen.insert(name.clone()) {
+                        continue;
+                    }
+
+                    // Prefer a callee defined alongside its caller.
+                    let callee = definitions(docs, &name)
+                        .map(|(_, f)| f)
+                        .min_by_key(|f| f.path != function.path);
+
+                    match callee {
+                        Some(callee) => {
+                            next.push(callee);
+                            added += 1;
+                        }
+                        None => unresolved.push(name),
+                    }
+                }
+            }
+
+            callees.extend(next.iter().cloned());
+            frontier = next;
+        }
+
+        let callers = callers(docs, &target.name)
+            .filter(|f| !f.is(&definition))
+            .take(limits.fan_out)
+            .collect();
+
+        Some(Self {
+            target: target.clone(),
+            definition,
+            callees,
+            callers,
+            unresolved,
+        })
+    }
+
+    /// Every function in the graph, the definition first, then callees, then callers.
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        let mut seen = Vec::<&Function>::new();
+
+        std::iter::once(&self.definition)
+            .chain(&self.callees)
+            .chain(&self.callers)
+            .filter(move |f| {
+                if seen.iter().any(|s| s.is(f)) {
+                    false
+                } else {
+                    seen.push(f);
+                    true
+                }
+            })
+    }
+
+    /// An outline of the role of each function, for the answer prompt.
+    pub fn outline(&self) -> String {
+        let line = |role: &str, f: &Function| {
+            format!(
+                "{role}: {}:{}-{} {}\n",
+                f.path, f.start_line, f.end_line, f.name
+            )
+        };
+
+        let mut s = line("definition", &self.definition);
+        s.extend(self.callees.iter().map(|f| line("callee", f)));
+        s.extend(self.callers.iter().map(|f| line("caller", f)));
+
+        if !self.unresolved.is_empty() {
+            s += &format!("unresolved callees: {}\n", self.unresolved.join(", "));
+        }
+
+        s
+    }
+}
+
+/// Find the functions named `name`, alongside the documents they are defined in.
+fn definitions<'a>(
+    docs: &'a [ContentDocument],
+    name: &'a str,
+) -> impl Iterator<Item = (&'a ContentDocument, Function)> + 'a {
+    docs.iter()
+        .filter(move |doc| doc.content.contains(name))
+        .flat_map(move |doc| {
+            let Some(file) = doc
+                .lang
+                .as_deref()
+                .and_then(|lang| TreeSitterFile::try_build(doc.content.as_bytes(), lang).ok())
+            else {
+                return vec![];
+            };
+
+            doc.symbol_locations
+                .list()
+                .into_iter()
+                .map(|symbol| symbol.range.start.byte..symbol.range.end.byte)
+                .filter(|range| doc.content.get(range.clone()) == Some(name))
+                .filter_map(|range| file.function_named_at(range))
+                .map(|bytes| (doc, Function::new(doc, name, bytes)))
+                .collect()
+        })
+}
+
+/// The names of the functions called by a function, without duplicates, in source order.
+fn calls(docs: &[ContentDocument], function: &Function) -> Vec<String> {
+    let Some(doc) = docs.iter().find(|d| d.relative_path == function.path) else {
+        return vec![];
+    };
+
+    let Some(file) = doc
+        .lang
+        .as_deref()
+        .and_then(|lang| TreeSitterFile::try_build(doc.content.as_bytes(), lang).ok())
+    else {
+        return vec![];
+    };
+
+    let mut names = file
+        .calls(function.bytes.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, _)| name.to_owned())
+        .collect::<Vec<_>>();
+
+    let mut seen = HashSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+    names
+}
+
+/// The functions that call a function named `name`.
+fn callers<'a>(docs: &'a [ContentDocument], name: &'a str) -> impl Iterator<Item = Function> + 'a {
+    docs.iter()
+        .filter(move |doc| doc.content.contains(name))
+        .flat_map(move |doc| {
+            let Some(file) = doc
+                .lang
+                .as_deref()
+                .and_then(|lang| TreeSitterFile::try_build(doc.content.as_bytes(), lang).ok())
+            else {
+                return vec![];
+            };
+
+            let symbols = doc.symbol_locations.list();
+            let mut callers = Vec::<Function>::new();
+
+            for (_, call) in file
+                .calls(0..doc.content.len())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(call, _)| *call == name)
+            {
+                let Some(bytes) = file.enclosing_function(call) else {
+                    continue;
+                };
+
+                if callers.iter().any(|f| f.bytes == bytes) {
+                    continue;
+                }
+
+                // The caller's name is the first symbol defined inside of it.
+                let caller_name = symbols
+                    .iter()
+                    .map(|s| s.range.start.byte..s.range.end.byte)
+                    .filter(|r| bytes.contains(&r.start))
+                    .min_by_key(|r| r.start)
+                    .and_then(|r| doc.content.get(r))
+                    .unwrap_or("?");
+
+                callers.push(Function::new(doc, caller_name, bytes));
+            }
+
+            callers
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::SymbolLocations;
+
+    const FIXTURE: &str = include_str!("fixtures/call_graph.rs");
+
+    fn doc(relative_path: &str, content: &str) -> ContentDocument {
+        let symbol_locations = TreeSitterFile::try_build(content.as_bytes(), "Rust")
+            .and_then(TreeSitterFile::scope_graph)
+            .map(SymbolLocations::TreeSitter)
+            .unwrap();
+
+        ContentDocument {
+            content: content.to_owned(),
+            lang: Some("Rust".to_owned()),
+            relative_path: relative_path.to_owned(),
+            repo_name: "bloop".to_owned(),
+            repo_ref: "github.com/BloopAI/bloop".to_owned(),
+            line_end_indices: content.match_indices('\n').map(|(i, _)| i as u32).collect(),
+            symbol_locations,
+            branches: None,
+            lfs_pointer: false,
+        }
+    }
+
+    const LIMITS: Limits = Limits {
+        depth: 1,
+        fan_out: 5,
+    };
+
+    fn names(functions: &[Function]) -> Vec<&str> {
+        functions.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let target = parse("explain `Agent::step`").unwrap();
+        assert_eq!(target.symbol, "Agent::step");
+        assert_eq!(target.name, "step");
+        assert_eq!(target.qualifier.as_deref(), Some("Agent"));
+
+        assert_eq!(parse("What does parse_nl do?").unwrap().name, "parse_nl");
+        assert_eq!(parse("how does foo.barBaz() work").unwrap().name, "barBaz");
+        assert_eq!(parse("explain the function step").unwrap().name, "step");
+        assert_eq!(parse("Explain step method").unwrap().name, "step");
+
+        assert_eq!(parse("explain authentication"), None);
+        assert_eq!(parse("explain how the indexer works"), None);
+        assert_eq!(parse("where is `Agent::step` called?"), None);
+    }
+
+    #[test]
+    fn test_callees() {
+        let docs = [doc("src/agent.rs", FIXTURE)];
+        let target = parse("explain `Agent::step`").unwrap();
+
+        let shallow = CallGraph::build(&target, &docs, LIMITS).unwrap();
+        assert_eq!(shallow.definition.name, "step");
+        assert_eq!(
+            (shallow.definition.start_line, shallow.definition.end_line),
+            (9, 15)
+        );
+        assert_eq!(names(&shallow.callees), ["parse_action", "record", "log"]);
+        assert_eq!(names(&shallow.callers), ["run"]);
+        assert!(shallow.unresolved.is_empty());
+
+        let limits = Limits {
+            depth: 2,
+            fan_out: 2,
+        };
+        let deep = CallGraph::build(&target, &docs, limits).unwrap();
+
+        // `log` is cut by the fan-out, and `validate` is only reachable through `record`.
+        assert_eq!(names(&deep.callees), ["parse_action", "record", "validate"]);
+        assert_eq!(deep.unresolved, ["trim", "len"]);
+
+        let missing = parse("explain `missing_fn`").unwrap();
+        assert!(CallGraph::build(&missing, &docs, LIMITS).is_none());
+    }
+
+    #[test]
+    fn test_packed_context() {
+        let main =
+            "fn main() {\n    let mut agent = Agent::default();\n    agent.step(\"main\");\n}\n";
+        let docs = [doc("src/agent.rs", FIXTURE), doc("src/main.rs", main)];
+        let target = parse("explain `Agent::step`").unwrap();
+        let graph = CallGraph::build(&target, &docs, LIMITS).unwrap();
+
+        let packed = graph
+            .functions()
+            .map(|f| format!("{}:{}-{} {}", f.path, f.start_line, f.end_line, f.name))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            packed,
+            [
+                "src/agent.rs:9-15 step",
+                "src/agent.rs:23-25 parse_action",
+                "src/agent.rs:17-20 record",
+                "src/agent.rs:37-37 log",
+                "src/agent.rs:31-34 run",
+                "src/main.rs:1-4 main",
+            ]
+        );
+
+        let step = graph.functions().next().unwrap();
+        assert!(step
+            .snippet
+            .starts_with("    pub fn step(&mut self, action: &str) -> usize {\n"));
+        assert!(step.snippet.ends_with("\n        self.steps\n    }"));
+
+        assert_eq!(
+            graph.outline(),
+            "definition: src/agent.rs:9-15 step\n\
+             callee: src/agent.rs:23-25 parse_action\n\
+             callee: src/agent.rs:17-20 record\n\
+             callee: src/agent.rs:37-37 log\n\
+             caller: src/agent.rs:31-34 run\n\
+             caller: src/main.rs:1-4 main\n"
+        );
+    }
+}
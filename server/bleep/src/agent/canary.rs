@@ -0,0 +1,526 @@
+//! Canary queries, which ask a known question end to end to check that every subsystem that
+//! answers depend on is working.
+//!
+//! The question is asked of a small repository that is set aside for it, with a cheap model and a
+//! budget of `MAX_STEPS` tool calls, so that it is quick and cheap to run. Canary queries send no
+//! analytics events, and their usage is not recorded, so they are never billed to a user.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    agent::{
+        builder::{self, Driver},
+        quick,
+    },
+    db::UsageRecord,
+    indexes::IndexCorrupt,
+    llm_gateway,
+    repo::RepoRef,
+    webserver::ErrorKind,
+    Application,
+};
+
+/// The most tool calls that a canary query can make before it is answered.
+pub const MAX_STEPS: usize = 2;
+
+/// How many characters of the answer are reported.
+const ANSWER_PREVIEW_CHARS: usize = 200;
+
+/// The canary query, and how quickly each subsystem must respond to it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CanaryConfig {
+    /// The repository that the question is asked of, which should be small. Canary queries fail
+    /// until one is set.
+    pub repo: Option<RepoRef>,
+    pub question: String,
+    /// The model that picks the tools to call, which should be a cheap one.
+    pub model: String,
+    /// The longest that each subsystem can take to respond, in milliseconds. Subsystems that are
+    /// not listed can take any time.
+    pub max_latency_ms: BTreeMap<Subsystem, u64>,
+}
+
+impl Default for CanaryConfig {
+    fn default() -> Self {
+        Self {
+            repo: None,
+            question: "What does this repository do?".to_owned(),
+            model: quick::ANSWER_MODEL.to_owned(),
+            max_latency_ms: [
+                (Subsystem::Index, 1_000),
+                (Subsystem::Semantic, 1_000),
+                (Subsystem::LlmGateway, 20_000),
+                (Subsystem::Agent, 30_000),
+            ]
+            .into(),
+        }
+    }
+}
+
+/// A part of bloop that answering a query depends on, in the order that they are checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// The canary repository, and the indexes it is in.
+    Index,
+    /// The vector store of semantic search.
+    Semantic,
+    /// The LLM calls of the canary query.
+    LlmGateway,
+    /// The canary query as a whole, from its question to its answer.
+    Agent,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// The subsystem responded, but took longer than its `max_latency_ms`.
+    Slow,
+    Failed,
+    /// The subsystem is turned off, which is not a failure.
+    Disabled,
+    /// The subsystem was not checked, because a subsystem that it depends on failed.
+    Skipped,
+}
+
+/// Why a subsystem failed, with the kind of error that the API would respond with.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct StageError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl StageError {
+    fn new(kind: ErrorKind, message: impl std::fmt::Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// How a single subsystem responded to the canary query.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Stage {
+    pub subsystem: Subsystem,
+    pub status: Status,
+    pub latency_ms: Option<u64>,
+    pub max_latency_ms: Option<u64>,
+    pub error: Option<StageError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CanaryReport {
+    /// Whether every subsystem that is turned on responded in time.
+    pub passed: bool,
+    /// The first subsystem that failed or was too slow, if any was.
+    pub failed: Option<Subsystem>,
+    pub stages: Vec<Stage>,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    /// The start of the answer, up to `ANSWER_PREVIEW_CHARS` characters.
+    pub answer: Option<String>,
+}
+
+/// Ask the canary question of `config.repo`, talking to the LLM with `llm_gateway`.
+pub async fn run(
+    app: &Application,
+    config: &CanaryConfig,
+    llm_gateway: llm_gateway::Client,
+) -> CanaryReport {
+    let mut stages = Vec::new();
+    let stage = |subsystem: Subsystem, result: Result<Option<Duration>, StageError>| {
+        let max_latency_ms = config.max_latency_ms.get(&subsystem).copied();
+        let (status, latency, error) = match result {
+            Ok(None) => (Status::Disabled, None, None),
+            Ok(Some(latency)) => {
+                let latency_ms = latency.as_millis() as u64;
+                match max_latency_ms {
+                    Some(max) if latency_ms > max => (Status::Slow, Some(latency_ms), None),
+                    _ => (Status::Ok, Some(latency_ms), None),
+                }
+            }
+            Err(error) => (Status::Failed, None, Some(error)),
+        };
+
+        Stage {
+            subsystem,
+            status,
+            latency_ms: latency,
+            max_latency_ms,
+            error,
+        }
+    };
+
+    let (index, elapsed) = timed(check_index(app, config.repo.as_ref())).await;
+    let repo = config.repo.as_ref().filter(|_| index.is_ok());
+    stages.push(stage(Subsystem::Index, index.map(|_| Some(elapsed))));
+
+    let semantic = match &app.semantic {
+        Some(semantic) => match timed(semantic.health_check()).await {
+            (Ok(()), elapsed) => Ok(Some(elapsed)),
+            (Err(err), _) => Err(StageError::new(ErrorKind::UpstreamService, err)),
+        },
+        None => Ok(None),
+    };
+    stages.push(stage(Subsystem::Semantic, semantic));
+
+    let Some(repo) = repo else {
+        stages.push(skipped(Subsystem::LlmGateway, config));
+        stages.push(skipped(Subsystem::Agent, config));
+        return report(stages, Vec::new(), None);
+    };
+
+    let driver = builder::builder(app.clone())
+        .repo(repo.clone())
+        .llm_gateway(llm_gateway)
+        .model(&config.model)
+        .max_steps(MAX_STEPS)
+        .canary(true)
+        .build();
+
+    let driver = match driver {
+        Ok(driver) => driver,
+        Err(err) => {
+            let error = StageError::new(ErrorKind::Configuration, err);
+            stages.push(skipped(Subsystem::LlmGateway, config));
+            stages.push(stage(Subsystem::Agent, Err(error)));
+            return report(stages, Vec::new(), None);
+        }
+    };
+
+    let asked = ask(driver, &config.question).await;
+    let llm_latency = asked
+        .usage
+        .iter()
+        .map(|record| Duration::from_millis(record.latency_ms as u64))
+        .sum::<Duration>();
+
+    let answer = match asked.answer {
+        Ok(answer) => {
+            stages.push(stage(Subsystem::LlmGateway, Ok(Some(llm_latency))));
+            stages.push(stage(Subsystem::Agent, Ok(Some(asked.elapsed))));
+            Some(answer.chars().take(ANSWER_PREVIEW_CHARS).collect())
+        }
+        Err(err) => {
+            let (failed, error) = classify(&err, &asked.usage);
+            warn!(?err, ?failed, "canary query failed");
+
+            // The failure replaces the stage that was checked before the query, if there was one.
+            stages.retain(|s| s.subsystem != failed);
+            stages.push(stage(failed, Err(error)));
+
+            if failed != Subsystem::LlmGateway {
+                stages.push(if asked.usage.is_empty() {
+                    skipped(Subsystem::LlmGateway, config)
+                } else {
+                    stage(Subsystem::LlmGateway, Ok(Some(llm_latency)))
+                });
+            }
+
+            if failed != Subsystem::Agent {
+                stages.push(skipped(Subsystem::Agent, config));
+            }
+
+            None
+        }
+    };
+
+    report(stages, asked.usage, answer)
+}
+
+fn report(mut stages: Vec<Stage>, usage: Vec<UsageRecord>, answer: Option<String>) -> CanaryReport {
+    stages.sort_by_key(|s| s.subsystem);
+    let failed = stages
+        .iter()
+        .find(|s| matches!(s.status, Status::Slow | Status::Failed))
+        .map(|s| s.subsystem);
+
+    info!(passed = failed.is_none(), ?failed, "ran canary query");
+    CanaryReport {
+        passed: failed.is_none(),
+        failed,
+        stages,
+        prompt_tokens: usage.iter().map(|record| record.prompt_tokens).sum(),
+        completion_tokens: usage.iter().map(|record| record.completion_tokens).sum(),
+        answer,
+    }
+}
+
+/// Check that `repo` is known, and that its documents can be read from the indexes.
+async fn check_index(app: &Application, repo: Option<&RepoRef>) -> Result<(), StageError> {
+    let repo = repo.ok_or_else(|| {
+        StageError::new(
+            ErrorKind::Configuration,
+            "no canary repository is configured",
+        )
+    })?;
+
+    app.repo_pool
+        .read_async(repo, |_, _| ())
+        .await
+        .ok_or_else(|| StageError::new(ErrorKind::NotFound, "Can't find repository"))?;
+
+    match app
+        .indexes
+        .repo_health(repo)
+        .await
+        .into_iter()
+        .find_map(Result::err)
+    {
+        Some(err) => Err(StageError::new(ErrorKind::IndexCorrupt, err)),
+        None => Ok(()),
+    }
+}
+
+/// The outcome of the canary question, with the LLM usage that it took.
+struct Asked {
+    answer: anyhow::Result<String>,
+    usage: Vec<UsageRecord>,
+    elapsed: Duration,
+}
+
+async fn ask(mut driver: Driver, question: &str) -> Asked {
+    let start = Instant::now();
+    let answer = driver
+        .run(question)
+        .await
+        .map(|exchange| exchange.answer.unwrap_or_default());
+    let elapsed = start.elapsed();
+
+    let agent = driver.into_agent();
+    let usage = agent
+        .canary_usage
+        .as_ref()
+        .map(|usage| std::mem::take(&mut *usage.lock().unwrap()))
+        .unwrap_or_default();
+
+    Asked {
+        answer,
+        usage,
+        elapsed,
+    }
+}
+
+/// The subsystem that a canary query failed in, and why.
+///
+/// Queries that fail before any LLM call completed are taken to have failed to reach the LLM.
+fn classify(err: &anyhow::Error, usage: &[UsageRecord]) -> (Subsystem, StageError) {
+    if let Some(corrupt) = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<IndexCorrupt>())
+    {
+        return (
+            Subsystem::Index,
+            StageError::new(ErrorKind::IndexCorrupt, corrupt),
+        );
+    }
+
+    let unreachable = err.chain().any(|cause| cause.is::<reqwest::Error>());
+    if unreachable || usage.is_empty() {
+        let error = StageError::new(ErrorKind::UpstreamService, format!("{err:#}"));
+        (Subsystem::LlmGateway, error)
+    } else {
+        let error = StageError::new(ErrorKind::Internal, format!("{err:#}"));
+        (Subsystem::Agent, error)
+    }
+}
+
+fn skipped(subsystem: Subsystem, config: &CanaryConfig) -> Stage {
+    Stage {
+        subsystem,
+        status: Status::Skipped,
+        latency_ms: None,
+        max_latency_ms: config.max_latency_ms.get(&subsystem).copied(),
+        error: None,
+    }
+}
+
+async fn timed<T>(future: impl Future<Output = T>) -> (T, Duration) {
+    let start = Instant::now();
+    let output = future.await;
+    (output, start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        db::Usage,
+        llm_gateway::mock::{call, Gateway, Reply, Request},
+        repo::{Backend, Repository},
+        Environment,
+    };
+
+    /// Serve a mock gateway, which searches for paths whenever it is asked to call a function, and
+    /// answers with `answer` otherwise.
+    fn serve(answer: String) -> Gateway {
+        Gateway::serve(move |request| {
+            if request.is_function_call() {
+                Reply::call(&call("path", serde_json::json!({ "query": "readme" })))
+            } else {
+                Reply::text(answer.clone())
+            }
+        })
+    }
+
+    /// An application with a canary repository, and an embedded semantic store if `semantic` is
+    /// set.
+    async fn app(
+        index_dir: &tempdir::TempDir,
+        repo_dir: &tempdir::TempDir,
+        semantic: bool,
+    ) -> (Application, CanaryConfig) {
+        let mut config = serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        });
+
+        if semantic {
+            config["semantic_backend"] = "embedded".into();
+            config["model_dir"] = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../../model")
+                .to_string_lossy()
+                .into();
+        }
+
+        let app = Application::initialize(
+            Environment::insecure_local(),
+            serde_json::from_value(config).unwrap(),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        app.repo_pool
+            .insert(repo_ref.clone(), Repository::local_from(&repo_ref))
+            .unwrap();
+
+        let config = CanaryConfig {
+            repo: Some(repo_ref),
+            ..Default::default()
+        };
+
+        (app, config)
+    }
+
+    fn statuses(report: &CanaryReport) -> Vec<(Subsystem, Status)> {
+        report
+            .stages
+            .iter()
+            .map(|stage| (stage.subsystem, stage.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let index_dir = tempdir::TempDir::new("bleep-canary").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve("The canary sings. ".repeat(20));
+        let (app, config) = app(&index_dir, &repo_dir, true).await;
+
+        let report = run(&app, &config, gateway.client()).await;
+        assert!(report.passed);
+        assert_eq!(report.failed, None);
+        assert_eq!(
+            statuses(&report),
+            [
+                (Subsystem::Index, Status::Ok),
+                (Subsystem::Semantic, Status::Ok),
+                (Subsystem::LlmGateway, Status::Ok),
+                (Subsystem::Agent, Status::Ok),
+            ]
+        );
+
+        // The query is answered once it runs out of steps.
+        assert_eq!(
+            gateway.requests_where(Request::is_function_call).len(),
+            MAX_STEPS
+        );
+        assert!(report.prompt_tokens > 0);
+        assert_eq!(report.answer.unwrap().chars().count(), ANSWER_PREVIEW_CHARS);
+
+        // Canary queries are not billed.
+        let usage = Usage::new(&app.sql).between(0, i64::MAX).await.unwrap();
+        assert!(usage.is_empty());
+
+        // Subsystems that take too long fail the canary query, even when they respond.
+        let strict = CanaryConfig {
+            max_latency_ms: [(Subsystem::Agent, 0)].into(),
+            ..config
+        };
+        let report = run(&app, &strict, gateway.client()).await;
+        assert!(!report.passed);
+        assert_eq!(report.failed, Some(Subsystem::Agent));
+        assert_eq!(report.stages[3].status, Status::Slow);
+    }
+
+    #[tokio::test]
+    async fn test_run_without_semantic() {
+        let index_dir = tempdir::TempDir::new("bleep-canary").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve("The canary sings.".to_owned());
+        let (app, config) = app(&index_dir, &repo_dir, false).await;
+
+        // A semantic layer that is turned off is not a failure.
+        let report = run(&app, &config, gateway.client()).await;
+        assert!(report.passed);
+        assert_eq!(
+            statuses(&report),
+            [
+                (Subsystem::Index, Status::Ok),
+                (Subsystem::Semantic, Status::Disabled),
+                (Subsystem::LlmGateway, Status::Ok),
+                (Subsystem::Agent, Status::Ok),
+            ]
+        );
+        assert_eq!(report.answer.as_deref(), Some("The canary sings."));
+
+        // A gateway that can't be reached is reported as such, with the error the API would give.
+        let unreachable = llm_gateway::Client::new("http://127.0.0.1:9");
+        let report = run(&app, &config, unreachable).await;
+        assert!(!report.passed);
+        assert_eq!(report.failed, Some(Subsystem::LlmGateway));
+        assert_eq!(
+            statuses(&report),
+            [
+                (Subsystem::Index, Status::Ok),
+                (Subsystem::Semantic, Status::Disabled),
+                (Subsystem::LlmGateway, Status::Failed),
+                (Subsystem::Agent, Status::Skipped),
+            ]
+        );
+        let error = report.stages[2].error.as_ref().unwrap();
+        assert_eq!(error.kind, ErrorKind::UpstreamService);
+
+        // Without a canary repository, there is nothing to ask.
+        let report = run(&app, &CanaryConfig::default(), gateway.client()).await;
+        assert_eq!(report.failed, Some(Subsystem::Index));
+        assert_eq!(
+            report.stages[0].error,
+            Some(StageError::new(
+                ErrorKind::Configuration,
+                "no canary repository is configured"
+            ))
+        );
+        assert_eq!(report.answer, None);
+    }
+}
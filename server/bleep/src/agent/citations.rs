@@ -0,0 +1,379 @@
+//! A thread-level registry of cited code chunks.
+//!
+//! Regenerated answers, and answers produced over several attempts, tend to cite the same chunks
+//! over and over again. Instead of embedding a copy of each chunk in every exchange, stored threads
+//! register every distinct chunk once, and exchanges refer to it by a stable id.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use crate::{
+    agent::exchange::{CodeChunk, Exchange},
+    repo::RepoRef,
+};
+
+/// A cited code chunk, as stored in a thread's registry.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Citation {
+    pub id: String,
+    pub repo: RepoRef,
+    pub path: String,
+    #[serde(rename = "start")]
+    pub start_line: usize,
+    #[serde(rename = "end")]
+    pub end_line: usize,
+    /// The blake3 hash of the cited snippet.
+    pub sha: String,
+    pub snippet: String,
+    /// The revision of the repository the snippet was cited at.
+    ///
+    /// This is a commit SHA for git repositories, and a file manifest hash for plain directories.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The symbols that enclose the snippet, if it was found by semantic search.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol_path: Option<String>,
+    /// The git blob of the cited file, which its lines are mapped from when the file changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+}
+
+impl Citation {
+    fn new(repo: &RepoRef, chunk: &CodeChunk, revision: Option<String>) -> Self {
+        let sha = blake3::hash(chunk.snippet.as_bytes()).to_string();
+
+        Self {
+            id: citation_id(repo, &chunk.path, chunk.start_line, chunk.end_line, &sha),
+            repo: repo.clone(),
+            path: chunk.path.clone(),
+            start_line: chunk.start_line,
+            end_line: chunk.end_line,
+            sha,
+            snippet: chunk.snippet.clone(),
+            revision: chunk.revision.clone().or(revision),
+            symbol_path: chunk.symbol_path.clone(),
+            blob: chunk.blob.clone(),
+        }
+    }
+
+    fn to_chunk(&self, alias: usize) -> CodeChunk {
+        CodeChunk {
+            path: self.path.clone(),
+            alias,
+            snippet: self.snippet.clone(),
+            start_line: self.start_line,
+            end_line: self.end_line,
+            moved_to: None,
+            deleted: false,
+            symbol_path: self.symbol_path.clone(),
+            blob: self.blob.clone(),
+            revision: self.revision.clone(),
+            mapped: None,
+        }
+    }
+}
+
+/// The id of a citation, derived from its `(repo, path, start, end, sha)` key.
+///
+/// Because the id only depends on the key, the same chunk gets the same id in every attempt at an
+/// answer, and across every store of the thread.
+fn citation_id(
+    repo: &RepoRef,
+    path: &str,
+    start_line: usize,
+    end_line: usize,
+    sha: &str,
+) -> String {
+    let mut hasher = blake3::Hasher::new();
+
+    for part in [
+        repo.to_string().as_str(),
+        path,
+        &start_line.to_string(),
+        &end_line.to_string(),
+        sha,
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\0");
+    }
+
+    hasher.finalize().to_hex()[..16].to_owned()
+}
+
+/// A reference from a stored exchange to a registered citation.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct CitationRef {
+    citation: String,
+    alias: usize,
+}
+
+/// Every distinct chunk cited by a thread, in the order they were first cited.
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct CitationRegistry {
+    citations: Vec<Citation>,
+    /// The revision that newly registered citations are stamped with.
+    #[serde(skip)]
+    revision: Option<String>,
+}
+
+impl CitationRegistry {
+    /// Build a registry from all chunks cited so far in a thread.
+    pub fn from_exchanges(repo: &RepoRef, exchanges: &[Exchange]) -> Self {
+        let mut registry = Self::default();
+
+        for chunk in exchanges.iter().flat_map(|e| &e.code_chunks) {
+            registry.register(repo, chunk);
+        }
+
+        registry
+    }
+
+    /// Stamp citations registered from now on with a repository revision.
+    pub fn at_revision(mut self, revision: Option<String>) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Register a chunk, returning its citation id.
+    pub fn register(&mut self, repo: &RepoRef, chunk: &CodeChunk) -> String {
+        let citation = Citation::new(repo, chunk, self.revision.clone());
+        let id = citation.id.clone();
+
+        if self.get(&id).is_none() {
+            self.citations.push(citation);
+        }
+
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Citation> {
+        self.citations.iter().find(|c| c.id == id)
+    }
+
+    /// Find the citation of `path` that best covers the 1-based line range `start..=end`.
+    pub fn find(&self, path: &str, start_line: usize, end_line: usize) -> Option<&Citation> {
+        self.citations
+            .iter()
+            .filter(|c| c.path == path)
+            .map(|c| {
+                let overlap =
+                    (c.end_line.min(end_line) + 1).saturating_sub(c.start_line.max(start_line));
+                (overlap, c)
+            })
+            .filter(|(overlap, _)| *overlap > 0)
+            .max_by_key(|(overlap, _)| *overlap)
+            .map(|(_, c)| c)
+    }
+
+    /// Convert exchanges into their stored form, where code chunks are replaced with references
+    /// into this registry.
+    ///
+    /// Chunks are cited from the repository that their exchange searched, or from `repo` if it
+    /// doesn't record one.
+    pub fn normalize(&mut self, repo: &RepoRef, exchanges: &[Exchange]) -> Result<Value> {
+        let stored = exchanges
+            .iter()
+            .map(|exchange| {
+                let repo = exchange.repo_ref.as_ref().unwrap_or(repo);
+                let refs = exchange
+                    .code_chunks
+                    .iter()
+                    .map(|chunk| CitationRef {
+                        citation: self.register(repo, chunk),
+                        alias: chunk.alias,
+                    })
+                    .collect::<Vec<_>>();
+
+                let mut value = serde_json::to_value(exchange)?;
+                value["code_chunks"] = serde_json::to_value(refs)?;
+                Ok(value)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Value::Array(stored))
+    }
+
+    /// Convert stored exchanges back, resolving their citation references.
+    ///
+    /// Threads stored before citations were registered embed full code chunks. These are accepted
+    /// as-is, and registered as they are found. This returns `true` alongside the exchanges if any
+    /// such chunks were found.
+    pub fn resolve(&mut self, repo: &RepoRef, stored: Value) -> Result<(Vec<Exchange>, bool)> {
+        let Value::Array(stored) = stored else {
+            bail!("stored exchanges were not an array");
+        };
+
+        let mut legacy = false;
+        let exchanges = stored
+            .into_iter()
+            .map(|mut value| {
+                if !value.is_object() {
+                    bail!("stored exchange was not an object");
+                }
+
+                let chunks = match value.get_mut("code_chunks").map(Value::take) {
+                    Some(Value::Array(chunks)) => chunks,
+                    _ => vec![],
+                };
+
+                let chunks = chunks
+                    .into_iter()
+                    .map(|chunk| {
+                        if chunk.get("citation").is_some() {
+                            let CitationRef { citation, alias } = serde_json::from_value(chunk)?;
+                            self.get(&citation)
+                                .map(|c| c.to_chunk(alias))
+                                .with_context(|| format!("unknown citation `{citation}`"))
+                        } else {
+                            legacy = true;
+                            let chunk = serde_json::from_value::<CodeChunk>(chunk)?;
+                            self.register(repo, &chunk);
+                            Ok(chunk)
+                        }
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                value["code_chunks"] = serde_json::to_value(chunks)?;
+                Ok(serde_json::from_value(value)?)
+            })
+            .collect::<Result<Vec<Exchange>>>()?;
+
+        Ok((exchanges, legacy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(alias: usize, path: &str, start_line: usize, end_line: usize) -> CodeChunk {
+        CodeChunk {
+            path: path.to_owned(),
+            alias,
+            snippet: format!("{path}:{start_line}-{end_line}"),
+            start_line,
+            end_line,
+            moved_to: None,
+            deleted: false,
+            symbol_path: None,
+            blob: None,
+            revision: None,
+            mapped: None,
+        }
+    }
+
+    fn exchange(chunks: Vec<CodeChunk>) -> Exchange {
+        let mut exchange = Exchange::new(uuid::Uuid::new_v4(), Default::default());
+        exchange.code_chunks = chunks;
+        exchange
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        let repo = RepoRef::from("github.com/BloopAI/bloop");
+
+        // The same chunks cited by two attempts at an answer, in a different order.
+        let first = exchange(vec![
+            chunk(0, "src/lib.rs", 1, 10),
+            chunk(1, "src/main.rs", 4, 8),
+        ]);
+        let second = exchange(vec![
+            chunk(1, "src/main.rs", 4, 8),
+            chunk(0, "src/lib.rs", 1, 10),
+        ]);
+
+        let mut registry = CitationRegistry::default();
+        let stored = registry.normalize(&repo, &[first.clone(), second]).unwrap();
+        assert_eq!(registry.citations.len(), 2);
+
+        let id = |i: usize, j: usize| stored[i]["code_chunks"][j]["citation"].clone();
+        assert_eq!(id(0, 0), id(1, 1));
+        assert_eq!(id(0, 1), id(1, 0));
+        assert_ne!(id(0, 0), id(0, 1));
+
+        // A fresh registry assigns the same ids again.
+        let fresh = CitationRegistry::from_exchanges(&repo, &[first]);
+        assert_eq!(fresh, registry);
+
+        // Changing the snippet changes the id.
+        let mut edited = chunk(0, "src/lib.rs", 1, 10);
+        edited.snippet += "\n// edited";
+        assert_ne!(
+            serde_json::Value::String(registry.clone().register(&repo, &edited)),
+            id(0, 0)
+        );
+
+        let (exchanges, legacy) = registry.clone().resolve(&repo, stored).unwrap();
+        assert!(!legacy);
+        assert_eq!(exchanges[1].code_chunks[0].path, "src/main.rs");
+        assert_eq!(exchanges[1].code_chunks[0].alias, 1);
+        assert_eq!(exchanges[1].code_chunks[0].snippet, "src/main.rs:4-8");
+    }
+
+    #[test]
+    fn test_legacy_thread() {
+        let repo = RepoRef::from("github.com/BloopAI/bloop");
+        let stored = serde_json::from_str(include_str!("fixtures/legacy_thread.json")).unwrap();
+
+        let mut registry = CitationRegistry::default();
+        let (exchanges, legacy) = registry.resolve(&repo, stored).unwrap();
+        assert!(legacy);
+        assert_eq!(exchanges.len(), 2);
+
+        // Both exchanges cite `server/bleep/src/query/parser.rs:10-20`.
+        assert_eq!(registry.citations.len(), 2);
+
+        // Re-storing the thread references the registry, and loads back to the same chunks.
+        let stored = registry.normalize(&repo, &exchanges).unwrap();
+        assert!(stored[0]["code_chunks"][0].get("snippet").is_none());
+
+        let (reloaded, legacy) = registry.resolve(&repo, stored).unwrap();
+        assert!(!legacy);
+        for (a, b) in exchanges.iter().zip(&reloaded) {
+            assert_eq!(
+                serde_json::to_value(&a.code_chunks).unwrap(),
+                serde_json::to_value(&b.code_chunks).unwrap()
+            );
+        }
+
+        assert!(CitationRegistry::default()
+            .resolve(&repo, registry.normalize(&repo, &exchanges).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_revision() {
+        let repo = RepoRef::from("localdir//srv/docs");
+        let chunks = [chunk(0, "guide.md", 1, 10)];
+
+        let mut registry = CitationRegistry::default().at_revision(Some("abc123".to_owned()));
+        let stored = registry
+            .normalize(&repo, &[exchange(chunks.to_vec())])
+            .unwrap();
+        assert_eq!(registry.citations[0].revision.as_deref(), Some("abc123"));
+
+        // The revision is stored with the citation, but doesn't change its id.
+        let unstamped = CitationRegistry::from_exchanges(&repo, &[exchange(chunks.to_vec())]);
+        assert_eq!(unstamped.citations[0].revision, None);
+        assert_eq!(unstamped.citations[0].id, registry.citations[0].id);
+
+        let reloaded: CitationRegistry =
+            serde_json::from_str(&serde_json::to_string(&registry).unwrap()).unwrap();
+        assert_eq!(reloaded.citations, registry.citations);
+        assert!(reloaded.clone().resolve(&repo, stored).is_ok());
+    }
+
+    #[test]
+    fn test_find() {
+        let repo = RepoRef::from("github.com/BloopAI/bloop");
+        let mut registry = CitationRegistry::default();
+        let a = registry.register(&repo, &chunk(0, "src/lib.rs", 1, 10));
+        let b = registry.register(&repo, &chunk(0, "src/lib.rs", 8, 30));
+
+        assert_eq!(registry.find("src/lib.rs", 2, 5).unwrap().id, a);
+        assert_eq!(registry.find("src/lib.rs", 9, 20).unwrap().id, b);
+        assert!(registry.find("src/lib.rs", 40, 50).is_none());
+        assert!(registry.find("src/main.rs", 2, 5).is_none());
+    }
+}
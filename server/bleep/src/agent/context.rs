@@ -0,0 +1,190 @@
+//! The logging context of a request, for correlating the logs of a single query.
+//!
+//! Log events are emitted in a `request` span that carries the ids of the query. Tasks spawned
+//! while answering a query are not in that span by default, so they must be spawned with
+//! `spawn_in_ctx` or `spawn_blocking_in_ctx`, which carry the context into the task.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+use tracing::{instrument::WithSubscriber, Instrument, Span};
+
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    /// The login of the user that made the request.
+    pub user: Option<String>,
+    pub thread_id: uuid::Uuid,
+    pub query_id: uuid::Uuid,
+    pub run_id: Option<uuid::Uuid>,
+}
+
+impl RequestContext {
+    /// A span that carries the ids of this request, for its log events.
+    pub fn span(&self) -> Span {
+        tracing::info_span!(
+            "request",
+            user = ?self.user,
+            thread_id = %self.thread_id,
+            query_id = %self.query_id,
+            run_id = ?self.run_id,
+        )
+    }
+}
+
+/// Spawn a task whose log events carry the ids of `ctx`.
+///
+/// The task also logs to the subscriber that is current here, which matters when it isn't the
+/// global one, like in tests.
+pub fn spawn_in_ctx<F>(ctx: &RequestContext, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(future.instrument(ctx.span()).with_current_subscriber())
+}
+
+/// Like `spawn_in_ctx`, for blocking code that runs on a dedicated thread.
+pub fn spawn_blocking_in_ctx<F, R>(ctx: &RequestContext, f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = ctx.span();
+    let dispatch = tracing::dispatcher::get_default(Clone::clone);
+
+    tokio::task::spawn_blocking(move || {
+        tracing::dispatcher::with_default(&dispatch, || span.in_scope(f))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span,
+    };
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    use super::*;
+    use crate::agent::{exchange::Exchange, ExchangeTx};
+
+    #[derive(Default, Clone)]
+    struct Fields(HashMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_owned(), format!("{value:?}"));
+        }
+    }
+
+    /// A layer that records the fields of every event, alongside those of its spans.
+    #[derive(Default, Clone)]
+    struct Capture(Arc<Mutex<Vec<Fields>>>);
+
+    impl<S> Layer<S> for Capture
+    where
+        S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+
+            for span in ctx.event_scope(event).into_iter().flatten() {
+                if let Some(span_fields) = span.extensions().get::<Fields>() {
+                    fields.0.extend(span_fields.0.clone());
+                }
+            }
+
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    /// A run of the agent, which logs from background tasks like the real one.
+    async fn mock_run(ctx: &RequestContext) {
+        let title = spawn_in_ctx(ctx, async {
+            tracing::info!("generating title");
+            tokio::task::yield_now().await;
+            tracing::warn!("failed to generate conversation title");
+        });
+
+        let tokens = spawn_blocking_in_ctx(ctx, || {
+            tracing::debug!("splitting by token");
+        });
+
+        title.await.unwrap();
+        tokens.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spawned_tasks_carry_ids() {
+        let capture = Capture::default();
+        let _guard = tracing_subscriber::registry()
+            .with(capture.clone())
+            .set_default();
+
+        let ctx = RequestContext {
+            user: Some("alice".to_owned()),
+            thread_id: uuid::Uuid::new_v4(),
+            query_id: uuid::Uuid::new_v4(),
+            run_id: Some(uuid::Uuid::new_v4()),
+        };
+        mock_run(&ctx).await;
+
+        let events = capture.0.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        for Fields(fields) in events.iter() {
+            assert_eq!(fields["thread_id"], ctx.thread_id.to_string());
+            assert_eq!(fields["query_id"], ctx.query_id.to_string());
+            assert_eq!(fields["run_id"], format!("{:?}", ctx.run_id));
+            assert_eq!(fields["user"], "Some(\"alice\")");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_updates_carry_span() {
+        let capture = Capture::default();
+        let _guard = tracing_subscriber::registry()
+            .with(capture.clone())
+            .set_default();
+
+        let ctx = RequestContext {
+            user: None,
+            thread_id: uuid::Uuid::new_v4(),
+            query_id: uuid::Uuid::new_v4(),
+            run_id: None,
+        };
+        let exchange = Exchange::new(ctx.query_id, Default::default());
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        for with_span in [true, false] {
+            ExchangeTx::new(tx.clone(), with_span)
+                .send(exchange.clone())
+                .instrument(ctx.span())
+                .await
+                .unwrap();
+        }
+
+        // The receiver logs in the span that an update was sent from, if it was sent with one.
+        let update = rx.recv().await.unwrap();
+        update.span.in_scope(|| tracing::info!("received update"));
+
+        let update = rx.recv().await.unwrap();
+        assert!(update.span.is_disabled());
+        update.span.in_scope(|| tracing::info!("received update"));
+
+        let events = capture.0.lock().unwrap();
+        assert_eq!(events[0].0["thread_id"], ctx.thread_id.to_string());
+        assert!(!events[1].0.contains_key("thread_id"));
+    }
+}
@@ -0,0 +1,72 @@
+//! Deadlines of individual tool calls.
+//!
+//! A single call can hang, for example when `proc` reads a pathological file. Rather than waiting
+//! until the whole step times out, every call gets its own deadline and a child of the agent's
+//! cancellation token, so that it can be given up on while the rest of the query goes on.
+
+use std::{future::Future, time::Duration};
+
+use tokio_util::sync::CancellationToken;
+
+/// The deadline of a tool call, for a step that times out after `step_timeout`.
+///
+/// This is shorter than the step timeout, so that the step can still finish with partial results.
+pub fn tool_timeout(step_timeout: Duration) -> Duration {
+    step_timeout / 2
+}
+
+/// The result sent to the model for a file that could not be read in time.
+pub fn timed_out_message(path: &str) -> String {
+    format!("Timed out reading {path}, so its contents are not available.")
+}
+
+/// The error of a call that did not finish before its deadline.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+#[error("timed out after {0:?}")]
+pub struct TimedOut(pub Duration);
+
+/// Run the future returned by `call` until it finishes, or until `timeout` elapses.
+///
+/// `call` is given a child of `parent`, which is cancelled once this returns, so that work which
+/// isn't dropped with the future (like blocking tasks) can stop early.
+pub async fn with_deadline<F, Fut>(
+    parent: &CancellationToken,
+    timeout: Duration,
+    call: F,
+) -> Result<Fut::Output, TimedOut>
+where
+    F: FnOnce(CancellationToken) -> Fut,
+    Fut: Future,
+{
+    let token = parent.child_token();
+    let _guard = token.clone().drop_guard();
+
+    tokio::time::timeout(timeout, call(token))
+        .await
+        .map_err(|_| TimedOut(timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        let parent = CancellationToken::new();
+
+        let output = with_deadline(&parent, Duration::from_secs(10), |_| async { 42 }).await;
+        assert_eq!(output, Ok(42));
+
+        let mut child = None;
+        let output = with_deadline(&parent, Duration::from_millis(10), |token| {
+            child = Some(token);
+            std::future::pending::<()>()
+        })
+        .await;
+        assert_eq!(output, Err(TimedOut(Duration::from_millis(10))));
+
+        // Only the call that timed out is cancelled.
+        assert!(child.unwrap().is_cancelled());
+        assert!(!parent.is_cancelled());
+    }
+}
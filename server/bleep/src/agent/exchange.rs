@@ -1,21 +1,60 @@
-use crate::query::parser::SemanticQuery;
-use std::{fmt, mem};
+use crate::query::parser::{Literal, SemanticQuery};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt, mem,
+    ops::Range,
+    time::SystemTime,
+};
 
+use super::{line_map::MappedLines, tokens::Tokenizer, Action};
+use crate::{indexes::diagnostics::IndexWarnings, repo::RepoRef};
 use chrono::prelude::{DateTime, Utc};
+use once_cell::sync::Lazy;
 
 /// A continually updated conversation exchange.
 ///
 /// This contains the query from the user, the intermediate steps the model takes, and the final
 /// conclusion from the model alongside the answer, if any.
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+///
+/// The derived serde impls are wrapped by the ones below, which backfill `last_updated_at`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[serde(remote = "Self")]
 pub struct Exchange {
     pub id: uuid::Uuid,
+
+    /// The run that answered this exchange, shared with its analytics events and usage records.
+    ///
+    /// This is `None` for exchanges stored before runs were tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<uuid::Uuid>,
+
+    /// The repository that this exchange searched.
+    ///
+    /// A thread can move on to another repository, so this is recorded per exchange. It is `None`
+    /// for exchanges stored before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo_ref: Option<RepoRef>,
+
     pub query: SemanticQuery<'static>,
     pub answer: Option<String>,
     pub search_steps: Vec<SearchStep>,
+
+    /// The indices of the search steps that reused the result of an identical, earlier step,
+    /// rather than running their tool again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cached_steps: Vec<usize>,
+
     pub paths: Vec<String>,
     pub code_chunks: Vec<CodeChunk>,
 
+    /// The files in scope for this exchange, in the order they were first included.
+    ///
+    /// This is derived from the search steps as they run, so that the front-end does not have to
+    /// reconstruct it from step responses.
+    #[serde(default)]
+    pub context: Vec<ContextFile>,
+
     /// A specifically chosen "focused" code chunk.
     ///
     /// This is different from the `code_chunks` list, as focused code chunks also contain the full
@@ -30,16 +69,193 @@ pub struct Exchange {
     #[serde(skip_serializing_if = "Option::is_none")]
     response_timestamp: Option<DateTime<Utc>>,
 
+    /// The last time this exchange was advanced, serialized as an ISO-8601 string.
+    ///
+    /// Exchanges stored before this was recorded take it from their timestamps instead.
+    #[serde(with = "iso8601", default = "unrecorded")]
+    pub last_updated_at: SystemTime,
+
+    /// Time spent counting tokens to fit prompts into their budgets, in microseconds.
+    #[serde(default)]
+    pub tokenization_us: u64,
+
+    /// Successful search steps of this exchange, by tool name and normalized arguments.
+    ///
+    /// This lets us avoid re-running tool calls that the model repeats.
+    #[serde(skip)]
+    completed_steps: HashMap<(&'static str, String), SearchStep>,
+
+    /// Files that `proc` found to be irrelevant to this exchange, with the reason it gave.
+    ///
+    /// Later tool calls consult this, so that the model is warned before it re-reads them.
+    #[serde(skip)]
+    irrelevant_paths: HashMap<String, String>,
+
+    /// Where the answer came from, if it was not generated by the agent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<AnswerSource>,
+
+    /// Canned answers that were similar to, but not a close enough match for, the query.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub suggestions: Vec<Suggestion>,
+
+    /// A user-visible error that ended this exchange early.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// Files that secrets were redacted from before they were sent to the LLM.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redactions: Vec<Redaction>,
+
+    /// Pull requests that the answer refers to, out of those found by pull request searches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pr_citations: Vec<PullRequestCitation>,
+
+    /// Whether files were left unread, because the query's budget of files sent to the LLM was
+    /// spent.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub file_budget_exhausted: bool,
+
+    /// Whether this exchange was answered in the quick mode, from search snippets alone.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub quick: bool,
+
+    /// The quick exchange that this exchange answers in full, which it replaced in the thread.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_analysis_of: Option<uuid::Uuid>,
+
+    /// Whether this exchange is kept in the agent's history in full, however long the thread gets.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+
+    /// A summary of the files that could not be indexed, if enough of the repository's files
+    /// were affected for this exchange's answer to carry a caveat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_warnings: Option<IndexWarnings>,
+
+    /// How many of the repositories named in the query were left out, because the user can't
+    /// query them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub omitted_repos: Option<usize>,
+
+    /// The pages that the user linked in the query, and what became of each.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub external_context: Vec<ExternalFetch>,
+
+    /// How the query was answered, once it has been answered or has failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<AnswerOutcome>,
+
+    /// Whether the model was made to answer before it chose to, because it was stuck in a loop.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forced_answer: bool,
+
+    /// How certain the answer sounds, as given by `answer_confidence` when the exchange was
+    /// concluded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+
     conclusion: Option<String>,
 }
 
+/// An update to an exchange, with the span of the agent that sent it.
+///
+/// The span is disabled unless the agent was built with `AgentBuilder::with_span`, so that
+/// receivers can always enter it.
+#[derive(Debug)]
+pub struct InstrumentedExchange {
+    pub exchange: Exchange,
+    pub span: tracing::Span,
+}
+
+/// A page linked in a query, which was fetched to add its content to the context.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct ExternalFetch {
+    pub url: String,
+    pub status: FetchStatus,
+    /// The number of bytes that were read, which stops at `external::MAX_BYTES`.
+    pub bytes: usize,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum FetchStatus {
+    /// The content was added to the context as it is.
+    Fetched,
+    /// The content was too long, so a summary of it was added instead.
+    Summarized,
+    /// The host is not allowed, so the page was not fetched.
+    Disallowed,
+    Failed {
+        error: String,
+    },
+}
+
+/// The `last_updated_at` of exchanges stored before it was recorded, until it is backfilled.
+fn unrecorded() -> SystemTime {
+    SystemTime::UNIX_EPOCH
+}
+
+impl serde::Serialize for Exchange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Exchange::serialize(self, serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Exchange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut exchange = Exchange::deserialize(deserializer)?;
+
+        // The last activity of an exchange stored without it is its response, or its query if it
+        // has none, so that it keeps its place among others.
+        if exchange.last_updated_at == unrecorded() {
+            if let Some(time) = exchange.response_timestamp.or(exchange.query_timestamp) {
+                exchange.last_updated_at = time.into();
+            }
+        }
+
+        Ok(exchange)
+    }
+}
+
 impl Exchange {
     pub fn new(id: uuid::Uuid, query: SemanticQuery<'static>) -> Self {
+        let now = SystemTime::now();
+
         Self {
             id,
+            run_id: Some(uuid::Uuid::new_v4()),
+            repo_ref: None,
             query,
-            query_timestamp: Some(Utc::now()),
-            ..Default::default()
+            answer: None,
+            search_steps: Vec::new(),
+            cached_steps: Vec::new(),
+            paths: Vec::new(),
+            code_chunks: Vec::new(),
+            context: Vec::new(),
+            focused_chunk: None,
+            query_timestamp: Some(now.into()),
+            response_timestamp: None,
+            last_updated_at: now,
+            tokenization_us: 0,
+            completed_steps: HashMap::new(),
+            irrelevant_paths: HashMap::new(),
+            source: None,
+            suggestions: Vec::new(),
+            error: None,
+            redactions: Vec::new(),
+            pr_citations: Vec::new(),
+            file_budget_exhausted: false,
+            quick: false,
+            full_analysis_of: None,
+            pinned: false,
+            index_warnings: None,
+            omitted_repos: None,
+            external_context: Vec::new(),
+            outcome: None,
+            forced_answer: false,
+            confidence: None,
+            conclusion: None,
         }
     }
 
@@ -47,29 +263,343 @@ impl Exchange {
     ///
     /// An update should not result in fewer search results or fewer search steps.
     pub fn apply_update(&mut self, update: Update) {
+        self.apply_update_at(update, SystemTime::now());
+    }
+
+    /// Advance this exchange by a batch of updates, which all happen at the same time.
+    ///
+    /// This is for replaying many updates at once, which are then sent as a single state.
+    pub fn apply_updates(&mut self, updates: Vec<Update>) {
+        let now = SystemTime::now();
+        for update in updates {
+            self.apply_update_at(update, now);
+        }
+    }
+
+    /// Advance this exchange, as of the time `now`.
+    fn apply_update_at(&mut self, update: Update, now: SystemTime) {
+        self.last_updated_at = now;
+
         match update {
             Update::StartStep(search_step) => self.search_steps.push(search_step),
+            Update::ReuseStep(search_step) => {
+                self.cached_steps.push(self.search_steps.len());
+                self.search_steps.push(search_step);
+            }
             Update::ReplaceStep(search_step) => match (self.search_steps.last_mut(), search_step) {
                 (Some(l @ SearchStep::Path { .. }), r @ SearchStep::Path { .. }) => *l = r,
                 (Some(l @ SearchStep::Code { .. }), r @ SearchStep::Code { .. }) => *l = r,
+                (Some(l @ SearchStep::ListFiles { .. }), r @ SearchStep::ListFiles { .. }) => {
+                    *l = r
+                }
                 (Some(l @ SearchStep::Proc { .. }), r @ SearchStep::Proc { .. }) => *l = r,
+                (
+                    Some(l @ SearchStep::DependencyVulns { .. }),
+                    r @ SearchStep::DependencyVulns { .. },
+                ) => *l = r,
+                (
+                    Some(l @ SearchStep::RelatedFiles { .. }),
+                    r @ SearchStep::RelatedFiles { .. },
+                ) => *l = r,
+                (Some(l @ SearchStep::ConfigAudit { .. }), r @ SearchStep::ConfigAudit { .. }) => {
+                    *l = r
+                }
+                (Some(l @ SearchStep::Changelog { .. }), r @ SearchStep::Changelog { .. }) => {
+                    *l = r
+                }
+                (
+                    Some(l @ SearchStep::ChangelogDiff { .. }),
+                    r @ SearchStep::ChangelogDiff { .. },
+                ) => *l = r,
+                (
+                    Some(l @ SearchStep::WeeklyDigest { .. }),
+                    r @ SearchStep::WeeklyDigest { .. },
+                ) => *l = r,
+                (Some(l @ SearchStep::Plan { .. }), r @ SearchStep::Plan { .. }) => *l = r,
+                (Some(l @ SearchStep::Prs { .. }), r @ SearchStep::Prs { .. }) => *l = r,
+                (Some(l @ SearchStep::Format { .. }), r @ SearchStep::Format { .. }) => *l = r,
+                (Some(l @ SearchStep::DeadCode { .. }), r @ SearchStep::DeadCode { .. }) => *l = r,
+                (
+                    Some(l @ SearchStep::DeprecatedUsage { .. }),
+                    r @ SearchStep::DeprecatedUsage { .. },
+                ) => *l = r,
+                (
+                    Some(l @ SearchStep::UpgradeSuggestions { .. }),
+                    r @ SearchStep::UpgradeSuggestions { .. },
+                ) => *l = r,
+                (Some(l @ SearchStep::TODOs { .. }), r @ SearchStep::TODOs { .. }) => *l = r,
+                (Some(l @ SearchStep::Allocations { .. }), r @ SearchStep::Allocations { .. }) => {
+                    *l = r
+                }
+                (Some(l @ SearchStep::FindSimilar { .. }), r @ SearchStep::FindSimilar { .. }) => {
+                    *l = r
+                }
+                (Some(l @ SearchStep::Symbol { .. }), r @ SearchStep::Symbol { .. }) => *l = r,
                 _ => panic!("Tried to replace a step that was not found"),
             },
             Update::Article(full_text) => {
                 *self.answer.get_or_insert_with(String::new) = full_text;
+                self.cite_pull_requests();
             }
             Update::Conclude(conclusion) => {
-                self.response_timestamp = Some(Utc::now());
+                self.response_timestamp = Some(now.into());
                 self.conclusion = Some(conclusion);
+                self.confidence = Some(self.answer_confidence());
+            }
+            Update::Error(message) => {
+                self.response_timestamp = Some(now.into());
+                self.error = Some(message);
+            }
+        }
+    }
+
+    /// Find an earlier, successful search step of this exchange with the same tool call key.
+    ///
+    /// This is to be reused with `Update::ReuseStep`.
+    pub fn cached_step(&self, key: &(&'static str, String)) -> Option<SearchStep> {
+        self.completed_steps.get(key).cloned()
+    }
+
+    /// Whether the search step at `index` reused the result of an identical, earlier step.
+    pub fn is_cached(&self, index: usize) -> bool {
+        self.cached_steps.contains(&index)
+    }
+
+    /// The response of the search step at `index`, as it is shown to the LLM.
+    ///
+    /// Reused results say so, so that the model doesn't keep repeating the same call.
+    pub fn step_response(&self, index: usize) -> Option<String> {
+        const CACHED_PREFIX: &str =
+            "This function was already called with the same arguments. The previous result was:";
+
+        let response = self.search_steps.get(index)?.get_response();
+        if self.is_cached(index) {
+            Some(format!("{CACHED_PREFIX}\n\n{response}"))
+        } else {
+            Some(response)
+        }
+    }
+
+    /// The responses of all search steps, in order, as they are shown to the LLM.
+    pub fn step_responses(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.search_steps.len()).filter_map(|i| self.step_response(i))
+    }
+
+    /// Remember the last search step of this exchange as the result of a tool call.
+    pub fn cache_last_step(&mut self, key: (&'static str, String)) {
+        if let Some(step) = self.search_steps.last() {
+            self.completed_steps.insert(key, step.clone());
+        }
+    }
+
+    /// Keep this exchange in the agent's history, so that its messages are never trimmed.
+    pub fn mark_as_pinned(&mut self) {
+        self.pinned = true;
+    }
+
+    /// Remember that `path` was examined and found irrelevant to this exchange.
+    pub fn mark_irrelevant(&mut self, path: &str, reason: &str) {
+        self.irrelevant_paths
+            .insert(path.to_owned(), reason.to_owned());
+    }
+
+    /// Why `path` was found irrelevant to this exchange, if it was.
+    pub fn irrelevant_reason(&self, path: &str) -> Option<&str> {
+        self.irrelevant_paths.get(path).map(String::as_str)
+    }
+
+    /// The files that `proc` gave up reading in this exchange, because they took too long.
+    pub fn timed_out_paths(&self) -> Vec<&str> {
+        let mut paths = self
+            .search_steps
+            .iter()
+            .flat_map(|s| match s {
+                SearchStep::Proc { timed_out, .. } => timed_out.as_slice(),
+                _ => &[],
+            })
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Record that `path` is in scope for this exchange, because of `source`.
+    ///
+    /// `lines` are 1-based and end-exclusive, and are merged with the lines already known for the
+    /// file.
+    pub fn include_context(
+        &mut self,
+        path: &str,
+        source: ContextSource,
+        lines: &[Range<usize>],
+        sent_to_llm: bool,
+    ) {
+        let file = match self.context.iter().position(|f| f.path == path) {
+            Some(i) => &mut self.context[i],
+            None => {
+                self.context.push(ContextFile {
+                    path: path.to_owned(),
+                    sources: Vec::new(),
+                    lines: Vec::new(),
+                    sent_to_llm: false,
+                });
+                self.context.last_mut().unwrap()
+            }
+        };
+
+        if !file.sources.contains(&source) {
+            file.sources.push(source);
+        }
+
+        file.lines
+            .extend(lines.iter().filter(|r| !r.is_empty()).cloned());
+        file.lines = merge_ranges(mem::take(&mut file.lines));
+        file.sent_to_llm |= sent_to_llm;
+    }
+
+    /// Record that secrets were redacted from a file, merging with earlier redactions of it.
+    pub fn record_redaction(&mut self, redaction: Redaction) {
+        match self
+            .redactions
+            .iter_mut()
+            .find(|r| r.path == redaction.path)
+        {
+            Some(existing) => {
+                existing.kinds.extend(redaction.kinds);
+                existing.kinds.sort();
+                existing.kinds.dedup();
             }
+            None => self.redactions.push(redaction),
         }
     }
 
+    /// Replace matches of any of `patterns` with `[REDACTED]`, in the query, the search steps, the
+    /// cited code and the answer of this exchange.
+    pub fn anonymize(&mut self, patterns: &[regex::Regex]) {
+        let redact = |text: &mut String| {
+            for pattern in patterns {
+                if let Cow::Owned(redacted) = pattern.replace_all(text, "[REDACTED]") {
+                    *text = redacted;
+                }
+            }
+        };
+
+        if let Some(Literal::Plain(target) | Literal::Regex(target)) = &mut self.query.target {
+            let mut text = target.to_string();
+            redact(&mut text);
+            *target = text.into();
+        }
+
+        for step in &mut self.search_steps {
+            step.anonymize(redact);
+        }
+
+        for fetch in &mut self.external_context {
+            redact(&mut fetch.url);
+            if let FetchStatus::Failed { error } = &mut fetch.status {
+                redact(error);
+            }
+        }
+
+        for chunk in &mut self.code_chunks {
+            redact(&mut chunk.snippet);
+        }
+
+        for citation in &mut self.pr_citations {
+            redact(&mut citation.title);
+            redact(&mut citation.url);
+        }
+
+        self.answer.iter_mut().for_each(redact);
+        self.conclusion.iter_mut().for_each(redact);
+        self.error.iter_mut().for_each(redact);
+    }
+
+    /// Collect the pull requests that the answer refers to, by link or by `#number`.
+    fn cite_pull_requests(&mut self) {
+        let Some(answer) = &self.answer else {
+            return;
+        };
+
+        let numbers = lazy_regex::regex!(r"(?:^|[^\w&])#(\d+)\b")
+            .captures_iter(answer)
+            .filter_map(|c| c[1].parse::<i64>().ok())
+            .collect::<HashSet<_>>();
+
+        let mut citations: Vec<PullRequestCitation> = Vec::new();
+        for step in &self.search_steps {
+            let SearchStep::Prs { pull_requests, .. } = step else {
+                continue;
+            };
+
+            for pr in pull_requests {
+                let cited = answer.contains(&pr.url) || numbers.contains(&pr.number);
+                if cited && !citations.iter().any(|c| c.number == pr.number) {
+                    citations.push(PullRequestCitation {
+                        number: pr.number,
+                        title: pr.title.clone(),
+                        url: pr.url.clone(),
+                    });
+                }
+            }
+        }
+
+        self.pr_citations = citations;
+    }
+
     /// Get the query associated with this exchange, if it has been made.
     pub fn query(&self) -> Option<String> {
         self.query.target().map(|q| q.to_string())
     }
 
+    /// Classify the query of this exchange by keywords, to choose a search strategy.
+    ///
+    /// Queries that mention an error or a bug are `Debug` queries, whatever their phrasing.
+    pub fn query_type(&self) -> QueryType {
+        const DEBUG_WORDS: &[&str] = &[
+            "bug",
+            "bugs",
+            "crash",
+            "crashes",
+            "error",
+            "errors",
+            "exception",
+            "panic",
+            "panicked",
+            "traceback",
+        ];
+        const PHRASES: &[(QueryType, &[&str])] = &[
+            (QueryType::HowTo, &["how to", "how do", "how can"]),
+            (
+                QueryType::WhereIs,
+                &["where is", "where are", "where do", "which file"],
+            ),
+            (QueryType::WhatIs, &["what is", "what are", "what does"]),
+        ];
+
+        let Some(query) = self.query() else {
+            return QueryType::Other;
+        };
+
+        let words = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+
+        if words.iter().any(|w| DEBUG_WORDS.contains(&w.as_str())) {
+            return QueryType::Debug;
+        }
+
+        let text = format!(" {} ", words.join(" "));
+        PHRASES
+            .iter()
+            .find(|(_, phrases)| phrases.iter().any(|p| text.contains(&format!(" {p} "))))
+            .map_or(QueryType::Other, |(ty, _)| *ty)
+    }
+
     /// Get the answer and conclusion associated with this exchange, if a conclusion has been made.
     ///
     /// This returns a tuple of `(full_text, conclusion)`.
@@ -80,14 +610,111 @@ impl Exchange {
         }
     }
 
+    /// Estimate how certain the model was of its answer, from 0.0 for an answer full of hedging
+    /// to 1.0 for an answer without any.
+    ///
+    /// This counts hedging phrases in the answer and its conclusion, leaving out code blocks.
+    /// Exchanges that have not been concluded have a confidence of 0.0.
+    pub fn answer_confidence(&self) -> f32 {
+        const HEDGING_PHRASES: &[&[&str]] = &[
+            &["might"],
+            &["could"],
+            &["possibly"],
+            &["perhaps"],
+            &["probably"],
+            &["i'm", "not", "sure"],
+            &["i", "am", "not", "sure"],
+            &["it", "seems"],
+        ];
+        /// How much each hedging phrase lowers the confidence.
+        const HEDGE_WEIGHT: f32 = 0.25;
+
+        let Some((answer, conclusion)) = self.answer() else {
+            return 0.0;
+        };
+
+        let mut in_code = false;
+        let prose = answer
+            .lines()
+            .chain(conclusion.lines())
+            .filter(|line| {
+                if line.trim_start().starts_with("```") {
+                    in_code = !in_code;
+                    return false;
+                }
+                !in_code
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+            .replace('\u{2019}', "'")
+            .to_lowercase();
+
+        let words = prose
+            .split(|c: char| !c.is_alphanumeric() && c != '\'')
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+
+        let hedges = HEDGING_PHRASES
+            .iter()
+            .map(|phrase| words.windows(phrase.len()).filter(|w| w == phrase).count())
+            .sum::<usize>();
+
+        1.0 / (1.0 + HEDGE_WEIGHT * hedges as f32)
+    }
+
+    /// Summarize the response of the search step at `index`, for compact thread previews.
+    ///
+    /// This is the first sentence of the response, on a single line, and cut off at 150
+    /// characters.
+    pub fn step_response_summary(&self, index: usize) -> Option<String> {
+        const MAX_SUMMARY_CHARS: usize = 150;
+
+        let response = self.step_response(index)?;
+        let sentence = match response.find('.') {
+            Some(i) => &response[..=i],
+            None => &response,
+        };
+
+        Some(
+            sentence
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .chars()
+                .take(MAX_SUMMARY_CHARS)
+                .collect(),
+        )
+    }
+
+    /// Render the search steps of this exchange as a Markdown table, one row per step.
+    pub fn serialize_search_steps_as_table(&self) -> String {
+        let mut table =
+            "| Type | Query | Files Found | Tokens |\n| --- | --- | --- | --- |\n".to_owned();
+
+        for step in &self.search_steps {
+            table += &format!(
+                "| {} | {} | {} | {} |\n",
+                step.kind(),
+                markdown_cell(&step.query_text()),
+                step.files_found(),
+                step.get_token_count(),
+            );
+        }
+
+        table
+    }
+
     /// Return a copy of this exchange, with all function call responses redacted.
     ///
     /// This is used to reduce the size of an exchange when we send it over the wire, by removing
     /// data that the front-end does not use.
+    ///
+    /// Code chunks whose files were renamed or deleted are kept, so that stale citations can be
+    /// flagged.
     pub fn compressed(&self) -> Self {
         let mut ex = self.clone();
 
-        ex.code_chunks.clear();
+        ex.code_chunks.retain(|c| c.moved_to.is_some() || c.deleted);
         ex.paths.clear();
         ex.search_steps = mem::take(&mut ex.search_steps)
             .into_iter()
@@ -96,9 +723,42 @@ impl Exchange {
 
         ex
     }
+
+    /// The parts of this exchange that `Eq` and `Hash` look at.
+    fn identity(&self) -> (Option<String>, Vec<String>) {
+        (self.query(), self.step_responses().collect())
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+/// Exchanges are equal when they answer the same query with the same search step responses.
+///
+/// Ids and timestamps are ignored, so that identical exchanges produced by different tool paths
+/// are duplicates.
+impl PartialEq for Exchange {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Exchange {}
+
+impl std::hash::Hash for Exchange {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.identity().hash(state);
+    }
+}
+
+/// The kind of question a query asks, as classified by [`Exchange::query_type`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryType {
+    HowTo,
+    WhatIs,
+    WhereIs,
+    Debug,
+    Other,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase", tag = "type", content = "content")]
 #[non_exhaustive]
 pub enum SearchStep {
@@ -110,10 +770,144 @@ pub enum SearchStep {
         query: String,
         response: String,
     },
+    #[serde(rename = "list_files")]
+    ListFiles {
+        pattern: String,
+        /// The matching paths, sorted alphabetically.
+        paths: Vec<String>,
+    },
     Proc {
         query: String,
         paths: Vec<String>,
         response: String,
+
+        /// The 1-based, end-exclusive line ranges of the processed files that were shown to the
+        /// model.
+        ///
+        /// Each part of a file that was read separately has a range of its own. This can be used
+        /// to highlight the regions of a file that were actually read.
+        #[serde(default)]
+        lines_read: Vec<Range<usize>>,
+
+        /// The files that could not be read before their deadline, which the response has no
+        /// content for.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        timed_out: Vec<String>,
+    },
+    #[serde(rename = "dependency_vulns")]
+    DependencyVulns {
+        /// The lockfiles that dependencies were read from.
+        lockfiles: Vec<String>,
+        packages: Vec<VulnerablePackage>,
+        /// Dependencies that could not be checked, as `name@version`.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        unchecked: Vec<String>,
+    },
+    #[serde(rename = "dead_code")]
+    DeadCode {
+        /// Unused functions and types, ordered by path and line.
+        dead_symbols: Vec<DeadSymbol>,
+    },
+    #[serde(rename = "deprecated_usage")]
+    DeprecatedUsage {
+        package: String,
+        /// The number of deprecated APIs of the package that were searched for, which is 0 if no
+        /// deprecations are known for it.
+        apis: usize,
+        /// Calls to deprecated APIs, ordered by path and line.
+        calls: Vec<DeprecatedCall>,
+    },
+    #[serde(rename = "todos")]
+    TODOs {
+        path: String,
+        /// The tagged comments of the file, grouped by tag and ordered by line.
+        todos: Vec<Todo>,
+    },
+    #[serde(rename = "allocations")]
+    Allocations {
+        path: String,
+        /// The language of the file, or `None` if allocations can't be found in its language.
+        language: Option<String>,
+        /// The calls and macros that allocate on the heap, ordered by line.
+        sites: Vec<AllocationSite>,
+    },
+    #[serde(rename = "find_similar")]
+    FindSimilar {
+        path: String,
+        /// The files most similar to `path`, most similar first.
+        similar: Vec<SimilarFile>,
+    },
+    #[serde(rename = "symbol")]
+    Symbol {
+        query: String,
+        /// The matching functions and types, best match first, with definitions that share a
+        /// name next to each other.
+        symbols: Vec<SymbolMatch>,
+    },
+    #[serde(rename = "upgrade_suggestions")]
+    UpgradeSuggestions {
+        dep_name: String,
+        /// The lowest version that the manifest allows, or `None` if it has no such dependency.
+        current_version: Option<String>,
+        /// The latest release of the next major version, if there is one.
+        target_version: Option<String>,
+        /// The breaking changes between the two versions, which affect the code found so far
+        /// first.
+        breaking_changes: Vec<BreakingChange>,
+        response: String,
+    },
+    #[serde(rename = "config_audit")]
+    ConfigAudit {
+        path: String,
+        /// The issues found, most severe first.
+        issues: Vec<ConfigIssue>,
+    },
+    Changelog {
+        /// The revision that the changelog starts after, or `None` for the most recent commits.
+        since: Option<String>,
+        breaking_changes: Vec<BreakingChange>,
+        response: String,
+    },
+    #[serde(rename = "changelog_diff")]
+    ChangelogDiff {
+        v1: String,
+        v2: String,
+        /// The diff from the changelog section of `v1` to that of `v2`, or `None` if either is
+        /// missing or they are identical.
+        diff: Option<String>,
+        response: String,
+    },
+    #[serde(rename = "weekly_digest")]
+    WeeklyDigest {
+        /// The start of the week that the digest covers, which ends when it was written.
+        since: DateTime<Utc>,
+        /// The number of commits made in the week.
+        commits: usize,
+        response: String,
+    },
+    Plan {
+        goal: String,
+        /// The function calls that the goal was broken down into, in the order they are made.
+        actions: Vec<Action>,
+    },
+    Prs {
+        query: String,
+        /// The best matching open pull requests, best first.
+        pull_requests: Vec<PullRequestSummary>,
+    },
+    Format {
+        path: String,
+        /// The formatter that the file was checked with, or `None` if none is available for its
+        /// language.
+        formatter: Option<String>,
+        /// The changes that the formatter would make, or `None` if there are none.
+        diff: Option<String>,
+    },
+    #[serde(rename = "related_files")]
+    RelatedFiles {
+        paths: Vec<String>,
+        /// Files that import or are imported by `paths`, followed by semantically similar files.
+        related: Vec<String>,
     },
 }
 
@@ -131,23 +925,710 @@ impl SearchStep {
                 query: query.clone(),
                 response: "[hidden, compressed]".into(),
             },
-            Self::Proc { query, paths, .. } => Self::Proc {
+            Self::ListFiles { pattern, .. } => Self::ListFiles {
+                pattern: pattern.clone(),
+                paths: Vec::new(),
+            },
+            Self::Proc {
+                query,
+                paths,
+                lines_read,
+                timed_out,
+                ..
+            } => Self::Proc {
                 query: query.clone(),
                 paths: paths.clone(),
                 response: "[hidden, compressed]".into(),
+                lines_read: lines_read.clone(),
+                timed_out: timed_out.clone(),
+            },
+            Self::DependencyVulns {
+                lockfiles,
+                packages,
+                unchecked,
+            } => Self::DependencyVulns {
+                lockfiles: lockfiles.clone(),
+                packages: packages.clone(),
+                unchecked: unchecked.clone(),
+            },
+            Self::ConfigAudit { path, issues } => Self::ConfigAudit {
+                path: path.clone(),
+                issues: issues.clone(),
+            },
+            Self::TODOs { path, todos } => Self::TODOs {
+                path: path.clone(),
+                todos: todos.clone(),
+            },
+            Self::Allocations {
+                path,
+                language,
+                sites,
+            } => Self::Allocations {
+                path: path.clone(),
+                language: language.clone(),
+                sites: sites.clone(),
+            },
+            Self::Symbol { query, symbols } => Self::Symbol {
+                query: query.clone(),
+                symbols: symbols.clone(),
+            },
+            Self::FindSimilar { path, similar } => Self::FindSimilar {
+                path: path.clone(),
+                similar: similar.clone(),
+            },
+            Self::Changelog {
+                since,
+                breaking_changes,
+                ..
+            } => Self::Changelog {
+                since: since.clone(),
+                breaking_changes: breaking_changes.clone(),
+                response: "[hidden, compressed]".into(),
+            },
+            Self::ChangelogDiff { v1, v2, .. } => Self::ChangelogDiff {
+                v1: v1.clone(),
+                v2: v2.clone(),
+                diff: None,
+                response: "[hidden, compressed]".into(),
+            },
+            Self::WeeklyDigest { since, commits, .. } => Self::WeeklyDigest {
+                since: *since,
+                commits: *commits,
+                response: "[hidden, compressed]".into(),
+            },
+            Self::Plan { .. } => self.clone(),
+            Self::Prs {
+                query,
+                pull_requests,
+            } => Self::Prs {
+                query: query.clone(),
+                pull_requests: pull_requests.clone(),
+            },
+            Self::Format {
+                path, formatter, ..
+            } => Self::Format {
+                path: path.clone(),
+                formatter: formatter.clone(),
+                diff: None,
+            },
+            Self::RelatedFiles { paths, .. } => Self::RelatedFiles {
+                paths: paths.clone(),
+                related: Vec::new(),
+            },
+            Self::DeadCode { dead_symbols } => Self::DeadCode {
+                dead_symbols: dead_symbols.clone(),
+            },
+            Self::DeprecatedUsage {
+                package,
+                apis,
+                calls,
+            } => Self::DeprecatedUsage {
+                package: package.clone(),
+                apis: *apis,
+                calls: calls.clone(),
+            },
+            Self::UpgradeSuggestions {
+                dep_name,
+                current_version,
+                target_version,
+                breaking_changes,
+                ..
+            } => Self::UpgradeSuggestions {
+                dep_name: dep_name.clone(),
+                current_version: current_version.clone(),
+                target_version: target_version.clone(),
+                breaking_changes: breaking_changes.clone(),
+                response: "[hidden, compressed]".into(),
             },
         }
     }
 
+    /// Apply `redact` to the free text of this step: queries, responses, and any text that was
+    /// read from the repository or written by users.
+    fn anonymize(&mut self, redact: impl Fn(&mut String)) {
+        let redact_changes = |changes: &mut Vec<BreakingChange>| {
+            for change in changes {
+                redact(&mut change.short_desc);
+                change.migration_hint.iter_mut().for_each(&redact);
+            }
+        };
+
+        match self {
+            Self::Path {
+                query, response, ..
+            }
+            | Self::Code {
+                query, response, ..
+            }
+            | Self::Proc {
+                query, response, ..
+            } => {
+                redact(query);
+                redact(response);
+            }
+            Self::ListFiles { pattern, .. } => redact(pattern),
+            Self::Changelog {
+                breaking_changes,
+                response,
+                ..
+            } => {
+                redact_changes(breaking_changes);
+                redact(response);
+            }
+            Self::WeeklyDigest { response, .. } => redact(response),
+            Self::ChangelogDiff { diff, response, .. } => {
+                diff.iter_mut().for_each(&redact);
+                redact(response);
+            }
+            Self::UpgradeSuggestions {
+                dep_name,
+                breaking_changes,
+                response,
+                ..
+            } => {
+                redact(dep_name);
+                redact_changes(breaking_changes);
+                redact(response);
+            }
+            Self::Prs {
+                query,
+                pull_requests,
+                ..
+            } => {
+                redact(query);
+                for pr in pull_requests {
+                    redact(&mut pr.title);
+                    redact(&mut pr.author);
+                    redact(&mut pr.url);
+                }
+            }
+            Self::Symbol { query, .. } => redact(query),
+            Self::DeprecatedUsage { package, .. } => redact(package),
+            // The arguments of planned calls are derived from the goal, so they are left out too.
+            Self::Plan { goal, actions, .. } => {
+                redact(goal);
+                actions.clear();
+            }
+            Self::Format { diff, .. } => diff.iter_mut().for_each(&redact),
+            Self::TODOs { todos, .. } => {
+                for todo in todos {
+                    redact(&mut todo.message);
+                    redact(&mut todo.context);
+                }
+            }
+            Self::ConfigAudit { issues, .. } => {
+                for issue in issues {
+                    redact(&mut issue.field);
+                    redact(&mut issue.description);
+                }
+            }
+            Self::Allocations { sites, .. } => {
+                for site in sites {
+                    redact(&mut site.expression);
+                }
+            }
+            // The other steps only list paths, packages and identifiers from the code.
+            Self::DependencyVulns { .. } | Self::RelatedFiles { .. } | Self::DeadCode { .. } => {}
+            Self::FindSimilar { .. } => {}
+        }
+    }
+
     pub fn get_response(&self) -> String {
         match self {
             Self::Path { response, .. } => response.clone(),
             Self::Code { response, .. } => response.clone(),
+            Self::ListFiles { paths, .. } => paths.join("\n"),
             Self::Proc { response, .. } => response.clone(),
+            Self::DependencyVulns {
+                lockfiles,
+                packages,
+                unchecked,
+                ..
+            } => {
+                let mut response = if lockfiles.is_empty() {
+                    "No lockfile was found at the root of the repository.".to_owned()
+                } else if packages.is_empty() {
+                    format!(
+                        "No known vulnerable dependencies were found in {}.",
+                        lockfiles.join(", ")
+                    )
+                } else {
+                    packages
+                        .iter()
+                        .map(|p| format!("{}@{}: {}", p.name, p.version, p.cve_ids.join(", ")))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+
+                if !unchecked.is_empty() {
+                    response += &format!(
+                        "\nThese dependencies could not be checked: {}",
+                        unchecked.join(", ")
+                    );
+                }
+
+                response
+            }
+            Self::ConfigAudit { path, issues, .. } => {
+                if issues.is_empty() {
+                    format!("No issues were found in {path}.")
+                } else {
+                    issues
+                        .iter()
+                        .map(|i| format!("{} {}: {}", i.severity, i.field, i.description))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Self::TODOs { path, todos, .. } => {
+                if todos.is_empty() {
+                    format!("No TODO, FIXME, HACK or XXX comments were found in {path}.")
+                } else {
+                    todos
+                        .iter()
+                        .map(|t| {
+                            format!(
+                                "{}:{} {}: {}\n```\n{}\n```",
+                                path, t.line, t.tag, t.message, t.context
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n\n")
+                }
+            }
+            Self::Allocations {
+                path,
+                language,
+                sites,
+                ..
+            } => {
+                if language.is_none() {
+                    format!("Allocations can only be found in Rust files, and {path} is not one.")
+                } else if sites.is_empty() {
+                    format!("No heap allocations were found in {path}.")
+                } else {
+                    sites
+                        .iter()
+                        .map(|s| format!("{path}:{} {} {}", s.line, s.frequency, s.expression))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Self::Symbol { query, symbols, .. } => {
+                if symbols.is_empty() {
+                    format!("No functions or types matching {query} were found.")
+                } else {
+                    // Definitions that share a name are next to each other, and listed under it.
+                    let mut groups = Vec::<Vec<&SymbolMatch>>::new();
+                    for symbol in symbols {
+                        match groups.last_mut() {
+                            Some(group) if group[0].name == symbol.name => group.push(symbol),
+                            _ => groups.push(vec![symbol]),
+                        }
+                    }
+
+                    groups
+                        .iter()
+                        .map(|group| match group.as_slice() {
+                            [s] => format!("{} ({}) {}:{}", s.name, s.kind, s.path, s.line),
+                            _ => {
+                                let definitions = group
+                                    .iter()
+                                    .map(|s| format!("  {}:{} ({})", s.path, s.line, s.kind))
+                                    .collect::<Vec<_>>()
+                                    .join("\n");
+                                let name = &group[0].name;
+                                format!("{name}, defined {} times:\n{definitions}", group.len())
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Self::FindSimilar { path, similar, .. } => {
+                if similar.is_empty() {
+                    format!("No files similar to {path} were found.")
+                } else {
+                    similar
+                        .iter()
+                        .map(|f| format!("{} ({:.2})", f.path, f.similarity))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Self::Changelog { response, .. } => response.clone(),
+            Self::ChangelogDiff { response, .. } => response.clone(),
+            Self::WeeklyDigest { response, .. } => response.clone(),
+            Self::UpgradeSuggestions { response, .. } => response.clone(),
+            Self::Plan { goal, actions, .. } => {
+                if actions.is_empty() {
+                    format!("No plan could be made for: {goal}")
+                } else {
+                    let calls = actions
+                        .iter()
+                        .filter_map(Action::to_gpt)
+                        .enumerate()
+                        .map(|(i, call)| {
+                            let name = call.name.unwrap_or_default();
+                            format!("{}. functions.{name}: {}", i + 1, call.arguments)
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n");
+
+                    format!("These functions are called next:\n{calls}")
+                }
+            }
+            Self::Prs {
+                query,
+                pull_requests,
+                ..
+            } => {
+                if pull_requests.is_empty() {
+                    format!("No open pull requests match {query}.")
+                } else {
+                    pull_requests
+                        .iter()
+                        .map(PullRequestSummary::to_markdown)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Self::Format {
+                path,
+                formatter,
+                diff,
+                ..
+            } => match (formatter, diff) {
+                (None, _) => {
+                    format!("No formatter is available for {path}, so it could not be checked.")
+                }
+                (Some(formatter), None) => format!("{path} is already formatted by {formatter}."),
+                (Some(formatter), Some(diff)) => {
+                    format!("{formatter} would make these changes to {path}:\n\n```diff\n{diff}```")
+                }
+            },
+            Self::RelatedFiles { paths, related, .. } => {
+                if related.is_empty() {
+                    format!("No files related to {} were found.", paths.join(", "))
+                } else {
+                    related.join("\n")
+                }
+            }
+            Self::DeadCode { dead_symbols, .. } => {
+                if dead_symbols.is_empty() {
+                    "No unused functions or types were found.".to_owned()
+                } else {
+                    dead_symbols
+                        .iter()
+                        .map(|s| format!("{}:{} {}", s.path, s.line, s.name))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+            Self::DeprecatedUsage {
+                package,
+                apis,
+                calls,
+                ..
+            } => {
+                if *apis == 0 {
+                    format!("No deprecated APIs are known for {package}.")
+                } else if calls.is_empty() {
+                    format!("No calls to the {apis} deprecated APIs of {package} were found.")
+                } else {
+                    calls
+                        .iter()
+                        .map(|c| match &c.replacement {
+                            Some(r) => format!("{}:{} {} (use {r})", c.path, c.line, c.api),
+                            None => format!("{}:{} {}", c.path, c.line, c.api),
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            }
+        }
+    }
+
+    /// The number of tokens in this step's response.
+    pub fn get_token_count(&self) -> usize {
+        static TOKENIZER: Lazy<Tokenizer> =
+            Lazy::new(|| Tokenizer::new("gpt-3.5-turbo").expect("failed to load tokenizer"));
+
+        TOKENIZER.count(&self.get_response())
+    }
+
+    /// The name of this step's function, as it is serialized.
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Path { .. } => "path",
+            Self::Code { .. } => "code",
+            Self::ListFiles { .. } => "list_files",
+            Self::Proc { .. } => "proc",
+            Self::DependencyVulns { .. } => "dependency_vulns",
+            Self::ConfigAudit { .. } => "config_audit",
+            Self::Changelog { .. } => "changelog",
+            Self::ChangelogDiff { .. } => "changelog_diff",
+            Self::WeeklyDigest { .. } => "weekly_digest",
+            Self::Plan { .. } => "plan",
+            Self::Prs { .. } => "prs",
+            Self::Format { .. } => "format",
+            Self::RelatedFiles { .. } => "related_files",
+            Self::DeadCode { .. } => "dead_code",
+            Self::DeprecatedUsage { .. } => "deprecated_usage",
+            Self::UpgradeSuggestions { .. } => "upgrade_suggestions",
+            Self::TODOs { .. } => "todos",
+            Self::Allocations { .. } => "allocations",
+            Self::FindSimilar { .. } => "find_similar",
+            Self::Symbol { .. } => "symbol",
+        }
+    }
+
+    /// The argument this step was called with.
+    fn query_text(&self) -> String {
+        match self {
+            Self::Path { query, .. }
+            | Self::Code { query, .. }
+            | Self::Proc { query, .. }
+            | Self::Prs { query, .. }
+            | Self::Symbol { query, .. } => query.clone(),
+            Self::Plan { goal, .. } => goal.clone(),
+            Self::ListFiles { pattern, .. } => pattern.clone(),
+            Self::DependencyVulns { lockfiles, .. } => lockfiles.join(", "),
+            Self::ConfigAudit { path, .. }
+            | Self::Format { path, .. }
+            | Self::TODOs { path, .. }
+            | Self::Allocations { path, .. }
+            | Self::FindSimilar { path, .. } => path.clone(),
+            Self::Changelog { since, .. } => since.clone().unwrap_or_default(),
+            Self::ChangelogDiff { v1, v2, .. } => format!("{v1}, {v2}"),
+            Self::WeeklyDigest { since, .. } => since.format("%Y-%m-%d").to_string(),
+            Self::RelatedFiles { paths, .. } => paths.join(", "),
+            Self::DeadCode { .. } => String::new(),
+            Self::DeprecatedUsage { package, .. } => package.clone(),
+            Self::UpgradeSuggestions { dep_name, .. } => dep_name.clone(),
+        }
+    }
+
+    /// The number of distinct files this step found.
+    fn files_found(&self) -> usize {
+        match self {
+            // Path responses list one `alias: path` per line.
+            Self::Path { response, .. } => response.lines().filter(|l| !l.is_empty()).count(),
+            // Code responses are blank line separated chunks, each starting with `alias: path`,
+            // optionally followed by the chunk's symbol path in parentheses.
+            Self::Code { response, .. } => response
+                .split("\n\n")
+                .filter_map(|chunk| {
+                    let header = chunk.lines().next()?;
+                    let (alias, path) = header.split_once(": ")?;
+                    let path = path.split_once(" (").map_or(path, |(path, _)| path);
+                    alias.parse::<usize>().ok().map(|_| path)
+                })
+                .collect::<HashSet<_>>()
+                .len(),
+            Self::ListFiles { paths, .. } => paths.len(),
+            Self::Proc { paths, .. } => paths.len(),
+            Self::DependencyVulns { lockfiles, .. } => lockfiles.len(),
+            Self::ConfigAudit { .. }
+            | Self::Format { .. }
+            | Self::TODOs { .. }
+            | Self::Allocations { .. } => 1,
+            Self::Changelog { .. }
+            | Self::ChangelogDiff { .. }
+            | Self::WeeklyDigest { .. }
+            | Self::UpgradeSuggestions { .. }
+            | Self::Plan { .. } => 0,
+            Self::Prs { .. } => 0,
+            Self::RelatedFiles { related, .. } => related.len(),
+            Self::FindSimilar { similar, .. } => similar.len(),
+            Self::DeadCode { dead_symbols, .. } => dead_symbols
+                .iter()
+                .map(|s| &s.path)
+                .collect::<HashSet<_>>()
+                .len(),
+            Self::DeprecatedUsage { calls, .. } => {
+                calls.iter().map(|c| &c.path).collect::<HashSet<_>>().len()
+            }
+            Self::Symbol { symbols, .. } => symbols
+                .iter()
+                .map(|s| &s.path)
+                .collect::<HashSet<_>>()
+                .len(),
         }
     }
 }
 
+/// A dependency version with known vulnerabilities.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct VulnerablePackage {
+    pub name: String,
+    pub version: String,
+    /// CVE identifiers, or the OSV identifier of vulnerabilities that have not been assigned one.
+    pub cve_ids: Vec<String>,
+}
+
+/// A comment tagged with TODO, FIXME, HACK or XXX.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Todo {
+    /// The 1-based line of the comment.
+    pub line: usize,
+    pub tag: String,
+    /// The text of the comment after its tag.
+    pub message: String,
+    /// The lines around the comment, including it.
+    pub context: String,
+}
+
+/// A call or macro that allocates on the heap.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AllocationSite {
+    /// The 1-based line that the expression starts on.
+    pub line: usize,
+    pub expression: String,
+    pub frequency: AllocFrequency,
+}
+
+/// How often an allocation is likely to run, judged by where it is in its function.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AllocFrequency {
+    /// Once per call of the function.
+    Low,
+    /// In a closure, which iterator adapters and callbacks tend to call many times.
+    Medium,
+    /// In the body of a loop, once per iteration.
+    High,
+}
+
+impl fmt::Display for AllocFrequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Low => "[low]",
+            Self::Medium => "[medium]",
+            Self::High => "[high]",
+        })
+    }
+}
+
+/// A function or type whose name matches a symbol search.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SymbolMatch {
+    pub name: String,
+    /// The kind of definition, such as `function`, `struct` or `class`, as its language names it.
+    pub kind: String,
+    pub path: String,
+    /// The 1-based line that the symbol is defined on.
+    pub line: usize,
+    /// Whether the symbol can be used outside of the module that defines it.
+    pub public: bool,
+}
+
+/// A file that is semantically similar to another.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SimilarFile {
+    pub path: String,
+    /// The score of the file's most similar chunk, where higher is more similar.
+    pub similarity: f32,
+}
+
+/// A function or type that is defined, but never referenced.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DeadSymbol {
+    pub path: String,
+    pub name: String,
+    /// The 1-based line of the definition.
+    pub line: usize,
+}
+
+/// A call to an API that its package has deprecated.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DeprecatedCall {
+    pub api: String,
+    pub path: String,
+    /// The 1-based line of the call.
+    pub line: usize,
+    /// What to use instead, if the package says.
+    pub replacement: Option<String>,
+}
+
+/// A breaking change, announced by a conventional commit.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BreakingChange {
+    pub short_desc: String,
+    /// How to migrate past the change, if the commit says.
+    pub migration_hint: Option<String>,
+}
+
+/// An open pull request that matched a pull request search.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PullRequestSummary {
+    pub number: i64,
+    pub title: String,
+    pub author: String,
+    pub url: String,
+    /// The changed files that matched the search, or that are in the context of the conversation.
+    pub matched_files: Vec<String>,
+}
+
+impl PullRequestSummary {
+    fn to_markdown(&self) -> String {
+        let mut s = format!(
+            "[#{} {}]({}) by {}",
+            self.number, self.title, self.url, self.author
+        );
+
+        if !self.matched_files.is_empty() {
+            s += &format!(", changing {}", self.matched_files.join(", "));
+        }
+
+        s
+    }
+}
+
+/// A pull request cited by an answer.
+///
+/// Code citations are code chunks, so these are tagged with their own `kind`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename = "pull_request")]
+pub struct PullRequestCitation {
+    pub number: i64,
+    pub title: String,
+    pub url: String,
+}
+
+/// A problem found in a configuration file.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ConfigIssue {
+    /// The dotted path of the field, e.g. `database.password`.
+    pub field: String,
+    pub severity: IssueSeverity,
+    pub description: String,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueSeverity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for IssueSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Low => "[low]",
+            Self::Medium => "[medium]",
+            Self::High => "[high]",
+            Self::Critical => "[critical]",
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct CodeChunk {
     pub path: String,
@@ -159,6 +1640,31 @@ pub struct CodeChunk {
     pub start_line: usize,
     #[serde(rename = "end")]
     pub end_line: usize,
+
+    /// The path this chunk's file was renamed to, if it has moved since the chunk was cited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moved_to: Option<String>,
+
+    /// Whether this chunk's file has been deleted since the chunk was cited.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+
+    /// The symbols that enclose this chunk, like `Agent::code_search`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol_path: Option<String>,
+
+    /// The git blob of this chunk's file when the chunk was cited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+
+    /// The revision of the repository this chunk was cited at, if it was stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+
+    /// Where this chunk's lines are in the current version of its file, if it has changed since
+    /// the chunk was cited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapped: Option<MappedLines>,
 }
 
 impl CodeChunk {
@@ -170,21 +1676,660 @@ impl CodeChunk {
 
 impl fmt::Display for CodeChunk {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {}\n{}", self.alias, self.path, self.snippet)
+        write!(f, "{}: {}", self.alias, self.path)?;
+        if let Some(symbol_path) = &self.symbol_path {
+            write!(f, " ({symbol_path})")?;
+        }
+        write!(f, "\n{}", self.snippet)
+    }
+}
+
+/// A file that secrets were redacted from.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    pub path: String,
+    /// The kinds of secret that were found, such as `aws_key`, sorted.
+    pub kinds: Vec<String>,
+}
+
+/// A file in scope for an exchange.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContextFile {
+    pub path: String,
+    /// How this file came into scope, in the order each source first included it.
+    pub sources: Vec<ContextSource>,
+    /// The 1-based, end-exclusive line ranges of this file that are known, sorted and merged.
+    pub lines: Vec<Range<usize>>,
+    /// Whether any content of this file was sent to the LLM, rather than only its path.
+    pub sent_to_llm: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextSource {
+    PathSearch,
+    CodeSearch,
+    Proc,
+    RelatedFiles,
+    /// The definition, callees or callers of a function that the query asks to explain.
+    CallGraph,
+    /// Chosen by the user, e.g. a file they asked to have explained.
+    Pinned,
+}
+
+/// Escape text for a single Markdown table cell.
+fn markdown_cell(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('|', "\\|")
+}
+
+/// Sort ranges, merging those that overlap or touch.
+fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|r| (r.start, r.end));
+
+    ranges.into_iter().fold(Vec::new(), |mut merged, r| {
+        match merged.last_mut() {
+            Some(prev) if r.start <= prev.end => prev.end = prev.end.max(r.end),
+            _ => merged.push(r),
+        }
+
+        merged
+    })
+}
+
+/// The origin of an answer that was not generated by a regular agent run.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerSource {
+    /// A canned answer configured by an administrator.
+    Faq,
+    /// The combined answers to the questions of a playbook.
+    Playbook,
+}
+
+/// How well a query was answered.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnswerOutcome {
+    Answered,
+    /// Only part of the query could be answered.
+    Partial,
+    /// What the query asks about was not found.
+    NotFound,
+    /// The answer asks the user to clarify the query.
+    Clarification,
+    /// The query failed before it was answered.
+    Error,
+}
+
+impl AnswerOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Answered => "answered",
+            Self::Partial => "partial",
+            Self::NotFound => "not_found",
+            Self::Clarification => "clarification",
+            Self::Error => "error",
+        }
     }
+
+    /// Parse an outcome by its name, ignoring case and surrounding whitespace.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_lowercase();
+        [
+            Self::Answered,
+            Self::Partial,
+            Self::NotFound,
+            Self::Clarification,
+            Self::Error,
+        ]
+        .into_iter()
+        .find(|outcome| outcome.as_str() == s)
+    }
+}
+
+/// A canned answer that the user may also be looking for.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub faq_id: i64,
+    pub question: String,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct FocusedChunk {
     pub file_path: String,
     pub start_line: usize,
     pub end_line: usize,
+
+    /// The path this chunk's file was renamed to, if it has moved since the chunk was cited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moved_to: Option<String>,
+
+    /// Whether this chunk's file has been deleted since the chunk was cited.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
 }
 
 #[derive(Debug)]
 pub enum Update {
     StartStep(SearchStep),
+    /// A step that reuses the result of an identical, earlier step of the same exchange.
+    ReuseStep(SearchStep),
     ReplaceStep(SearchStep),
     Article(String),
     Conclude(String),
+    /// A step failed, and the exchange will not be advanced any further.
+    Error(String),
+}
+
+/// (De)serialize a `SystemTime` as an ISO-8601 string.
+mod iso8601 {
+    use std::time::SystemTime;
+
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        DateTime::<Utc>::from(*time).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        DateTime::<Utc>::deserialize(deserializer).map(SystemTime::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_last_updated_at() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        let created_at = exchange.last_updated_at;
+
+        let later = created_at + Duration::from_secs(5);
+        exchange.apply_update_at(Update::Article("foo".into()), later);
+
+        assert!(exchange.last_updated_at > created_at);
+        assert_eq!(exchange.last_updated_at, later);
+    }
+
+    #[test]
+    fn test_answer_confidence() {
+        let confidence = |article: &str| {
+            let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+            exchange.apply_update(Update::Article(article.into()));
+            exchange.apply_update(Update::Conclude("Retries are handled by `send`.".into()));
+            assert_eq!(exchange.confidence, Some(exchange.answer_confidence()));
+            exchange.answer_confidence()
+        };
+
+        let none = confidence(
+            "# Retries\n\nRequests are retried by [`send`](src/client.rs#L10-L30), up to three \
+             times.",
+        );
+        let three = confidence(
+            "# Retries\n\nRequests might be retried by [`send`](src/client.rs#L10-L30). It could \
+             be configured elsewhere, possibly in `config.rs`.\n\n\
+             ```type:Quoted,lang:Rust,path:src/client.rs,lines:10-12\n\
+             // This might retry, or could give up.\n\
+             ```",
+        );
+        let six = confidence(
+            "# Retries\n\nI\u{2019}m not sure how requests are retried. It seems that `send` \
+             might retry them, but it could also be the caller. Perhaps the client does, or \
+             possibly a middleware.",
+        );
+
+        assert_eq!(none, 1.0);
+        assert!(three < none, "{three} >= {none}");
+        assert!(six < three, "{six} >= {three}");
+        assert!(six > 0.0);
+
+        // Exchanges that haven't been answered have no confidence.
+        let unanswered = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        assert_eq!(unanswered.answer_confidence(), 0.0);
+        assert_eq!(unanswered.confidence, None);
+    }
+
+    #[test]
+    fn test_step_response_summary() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        let step = |response: &str| {
+            Update::StartStep(SearchStep::Code {
+                query: "foo".into(),
+                response: response.into(),
+            })
+        };
+
+        exchange.apply_update(step("The first sentence.\nThe second one. And a third."));
+        exchange.apply_update(step(&format!("{}. Short.", "long ".repeat(40))));
+        exchange.apply_update(step("No full stop"));
+
+        assert_eq!(
+            exchange.step_response_summary(0).as_deref(),
+            Some("The first sentence.")
+        );
+        assert_eq!(exchange.step_response_summary(1), Some("long ".repeat(30)));
+        assert_eq!(
+            exchange.step_response_summary(2).as_deref(),
+            Some("No full stop")
+        );
+        assert_eq!(exchange.step_response_summary(3), None);
+    }
+
+    #[test]
+    fn test_search_steps_table() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        let steps = [
+            SearchStep::Path {
+                query: "config".into(),
+                response: "0: src/config.rs\n1: src/lib.rs".into(),
+            },
+            SearchStep::Code {
+                query: "parse | validate config".into(),
+                response: "0: src/config.rs (Config::parse)\nfn parse() {}\n\n\
+                           0: src/config.rs (Config::validate)\nfn validate() {}"
+                    .into(),
+            },
+            SearchStep::ListFiles {
+                pattern: "src/**/*.rs".into(),
+                paths: vec!["src/config.rs".into(), "src/lib.rs".into()],
+            },
+            SearchStep::Proc {
+                query: "where is the\nconfig parsed?".into(),
+                paths: vec!["src/config.rs".into()],
+                response: "In `parse`.".into(),
+                lines_read: vec![],
+                timed_out: vec![],
+            },
+            SearchStep::DependencyVulns {
+                lockfiles: vec!["Cargo.lock".into()],
+                packages: vec![],
+                unchecked: vec![],
+            },
+            SearchStep::ConfigAudit {
+                path: "config.toml".into(),
+                issues: vec![],
+            },
+            SearchStep::Changelog {
+                since: Some("v0.5.0".into()),
+                breaking_changes: vec![],
+                response: "## Fixes\n\n- handle empty queries (1b2c3d4)".into(),
+            },
+            SearchStep::RelatedFiles {
+                paths: vec!["src/config.rs".into()],
+                related: vec!["src/lib.rs".into(), "src/main.rs".into()],
+            },
+            SearchStep::DeadCode {
+                dead_symbols: vec![DeadSymbol {
+                    path: "src/lib.rs".into(),
+                    name: "unused".into(),
+                    line: 3,
+                }],
+            },
+        ];
+
+        for step in steps {
+            exchange.apply_update(Update::StartStep(step));
+        }
+
+        let table = exchange.serialize_search_steps_as_table();
+        let rows = table.lines().collect::<Vec<_>>();
+
+        // A header, a separator, and a row per step.
+        assert_eq!(rows.len(), 2 + exchange.search_steps.len());
+        for row in &rows {
+            let columns = row.replace("\\|", "").matches('|').count() - 1;
+            assert_eq!(columns, 4, "{row}");
+        }
+
+        assert_eq!(rows[0], "| Type | Query | Files Found | Tokens |");
+        assert!(rows[3].starts_with("| code | parse \\| validate config | 1 | "));
+        assert!(rows[5].starts_with("| proc | where is the config parsed? | 1 | "));
+        assert!(rows[8].starts_with("| changelog | v0.5.0 | 0 | "));
+        assert!(rows[9].starts_with("| related_files | src/config.rs | 2 | "));
+        assert!(rows[10].starts_with("| dead_code |  | 1 | "));
+        assert!(rows[2].ends_with(&format!(
+            " {} |",
+            exchange.search_steps[0].get_token_count()
+        )));
+    }
+
+    #[test]
+    fn test_context() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+
+        // The user asks about a region of a file.
+        exchange.include_context("src/main.rs", ContextSource::Pinned, &[10..21], true);
+
+        exchange.apply_update(Update::StartStep(SearchStep::Path {
+            query: "config".into(),
+            response: String::new(),
+        }));
+        for path in ["src/main.rs", "src/config.rs", "README.md"] {
+            exchange.include_context(path, ContextSource::PathSearch, &[], false);
+        }
+
+        exchange.apply_update(Update::StartStep(SearchStep::Code {
+            query: "parse config".into(),
+            response: String::new(),
+        }));
+        exchange.include_context("src/config.rs", ContextSource::CodeSearch, &[1..12], true);
+        exchange.include_context("src/lib.rs", ContextSource::CodeSearch, &[40..55], true);
+
+        // Nothing was read from the README, so none of its content was sent.
+        exchange.apply_update(Update::StartStep(SearchStep::Proc {
+            query: "where is the config parsed".into(),
+            paths: vec!["src/config.rs".into(), "README.md".into()],
+            response: String::new(),
+            lines_read: vec![8..20, 30..31],
+            timed_out: vec![],
+        }));
+        exchange.include_context("src/config.rs", ContextSource::Proc, &[8..20, 30..31], true);
+        exchange.include_context("README.md", ContextSource::Proc, &[], false);
+
+        exchange.apply_update(Update::StartStep(SearchStep::Code {
+            query: "config loading".into(),
+            response: String::new(),
+        }));
+        exchange.include_context("src/lib.rs", ContextSource::CodeSearch, &[50..60], true);
+
+        let expected = serde_json::from_str::<serde_json::Value>(include_str!(
+            "fixtures/exchange_context.json"
+        ))
+        .unwrap();
+
+        let json = serde_json::to_value(&exchange).unwrap();
+        assert_eq!(json["context"], expected);
+        assert_eq!(
+            serde_json::to_value(&exchange.compressed()).unwrap()["context"],
+            expected
+        );
+
+        // Exchanges stored before the context was tracked have none.
+        let mut json = json;
+        json.as_object_mut().unwrap().remove("context");
+        let old = serde_json::from_value::<Exchange>(json).unwrap();
+        assert!(old.context.is_empty());
+    }
+
+    #[test]
+    fn test_record_redaction() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        assert!(serde_json::to_value(&exchange).unwrap()["redactions"].is_null());
+
+        let redaction = |path: &str, kinds: &[&str]| Redaction {
+            path: path.into(),
+            kinds: kinds.iter().map(|k| k.to_string()).collect(),
+        };
+
+        exchange.record_redaction(redaction(".env", &["private_key"]));
+        exchange.record_redaction(redaction("src/deploy.rs", &["aws_key"]));
+        exchange.record_redaction(redaction(".env", &["aws_key", "private_key"]));
+
+        assert_eq!(
+            exchange.compressed().redactions,
+            [
+                redaction(".env", &["aws_key", "private_key"]),
+                redaction("src/deploy.rs", &["aws_key"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let email = regex::Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").unwrap();
+
+        let mut exchange = Exchange::new(
+            uuid::Uuid::nil(),
+            SemanticQuery {
+                target: Some(Literal::Plain(
+                    "why does jane.doe@example.com get no emails?".into(),
+                )),
+                ..Default::default()
+            },
+        );
+        exchange.search_steps = vec![
+            SearchStep::Code {
+                query: "mail to jane.doe@example.com".into(),
+                response: "0: src/users.rs\nconst ADMIN: &str = \"admin+ops@corp.example.org\";"
+                    .into(),
+            },
+            SearchStep::ListFiles {
+                pattern: "src/**/*.rs".into(),
+                paths: vec!["src/users.rs".into()],
+            },
+            SearchStep::TODOs {
+                path: "src/users.rs".into(),
+                todos: vec![Todo {
+                    line: 3,
+                    tag: "TODO".into(),
+                    message: "ask jane.doe@example.com".into(),
+                    context: "// TODO: ask jane.doe@example.com".into(),
+                }],
+            },
+            SearchStep::ConfigAudit {
+                path: "config.toml".into(),
+                issues: vec![ConfigIssue {
+                    field: "smtp.user".into(),
+                    severity: IssueSeverity::Low,
+                    description: "`admin+ops@corp.example.org` is a personal address".into(),
+                }],
+            },
+        ];
+        exchange.external_context = vec![ExternalFetch {
+            url: "https://example.com/jane.doe@example.com".into(),
+            status: FetchStatus::Failed {
+                error: "no such user jane.doe@example.com".into(),
+            },
+            bytes: 0,
+        }];
+        exchange.answer = Some("Mail to jane.doe@example.com bounces.".into());
+        exchange.error = Some("jane.doe@example.com is unreachable".into());
+
+        exchange.anonymize(&[email]);
+
+        let json = serde_json::to_string(&exchange).unwrap();
+        assert!(!json.contains('@'), "{json}");
+
+        assert_eq!(
+            exchange.query().as_deref(),
+            Some("why does [REDACTED] get no emails?")
+        );
+        assert_eq!(
+            exchange.search_steps[0].get_response(),
+            "0: src/users.rs\nconst ADMIN: &str = \"[REDACTED]\";"
+        );
+        assert_eq!(exchange.search_steps[0].query_text(), "mail to [REDACTED]");
+        assert_eq!(exchange.search_steps[1].query_text(), "src/**/*.rs");
+        assert_eq!(
+            exchange.answer.as_deref(),
+            Some("Mail to [REDACTED] bounces.")
+        );
+    }
+
+    #[test]
+    fn test_exchange_eq() {
+        let exchange = |query: &str, steps: &[(&str, &str)]| {
+            let query = SemanticQuery {
+                target: Some(Literal::Plain(query.to_owned().into())),
+                ..Default::default()
+            };
+            let mut exchange = Exchange::new(uuid::Uuid::new_v4(), query);
+            for (query, response) in steps {
+                exchange.apply_update(Update::StartStep(SearchStep::Code {
+                    query: (*query).into(),
+                    response: (*response).into(),
+                }));
+            }
+            exchange
+        };
+
+        let steps = [("parse config", "0: src/config.rs\nfn parse() {}")];
+        let a = exchange("where is the config parsed?", &steps);
+        let b = exchange("where is the config parsed?", &steps);
+        assert_ne!(a.id, b.id);
+        assert_eq!(a, b);
+
+        let unique = [a.clone(), b, a.clone()]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(unique.len(), 1);
+
+        let other_response = exchange(
+            "where is the config parsed?",
+            &[("parse config", "1: src/lib.rs\nmod config;")],
+        );
+        let more_steps = exchange(
+            "where is the config parsed?",
+            &[steps[0], ("validate config", "0: src/config.rs")],
+        );
+        let other_query = exchange("where is the config validated?", &steps);
+        assert_ne!(a, other_response);
+        assert_ne!(a, more_steps);
+        assert_ne!(a, other_query);
+
+        let unique = [a, other_response, more_steps, other_query]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        assert_eq!(unique.len(), 4);
+    }
+
+    #[test]
+    fn test_query_type() {
+        let query_type = |query: &str| {
+            let query = SemanticQuery {
+                target: Some(Literal::Plain(query.to_owned().into())),
+                ..Default::default()
+            };
+            Exchange::new(uuid::Uuid::nil(), query).query_type()
+        };
+
+        assert_eq!(query_type("How to add a new language?"), QueryType::HowTo);
+        assert_eq!(query_type("how do I run the tests"), QueryType::HowTo);
+        assert_eq!(query_type("What is a RepoRef?"), QueryType::WhatIs);
+        assert_eq!(query_type("what does the indexer do"), QueryType::WhatIs);
+        assert_eq!(
+            query_type("Where is the config parsed?"),
+            QueryType::WhereIs
+        );
+        assert_eq!(
+            query_type("which file defines the routes"),
+            QueryType::WhereIs
+        );
+        assert_eq!(query_type("Why do I get this error?"), QueryType::Debug);
+        assert_eq!(query_type("what is causing this bug"), QueryType::Debug);
+        assert_eq!(query_type("thread 'main' panicked"), QueryType::Debug);
+        assert_eq!(query_type("What is ErrorKind?"), QueryType::WhatIs);
+        assert_eq!(query_type("list the endpoints"), QueryType::Other);
+        assert_eq!(
+            Exchange::new(uuid::Uuid::nil(), SemanticQuery::default()).query_type(),
+            QueryType::Other
+        );
+    }
+
+    #[test]
+    fn test_cite_pull_requests() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        let pr = |number: i64| PullRequestSummary {
+            number,
+            title: format!("PR {number}"),
+            author: "alice".into(),
+            url: format!("https://github.com/bloopai/bloop/pull/{number}"),
+            matched_files: Vec::new(),
+        };
+
+        exchange.apply_update(Update::StartStep(SearchStep::Prs {
+            query: "rate limiting".into(),
+            pull_requests: vec![pr(12), pr(7), pr(3)],
+        }));
+        exchange.apply_update(Update::Article(
+            "Yes, see [this PR](https://github.com/bloopai/bloop/pull/7) and #12. Issue #5 and \
+             `&#3;` are not pull requests that were found."
+                .into(),
+        ));
+
+        assert_eq!(
+            exchange
+                .pr_citations
+                .iter()
+                .map(|c| c.number)
+                .collect::<Vec<_>>(),
+            [12, 7]
+        );
+
+        let json = serde_json::to_value(&exchange.pr_citations[0]).unwrap();
+        assert_eq!(json["kind"], "pull_request");
+        assert_eq!(json["url"], "https://github.com/bloopai/bloop/pull/12");
+    }
+
+    #[test]
+    fn test_last_updated_at_serialization() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        exchange.last_updated_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_696_204_800);
+
+        let json = serde_json::to_value(&exchange).unwrap();
+        assert_eq!(json["last_updated_at"], "2023-10-02T00:00:00Z");
+
+        let roundtrip = serde_json::from_value::<Exchange>(json).unwrap();
+        assert_eq!(roundtrip.last_updated_at, exchange.last_updated_at);
+    }
+
+    #[test]
+    fn test_legacy_last_updated_at() {
+        let legacy = |exchange: &Exchange| {
+            let mut json = serde_json::to_value(exchange).unwrap();
+            json.as_object_mut().unwrap().remove("last_updated_at");
+            serde_json::from_value::<Exchange>(json)
+                .unwrap()
+                .last_updated_at
+        };
+
+        // Exchanges stored without a last update take it from their response, or their query.
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        let query_timestamp = exchange.query_timestamp.unwrap();
+        assert_eq!(legacy(&exchange), SystemTime::from(query_timestamp));
+
+        let response_timestamp = query_timestamp + chrono::Duration::minutes(2);
+        exchange.response_timestamp = Some(response_timestamp);
+        assert_eq!(legacy(&exchange), SystemTime::from(response_timestamp));
+
+        // The time doesn't change each time the exchange is read.
+        assert_eq!(legacy(&exchange), legacy(&exchange));
+
+        exchange.query_timestamp = None;
+        exchange.response_timestamp = None;
+        assert_eq!(legacy(&exchange), SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_timed_out_paths() {
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        assert!(exchange.timed_out_paths().is_empty());
+
+        for timed_out in [
+            vec!["src/generated.rs"],
+            vec![],
+            vec!["a.rs", "src/generated.rs"],
+        ] {
+            exchange.apply_update(Update::StartStep(SearchStep::Proc {
+                query: "where is the schema defined".into(),
+                paths: vec!["src/generated.rs".into(), "a.rs".into()],
+                response: String::new(),
+                lines_read: vec![],
+                timed_out: timed_out.into_iter().map(str::to_owned).collect(),
+            }));
+        }
+
+        assert_eq!(exchange.timed_out_paths(), ["a.rs", "src/generated.rs"]);
+
+        // Steps that didn't time out are serialized as they were.
+        let json = serde_json::to_value(&exchange.search_steps[1]).unwrap();
+        assert!(json["content"].get("timed_out").is_none());
+    }
 }
@@ -0,0 +1,643 @@
+//! Context from pages that users link in their queries, like the GitHub issue that a question is
+//! about.
+//!
+//! Links to GitHub issues and pull requests are fetched from the GitHub API, with the app's token
+//! only if the repository is indexed and the user may access it. Links to other pages are only
+//! fetched if their host is in `external_context_hosts`, as raw text or markdown, and are
+//! otherwise ignored. Redirects are not followed, so that allowed hosts can't point elsewhere.
+//! Pages are read up to `MAX_BYTES`, and pages that are longer than `MAX_TOKENS` are summarised
+//! with a cheaper model. The pages are shown in the system prompt of every step, and what became
+//! of each link is recorded on the exchange.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use futures::TryStreamExt;
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE, USER_AGENT},
+    RequestBuilder, Response, Url,
+};
+use secrecy::{ExposeSecret, SecretString};
+use tracing::{debug, warn};
+
+use crate::{
+    agent::{
+        exchange::{ExternalFetch, FetchStatus},
+        prompts, quick,
+        tokens::Tokenizer,
+        Agent, ANSWER_MODEL,
+    },
+    analytics::EventData,
+    llm_gateway::api::Message,
+    repo::{Backend, RepoRef},
+};
+
+/// The most bytes of a page that are read.
+pub const MAX_BYTES: usize = 256 * 1024;
+
+/// The most tokens of a page that are shown in the system prompt, before it is summarised.
+pub const MAX_TOKENS: usize = 1000;
+
+/// The most links of a query that are fetched.
+const MAX_LINKS: usize = 5;
+
+/// How long a page can take to fetch.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The model that summarises long pages.
+const SUMMARY_MODEL: &str = quick::ANSWER_MODEL;
+
+/// The most tokens of a page that are summarised, to fit the context window of `SUMMARY_MODEL`.
+const MAX_SUMMARY_INPUT_TOKENS: usize = 3000;
+
+/// The most words of a summary.
+const MAX_SUMMARY_WORDS: usize = 400;
+
+impl Agent {
+    /// Fetch the pages linked in `query`, to show them in the system prompt of every step.
+    pub(super) async fn seed_external_context(&mut self, query: &str) -> Result<()> {
+        self.external_context = Vec::new();
+
+        let links = find_links(query);
+        if links.is_empty() {
+            return Ok(());
+        }
+
+        let token = self
+            .app
+            .credentials
+            .github()
+            .map(|github| github.auth.api_token().clone());
+        let fetcher = Fetcher::new(&self.app.config.github_api_url, token)?;
+        let tokenizer = self.tokenizer(ANSWER_MODEL)?;
+
+        let mut sections = Vec::new();
+        let mut fetches = Vec::new();
+        for url in links.into_iter().take(MAX_LINKS) {
+            let Some(link) = classify(&url, &self.app.config.external_context_hosts) else {
+                debug!(%url, "ignoring link to a host that is not allowed");
+                sections.push(format!(
+                    "### {url}\nThis page was not fetched, because its host is not allowed."
+                ));
+                fetches.push(ExternalFetch {
+                    url: url.to_string(),
+                    status: FetchStatus::Disallowed,
+                    bytes: 0,
+                });
+                continue;
+            };
+
+            let authenticated = match &link {
+                Link::Github { owner, repo, .. } => self.may_use_github_token(owner, repo),
+                Link::Page(_) => false,
+            };
+
+            let fetched = fetcher.fetch(&link, authenticated).await;
+            let (content, status, bytes) = match fetched {
+                Ok((content, bytes)) if tokenizer.count(&content) > MAX_TOKENS => {
+                    match self
+                        .summarize_page(url.as_str(), &content, &tokenizer)
+                        .await
+                    {
+                        Ok(summary) => (summary, FetchStatus::Summarized, bytes),
+                        Err(err) => (String::new(), failed(&url, err), bytes),
+                    }
+                }
+                Ok((content, bytes)) => (content, FetchStatus::Fetched, bytes),
+                Err(err) => (String::new(), failed(&url, err), 0),
+            };
+
+            if !matches!(status, FetchStatus::Failed { .. }) {
+                sections.push(format!("### {url}\n{}", content.trim()));
+            }
+
+            fetches.push(ExternalFetch {
+                url: url.to_string(),
+                status,
+                bytes,
+            });
+        }
+
+        self.track_query(
+            EventData::input_stage("external context").with_payload("fetches", &fetches),
+        );
+
+        self.external_context = sections;
+        self.last_exchange_mut().external_context = fetches;
+        Ok(())
+    }
+
+    /// Whether the app's GitHub token may be used to read `owner/repo`.
+    ///
+    /// The token can read private repositories that the user can't, so it is only used for
+    /// repositories that are indexed, and that the user may access.
+    fn may_use_github_token(&self, owner: &str, repo: &str) -> bool {
+        let Ok(repo_ref) = RepoRef::new(Backend::Github, &format!("{owner}/{repo}")) else {
+            return false;
+        };
+
+        self.app.repo_pool.read(&repo_ref, |_, _| ()).is_some()
+            && self.app.access.allows(&self.user, &repo_ref)
+    }
+
+    async fn summarize_page(
+        &self,
+        url: &str,
+        content: &str,
+        tokenizer: &Tokenizer,
+    ) -> Result<String> {
+        let mut tokens = tokenizer.encode(content);
+        tokens.truncate(MAX_SUMMARY_INPUT_TOKENS);
+        let content = tokenizer.decode(tokens)?;
+
+        let prompt = prompts::summarize_external(url, &content, MAX_SUMMARY_WORDS);
+        let messages = [Message::system(&prompt)];
+
+        let start = Instant::now();
+        let summary = self
+            .llm_gateway
+            .clone()
+            .model(SUMMARY_MODEL)
+            .max_tokens(MAX_TOKENS as u32)
+            .chat(&messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "external context",
+            SUMMARY_MODEL,
+            &messages,
+            &summary,
+            start.elapsed(),
+        )
+        .await;
+
+        Ok(summary)
+    }
+}
+
+fn failed(url: &Url, err: anyhow::Error) -> FetchStatus {
+    warn!(?err, %url, "failed to fetch linked page");
+    FetchStatus::Failed {
+        error: err.to_string(),
+    }
+}
+
+/// The links in `query`, in order and without duplicates.
+fn find_links(query: &str) -> Vec<Url> {
+    let mut links = Vec::new();
+
+    for m in lazy_regex::regex!(r#"https?://[^\s<>()\[\]"'`]+"#).find_iter(query) {
+        // Links are often followed by punctuation, which is not part of them.
+        let link = m.as_str().trim_end_matches(['.', ',', ';', ':', '!', '?']);
+        if let Ok(url) = Url::parse(link) {
+            if !links.contains(&url) {
+                links.push(url);
+            }
+        }
+    }
+
+    links
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Link {
+    /// An issue or a pull request, which the GitHub API serves as an issue either way.
+    Github {
+        owner: String,
+        repo: String,
+        number: u64,
+    },
+    /// A raw text or markdown page, from an allowed host.
+    Page(Url),
+}
+
+/// What `url` links to, or `None` if it can't be fetched.
+///
+/// GitHub pages other than issues and pull requests are HTML, so they are not fetched.
+fn classify(url: &Url, allowed_hosts: &[String]) -> Option<Link> {
+    let host = url.host_str()?;
+
+    if host == "github.com" || host == "www.github.com" {
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+        return match segments[..] {
+            [owner, repo, "issues" | "pull", number, ..] => Some(Link::Github {
+                owner: owner.to_owned(),
+                repo: repo.to_owned(),
+                number: number.parse().ok()?,
+            }),
+            _ => None,
+        };
+    }
+
+    allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        .then(|| Link::Page(url.clone()))
+}
+
+/// An issue or a pull request, as returned by the GitHub API.
+#[derive(serde::Deserialize)]
+struct GithubIssue {
+    number: u64,
+    title: String,
+    body: Option<String>,
+    state: String,
+    #[serde(default)]
+    pull_request: Option<serde_json::Value>,
+}
+
+impl GithubIssue {
+    fn text(&self) -> String {
+        let kind = match self.pull_request {
+            Some(_) => "Pull request",
+            None => "Issue",
+        };
+
+        format!(
+            "{kind} #{} ({}): {}\n\n{}",
+            self.number,
+            self.state,
+            self.title,
+            self.body.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// Fetches linked pages, within the size and time limits of this module.
+struct Fetcher {
+    http: reqwest::Client,
+    github_api_url: String,
+    github_token: Option<SecretString>,
+}
+
+impl Fetcher {
+    fn new(github_api_url: &str, github_token: Option<SecretString>) -> Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+            github_api_url: github_api_url.trim_end_matches('/').to_owned(),
+            github_token,
+        })
+    }
+
+    /// The text of `link`, and the number of bytes that were read for it.
+    ///
+    /// GitHub links are fetched with the app's token if `authenticated` is set.
+    async fn fetch(&self, link: &Link, authenticated: bool) -> Result<(String, usize)> {
+        match link {
+            Link::Github {
+                owner,
+                repo,
+                number,
+            } => {
+                let url = format!(
+                    "{}/repos/{owner}/{repo}/issues/{number}",
+                    self.github_api_url
+                );
+                let mut request = self
+                    .http
+                    .get(url)
+                    .header(ACCEPT, "application/vnd.github+json")
+                    .header(USER_AGENT, "bloop");
+                if let Some(token) = self.github_token.as_ref().filter(|_| authenticated) {
+                    request = request.bearer_auth(token.expose_secret());
+                }
+
+                let (body, bytes) = read_capped(send(request).await?).await?;
+                let issue =
+                    serde_json::from_str::<GithubIssue>(&body).context("invalid GitHub issue")?;
+
+                Ok((issue.text(), bytes))
+            }
+
+            Link::Page(url) => {
+                let response = send(self.http.get(url.clone())).await?;
+
+                let content_type = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("text/plain");
+                if !["text/plain", "text/markdown", "text/x-markdown"]
+                    .iter()
+                    .any(|t| content_type.starts_with(t))
+                {
+                    bail!("unsupported content type {content_type}");
+                }
+
+                read_capped(response).await
+            }
+        }
+    }
+}
+
+/// Send `request`, failing on error statuses and on redirects, which are not followed.
+async fn send(request: RequestBuilder) -> Result<Response> {
+    let response = request.send().await?.error_for_status()?;
+    if response.status().is_redirection() {
+        bail!("redirected with {}", response.status());
+    }
+
+    Ok(response)
+}
+
+/// Read the body of `response` up to `MAX_BYTES`, returning it and the number of bytes read.
+async fn read_capped(mut response: Response) -> Result<(String, usize)> {
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        let room = MAX_BYTES - body.len();
+        body.extend_from_slice(&chunk[..chunk.len().min(room)]);
+
+        if body.len() == MAX_BYTES {
+            debug!(url = %response.url(), "linked page was cut off");
+            break;
+        }
+    }
+
+    let bytes = body.len();
+    Ok((String::from_utf8_lossy(&body).into_owned(), bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        agent::builder,
+        llm_gateway::mock::{call, Gateway, Reply, Request},
+        Application, Environment,
+    };
+
+    #[test]
+    fn test_find_links() {
+        let links = find_links(
+            "Fix the bug in https://github.com/acme/web/issues/7. See \
+             (https://raw.githubusercontent.com/acme/web/main/NOTES.md), and again \
+             https://github.com/acme/web/issues/7!",
+        );
+
+        assert_eq!(
+            links.iter().map(Url::as_str).collect::<Vec<_>>(),
+            [
+                "https://github.com/acme/web/issues/7",
+                "https://raw.githubusercontent.com/acme/web/main/NOTES.md",
+            ]
+        );
+        assert!(find_links("How is auth handled?").is_empty());
+    }
+
+    #[test]
+    fn test_classify() {
+        let allowed = ["raw.githubusercontent.com".to_owned()];
+        let link = |url: &str| classify(&Url::parse(url).unwrap(), &allowed);
+
+        assert_eq!(
+            link("https://github.com/acme/web/pull/12/files"),
+            Some(Link::Github {
+                owner: "acme".to_owned(),
+                repo: "web".to_owned(),
+                number: 12,
+            })
+        );
+        assert!(matches!(
+            link("https://raw.githubusercontent.com/acme/web/main/NOTES.md"),
+            Some(Link::Page(_))
+        ));
+
+        // Other GitHub pages, and pages from other hosts, are not fetched.
+        assert_eq!(link("https://github.com/acme/web/blob/main/NOTES.md"), None);
+        assert_eq!(link("https://github.com/acme/web/issues/new"), None);
+        assert_eq!(link("https://example.com/NOTES.md"), None);
+    }
+
+    /// Serve a mock LLM gateway, GitHub API and page host.
+    ///
+    /// The gateway answers straight away, and summarises pages with `SUMMARY`.
+    fn serve() -> Gateway {
+        let routes = axum::Router::new()
+            .route(
+                "/repos/acme/web/issues/7",
+                get(|| async { axum::Json(issue()) }),
+            )
+            .route(
+                "/docs/small.md",
+                get(|| async { ([(CONTENT_TYPE, "text/markdown")], SMALL) }),
+            )
+            .route("/docs/huge.txt", get(|| async { huge() }))
+            .route(
+                "/docs/page.html",
+                get(|| async { ([(CONTENT_TYPE, "text/html")], "<html></html>") }),
+            )
+            .route(
+                "/docs/moved.md",
+                get(|| async { axum::response::Redirect::temporary("/docs/small.md") }),
+            )
+            .route("/repos/acme/web/issues/8", get(private_issue))
+            .route("/repos/acme/secret/issues/8", get(private_issue));
+
+        Gateway::serve_with(routes, |request| {
+            if request.is_function_call() {
+                Reply::call(&call("none", serde_json::json!({ "paths": [] })))
+            } else if is_summary(request) {
+                Reply::text(SUMMARY)
+            } else {
+                Reply::text("Check the session before refreshing it.")
+            }
+        })
+    }
+
+    /// An issue that can only be read with the app's GitHub token, like those of private repos.
+    async fn private_issue(headers: axum::http::HeaderMap) -> axum::response::Response {
+        use axum::{http::StatusCode, response::IntoResponse};
+
+        match headers.get(axum::http::header::AUTHORIZATION) {
+            Some(auth) if auth == "Bearer ghp_secret" => axum::Json(issue()).into_response(),
+            _ => StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+
+    fn is_summary(request: &Request) -> bool {
+        request.system().contains("which a user linked")
+    }
+
+    const SUMMARY: &str = "The notes list every session timeout.";
+    const SMALL: &str = "Sessions are refreshed by `refresh_session`.";
+
+    fn issue() -> serde_json::Value {
+        serde_json::json!({
+            "number": 7,
+            "title": "Login fails after upgrading",
+            "body": "Users are logged out when their session is refreshed.",
+            "state": "open",
+        })
+    }
+
+    fn huge() -> String {
+        "the session timeout is configurable\n".repeat(MAX_BYTES / 16)
+    }
+
+    /// An application that fetches GitHub issues and pages from `url`.
+    async fn app(index_dir: &tempdir::TempDir, url: &str) -> Application {
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": url,
+            "github_api_url": url,
+            "external_context_hosts": ["127.0.0.1"],
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+
+        Application::initialize(Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_seed_external_context() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+        let gateway = serve();
+        let url = &gateway.url;
+        let app = app(&index_dir, url).await;
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let mut driver = builder::builder(app).repo(repo_ref).build().unwrap();
+
+        let links = [
+            "https://github.com/acme/web/issues/7".to_owned(),
+            format!("{url}/docs/small.md"),
+            format!("{url}/docs/huge.txt"),
+            format!("{url}/docs/page.html"),
+            "https://example.com/notes.md".to_owned(),
+        ];
+        let exchange = driver
+            .run(&format!("Fix the bug described in {}", links.join(" and ")))
+            .await
+            .unwrap();
+
+        // Pages are read up to the size cap, and long pages are summarised. Pages from other hosts
+        // are not fetched at all.
+        let fetched = |i: usize, status, bytes| ExternalFetch {
+            url: links[i].clone(),
+            status,
+            bytes,
+        };
+        assert_eq!(
+            exchange.external_context,
+            [
+                fetched(0, FetchStatus::Fetched, issue().to_string().len()),
+                fetched(1, FetchStatus::Fetched, SMALL.len()),
+                fetched(2, FetchStatus::Summarized, MAX_BYTES),
+                fetched(
+                    3,
+                    FetchStatus::Failed {
+                        error: "unsupported content type text/html".to_owned()
+                    },
+                    0
+                ),
+                fetched(4, FetchStatus::Disallowed, 0),
+            ]
+        );
+
+        let summaries = gateway.requests_where(is_summary);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].body["model"], SUMMARY_MODEL);
+
+        // The pages are shown in a block of their own, and the summary stands in for the long one.
+        let steps = gateway.requests_where(Request::is_function_call);
+        let system = steps[0].system();
+        let block = &system[system.find("## EXTERNAL CONTEXT ##").unwrap()..];
+        assert!(block.contains("Issue #7 (open): Login fails after upgrading"));
+        assert!(block.contains(SMALL));
+        assert!(block.contains(SUMMARY));
+        assert!(!block.contains("the session timeout is configurable"));
+        assert!(block.contains("https://example.com/notes.md\nThis page was not fetched"));
+        assert!(!block.contains("page.html"));
+    }
+
+    #[tokio::test]
+    async fn test_github_token_scope() {
+        use crate::{
+            acl::tests::user,
+            db::RepoAcl,
+            remotes::github::{Auth, State},
+            repo::Repository,
+            webserver::middleware::User,
+        };
+
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+        let gateway = serve();
+        let app = app(&index_dir, &gateway.url).await;
+
+        app.credentials.set_github(State {
+            auth: Auth::OAuth {
+                access_token: "ghp_secret".to_owned().into(),
+                token_type: "bearer".to_owned(),
+                scope: vec![],
+            },
+            repositories: Default::default(),
+        });
+
+        // acme/web is indexed, and only alice may access it. acme/secret is not indexed.
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let web = RepoRef::new(Backend::Github, "acme/web").unwrap();
+        app.repo_pool
+            .insert(web.clone(), Repository::local_from(&repo_ref))
+            .unwrap();
+        let acl = RepoAcl {
+            repo_ref: web.to_string(),
+            public: false,
+            users: vec!["alice".to_owned()],
+            groups: vec![],
+        };
+        app.access.set(&app.sql, acl).await.unwrap();
+
+        let statuses = |user: User| {
+            let app = app.clone();
+            let repo_ref = repo_ref.clone();
+            async move {
+                let mut driver = builder::builder(app)
+                    .repo(repo_ref)
+                    .user(user)
+                    .build()
+                    .unwrap();
+                let exchange = driver
+                    .run(
+                        "What do https://github.com/acme/web/issues/8 and \
+                         https://github.com/acme/secret/issues/8 have in common?",
+                    )
+                    .await
+                    .unwrap();
+
+                exchange
+                    .external_context
+                    .into_iter()
+                    .map(|fetch| matches!(fetch.status, FetchStatus::Fetched))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        // The token is only sent for repositories that the user may read through bloop.
+        assert_eq!(statuses(user("alice")).await, [true, false]);
+        assert_eq!(statuses(user("mallory")).await, [false, false]);
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_not_followed() {
+        let gateway = serve();
+        let fetcher = Fetcher::new(&gateway.url, None).unwrap();
+        let link = Link::Page(Url::parse(&format!("{}/docs/moved.md", gateway.url)).unwrap());
+
+        let err = fetcher.fetch(&link, false).await.unwrap_err();
+        assert_eq!(err.to_string(), "redirected with 307 Temporary Redirect");
+    }
+}
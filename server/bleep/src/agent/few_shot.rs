@@ -0,0 +1,332 @@
+//! Few-shot examples of tool call sequences, mined from positively rated exchanges.
+//!
+//! When a user upvotes an answer, the sequence of tool calls that led to it is stored as a
+//! candidate example for its repository. The best few candidates are selected, and shown to the
+//! model in the system prompt of later queries against the same repository.
+
+use std::cmp::Reverse;
+
+use anyhow::Result;
+use sqlx::SqlitePool;
+
+use super::{
+    exchange::{Exchange, SearchStep},
+    tokens::Tokenizer,
+};
+use crate::{
+    db::{PromptExample, PromptExamples},
+    repo::RepoRef,
+};
+
+/// The maximum number of examples shown to the model.
+const MAX_EXAMPLES: usize = 3;
+
+/// Exchanges with more tool calls than this are not compact enough to make good examples.
+const MAX_STEPS: usize = 6;
+
+/// The maximum number of tokens that examples may take up in the system prompt.
+const TOKEN_BUDGET: usize = 400;
+
+/// A compact example of the tool calls made to answer a query.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Extracted {
+    pub text: String,
+    pub steps: usize,
+}
+
+/// Extract a `query → tool calls` example from a concluded exchange.
+///
+/// Repeated tool calls are left out. This returns `None` if the exchange has not been answered, or
+/// if it made no tool calls or too many.
+pub fn extract(exchange: &Exchange) -> Option<Extracted> {
+    exchange.answer()?;
+
+    let query = exchange.query()?;
+    let query = query.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let calls = exchange
+        .search_steps
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !exchange.is_cached(*i))
+        .map(|(_, step)| match step {
+            SearchStep::Path { query, .. } => format!("functions.path: {query:?}"),
+            SearchStep::Code { query, .. } => format!("functions.code: {query:?}"),
+            SearchStep::ListFiles { pattern, .. } => format!("functions.list_files: {pattern:?}"),
+            SearchStep::Proc { query, paths, .. } => {
+                format!("functions.proc: {query:?} in {}", paths.join(", "))
+            }
+            SearchStep::DependencyVulns { .. } => "functions.dependency_vulns".to_owned(),
+            SearchStep::DeadCode { .. } => "functions.dead_code".to_owned(),
+            SearchStep::DeprecatedUsage { package, .. } => {
+                format!("functions.deprecated_usage: {package}")
+            }
+            SearchStep::UpgradeSuggestions { dep_name, .. } => {
+                format!("functions.upgrade_suggestions: {dep_name}")
+            }
+            SearchStep::ConfigAudit { path, .. } => format!("functions.config_audit: {path}"),
+            SearchStep::TODOs { path, .. } => format!("functions.todos: {path}"),
+            SearchStep::Allocations { path, .. } => format!("functions.allocations: {path}"),
+            SearchStep::FindSimilar { path, .. } => format!("functions.find_similar: {path}"),
+            SearchStep::Symbol { query, .. } => format!("functions.symbol: {query:?}"),
+            SearchStep::Changelog { since, .. } => match since {
+                Some(since) => format!("functions.changelog: since {since}"),
+                None => "functions.changelog".to_owned(),
+            },
+            SearchStep::ChangelogDiff { v1, v2, .. } => {
+                format!("functions.changelog_diff: {v1} to {v2}")
+            }
+            SearchStep::WeeklyDigest { .. } => "functions.weekly_digest".to_owned(),
+            SearchStep::Plan { goal, .. } => format!("functions.plan: {goal:?}"),
+            SearchStep::Prs { query, .. } => format!("functions.prs: {query:?}"),
+            SearchStep::Format { path, .. } => format!("functions.format: {path}"),
+            SearchStep::RelatedFiles { paths, .. } => {
+                format!("functions.related_files: {}", paths.join(", "))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if calls.is_empty() || calls.len() > MAX_STEPS {
+        return None;
+    }
+
+    let mut text = format!("Query: {query}");
+    for (i, call) in calls
+        .iter()
+        .map(String::as_str)
+        .chain(["functions.none"])
+        .enumerate()
+    {
+        text += &format!("\n{}. {call}", i + 1);
+    }
+
+    Some(Extracted {
+        text,
+        steps: calls.len(),
+    })
+}
+
+/// Re-run the selection of the best examples for a repository.
+///
+/// Pinned examples count towards the maximum number of examples.
+pub async fn select(db: &SqlitePool, repo_ref: &RepoRef) -> Result<()> {
+    let store = PromptExamples::new(db);
+    let examples = store.for_repo(&repo_ref.to_string()).await?;
+
+    store.set_selected(&examples, &select_best(&examples)).await
+}
+
+/// Load the examples to show to the model for a repository, in order.
+///
+/// This is empty if there are no pinned or selected examples for the repository.
+pub async fn load(db: &SqlitePool, repo_ref: &RepoRef) -> Result<Vec<String>> {
+    let examples = PromptExamples::new(db)
+        .for_repo(&repo_ref.to_string())
+        .await?;
+
+    if examples.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let tokenizer = Tokenizer::new(super::ANSWER_MODEL)?;
+    Ok(within_budget(order(examples), TOKEN_BUDGET, |example| {
+        tokenizer.count(example)
+    }))
+}
+
+/// Shorter examples are better, and more recent ones break ties.
+fn rank(example: &PromptExample) -> (i64, Reverse<i64>, Reverse<i64>) {
+    (
+        example.steps,
+        Reverse(example.created_at),
+        Reverse(example.id),
+    )
+}
+
+fn select_best(examples: &[PromptExample]) -> Vec<i64> {
+    let pinned = examples.iter().filter(|e| e.pinned).count();
+
+    let mut candidates = examples.iter().filter(|e| !e.pinned).collect::<Vec<_>>();
+    candidates.sort_by_key(|e| rank(e));
+
+    candidates
+        .into_iter()
+        .take(MAX_EXAMPLES.saturating_sub(pinned))
+        .map(|e| e.id)
+        .collect()
+}
+
+/// The examples to show, with pinned examples first and then the selected ones, best first.
+fn order(examples: Vec<PromptExample>) -> Vec<String> {
+    let mut shown = examples
+        .into_iter()
+        .filter(|e| e.pinned || e.selected)
+        .collect::<Vec<_>>();
+
+    shown.sort_by_key(|e| (!e.pinned, rank(e)));
+
+    shown
+        .into_iter()
+        .take(MAX_EXAMPLES)
+        .map(|e| e.example)
+        .collect()
+}
+
+/// Keep examples in order while they fit in `budget` tokens, skipping those that would not fit.
+fn within_budget(
+    examples: Vec<String>,
+    budget: usize,
+    count_tokens: impl Fn(&str) -> usize,
+) -> Vec<String> {
+    let mut remaining = budget;
+
+    examples
+        .into_iter()
+        .filter(|example| {
+            let tokens = count_tokens(example);
+            let fits = tokens <= remaining;
+            if fits {
+                remaining -= tokens;
+            }
+
+            fits
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{agent::exchange::Update, query::parser};
+
+    fn exchange(query: &str, steps: Vec<SearchStep>, answered: bool) -> Exchange {
+        let mut exchange = Exchange::new(
+            uuid::Uuid::nil(),
+            parser::SemanticQuery {
+                target: Some(parser::Literal::Plain(query.to_owned().into())),
+                ..Default::default()
+            },
+        );
+
+        for step in steps {
+            exchange.apply_update(Update::StartStep(step));
+        }
+
+        if answered {
+            exchange.apply_update(Update::Article("The answer".into()));
+            exchange.apply_update(Update::Conclude("Anything else?".into()));
+        }
+
+        exchange
+    }
+
+    fn example(
+        id: i64,
+        created_at: i64,
+        steps: i64,
+        pinned: bool,
+        selected: bool,
+    ) -> PromptExample {
+        PromptExample {
+            id,
+            created_at,
+            repo_ref: "github.com/bloopai/bloop".into(),
+            query_id: id.to_string(),
+            example: format!("example {id}"),
+            steps,
+            pinned,
+            selected,
+        }
+    }
+
+    #[test]
+    fn test_extract_format() {
+        let code = || SearchStep::Code {
+            query: "auth middleware".into(),
+            response: String::new(),
+        };
+        let steps = vec![
+            code(),
+            SearchStep::ListFiles {
+                pattern: "**/*.sql".into(),
+                paths: vec![],
+            },
+            code(),
+            SearchStep::Proc {
+                query: "how is a \"session\" checked".into(),
+                paths: vec!["src/auth.rs".into(), "src/session.rs".into()],
+                response: String::new(),
+                lines_read: vec![],
+                timed_out: vec![],
+            },
+        ];
+
+        // The second code search reused the result of the first.
+        let mut answered = exchange("How is\nauth handled?", steps.clone(), true);
+        answered.cached_steps.push(2);
+
+        assert_eq!(
+            extract(&answered),
+            Some(Extracted {
+                text: "Query: How is auth handled?\n\
+                       1. functions.code: \"auth middleware\"\n\
+                       2. functions.list_files: \"**/*.sql\"\n\
+                       3. functions.proc: \"how is a \\\"session\\\" checked\" in src/auth.rs, src/session.rs\n\
+                       4. functions.none"
+                    .into(),
+                steps: 3,
+            })
+        );
+
+        // Unanswered exchanges, and exchanges that answer without a tool call, are not examples.
+        assert_eq!(extract(&exchange("query", steps, false)), None);
+        assert_eq!(extract(&exchange("hello", vec![], true)), None);
+    }
+
+    #[test]
+    fn test_token_budget() {
+        let examples = vec!["a b c".to_owned(), "d e f g".to_owned(), "h i".to_owned()];
+        let words = |s: &str| s.split_whitespace().count();
+
+        assert_eq!(
+            within_budget(examples.clone(), 9, words),
+            vec!["a b c", "d e f g", "h i"]
+        );
+        assert_eq!(
+            within_budget(examples.clone(), 6, words),
+            vec!["a b c", "h i"]
+        );
+        assert_eq!(within_budget(examples.clone(), 2, words), vec!["h i"]);
+        assert_eq!(within_budget(examples, 0, words), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_selection_and_ordering() {
+        let examples = vec![
+            example(1, 100, 3, false, false),
+            example(2, 200, 2, false, false),
+            example(3, 300, 5, true, false),
+            example(4, 400, 2, false, false),
+            example(5, 500, 1, false, false),
+        ];
+
+        // One slot is taken by the pinned example. Of the others, shorter and newer are better.
+        assert_eq!(select_best(&examples), vec![5, 4]);
+
+        let selected = examples
+            .into_iter()
+            .map(|mut e| {
+                e.selected = [5, 4].contains(&e.id);
+                e
+            })
+            .collect();
+
+        assert_eq!(order(selected), vec!["example 3", "example 5", "example 4"]);
+        assert_eq!(
+            order(vec![example(1, 100, 1, false, false)]),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! A limit on the number of distinct files whose content is sent to the LLM for a query.
+//!
+//! Without this, a single query can send dozens of files, which is slow and exposes more of the
+//! codebase than needed. Every file read for the LLM goes through `Agent::get_file_content`,
+//! which spends the budget.
+
+/// The result sent to the model for files that are not read because the budget is spent.
+pub const EXHAUSTED_MESSAGE: &str = "The file budget for this query is exhausted, so no more \
+    files can be read. Work with the files that you have already read.";
+
+/// The error of reading a file once the budget is spent.
+#[derive(thiserror::Error, Debug)]
+#[error("file budget exhausted: {path} was not read")]
+pub struct FileBudgetExhausted {
+    pub path: String,
+}
+
+#[derive(Debug)]
+pub struct FileBudget {
+    limit: usize,
+    /// The files that were sent, in the order they were first sent.
+    sent: Vec<String>,
+    /// Whether a file was refused, because the budget was spent.
+    exhausted: bool,
+}
+
+impl FileBudget {
+    /// A budget of `limit` files, which files the user pinned are taken out of first.
+    pub fn new<'a>(limit: usize, pinned: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut budget = Self {
+            limit,
+            sent: Vec::new(),
+            exhausted: false,
+        };
+
+        for path in pinned {
+            if !budget.sent.iter().any(|p| p == path) {
+                budget.sent.push(path.to_owned());
+            }
+        }
+
+        budget
+    }
+
+    /// Spend the budget on `path`, returning whether its content can be sent.
+    ///
+    /// Files that were already sent are free to send again.
+    pub fn spend(&mut self, path: &str) -> bool {
+        if self.sent.iter().any(|p| p == path) {
+            return true;
+        }
+
+        if self.sent.len() >= self.limit {
+            self.exhausted = true;
+            return false;
+        }
+
+        self.sent.push(path.to_owned());
+        true
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A model that asks to read a new file on every step, until a read is refused.
+    fn greedy_model(budget: &mut FileBudget) -> (Vec<String>, Option<String>) {
+        let mut read = Vec::new();
+        for i in 0.. {
+            let path = format!("src/file_{i}.rs");
+            if !budget.spend(&path) {
+                return (read, Some(path));
+            }
+            read.push(path);
+        }
+
+        unreachable!()
+    }
+
+    #[test]
+    fn test_budget_cuts_off_greedy_model() {
+        let mut budget = FileBudget::new(15, []);
+        assert!(!budget.is_exhausted());
+
+        let (read, refused) = greedy_model(&mut budget);
+        assert_eq!(read.len(), 15);
+        assert_eq!(refused.as_deref(), Some("src/file_15.rs"));
+        assert!(budget.is_exhausted());
+
+        // Files that were already sent can still be read again.
+        assert!(budget.spend("src/file_3.rs"));
+        assert!(!budget.spend("src/file_16.rs"));
+    }
+
+    #[test]
+    fn test_pinned_files_count_first() {
+        let mut budget = FileBudget::new(3, ["src/main.rs", "src/main.rs", "src/lib.rs"]);
+        assert_eq!(budget.sent, ["src/main.rs", "src/lib.rs"]);
+
+        let (read, _) = greedy_model(&mut budget);
+        assert_eq!(read, ["src/file_0.rs"]);
+        assert!(budget.spend("src/main.rs"));
+        assert!(budget.is_exhausted());
+
+        // Pinned files are sent even if they don't fit in the budget.
+        let mut budget = FileBudget::new(1, ["src/main.rs", "src/lib.rs"]);
+        assert!(budget.spend("src/lib.rs"));
+        assert!(!budget.spend("src/other.rs"));
+    }
+}
@@ -0,0 +1,38 @@
+//! A fixture for call graph extraction.
+
+#[derive(Default)]
+pub struct Agent {
+    steps: usize,
+}
+
+impl Agent {
+    pub fn step(&mut self, action: &str) -> usize {
+        let parsed = parse_action(action);
+        self.record(parsed);
+        helpers::log(parsed);
+        println!("{parsed}");
+        self.steps
+    }
+
+    fn record(&mut self, action: usize) {
+        self.steps += action;
+        validate(self.steps);
+    }
+}
+
+fn parse_action(action: &str) -> usize {
+    action.trim().len()
+}
+
+fn validate(steps: usize) -> bool {
+    steps > 0
+}
+
+pub fn run(agent: &mut Agent) {
+    agent.step("query");
+    agent.step("answer");
+}
+
+mod helpers {
+    pub fn log(_value: usize) {}
+}
@@ -0,0 +1,18 @@
+class Report:
+    def __init__(self, rows):
+        self.rows = rows
+
+    def total(self):
+        return sum(self.rows)
+
+
+class Legacy:
+    pass
+
+
+def stale_export(rows):
+    return rows
+
+
+def main():
+    print(Report([1, 2, 3]).total())
@@ -0,0 +1,49 @@
+mod util;
+
+use std::fmt;
+
+pub struct Config {
+    pub name: String,
+}
+
+pub struct Orphan;
+
+#[allow(dead_code)]
+struct Scaffolding {
+    size: usize,
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", util::shout(&self.name))
+    }
+}
+
+pub fn load(name: &str) -> Config {
+    Config {
+        name: name.to_owned(),
+    }
+}
+
+pub fn render(name: &str) -> String {
+    load(name).to_string()
+}
+
+fn unused_helper() -> usize {
+    42
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture() -> Config {
+        load("test")
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render("a"), "A");
+        assert_eq!(fixture().name, "test");
+    }
+}
@@ -0,0 +1,12 @@
+pub fn shout(s: &str) -> String {
+    s.to_uppercase()
+}
+
+pub fn whisper(s: &str) -> String {
+    s.to_lowercase()
+}
+
+#[allow(dead_code)]
+pub fn debug_dump(s: &str) {
+    println!("{s}");
+}
@@ -0,0 +1,6 @@
+fn never_called_in_tests() {}
+
+#[test]
+fn renders() {
+    assert_eq!(fixture::render("b"), "B");
+}
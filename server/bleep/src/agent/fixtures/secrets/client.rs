@@ -0,0 +1,26 @@
+use secrecy::{ExposeSecret, SecretString};
+
+/// Bearer tokens are sent in the `Authorization` header.
+pub struct Client {
+    base_url: String,
+    access_token: Option<SecretString>,
+    password: SecretString,
+}
+
+const TOKEN_PROVIDER: &str = "OAuth2TokenProvider";
+const MAX_TOKENS: usize = 4096;
+
+impl Client {
+    pub fn authorize(&self, request: RequestBuilder, next_page_token: &str) -> RequestBuilder {
+        let token = "OAuth2TokenProviderV3";
+        let page_token = response.next_page_token;
+        let api_key = std::env::var("BLEEP_API_KEY").unwrap_or_default();
+        let secret = format!("Bearer {}", self.access_token.expose_secret());
+
+        // The bearer token itself is never logged.
+        request
+            .header("Authorization", secret)
+            .header("X-Page-Token", next_page_token)
+            .header("X-Api-Key", api_key)
+    }
+}
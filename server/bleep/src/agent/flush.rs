@@ -0,0 +1,378 @@
+//! Buffering of streamed answers, for consumers that need whole sentences.
+//!
+//! Answers are normally streamed with an update for every token, which chops words in half.
+//! Text-to-speech needs complete sentences instead, so in the sentence mode, the answer is only
+//! updated at the end of a sentence, or with whole words once the buffered text has waited too
+//! long.
+
+use std::time::{Duration, Instant};
+
+/// Buffered text is sent, up to its last whole word, once it has waited this long.
+pub const MAX_FLUSH_LATENCY: Duration = Duration::from_secs(2);
+
+/// Words that are abbreviated with a trailing period, in lowercase and without that period.
+const ABBREVIATIONS: &[&str] = &[
+    "e.g", "i.e", "eg", "ie", "etc", "vs", "cf", "al", "approx", "fig", "mr", "mrs", "ms", "dr",
+    "prof", "sr", "jr", "st", "inc", "ltd",
+];
+
+/// Characters that can follow the punctuation ending a sentence, like a closing quote.
+const CLOSERS: &[char] = &['"', '\'', ')', ']', '*', '_', '`', '”', '’'];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushMode {
+    /// Update the answer with every token.
+    #[default]
+    Token,
+    /// Only update the answer at the end of a sentence.
+    Sentence,
+}
+
+/// How code blocks are streamed in the sentence mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodeBlocks {
+    /// Send each code block in a single update, once it is complete.
+    #[default]
+    Whole,
+    /// Leave code blocks out of updates, until the whole answer is sent.
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flush {
+    pub mode: FlushMode,
+    pub code_blocks: CodeBlocks,
+}
+
+impl Flush {
+    /// A buffer for an answer streamed with these options, or `None` if every token is sent.
+    pub(crate) fn buffer(self, now: Instant) -> Option<SentenceBuffer> {
+        match self.mode {
+            FlushMode::Token => None,
+            FlushMode::Sentence => Some(SentenceBuffer {
+                code_blocks: self.code_blocks,
+                max_latency: MAX_FLUSH_LATENCY,
+                flushed: 0,
+                last_flush: now,
+            }),
+        }
+    }
+}
+
+/// Tracks how much of a streamed answer has been sent.
+///
+/// The answer is never sent up to a point inside a code block, so code blocks are always sent
+/// whole.
+pub(crate) struct SentenceBuffer {
+    code_blocks: CodeBlocks,
+    max_latency: Duration,
+    /// The length of the prefix of the answer that was last sent.
+    flushed: usize,
+    last_flush: Instant,
+}
+
+impl SentenceBuffer {
+    /// Take the answer so far, returning the text to update the answer with, if it is time to.
+    pub(crate) fn push(&mut self, response: &str, now: Instant) -> Option<String> {
+        let pending = &response[self.flushed..];
+        let at_line_start = self.flushed == 0 || response[..self.flushed].ends_with('\n');
+        let scan = scan(pending, at_line_start, self.code_blocks);
+
+        let end = match scan.boundary {
+            Some(end) => end,
+            None if now.duration_since(self.last_flush) >= self.max_latency => {
+                // The last word may still be incomplete, so it stays in the buffer.
+                let words = pending[..scan.open_code.unwrap_or(pending.len())]
+                    .trim_end_matches(|c: char| !c.is_whitespace());
+                words.len()
+            }
+            None => return None,
+        };
+
+        if pending[..end].trim().is_empty() {
+            return None;
+        }
+
+        self.flushed += end;
+        self.last_flush = now;
+
+        let text = &response[..self.flushed];
+        Some(match self.code_blocks {
+            CodeBlocks::Whole => text.to_owned(),
+            CodeBlocks::Skip => strip_code_blocks(text),
+        })
+    }
+}
+
+struct Scan {
+    /// The end of the last point that the text can be sent up to.
+    boundary: Option<usize>,
+    /// The start of a code block that is not closed yet.
+    open_code: Option<usize>,
+}
+
+/// Find the points in `text` that it can be sent up to: the ends of sentences and lines, and of
+/// code blocks if they are sent whole.
+fn scan(text: &str, at_line_start: bool, code_blocks: CodeBlocks) -> Scan {
+    let mut scan = Scan {
+        boundary: None,
+        open_code: None,
+    };
+
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len();
+
+        let complete = line.ends_with('\n');
+        let at_start = i > 0 || at_line_start;
+        let trimmed = line.trim_start();
+        let is_fence = at_start && trimmed.starts_with("```");
+
+        if !complete && at_start && (is_fence || "```".starts_with(trimmed)) {
+            // This might be a fence, which is only known once the rest of the line arrives.
+            scan.open_code.get_or_insert(line_start);
+            break;
+        }
+
+        if is_fence {
+            if scan.open_code.take().is_none() {
+                scan.open_code = Some(line_start);
+            } else if code_blocks == CodeBlocks::Whole {
+                scan.boundary = Some(offset);
+            }
+            continue;
+        }
+
+        if scan.open_code.is_some() {
+            continue;
+        }
+
+        if let Some(end) = last_sentence_end(line) {
+            scan.boundary = Some(line_start + end);
+        }
+
+        if complete && !line.trim().is_empty() {
+            scan.boundary = Some(offset);
+        }
+    }
+
+    scan
+}
+
+/// The end of the last sentence that ends in `line`, after its punctuation.
+///
+/// A sentence only ends once whitespace follows, so that decimal numbers and file names don't end
+/// sentences.
+fn last_sentence_end(line: &str) -> Option<usize> {
+    let mut last = None;
+
+    for (i, c) in line.char_indices() {
+        if !matches!(c, '.' | '!' | '?') {
+            continue;
+        }
+
+        let rest = &line[i + c.len_utf8()..];
+        let after = rest.trim_start_matches(CLOSERS);
+        let end = line.len() - after.len();
+
+        if !after.starts_with(char::is_whitespace) {
+            continue;
+        }
+
+        if c == '.' && is_abbreviation(&line[..i]) {
+            continue;
+        }
+
+        last = Some(end);
+    }
+
+    last
+}
+
+/// Whether the period after `before` is part of an abbreviation, an initial, or the number of a
+/// list item.
+fn is_abbreviation(before: &str) -> bool {
+    let word = before
+        .rsplit(|c: char| c.is_whitespace() || c == '(')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches(CLOSERS);
+
+    let is_initial = word.chars().count() == 1 && word.chars().all(char::is_alphabetic);
+    let is_list_number = !before.trim().is_empty()
+        && before.trim() == word
+        && word.chars().all(|c| c.is_ascii_digit());
+
+    is_initial || is_list_number || ABBREVIATIONS.contains(&word.to_lowercase().as_str())
+}
+
+/// Remove complete fenced code blocks from `text`.
+fn strip_code_blocks(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_code = false;
+
+    for line in text.split_inclusive('\n') {
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        } else if !in_code {
+            out += line;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const TOKENS: &[&str] = &[
+        "Use a ",
+        "helper, e",
+        ".g",
+        ". `parse`",
+        ", to read ",
+        "3.",
+        "14 as a ",
+        "float",
+        ". Then",
+        " call it:\n",
+        "```rust\n",
+        "let pi",
+        " = 3.",
+        "14;\n",
+        "```",
+        "\n",
+        "It returns ",
+        "a float",
+        ".",
+    ];
+
+    /// Stream `TOKENS` into a buffer, returning the index of each token that caused an update,
+    /// and the text of the update.
+    fn flushes(code_blocks: CodeBlocks) -> Vec<(usize, String)> {
+        let start = Instant::now();
+        let mut buffer = Flush {
+            mode: FlushMode::Sentence,
+            code_blocks,
+        }
+        .buffer(start)
+        .unwrap();
+
+        let mut response = String::new();
+        let mut flushes = Vec::new();
+        for (i, token) in TOKENS.iter().enumerate() {
+            response += token;
+            if let Some(text) = buffer.push(&response, start) {
+                flushes.push((i, text));
+            }
+        }
+
+        flushes
+    }
+
+    #[test]
+    fn test_flush_sentences() {
+        assert_eq!(
+            flushes(CodeBlocks::Whole),
+            [
+                (
+                    8,
+                    "Use a helper, e.g. `parse`, to read 3.14 as a float.".to_owned()
+                ),
+                (
+                    9,
+                    "Use a helper, e.g. `parse`, to read 3.14 as a float. Then call it:\n"
+                        .to_owned()
+                ),
+                (
+                    15,
+                    "Use a helper, e.g. `parse`, to read 3.14 as a float. Then call it:\n\
+                     ```rust\nlet pi = 3.14;\n```\n"
+                        .to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skip_code_blocks() {
+        let flushes = flushes(CodeBlocks::Skip);
+        assert_eq!(flushes.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [8, 9]);
+        assert!(flushes.iter().all(|(_, text)| !text.contains("let pi")));
+
+        let mut buffer = Flush {
+            mode: FlushMode::Sentence,
+            code_blocks: CodeBlocks::Skip,
+        }
+        .buffer(Instant::now())
+        .unwrap();
+        let response = "Call it:\n```rust\nparse()\n```\nIt returns a float. Done";
+        assert_eq!(
+            buffer.push(response, Instant::now()).unwrap(),
+            "Call it:\nIt returns a float."
+        );
+    }
+
+    #[test]
+    fn test_flush_after_max_latency() {
+        let start = Instant::now();
+        let mut buffer = Flush {
+            mode: FlushMode::Sentence,
+            code_blocks: CodeBlocks::Whole,
+        }
+        .buffer(start)
+        .unwrap();
+
+        let response = "This sentence takes a long time to gener";
+        assert_eq!(buffer.push(response, start), None);
+
+        // Only whole words are sent.
+        let late = start + MAX_FLUSH_LATENCY;
+        assert_eq!(
+            buffer.push(response, late).unwrap(),
+            "This sentence takes a long time to "
+        );
+
+        // Text in an open code block is held back, however long it waits.
+        let response = "This sentence takes a long time to generate:\n```\nfn main";
+        assert_eq!(
+            buffer.push(response, late).unwrap(),
+            "This sentence takes a long time to generate:\n"
+        );
+        assert_eq!(buffer.push(response, late + MAX_FLUSH_LATENCY), None);
+    }
+
+    #[test]
+    fn test_inline_code_is_not_a_fence() {
+        let mut buffer = Flush {
+            mode: FlushMode::Sentence,
+            code_blocks: CodeBlocks::Whole,
+        }
+        .buffer(Instant::now())
+        .unwrap();
+
+        let response = "`parse` reads floats. It";
+        assert_eq!(
+            buffer.push(response, Instant::now()).unwrap(),
+            "`parse` reads floats."
+        );
+    }
+
+    #[test]
+    fn test_last_sentence_end() {
+        assert_eq!(last_sentence_end("It works. Mostly"), Some(9));
+        assert_eq!(last_sentence_end("Really?! Yes"), Some(8));
+        assert_eq!(last_sentence_end("See \"main.rs\". Then"), Some(14));
+        assert_eq!(last_sentence_end("Pi is 3.14 or so"), None);
+        assert_eq!(last_sentence_end("Tools, i.e. linters"), None);
+        assert_eq!(last_sentence_end("Written by J. Doe, etc. and"), None);
+        assert_eq!(last_sentence_end("1. First item"), None);
+        assert_eq!(last_sentence_end("It ends here."), None);
+    }
+}
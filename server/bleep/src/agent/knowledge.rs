@@ -0,0 +1,143 @@
+//! Documents that operators add to the agent's context, without indexing a repository.
+//!
+//! Documents are added with `Agent::add_knowledge_base_document`, which agents are built with for
+//! every file of `Configuration::knowledge_base`. On every step, the ones most relevant to the
+//! query are shown in the system prompt, as `Additional context:` sections.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use super::tokens::Tokenizer;
+use crate::semantic;
+
+/// The most tokens of documents that are shown in the system prompt.
+pub const MAX_TOKENS: usize = 2000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnowledgeDoc {
+    pub title: String,
+    pub content: String,
+}
+
+impl KnowledgeDoc {
+    /// Read the documents of `Configuration::knowledge_base`, titled with their file names.
+    pub fn load(paths: &[PathBuf]) -> Result<Vec<Self>> {
+        paths
+            .iter()
+            .map(|path| {
+                let content = std::fs::read_to_string(path).with_context(|| {
+                    format!("failed to read knowledge base document {}", path.display())
+                })?;
+                let title = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                Ok(Self { title, content })
+            })
+            .collect()
+    }
+
+    fn section(&self) -> String {
+        format!("Additional context: {}\n{}\n", self.title, self.content)
+    }
+}
+
+/// The sections of the system prompt for the documents most relevant to `query`.
+///
+/// Documents are ranked by their BM25 score against the query, and those that match none of its
+/// terms are left out. Sections are added in order until they take up `max_tokens`, and the
+/// section that doesn't fit is cut off.
+pub fn sections(
+    docs: &[KnowledgeDoc],
+    query: &str,
+    tokenizer: &Tokenizer,
+    max_tokens: usize,
+) -> Result<Vec<String>> {
+    let texts = docs
+        .iter()
+        .map(|d| format!("{}\n{}", d.title, d.content))
+        .collect::<Vec<_>>();
+    let scores = semantic::bm25_scores(query, texts.iter().map(String::as_str));
+
+    let mut ranked = docs
+        .iter()
+        .zip(scores)
+        .filter(|(_, score)| *score > 0.)
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let mut sections = Vec::new();
+    let mut remaining = max_tokens;
+    for (doc, _) in ranked {
+        let section = doc.section();
+        let mut tokens = tokenizer.encode(&section);
+
+        if tokens.len() <= remaining {
+            remaining -= tokens.len();
+            sections.push(section);
+            continue;
+        }
+
+        if remaining > 0 {
+            tokens.truncate(remaining);
+            sections.push(tokenizer.decode(tokens)?);
+        }
+        break;
+    }
+
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(title: &str, content: &str) -> KnowledgeDoc {
+        KnowledgeDoc {
+            title: title.to_owned(),
+            content: content.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_sections_ranked_and_capped() {
+        let tokenizer = Tokenizer::new("gpt-4-0613").unwrap();
+        let docs = [
+            doc(
+                "Deployments",
+                "Services are deployed with the internal release tool.",
+            ),
+            doc(
+                "On-call",
+                "Pages for the billing service go to the payments team.",
+            ),
+            doc(
+                "Billing",
+                "The billing service retries failed charges three times.",
+            ),
+        ];
+
+        let found = sections(
+            &docs,
+            "How does billing retry charges?",
+            &tokenizer,
+            MAX_TOKENS,
+        )
+        .unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found[0].starts_with("Additional context: Billing\n"));
+        assert!(found[1].starts_with("Additional context: On-call\n"));
+
+        // The cap cuts off the sections that don't fit in it.
+        let long = doc(
+            "Billing",
+            &"The billing service retries charges. ".repeat(1000),
+        );
+        let found = sections(&[long], "billing", &tokenizer, MAX_TOKENS).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(tokenizer.count(&found[0]) <= MAX_TOKENS);
+        assert!(tokenizer.count(&found[0]) > MAX_TOKENS - 10);
+    }
+}
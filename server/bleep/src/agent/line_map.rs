@@ -0,0 +1,360 @@
+//! Mapping of cited line ranges onto newer versions of their files.
+//!
+//! Stored threads are read long after they were answered, by which time the repository may have
+//! moved on, and cited lines may have moved down or up their file. Code chunks are stored with
+//! the git blob of the file they were cited from, so that when a thread is read, the cited blob
+//! can be diffed against the current one, and each cited range moved through the changes.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use tracing::debug;
+
+use crate::{
+    agent::{exchange::Exchange, relocation::git},
+    repo::RepoRef,
+    Application,
+};
+
+/// The most lines that are inserted or deleted between two versions of a file, for their diff to
+/// be worth computing. Ranges in files that changed more than this are marked as approximate.
+const MAX_EDITS: usize = 2000;
+
+/// A cited line range, as it is in the current version of its file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MappedLines {
+    pub start: usize,
+    pub end: usize,
+    /// Whether some of the cited lines were changed, so that the range may not cite the same code.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub approximate: bool,
+}
+
+/// Map the 1-based, inclusive line range `start..=end` of `old` onto `new`.
+///
+/// The range ends up spanning the cited lines that are left in `new`. If they were all changed,
+/// it is moved to where they used to be instead.
+pub fn map_lines(old: &str, new: &str, start: usize, end: usize) -> MappedLines {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    let Some(common) = common_lines(&old, &new) else {
+        return MappedLines {
+            start,
+            end,
+            approximate: true,
+        };
+    };
+
+    let first = start.saturating_sub(1);
+    let last = end.max(start).saturating_sub(1);
+    let kept = common
+        .iter()
+        .filter(|(i, _)| (first..=last).contains(i))
+        .map(|(_, j)| *j)
+        .collect::<Vec<_>>();
+
+    match (kept.first(), kept.last()) {
+        (Some(&new_first), Some(&new_last)) => MappedLines {
+            start: new_first + 1,
+            end: new_last + 1,
+            approximate: kept.len() != last - first + 1 || new_last - new_first != last - first,
+        },
+        _ => {
+            let last_line = new.len().saturating_sub(1);
+            let anchor = common
+                .iter()
+                .rev()
+                .find(|(i, _)| *i < first)
+                .map(|(_, j)| j + 1)
+                .unwrap_or(0)
+                .min(last_line);
+
+            MappedLines {
+                start: anchor + 1,
+                end: (anchor + last - first).min(last_line) + 1,
+                approximate: true,
+            }
+        }
+    }
+}
+
+/// The 0-based indexes of the lines of `old` and `new` that are left as they are by a shortest
+/// edit between them, or `None` if they differ by more than `MAX_EDITS` lines.
+fn common_lines(old: &[&str], new: &[&str]) -> Option<Vec<(usize, usize)>> {
+    // Unchanged lines at the start and end are matched up front, which keeps the diff of a small
+    // change to a large file cheap.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let inner = myers(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    )?;
+
+    let mut common = (0..prefix).map(|i| (i, i)).collect::<Vec<_>>();
+    common.extend(inner.into_iter().map(|(i, j)| (i + prefix, j + prefix)));
+    common.extend((0..suffix).map(|k| (old.len() - suffix + k, new.len() - suffix + k)));
+    Some(common)
+}
+
+/// Myers' diff algorithm, returning the pairs of indexes of lines that are kept.
+fn myers(a: &[&str], b: &[&str]) -> Option<Vec<(usize, usize)>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (a.len() + b.len()).min(MAX_EDITS) as isize;
+    let index = |k: isize| (k + max) as usize;
+
+    // The furthest `x` reached on each diagonal `k = x - y`, saved before every round.
+    let mut v = vec![0isize; 2 * max as usize + 2];
+    let mut trace = Vec::new();
+
+    'search: {
+        for d in 0..=max {
+            trace.push(v.clone());
+
+            for k in (-d..=d).step_by(2) {
+                let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                    v[index(k + 1)]
+                } else {
+                    v[index(k - 1)] + 1
+                };
+                let mut y = x - k;
+
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+
+                v[index(k)] = x;
+                if x >= n && y >= m {
+                    break 'search;
+                }
+            }
+        }
+
+        return None;
+    }
+
+    let (mut x, mut y) = (n, m);
+    let mut kept = Vec::new();
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[index(prev_k)];
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            kept.push((x as usize, y as usize));
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+
+    kept.reverse();
+    Some(kept)
+}
+
+/// The git directory of a repository, if it has a history to read blobs from.
+fn git_dir(app: &Application, repo_ref: &RepoRef) -> Option<(PathBuf, Option<String>)> {
+    app.repo_pool
+        .read(repo_ref, |_, repo| {
+            (repo.disk_path.clone(), repo.revision.clone())
+        })
+        .filter(|_| repo_ref.has_branches())
+}
+
+/// Whether `exchange` searched `repo_ref`. Exchanges that don't record their repository searched
+/// the thread's.
+fn in_repo(exchange: &Exchange, repo_ref: &RepoRef) -> bool {
+    exchange.repo_ref.as_ref().map_or(true, |r| r == repo_ref)
+}
+
+/// The blob of `path` at `revision`.
+async fn blob_at(dir: &Path, revision: &str, path: &str) -> Option<String> {
+    let spec = format!("{revision}:{path}");
+    match git(dir, &["rev-parse", &spec]).await {
+        Ok(blob) => Some(blob.trim().to_owned()),
+        Err(e) => {
+            debug!(?e, path, revision, "failed to find the blob of a file");
+            None
+        }
+    }
+}
+
+/// Stamp the code chunks of `exchanges` with the blobs of their files at `revision`.
+///
+/// Chunks that already have a blob keep it, as they were cited at an earlier revision. Exchanges
+/// that searched another repository are left as they are.
+pub async fn stamp_blobs(
+    app: &Application,
+    repo_ref: &RepoRef,
+    revision: Option<&str>,
+    exchanges: &mut [Exchange],
+) {
+    let (Some((dir, _)), Some(revision)) = (git_dir(app, repo_ref), revision) else {
+        return;
+    };
+
+    let mut blobs = HashMap::new();
+    for chunk in exchanges
+        .iter_mut()
+        .filter(|e| in_repo(e, repo_ref))
+        .flat_map(|e| &mut e.code_chunks)
+        .filter(|c| c.blob.is_none())
+    {
+        chunk.blob = match blobs.get(&chunk.path) {
+            Some(blob) => blob.clone(),
+            None => {
+                let blob = blob_at(&dir, revision, &chunk.path).await;
+                blobs.insert(chunk.path.clone(), blob.clone());
+                blob
+            }
+        };
+    }
+}
+
+/// Map the code chunks of `exchanges` onto the current, indexed versions of their files.
+///
+/// Chunks of files that changed since they were cited get a `mapped` range, next to their
+/// original one. Chunks without a blob, chunks of deleted files, and chunks of exchanges that
+/// searched another repository are left as they are.
+pub async fn remap(app: &Application, repo_ref: &RepoRef, exchanges: &mut [Exchange]) {
+    let Some((dir, Some(revision))) = git_dir(app, repo_ref) else {
+        return;
+    };
+
+    let mut current_blobs = HashMap::<String, Option<String>>::new();
+    let mut contents = HashMap::<String, Option<String>>::new();
+
+    for chunk in exchanges
+        .iter_mut()
+        .filter(|e| in_repo(e, repo_ref))
+        .flat_map(|e| &mut e.code_chunks)
+        .filter(|c| !c.deleted)
+    {
+        let Some(blob) = chunk.blob.clone() else {
+            continue;
+        };
+
+        let path = chunk.moved_to.clone().unwrap_or_else(|| chunk.path.clone());
+        let current = match current_blobs.get(&path) {
+            Some(current) => current.clone(),
+            None => {
+                let current = blob_at(&dir, &revision, &path).await;
+                current_blobs.insert(path, current.clone());
+                current
+            }
+        };
+
+        let Some(current) = current.filter(|c| *c != blob) else {
+            continue;
+        };
+
+        for sha in [&blob, &current] {
+            if contents.get(sha).is_none() {
+                let content = git(&dir, &["cat-file", "blob", sha]).await.ok();
+                contents.insert(sha.clone(), content);
+            }
+        }
+
+        if let (Some(Some(old)), Some(Some(new))) = (contents.get(&blob), contents.get(&current)) {
+            chunk.mapped = Some(map_lines(old, new, chunk.start_line, chunk.end_line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = "fn one() {}\nfn two() {}\nfn three() {}\nfn four() {}\nfn five() {}\n";
+
+    #[test]
+    fn test_unchanged() {
+        assert_eq!(
+            map_lines(OLD, OLD, 2, 4),
+            MappedLines {
+                start: 2,
+                end: 4,
+                approximate: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_insertions_above() {
+        let new = format!("use std::fmt;\nuse std::io;\n\n{OLD}");
+        assert_eq!(
+            map_lines(OLD, &new, 2, 4),
+            MappedLines {
+                start: 5,
+                end: 7,
+                approximate: false
+            }
+        );
+
+        // Lines inserted inside the range widen it.
+        let new = "fn one() {}\nfn two() {}\nfn inserted() {}\nfn three() {}\nfn four() {}\n";
+        assert_eq!(
+            map_lines(OLD, new, 2, 4),
+            MappedLines {
+                start: 2,
+                end: 5,
+                approximate: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_deletions_overlapping() {
+        // The first cited line is deleted, along with the line above it.
+        let new = "fn three() {}\nfn four() {}\nfn five() {}\n";
+        assert_eq!(
+            map_lines(OLD, new, 2, 4),
+            MappedLines {
+                start: 1,
+                end: 2,
+                approximate: true
+            }
+        );
+
+        // Every cited line is replaced, so the range stays where it was.
+        let new = "fn one() {}\nfn replaced() {}\nfn five() {}\n";
+        assert_eq!(
+            map_lines(OLD, new, 3, 4),
+            MappedLines {
+                start: 2,
+                end: 3,
+                approximate: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_myers() {
+        let a = ["a", "b", "c", "a", "b", "b", "a"];
+        let b = ["c", "b", "a", "b", "a", "c"];
+
+        let kept = myers(&a, &b).unwrap();
+        assert_eq!(kept.len(), 4);
+        assert!(kept.iter().all(|(i, j)| a[*i] == b[*j]));
+        assert!(kept.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 < w[1].1));
+
+        assert_eq!(myers(&[], &[]).unwrap(), []);
+    }
+}
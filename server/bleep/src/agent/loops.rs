@@ -0,0 +1,157 @@
+//! Detection of answer loops, where the model keeps calling a tool with near-identical arguments.
+//!
+//! Without this, the model can oscillate between rephrasings of one search, like "retry logic" and
+//! "retry logic implementation", and never answer. Every tool call is compared to the one before
+//! it. After a few near-duplicate calls in a row the model is reminded to use what it has, and
+//! after a few more it is made to answer.
+
+/// The system message that reminds the model to stop repeating a search.
+pub const NUDGE_MESSAGE: &str = "You have already searched for this. Either use the results you \
+    already have, or answer the query.";
+
+pub const DEFAULT_SIMILARITY: f32 = 0.8;
+pub const DEFAULT_NUDGE_AFTER: usize = 2;
+pub const DEFAULT_ANSWER_AFTER: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// How similar the arguments of two calls to a tool must be, for them to be near-duplicates.
+    pub similarity: f32,
+    /// The length of a run of near-duplicate calls at which the model is nudged.
+    pub nudge_after: usize,
+    /// The length of a run of near-duplicate calls at which the model is made to answer.
+    pub answer_after: usize,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            similarity: DEFAULT_SIMILARITY,
+            nudge_after: DEFAULT_NUDGE_AFTER,
+            answer_after: DEFAULT_ANSWER_AFTER,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The call goes ahead as usual.
+    Continue,
+    /// The call goes ahead, and the model is nudged on the following steps.
+    Nudge,
+    /// The call is dropped, and the model answers with what it has.
+    Answer,
+}
+
+#[derive(Debug, Default)]
+pub struct LoopGuard {
+    /// The tool and arguments of the last call, as given by `Action::cache_key`.
+    last: Option<(&'static str, String)>,
+    /// The length of the run of near-duplicate calls that ends with `last`.
+    run: usize,
+}
+
+impl LoopGuard {
+    /// Record a call to `tool` with `args`, returning what to do about it.
+    pub fn record(&mut self, tool: &'static str, args: &str, thresholds: &Thresholds) -> Verdict {
+        let repeated = matches!(
+            &self.last,
+            Some((last_tool, last_args))
+                if *last_tool == tool && similarity(last_args, args) >= thresholds.similarity
+        );
+
+        self.run = if repeated { self.run + 1 } else { 1 };
+        self.last = Some((tool, args.to_owned()));
+
+        if self.run >= thresholds.answer_after {
+            Verdict::Answer
+        } else if self.run >= thresholds.nudge_after {
+            Verdict::Nudge
+        } else {
+            Verdict::Continue
+        }
+    }
+
+    /// Whether the model should be nudged on the next step.
+    pub fn is_nudging(&self, thresholds: &Thresholds) -> bool {
+        self.run >= thresholds.nudge_after
+    }
+
+    /// The length of the current run of near-duplicate calls.
+    pub fn run(&self) -> usize {
+        self.run
+    }
+}
+
+/// How similar two sets of tool arguments are, between 0 and 1.
+///
+/// This is the share of the words of the shorter argument that are also in the longer one, with
+/// case and word order ignored. Refinements like "retry logic" and "retry logic implementation"
+/// are near-identical by this measure.
+pub fn similarity(a: &str, b: &str) -> f32 {
+    let words = |s: &str| {
+        let mut words = s
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>();
+        words.sort();
+        words.dedup();
+        words
+    };
+
+    let (a, b) = (words(a), words(b));
+    let shortest = a.len().min(b.len());
+    if shortest == 0 {
+        return if a.len() == b.len() { 1. } else { 0. };
+    }
+
+    let shared = a.iter().filter(|w| b.contains(w)).count();
+    shared as f32 / shortest as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity("retry logic", "Retry  logic"), 1.);
+        assert_eq!(similarity("retry logic", "retry logic implementation"), 1.);
+        assert_eq!(similarity("retry logic", "database migrations"), 0.);
+        assert_eq!(similarity("", ""), 1.);
+        assert!(similarity("retry logic", "retry backoff constants") < DEFAULT_SIMILARITY);
+    }
+
+    #[test]
+    fn test_guard_nudges_then_answers() {
+        let thresholds = Thresholds::default();
+        let mut guard = LoopGuard::default();
+
+        assert_eq!(
+            guard.record("code", "retry logic", &thresholds),
+            Verdict::Continue
+        );
+        assert!(!guard.is_nudging(&thresholds));
+
+        // A different tool breaks the run.
+        assert_eq!(
+            guard.record("path", "retry logic", &thresholds),
+            Verdict::Continue
+        );
+        assert_eq!(
+            guard.record("path", "retry logic implementation", &thresholds),
+            Verdict::Nudge
+        );
+        assert!(guard.is_nudging(&thresholds));
+        assert_eq!(guard.record("path", "retry", &thresholds), Verdict::Answer);
+        assert_eq!(guard.run(), 3);
+
+        // A new search ends the nudge.
+        assert_eq!(
+            guard.record("path", "database migrations", &thresholds),
+            Verdict::Continue
+        );
+        assert!(!guard.is_nudging(&thresholds));
+    }
+}
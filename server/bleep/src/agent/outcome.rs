@@ -0,0 +1,216 @@
+//! Classification of answers by outcome, so that an apology for finding nothing can be told apart
+//! from a confident answer.
+//!
+//! The answer model starts its answer with a tag like `[outcome: not_found]`, which is stripped
+//! before the answer is shown. Answers without a valid tag are classified by their text instead.
+//! Either way, the outcome is adjusted by what is known about how the answer came about.
+
+use super::exchange::AnswerOutcome;
+
+const TAG_PREFIX: &str = "[outcome:";
+
+/// The longest that a tag can be. Text that starts like a tag but runs longer is not one.
+const MAX_TAG_LEN: usize = 32;
+
+/// Phrases that answers use to own up to not finding what the query asks about.
+const NOT_FOUND_PHRASES: &[&str] = &[
+    "couldn't find",
+    "could not find",
+    "unable to find",
+    "wasn't able to find",
+    "was not able to find",
+    "no relevant",
+    "not enough information",
+    "don't have enough information",
+    "do not have enough information",
+];
+
+/// How an answer came about, besides its text.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Signals {
+    /// Whether the model was made to answer before it chose to, such as when it was stuck in a
+    /// loop.
+    pub forced: bool,
+    /// Whether the answer was written without any code in its context.
+    pub empty_context: bool,
+}
+
+/// Split the outcome tag off the start of `response`, returning the outcome it names and the rest
+/// of the response.
+///
+/// While the response is still being streamed, text that may turn out to be a tag is held back,
+/// so that the tag is never shown. Once it is `complete`, text that didn't turn out to be a tag
+/// is returned in full. Tags that name an unknown outcome are stripped all the same.
+pub fn split_tag(response: &str, complete: bool) -> (Option<AnswerOutcome>, &str) {
+    let trimmed = response.trim_start();
+
+    if !trimmed.starts_with(TAG_PREFIX) {
+        let pending = !complete && !trimmed.is_empty() && TAG_PREFIX.starts_with(trimmed);
+        return (None, if pending { "" } else { response });
+    }
+
+    match trimmed.find(']') {
+        Some(end) if end < MAX_TAG_LEN => {
+            let outcome = AnswerOutcome::parse(&trimmed[TAG_PREFIX.len()..end]);
+            (outcome, trimmed[end + 1..].trim_start())
+        }
+        None if !complete && trimmed.len() < MAX_TAG_LEN => (None, ""),
+        _ => (None, response),
+    }
+}
+
+/// Decide the outcome of an answer, from its `tag` if it had one, or else from its `article` and
+/// `summary`.
+pub fn classify(
+    tag: Option<AnswerOutcome>,
+    article: &str,
+    summary: Option<&str>,
+    signals: Signals,
+) -> AnswerOutcome {
+    match tag.unwrap_or_else(|| classify_text(article, summary)) {
+        // The model was cut off while searching, so it may have missed something.
+        AnswerOutcome::Answered if signals.forced => AnswerOutcome::Partial,
+        // Without code, there is nothing that part of the answer could have been found in.
+        AnswerOutcome::Partial if tag.is_none() && signals.empty_context => AnswerOutcome::NotFound,
+        outcome => outcome,
+    }
+}
+
+/// A fallback classification of answers that have no tag.
+fn classify_text(article: &str, summary: Option<&str>) -> AnswerOutcome {
+    let summary = summary.unwrap_or_default().trim();
+    let text = format!("{article}\n{summary}").to_lowercase();
+
+    if NOT_FOUND_PHRASES.iter().any(|phrase| text.contains(phrase)) {
+        // Answers that found nothing are only a summary, as the answer prompt asks.
+        if article.trim().is_empty() {
+            AnswerOutcome::NotFound
+        } else {
+            AnswerOutcome::Partial
+        }
+    } else if article.trim().is_empty() && summary.ends_with('?') {
+        AnswerOutcome::Clarification
+    } else {
+        AnswerOutcome::Answered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_split_tag() {
+        let response = "[outcome: not_found]\n# Retries\n\nThere is no retry logic.";
+        assert_eq!(
+            split_tag(response, true),
+            (
+                Some(AnswerOutcome::NotFound),
+                "# Retries\n\nThere is no retry logic."
+            )
+        );
+
+        // Partial tags are held back while the answer is streamed, and shown if they don't turn
+        // out to be tags.
+        for partial in ["[", "[outc", "  [outcome: ans"] {
+            assert_eq!(split_tag(partial, false), (None, ""));
+        }
+        assert_eq!(split_tag("[outc", true), (None, "[outc"));
+        assert_eq!(split_tag("[outcome: answ", true), (None, "[outcome: answ"));
+
+        // Links are not mistaken for tags.
+        let link = "[`Retry`](src/retry.rs#L10) wraps requests.";
+        assert_eq!(split_tag(link, false), (None, link));
+
+        // Unknown outcomes are stripped, but not used.
+        assert_eq!(split_tag("[outcome: great]Hi", true), (None, "Hi"));
+        assert_eq!(
+            split_tag("[outcome:PARTIAL] Hi", true),
+            (Some(AnswerOutcome::Partial), "Hi")
+        );
+
+        let untagged = "# Retries\n\nRequests are retried.";
+        assert_eq!(split_tag(untagged, false), (None, untagged));
+        assert_eq!(split_tag("", false), (None, ""));
+    }
+
+    #[test]
+    fn test_classify() {
+        let signals = Signals::default();
+        let forced = Signals {
+            forced: true,
+            ..signals
+        };
+        let empty_context = Signals {
+            empty_context: true,
+            ..signals
+        };
+
+        // Tags are trusted, unless the model was cut off.
+        let article = "# Retries\n\nRequests are retried.";
+        let tagged = |outcome, signals| classify(Some(outcome), article, None, signals);
+        assert_eq!(
+            tagged(AnswerOutcome::Answered, signals),
+            AnswerOutcome::Answered
+        );
+        assert_eq!(
+            tagged(AnswerOutcome::Answered, forced),
+            AnswerOutcome::Partial
+        );
+        assert_eq!(
+            tagged(AnswerOutcome::NotFound, forced),
+            AnswerOutcome::NotFound
+        );
+        assert_eq!(
+            tagged(AnswerOutcome::Partial, empty_context),
+            AnswerOutcome::Partial
+        );
+    }
+
+    #[test]
+    fn test_classify_without_tag() {
+        let signals = Signals::default();
+        let untagged = |article, summary| classify(None, article, Some(summary), signals);
+
+        assert_eq!(
+            untagged("", "I'm sorry, I couldn't find any retry logic."),
+            AnswerOutcome::NotFound
+        );
+        assert_eq!(
+            untagged(
+                "# Retries\n\nRequests are retried by `send`.",
+                "I could not find where the backoff is configured."
+            ),
+            AnswerOutcome::Partial
+        );
+        assert_eq!(
+            untagged("", "Do you mean the HTTP client, or the job queue?"),
+            AnswerOutcome::Clarification
+        );
+        assert_eq!(
+            untagged("# Retries\n\nRequests are retried.", "Retries use backoff."),
+            AnswerOutcome::Answered
+        );
+        assert_eq!(
+            classify(None, "Hello!", None, signals),
+            AnswerOutcome::Answered
+        );
+
+        // With no code to go on, nothing was found.
+        let empty_context = Signals {
+            empty_context: true,
+            ..signals
+        };
+        assert_eq!(
+            classify(
+                None,
+                "# Retries\n\nRetries may be handled by a library.",
+                Some("I couldn't find the retry logic itself."),
+                empty_context,
+            ),
+            AnswerOutcome::NotFound
+        );
+    }
+}
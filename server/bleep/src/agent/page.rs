@@ -0,0 +1,172 @@
+//! Pages of the exchanges in a thread, for clients that show long threads.
+//!
+//! A page ends with a cursor to the next page, which names the index of the exchange that the next
+//! page starts at. The cursor is signed for the thread, so that a client can't forge a cursor, or
+//! use the cursor of one thread to read another.
+
+use anyhow::{bail, Context, Result};
+use ring::hmac;
+
+use super::exchange::Exchange;
+
+/// The number of exchanges in a page, when a client doesn't ask for a size.
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+
+#[derive(serde::Serialize, Debug)]
+pub struct ThreadPage {
+    pub exchanges: Vec<Exchange>,
+    /// The cursor to pass to get the next page, or `None` if this is the last page.
+    pub next_cursor: Option<String>,
+}
+
+/// The page of `exchanges` that starts at `cursor`, or at the first exchange if there is no cursor.
+///
+/// `key` signs the cursors, and must be the same key that signed `cursor`.
+pub fn page(
+    exchanges: &[Exchange],
+    thread_id: uuid::Uuid,
+    key: &[u8],
+    cursor: Option<&str>,
+    page_size: usize,
+) -> Result<ThreadPage> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+
+    let start = match cursor {
+        Some(cursor) => decode(&key, thread_id, cursor)?,
+        None => 0,
+    };
+
+    // Exchanges are never removed from a thread, but a cursor can outlive a thread that is
+    // deleted and stored again.
+    let start = start.min(exchanges.len());
+    let end = start.saturating_add(page_size.max(1)).min(exchanges.len());
+
+    Ok(ThreadPage {
+        exchanges: exchanges[start..end].to_vec(),
+        next_cursor: (end < exchanges.len()).then(|| encode(&key, thread_id, end)),
+    })
+}
+
+fn sign(key: &hmac::Key, thread_id: uuid::Uuid, index: usize) -> hmac::Tag {
+    hmac::sign(key, format!("{thread_id}:{index}").as_bytes())
+}
+
+fn encode(key: &hmac::Key, thread_id: uuid::Uuid, index: usize) -> String {
+    let tag = sign(key, thread_id, index)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    format!("{index}.{tag}")
+}
+
+fn decode(key: &hmac::Key, thread_id: uuid::Uuid, cursor: &str) -> Result<usize> {
+    let (index, tag) = cursor.split_once('.').context("malformed cursor")?;
+    let index = index.parse::<usize>().context("malformed cursor")?;
+
+    if tag.len() % 2 != 0 || !tag.is_ascii() {
+        bail!("malformed cursor");
+    }
+
+    let tag = (0..tag.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&tag[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .context("malformed cursor")?;
+
+    hmac::verify(key, format!("{thread_id}:{index}").as_bytes(), &tag)
+        .ok()
+        .context("invalid cursor")?;
+
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"a key that signs cursors in tests";
+
+    fn thread(len: usize) -> Vec<Exchange> {
+        (0..len)
+            .map(|_| Exchange::new(uuid::Uuid::new_v4(), Default::default()))
+            .collect()
+    }
+
+    fn ids(exchanges: &[Exchange]) -> Vec<uuid::Uuid> {
+        exchanges.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn test_first_page() {
+        let thread_id = uuid::Uuid::new_v4();
+        let exchanges = thread(5);
+
+        let first = page(&exchanges, thread_id, KEY, None, 2).unwrap();
+        assert_eq!(ids(&first.exchanges), ids(&exchanges[..2]));
+        assert!(first.next_cursor.is_some());
+
+        // A thread that fits in one page has no next page.
+        let whole = page(&exchanges, thread_id, KEY, None, 5).unwrap();
+        assert_eq!(ids(&whole.exchanges), ids(&exchanges));
+        assert_eq!(whole.next_cursor, None);
+
+        let empty = page(&[], thread_id, KEY, None, 5).unwrap();
+        assert!(empty.exchanges.is_empty());
+        assert_eq!(empty.next_cursor, None);
+    }
+
+    #[test]
+    fn test_next_page() {
+        let thread_id = uuid::Uuid::new_v4();
+        let exchanges = thread(5);
+
+        let mut cursor = None;
+        let mut pages = Vec::new();
+        loop {
+            let page = page(&exchanges, thread_id, KEY, cursor.as_deref(), 2).unwrap();
+            pages.push(ids(&page.exchanges));
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(
+            pages,
+            [
+                ids(&exchanges[..2]),
+                ids(&exchanges[2..4]),
+                ids(&exchanges[4..])
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_cursor() {
+        let thread_id = uuid::Uuid::new_v4();
+        let exchanges = thread(5);
+
+        let cursor = page(&exchanges, thread_id, KEY, None, 2)
+            .unwrap()
+            .next_cursor
+            .unwrap();
+        let (_, tag) = cursor.split_once('.').unwrap();
+
+        // Moving the cursor to another exchange breaks its signature.
+        let forged = format!("4.{tag}");
+        assert!(page(&exchanges, thread_id, KEY, Some(&forged), 2).is_err());
+
+        // Cursors only work for the thread, and with the key, that they were made for.
+        let other_thread = uuid::Uuid::new_v4();
+        assert!(page(&exchanges, other_thread, KEY, Some(&cursor), 2).is_err());
+        assert!(page(&exchanges, thread_id, b"another key", Some(&cursor), 2).is_err());
+
+        for malformed in ["", "2", "two.00", "2.0", "2.zz", "2.é0"] {
+            assert!(page(&exchanges, thread_id, KEY, Some(malformed), 2).is_err());
+        }
+
+        assert!(page(&exchanges, thread_id, KEY, Some(&cursor), 2).is_ok());
+    }
+}
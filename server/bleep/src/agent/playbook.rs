@@ -0,0 +1,490 @@
+//! Playbooks: templates of questions that are asked one after another in a single thread, and whose
+//! answers are then stitched together into one.
+//!
+//! Recurring tasks, like reviewing the security of a module, follow the same questions every time.
+//! A playbook names these questions once, with `{parameter}` placeholders, alongside an instruction
+//! for the final answer. Playbooks are built in, read from `Configuration::playbooks_dir`, or set
+//! in the configuration file, in increasing order of precedence.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{bail, Context, Result};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    agent::{
+        builder::Driver,
+        exchange::{AnswerSource, Exchange, Update},
+        prompts, Agent, ANSWER_MODEL,
+    },
+    analytics::EventData,
+    llm_gateway::api::Message,
+    query::parser,
+    Configuration,
+};
+
+/// A named list of questions, and an instruction to combine their answers with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Playbook {
+    pub name: String,
+    /// A short title of what the playbook does, which may contain parameters.
+    ///
+    /// This is the query of the exchange that holds the combined answer.
+    pub description: String,
+    /// The names of the parameters that every run must be given.
+    #[serde(default)]
+    pub parameters: Vec<String>,
+    pub questions: Vec<String>,
+    /// How the answers to `questions` are combined into the final answer.
+    pub aggregation: String,
+}
+
+/// A playbook, with its parameters filled in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expanded {
+    pub name: String,
+    pub description: String,
+    pub questions: Vec<String>,
+    pub aggregation: String,
+}
+
+/// The answer to a single question of a playbook run.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubAnswer {
+    pub question: String,
+    pub answer: Option<String>,
+    /// Why the question could not be answered, if it failed.
+    pub error: Option<String>,
+}
+
+/// The outcome of a playbook run.
+#[derive(Serialize, Debug, Clone)]
+pub struct PlaybookRun {
+    pub thread_id: uuid::Uuid,
+    pub sub_answers: Vec<SubAnswer>,
+    /// The combined answer, with the citations of the answers it was combined from.
+    pub answer: String,
+}
+
+impl Playbook {
+    /// Fill in the parameters of this playbook with `params`.
+    ///
+    /// Every parameter must be given, and no others.
+    pub fn expand(&self, params: &HashMap<String, String>) -> Result<Expanded> {
+        if let Some(missing) = self.parameters.iter().find(|p| !params.contains_key(*p)) {
+            bail!("missing parameter `{missing}`");
+        }
+
+        if let Some(unknown) = params.keys().find(|p| !self.parameters.contains(*p)) {
+            bail!("unknown parameter `{unknown}`");
+        }
+
+        let fill = |template: &str| {
+            params
+                .iter()
+                .fold(template.to_owned(), |text, (name, value)| {
+                    text.replace(&format!("{{{name}}}"), value)
+                })
+        };
+
+        Ok(Expanded {
+            name: self.name.clone(),
+            description: fill(&self.description),
+            questions: self.questions.iter().map(|q| fill(q)).collect(),
+            aggregation: fill(&self.aggregation),
+        })
+    }
+}
+
+/// The playbooks that ship with bloop.
+pub fn builtin() -> Vec<Playbook> {
+    let owned = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+
+    vec![
+        Playbook {
+            name: "security_review".to_owned(),
+            description: "Security review of {module}".to_owned(),
+            parameters: owned(&["module"]),
+            questions: owned(&[
+                "What does {module} do, and where does it receive input from users or other \
+                 services?",
+                "How does {module} validate and sanitise the input it receives?",
+                "How are authentication and authorisation checked in {module}?",
+                "How does {module} handle secrets, like passwords, tokens and keys?",
+                "What do errors in {module} reveal to callers, and is sensitive data ever logged?",
+            ]),
+            aggregation: "Write a security review of {module}. List every risk that was found \
+                          with its severity and a suggested fix, most severe first, followed by \
+                          what {module} already does well."
+                .to_owned(),
+        },
+        Playbook {
+            name: "onboarding_notes".to_owned(),
+            description: "Onboarding notes for {directory}".to_owned(),
+            parameters: owned(&["directory"]),
+            questions: owned(&[
+                "What is the purpose of {directory}, and how does it fit into the rest of the \
+                 codebase?",
+                "What are the entry points and the most important types in {directory}?",
+                "How does {directory} interact with other parts of the codebase?",
+                "How is the code in {directory} tested?",
+            ]),
+            aggregation: "Write onboarding notes for an engineer who is new to {directory}, going \
+                          from an overview down to the details they need to make their first \
+                          change."
+                .to_owned(),
+        },
+    ]
+}
+
+/// Every playbook that can be run, by name.
+///
+/// Playbooks in `Configuration::playbooks_dir` replace built-in playbooks of the same name, and
+/// playbooks in the configuration replace both.
+pub fn load(config: &Configuration) -> BTreeMap<String, Playbook> {
+    builtin()
+        .into_iter()
+        .chain(read_dir(&config.playbooks_dir()))
+        .chain(config.playbooks.iter().cloned())
+        .map(|playbook| (playbook.name.clone(), playbook))
+        .collect()
+}
+
+/// Read the playbooks in the `.json` files of `dir`, skipping files that can't be read.
+fn read_dir(dir: &Path) -> Vec<Playbook> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut paths = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let playbook = std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice::<Playbook>(&bytes)?));
+
+            match playbook {
+                Ok(playbook) => Some(playbook),
+                Err(err) => {
+                    warn!(?err, ?path, "failed to read playbook");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Ask every question of `playbook` in the thread of `driver`, then combine their answers into the
+/// answer of a final exchange.
+///
+/// Questions are asked in order, so that later ones can refer to what earlier ones found. A
+/// question that fails is reported in the run, without stopping the rest.
+pub async fn run(mut driver: Driver, playbook: &Expanded) -> Result<(Agent, PlaybookRun)> {
+    let mut sub_answers = Vec::with_capacity(playbook.questions.len());
+    for question in &playbook.questions {
+        let sub_answer = match driver.run(question).await {
+            Ok(exchange) => SubAnswer {
+                question: question.clone(),
+                answer: exchange.answer,
+                error: exchange.error,
+            },
+            Err(err) => {
+                warn!(
+                    ?err,
+                    question,
+                    playbook = playbook.name,
+                    "playbook question failed"
+                );
+                SubAnswer {
+                    question: question.clone(),
+                    answer: None,
+                    error: Some(err.to_string()),
+                }
+            }
+        };
+
+        sub_answers.push(sub_answer);
+    }
+
+    let mut agent = driver.into_agent();
+    let answer = agent.aggregate_playbook(playbook, &sub_answers).await?;
+
+    let run = PlaybookRun {
+        thread_id: agent.thread_id,
+        sub_answers,
+        answer,
+    };
+
+    Ok((agent, run))
+}
+
+impl Agent {
+    /// Combine the answers of a playbook run, in a new exchange.
+    async fn aggregate_playbook(
+        &mut self,
+        playbook: &Expanded,
+        sub_answers: &[SubAnswer],
+    ) -> Result<String> {
+        let answered = sub_answers.iter().filter(|s| s.answer.is_some()).count();
+        if answered == 0 {
+            bail!(
+                "none of the questions of playbook `{}` were answered",
+                playbook.name
+            );
+        }
+
+        let query = parser::parse_nl(&playbook.description)
+            .context("parse error")?
+            .into_semantic()
+            .context("got a 'Grep' query")?
+            .with_raw_target(&playbook.description)
+            .into_owned();
+
+        self.query_id = uuid::Uuid::new_v4();
+        let mut exchange = Exchange::new(self.query_id, query);
+        exchange.source = Some(AnswerSource::Playbook);
+        self.exchanges.push(exchange);
+
+        let prompt = prompts::playbook_aggregation(&playbook.aggregation, sub_answers);
+        let messages = [Message::system(&prompt)];
+        debug!(playbook = playbook.name, answered, "aggregating answers");
+
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model(ANSWER_MODEL)
+            .chat(&messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "playbook_aggregation",
+            ANSWER_MODEL,
+            &messages,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
+        let exchange = self.last_exchange_mut();
+        exchange.apply_update(Update::Article(response.clone()));
+        exchange.apply_update(Update::Conclude(format!(
+            "This combines the answers to {answered} of the {} questions of the `{}` playbook.",
+            sub_answers.len(),
+            playbook.name
+        )));
+
+        self.track_query(
+            EventData::output_stage("playbook_aggregation")
+                .with_payload("playbook", &playbook.name)
+                .with_payload("questions", sub_answers.len())
+                .with_payload("answered", answered)
+                .with_payload("response", &response)
+                .with_payload("raw_prompt", &prompt),
+        );
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        agent::builder,
+        llm_gateway::mock::{call, Gateway, Reply, Request},
+        repo::{Backend, RepoRef},
+        Application, Environment,
+    };
+
+    fn params(items: &[(&str, &str)]) -> HashMap<String, String> {
+        items
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let playbook = builtin()
+            .into_iter()
+            .find(|p| p.name == "security_review")
+            .unwrap();
+
+        let expanded = playbook
+            .expand(&params(&[("module", "server/src/auth")]))
+            .unwrap();
+        assert_eq!(expanded.description, "Security review of server/src/auth");
+        assert_eq!(expanded.questions.len(), playbook.questions.len());
+        assert!(expanded
+            .questions
+            .iter()
+            .chain([&expanded.aggregation])
+            .all(|text| text.contains("server/src/auth") && !text.contains("{module}")));
+
+        assert!(playbook.expand(&params(&[])).is_err());
+        assert!(playbook
+            .expand(&params(&[("module", "auth"), ("depth", "3")]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let index_dir = tempdir::TempDir::new("bleep-playbooks").unwrap();
+        let mut config = serde_json::from_value::<Configuration>(serde_json::json!({
+            "index_dir": index_dir.path(),
+        }))
+        .unwrap();
+
+        let playbook = |name: &str, description: &str| Playbook {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            parameters: vec![],
+            questions: vec!["What is this?".to_owned()],
+            aggregation: "Summarise.".to_owned(),
+        };
+
+        std::fs::create_dir_all(config.playbooks_dir()).unwrap();
+        for (file, playbook) in [
+            (
+                "onboarding.json",
+                playbook("onboarding_notes", "From the data dir"),
+            ),
+            (
+                "release.json",
+                playbook("release_checklist", "From the data dir"),
+            ),
+        ] {
+            let path = config.playbooks_dir().join(file);
+            std::fs::write(path, serde_json::to_vec(&playbook).unwrap()).unwrap();
+        }
+        std::fs::write(config.playbooks_dir().join("broken.json"), "{").unwrap();
+
+        config.playbooks = vec![playbook("release_checklist", "From the config")];
+
+        let playbooks = load(&config);
+        assert_eq!(
+            playbooks.keys().collect::<Vec<_>>(),
+            ["onboarding_notes", "release_checklist", "security_review"]
+        );
+        assert_eq!(
+            playbooks["onboarding_notes"].description,
+            "From the data dir"
+        );
+        assert_eq!(
+            playbooks["release_checklist"].description,
+            "From the config"
+        );
+        assert_eq!(
+            playbooks["security_review"].description,
+            "Security review of {module}"
+        );
+    }
+
+    /// The question whose answer fails, in `test_run`.
+    const FAILING_QUESTION: &str = "How is the code in src/retry tested?";
+
+    /// Serve a mock gateway, which answers every question without searching, and fails to answer
+    /// `FAILING_QUESTION`. Aggregation requests are answered with `aggregated`.
+    fn serve(aggregated: &'static str) -> Gateway {
+        Gateway::serve(move |request| {
+            if request.is_function_call() {
+                Reply::call(&call("none", serde_json::json!({ "paths": [] })))
+            } else if is_aggregation(request) {
+                Reply::text(aggregated)
+            } else if request.last() == FAILING_QUESTION {
+                Reply::Events(vec![serde_json::json!({ "Err": "BadConfiguration" })])
+            } else {
+                Reply::text(format!("Answer to: {}", request.last()))
+            }
+        })
+    }
+
+    fn is_aggregation(request: &Request) -> bool {
+        request.system().contains("##### ANSWERS #####")
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let aggregated = "Retries are scheduled in [`src/retry`](src/retry/).";
+        let gateway = serve(aggregated);
+
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": gateway.url,
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let playbook = load(&app.config)["onboarding_notes"]
+            .expand(&params(&[("directory", "src/retry")]))
+            .unwrap();
+        assert!(playbook.questions.iter().any(|q| q == FAILING_QUESTION));
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let driver = builder::builder(app).repo(repo_ref).build().unwrap();
+        let (agent, playbook_run) = run(driver, &playbook).await.unwrap();
+
+        // Every question is asked in the same thread, followed by the combined answer.
+        assert_eq!(agent.exchanges.len(), playbook.questions.len() + 1);
+        assert_eq!(playbook_run.thread_id, agent.thread_id);
+        assert_eq!(playbook_run.answer, aggregated);
+
+        let last = agent.exchanges.last().unwrap();
+        assert_eq!(last.source, Some(AnswerSource::Playbook));
+        assert_eq!(last.answer.as_deref(), Some(aggregated));
+        assert_eq!(
+            last.query().as_deref(),
+            Some("Onboarding notes for src/retry")
+        );
+
+        // The failing question is reported, without stopping the questions after it.
+        for (sub_answer, question) in playbook_run.sub_answers.iter().zip(&playbook.questions) {
+            assert_eq!(&sub_answer.question, question);
+            if question == FAILING_QUESTION {
+                assert_eq!(sub_answer.answer, None);
+                assert!(sub_answer.error.is_some());
+            } else {
+                assert_eq!(
+                    sub_answer.answer.as_deref(),
+                    Some(format!("Answer to: {question}").as_str())
+                );
+            }
+        }
+
+        // The model that combines the answers is given every one of them, and the instruction.
+        let aggregations = gateway.requests_where(is_aggregation);
+        assert_eq!(aggregations.len(), 1);
+        let prompt = aggregations[0].system();
+        assert!(prompt.contains(&playbook.aggregation));
+        for sub_answer in &playbook_run.sub_answers {
+            assert!(prompt.contains(&sub_answer.question));
+            if let Some(answer) = &sub_answer.answer {
+                assert!(prompt.contains(answer));
+            }
+        }
+    }
+}
@@ -1,4 +1,53 @@
-pub fn functions(add_proc: bool) -> serde_json::Value {
+use super::{exchange::QueryType, playbook::SubAnswer};
+use crate::indexes::diagnostics::IndexWarnings;
+
+/// A nudge for the agent to keep calling functions, rather than answering directly.
+pub const FUNCTION_CALL_INSTRUCTION: &str = "Call a function. Do not answer";
+
+/// Instructions addressed to the model, which should never be repeated back to the user.
+///
+/// Longer instructions come first, so that they are removed before any instruction they contain.
+pub const INTERNAL_INSTRUCTIONS: &[&str] = &[
+    "ALWAYS call a function, DO NOT answer the question directly, even if the query is not in English",
+    "ALWAYS call a function. DO NOT answer the question directly",
+    FUNCTION_CALL_INSTRUCTION,
+];
+
+/// What the agent can make use of in the current repository.
+///
+/// This decides which functions the agent is offered, and how some of them are described.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Whether semantic search is available, which `code` needs.
+    pub semantic: bool,
+
+    /// Whether the repository has git history, which `changelog` reads.
+    pub commit_history: bool,
+
+    /// Whether the repository has documentation, such as a README or a `docs` directory.
+    pub docs: bool,
+
+    /// Whether open pull requests are synced for the repository, which `prs` searches.
+    pub pull_requests: bool,
+
+    /// The number of paths that the query is restricted to, or 0 if it isn't restricted.
+    pub path_count: usize,
+
+    /// The most common language in the repository.
+    pub language: Option<String>,
+}
+
+/// The functions available to the agent.
+///
+/// Debug queries always get `proc`, so that the model can read the code around an error. Where-is
+/// queries don't get the functions that summarise history or dependencies, as they can't locate
+/// code. Functions that `capabilities` can't support are left out, and `path` is left out when
+/// the query is restricted to a single file, as there is nothing to find.
+pub fn functions(
+    add_proc: bool,
+    capabilities: &Capabilities,
+    query_type: QueryType,
+) -> serde_json::Value {
     let mut funcs = serde_json::json!(
         [
             {
@@ -29,6 +78,220 @@ pub fn functions(add_proc: bool) -> serde_json::Value {
                     "required": ["query"]
                 }
             },
+            {
+                "name": "symbol",
+                "description": "Find the functions, classes, structs and other types whose names match a query, in any naming convention and allowing for small typos, e.g. 'getUserById' finds 'get_user_by_id'. Returns the kind, file and line of each definition.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "query": {
+                            "type": "string",
+                            "description": "The name of the symbol, or the words in it, e.g. 'getUserById' or 'user cache'"
+                        }
+                    },
+                    "required": ["query"]
+                }
+            },
+            {
+                "name": "list_files",
+                "description": "List the paths in a codebase matching a glob pattern, in alphabetical order. Use when you want to see every file of a certain kind, or in a certain directory, before deciding which to read.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "pattern": {
+                            "type": "string",
+                            "description": "The glob pattern to match paths against, e.g. '**/*.sql', 'server/src/*.rs' or 'migrations/**'. Patterns without a '/' match files in any directory."
+                        }
+                    },
+                    "required": ["pattern"]
+                }
+            },
+            {
+                "name": "dependency_vulns",
+                "description": "Check the dependencies pinned in the lockfiles at the root of the codebase (Cargo.lock, package-lock.json or requirements.txt) for versions with known vulnerabilities. Use when the user asks about security issues or outdated dependencies.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {}
+                }
+            },
+            {
+                "name": "upgrade_suggestions",
+                "description": "Explain the breaking changes of upgrading a dependency from the version in Cargo.toml or package.json to its next major version, and how they affect the code found so far.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "dep_name": {
+                            "type": "string",
+                            "description": "The name of the dependency, as written in the manifest, e.g. 'tokio' or '@types/react'"
+                        }
+                    },
+                    "required": ["dep_name"]
+                }
+            },
+            {
+                "name": "dead_code",
+                "description": "Find functions and types that are defined in the codebase but never referenced, excluding tests. Use when the user asks about unused code or what can be removed.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {}
+                }
+            },
+            {
+                "name": "deprecated_usage",
+                "description": "Find calls in the codebase to APIs that a package has deprecated, with what to use instead. Use when the user asks about deprecated APIs or tech debt from outdated usage of a package.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "package": {
+                            "type": "string",
+                            "description": "The name of the package, e.g. 'chrono', 'react-dom', or 'std' for the Rust standard library"
+                        }
+                    },
+                    "required": ["package"]
+                }
+            },
+            {
+                "name": "config_audit",
+                "description": "Review a configuration file (YAML, TOML, JSON or INI) for misconfigurations, security issues and missing required fields.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The full path of the configuration file, e.g. 'deploy/values.yaml'"
+                        }
+                    },
+                    "required": ["path"]
+                }
+            },
+            {
+                "name": "todos",
+                "description": "List the TODO, FIXME, HACK and XXX comments in a file, with the lines around each of them.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The full path of the file, e.g. 'server/src/main.rs'"
+                        }
+                    },
+                    "required": ["path"]
+                }
+            },
+            {
+                "name": "allocations",
+                "description": "Find the heap allocations in a Rust file, such as `Box::new`, `clone()` or `vec!`, with how often each one is likely to run: high in loops, medium in closures and low elsewhere.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The full path of the Rust file, e.g. 'server/src/main.rs'"
+                        }
+                    },
+                    "required": ["path"]
+                }
+            },
+            {
+                "name": "find_similar",
+                "description": "Find the files whose contents are most semantically similar to a file, such as other implementations of the same interface or copies of the same logic.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The full path of the file, e.g. 'server/src/main.rs'"
+                        }
+                    },
+                    "required": ["path"]
+                }
+            },
+            {
+                "name": "format",
+                "description": "Check a source file (Rust, Python, Go, or JavaScript, TypeScript and other web languages) with its formatter, and show the changes that the formatter would make.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "The full path of the file, e.g. 'server/src/main.rs'"
+                        }
+                    },
+                    "required": ["path"]
+                }
+            },
+            {
+                "name": "changelog",
+                "description": "Summarise the recent commit history of the repository as a changelog, grouped into features, fixes and breaking changes.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "since": {
+                            "type": "string",
+                            "description": "A git revision, such as a tag, to list the changes after, e.g. 'v0.5.0'. Omit this to list the most recent commits"
+                        }
+                    },
+                    "required": []
+                }
+            },
+            {
+                "name": "changelog_diff",
+                "description": "Compare the sections of two versions in the repository's CHANGELOG.md, and explain what was added, removed or modified in the release notes.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "v1": {
+                            "type": "string",
+                            "description": "The older version, as it is named in the changelog, e.g. '0.4.0'"
+                        },
+                        "v2": {
+                            "type": "string",
+                            "description": "The newer version, as it is named in the changelog, e.g. '0.5.0'"
+                        }
+                    },
+                    "required": ["v1", "v2"]
+                }
+            },
+            {
+                "name": "weekly_digest",
+                "description": "Write a weekly status update from the commits of the last 7 days, grouped by author and component, for posting to Slack.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {},
+                    "required": []
+                }
+            },
+            {
+                "name": "plan",
+                "description": "Break a complex task, such as implementing a feature, into a few concrete function calls that are made next. Use before searching, when the task involves several parts of the codebase.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "goal": {
+                            "type": "string",
+                            "description": "The task to plan, e.g. 'add OAuth2 authentication to the webserver'"
+                        }
+                    },
+                    "required": ["goal"]
+                }
+            },
+            {
+                "name": "related_files",
+                "description": "Find files related to a set of files: files that import them, files they import, and files with similar code. Use when you have found a relevant file and want to know what else is involved in the same feature.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "paths": {
+                            "type": "array",
+                            "items": {
+                                "type": "string",
+                                "description": "A full file path, e.g. 'server/src/main.rs'"
+                            }
+                        }
+                    },
+                    "required": ["paths"]
+                }
+            },
             {
                 "name": "none",
                 "description": "You have enough information to answer the user's query. This is the final step, and signals that you have enough information to respond to the user's query. Use this if the user has instructed you to modify some code.",
@@ -49,7 +312,46 @@ pub fn functions(add_proc: bool) -> serde_json::Value {
         ]
     );
 
-    if add_proc {
+    funcs
+        .as_array_mut()
+        .unwrap()
+        .retain(|f| match f["name"].as_str() {
+            Some("code" | "find_similar") => capabilities.semantic,
+            Some("path") => capabilities.path_count != 1,
+            Some("changelog" | "weekly_digest") => {
+                capabilities.commit_history && query_type != QueryType::WhereIs
+            }
+            Some(
+                "dependency_vulns"
+                | "dead_code"
+                | "deprecated_usage"
+                | "allocations"
+                | "upgrade_suggestions"
+                | "plan"
+                | "changelog_diff",
+            ) => query_type != QueryType::WhereIs,
+            _ => true,
+        });
+
+    for f in funcs.as_array_mut().unwrap() {
+        let description = match f["name"].as_str() {
+            Some("code") => capabilities
+                .language
+                .as_ref()
+                .map(|lang| format!(" Most of this codebase is written in {lang}.")),
+            Some("path") if capabilities.docs => Some(
+                " This codebase has documentation, which can be found with queries like 'docs' or 'readme'."
+                    .to_owned(),
+            ),
+            _ => None,
+        };
+
+        if let (Some(description), Some(s)) = (description, f["description"].as_str()) {
+            f["description"] = (s.to_owned() + &description).into();
+        }
+    }
+
+    if add_proc || query_type == QueryType::Debug {
         funcs.as_array_mut().unwrap().push(
             serde_json::json!(
             {
@@ -76,10 +378,74 @@ pub fn functions(add_proc: bool) -> serde_json::Value {
             )
         );
     }
+
+    if capabilities.pull_requests {
+        funcs.as_array_mut().unwrap().push(
+            serde_json::json!(
+            {
+                "name": "prs",
+                "description": "Search the open pull requests of the repository by title, description and changed files. Use when the user asks whether something is already being worked on, or about pending changes.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "query": {
+                            "type": "string",
+                            "description": "Keywords to match against the pull requests, e.g. 'rate limiting' or 'webserver middleware'"
+                        }
+                    },
+                    "required": ["query"]
+                }
+            }
+            )
+        );
+    }
     funcs
 }
 
-pub fn system<'a>(paths: impl IntoIterator<Item = &'a str>) -> String {
+/// A note that stands in for a query without a target, with the search filters it set.
+pub fn filter_change_note(filters: &[String]) -> String {
+    if filters.is_empty() {
+        "The user sent an empty query.".to_owned()
+    } else {
+        format!(
+            "The user changed the search filters to: {}",
+            filters.join(" ")
+        )
+    }
+}
+
+/// A section of the answer context, on files that `proc` gave up reading because they took too
+/// long. This is empty if every file was read.
+pub fn timed_out_note(paths: &[&str]) -> String {
+    if paths.is_empty() {
+        return String::new();
+    }
+
+    format!(
+        "##### INCOMPLETE COVERAGE #####\n\
+         These files could not be read in time, so the information above may be incomplete. \
+         Mention this in your answer:\n{}\n\n",
+        paths.join("\n")
+    )
+}
+
+/// A line of the answer context, on the files of the repository that could not be indexed. This
+/// is empty if too few files were affected to be worth a caveat.
+pub fn index_warnings_note(warnings: Option<&IndexWarnings>) -> String {
+    match warnings {
+        Some(warnings) => format!("##### INDEX COVERAGE #####\n{}\n\n", warnings.caveat()),
+        None => String::new(),
+    }
+}
+
+pub fn system<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    examples: &[String],
+    stack_trace: &[String],
+    knowledge: &[String],
+    external: &[String],
+    filters: &[String],
+) -> String {
     let mut s = "".to_string();
 
     let mut paths = paths.into_iter().peekable();
@@ -92,6 +458,49 @@ pub fn system<'a>(paths: impl IntoIterator<Item = &'a str>) -> String {
         s.push('\n');
     }
 
+    if !examples.is_empty() {
+        s.push_str(
+            "## EXAMPLES ##\nThese function calls answered past queries about this codebase well:\n\n",
+        );
+        for example in examples {
+            s.push_str(example);
+            s.push_str("\n\n");
+        }
+    }
+
+    if !stack_trace.is_empty() {
+        s.push_str(
+            "## STACK TRACE ##\nThe user's query contains a stack trace. Its frames in this codebase, innermost first, are:\nindex, location, symbol\n",
+        );
+        for frame in stack_trace {
+            s.push_str(frame);
+            s.push('\n');
+        }
+        s.push('\n');
+    }
+
+    for section in knowledge {
+        s.push_str(section);
+        s.push('\n');
+    }
+
+    if !external.is_empty() {
+        s.push_str(
+            "## EXTERNAL CONTEXT ##\nThe user linked these pages in their query. They are not part of this codebase and may be out of date, so use them to understand the query, and search the codebase for the code they describe:\n\n",
+        );
+        for section in external {
+            s.push_str(section);
+            s.push_str("\n\n");
+        }
+    }
+
+    if !filters.is_empty() {
+        s.push_str(&format!(
+            "## FILTERS ##\nThe user restricted their query with these filters, which are already applied to every function: {}\nDO NOT repeat them in function arguments, or search again to apply them\n\n",
+            filters.join(" ")
+        ));
+    }
+
     s.push_str(
         r#"Follow these rules at all times:
 
@@ -104,6 +513,24 @@ pub fn system<'a>(paths: impl IntoIterator<Item = &'a str>) -> String {
 - If functions.code or functions.path did not return any relevant information, call them again with a SIGNIFICANTLY different query. The terms in the new query should not overlap with terms in your old one
 - Call functions.proc with paths that you have reason to believe might contain relevant information. Either because of the path name, or to expand on code that's already been returned by functions.code 
 - DO NOT pass more than 5 paths to functions.proc at a time
+- Call functions.list_files to enumerate files by name or extension. To read a listed file, first find it with functions.path
+- If there is a STACK TRACE above, walk it from the top by calling functions.proc on the path indices of its frames, in order
+- Call functions.symbol when the user names a function, class or type, to find where it is defined
+- Call functions.related_files with full paths of relevant files to find the files that they import or are imported by. The results are added to the PATHS above
+- Call functions.config_audit when the user asks for a review of a configuration file. Find its full path first
+- Call functions.todos when the user asks about TODO or FIXME comments, or known technical debt, in a file. Find its full path first
+- Call functions.find_similar when the user asks for code like a file, such as duplicated logic or other implementations of the same thing. Find its full path first
+- Call functions.format when the user asks about formatting or style issues in a file. Find its full path first
+- Call functions.changelog when the user asks what changed recently or since a release, or about breaking changes
+- Call functions.changelog_diff when the user asks how the release notes of two versions differ
+- Call functions.weekly_digest when the user asks for a weekly update, status report or digest of the team's work
+- Call functions.plan first when the user asks how to carry out a complex task that touches several parts of the codebase, such as implementing a feature
+- Call functions.dependency_vulns when the user asks whether the codebase uses vulnerable dependency versions
+- Call functions.upgrade_suggestions when the user asks what would break if a dependency were upgraded. Find the code that uses the dependency first
+- Call functions.dead_code when the user asks which functions or types are unused, or what code can be removed
+- Call functions.deprecated_usage when the user asks where the codebase calls deprecated APIs of a package
+- Call functions.allocations when the user asks about memory usage, allocations or performance hot paths in a Rust file. Find its full path first
+- Call functions.prs when the user asks whether anyone is already working on something, or about open pull requests
 - In most cases call functions.code or functions.path functions before calling functions.none
 - When you have enough information to answer the user call functions.none. DO NOT answer the user directly
 - If the user is referring to information that is already in your history, call functions.none
@@ -128,28 +555,237 @@ pub fn file_explanation(question: &str, path: &str, code: &str) -> String {
 Your job is to perform the following tasks:
 1. Find all the relevant line ranges of code.
 2. DO NOT cite line ranges that you are not given above
-3. You MUST answer with only line ranges. DO NOT answer the question
+3. If no lines are relevant, give the reason in one short sentence
+4. You MUST answer with only JSON. DO NOT answer the question
 
 Q: find Kafka auth keys
-A: [[12,15]]
+A: {{"relevant_ranges": [[12,15]]}}
 
 Q: find where we submit payment requests
-A: [[37,50]]
+A: {{"relevant_ranges": [[37,50]]}}
 
 Q: auth code expiration
-A: [[486,501],[520,560],[590,631]]
+A: {{"relevant_ranges": [[486,501],[520,560],[590,631]]}}
 
 Q: library matrix multiplication
-A: [[68,74],[82,85],[103,107],[187,193]]
+A: {{"relevant_ranges": [[68,74],[82,85],[103,107],[187,193]]}}
 
 Q: how combine result streams
-A: []
+A: {{"irrelevant": {{"reason": "This file only defines HTTP routes, and does not handle streams"}}}}
 
 Q: {question}
 A: "#
     )
 }
 
+pub fn proc_json(query: &str, content: &str) -> String {
+    format!(
+        r#"Below are some lines from a file. Each line is numbered.
+
+#####
+
+{content}
+
+#####
+
+Your job is to find the lines that are relevant to the query below, and to summarise what they do.
+Answer with only a JSON object, in this format:
+{{"relevant_lines": [12, 13, 14], "summary": "Builds the Kafka client with the auth keys from the environment", "confidence": 0.9}}
+
+- relevant_lines lists the numbers of the relevant lines. DO NOT cite lines that you are not given above
+- If no lines are relevant, relevant_lines is empty, and summary gives the reason in one short sentence
+- confidence is between 0.0 and 1.0, and is how sure you are that the lines answer the query
+- DO NOT answer the query
+
+Query: {query}"#
+    )
+}
+
+pub fn config_audit(path: &str, format: &str, config: &str) -> String {
+    format!(
+        r#"Below is the {format} configuration file /{path}.
+
+#####
+
+{config}
+
+#####
+
+Your job is to review this configuration:
+1. Find misconfigurations, security issues, and required fields that are missing
+2. DO NOT report issues that are not supported by the lines given above
+3. Rate each issue as "critical", "high", "medium" or "low"
+4. You MUST answer with only a JSON array of issues, or [] if there are none
+
+Example:
+[{{"field": "server.tls.enabled", "severity": "high", "description": "TLS is disabled, so traffic is sent in plain text"}}, {{"field": "database.pool_size", "severity": "low", "description": "A pool size of 1 will serialize all queries"}}]
+
+A: "#
+    )
+}
+
+pub fn upgrade_suggestions(
+    dep_name: &str,
+    current: &str,
+    target: &str,
+    changelog: &str,
+    code: &str,
+) -> String {
+    format!(
+        r#"Below is the changelog of {dep_name} from version {current} to {target}.
+
+#####
+
+{changelog}
+
+#####
+
+Below is code from the codebase, which may use {dep_name}.
+
+#####
+
+{code}
+
+#####
+
+Your job is to find the breaking changes of upgrading {dep_name} from {current} to {target}:
+1. Only report changes that can break code which uses {dep_name}, such as removed or renamed APIs, changed behaviour and raised minimum requirements
+2. Report the changes that affect the code above first, and say how to migrate it
+3. DO NOT report changes that are not in the changelog above
+4. You MUST answer with only a JSON array of changes, or [] if there are none
+
+Example:
+[{{"short_desc": "`Client::new` was removed, which src/client.rs calls", "migration_hint": "Use `Client::builder().build()` instead"}}, {{"short_desc": "The minimum supported Rust version is now 1.70", "migration_hint": null}}]
+
+A: "#
+    )
+}
+
+pub fn changelog_diff(v1: &str, v2: &str, diff: &str) -> String {
+    format!(
+        r#"Below is a diff from the section for {v1} to the section for {v2} in the changelog of a codebase.
+
+#####
+
+{diff}
+
+#####
+
+Your job is to explain how the release notes of {v2} differ from those of {v1}:
+1. List the entries that were added, removed and modified, under the headings Added, Removed and Modified. Leave out headings with no entries
+2. For modified entries, say what changed in their wording or meaning
+3. DO NOT describe entries that are the same in both sections
+4. DO NOT mention changes that are not in the diff above
+
+A: "#
+    )
+}
+
+/// The follow-up instruction for a weekly digest that is over its word limit.
+pub const SHORTEN_DIGEST: &str = "Shorten this, keeping the most important changes.";
+
+pub fn weekly_digest(commits: &str, max_words: usize) -> String {
+    format!(
+        r#"Below are the commits of the last 7 days, grouped by author and by the top-level directory they changed.
+
+#####
+
+{commits}
+
+#####
+
+Your job is to write a weekly status update about these commits for an engineering manager, to be posted to Slack:
+1. Start with a one-line overview of the week, then summarise the work of each author, grouped by component
+2. Describe what changed and why it matters, rather than listing every commit
+3. Use Slack-friendly markdown: *bold* headings and - bullet points, with an emoji at the start of each section, e.g. 🚀 for features, 🐛 for fixes, 📝 for docs
+4. DO NOT mention work that is not in the commits above
+5. You MUST use at most {max_words} words
+
+A: "#
+    )
+}
+
+/// The prompt that combines the answers of a playbook run, following its `instruction`.
+///
+/// Questions that failed are listed without an answer, so that the gaps can be pointed out.
+pub fn playbook_aggregation(instruction: &str, sub_answers: &[SubAnswer]) -> String {
+    let answers = sub_answers
+        .iter()
+        .enumerate()
+        .map(|(i, sub_answer)| {
+            let answer = sub_answer
+                .answer
+                .as_deref()
+                .unwrap_or("This question could not be answered.");
+            format!(
+                "### Question {}: {}\n\n{answer}",
+                i + 1,
+                sub_answer.question
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    format!(
+        r#"Below are the answers to a series of questions about a codebase.
+
+##### ANSWERS #####
+
+{answers}
+
+#####
+
+Your job is to combine these answers into a single answer. {instruction}
+
+Respect these rules at all times:
+- Only use information from the answers above, DO NOT make anything up
+- Keep every code block and every markdown link to a path or symbol EXACTLY as it appears in the answers, as they cite the code
+- Do not repeat the same information twice, even if several answers contain it
+- If a question could not be answered, say which information is missing
+- Do not refer to the questions by number, the reader has not seen them"#
+    )
+}
+
+pub fn plan(goal: &str, functions: &str, max_steps: usize) -> String {
+    format!(
+        r#"Below are the functions that you can call to search a codebase.
+
+#####
+
+{functions}
+
+#####
+
+Your job is to break down this task into the function calls that will find the code involved in it: {goal}
+1. Plan between 3 and {max_steps} function calls, which will be made in order
+2. Each call MUST be to one of the functions above, with arguments that match its parameters
+3. Prefer specific searches, for the files, functions and types that the task involves
+4. Respond with a JSON array of calls and nothing else, e.g. [{{"name": "path", "arguments": {{"query": "auth"}}}}]
+
+A: "#
+    )
+}
+
+pub fn summarize_external(url: &str, content: &str, max_words: usize) -> String {
+    format!(
+        r#"Below is the content of {url}, which a user linked in a question about a codebase.
+
+#####
+
+{content}
+
+#####
+
+Your job is to summarise this content for an engineer who will search the codebase for the code it describes:
+1. Keep error messages, stack traces, file paths, function names and steps to reproduce EXACTLY as they appear
+2. Leave out greetings, signatures, and discussion that doesn't describe the problem or the change
+3. DO NOT add information that is not in the content above
+4. You MUST use at most {max_words} words
+
+A: "#
+    )
+}
+
 pub fn answer_article_prompt(context: &str) -> String {
     format!(
         r#"{context}Your job is to answer a query about a codebase using the information above.
@@ -173,7 +809,11 @@ Respect these rules at all times:
   - E.g. Do not simply write: "It has one main field: `foo`." Instead, write: "It has one main field: [`foo`](src/foo.rs#L193)."
 - Link all symbols, even when there are multiple in one sentence
   - E.g. Do not simply write: "Bars are [`Foo`]( that return a list filled with `Bar` variants." Instead, write: "Bars are functions that return a list filled with [`Bar`](src/bar.rs#L38-L57) variants."
-- Always begin your answer with an appropriate title
+- Always begin your answer with an outcome tag on its own line, followed by an appropriate title. The tag is hidden from the user, and MUST be one of:
+  - `[outcome: answered]` if the information above answers the query
+  - `[outcome: partial]` if it only answers part of the query
+  - `[outcome: not_found]` if it does not contain what the query asks about
+  - `[outcome: clarification]` if the query is unclear, and you ask the user to clarify it
 - Always finish your answer with a summary in a [^summary] footnote
   - If you do not have enough information needed to answer the query, do not make up an answer. Instead respond only with a [^summary] f
 ootnote that asks the user for more information, e.g. `assistant: [^summary]: I'm sorry, I couldn't find what you were looking for, could you provide more information?`
@@ -210,6 +850,44 @@ println!("hello world!");
     )
 }
 
+/// Like `answer_article_prompt`, for answers that are written as a JSON object.
+pub fn answer_json_prompt(context: &str) -> String {
+    format!(
+        r#"{context}Your job is to answer a query about a codebase using the information above.
+
+Provide only as much information as is necessary to answer the query, but be concise. If you do not have enough information needed to answer the query, do not make up an answer.
+
+Respond ONLY with a JSON object, without a code block or any other text around it, in the following format (example given):
+{{"outcome": "answered", "answer": "The compiler is initialized in `src/foo.rs` by the `new` function.", "summary": "The compiler is initialized on startup.", "references": [{{"path": "src/foo.rs", "start_line": 26, "end_line": 53}}]}}
+
+Respect these rules at all times:
+- `outcome` MUST be one of:
+  - `answered` if the information above answers the query
+  - `partial` if it only answers part of the query
+  - `not_found` if it does not contain what the query asks about
+  - `clarification` if the query is unclear, and you ask the user to clarify it
+- `answer` is the answer itself, in plain text. If you do not have enough information needed to answer the query, leave it empty
+- `summary` is a single sentence that summarizes the answer, or that asks the user for more information
+- `references` lists the code that the answer refers to, with inclusive line ranges
+- Do not refer to paths by alias, expand to the full path
+- Escape quotes and line breaks in strings, so that the response is valid JSON"#
+    )
+}
+
+pub fn explain_function_prompt(symbol: &str, call_graph: &str, context: &str) -> String {
+    let rules = answer_article_prompt("");
+
+    format!(
+        r#"{context}##### CALL GRAPH #####
+{call_graph}
+The query asks what the function `{symbol}` does. The code above contains its definition, the functions it calls (callees), and the functions that call it (callers), as listed in the call graph. Unresolved callees are defined outside of the codebase.
+
+Explain the definition first, step by step, describing how it uses each of its callees. Then briefly describe how and why its callers use it. Only explain callees and callers as much as is needed to understand `{symbol}`.
+
+{rules}"#
+    )
+}
+
 pub fn hypothetical_document_prompt(query: &str) -> String {
     format!(
         r#"Write a code snippet that could hypothetically be returned by a code search engine as the answer to the query: {query}
@@ -236,6 +914,38 @@ SearchPoints {{
     )
 }
 
+pub fn conversation_title_prompt(query: &str) -> String {
+    format!(
+        r#"Summarise the following question about a codebase as a title of at most 5 words. Respond only with the title, without quotes or punctuation at the end.
+
+Question: {query}
+Title: "#
+    )
+}
+
+/// Default few-shot examples for `code_search`, pairing vague search queries with better ones.
+pub const CODE_SEARCH_EXAMPLES: &[&str] = &[
+    "Bad: where is the stuff that handles logging in\nGood: login authentication handler",
+    "Bad: how does the app talk to the database?\nGood: database connection pool query",
+    "Bad: what happens when something goes wrong\nGood: error handling retry",
+    "Bad: code for the thing that makes the http server\nGood: http server router listen",
+];
+
+pub fn code_search(query: &str, examples: &[&str]) -> String {
+    let examples = examples.join("\n\n");
+
+    format!(
+        r#"Here are some examples of bad semantic code search queries, and better queries to replace them with:
+
+{examples}
+
+Rewrite the following query into a good semantic code search query. The query should consist of keywords that might match something in the codebase. Respond only with the rewritten query.
+
+Bad: {query}
+Good: "#
+    )
+}
+
 pub fn try_parse_hypothetical_documents(document: &str) -> Vec<String> {
     let pattern = r"```([\s\S]*?)```";
     let re = regex::Regex::new(pattern).unwrap();
@@ -249,6 +959,137 @@ pub fn try_parse_hypothetical_documents(document: &str) -> Vec<String> {
 mod tests {
     use super::*;
 
+    fn all_capabilities() -> Capabilities {
+        Capabilities {
+            semantic: true,
+            commit_history: true,
+            docs: true,
+            pull_requests: true,
+            path_count: 0,
+            language: Some("Rust".into()),
+        }
+    }
+
+    fn function_names(capabilities: &Capabilities) -> Vec<String> {
+        functions(false, capabilities, QueryType::Other)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["name"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    fn description(capabilities: &Capabilities, name: &str) -> String {
+        functions(false, capabilities, QueryType::Other)
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|f| f["name"] == name)
+            .map(|f| f["description"].as_str().unwrap().to_owned())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_functions_by_query_type() {
+        let names = |add_proc, query_type| {
+            functions(add_proc, &all_capabilities(), query_type)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|f| f["name"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(!names(false, QueryType::Other).contains(&"proc".to_owned()));
+        assert!(names(true, QueryType::Other).contains(&"proc".to_owned()));
+        assert!(names(false, QueryType::Debug).contains(&"proc".to_owned()));
+
+        let where_is = names(false, QueryType::WhereIs);
+        assert!(!where_is.contains(&"changelog".to_owned()));
+        assert!(!where_is.contains(&"dependency_vulns".to_owned()));
+        assert!(!where_is.contains(&"dead_code".to_owned()));
+        assert!(!where_is.contains(&"upgrade_suggestions".to_owned()));
+        assert!(!where_is.contains(&"weekly_digest".to_owned()));
+        assert!(!where_is.contains(&"plan".to_owned()));
+        assert!(!where_is.contains(&"changelog_diff".to_owned()));
+        assert!(!where_is.contains(&"deprecated_usage".to_owned()));
+        assert!(where_is.contains(&"symbol".to_owned()));
+        assert!(!where_is.contains(&"allocations".to_owned()));
+        assert!(where_is.contains(&"code".to_owned()));
+        assert!(names(false, QueryType::HowTo).contains(&"changelog".to_owned()));
+    }
+
+    #[test]
+    fn test_functions_by_capabilities() {
+        let all = all_capabilities();
+        let has = |capabilities: &Capabilities, name: &str| {
+            function_names(capabilities).contains(&name.to_owned())
+        };
+
+        for name in ["code", "path", "changelog", "weekly_digest", "prs"] {
+            assert!(has(&all, name), "{name} is missing");
+        }
+
+        let no_semantic = Capabilities {
+            semantic: false,
+            ..all.clone()
+        };
+        assert!(!has(&no_semantic, "code"));
+        assert!(!has(&no_semantic, "find_similar"));
+        assert!(has(&no_semantic, "path"));
+
+        let no_history = Capabilities {
+            commit_history: false,
+            ..all.clone()
+        };
+        assert!(!has(&no_history, "changelog"));
+        assert!(!has(&no_history, "weekly_digest"));
+
+        let no_prs = Capabilities {
+            pull_requests: false,
+            ..all.clone()
+        };
+        assert!(!has(&no_prs, "prs"));
+
+        let one_path = Capabilities {
+            path_count: 1,
+            ..all.clone()
+        };
+        assert!(!has(&one_path, "path"));
+        assert!(has(
+            &Capabilities {
+                path_count: 2,
+                ..all.clone()
+            },
+            "path"
+        ));
+    }
+
+    #[test]
+    fn test_function_descriptions_by_capabilities() {
+        let all = all_capabilities();
+
+        assert!(description(&all, "code").ends_with("Most of this codebase is written in Rust."));
+        assert!(!description(
+            &Capabilities {
+                language: None,
+                ..all.clone()
+            },
+            "code"
+        )
+        .contains("written in"));
+
+        assert!(description(&all, "path").contains("documentation"));
+        assert!(!description(
+            &Capabilities {
+                docs: false,
+                ..all.clone()
+            },
+            "path"
+        )
+        .contains("documentation"));
+    }
+
     #[test]
     fn test_parse_hypothetical_document() {
         let document = r#"Here is some pointless text
@@ -281,4 +1122,79 @@ pub fn final_explanation_prompt(context: &str, query: &str, query_history: &str)
 
         assert_eq!(try_parse_hypothetical_documents(document), expected);
     }
+
+    #[test]
+    fn test_code_search_examples() {
+        let examples = [
+            "Bad: where do we keep the colours\nGood: theme color palette",
+            "Bad: the part that sends mail\nGood: smtp email client",
+        ];
+
+        let prompt = code_search("how are users stored", &examples);
+
+        assert!(prompt.contains(
+            "Bad: where do we keep the colours\nGood: theme color palette\n\n\
+             Bad: the part that sends mail\nGood: smtp email client"
+        ));
+        assert!(prompt.ends_with("Bad: how are users stored\nGood: "));
+    }
+
+    #[test]
+    fn test_system_examples() {
+        let paths = ["src/main.rs"];
+
+        let without = system(paths, &[], &[], &[], &[], &[]);
+        assert!(
+            without.starts_with("## PATHS ##\nindex, path\n0, src/main.rs\n\nFollow these rules")
+        );
+        assert!(!without.contains("## EXAMPLES ##"));
+
+        let examples = ["Query: first".to_owned(), "Query: second".to_owned()];
+        let with = system(paths, &examples, &[], &[], &[], &[]);
+        let paths_at = with.find("## PATHS ##").unwrap();
+        let first_at = with.find("Query: first").unwrap();
+        let second_at = with.find("Query: second").unwrap();
+        let rules_at = with.find("Follow these rules").unwrap();
+
+        assert!(paths_at < first_at && first_at < second_at && second_at < rules_at);
+    }
+
+    #[test]
+    fn test_system_stack_trace() {
+        let frames = ["0, src/main.rs:10, main".to_owned()];
+
+        let prompt = system(["src/main.rs"], &[], &frames, &[], &[], &[]);
+        let trace_at = prompt.find("## STACK TRACE ##").unwrap();
+        let frame_at = prompt.find("\n0, src/main.rs:10, main\n").unwrap();
+        let rules_at = prompt.find("Follow these rules").unwrap();
+
+        assert!(trace_at < frame_at && frame_at < rules_at);
+        assert!(!system(["src/main.rs"], &[], &[], &[], &[], &[]).contains("## STACK TRACE ##"));
+    }
+
+    #[test]
+    fn test_system_filters() {
+        let filters = ["-path:tests".to_owned(), "lang:go".to_owned()];
+
+        let prompt = system(["src/main.rs"], &[], &[], &[], &[], &filters);
+        let filters_at = prompt.find("## FILTERS ##").unwrap();
+        let rules_at = prompt.find("Follow these rules").unwrap();
+
+        assert!(filters_at < rules_at);
+        assert!(prompt.contains("already applied to every function: -path:tests lang:go\n"));
+        assert!(!system(["src/main.rs"], &[], &[], &[], &[], &[]).contains("## FILTERS ##"));
+    }
+
+    #[test]
+    fn test_system_external() {
+        let external = ["### https://github.com/acme/web/issues/7\nLogin fails".to_owned()];
+
+        let prompt = system(["src/main.rs"], &[], &[], &[], &external, &[]);
+        let external_at = prompt.find("## EXTERNAL CONTEXT ##").unwrap();
+        let page_at = prompt.find("issues/7\nLogin fails\n").unwrap();
+        let rules_at = prompt.find("Follow these rules").unwrap();
+
+        assert!(external_at < page_at && page_at < rules_at);
+        assert!(!system(["src/main.rs"], &[], &[], &[], &[], &[]).contains("## EXTERNAL CONTEXT"));
+    }
 }
@@ -0,0 +1,287 @@
+//! Quick answers, which trade depth for a rough answer within a few seconds.
+//!
+//! In the quick mode, the agent runs a single path search and a single code search at the same
+//! time, never reads whole files with `proc`, and answers from the top search snippets with a
+//! faster model. Quick exchanges are flagged, so that the user can ask for a full analysis of the
+//! same question, which replaces the quick exchange in its thread.
+
+use anyhow::{bail, Context, Result};
+use tracing::debug;
+
+use crate::{
+    agent::{
+        exchange::{CodeChunk, Exchange, SearchStep, Update},
+        tokens::Tokenizer,
+        Agent,
+    },
+    analytics::EventData,
+    llm_gateway,
+};
+
+/// The model that quick answers are written by.
+pub const ANSWER_MODEL: &str = "gpt-3.5-turbo-0613";
+
+/// The most tokens that a quick answer can take up.
+pub const MAX_TOKENS: u32 = 512;
+
+/// The most tokens of search snippets that a quick answer is written from.
+pub const MAX_CONTEXT_TOKENS: usize = 2048;
+
+/// The most search steps that a quick exchange takes, before it is answered.
+pub const MAX_STEPS: usize = 2;
+
+/// The most code search results that a quick answer is written from.
+const CODE_SEARCH_LIMIT: u64 = 5;
+
+/// The functions that the model is offered in the quick mode.
+const FUNCTIONS: &[&str] = &["path", "code", "none"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Search and read files until the model has enough information to answer.
+    #[default]
+    Normal,
+    /// Answer from the snippets of a single path search and code search.
+    Quick,
+}
+
+impl Agent {
+    /// Run a path search and a code search for `query` at the same time, and record both as the
+    /// steps of a quick exchange.
+    ///
+    /// The code search is skipped when there is no semantic index.
+    pub async fn quick_search(&mut self, query: &str) -> Result<()> {
+        self.last_exchange_mut().quick = true;
+
+        let (mut paths, results) = futures::join!(
+            async {
+                self.fuzzy_path_search(query)
+                    .await
+                    .map(|doc| doc.relative_path)
+                    .collect::<Vec<_>>()
+            },
+            async {
+                match self.app.semantic {
+                    Some(_) => self
+                        .code_semantic_search(query.into(), CODE_SEARCH_LIMIT, 0.0)
+                        .await
+                        .map(Some),
+                    None => Ok(None),
+                }
+            },
+        );
+
+        paths.sort();
+        paths.dedup();
+
+        let (paths, response) = self.record_paths(&paths);
+        self.update(Update::StartStep(SearchStep::Path {
+            query: query.to_owned(),
+            response,
+        }))
+        .await?;
+
+        let chunks = match results? {
+            Some(results) => {
+                let (chunks, response) = self.record_code_chunks(results);
+                self.update(Update::StartStep(SearchStep::Code {
+                    query: query.to_owned(),
+                    response,
+                }))
+                .await?;
+                chunks.len()
+            }
+            None => 0,
+        };
+
+        self.track_query(
+            EventData::input_stage("quick search")
+                .with_payload("query", query)
+                .with_payload("paths", paths.len())
+                .with_payload("chunks", chunks),
+        );
+
+        Ok(())
+    }
+
+    /// The context of a quick answer, which is the top snippets of the last exchange.
+    pub(crate) fn quick_answer_context(&self) -> Result<String> {
+        let tokenizer = self.tokenizer(ANSWER_MODEL)?;
+        Ok(pack_snippets(
+            &self.last_exchange().code_chunks,
+            &tokenizer,
+            MAX_CONTEXT_TOKENS,
+        ))
+    }
+}
+
+/// Leave out the functions that the quick mode doesn't use, from a list of function definitions.
+pub fn restrict_functions(functions: &mut Vec<llm_gateway::api::Function>) {
+    functions.retain(|f| FUNCTIONS.contains(&f.name.as_str()));
+}
+
+/// Whether a quick exchange took all the search steps it can, and should be answered.
+pub fn is_spent(exchange: &Exchange) -> bool {
+    exchange.quick && exchange.search_steps.len() >= MAX_STEPS
+}
+
+/// Render code chunks with their line numbers, in order, until `max_tokens` are taken up.
+pub fn pack_snippets(chunks: &[CodeChunk], tokenizer: &Tokenizer, max_tokens: usize) -> String {
+    let mut s = String::new();
+    let mut remaining = max_tokens;
+
+    for chunk in chunks.iter().filter(|c| !c.is_empty()) {
+        let snippet = chunk
+            .snippet
+            .lines()
+            .enumerate()
+            .map(|(i, line)| format!("{} {line}\n", i + chunk.start_line))
+            .collect::<String>();
+        let formatted = format!("### {} ###\n{snippet}\n", chunk.path);
+
+        let tokens = tokenizer.count(&formatted);
+        if tokens > remaining {
+            debug!(path = chunk.path, "quick answer context is full");
+            break;
+        }
+
+        remaining -= tokens;
+        s += &formatted;
+    }
+
+    if s.is_empty() {
+        s
+    } else {
+        format!("##### CODE CHUNKS #####\n\n{s}")
+    }
+}
+
+/// Replace the quick exchange `id` of a thread with a new exchange `new_id` for the same question,
+/// which is answered in full.
+///
+/// The quick exchange, and every exchange after it, are removed from `exchanges`. The returned
+/// exchange is yet to be added to the thread.
+pub fn full_analysis(
+    exchanges: &mut Vec<Exchange>,
+    id: uuid::Uuid,
+    new_id: uuid::Uuid,
+) -> Result<Exchange> {
+    let i = exchanges
+        .iter()
+        .position(|e| e.id == id)
+        .context("quick exchange not found in the thread")?;
+
+    if !exchanges[i].quick {
+        bail!("exchange {id} was already answered in full");
+    }
+
+    let quick = exchanges.drain(i..).next().unwrap();
+    let mut exchange = Exchange::new(new_id, quick.query);
+    exchange.full_analysis_of = Some(id);
+
+    Ok(exchange)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agent::{exchange::QueryType, prompts},
+        query::parser,
+    };
+
+    fn chunk(path: &str, snippet: &str) -> CodeChunk {
+        CodeChunk {
+            path: path.to_owned(),
+            alias: 0,
+            snippet: snippet.to_owned(),
+            start_line: 10,
+            end_line: 10 + snippet.lines().count(),
+            moved_to: None,
+            deleted: false,
+            symbol_path: None,
+            blob: None,
+            revision: None,
+            mapped: None,
+        }
+    }
+
+    #[test]
+    fn test_pack_snippets() {
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
+        let chunks = [
+            chunk("src/retry.rs", "fn retry() {\n    backoff();\n}"),
+            chunk("src/empty.rs", "  \n"),
+            chunk("src/large.rs", &"let x = 1;\n".repeat(1000)),
+            chunk("src/backoff.rs", "fn backoff() {}"),
+        ];
+
+        let packed = pack_snippets(&chunks, &tokenizer, 200);
+        assert!(packed.starts_with("##### CODE CHUNKS #####"));
+        assert!(packed.contains("### src/retry.rs ###\n10 fn retry() {\n11     backoff();\n"));
+        assert!(!packed.contains("src/empty.rs"));
+        assert!(!packed.contains("src/large.rs"));
+        // Snippets are packed in order, so nothing after a snippet that doesn't fit is included.
+        assert!(!packed.contains("src/backoff.rs"));
+
+        assert!(pack_snippets(&[], &tokenizer, 200).is_empty());
+    }
+
+    #[test]
+    fn test_restrict_functions() {
+        let capabilities = prompts::Capabilities {
+            semantic: true,
+            ..Default::default()
+        };
+        let mut functions = serde_json::from_value::<Vec<llm_gateway::api::Function>>(
+            prompts::functions(true, &capabilities, QueryType::Other),
+        )
+        .unwrap();
+
+        restrict_functions(&mut functions);
+        let mut names = functions
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["code", "none", "path"]);
+    }
+
+    #[test]
+    fn test_full_analysis() {
+        let query = |q: &str| parser::SemanticQuery {
+            target: Some(parser::Literal::Plain(q.to_owned().into())),
+            ..Default::default()
+        };
+
+        let first = Exchange::new(uuid::Uuid::new_v4(), query("What does this repo do?"));
+        let mut quick = Exchange::new(uuid::Uuid::new_v4(), query("How are retries scheduled?"));
+        quick.quick = true;
+        quick.apply_update(Update::Article("Probably with a timer.".into()));
+        let mut exchanges = vec![first.clone(), quick.clone()];
+
+        // Exchanges that were answered in full can't be analysed again.
+        assert!(full_analysis(&mut exchanges.clone(), first.id, uuid::Uuid::new_v4()).is_err());
+        assert!(full_analysis(
+            &mut exchanges.clone(),
+            uuid::Uuid::nil(),
+            uuid::Uuid::new_v4()
+        )
+        .is_err());
+
+        let new_id = uuid::Uuid::new_v4();
+        let full = full_analysis(&mut exchanges, quick.id, new_id).unwrap();
+        assert_eq!(full.id, new_id);
+        assert_eq!(full.query(), quick.query());
+        assert_eq!(full.full_analysis_of, Some(quick.id));
+        assert!(!full.quick);
+        assert!(full.answer.is_none());
+
+        // The quick exchange is replaced by the full analysis.
+        assert_eq!(
+            exchanges.iter().map(|e| e.id).collect::<Vec<_>>(),
+            [first.id]
+        );
+    }
+}
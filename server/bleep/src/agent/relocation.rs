@@ -0,0 +1,480 @@
+//! Tracking of files that have been renamed or deleted since they were referenced.
+//!
+//! Stored threads can outlive the files they refer to. When a path is no longer present in the
+//! index, we try to follow it through the repository history so that citations and tool calls
+//! can point at the file's new location, or explain that it no longer exists.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+
+use crate::{agent::exchange::Exchange, repo::RepoRef, Application};
+
+/// The maximum number of consecutive renames we follow for a single path.
+const MAX_RENAMES: usize = 10;
+
+/// Renames found in repository histories, keyed by the repository's directory, the range of
+/// commits that was searched, and the path that was looked up.
+static RENAMES: Lazy<Mutex<HashMap<(PathBuf, String, String), Option<Rename>>>> =
+    Lazy::new(Default::default);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Relocation {
+    /// The file was renamed, and can now be found at this path.
+    Moved(String),
+    /// The file no longer exists.
+    Deleted,
+}
+
+/// A commit that renamed a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rename {
+    commit: String,
+    to: String,
+}
+
+/// Check whether `path` still exists in the index, and if not, try to find out what happened to
+/// it since `revision`, the commit it was cited at, if that is known.
+///
+/// This returns `None` if the path exists.
+pub async fn relocate(
+    app: &Application,
+    repo_ref: &RepoRef,
+    path: &str,
+    branch: Option<&str>,
+    revision: Option<&str>,
+) -> Result<Option<Relocation>> {
+    if exists(app, repo_ref, path, branch).await? {
+        return Ok(None);
+    }
+
+    let Some((disk_path, indexed)) = app
+        .repo_pool
+        .read(repo_ref, |_, repo| {
+            (repo.disk_path.clone(), repo.revision.clone())
+        })
+        .filter(|_| repo_ref.has_branches())
+    else {
+        // Without a repository history, there is no way to tell whether the file was renamed.
+        return Ok(Some(Relocation::Deleted));
+    };
+
+    let mut current = path.to_owned();
+    let mut since = revision.map(str::to_owned);
+    for _ in 0..MAX_RENAMES {
+        let rename = rename_target(&disk_path, &current, since.as_deref(), indexed.as_deref())
+            .await
+            .with_context(|| format!("failed to track renames of {path}"))?;
+
+        match rename {
+            Some(Rename { commit, to }) => {
+                if exists(app, repo_ref, &to, branch).await? {
+                    debug!(path, moved_to = to, "found renamed file");
+                    return Ok(Some(Relocation::Moved(to)));
+                }
+
+                current = to;
+                since = Some(commit);
+            }
+            None => break,
+        }
+    }
+
+    Ok(Some(Relocation::Deleted))
+}
+
+/// Annotate the code chunks and focused chunks of a list of exchanges with their relocation
+/// status.
+///
+/// Each path is only looked up once. Paths that can't be looked up are left as they are.
+pub async fn annotate(app: &Application, repo_ref: &RepoRef, exchanges: &mut [Exchange]) {
+    let mut relocations = HashMap::new();
+
+    for exchange in exchanges {
+        let branch = exchange
+            .query
+            .first_branch()
+            .filter(|_| repo_ref.has_branches())
+            .map(|b| b.into_owned());
+
+        for chunk in &mut exchange.code_chunks {
+            let key = (chunk.path.clone(), branch.clone(), chunk.revision.clone());
+            match relocate_once(app, repo_ref, &mut relocations, key).await {
+                None => {}
+                Some(Relocation::Moved(path)) => chunk.moved_to = Some(path),
+                Some(Relocation::Deleted) => chunk.deleted = true,
+            }
+        }
+
+        if let Some(chunk) = exchange.focused_chunk.as_mut() {
+            let key = (chunk.file_path.clone(), branch.clone(), None);
+            match relocate_once(app, repo_ref, &mut relocations, key).await {
+                None => {}
+                Some(Relocation::Moved(path)) => chunk.moved_to = Some(path),
+                Some(Relocation::Deleted) => chunk.deleted = true,
+            }
+        }
+    }
+}
+
+/// Relocate the `(path, branch, revision)` of `key`, reusing the result of an earlier lookup in
+/// `relocations`.
+async fn relocate_once(
+    app: &Application,
+    repo_ref: &RepoRef,
+    relocations: &mut HashMap<(String, Option<String>, Option<String>), Option<Relocation>>,
+    key: (String, Option<String>, Option<String>),
+) -> Option<Relocation> {
+    if let Some(relocation) = relocations.get(&key) {
+        return relocation.clone();
+    }
+
+    let (path, branch, revision) = &key;
+    let relocation = relocate(app, repo_ref, path, branch.as_deref(), revision.as_deref())
+        .await
+        .unwrap_or_else(|e| {
+            warn!(?e, path, "failed to relocate path");
+            None
+        });
+
+    relocations.insert(key, relocation.clone());
+    relocation
+}
+
+async fn exists(
+    app: &Application,
+    repo_ref: &RepoRef,
+    path: &str,
+    branch: Option<&str>,
+) -> Result<bool> {
+    Ok(app
+        .indexes
+        .file
+        .by_path(repo_ref, path, branch)
+        .await
+        .with_context(|| format!("failed to read path: {path}"))?
+        .is_some())
+}
+
+/// Find the path that `path` was renamed to after `since`, up to `until`, or `HEAD`.
+///
+/// Lookups that end at a fixed commit are cached, as their history can't change.
+async fn rename_target(
+    repo_dir: &Path,
+    path: &str,
+    since: Option<&str>,
+    until: Option<&str>,
+) -> Result<Option<Rename>> {
+    let end = until.unwrap_or("HEAD");
+    let range = match since {
+        Some(since) => format!("{since}..{end}"),
+        None => end.to_owned(),
+    };
+
+    if until.is_none() {
+        return git_rename_target(repo_dir, path, &range).await;
+    }
+
+    let key = (repo_dir.to_owned(), range, path.to_owned());
+    if let Some(rename) = RENAMES.lock().unwrap().get(&key) {
+        return Ok(rename.clone());
+    }
+
+    let rename = git_rename_target(repo_dir, path, &key.1).await?;
+    RENAMES.lock().unwrap().insert(key, rename.clone());
+    Ok(rename)
+}
+
+/// Find the path that `path` was renamed to, by looking at the first commit of `range` that
+/// removed it.
+///
+/// This returns `None` if the file was deleted, or was never part of the repository history.
+async fn git_rename_target(repo_dir: &Path, path: &str, range: &str) -> Result<Option<Rename>> {
+    let log = git(
+        repo_dir,
+        &[
+            "log",
+            "--reverse",
+            "--format=%H",
+            "--diff-filter=D",
+            range,
+            "--",
+            path,
+        ],
+    )
+    .await?;
+
+    let Some(commit) = log.lines().next().map(str::trim).filter(|c| !c.is_empty()) else {
+        return Ok(None);
+    };
+
+    let diff = git(
+        repo_dir,
+        &["diff", "--name-status", "-M", &format!("{commit}^"), commit],
+    )
+    .await?;
+
+    Ok(parse_rename(&diff, path).map(|to| Rename {
+        commit: commit.to_owned(),
+        to,
+    }))
+}
+
+/// Find the new name of `path` in the output of `git diff --name-status`.
+fn parse_rename(diff: &str, path: &str) -> Option<String> {
+    diff.lines().find_map(|line| {
+        let mut columns = line.split('\t');
+        let status = columns.next()?;
+        let from = columns.next()?;
+        let to = columns.next()?;
+
+        (status.starts_with('R') && from == path).then(|| to.to_owned())
+    })
+}
+
+pub(super) async fn git(repo_dir: &Path, args: &[&str]) -> Result<String> {
+    let mut command = tokio::process::Command::new("git");
+    command.arg("-C").arg(repo_dir).args(args);
+
+    #[cfg(windows)]
+    {
+        // CREATE_NO_WINDOW
+        command.creation_flags(0x08000000);
+    }
+
+    let output = command.output().await.context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        agent::exchange::{CodeChunk, FocusedChunk},
+        background::SyncHandle,
+        repo::{Backend, Repository},
+    };
+
+    fn run_git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=bloop", "-c", "user.email=bloop@bloop.ai"])
+            .args(args)
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+    }
+
+    fn head(dir: &Path) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .unwrap();
+
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    /// Index the git repository at `dir` into a new application, returning it with the temporary
+    /// directory of its index.
+    async fn indexed(dir: &Path) -> (TempDir, Application, RepoRef) {
+        let index_dir = TempDir::new("relocation-index").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref = RepoRef::new(Backend::Local, &dir.to_string_lossy()).unwrap();
+        let repo = Repository::local_from(&repo_ref);
+        app.repo_pool
+            .insert(repo_ref.clone(), repo.clone())
+            .unwrap();
+
+        let progress = tokio::sync::broadcast::channel(1).0;
+        let handle = SyncHandle::new(app.clone(), repo_ref.clone(), progress, None).await;
+        let writers = app.indexes.writers().await.unwrap();
+        writers.index(&handle, &repo).await.unwrap();
+        writers.commit().await.unwrap();
+
+        (index_dir, app, repo_ref)
+    }
+
+    fn cited(path: &str, revision: Option<&str>) -> CodeChunk {
+        CodeChunk {
+            path: path.to_owned(),
+            alias: 0,
+            snippet: String::new(),
+            start_line: 1,
+            end_line: 1,
+            moved_to: None,
+            deleted: false,
+            symbol_path: None,
+            blob: None,
+            revision: revision.map(str::to_owned),
+            mapped: None,
+        }
+    }
+
+    fn exchange(chunks: Vec<CodeChunk>, focused: Option<&str>) -> Exchange {
+        let mut exchange = Exchange::new(uuid::Uuid::new_v4(), Default::default());
+        exchange.code_chunks = chunks;
+        exchange.focused_chunk = focused.map(|path| FocusedChunk {
+            file_path: path.to_owned(),
+            start_line: 1,
+            end_line: 1,
+            moved_to: None,
+            deleted: false,
+        });
+        exchange
+    }
+
+    #[test]
+    fn test_parse_rename() {
+        let diff = "M\tsrc/lib.rs\nR087\tsrc/old.rs\tsrc/new.rs\nD\tsrc/gone.rs\n";
+
+        assert_eq!(
+            parse_rename(diff, "src/old.rs"),
+            Some("src/new.rs".to_owned())
+        );
+        assert_eq!(parse_rename(diff, "src/gone.rs"), None);
+        assert_eq!(parse_rename(diff, "src/lib.rs"), None);
+    }
+
+    #[tokio::test]
+    async fn test_git_rename_target() {
+        let tmp = TempDir::new("relocation").unwrap();
+        let dir = tmp.path();
+
+        run_git(dir, &["init", "-q"]);
+        std::fs::write(dir.join("renamed.rs"), "fn renamed() {}\n".repeat(20)).unwrap();
+        std::fs::write(dir.join("deleted.rs"), "fn deleted() {}\n".repeat(20)).unwrap();
+        run_git(dir, &["add", "."]);
+        run_git(dir, &["commit", "-q", "-m", "initial"]);
+
+        run_git(dir, &["mv", "renamed.rs", "moved.rs"]);
+        run_git(dir, &["rm", "-q", "deleted.rs"]);
+        run_git(dir, &["commit", "-q", "-m", "refactor"]);
+
+        let target = |path| async move {
+            git_rename_target(dir, path, "HEAD")
+                .await
+                .unwrap()
+                .map(|rename| rename.to)
+        };
+        assert_eq!(target("renamed.rs").await, Some("moved.rs".to_owned()));
+        assert_eq!(target("deleted.rs").await, None);
+        assert_eq!(target("never_existed.rs").await, None);
+    }
+    #[tokio::test]
+    async fn test_annotate_renamed() {
+        let tmp = TempDir::new("relocation").unwrap();
+        let dir = &crate::canonicalize(tmp.path()).unwrap();
+
+        run_git(dir, &["init", "-q"]);
+        std::fs::write(dir.join("renamed.rs"), "fn renamed() {}\n".repeat(20)).unwrap();
+        std::fs::write(dir.join("kept.rs"), "fn kept() {}\n").unwrap();
+        run_git(dir, &["add", "."]);
+        run_git(dir, &["commit", "-q", "-m", "initial"]);
+        let initial = head(dir);
+
+        run_git(dir, &["mv", "renamed.rs", "moved.rs"]);
+        run_git(dir, &["commit", "-q", "-m", "rename"]);
+
+        // A different file is added at the old path, and removed again.
+        std::fs::write(dir.join("renamed.rs"), "fn replacement() {}\n").unwrap();
+        run_git(dir, &["add", "."]);
+        run_git(dir, &["commit", "-q", "-m", "re-add"]);
+        let readded = head(dir);
+        run_git(dir, &["rm", "-q", "renamed.rs"]);
+        run_git(dir, &["commit", "-q", "-m", "remove"]);
+
+        let (_index_dir, app, repo_ref) = indexed(dir).await;
+        let mut exchanges = [exchange(
+            vec![
+                cited("renamed.rs", Some(&initial)),
+                cited("renamed.rs", Some(&readded)),
+                cited("kept.rs", Some(&initial)),
+            ],
+            Some("renamed.rs"),
+        )];
+        annotate(&app, &repo_ref, &mut exchanges).await;
+
+        // The chunk cited before the rename follows it, and the one cited after doesn't.
+        let chunks = &exchanges[0].code_chunks;
+        assert_eq!(chunks[0].moved_to.as_deref(), Some("moved.rs"));
+        assert!(!chunks[0].deleted);
+        assert_eq!(chunks[1].moved_to, None);
+        assert!(chunks[1].deleted);
+        assert_eq!(chunks[2].moved_to, None);
+        assert!(!chunks[2].deleted);
+
+        let focused = exchanges[0].focused_chunk.as_ref().unwrap();
+        assert_eq!(focused.moved_to.as_deref(), Some("moved.rs"));
+    }
+
+    #[tokio::test]
+    async fn test_annotate_deleted() {
+        let tmp = TempDir::new("relocation").unwrap();
+        let dir = &crate::canonicalize(tmp.path()).unwrap();
+
+        run_git(dir, &["init", "-q"]);
+        std::fs::write(dir.join("deleted.rs"), "fn deleted() {}\n".repeat(20)).unwrap();
+        std::fs::write(dir.join("kept.rs"), "fn kept() {}\n").unwrap();
+        run_git(dir, &["add", "."]);
+        run_git(dir, &["commit", "-q", "-m", "initial"]);
+        let initial = head(dir);
+
+        run_git(dir, &["rm", "-q", "deleted.rs"]);
+        run_git(dir, &["commit", "-q", "-m", "delete"]);
+
+        let (_index_dir, app, repo_ref) = indexed(dir).await;
+        let mut exchanges = [exchange(
+            vec![
+                cited("deleted.rs", Some(&initial)),
+                cited("kept.rs", Some(&initial)),
+                // A revision that isn't in the repository can't be followed, so the chunk is
+                // left as it is.
+                cited(
+                    "deleted.rs",
+                    Some("0000000000000000000000000000000000000000"),
+                ),
+            ],
+            Some("deleted.rs"),
+        )];
+        annotate(&app, &repo_ref, &mut exchanges).await;
+
+        let chunks = &exchanges[0].code_chunks;
+        assert!(chunks[0].deleted);
+        assert_eq!(chunks[0].moved_to, None);
+        assert!(!chunks[1].deleted);
+        assert!(!chunks[2].deleted);
+        assert_eq!(chunks[2].moved_to, None);
+        assert!(exchanges[0].focused_chunk.as_ref().unwrap().deleted);
+    }
+}
@@ -0,0 +1,108 @@
+//! Snapshots of an agent's context, so that a thread can be rolled back when the agent takes a
+//! wrong turn.
+
+use super::{exchange::Exchange, Agent};
+
+/// The exchanges of a thread and its path aliases, as of some point in time.
+#[derive(Debug, Clone)]
+pub struct ContextSnapshot {
+    exchanges: Vec<Exchange>,
+    archived_paths: Vec<String>,
+}
+
+impl ContextSnapshot {
+    pub fn exchanges(&self) -> &[Exchange] {
+        &self.exchanges
+    }
+}
+
+impl Agent {
+    /// Take a snapshot of this agent's exchanges and paths.
+    ///
+    /// This clones every exchange with its search steps, and nothing else.
+    pub fn context_snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            exchanges: self.exchanges.clone(),
+            archived_paths: self.archived_paths.clone(),
+        }
+    }
+
+    /// Roll this agent back to a snapshot taken with `Agent::context_snapshot`.
+    ///
+    /// Path aliases are restored with the exchanges, so aliases handed out since the snapshot
+    /// are free to be reused. The loop guard is reset, as the calls it saw may have been rolled
+    /// back.
+    pub fn restore_context_snapshot(&mut self, snapshot: ContextSnapshot) {
+        self.exchanges = snapshot.exchanges;
+        self.archived_paths = snapshot.archived_paths;
+        self.loop_guard = Default::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::{
+        agent::{builder, exchange::SearchStep, ExchangeTx},
+        query::parser,
+        repo::{Backend, RepoRef},
+        Application,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_restore_context_snapshot() {
+        let index_dir = tempdir::TempDir::new("test-context-snapshot").unwrap();
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(crate::Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref =
+            RepoRef::new(Backend::LocalDir, &index_dir.path().to_string_lossy()).unwrap();
+        let query = parser::parse_nl("Where are migrations defined?")
+            .unwrap()
+            .into_semantic()
+            .unwrap()
+            .into_owned();
+        let mut agent = builder::builder(app)
+            .repo(repo_ref)
+            .exchanges(vec![Exchange::new(uuid::Uuid::new_v4(), query)])
+            .build()
+            .unwrap()
+            .into_agent();
+
+        // The driver's receiver is gone with it, so the updates of the steps go here instead.
+        let (tx, _updates) = tokio::sync::mpsc::channel(16);
+        agent.exchange_tx = ExchangeTx::new(tx, false);
+
+        agent.list_files(&"**/*.sql".to_owned()).await.unwrap();
+        agent.archived_paths.push("migrations/0001.sql".into());
+        let snapshot = agent.context_snapshot();
+
+        // Two more steps, which turn out to be a wrong turn.
+        for pattern in ["migrations/**", "**/*.rs"] {
+            agent.list_files(&pattern.to_owned()).await.unwrap();
+        }
+        agent.last_exchange_mut().paths.push("src/db.rs".into());
+        agent.archived_paths.clear();
+        assert_eq!(agent.last_exchange().search_steps.len(), 3);
+
+        agent.restore_context_snapshot(snapshot.clone());
+        assert_eq!(agent.exchanges, snapshot.exchanges());
+        assert!(matches!(
+            &agent.last_exchange().search_steps[..],
+            [SearchStep::ListFiles { pattern, .. }] if pattern == "**/*.sql"
+        ));
+        assert_eq!(agent.paths(), ["migrations/0001.sql"]);
+
+        agent.complete();
+    }
+}
@@ -0,0 +1,424 @@
+//! Parsing of stack traces pasted into queries.
+//!
+//! Frames are normalized to a common format across languages, and ordered innermost first, so
+//! that the frame that crashed always comes first.
+
+use lazy_regex::regex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Rust,
+    Python,
+    JavaScript,
+    Java,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub path: String,
+    pub line: usize,
+    pub symbol: Option<String>,
+
+    /// Whether this frame points outside of the repository, into a standard library or a
+    /// dependency.
+    pub external: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackTrace {
+    pub language: Language,
+    /// Frames, innermost first.
+    pub frames: Vec<Frame>,
+}
+
+/// Parse a stack trace out of some text, if it contains one.
+///
+/// Text is taken to contain a stack trace if it has at least two frames, or a single frame
+/// alongside a language-specific marker like `Traceback (most recent call last):`.
+pub fn parse(text: &str) -> Option<StackTrace> {
+    let mut best: Option<(StackTrace, bool)> = None;
+
+    for language in [
+        Language::Rust,
+        Language::Python,
+        Language::JavaScript,
+        Language::Java,
+    ] {
+        let (frames, marker) = match language {
+            Language::Rust => parse_rust(text),
+            Language::Python => parse_python(text),
+            Language::JavaScript => parse_javascript(text),
+            Language::Java => parse_java(text),
+        };
+        let frames = dedup(frames);
+
+        if frames.len() < 2 && !(marker && !frames.is_empty()) {
+            continue;
+        }
+
+        let better = match &best {
+            Some((trace, best_marker)) => {
+                (marker, frames.len()) > (*best_marker, trace.frames.len())
+            }
+            None => true,
+        };
+
+        if better {
+            best = Some((StackTrace { language, frames }, marker));
+        }
+    }
+
+    best.map(|(trace, _)| trace)
+}
+
+/// Normalize a frame path, by removing URL schemes, bundler prefixes and leading `./`.
+pub fn normalize_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let path = match path.strip_prefix("webpack://") {
+        // Bundled paths are relative to the project root.
+        Some(rest) => rest.trim_start_matches('/'),
+        None => path.strip_prefix("file://").unwrap_or(&path),
+    };
+
+    path.trim_start_matches("./").to_owned()
+}
+
+/// Pick the candidate path that shares the most trailing path components with `frame_path`.
+///
+/// Candidates must at least have the same file name.
+pub fn best_match<'a>(
+    frame_path: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    fn components(path: &str) -> impl Iterator<Item = &str> {
+        path.rsplit('/').filter(|c| !c.is_empty() && *c != ".")
+    }
+
+    let frame = components(frame_path).collect::<Vec<_>>();
+
+    let mut best = None;
+    for candidate in candidates {
+        let shared = components(candidate)
+            .zip(&frame)
+            .take_while(|(a, b)| a == *b)
+            .count();
+
+        if shared > 0 && best.map_or(true, |(_, best_shared)| shared > best_shared) {
+            best = Some((candidate, shared));
+        }
+    }
+
+    best.map(|(candidate, _)| candidate)
+}
+
+/// Remove repeated frames, keeping the first occurrence and any symbol that a repeat adds.
+fn dedup(frames: Vec<Frame>) -> Vec<Frame> {
+    let mut out = Vec::<Frame>::new();
+
+    for frame in frames {
+        match out
+            .iter_mut()
+            .find(|f| f.path == frame.path && f.line == frame.line)
+        {
+            Some(existing) => {
+                if existing.symbol.is_none() {
+                    existing.symbol = frame.symbol;
+                }
+            }
+            None => out.push(frame),
+        }
+    }
+
+    out
+}
+
+fn frame(path: &str, line: &str, symbol: Option<&str>, external: bool) -> Option<Frame> {
+    Some(Frame {
+        path: normalize_path(path),
+        line: line.parse().ok()?,
+        symbol: symbol.map(str::to_owned),
+        external,
+    })
+}
+
+/// A panic location, followed by `RUST_BACKTRACE` frames.
+fn parse_rust(text: &str) -> (Vec<Frame>, bool) {
+    let mut frames = Vec::new();
+
+    let panic = regex!(r"panicked at (?:'.*', )?([^\s:']+):(\d+):\d+");
+    let marker = text.contains("stack backtrace:") || panic.is_match(text);
+
+    if let Some(c) = panic.captures(text) {
+        let path = &c[1];
+        frames.extend(frame(path, &c[2], None, is_external_rust(path)));
+    }
+
+    let mut symbol = None;
+    for line in text.lines() {
+        if let Some(c) = regex!(r"^\s*\d+:\s+(\S.*?)\s*$").captures(line) {
+            let name = regex!(r"::h[0-9a-f]{16}$").replace(&c[1], "");
+            symbol = Some(name.trim_end_matches("::{{closure}}").to_owned());
+        } else if let Some(c) = regex!(r"^\s*at\s+(.+?):(\d+):\d+\s*$").captures(line) {
+            let path = &c[1];
+            frames.extend(frame(
+                path,
+                &c[2],
+                symbol.take().as_deref(),
+                is_external_rust(path),
+            ));
+        }
+    }
+
+    (frames, marker)
+}
+
+fn is_external_rust(path: &str) -> bool {
+    path.starts_with("/rustc/")
+        || path.contains("/.cargo/registry/")
+        || path.contains("/.cargo/git/")
+        || path.contains("/rustlib/")
+}
+
+/// A `Traceback`, which lists the innermost frame last.
+fn parse_python(text: &str) -> (Vec<Frame>, bool) {
+    let marker = text.contains("Traceback (most recent call last):");
+
+    let mut frames = text
+        .lines()
+        .filter_map(|line| {
+            let c = regex!(r#"^\s*File "(.+?)", line (\d+)(?:, in (.+?))?\s*$"#).captures(line)?;
+            let path = &c[1];
+            let external = path.starts_with('<')
+                || path.contains("/site-packages/")
+                || path.contains("/dist-packages/")
+                || regex!(r"/lib/python\d").is_match(path);
+
+            frame(path, &c[2], c.get(3).map(|m| m.as_str()), external)
+        })
+        .collect::<Vec<_>>();
+
+    frames.reverse();
+    (frames, marker)
+}
+
+/// A V8 or SpiderMonkey style `Error.stack`.
+fn parse_javascript(text: &str) -> (Vec<Frame>, bool) {
+    let marker = text
+        .lines()
+        .any(|line| regex!(r"^\s*\w*(Error|Exception)\b").is_match(line));
+
+    let frames = text
+        .lines()
+        .filter_map(|line| {
+            let c = regex!(r"^\s*at (?:async )?(?:([^()]+?) \()?([^()\s]+?):(\d+):\d+\)?\s*$")
+                .captures(line)?;
+            let path = &c[2];
+            let external = path.starts_with("node:")
+                || path.starts_with("internal/")
+                || path.contains("node_modules/");
+
+            frame(path, &c[3], c.get(1).map(|m| m.as_str()), external)
+        })
+        .collect();
+
+    (frames, marker)
+}
+
+/// A JVM stack trace. These only include a file name, so the path is derived from the package.
+fn parse_java(text: &str) -> (Vec<Frame>, bool) {
+    let marker = regex!(r"(Exception|Error)\b").is_match(text);
+
+    let frames = text
+        .lines()
+        .filter_map(|line| {
+            let c = regex!(
+                r"^\s*at\s+(?:[\w.@-]*/+)?([\w$.]+)\.([\w$<>]+)\(([\w$.]+\.(?:java|kt|scala|groovy)):(\d+)\)\s*$"
+            )
+            .captures(line)?;
+
+            let class = &c[1];
+            let path = match class.rsplit_once('.') {
+                Some((package, _)) => format!("{}/{}", package.replace('.', "/"), &c[3]),
+                None => c[3].to_owned(),
+            };
+            let external = ["java.", "javax.", "jdk.", "sun.", "com.sun.", "kotlin.", "scala."]
+                .iter()
+                .any(|prefix| class.starts_with(prefix));
+
+            frame(&path, &c[4], Some(&format!("{class}.{}", &c[2])), external)
+        })
+        .collect();
+
+    (frames, marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// The `(path, line, symbol)` of internal frames, and the number of external frames.
+    fn summarize(trace: &StackTrace) -> (Vec<(&str, usize, Option<&str>)>, usize) {
+        let internal = trace
+            .frames
+            .iter()
+            .filter(|f| !f.external)
+            .map(|f| (f.path.as_str(), f.line, f.symbol.as_deref()))
+            .collect();
+        let external = trace.frames.iter().filter(|f| f.external).count();
+
+        (internal, external)
+    }
+
+    #[test]
+    fn test_rust() {
+        let trace = parse(include_str!("fixtures/stack_traces/rust.txt")).unwrap();
+
+        assert_eq!(trace.language, Language::Rust);
+        assert_eq!(
+            summarize(&trace),
+            (
+                vec![
+                    (
+                        "server/bleep/src/agent/exchange.rs",
+                        96,
+                        Some("bleep::agent::exchange::Exchange::apply_update")
+                    ),
+                    (
+                        "server/bleep/src/agent.rs",
+                        121,
+                        Some("bleep::agent::Agent::update")
+                    ),
+                    (
+                        "server/bleep/src/webserver/answer.rs",
+                        402,
+                        Some("bleep::webserver::answer::try_execute_agent")
+                    ),
+                ],
+                3
+            )
+        );
+
+        // The panic location is the first frame, even though it is listed before the backtrace.
+        assert!(!trace.frames[0].external);
+    }
+
+    #[test]
+    fn test_python() {
+        let trace = parse(include_str!("fixtures/stack_traces/python.txt")).unwrap();
+
+        assert_eq!(trace.language, Language::Python);
+        assert_eq!(
+            summarize(&trace),
+            (
+                vec![
+                    ("/home/user/project/app/loader.py", 18, Some("load_records")),
+                    ("/home/user/project/scripts/import.py", 37, Some("main")),
+                    ("/home/user/project/scripts/import.py", 42, Some("<module>")),
+                ],
+                2
+            )
+        );
+
+        // The innermost call is listed last in Python, but comes first here.
+        assert!(trace.frames[0].path.ends_with("simplejson/decoder.py"));
+    }
+
+    #[test]
+    fn test_javascript() {
+        let trace = parse(include_str!("fixtures/stack_traces/javascript.txt")).unwrap();
+
+        assert_eq!(trace.language, Language::JavaScript);
+        assert_eq!(
+            summarize(&trace),
+            (
+                vec![
+                    (
+                        "client/src/mappers/conversation.ts",
+                        10,
+                        Some("mapLoadingSteps")
+                    ),
+                    (
+                        "/app/client/src/components/Chat/Conversation.tsx",
+                        55,
+                        Some("ConversationResult")
+                    ),
+                    ("/app/client/src/services/api.ts", 120, None),
+                ],
+                2
+            )
+        );
+    }
+
+    #[test]
+    fn test_java() {
+        let trace = parse(include_str!("fixtures/stack_traces/java.txt")).unwrap();
+
+        assert_eq!(trace.language, Language::Java);
+        assert_eq!(
+            summarize(&trace),
+            (
+                vec![
+                    (
+                        "com/example/search/IndexManager.java",
+                        88,
+                        Some("com.example.search.IndexManager.open")
+                    ),
+                    (
+                        "com/example/search/IndexManager.java",
+                        140,
+                        Some("com.example.search.IndexManager$Loader.run")
+                    ),
+                    (
+                        "org/apache/lucene/store/FSDirectory.java",
+                        223,
+                        Some("org.apache.lucene.store.FSDirectory.openInput")
+                    ),
+                    (
+                        "com/example/search/Main.java",
+                        12,
+                        Some("com.example.search.Main.main")
+                    ),
+                ],
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn test_not_a_stack_trace() {
+        assert_eq!(parse("where is the config parsed?"), None);
+        assert_eq!(parse("what happens at src/main.rs:10:5"), None);
+        assert_eq!(parse("  at foo (src/index.js:1:1)"), None);
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("./src/main.rs"), "src/main.rs");
+        assert_eq!(normalize_path("webpack:///./src/app.ts"), "src/app.ts");
+        assert_eq!(normalize_path("file:///app/src/app.js"), "/app/src/app.js");
+        assert_eq!(normalize_path("/app/src/app.js"), "/app/src/app.js");
+        assert_eq!(normalize_path("C:\\app\\main.py"), "C:/app/main.py");
+    }
+
+    #[test]
+    fn test_best_match() {
+        let candidates = [
+            "client/src/mappers/conversation.ts",
+            "server/src/conversation.ts",
+            "client/src/mappers/index.ts",
+        ];
+
+        assert_eq!(
+            best_match("/app/client/src/mappers/conversation.ts", candidates),
+            Some("client/src/mappers/conversation.ts")
+        );
+        assert_eq!(
+            best_match("/deploy/src/conversation.ts", candidates),
+            Some("server/src/conversation.ts")
+        );
+        assert_eq!(best_match("/app/src/other.ts", candidates), None);
+    }
+}
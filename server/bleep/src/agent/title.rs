@@ -0,0 +1,126 @@
+//! Short, generated titles for conversation threads.
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+
+use crate::{
+    agent::{prompts, Agent},
+    llm_gateway,
+};
+
+pub const TITLE_MODEL: &str = "gpt-3.5-turbo";
+
+/// Titles are cut at the last word boundary before this many characters.
+const MAX_TITLE_CHARS: usize = 60;
+
+impl Agent {
+    /// A short title for this thread, summarising its first query.
+    ///
+    /// The title is generated once, and cached for subsequent calls.
+    pub async fn conversation_title(&mut self) -> Result<String> {
+        if let Some(title) = &self.thread_title {
+            return Ok(title.clone());
+        }
+
+        let query = self
+            .exchanges
+            .first()
+            .and_then(|e| e.query())
+            .context("thread has no query")?;
+
+        let title = generate(&self.llm_gateway, &query).await?;
+        self.thread_title = Some(title.clone());
+
+        Ok(title)
+    }
+}
+
+/// Ask the LLM for a title summarising `query`.
+pub async fn generate(llm_gateway: &llm_gateway::Client, query: &str) -> Result<String> {
+    let messages = [llm_gateway::api::Message::user(
+        &prompts::conversation_title_prompt(query),
+    )];
+
+    let response = llm_gateway
+        .clone()
+        .model(TITLE_MODEL)
+        .chat(&messages, None)
+        .await?
+        .try_collect::<String>()
+        .await?;
+
+    let title = clean(&response);
+    if title.is_empty() {
+        anyhow::bail!("LLM returned an empty title");
+    }
+
+    Ok(title)
+}
+
+/// Strip quotes and trailing punctuation from a generated title, and cap its length.
+fn clean(response: &str) -> String {
+    let title = response
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or_default();
+
+    let title = title
+        .strip_prefix("Title:")
+        .unwrap_or(title)
+        .trim()
+        .trim_matches(|c| matches!(c, '"' | '\'' | '`' | '*'))
+        .trim_end_matches(|c| matches!(c, '.' | '!' | ':' | ';' | ','))
+        .trim();
+
+    if title.chars().count() < MAX_TITLE_CHARS {
+        return title.to_owned();
+    }
+
+    let capped = title
+        .char_indices()
+        .nth(MAX_TITLE_CHARS - 1)
+        .map(|(i, _)| &title[..i])
+        .unwrap_or(title);
+
+    match capped.rfind(char::is_whitespace) {
+        Some(i) => capped[..i].trim_end().to_owned(),
+        None => capped.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_gateway::mock::{Gateway, Reply};
+
+    /// A client of a mock gateway, which answers every request with `response`.
+    fn mock_client(response: &'static str) -> llm_gateway::Client {
+        Gateway::serve(move |_| Reply::text(response)).client()
+    }
+
+    #[tokio::test]
+    async fn test_generate() {
+        let client = mock_client("\"Query parser error handling.\"\n");
+        let title = generate(&client, "How are errors in the query parser handled?")
+            .await
+            .unwrap();
+
+        assert_eq!(title, "Query parser error handling");
+        assert!(title.chars().count() < MAX_TITLE_CHARS);
+
+        let client = mock_client(
+            "Title: A very long title that ignores the instruction to be shorter than five words",
+        );
+        let title = generate(&client, "What does this do?").await.unwrap();
+
+        assert_eq!(
+            title,
+            "A very long title that ignores the instruction to be"
+        );
+        assert!(title.chars().count() < MAX_TITLE_CHARS);
+
+        let client = mock_client("  \n");
+        assert!(generate(&client, "What does this do?").await.is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Token counts for prompt budgets.
+//!
+//! Budgets are always measured by encoding the strings that are sent. Character counts are a poor
+//! proxy outside of English: Chinese and Japanese text often takes a token or more per character.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use tiktoken_rs::{ChatCompletionRequestMessage, CoreBPE};
+
+/// The default for `Configuration::token_safety_margin`.
+pub const DEFAULT_SAFETY_MARGIN: usize = 128;
+
+/// Tokenizers are expensive to build, so each model's is built once and shared.
+static TOKENIZERS: Lazy<Mutex<HashMap<String, Arc<CoreBPE>>>> = Lazy::new(Default::default);
+
+/// The time spent encoding text, shared by the tokenizers of one agent.
+#[derive(Debug, Default, Clone)]
+pub struct Stopwatch(Arc<AtomicU64>);
+
+impl Stopwatch {
+    fn add(&self, elapsed: Duration) {
+        self.0
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Take the time measured so far, resetting it to zero.
+    pub fn take(&self) -> Duration {
+        Duration::from_micros(self.0.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// The tokenizer of a model, which measures the time spent encoding with it.
+#[derive(Clone)]
+pub struct Tokenizer {
+    model: String,
+    bpe: Arc<CoreBPE>,
+    stopwatch: Stopwatch,
+}
+
+impl Tokenizer {
+    pub fn new(model: &str) -> Result<Self> {
+        Self::with_stopwatch(model, Stopwatch::default())
+    }
+
+    pub fn with_stopwatch(model: &str, stopwatch: Stopwatch) -> Result<Self> {
+        let mut tokenizers = TOKENIZERS.lock().unwrap();
+        let bpe = match tokenizers.get(model) {
+            Some(bpe) => Arc::clone(bpe),
+            None => {
+                let bpe = Arc::new(tiktoken_rs::get_bpe_from_model(model)?);
+                tokenizers.insert(model.to_owned(), Arc::clone(&bpe));
+                bpe
+            }
+        };
+
+        Ok(Self {
+            model: model.to_owned(),
+            bpe,
+            stopwatch,
+        })
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<usize> {
+        let start = Instant::now();
+        let tokens = self.bpe.encode_ordinary(text);
+        self.stopwatch.add(start.elapsed());
+        tokens
+    }
+
+    pub fn decode(&self, tokens: Vec<usize>) -> Result<String> {
+        self.bpe.decode(tokens)
+    }
+
+    pub fn count(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    /// The number of tokens that `messages` take up in a chat completion prompt.
+    ///
+    /// This follows the accounting of `tiktoken_rs::num_tokens_from_messages`, which includes the
+    /// few tokens that frame each message and prime the reply.
+    pub fn count_messages(&self, messages: &[ChatCompletionRequestMessage]) -> usize {
+        const REPLY_PRIMING: usize = 3;
+
+        messages
+            .iter()
+            .map(|m| self.count_message(m))
+            .sum::<usize>()
+            + REPLY_PRIMING
+    }
+
+    /// The number of tokens that a single message takes up in a chat completion prompt, including
+    /// the tokens that frame it.
+    pub fn count_message(&self, message: &ChatCompletionRequestMessage) -> usize {
+        const TOKENS_PER_MESSAGE: usize = 3;
+        const TOKENS_PER_NAME: usize = 1;
+
+        let name = message
+            .name
+            .as_deref()
+            .map_or(0, |name| self.count(name) + TOKENS_PER_NAME);
+
+        TOKENS_PER_MESSAGE + self.count(&message.role) + self.count(&message.content) + name
+    }
+
+    /// The size of this model's context window, in tokens.
+    pub fn context_size(&self) -> usize {
+        tiktoken_rs::model::get_context_size(&self.model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A thread about this codebase, in Japanese and Chinese.
+    const CJK: &str = include_str!("fixtures/cjk_thread.txt");
+
+    #[test]
+    fn test_cjk_token_counts() {
+        let tokenizer = Tokenizer::new("gpt-4-0613").unwrap();
+
+        // A quarter of the characters, as is commonly assumed for English, is far too few.
+        let chars = CJK.chars().count();
+        assert!(tokenizer.count(CJK) > chars / 2);
+
+        let messages = [ChatCompletionRequestMessage {
+            role: "user".to_owned(),
+            content: CJK.to_owned(),
+            name: None,
+        }];
+        assert_eq!(
+            tokenizer.count_messages(&messages),
+            tiktoken_rs::num_tokens_from_messages("gpt-4-0613", &messages).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tokenizer_is_shared() {
+        let stopwatch = Stopwatch::default();
+        let a = Tokenizer::with_stopwatch("gpt-4-0613", stopwatch.clone()).unwrap();
+        let b = Tokenizer::new("gpt-4-0613").unwrap();
+        assert!(Arc::ptr_eq(&a.bpe, &b.bpe));
+
+        a.count(&CJK.repeat(10));
+        b.count(&CJK.repeat(10));
+        assert!(stopwatch.take() > Duration::ZERO);
+        assert_eq!(stopwatch.take(), Duration::ZERO);
+    }
+}
@@ -1,14 +1,18 @@
-use std::{collections::HashMap, mem, ops::Range, pin::pin};
+use std::{borrow::Cow, collections::HashMap, mem, ops::Range, pin::pin, time::Instant};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use futures::StreamExt;
 use rand::{rngs::OsRng, seq::SliceRandom};
+use serde::Deserialize;
 use tracing::debug;
 
 use crate::{
     agent::{
-        exchange::{CodeChunk, Update},
-        prompts, transcoder, Agent, ANSWER_MODEL,
+        citations::CitationRegistry,
+        exchange::{AnswerOutcome, CodeChunk, Update},
+        outcome, prompts, quick,
+        tokens::Tokenizer,
+        transcoder, Agent, OutputFormat, ANSWER_MODEL,
     },
     analytics::EventData,
     llm_gateway,
@@ -17,13 +21,15 @@ use crate::{
 impl Agent {
     async fn answer_context(&mut self, aliases: &[usize], gpt_model: &str) -> Result<String> {
         let paths = self.paths();
+        let repo_aliases = self.repo_aliases();
 
         let mut s = "".to_owned();
 
+        // Paths that earlier exchanges found in other repositories can't be read from this one.
         let mut aliases = aliases
             .iter()
             .copied()
-            .filter(|alias| *alias < paths.len())
+            .filter(|alias| repo_aliases.contains(alias))
             .collect::<Vec<_>>();
 
         aliases.sort();
@@ -38,7 +44,7 @@ impl Agent {
         let aliases = if aliases.len() == 1 {
             aliases
         } else {
-            (0..paths.len()).collect()
+            repo_aliases
         };
 
         if !aliases.is_empty() {
@@ -56,8 +62,10 @@ impl Agent {
         // doesn't trim enough chunks. So, we enforce a hard limit here that stops adding tokens
         // early if we reach a heuristic limit.
         const PROMPT_HEADROOM: usize = 2500;
-        let bpe = tiktoken_rs::get_bpe_from_model(gpt_model)?;
-        let mut remaining_prompt_tokens = tiktoken_rs::get_completion_max_tokens(gpt_model, &s)?;
+        let tokenizer = self.tokenizer(gpt_model)?;
+        let reserved = PROMPT_HEADROOM + self.app.config.token_safety_margin;
+        let mut remaining_prompt_tokens =
+            tokenizer.context_size().saturating_sub(tokenizer.count(&s));
 
         // Select as many recent chunks as possible
         let mut recent_chunks = Vec::new();
@@ -71,9 +79,9 @@ impl Agent {
 
             let formatted_snippet = format!("### {} ###\n{snippet}\n\n", chunk.path);
 
-            let snippet_tokens = bpe.encode_ordinary(&formatted_snippet).len();
+            let snippet_tokens = tokenizer.count(&formatted_snippet);
 
-            if snippet_tokens >= remaining_prompt_tokens - PROMPT_HEADROOM {
+            if snippet_tokens + reserved >= remaining_prompt_tokens {
                 debug!("Breaking at {} tokens...", remaining_prompt_tokens);
                 break;
             }
@@ -84,76 +92,128 @@ impl Agent {
             debug!("{}", remaining_prompt_tokens);
         }
 
-        // group recent chunks by path alias
-        let mut recent_chunks_by_alias: HashMap<_, _> =
-            recent_chunks
-                .into_iter()
-                .fold(HashMap::new(), |mut map, item| {
-                    map.entry(item.0.alias).or_insert_with(Vec::new).push(item);
-                    map
-                });
-
-        // write the header if we have atleast one chunk
-        if !recent_chunks_by_alias.values().all(Vec::is_empty) {
-            s += "\n##### CODE CHUNKS #####\n\n";
-        }
-
-        // sort by alias, then sort by lines
-        let mut aliases = recent_chunks_by_alias.keys().copied().collect::<Vec<_>>();
-        aliases.sort();
-
-        for alias in aliases {
-            let chunks = recent_chunks_by_alias.get_mut(&alias).unwrap();
-            chunks.sort_by(|a, b| a.0.start_line.cmp(&b.0.start_line));
-            for (_, formatted_snippet) in chunks {
-                s += formatted_snippet;
+        // Tokens don't always add up across the boundaries of the strings they were counted in, so
+        // we check the assembled context too, dropping the oldest chunks until it fits.
+        loop {
+            let context = render_context(&s, &recent_chunks);
+            if recent_chunks.is_empty()
+                || tokenizer.count(&context) + reserved <= tokenizer.context_size()
+            {
+                break Ok(context);
             }
-        }
 
-        Ok(s)
+            debug!("assembled context is too long, dropping a chunk");
+            recent_chunks.pop();
+        }
     }
 
     pub async fn answer(&mut self, aliases: &[usize]) -> Result<()> {
-        const ANSWER_HEADROOM: usize = 1024; // the number of tokens reserved for the answer
+        const ANSWER_HEADROOM: usize = 4096; // the number of tokens reserved for the answer
 
         debug!(?aliases, "creating article response");
 
-        let context = self.answer_context(aliases, ANSWER_MODEL).await?;
-        let system_prompt = prompts::answer_article_prompt(&context);
+        // Quick exchanges are answered from their search snippets, by a faster model.
+        let quick = self.last_exchange().quick;
+        let (model, context) = if quick {
+            self.adjust_headroom(quick::MAX_TOKENS as usize);
+            (quick::ANSWER_MODEL, self.quick_answer_context()?)
+        } else {
+            self.adjust_headroom(ANSWER_HEADROOM);
+            (
+                ANSWER_MODEL,
+                self.answer_context(aliases, ANSWER_MODEL).await?,
+            )
+        };
+
+        // Files that timed out were never read, which the answer should own up to.
+        let context = context + &prompts::timed_out_note(&self.last_exchange().timed_out_paths());
+        let context =
+            context + &prompts::index_warnings_note(self.last_exchange().index_warnings.as_ref());
+
+        let system_prompt = match (&self.call_graph, self.config.output_format) {
+            (_, OutputFormat::Json) => prompts::answer_json_prompt(&context),
+            (Some(graph), _) => {
+                prompts::explain_function_prompt(&graph.target.symbol, &graph.outline(), &context)
+            }
+            (None, _) => prompts::answer_article_prompt(&context),
+        };
         let system_message = llm_gateway::api::Message::system(&system_prompt);
         let history = {
             let h = self.utter_history().collect::<Vec<_>>();
-            let system_headroom =
-                tiktoken_rs::num_tokens_from_messages(ANSWER_MODEL, &[(&system_message).into()])?;
-            trim_utter_history(h, ANSWER_HEADROOM + system_headroom)?
+            let tokenizer = self.tokenizer(model)?;
+            let system_headroom = tokenizer.count_messages(&[(&system_message).into()]);
+            trim_utter_history(
+                h,
+                self.config.headroom_tokens + system_headroom + self.app.config.token_safety_margin,
+                &tokenizer,
+            )?
         };
         let messages = Some(system_message)
             .into_iter()
             .chain(history.iter().cloned())
             .collect::<Vec<_>>();
 
-        let mut stream = pin!(
-            self.llm_gateway
-                .clone()
-                .model(ANSWER_MODEL)
-                .chat(&messages, None)
-                .await?
-        );
+        let mut llm_gateway = self.llm_gateway.clone().model(model);
+        if quick {
+            let max_tokens = self
+                .config
+                .max_response_tokens
+                .map_or(quick::MAX_TOKENS, |n| n.min(quick::MAX_TOKENS));
+            llm_gateway = llm_gateway.max_tokens(max_tokens);
+        }
+
+        let start = Instant::now();
+        let mut stream = pin!(llm_gateway.chat(&messages, None).await?);
+
+        let citations = CitationRegistry::from_exchanges(&self.repo_ref, &self.exchanges);
 
+        let mut buffer = self.config.flush.buffer(Instant::now());
         let mut response = String::new();
         while let Some(fragment) = stream.next().await {
             let fragment = fragment?;
             response += &fragment;
 
-            let (article, summary) = transcoder::decode(&response);
-            self.update(Update::Article(article)).await?;
+            let text = match &mut buffer {
+                Some(buffer) => match buffer.push(&response, Instant::now()) {
+                    Some(text) => Cow::Owned(text),
+                    None => continue,
+                },
+                None => Cow::Borrowed(response.as_str()),
+            };
 
-            if let Some(summary) = summary {
-                self.update(Update::Conclude(summary)).await?;
-            }
+            self.update_article(&text, &citations).await?;
+        }
+
+        // Whatever is left in the buffer is sent with the rest of the answer.
+        if buffer.is_some() {
+            self.update_article(&response, &citations).await?;
         }
 
-        let summary = transcoder::decode(&response).1.unwrap_or_else(|| {
+        let (tag, untagged) = outcome::split_tag(&response, true);
+        let scrubbed = scrub_instructions(untagged);
+        let redacted = self.redact_secrets(None, &scrubbed);
+        let (tag, article, summary) = match self.config.output_format {
+            OutputFormat::Json => {
+                let (json, answer) = parse_json_answer(&redacted)?;
+                self.update(Update::Article(json)).await?;
+
+                let tag = answer.outcome.as_deref().and_then(AnswerOutcome::parse);
+                (tag, answer.answer, answer.summary)
+            }
+            OutputFormat::Prose | OutputFormat::Markdown => {
+                let (article, summary) = transcoder::decode(&redacted);
+                (tag, article, summary)
+            }
+        };
+
+        let signals = outcome::Signals {
+            forced: self.last_exchange().forced_answer,
+            empty_context: self.code_chunks().next().is_none(),
+        };
+        let answer_outcome = outcome::classify(tag, &article, summary.as_deref(), signals);
+        debug!(?answer_outcome, ?tag, ?signals, "classified answer");
+
+        let summary = summary.unwrap_or_else(|| {
             [
                 "I hope that was useful, can I help with anything else?",
                 "Is there anything else I can help you with?",
@@ -165,19 +225,48 @@ impl Agent {
             .to_owned()
         });
 
+        self.last_exchange_mut().outcome = Some(answer_outcome);
         self.update(Update::Conclude(summary)).await?;
 
+        self.track_usage("answer", model, &messages, &response, start.elapsed())
+            .await;
+        self.record_outcome(answer_outcome).await;
+
         self.track_query(
             EventData::output_stage("answer_article")
                 .with_payload("query", self.last_exchange().query())
                 .with_payload("query_history", &history)
                 .with_payload("response", &response)
-                .with_payload("raw_prompt", &system_prompt),
+                .with_payload("raw_prompt", &system_prompt)
+                .with_payload("quick", quick),
         );
 
         Ok(())
     }
 
+    async fn update_article(&mut self, response: &str, citations: &CitationRegistry) -> Result<()> {
+        // Partial JSON can't be parsed, so JSON answers are only sent once they are complete.
+        if self.config.output_format == OutputFormat::Json {
+            return Ok(());
+        }
+
+        let (_, untagged) = outcome::split_tag(response, false);
+        let scrubbed = scrub_instructions(untagged);
+        let redacted = self.redact_secrets(None, &scrubbed);
+        let (article, summary) = transcoder::decode_cited(&redacted, Some(citations));
+        let article = match self.config.output_format {
+            OutputFormat::Prose => transcoder::to_prose(&article),
+            _ => article,
+        };
+        self.update(Update::Article(article)).await?;
+
+        if let Some(summary) = summary {
+            self.update(Update::Conclude(summary)).await?;
+        }
+
+        Ok(())
+    }
+
     /// History of `user`, `assistant` messages. These are the messages that are shown to the user.
     fn utter_history(&self) -> impl Iterator<Item = llm_gateway::api::Message> + '_ {
         const ANSWER_MAX_HISTORY_SIZE: usize = 5;
@@ -230,9 +319,8 @@ impl Agent {
         /// Making this closure to 1 means that more of the context is taken up by source code.
         const CONTEXT_CODE_RATIO: f32 = 0.5;
 
-        let bpe = tiktoken_rs::get_bpe_from_model(gpt_model).unwrap();
-        let context_size = tiktoken_rs::model::get_context_size(gpt_model);
-        let max_tokens = (context_size as f32 * CONTEXT_CODE_RATIO) as usize;
+        let tokenizer = self.tokenizer(gpt_model).unwrap();
+        let max_tokens = (tokenizer.context_size() as f32 * CONTEXT_CODE_RATIO) as usize;
 
         let mut spans_by_path = HashMap::<_, Vec<_>>::new();
         for c in self.code_chunks().filter(|c| aliases.contains(&c.alias)) {
@@ -247,24 +335,25 @@ impl Agent {
         let self_ = &*self;
         // Map of path -> line list
         let lines_by_file = futures::stream::iter(&mut spans_by_path)
-            .then(|(path, spans)| async move {
+            .filter_map(|(path, spans)| async move {
                 spans.sort_by_key(|c| c.start);
 
-                let lines = self_
-                    .get_file_content(path)
-                    .await
-                    .unwrap()
-                    .unwrap_or_else(|| panic!("path did not exist in the index: {path}"))
-                    .content
-                    .lines()
-                    .map(str::to_owned)
-                    .collect::<Vec<_>>();
+                // Files may have been renamed or deleted since their chunks were cited, in which
+                // case we simply leave them out of the context.
+                let Some(doc) = self_.get_file_content(path).await.ok().flatten() else {
+                    debug!(?path, "path did not exist in the index, skipping");
+                    return None;
+                };
+
+                let lines = doc.content.lines().map(str::to_owned).collect::<Vec<_>>();
 
-                (path.clone(), lines)
+                Some((path.clone(), lines))
             })
             .collect::<HashMap<_, _>>()
             .await;
 
+        spans_by_path.retain(|path, _| lines_by_file.contains_key(path));
+
         // Total number of lines to try and expand by, per loop iteration.
         const TOTAL_LINE_INC: usize = 100;
 
@@ -281,7 +370,7 @@ impl Agent {
                 .map(|(path, span)| {
                     let range = span.start.saturating_sub(1)..span.end.saturating_sub(1);
                     let snippet = lines_by_file.get(path).unwrap()[range].join("\n");
-                    bpe.encode_ordinary(&snippet).len()
+                    tokenizer.count(&snippet)
                 })
                 .sum::<usize>();
 
@@ -358,22 +447,66 @@ impl Agent {
                     snippet,
                     start_line: span.start,
                     end_line: span.end,
+                    moved_to: None,
+                    deleted: false,
+                    symbol_path: None,
+                    blob: None,
+                    revision: None,
+                    mapped: None,
                 }
             })
             .collect()
     }
 }
 
+/// Append the code chunks of a context to its `header`, grouped by path alias.
+fn render_context(header: &str, chunks: &[(CodeChunk, String)]) -> String {
+    let mut s = header.to_owned();
+
+    // group recent chunks by path alias
+    let mut chunks_by_alias: HashMap<_, Vec<_>> = HashMap::new();
+    for (chunk, formatted_snippet) in chunks {
+        chunks_by_alias
+            .entry(chunk.alias)
+            .or_default()
+            .push((chunk, formatted_snippet));
+    }
+
+    // write the header if we have atleast one chunk
+    if !chunks_by_alias.is_empty() {
+        s += "\n##### CODE CHUNKS #####\n\n";
+    }
+
+    // sort by alias, then sort by lines
+    let mut aliases = chunks_by_alias.keys().copied().collect::<Vec<_>>();
+    aliases.sort();
+
+    for alias in aliases {
+        let chunks = chunks_by_alias.get_mut(&alias).unwrap();
+        chunks.sort_by(|a, b| a.0.start_line.cmp(&b.0.start_line));
+        for (_, formatted_snippet) in chunks {
+            s += formatted_snippet;
+        }
+    }
+
+    s
+}
+
 // headroom refers to the amount of space reserved for the rest of the prompt
 fn trim_utter_history(
     mut history: Vec<llm_gateway::api::Message>,
     headroom: usize,
+    tokenizer: &Tokenizer,
 ) -> Result<Vec<llm_gateway::api::Message>> {
     let mut tiktoken_msgs: Vec<tiktoken_rs::ChatCompletionRequestMessage> =
         history.iter().map(|m| m.into()).collect::<Vec<_>>();
 
     // remove the earliest messages, one by one, until we can accomodate into prompt
-    while tiktoken_rs::get_chat_completion_max_tokens(ANSWER_MODEL, &tiktoken_msgs)? < headroom {
+    while tokenizer
+        .context_size()
+        .saturating_sub(tokenizer.count_messages(&tiktoken_msgs))
+        < headroom
+    {
         if !tiktoken_msgs.is_empty() {
             tiktoken_msgs.remove(0);
             history.remove(0);
@@ -385,6 +518,55 @@ fn trim_utter_history(
     Ok(history)
 }
 
+/// The fields of an answer written with `prompts::answer_json_prompt`.
+#[derive(Deserialize, Debug, PartialEq)]
+struct JsonAnswer {
+    outcome: Option<String>,
+    #[serde(default)]
+    answer: String,
+    summary: Option<String>,
+}
+
+/// Check that `response` is a JSON answer, returning it alongside its fields.
+///
+/// Models sometimes wrap JSON in a code block despite being asked not to, which is removed.
+fn parse_json_answer(response: &str) -> Result<(String, JsonAnswer)> {
+    let json = response.trim();
+    let json = json
+        .strip_prefix("```json")
+        .or_else(|| json.strip_prefix("```"))
+        .and_then(|json| json.strip_suffix("```"))
+        .unwrap_or(json)
+        .trim();
+
+    let value =
+        serde_json::from_str::<serde_json::Value>(json).context("the answer was not valid JSON")?;
+    let answer = JsonAnswer::deserialize(&value).context("the answer was not a JSON answer")?;
+
+    Ok((value.to_string(), answer))
+}
+
+/// Remove any internal instructions that the model repeated verbatim in its response.
+fn scrub_instructions(response: &str) -> Cow<'_, str> {
+    if !prompts::INTERNAL_INSTRUCTIONS
+        .iter()
+        .any(|instruction| response.contains(instruction))
+    {
+        return response.into();
+    }
+
+    let mut scrubbed = response.to_owned();
+    for instruction in prompts::INTERNAL_INSTRUCTIONS {
+        // Try to take trailing punctuation and line breaks with the instruction, so that we don't
+        // leave dangling fragments behind.
+        for suffix in [".\n", "\n", ".", ""] {
+            scrubbed = scrubbed.replace(&format!("{instruction}{suffix}"), "");
+        }
+    }
+
+    scrubbed.into()
+}
+
 /// Merge line ranges if they overlap.
 ///
 /// This function assumes that the first parameter is a line range which starts *before* the line
@@ -408,6 +590,7 @@ mod tests {
 
     #[test]
     fn test_trimming_utter_history() {
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
         let long_string = "long string ".repeat(2000);
         let history = vec![
             llm_gateway::api::Message::user("bar"),
@@ -422,14 +605,14 @@ mod tests {
 
         // the answer needs 8100 tokens of 8192, the utter history can admit just one message
         assert_eq!(
-            trim_utter_history(history.clone(), 8100).unwrap(),
+            trim_utter_history(history.clone(), 8100, &tokenizer).unwrap(),
             vec![llm_gateway::api::Message::user("corge"),]
         );
 
         // the answer needs just 4000 tokens of 8192, the utter history can accomodate
         // one long_string, but no more long_strings
         assert_eq!(
-            trim_utter_history(history, 4000).unwrap(),
+            trim_utter_history(history, 4000, &tokenizer).unwrap(),
             vec![
                 llm_gateway::api::Message::assistant("quux"),
                 llm_gateway::api::Message::user("fred"),
@@ -439,4 +622,88 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_trimming_cjk_utter_history() {
+        let tokenizer = Tokenizer::new(ANSWER_MODEL).unwrap();
+        let cjk = include_str!("../fixtures/cjk_thread.txt").repeat(3);
+        let history = vec![
+            llm_gateway::api::Message::user(&cjk),
+            llm_gateway::api::Message::assistant(&cjk),
+            llm_gateway::api::Message::user(&cjk),
+            llm_gateway::api::Message::assistant("quux"),
+            llm_gateway::api::Message::user("corge"),
+        ];
+
+        let headroom = 6000;
+        let trimmed = trim_utter_history(history.clone(), headroom, &tokenizer).unwrap();
+
+        // Counting characters, the whole history would appear to fit.
+        let chars = history
+            .iter()
+            .map(|m| {
+                tiktoken_rs::ChatCompletionRequestMessage::from(m)
+                    .content
+                    .chars()
+                    .count()
+            })
+            .sum::<usize>();
+        assert!(chars / 4 + headroom < tokenizer.context_size());
+        assert!(trimmed.len() < history.len());
+
+        let msgs = trimmed.iter().map(|m| m.into()).collect::<Vec<_>>();
+        assert!(tokenizer.count_messages(&msgs) + headroom <= tokenizer.context_size());
+        assert_eq!(trimmed.last(), history.last());
+    }
+
+    #[test]
+    fn test_scrub_instructions() {
+        assert_eq!(
+            scrub_instructions("The answer is in `src/lib.rs`."),
+            "The answer is in `src/lib.rs`."
+        );
+        assert_eq!(
+            scrub_instructions("Call a function. Do not answer.\nThe answer is in `src/lib.rs`."),
+            "The answer is in `src/lib.rs`."
+        );
+        assert_eq!(
+            scrub_instructions(
+                "ALWAYS call a function. DO NOT answer the question directly\nSee `lib.rs`. \
+                 Call a function. Do not answer"
+            ),
+            "See `lib.rs`. "
+        );
+    }
+
+    #[test]
+    fn test_parse_json_answer() {
+        let response = serde_json::json!({
+            "outcome": "partial",
+            "answer": "Requests are retried.",
+            "summary": "Requests are retried.",
+            "references": [],
+        })
+        .to_string();
+        let (json, answer) = parse_json_answer(&response).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&response).unwrap()
+        );
+        assert_eq!(
+            answer,
+            JsonAnswer {
+                outcome: Some("partial".to_owned()),
+                answer: "Requests are retried.".to_owned(),
+                summary: Some("Requests are retried.".to_owned()),
+            }
+        );
+
+        // Code blocks around the answer are removed.
+        let fenced = format!("```json\n{response}\n```");
+        assert_eq!(parse_json_answer(&fenced).unwrap().0, json);
+
+        assert!(parse_json_answer("Requests are retried.").is_err());
+        assert!(parse_json_answer(r#"{"answer": "Requests are"#).is_err());
+        assert!(parse_json_answer(r#"["Requests are retried."]"#).is_err());
+    }
 }
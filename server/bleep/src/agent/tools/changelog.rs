@@ -0,0 +1,325 @@
+use anyhow::{Context, Result};
+use tracing::debug;
+
+use crate::{
+    agent::{
+        exchange::{BreakingChange, SearchStep, Update},
+        relocation, Agent,
+    },
+    analytics::EventData,
+};
+
+/// The maximum number of commits that a changelog covers.
+const MAX_COMMITS: usize = 100;
+
+/// Footer tokens that mark a breaking change, as defined by conventional commits.
+const BREAKING_TOKENS: &[&str] = &["BREAKING CHANGE", "BREAKING-CHANGE"];
+
+impl Agent {
+    pub async fn changelog(&mut self, since: Option<&String>) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::Changelog {
+            since: since.cloned(),
+            breaking_changes: Vec::new(),
+            response: String::new(),
+        }))
+        .await?;
+
+        let disk_path = self
+            .app
+            .repo_pool
+            .read(&self.repo_ref, |_, repo| repo.disk_path.clone())
+            .context("repository was not found")?;
+
+        let range = match since {
+            Some(rev) => format!("{}..HEAD", rev.trim()),
+            None => "HEAD".to_owned(),
+        };
+
+        let log = relocation::git(
+            &disk_path,
+            &[
+                "log",
+                &format!("--max-count={MAX_COMMITS}"),
+                "--format=commit %H%n%B",
+                &range,
+            ],
+        )
+        .await?;
+
+        let commits = parse_log(&log);
+        debug!(range, count = commits.len(), "building changelog");
+
+        let response = if commits.is_empty() {
+            format!("No commits were found in {range}.")
+        } else {
+            render(&commits)
+        };
+
+        let breaking_changes = commits
+            .iter()
+            .filter_map(|c| c.breaking.clone())
+            .collect::<Vec<_>>();
+
+        self.update(Update::ReplaceStep(SearchStep::Changelog {
+            since: since.cloned(),
+            breaking_changes: breaking_changes.clone(),
+            response: response.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("changelog")
+                .with_payload("range", &range)
+                .with_payload("commits", commits.len())
+                .with_payload("breaking_changes", &breaking_changes)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// A commit, parsed according to the conventional commits specification.
+///
+/// Commits that don't follow the specification have no `kind`, and their full subject line as
+/// their description.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Commit {
+    hash: String,
+    kind: Option<String>,
+    scope: Option<String>,
+    description: String,
+    breaking: Option<BreakingChange>,
+}
+
+impl Commit {
+    fn parse(hash: &str, message: &str) -> Self {
+        let mut lines = message.trim().lines();
+        let subject = lines.next().unwrap_or_default().trim();
+        let body = lines.collect::<Vec<_>>();
+
+        let header = lazy_regex::regex!(r"^(\w+)(?:\(([^)]+)\))?(!)?: (.+)$").captures(subject);
+        let (kind, scope, bang, description) = match header {
+            Some(c) => (
+                Some(c[1].to_lowercase()),
+                c.get(2).map(|m| m.as_str().to_owned()),
+                c.get(3).is_some(),
+                c[4].trim().to_owned(),
+            ),
+            None => (None, None, false, subject.to_owned()),
+        };
+
+        let footers = parse_footers(&body);
+        let footer = |tokens: &[&str]| {
+            footers
+                .iter()
+                .find(|(token, _)| tokens.contains(&token.as_str()))
+                .map(|(_, value)| value.as_str())
+        };
+
+        let breaking = match footer(BREAKING_TOKENS) {
+            Some(value) => {
+                let (short_desc, rest) = value.split_once('\n').unwrap_or((value, ""));
+                let rest = rest.split_whitespace().collect::<Vec<_>>().join(" ");
+
+                Some(BreakingChange {
+                    short_desc: short_desc.trim().to_owned(),
+                    migration_hint: footer(&["Migration"])
+                        .map(|hint| hint.split_whitespace().collect::<Vec<_>>().join(" "))
+                        .or_else(|| (!rest.is_empty()).then_some(rest)),
+                })
+            }
+            None if bang => Some(BreakingChange {
+                short_desc: description.clone(),
+                migration_hint: footer(&["Migration"]).map(str::to_owned),
+            }),
+            None => None,
+        };
+
+        Self {
+            hash: hash.to_owned(),
+            kind,
+            scope,
+            description,
+            breaking,
+        }
+    }
+
+    fn short_hash(&self) -> &str {
+        &self.hash[..self.hash.len().min(7)]
+    }
+
+    fn to_markdown(&self) -> String {
+        match &self.scope {
+            Some(scope) => format!(
+                "- **{scope}:** {} ({})",
+                self.description,
+                self.short_hash()
+            ),
+            None => format!("- {} ({})", self.description, self.short_hash()),
+        }
+    }
+}
+
+/// Parse the trailing footers of a commit body into `(token, value)` pairs.
+///
+/// Footer values can span several lines; continuation lines are kept, separated by newlines.
+fn parse_footers(body: &[&str]) -> Vec<(String, String)> {
+    let footer_start = lazy_regex::regex!(r"^(BREAKING[ -]CHANGE|[\w-]+): (.*)$");
+
+    // Footers make up the last paragraph of the body.
+    let start = body
+        .iter()
+        .rposition(|line| line.trim().is_empty())
+        .map_or(0, |i| i + 1);
+
+    let mut footers: Vec<(String, String)> = Vec::new();
+    for line in &body[start..] {
+        match footer_start.captures(line) {
+            Some(c) => footers.push((c[1].to_owned(), c[2].trim().to_owned())),
+            None => {
+                if let Some((_, value)) = footers.last_mut() {
+                    value.push('\n');
+                    value.push_str(line.trim());
+                }
+            }
+        }
+    }
+
+    footers
+}
+
+/// Split the output of `git log --format='commit %H%n%B'` into commits.
+fn parse_log(log: &str) -> Vec<Commit> {
+    let header = lazy_regex::regex!(r"(?m)^commit ([0-9a-f]{40})$");
+    let starts = header.captures_iter(log).collect::<Vec<_>>();
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let whole = c.get(0).unwrap();
+            let end = starts
+                .get(i + 1)
+                .map_or(log.len(), |next| next.get(0).unwrap().start());
+
+            Commit::parse(&c[1], &log[whole.end()..end])
+        })
+        .collect()
+}
+
+/// Render commits as a changelog, with breaking changes listed in their own section first.
+fn render(commits: &[Commit]) -> String {
+    let mut out = String::new();
+
+    let breaking = commits
+        .iter()
+        .filter_map(|c| Some((c, c.breaking.as_ref()?)))
+        .collect::<Vec<_>>();
+
+    if !breaking.is_empty() {
+        out += "## Breaking Changes\n\n";
+        for (commit, change) in breaking {
+            out += &format!("- {} ({})\n", change.short_desc, commit.short_hash());
+            if let Some(hint) = &change.migration_hint {
+                out += &format!("  Migration: {hint}\n");
+            }
+        }
+        out += "\n";
+    }
+
+    let sections: &[(&str, fn(Option<&str>) -> bool)] = &[
+        ("Features", |kind| kind == Some("feat")),
+        ("Fixes", |kind| kind == Some("fix")),
+        ("Other", |kind| !matches!(kind, Some("feat" | "fix"))),
+    ];
+
+    for (title, includes) in sections {
+        let entries = commits
+            .iter()
+            .filter(|c| includes(c.kind.as_deref()))
+            .map(Commit::to_markdown)
+            .collect::<Vec<_>>();
+
+        if !entries.is_empty() {
+            out += &format!("## {title}\n\n{}\n\n", entries.join("\n"));
+        }
+    }
+
+    out.trim_end().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_commit() {
+        let commit = Commit::parse(
+            "a".repeat(40).as_str(),
+            "fix(parser): handle empty queries\n",
+        );
+        assert_eq!(commit.kind.as_deref(), Some("fix"));
+        assert_eq!(commit.scope.as_deref(), Some("parser"));
+        assert_eq!(commit.description, "handle empty queries");
+        assert_eq!(commit.breaking, None);
+
+        let commit = Commit::parse("b", "feat!: drop the v1 API");
+        assert_eq!(
+            commit.breaking,
+            Some(BreakingChange {
+                short_desc: "drop the v1 API".to_owned(),
+                migration_hint: None,
+            })
+        );
+
+        let commit = Commit::parse(
+            "c",
+            "refactor: rename config fields\n\nSome context.\n\nBREAKING CHANGE: `index_path` \
+             is now `index_dir`.\nUpdate config files accordingly.\nReviewed-by: someone",
+        );
+        assert_eq!(
+            commit.breaking,
+            Some(BreakingChange {
+                short_desc: "`index_path` is now `index_dir`.".to_owned(),
+                migration_hint: Some("Update config files accordingly.".to_owned()),
+            })
+        );
+
+        let commit = Commit::parse("d", "Merge branch 'main'");
+        assert_eq!(commit.kind, None);
+        assert_eq!(commit.description, "Merge branch 'main'");
+    }
+
+    #[test]
+    fn test_breaking_changes_section() {
+        let commits = parse_log(include_str!("../fixtures/changelog.log"));
+        assert_eq!(commits.len(), 5);
+
+        let changelog = render(&commits);
+        let sections = changelog
+            .split("## ")
+            .filter(|s| !s.is_empty())
+            .map(|s| s.split_once('\n').unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            sections.iter().map(|(title, _)| *title).collect::<Vec<_>>(),
+            ["Breaking Changes", "Features", "Fixes", "Other"]
+        );
+
+        let (_, breaking) = sections[0];
+        assert_eq!(
+            breaking.trim(),
+            "- `SemanticQuery::from_str` now returns a `Result` (3f2c1d9)\n  \
+             Migration: Handle the parse error, or call `SemanticQuery::parse_or_default`."
+        );
+
+        // The breaking commit is also listed by its type.
+        let (_, features) = sections[1];
+        assert!(features.contains("- **query:** parse queries eagerly (3f2c1d9)"));
+        assert!(!features.contains("Migration"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Comparisons of two sections of a repository's changelog, such as the notes of two releases.
+
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use tracing::debug;
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Update},
+        prompts, Agent,
+    },
+    analytics::EventData,
+    llm_gateway::api::Message,
+};
+
+/// The model that explains the differences between two sections.
+const DIFF_MODEL: &str = "gpt-4-0613";
+
+/// The changelog that is read, at the root of the repository.
+const CHANGELOG_PATH: &str = "CHANGELOG.md";
+
+impl Agent {
+    pub async fn changelog_diff(&mut self, v1: &str, v2: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::ChangelogDiff {
+            v1: v1.to_owned(),
+            v2: v2.to_owned(),
+            diff: None,
+            response: String::new(),
+        }))
+        .await?;
+
+        let disk_path = self
+            .app
+            .repo_pool
+            .read(&self.repo_ref, |_, repo| repo.disk_path.clone())
+            .context("repository was not found")?;
+
+        let changelog = tokio::fs::read_to_string(disk_path.join(CHANGELOG_PATH))
+            .await
+            .ok();
+
+        let (diff, response) = match changelog.as_deref().map(|c| compare(c, v1, v2)) {
+            None => (
+                None,
+                format!("No {CHANGELOG_PATH} was found at the root of the repository."),
+            ),
+            Some(Comparison::Missing { version, versions }) if versions.is_empty() => (
+                None,
+                format!("No section for version {version} was found in {CHANGELOG_PATH}."),
+            ),
+            Some(Comparison::Missing { version, versions }) => (
+                None,
+                format!(
+                    "No section for version {version} was found in {CHANGELOG_PATH}, which has \
+                     sections for {}.",
+                    versions.join(", ")
+                ),
+            ),
+            Some(Comparison::Identical) => (
+                None,
+                format!("The sections for {v1} and {v2} in {CHANGELOG_PATH} are identical."),
+            ),
+            Some(Comparison::Changed(diff)) => {
+                debug!(
+                    v1,
+                    v2,
+                    lines = diff.lines().count(),
+                    "explaining changelog diff"
+                );
+                let response = self.explain_changelog_diff(v1, v2, &diff).await?;
+                (Some(diff), response)
+            }
+        };
+
+        self.update(Update::ReplaceStep(SearchStep::ChangelogDiff {
+            v1: v1.to_owned(),
+            v2: v2.to_owned(),
+            diff: diff.clone(),
+            response: response.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("changelog diff")
+                .with_payload("v1", v1)
+                .with_payload("v2", v2)
+                .with_payload("diff", &diff)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+
+    async fn explain_changelog_diff(&self, v1: &str, v2: &str, diff: &str) -> Result<String> {
+        let messages = [Message::system(&prompts::changelog_diff(v1, v2, diff))];
+
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model(DIFF_MODEL)
+            .chat(&messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "changelog_diff",
+            DIFF_MODEL,
+            &messages,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
+        Ok(response)
+    }
+}
+
+/// How the sections of two versions in a changelog compare.
+#[derive(Debug, PartialEq, Eq)]
+enum Comparison {
+    /// There is no section for `version`. The changelog has sections for `versions`, in order.
+    Missing {
+        version: String,
+        versions: Vec<String>,
+    },
+    Identical,
+    /// A unified diff from the first section to the second.
+    Changed(String),
+}
+
+/// Compare the sections of `v1` and `v2` in `changelog`.
+fn compare(changelog: &str, v1: &str, v2: &str) -> Comparison {
+    let sections = sections(changelog);
+    let find = |version: &str| {
+        let version = normalize(version);
+        sections
+            .iter()
+            .find(|s| s.version == version)
+            .map(|s| s.body.as_str())
+    };
+
+    let (old, new) = match (find(v1), find(v2)) {
+        (Some(old), Some(new)) => (old, new),
+        (old, _) => {
+            return Comparison::Missing {
+                version: if old.is_none() { v1 } else { v2 }.to_owned(),
+                versions: sections.into_iter().map(|s| s.version).collect(),
+            }
+        }
+    };
+
+    if old == new {
+        return Comparison::Identical;
+    }
+
+    let mut options = diffy::DiffOptions::new();
+    options.set_original_filename(format!("{CHANGELOG_PATH} ({v1})"));
+    options.set_modified_filename(format!("{CHANGELOG_PATH} ({v2})"));
+    Comparison::Changed(options.create_patch(old, new).to_string())
+}
+
+/// A section of a changelog, from the heading of a version to the next heading of the same or a
+/// higher level.
+#[derive(Debug, PartialEq, Eq)]
+struct Section {
+    version: String,
+    /// The lines under the heading, without it.
+    body: String,
+}
+
+/// Split a markdown changelog into the sections of its versions, in order.
+///
+/// Headings name versions in any of the common styles, such as `## [1.2.0] - 2023-07-01`,
+/// `## v1.2.0` or `# 1.2.0 (2023-07-01)`. Headings that don't name a version, such as
+/// `## Unreleased`, end the section before them but don't start one. Link reference definitions,
+/// which usually link each version to its diff, are left out.
+fn sections(changelog: &str) -> Vec<Section> {
+    let heading = lazy_regex::regex!(r"^#{1,6}\s+\[?v?(\d+(?:\.\d+)*(?:-[\w.]+)?)\]?(?:\s|$)");
+    let link_definition = lazy_regex::regex!(r"^\s{0,3}\[[^\]]+\]:\s");
+
+    let mut sections = Vec::new();
+    let mut current: Option<(usize, Section)> = None;
+
+    for line in changelog.lines() {
+        let level = line.chars().take_while(|c| *c == '#').count();
+        let is_heading = level > 0 && line[level..].starts_with(char::is_whitespace);
+
+        if is_heading && current.as_ref().map_or(true, |(l, _)| level <= *l) {
+            sections.extend(current.take().map(|(_, s)| s));
+
+            if let Some(c) = heading.captures(line) {
+                let section = Section {
+                    version: c[1].to_owned(),
+                    body: String::new(),
+                };
+                current = Some((level, section));
+            }
+
+            continue;
+        }
+
+        if link_definition.is_match(line) {
+            continue;
+        }
+
+        if let Some((_, section)) = &mut current {
+            section.body.push_str(line);
+            section.body.push('\n');
+        }
+    }
+
+    sections.extend(current.map(|(_, s)| s));
+    for section in &mut sections {
+        section.body = section.body.trim().to_owned() + "\n";
+    }
+
+    sections
+}
+
+/// A version as it is named in a heading, without brackets or a `v` prefix.
+fn normalize(version: &str) -> String {
+    let version = version.trim().trim_start_matches('[').trim_end_matches(']');
+    version
+        .strip_prefix(['v', 'V'])
+        .unwrap_or(version)
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const CHANGELOG: &str = include_str!("../fixtures/CHANGELOG.md");
+
+    #[test]
+    fn test_sections() {
+        let sections = sections(CHANGELOG);
+        assert_eq!(
+            sections
+                .iter()
+                .map(|s| s.version.as_str())
+                .collect::<Vec<_>>(),
+            ["0.5.0", "0.4.0", "0.3.1"]
+        );
+
+        // Subsections are part of their version's section, and the heading isn't.
+        let body = &sections[1].body;
+        assert!(body.starts_with("### Added\n"));
+        assert!(body.contains("### Fixed\n"));
+        assert!(!body.contains("0.4.0"));
+        assert!(!body.contains("0.3.1"));
+
+        // Links at the end of the file are not part of the last section.
+        assert!(!sections[2].body.contains("[0.4.0]:"));
+    }
+
+    #[test]
+    fn test_compare() {
+        let Comparison::Changed(diff) = compare(CHANGELOG, "v0.4.0", "[0.5.0]") else {
+            panic!("the sections should differ");
+        };
+
+        assert!(diff.starts_with("--- CHANGELOG.md (v0.4.0)\n+++ CHANGELOG.md ([0.5.0])\n"));
+        assert!(diff.contains("\n+- Queries can be restricted to several repositories.\n"));
+        assert!(diff.contains("\n-- Regex queries in the search bar.\n"));
+        assert!(diff.contains("\n ### Added\n"));
+        assert!(diff.contains("\n-- Symbols of Go files are found again.\n"));
+
+        assert_eq!(compare(CHANGELOG, "0.5.0", "0.5.0"), Comparison::Identical);
+        assert_eq!(
+            compare(CHANGELOG, "0.4.0", "0.6.0"),
+            Comparison::Missing {
+                version: "0.6.0".into(),
+                versions: vec!["0.5.0".into(), "0.4.0".into(), "0.3.1".into()],
+            }
+        );
+    }
+}
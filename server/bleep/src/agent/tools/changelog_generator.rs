@@ -0,0 +1,413 @@
+//! Weekly digests of the commit history, written for status updates.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    path::Path,
+    time::Instant,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use tracing::debug;
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Update},
+        prompts, relocation, Agent,
+    },
+    analytics::EventData,
+    llm_gateway::api::Message,
+};
+
+/// The model that writes the digest.
+const DIGEST_MODEL: &str = "gpt-4-0613";
+
+/// The number of days that a digest covers.
+const DIGEST_DAYS: i64 = 7;
+
+/// The maximum number of commits that a digest covers.
+const MAX_COMMITS: usize = 500;
+
+/// The most words that a digest can have.
+const MAX_WORDS: usize = 800;
+
+/// How many times the model is asked to shorten a digest that is too long, before it is cut off.
+const MAX_SHORTEN_ATTEMPTS: usize = 2;
+
+/// The component of files at the root of the repository.
+const ROOT_COMPONENT: &str = "(root)";
+
+/// Separates commits, and the fields of a commit, in the output of `git log`.
+const RECORD_SEPARATOR: char = '\x1e';
+const FIELD_SEPARATOR: char = '\x1f';
+
+impl Agent {
+    pub async fn weekly_digest(&mut self) -> Result<String> {
+        let since = Utc::now() - chrono::Duration::days(DIGEST_DAYS);
+
+        self.update(Update::StartStep(SearchStep::WeeklyDigest {
+            since,
+            commits: 0,
+            response: String::new(),
+        }))
+        .await?;
+
+        let disk_path = self
+            .app
+            .repo_pool
+            .read(&self.repo_ref, |_, repo| repo.disk_path.clone())
+            .context("repository was not found")?;
+
+        let commits = recent_commits(&disk_path, since).await?;
+        debug!(%since, count = commits.len(), "writing weekly digest");
+
+        let response = if commits.is_empty() {
+            format!("No commits were made in the last {DIGEST_DAYS} days.")
+        } else {
+            let system = prompts::weekly_digest(&group(&commits), MAX_WORDS);
+            let digest = self.complete_digest(&[Message::system(&system)]).await?;
+
+            let this = &*self;
+            fit_to_words(digest, |digest| {
+                let messages = [
+                    Message::system(&system),
+                    Message::assistant(&digest),
+                    Message::user(prompts::SHORTEN_DIGEST),
+                ];
+                async move { this.complete_digest(&messages).await }
+            })
+            .await?
+        };
+
+        self.update(Update::ReplaceStep(SearchStep::WeeklyDigest {
+            since,
+            commits: commits.len(),
+            response: response.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("weekly digest")
+                .with_payload("since", since.to_rfc3339())
+                .with_payload("commits", commits.len())
+                .with_payload("words", word_count(&response))
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+
+    async fn complete_digest(&self, messages: &[Message]) -> Result<String> {
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model(DIGEST_MODEL)
+            .chat(messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "weekly_digest",
+            DIGEST_MODEL,
+            messages,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
+        Ok(response)
+    }
+}
+
+/// A commit of the digest, with the top-level directories that it changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DigestCommit {
+    hash: String,
+    author: String,
+    subject: String,
+    components: BTreeSet<String>,
+}
+
+impl DigestCommit {
+    fn short_hash(&self) -> &str {
+        &self.hash[..self.hash.len().min(7)]
+    }
+}
+
+/// The commits made to the checked out branch since `since`, newest first, without merges.
+async fn recent_commits(disk_path: &Path, since: DateTime<Utc>) -> Result<Vec<DigestCommit>> {
+    let log = relocation::git(
+        disk_path,
+        &[
+            "log",
+            "--no-merges",
+            &format!("--max-count={MAX_COMMITS}"),
+            &format!("--since={}", since.format("%Y-%m-%d %H:%M:%S +0000")),
+            "--format=%x1e%H%x1f%an%x1f%s",
+            "--name-only",
+            "HEAD",
+        ],
+    )
+    .await?;
+
+    Ok(parse_log(&log))
+}
+
+/// Parse the output of `git log --format='%x1e%H%x1f%an%x1f%s' --name-only`.
+fn parse_log(log: &str) -> Vec<DigestCommit> {
+    log.split(RECORD_SEPARATOR)
+        .filter_map(|record| {
+            let mut lines = record.lines();
+            let mut fields = lines.next()?.splitn(3, FIELD_SEPARATOR);
+            let (hash, author, subject) = (fields.next()?, fields.next()?, fields.next()?);
+
+            let mut components = lines
+                .filter(|path| !path.trim().is_empty())
+                .map(|path| match path.split_once('/') {
+                    Some((dir, _)) => dir.to_owned(),
+                    None => ROOT_COMPONENT.to_owned(),
+                })
+                .collect::<BTreeSet<_>>();
+
+            if components.is_empty() {
+                components.insert(ROOT_COMPONENT.to_owned());
+            }
+
+            Some(DigestCommit {
+                hash: hash.to_owned(),
+                author: author.to_owned(),
+                subject: subject.trim().to_owned(),
+                components,
+            })
+        })
+        .collect()
+}
+
+/// Render commits grouped by author, then by component, for the model to summarise.
+///
+/// A commit that changes several components is listed under each of them.
+fn group(commits: &[DigestCommit]) -> String {
+    let mut by_author = BTreeMap::<&str, BTreeMap<&str, Vec<&DigestCommit>>>::new();
+    for commit in commits {
+        let components = by_author.entry(&commit.author).or_default();
+        for component in &commit.components {
+            components.entry(component).or_default().push(commit);
+        }
+    }
+
+    let mut out = String::new();
+    for (author, components) in by_author {
+        let count = commits.iter().filter(|c| c.author == author).count();
+        let plural = if count == 1 { "" } else { "s" };
+        out += &format!("## {author} ({count} commit{plural})\n\n");
+
+        for (component, commits) in components {
+            out += &format!("### {component}\n\n");
+            for commit in commits {
+                out += &format!("- {} ({})\n", commit.subject, commit.short_hash());
+            }
+            out += "\n";
+        }
+    }
+
+    out.trim_end().to_owned()
+}
+
+fn word_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+/// Cut `text` off after `max` words, keeping its formatting up to there.
+fn truncate_words(text: &str, max: usize) -> String {
+    let mut words = 0;
+    let mut in_word = false;
+
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            in_word = false;
+        } else if !in_word {
+            in_word = true;
+            words += 1;
+            if words > max {
+                return text[..i].trim_end().to_owned();
+            }
+        }
+    }
+
+    text.to_owned()
+}
+
+/// Make sure that `digest` has at most `MAX_WORDS` words, asking for shorter versions with
+/// `shorten` until it does, and cutting off the last one if it never does.
+async fn fit_to_words<F, Fut>(mut digest: String, mut shorten: F) -> Result<String>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    for _ in 0..MAX_SHORTEN_ATTEMPTS {
+        if word_count(&digest) <= MAX_WORDS {
+            return Ok(digest);
+        }
+
+        debug!(
+            words = word_count(&digest),
+            "digest is too long, shortening"
+        );
+        digest = shorten(digest).await?;
+    }
+
+    Ok(truncate_words(&digest, MAX_WORDS))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use pretty_assertions::assert_eq;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Commit every file in `dir` as `author`, with both dates `days_ago` days before now.
+    fn commit(dir: &Path, author: &str, message: &str, days_ago: i64) {
+        let date = (Utc::now() - chrono::Duration::days(days_ago)).to_rfc2822();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .arg("-C")
+                .arg(dir)
+                .arg("-c")
+                .arg(format!("user.name={author}"))
+                .args(["-c", "user.email=bloop@bloop.ai"])
+                .args(args)
+                .env("GIT_AUTHOR_DATE", &date)
+                .env("GIT_COMMITTER_DATE", &date)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", message]);
+    }
+
+    fn write(dir: &Path, path: &str, content: &str) {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recent_commits() {
+        let tmp = TempDir::new("weekly-digest").unwrap();
+        let dir = tmp.path();
+        assert!(Command::new("git")
+            .args(["init", "-q"])
+            .arg(dir)
+            .status()
+            .unwrap()
+            .success());
+
+        write(dir, "server/src/lib.rs", "pub fn old() {}\n");
+        commit(dir, "Carol", "Initial commit", 30);
+
+        write(dir, "server/src/lib.rs", "pub fn digest() {}\n");
+        write(dir, "client/src/digest.ts", "export {};\n");
+        commit(dir, "Alice", "Add weekly digests", 3);
+
+        write(dir, "README.md", "# Digests\n");
+        commit(dir, "Bob", "Document digests", 2);
+
+        write(
+            dir,
+            "server/src/lib.rs",
+            "pub fn digest() -> String { todo!() }\n",
+        );
+        commit(dir, "Alice", "Return the digest", 1);
+
+        let since = Utc::now() - chrono::Duration::days(DIGEST_DAYS);
+        let commits = recent_commits(dir, since).await.unwrap();
+
+        assert_eq!(
+            commits
+                .iter()
+                .map(|c| (c.author.as_str(), c.subject.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("Alice", "Return the digest"),
+                ("Bob", "Document digests"),
+                ("Alice", "Add weekly digests"),
+            ]
+        );
+        assert_eq!(
+            commits[2].components,
+            BTreeSet::from(["client".to_owned(), "server".to_owned()])
+        );
+        assert_eq!(
+            commits[1].components,
+            BTreeSet::from([ROOT_COMPONENT.to_owned()])
+        );
+
+        let grouped = group(&commits);
+        let headings = grouped
+            .lines()
+            .filter(|l| l.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headings,
+            [
+                "## Alice (2 commits)",
+                "### client",
+                "### server",
+                "## Bob (1 commit)",
+                "### (root)",
+            ]
+        );
+        assert_eq!(grouped.matches("Add weekly digests").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fit_to_words() {
+        let long = "word ".repeat(MAX_WORDS + 100);
+
+        // A digest that fits is kept as it is.
+        let digest = fit_to_words("🚀 Shipped digests".to_owned(), |_| async {
+            Err(anyhow::anyhow!("a short digest was shortened"))
+        })
+        .await
+        .unwrap();
+        assert_eq!(digest, "🚀 Shipped digests");
+
+        // Digests that are too long are shortened until they fit.
+        let mut attempts = 0;
+        let digest = fit_to_words(long.clone(), |digest| {
+            attempts += 1;
+            async move {
+                Ok(digest
+                    .split_whitespace()
+                    .take(MAX_WORDS)
+                    .collect::<Vec<_>>()
+                    .join(" "))
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(word_count(&digest), MAX_WORDS);
+
+        // And cut off, if the model never shortens them enough.
+        let mut attempts = 0;
+        let digest = fit_to_words(long.clone(), |digest| {
+            attempts += 1;
+            async move { Ok(digest) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(attempts, MAX_SHORTEN_ATTEMPTS);
+        assert_eq!(word_count(&digest), MAX_WORDS);
+        assert!(long.starts_with(&digest));
+    }
+}
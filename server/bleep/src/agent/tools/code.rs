@@ -1,14 +1,18 @@
+use std::time::Instant;
+
 use anyhow::Result;
 use futures::TryStreamExt;
 use tracing::info;
 
 use crate::{
     agent::{
-        exchange::{CodeChunk, SearchStep, Update},
+        exchange::{CodeChunk, ContextSource, SearchStep, Update},
         prompts, Agent,
     },
     analytics::EventData,
     llm_gateway,
+    query::parser,
+    semantic,
 };
 
 impl Agent {
@@ -20,19 +24,47 @@ impl Agent {
         }))
         .await?;
 
+        let search_query = self.refine_code_query(query).await?;
+
         let mut results = self
-            .semantic_search(query.into(), CODE_SEARCH_LIMIT, 0, 0.0, true)
+            .code_semantic_search((&search_query).into(), CODE_SEARCH_LIMIT, 0.0)
             .await?;
 
         let hyde_docs = self.hyde(query).await?;
         if !hyde_docs.is_empty() {
             let hyde_doc = hyde_docs.first().unwrap().into();
             let hyde_results = self
-                .semantic_search(hyde_doc, CODE_SEARCH_LIMIT, 0, 0.3, true)
+                .code_semantic_search(hyde_doc, CODE_SEARCH_LIMIT, 0.3)
                 .await?;
             results.extend(hyde_results);
         }
 
+        let (chunks, response) = self.record_code_chunks(results);
+
+        self.update(Update::ReplaceStep(SearchStep::Code {
+            query: query.clone(),
+            response: response.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("semantic code search")
+                .with_payload("query", query)
+                .with_payload("search_query", &search_query)
+                .with_payload("hyde_queries", &hyde_docs)
+                .with_payload("chunks", &chunks)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+
+    /// Add the chunks of semantic search results to the last exchange, returning them with the
+    /// response that is sent to the model.
+    pub(crate) fn record_code_chunks(
+        &mut self,
+        results: Vec<semantic::Payload>,
+    ) -> (Vec<CodeChunk>, String) {
         let chunks = results
             .into_iter()
             .map(|chunk| {
@@ -44,40 +76,111 @@ impl Agent {
                     snippet: chunk.text,
                     start_line: (chunk.start_line as usize).saturating_add(1),
                     end_line: (chunk.end_line as usize).saturating_add(1),
+                    moved_to: None,
+                    deleted: false,
+                    symbol_path: chunk.symbol_path,
+                    blob: None,
+                    revision: None,
+                    mapped: None,
                 }
             })
             .collect::<Vec<_>>();
 
+        let exchange = self.exchanges.last_mut().unwrap();
         for chunk in chunks.iter().filter(|c| !c.is_empty()) {
-            self.exchanges
-                .last_mut()
-                .unwrap()
-                .code_chunks
-                .push(chunk.clone())
+            exchange.include_context(
+                &chunk.path,
+                ContextSource::CodeSearch,
+                &[chunk.start_line..chunk.end_line + 1],
+                true,
+            );
+            exchange.code_chunks.push(chunk.clone())
+        }
+
+        // Warn the model about files it already found irrelevant, so that it does not read them
+        // again.
+        let mut examined = Vec::new();
+        for chunk in &chunks {
+            if let Some(reason) = exchange.irrelevant_reason(&chunk.path) {
+                let note = format!("{}: {} ({reason})", chunk.alias, chunk.path);
+                if !examined.contains(&note) {
+                    examined.push(note);
+                }
+            }
         }
 
         let response = chunks
             .iter()
             .filter(|c| !c.is_empty())
             .map(|c| c.to_string())
+            .chain((!examined.is_empty()).then(|| {
+                format!(
+                    "These files were already examined and are not relevant:\n{}",
+                    examined.join("\n")
+                )
+            }))
             .collect::<Vec<_>>()
             .join("\n\n");
 
-        self.update(Update::ReplaceStep(SearchStep::Code {
-            query: query.clone(),
-            response: response.clone(),
-        }))
-        .await?;
+        (chunks, response)
+    }
 
-        self.track_query(
-            EventData::input_stage("semantic code search")
-                .with_payload("query", query)
-                .with_payload("hyde_queries", &hyde_docs)
-                .with_payload("chunks", &chunks)
-                .with_payload("raw_prompt", &response),
-        );
+    /// Search semantically, restricted to the language of `Agent::set_language_hint` if one was
+    /// given.
+    pub(crate) async fn code_semantic_search(
+        &self,
+        query: parser::Literal<'_>,
+        limit: u64,
+        threshold: f32,
+    ) -> Result<Vec<semantic::Payload>> {
+        match &self.config.language_hint {
+            Some(language) => {
+                self.semantic_search_by_language(query, language, limit, 0, threshold, true)
+                    .await
+            }
+            None => self.semantic_search(query, limit, 0, threshold, true).await,
+        }
+    }
 
-        Ok(response)
+    /// Rewrite a query into a keyword-based semantic search query, guided by few-shot examples.
+    ///
+    /// If the model does not return a query, the original query is used.
+    async fn refine_code_query(&self, query: &str) -> Result<String> {
+        let examples = match &self.config.search_examples {
+            Some(examples) => examples.iter().map(String::as_str).collect::<Vec<_>>(),
+            None => prompts::CODE_SEARCH_EXAMPLES.to_vec(),
+        };
+
+        let prompt = vec![llm_gateway::api::Message::system(&prompts::code_search(
+            query, &examples,
+        ))];
+
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model("gpt-3.5-turbo-0613")
+            .chat(&prompt, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "code_query",
+            "gpt-3.5-turbo-0613",
+            &prompt,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
+        let refined = response.lines().next().unwrap_or_default().trim();
+        if refined.is_empty() {
+            Ok(query.to_owned())
+        } else {
+            info!(?query, ?refined, "refined code search query");
+            Ok(refined.to_owned())
+        }
     }
 
     /// Hypothetical Document Embedding (HyDE): https://arxiv.org/abs/2212.10496
@@ -91,6 +194,7 @@ impl Agent {
 
         tracing::trace!(?query, "generating hyde docs");
 
+        let start = Instant::now();
         let response = self
             .llm_gateway
             .clone()
@@ -100,6 +204,15 @@ impl Agent {
             .try_collect::<String>()
             .await?;
 
+        self.track_usage(
+            "hyde",
+            "gpt-3.5-turbo-0613",
+            &prompt,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
         tracing::trace!("parsing hyde response");
 
         let documents = prompts::try_parse_hypothetical_documents(&response);
@@ -0,0 +1,441 @@
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use futures::TryStreamExt;
+use lazy_regex::regex;
+use tracing::{debug, warn};
+
+use crate::{
+    agent::{
+        exchange::{ConfigIssue, IssueSeverity, SearchStep, Update},
+        prompts, Agent,
+    },
+    analytics::EventData,
+    llm_gateway,
+};
+
+/// The model used to review configuration files.
+const AUDIT_MODEL: &str = "gpt-3.5-turbo-16k-0613";
+
+/// The maximum number of tokens of a configuration file that are shown to the model.
+const MAX_TOKENS: usize = 12000;
+
+/// Values that are commonly left in place of a real credential.
+const WEAK_VALUES: &[&str] = &[
+    "admin", "password", "root", "secret", "changeme", "123456", "test", "default",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+    Ini,
+}
+
+impl ConfigFormat {
+    fn detect(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+
+        Some(match extension.as_str() {
+            "yaml" | "yml" => Self::Yaml,
+            "toml" => Self::Toml,
+            "json" => Self::Json,
+            "ini" | "cfg" | "conf" => Self::Ini,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Yaml => "YAML",
+            Self::Toml => "TOML",
+            Self::Json => "JSON",
+            Self::Ini => "INI",
+        }
+    }
+}
+
+impl Agent {
+    pub async fn config_audit(&mut self, path: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::ConfigAudit {
+            path: path.to_owned(),
+            issues: Vec::new(),
+        }))
+        .await?;
+
+        let Some(format) = ConfigFormat::detect(path) else {
+            bail!("{path} is not a YAML, TOML, JSON or INI file");
+        };
+
+        let content = self
+            .get_file_content(path)
+            .await?
+            .with_context(|| format!("path does not exist in the index: {path}"))?
+            .content;
+
+        let tokenizer = self.tokenizer(AUDIT_MODEL)?;
+        let mut remaining = MAX_TOKENS;
+        let config = content
+            .lines()
+            .take_while(|line| {
+                let tokens = tokenizer.count(line) + 1;
+                remaining = remaining.saturating_sub(tokens);
+                remaining > 0
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let messages = [llm_gateway::api::Message::system(&prompts::config_audit(
+            path,
+            format.name(),
+            &config,
+        ))];
+
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model(AUDIT_MODEL)
+            .chat(&messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "config_audit",
+            AUDIT_MODEL,
+            &messages,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
+        // The rule-based checks still apply if the model response can't be used.
+        let reviewed = parse_issues(&response).unwrap_or_else(|err| {
+            warn!(?err, %path, "failed to parse config audit response");
+            Vec::new()
+        });
+
+        let issues = merge_issues(rule_issues(format, &content), reviewed);
+        debug!(%path, count = issues.len(), "audited config file");
+
+        let step = SearchStep::ConfigAudit {
+            path: path.to_owned(),
+            issues: issues.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("config audit")
+                .with_payload("path", path)
+                .with_payload("issues", &issues)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Parse the issues returned by the model, which may be wrapped in a Markdown code block.
+fn parse_issues(response: &str) -> Result<Vec<ConfigIssue>> {
+    let json = response
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```");
+
+    Ok(serde_json::from_str(json.trim())?)
+}
+
+/// Combine rule-based and reviewed issues, most severe first.
+///
+/// Reviewed issues for a field that a rule already flagged are left out.
+fn merge_issues(rules: Vec<ConfigIssue>, reviewed: Vec<ConfigIssue>) -> Vec<ConfigIssue> {
+    let mut issues = rules;
+    for issue in reviewed {
+        if !issues.iter().any(|i| i.field == issue.field) {
+            issues.push(issue);
+        }
+    }
+
+    issues.sort_by(|a, b| b.severity.cmp(&a.severity));
+    issues
+}
+
+/// Issues that can be found without the model, such as hardcoded credentials.
+fn rule_issues(format: ConfigFormat, content: &str) -> Vec<ConfigIssue> {
+    let issue = |field: &str, severity, description: String| ConfigIssue {
+        field: field.to_owned(),
+        severity,
+        description,
+    };
+
+    let mut issues = Vec::new();
+
+    for (field, value) in fields(format, content) {
+        let key = field.rsplit('.').next().unwrap_or(&field).to_lowercase();
+        let lower = value.to_lowercase();
+
+        if is_secret_key(&key) && !value.is_empty() && !is_placeholder(&value) {
+            let description = if WEAK_VALUES.contains(&lower.as_str()) {
+                format!("The credential is hardcoded to the easily guessed value {value:?}")
+            } else {
+                "The credential is hardcoded in the file, instead of being read from the \
+                 environment or a secret store"
+                    .to_owned()
+            };
+            issues.push(issue(&field, IssueSeverity::Critical, description));
+        } else if ["ssl", "tls", "verify", "secure"]
+            .iter()
+            .any(|k| key.contains(k))
+            && ["false", "no", "off", "0", "none", "disable", "disabled"].contains(&lower.as_str())
+        {
+            issues.push(issue(
+                &field,
+                IssueSeverity::High,
+                "Transport security or certificate verification is disabled".to_owned(),
+            ));
+        } else if key.contains("debug") && ["true", "yes", "on", "1"].contains(&lower.as_str()) {
+            issues.push(issue(
+                &field,
+                IssueSeverity::Medium,
+                "Debug mode is enabled, which can leak internal details".to_owned(),
+            ));
+        } else if (key.contains("origin") || key.contains("cors")) && value == "*" {
+            issues.push(issue(
+                &field,
+                IssueSeverity::Medium,
+                "Requests are allowed from any origin".to_owned(),
+            ));
+        } else if value == "0.0.0.0" {
+            issues.push(issue(
+                &field,
+                IssueSeverity::Low,
+                "The service listens on all network interfaces".to_owned(),
+            ));
+        }
+    }
+
+    issues
+}
+
+fn is_secret_key(key: &str) -> bool {
+    [
+        "password",
+        "passwd",
+        "pwd",
+        "secret",
+        "token",
+        "api_key",
+        "apikey",
+        "private_key",
+        "access_key",
+    ]
+    .iter()
+    .any(|k| key.contains(k))
+}
+
+/// Values that refer to a secret stored elsewhere, or that are left to be filled in.
+fn is_placeholder(value: &str) -> bool {
+    ["null", "~", "none", "\"\"", "''"].contains(&value.to_lowercase().as_str())
+        || ["${", "$(", "{{", "%(", "<", "env:", "vault:"]
+            .iter()
+            .any(|p| value.starts_with(p))
+        || regex!(r"^\$[A-Z_][A-Z0-9_]*$").is_match(value)
+}
+
+/// The `(field, value)` pairs of a configuration file.
+///
+/// This reads the file line by line, which is enough to find scalar settings in the common layout
+/// of each format. Fields include their section or parent keys where the format makes that cheap
+/// to track.
+fn fields(format: ConfigFormat, content: &str) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+
+    // The indentation and key of each YAML mapping that encloses the current line.
+    let mut parents = Vec::<(usize, String)>::new();
+    let mut section = String::new();
+
+    for line in content.lines() {
+        match format {
+            ConfigFormat::Yaml => {
+                let Some(c) =
+                    regex!(r#"^(\s*)(?:-\s+)?["']?([\w.-]+)["']?\s*:(?:\s+(.*))?$"#).captures(line)
+                else {
+                    continue;
+                };
+
+                let indent = c[1].len();
+                while matches!(parents.last(), Some((i, _)) if *i >= indent) {
+                    parents.pop();
+                }
+
+                let key = c[2].to_owned();
+                let field = parents
+                    .iter()
+                    .map(|(_, k)| k.as_str())
+                    .chain([key.as_str()])
+                    .collect::<Vec<_>>()
+                    .join(".");
+
+                match c.get(3).map(|m| clean_value(m.as_str(), "#")) {
+                    Some(value) if !value.is_empty() => fields.push((field, value)),
+                    _ => parents.push((indent, key)),
+                }
+            }
+            ConfigFormat::Toml | ConfigFormat::Ini => {
+                if let Some(c) = regex!(r"^\s*\[+\s*([^\]]+?)\s*\]+\s*$").captures(line) {
+                    section = c[1].to_owned();
+                    continue;
+                }
+
+                let Some(c) = regex!(r#"^\s*["']?([\w.-]+)["']?\s*[=:]\s*(.*)$"#).captures(line)
+                else {
+                    continue;
+                };
+
+                let comment = if format == ConfigFormat::Ini {
+                    ";"
+                } else {
+                    "#"
+                };
+                let field = match section.as_str() {
+                    "" => c[1].to_owned(),
+                    section => format!("{section}.{}", &c[1]),
+                };
+                fields.push((field, clean_value(&c[2], comment)));
+            }
+            ConfigFormat::Json => {
+                fields.extend(
+                    regex!(r#""([^"]+)"\s*:\s*("(?:[^"\\]|\\.)*"|[^\s,{}\[\]]+)"#)
+                        .captures_iter(line)
+                        .map(|c| (c[1].to_owned(), clean_value(&c[2], ""))),
+                );
+            }
+        }
+    }
+
+    fields
+}
+
+/// Strip a trailing comment and surrounding quotes from a value.
+fn clean_value(value: &str, comment: &str) -> String {
+    let value = value.trim();
+
+    let value = if value.starts_with('"') || value.starts_with('\'') {
+        // Comment markers may appear inside quoted values.
+        let quote = &value[..1];
+        match value[1..].find(quote) {
+            Some(end) => &value[1..end + 1],
+            None => value,
+        }
+    } else if comment.is_empty() {
+        value
+    } else {
+        value
+            .split_once(&format!(" {comment}"))
+            .map_or(value, |(value, _)| value)
+    };
+
+    value.trim().trim_end_matches(',').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_insecure_yaml() {
+        let config = r#"
+server:
+  host: 0.0.0.0
+  port: 8080
+database:
+  user: admin
+  password: "admin" # TODO: rotate
+  api_token: ${API_TOKEN}
+logging:
+  debug: true
+"#;
+
+        let issues = rule_issues(ConfigFormat::detect("deploy/values.yaml").unwrap(), config);
+        let flagged = issues
+            .iter()
+            .map(|i| (i.field.as_str(), i.severity))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            flagged,
+            [
+                ("server.host", IssueSeverity::Low),
+                ("database.password", IssueSeverity::Critical),
+                ("logging.debug", IssueSeverity::Medium),
+            ]
+        );
+        assert!(issues[1].description.contains("\"admin\""));
+    }
+
+    #[test]
+    fn test_fields() {
+        let toml = "name = \"bleep\"\n[database]\npassword = \"hunter2\" # inline\n";
+        assert_eq!(
+            fields(ConfigFormat::Toml, toml),
+            [
+                ("name".to_owned(), "bleep".to_owned()),
+                ("database.password".to_owned(), "hunter2".to_owned()),
+            ]
+        );
+
+        let ini = "[ssl]\nverify = off ; for local testing\n";
+        assert_eq!(
+            fields(ConfigFormat::Ini, ini),
+            [("ssl.verify".to_owned(), "off".to_owned())]
+        );
+
+        let json = r#"{"auth": {"secret": "s3cr3t", "ttl": 3600}, "cors_origin": "*"}"#;
+        assert_eq!(
+            fields(ConfigFormat::Json, json),
+            [
+                ("secret".to_owned(), "s3cr3t".to_owned()),
+                ("ttl".to_owned(), "3600".to_owned()),
+                ("cors_origin".to_owned(), "*".to_owned()),
+            ]
+        );
+
+        assert_eq!(ConfigFormat::detect("README.md"), None);
+        assert_eq!(ConfigFormat::detect("app.CONF"), Some(ConfigFormat::Ini));
+    }
+
+    #[test]
+    fn test_merge_response() {
+        let response = r#"```json
+[
+  {"field": "database.password", "severity": "high", "description": "Weak password"},
+  {"field": "database.pool_size", "severity": "low", "description": "Pool is too small"},
+  {"field": "server.tls", "severity": "critical", "description": "TLS is missing"}
+]
+```"#;
+
+        let rules = rule_issues(ConfigFormat::Yaml, "database:\n  password: admin\n");
+        let issues = merge_issues(rules, parse_issues(response).unwrap());
+
+        assert_eq!(
+            issues
+                .iter()
+                .map(|i| (i.field.as_str(), i.severity))
+                .collect::<Vec<_>>(),
+            [
+                ("database.password", IssueSeverity::Critical),
+                ("server.tls", IssueSeverity::Critical),
+                ("database.pool_size", IssueSeverity::Low),
+            ]
+        );
+
+        assert!(parse_issues("I could not find any issues.").is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::{collections::HashMap, ops::Range};
+
+use anyhow::Result;
+use lazy_regex::regex;
+
+use crate::{
+    agent::{
+        exchange::{DeadSymbol, SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+    indexes::reader::ContentDocument,
+};
+
+/// The maximum number of unused symbols returned.
+const MAX_DEAD_SYMBOLS: usize = 50;
+
+/// Symbol kinds, across languages, that define a function or a type.
+pub(super) const DEFINITION_KINDS: &[&str] = &[
+    "function",
+    "func",
+    "method",
+    "generator",
+    "struct",
+    "class",
+    "enum",
+    "union",
+    "typedef",
+    "type",
+    "interface",
+    "record",
+    "trait",
+];
+
+impl Agent {
+    pub async fn dead_code(&mut self) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::DeadCode {
+            dead_symbols: Vec::new(),
+        }))
+        .await?;
+
+        let branch = self.branch();
+        let (files, dead_symbols) = self
+            .app
+            .indexes
+            .file
+            .scan_files(&self.repo_ref, branch.as_deref(), |docs| {
+                let mut files = 0;
+                let dead_symbols = dead_symbols(docs.inspect(|_| files += 1));
+                (files, dead_symbols)
+            })
+            .await?;
+
+        let step = SearchStep::DeadCode {
+            dead_symbols: dead_symbols.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("dead code")
+                .with_payload("files", files)
+                .with_payload("results", &dead_symbols)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Functions and types that are defined in `docs`, but never referenced by name.
+///
+/// References are matched by name alone, across all files, so a symbol is only reported if no
+/// identifier anywhere shares its name. Symbols in tests, in trait implementations, and those
+/// marked `#[allow(dead_code)]` are left out.
+///
+/// Only the names and locations of symbols are kept, so `docs` can be read one at a time.
+fn dead_symbols(docs: impl IntoIterator<Item = ContentDocument>) -> Vec<DeadSymbol> {
+    let mut occurrences = HashMap::<String, usize>::new();
+    let mut definitions = HashMap::<String, usize>::new();
+    let mut candidates = Vec::new();
+
+    for doc in docs {
+        match doc.hoverable_ranges() {
+            Some(ranges) => {
+                for range in ranges {
+                    if let Some(name) = doc.content.get(range.start.byte..range.end.byte) {
+                        count(&mut occurrences, name);
+                    }
+                }
+            }
+            // Languages without a tree-sitter grammar can still reference symbols of others.
+            None => {
+                for name in regex!(r"[A-Za-z_$][\w$]*").find_iter(&doc.content) {
+                    count(&mut occurrences, name.as_str());
+                }
+            }
+        }
+
+        let excluded = excluded_ranges(&doc.content);
+        let skip_file = is_test_path(&doc.relative_path)
+            || regex!(r"#!\[allow\([^)]*\bdead_code\b").is_match(&doc.content);
+
+        for symbol in doc.symbol_locations.list() {
+            if !DEFINITION_KINDS.contains(&symbol.kind.as_str()) {
+                continue;
+            }
+
+            let Some(name) = doc
+                .content
+                .get(symbol.range.start.byte..symbol.range.end.byte)
+            else {
+                continue;
+            };
+
+            count(&mut definitions, name);
+
+            let byte = symbol.range.start.byte;
+            if skip_file || is_entry_point(name) || excluded.iter().any(|r| r.contains(&byte)) {
+                continue;
+            }
+
+            candidates.push(DeadSymbol {
+                path: doc.relative_path.clone(),
+                name: name.to_owned(),
+                line: symbol.range.start.line + 1,
+            });
+        }
+    }
+
+    let mut dead = candidates
+        .into_iter()
+        .filter(|s| occurrences.get(&s.name) <= definitions.get(&s.name))
+        .collect::<Vec<_>>();
+
+    dead.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    dead.truncate(MAX_DEAD_SYMBOLS);
+    dead
+}
+
+/// Count an occurrence of `name`, without allocating for names that were already seen.
+fn count(counts: &mut HashMap<String, usize>, name: &str) {
+    match counts.get_mut(name) {
+        Some(count) => *count += 1,
+        None => {
+            counts.insert(name.to_owned(), 1);
+        }
+    }
+}
+
+fn is_test_path(path: &str) -> bool {
+    regex!(r"(^|/)(tests?|__tests__|spec)/|(^|/)test_[^/]*$|_test\.[^/]*$|\.(test|spec)\.[^/]*$")
+        .is_match(path)
+}
+
+/// Names that are called by a runtime or framework, rather than by the code itself.
+fn is_entry_point(name: &str) -> bool {
+    name == "main"
+        || name == "constructor"
+        || (name.len() > 4 && name.starts_with("__") && name.ends_with("__"))
+}
+
+/// Byte ranges of items whose symbols should never be reported: test modules, items marked
+/// `#[allow(dead_code)]`, and trait implementations, which are called through the trait.
+fn excluded_ranges(content: &str) -> Vec<Range<usize>> {
+    regex!(
+        r"(?m)#\[cfg\(test\)\]|#\[allow\([^)]*\bdead_code\b[^)]*\)\]|^[ \t]*impl\b[^{;]*\bfor\b[^{;]*\{"
+    )
+    .find_iter(content)
+    .map(|m| m.start()..item_end(content, m.start()))
+    .collect()
+}
+
+/// The end of the item that starts at `start`: either its first `;`, or the brace that closes its
+/// first `{`.
+fn item_end(content: &str, start: usize) -> usize {
+    let mut depth = 0usize;
+
+    for (i, c) in content[start..].char_indices() {
+        match c {
+            ';' if depth == 0 => return start + i + 1,
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.saturating_sub(1);
+                if depth == 0 {
+                    return start + i + 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    content.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{intelligence::TreeSitterFile, symbol::SymbolLocations};
+
+    const FIXTURE: &[(&str, &str, &str)] = &[
+        (
+            "src/lib.rs",
+            "Rust",
+            include_str!("../fixtures/dead_code/src/lib.rs"),
+        ),
+        (
+            "src/util.rs",
+            "Rust",
+            include_str!("../fixtures/dead_code/src/util.rs"),
+        ),
+        (
+            "tests/render.rs",
+            "Rust",
+            include_str!("../fixtures/dead_code/tests/render.rs"),
+        ),
+        (
+            "scripts/report.py",
+            "Python",
+            include_str!("../fixtures/dead_code/scripts/report.py"),
+        ),
+    ];
+
+    fn doc(relative_path: &str, lang: &str, content: &str) -> ContentDocument {
+        let symbol_locations = TreeSitterFile::try_build(content.as_bytes(), lang)
+            .and_then(TreeSitterFile::scope_graph)
+            .map(SymbolLocations::TreeSitter)
+            .unwrap();
+
+        ContentDocument {
+            content: content.to_owned(),
+            lang: Some(lang.to_owned()),
+            relative_path: relative_path.to_owned(),
+            repo_name: "fixture".to_owned(),
+            repo_ref: "local//fixture".to_owned(),
+            line_end_indices: content.match_indices('\n').map(|(i, _)| i as u32).collect(),
+            symbol_locations,
+            branches: None,
+            lfs_pointer: false,
+        }
+    }
+
+    fn fixture() -> Vec<ContentDocument> {
+        FIXTURE
+            .iter()
+            .map(|(path, lang, content)| doc(path, lang, content))
+            .collect()
+    }
+
+    #[test]
+    fn test_dead_symbols() {
+        let dead = dead_symbols(fixture())
+            .into_iter()
+            .map(|s| format!("{}:{} {}", s.path, s.line, s.name))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            dead,
+            [
+                "scripts/report.py:9 Legacy",
+                "scripts/report.py:13 stale_export",
+                "src/lib.rs:9 Orphan",
+                "src/lib.rs:33 unused_helper",
+                "src/util.rs:5 whisper",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_references_from_other_files() {
+        let mut docs = fixture();
+        // Shell scripts have no tree-sitter grammar, so their identifiers are found by scanning.
+        docs.push(ContentDocument {
+            content: "fixture whisper --quiet\n".to_owned(),
+            lang: Some("Shell".to_owned()),
+            relative_path: "scripts/quiet.sh".to_owned(),
+            repo_name: "fixture".to_owned(),
+            repo_ref: "local//fixture".to_owned(),
+            line_end_indices: vec![23],
+            symbol_locations: SymbolLocations::Empty,
+            branches: None,
+            lfs_pointer: false,
+        });
+
+        let dead = dead_symbols(docs);
+        assert!(dead.iter().all(|s| s.name != "whisper"));
+        assert!(dead.iter().any(|s| s.name == "Orphan"));
+    }
+
+    #[test]
+    fn test_is_test_path() {
+        assert!(is_test_path("tests/render.rs"));
+        assert!(is_test_path("client/src/__tests__/cart.js"));
+        assert!(is_test_path("server/test_auth.py"));
+        assert!(is_test_path("pkg/auth_test.go"));
+        assert!(is_test_path("src/cart.spec.ts"));
+
+        assert!(!is_test_path("src/testing.rs"));
+        assert!(!is_test_path("src/contest/mod.rs"));
+    }
+}
@@ -0,0 +1,614 @@
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use tracing::{debug, warn};
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Update, VulnerablePackage},
+        Agent,
+    },
+    analytics::EventData,
+};
+
+/// The maximum number of concurrent requests to the OSV API.
+const OSV_PARALLELISM: usize = 8;
+
+/// The most queries that the OSV API accepts in one batch.
+const OSV_BATCH_SIZE: usize = 1000;
+
+/// Lockfiles that we read from the repository root, with their OSV ecosystem and parser.
+const LOCKFILES: &[(&str, &str, fn(&str) -> Vec<(String, String)>)] = &[
+    ("Cargo.lock", "crates.io", parse_cargo_lock),
+    ("package-lock.json", "npm", parse_package_lock),
+    ("requirements.txt", "PyPI", parse_requirements),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Dependency {
+    ecosystem: &'static str,
+    name: String,
+    version: String,
+}
+
+impl Agent {
+    pub async fn dependency_vulns(&mut self) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::DependencyVulns {
+            lockfiles: Vec::new(),
+            packages: Vec::new(),
+            unchecked: Vec::new(),
+        }))
+        .await?;
+
+        let mut lockfiles = Vec::new();
+        let mut dependencies = Vec::new();
+        let branch = self.branch();
+
+        // Lockfiles are parsed here rather than sent to the LLM, so they are read in full, without
+        // the file size limit or the file budget.
+        for (path, ecosystem, parse) in LOCKFILES {
+            let Some(doc) = self
+                .app
+                .indexes
+                .file
+                .by_path(&self.repo_ref, path, branch.as_deref())
+                .await
+                .with_context(|| format!("failed to read path: {path}"))?
+            else {
+                continue;
+            };
+
+            lockfiles.push(path.to_string());
+            dependencies.extend(parse(&doc.content).into_iter().map(|(name, version)| {
+                Dependency {
+                    ecosystem: *ecosystem,
+                    name,
+                    version,
+                }
+            }));
+        }
+
+        dependencies.sort();
+        dependencies.dedup();
+
+        debug!(
+            ?lockfiles,
+            count = dependencies.len(),
+            "checking dependencies"
+        );
+
+        let OsvReport {
+            vulnerable: packages,
+            unchecked,
+        } = query_osv(
+            &reqwest::Client::new(),
+            &self.app.config.osv_api_url,
+            &dependencies,
+        )
+        .await;
+
+        let step = SearchStep::DependencyVulns {
+            lockfiles: lockfiles.clone(),
+            packages: packages.clone(),
+            unchecked: unchecked.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("dependency check")
+                .with_payload("lockfiles", &lockfiles)
+                .with_payload("dependencies", dependencies.len())
+                .with_payload("results", &packages)
+                .with_payload("unchecked", &unchecked)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct OsvBatchResponse {
+    results: Vec<OsvResponse>,
+}
+
+#[derive(serde::Deserialize)]
+struct OsvResponse {
+    #[serde(default)]
+    vulns: Vec<OsvVuln>,
+}
+
+#[derive(serde::Deserialize)]
+struct OsvVuln {
+    id: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+/// The vulnerable dependencies, and the ones that could not be checked.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct OsvReport {
+    vulnerable: Vec<VulnerablePackage>,
+    /// Dependencies that OSV failed to answer for, as `name@version`.
+    unchecked: Vec<String>,
+}
+
+/// Look up known vulnerabilities of each dependency, returning only the vulnerable ones.
+///
+/// Dependencies are looked up in batches. If a batch fails, its dependencies are looked up one
+/// by one, so that a dependency that OSV can't answer for is reported without failing the rest.
+async fn query_osv(
+    client: &reqwest::Client,
+    base_url: &str,
+    dependencies: &[Dependency],
+) -> OsvReport {
+    let base_url = base_url.trim_end_matches('/');
+    let mut report = OsvReport::default();
+
+    for batch in dependencies.chunks(OSV_BATCH_SIZE) {
+        let results: Vec<Result<_>> = match query_batch(client, base_url, batch).await {
+            Ok(results) => results.into_iter().map(Ok).collect(),
+            Err(err) => {
+                warn!(
+                    ?err,
+                    count = batch.len(),
+                    "OSV batch failed, querying packages one by one"
+                );
+                futures::stream::iter(batch)
+                    .map(|dep| query_one(client, base_url, dep))
+                    .buffered(OSV_PARALLELISM)
+                    .collect()
+                    .await
+            }
+        };
+
+        for (dep, result) in batch.iter().zip(results) {
+            let ids = match result {
+                Ok(ids) => ids,
+                Err(err) => {
+                    warn!(
+                        ?err,
+                        name = %dep.name,
+                        version = %dep.version,
+                        "failed to query OSV"
+                    );
+                    report
+                        .unchecked
+                        .push(format!("{}@{}", dep.name, dep.version));
+                    continue;
+                }
+            };
+
+            if ids.is_empty() {
+                continue;
+            }
+
+            let mut cve_ids = futures::stream::iter(ids)
+                .map(|id| cve_ids(client, base_url, id))
+                .buffered(OSV_PARALLELISM)
+                .concat()
+                .await;
+
+            cve_ids.sort();
+            cve_ids.dedup();
+
+            report.vulnerable.push(VulnerablePackage {
+                name: dep.name.clone(),
+                version: dep.version.clone(),
+                cve_ids,
+            });
+        }
+    }
+
+    report
+}
+
+fn osv_query(dep: &Dependency) -> serde_json::Value {
+    serde_json::json!({
+        "version": dep.version,
+        "package": {
+            "name": dep.name,
+            "ecosystem": dep.ecosystem,
+        }
+    })
+}
+
+/// The OSV identifiers of the vulnerabilities of each dependency in `batch`, in order.
+async fn query_batch(
+    client: &reqwest::Client,
+    base_url: &str,
+    batch: &[Dependency],
+) -> Result<Vec<Vec<String>>> {
+    let response = client
+        .post(format!("{base_url}/v1/querybatch"))
+        .json(&serde_json::json!({
+            "queries": batch.iter().map(osv_query).collect::<Vec<_>>(),
+        }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<OsvBatchResponse>()
+        .await?;
+
+    if response.results.len() != batch.len() {
+        bail!(
+            "OSV answered {} queries out of {}",
+            response.results.len(),
+            batch.len()
+        );
+    }
+
+    Ok(response
+        .results
+        .into_iter()
+        .map(|result| result.vulns.into_iter().map(|vuln| vuln.id).collect())
+        .collect())
+}
+
+/// The OSV identifiers of the vulnerabilities of `dep`.
+async fn query_one(
+    client: &reqwest::Client,
+    base_url: &str,
+    dep: &Dependency,
+) -> Result<Vec<String>> {
+    let response = client
+        .post(format!("{base_url}/v1/query"))
+        .json(&osv_query(dep))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<OsvResponse>()
+        .await?;
+
+    Ok(response.vulns.into_iter().map(|vuln| vuln.id).collect())
+}
+
+/// The CVE identifiers of the vulnerability `id`, or `id` itself if it has not been assigned one.
+///
+/// Batch results only have identifiers, so the aliases of each vulnerability are looked up
+/// separately. If that fails, `id` is reported as is.
+async fn cve_ids(client: &reqwest::Client, base_url: &str, id: String) -> Vec<String> {
+    let vuln = async {
+        client
+            .get(format!("{base_url}/v1/vulns/{id}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<OsvVuln>()
+            .await
+    };
+
+    let aliases = match vuln.await {
+        Ok(vuln) => vuln.aliases,
+        Err(err) => {
+            debug!(%err, %id, "failed to get vulnerability aliases");
+            Vec::new()
+        }
+    };
+
+    let cves = std::iter::once(&id)
+        .chain(&aliases)
+        .filter(|id| id.starts_with("CVE-"))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if cves.is_empty() {
+        vec![id]
+    } else {
+        cves
+    }
+}
+
+/// Registry packages pinned in a `Cargo.lock`.
+///
+/// Workspace and git dependencies are skipped, as they are not tracked by OSV.
+fn parse_cargo_lock(lockfile: &str) -> Vec<(String, String)> {
+    let field = |block: &str, key: &str| {
+        block.lines().find_map(|line| {
+            Some(
+                line.strip_prefix(key)?
+                    .trim_start()
+                    .strip_prefix('=')?
+                    .trim()
+                    .strip_prefix('"')?
+                    .strip_suffix('"')?
+                    .to_owned(),
+            )
+        })
+    };
+
+    lockfile
+        .split("[[package]]")
+        .skip(1)
+        .filter(|block| field(*block, "source").map_or(false, |s| s.starts_with("registry+")))
+        .filter_map(|block| Some((field(block, "name")?, field(block, "version")?)))
+        .collect()
+}
+
+/// Packages pinned in a `package-lock.json`, in either the v1 or v2+ format.
+fn parse_package_lock(lockfile: &str) -> Vec<(String, String)> {
+    fn v1_dependencies(deps: &serde_json::Value, out: &mut Vec<(String, String)>) {
+        for (name, dep) in deps.as_object().into_iter().flatten() {
+            if let Some(version) = dep["version"].as_str() {
+                out.push((name.clone(), version.to_owned()));
+            }
+
+            v1_dependencies(&dep["dependencies"], out);
+        }
+    }
+
+    let Ok(lockfile) = serde_json::from_str::<serde_json::Value>(lockfile) else {
+        return Vec::new();
+    };
+
+    let mut out = Vec::new();
+
+    match lockfile["packages"].as_object() {
+        Some(packages) => {
+            for (path, package) in packages {
+                // The empty path is the root package, and links point to local directories.
+                if path.is_empty() || package["link"].as_bool() == Some(true) {
+                    continue;
+                }
+
+                let name = path.rsplit("node_modules/").next().unwrap_or(path);
+                if let Some(version) = package["version"].as_str() {
+                    out.push((name.to_owned(), version.to_owned()));
+                }
+            }
+        }
+        None => v1_dependencies(&lockfile["dependencies"], &mut out),
+    }
+
+    out
+}
+
+/// Packages pinned to an exact version with `==` in a `requirements.txt`.
+fn parse_requirements(requirements: &str) -> Vec<(String, String)> {
+    requirements
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next()?.trim();
+            if line.starts_with('-') {
+                return None;
+            }
+
+            let (name, version) = line.split_once("==")?;
+            let name = name.split('[').next()?.trim();
+            let version = version.split(';').next()?.trim();
+
+            (!name.is_empty() && !version.is_empty())
+                .then(|| (name.to_lowercase(), version.to_owned()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        extract::Path,
+        http::StatusCode,
+        routing::{get, post},
+        Json,
+    };
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_lockfiles() {
+        let cargo_lock = r#"
+version = 3
+
+[[package]]
+name = "bleep"
+version = "0.1.0"
+dependencies = [
+ "smallvec",
+]
+
+[[package]]
+name = "smallvec"
+version = "1.6.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+checksum = "fe0f37c9e8f3c5a4a66ad655a93c74daac4ad00c441533bf5c6e7990bb42604e"
+
+[[package]]
+name = "tree-sitter-cobol"
+version = "0.0.1"
+source = "git+https://github.com/example/tree-sitter-cobol#1c3a2b"
+"#;
+
+        assert_eq!(
+            parse_cargo_lock(cargo_lock),
+            pairs(&[("smallvec", "1.6.0")])
+        );
+
+        let package_lock_v2 = r#"{
+            "lockfileVersion": 2,
+            "packages": {
+                "": { "name": "client", "version": "1.0.0" },
+                "node_modules/lodash": { "version": "4.17.15" },
+                "node_modules/a/node_modules/@babel/core": { "version": "7.0.0" },
+                "node_modules/local": { "resolved": "../local", "link": true }
+            }
+        }"#;
+
+        assert_eq!(
+            parse_package_lock(package_lock_v2),
+            pairs(&[("@babel/core", "7.0.0"), ("lodash", "4.17.15")])
+        );
+
+        let package_lock_v1 = r#"{
+            "lockfileVersion": 1,
+            "dependencies": {
+                "minimist": {
+                    "version": "0.0.8",
+                    "dependencies": { "left-pad": { "version": "1.0.0" } }
+                }
+            }
+        }"#;
+
+        assert_eq!(
+            parse_package_lock(package_lock_v1),
+            pairs(&[("minimist", "0.0.8"), ("left-pad", "1.0.0")])
+        );
+
+        let requirements = "\
+# pinned
+Django==2.2.0
+requests[security] == 2.19.0 ; python_version > '3'
+numpy>=1.0
+-r other.txt
+";
+
+        assert_eq!(
+            parse_requirements(requirements),
+            pairs(&[("django", "2.2.0"), ("requests", "2.19.0")])
+        );
+    }
+
+    fn dependencies(deps: &[(&'static str, &str, &str)]) -> Vec<Dependency> {
+        deps.iter()
+            .map(|(ecosystem, name, version)| Dependency {
+                ecosystem: *ecosystem,
+                name: name.to_string(),
+                version: version.to_string(),
+            })
+            .collect()
+    }
+
+    /// An OSV API that knows two vulnerable packages, and fails for `bad-package`.
+    fn serve_osv() -> String {
+        fn vulns(name: &str) -> serde_json::Value {
+            match name {
+                "smallvec" => serde_json::json!([
+                    { "id": "GHSA-43w2-9j62-hq99" },
+                    { "id": "RUSTSEC-2021-0003" },
+                ]),
+                "lodash" => serde_json::json!([{ "id": "GHSA-p6mc-m468-83gw" }]),
+                _ => serde_json::json!([]),
+            }
+        }
+
+        let osv = axum::Router::new()
+            .route(
+                "/v1/querybatch",
+                post(|Json(batch): Json<serde_json::Value>| async move {
+                    let queries = batch["queries"].as_array().unwrap().clone();
+                    let names = queries
+                        .iter()
+                        .map(|query| query["package"]["name"].as_str().unwrap())
+                        .collect::<Vec<_>>();
+
+                    if names.contains(&"bad-package") {
+                        return Err(StatusCode::BAD_REQUEST);
+                    }
+
+                    let results = names
+                        .into_iter()
+                        .map(|name| serde_json::json!({ "vulns": vulns(name) }))
+                        .collect::<Vec<_>>();
+                    Ok(Json(serde_json::json!({ "results": results })))
+                }),
+            )
+            .route(
+                "/v1/query",
+                post(|Json(query): Json<serde_json::Value>| async move {
+                    match query["package"]["name"].as_str().unwrap() {
+                        "bad-package" => Err(StatusCode::INTERNAL_SERVER_ERROR),
+                        name => Ok(Json(serde_json::json!({ "vulns": vulns(name) }))),
+                    }
+                }),
+            )
+            .route(
+                "/v1/vulns/:id",
+                get(|Path(id): Path<String>| async move {
+                    match id.as_str() {
+                        "GHSA-43w2-9j62-hq99" | "RUSTSEC-2021-0003" => {
+                            Ok(Json(serde_json::json!({
+                                "id": id,
+                                "aliases": ["CVE-2021-25900"],
+                            })))
+                        }
+                        _ => Err(StatusCode::NOT_FOUND),
+                    }
+                }),
+            );
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(osv.into_make_service());
+        let base_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        base_url
+    }
+
+    fn vulnerable() -> Vec<VulnerablePackage> {
+        vec![
+            VulnerablePackage {
+                name: "smallvec".into(),
+                version: "1.6.0".into(),
+                cve_ids: vec!["CVE-2021-25900".into()],
+            },
+            // Vulnerabilities that can't be looked up are reported by their OSV identifier.
+            VulnerablePackage {
+                name: "lodash".into(),
+                version: "4.17.15".into(),
+                cve_ids: vec!["GHSA-p6mc-m468-83gw".into()],
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_query_osv() {
+        let base_url = serve_osv();
+        let dependencies = dependencies(&[
+            ("crates.io", "serde", "1.0.0"),
+            ("crates.io", "smallvec", "1.6.0"),
+            ("npm", "lodash", "4.17.15"),
+        ]);
+
+        assert_eq!(
+            query_osv(&reqwest::Client::new(), &base_url, &dependencies).await,
+            OsvReport {
+                vulnerable: vulnerable(),
+                unchecked: vec![],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_osv_failures() {
+        let base_url = serve_osv();
+        let dependencies = dependencies(&[
+            ("crates.io", "bad-package", "0.1.0"),
+            ("crates.io", "smallvec", "1.6.0"),
+            ("npm", "lodash", "4.17.15"),
+        ]);
+
+        // A package that OSV fails for is reported, without failing the check of the others.
+        assert_eq!(
+            query_osv(&reqwest::Client::new(), &base_url, &dependencies).await,
+            OsvReport {
+                vulnerable: vulnerable(),
+                unchecked: vec!["bad-package@0.1.0".into()],
+            }
+        );
+
+        // If OSV can't be reached at all, every package is unchecked.
+        let report = query_osv(&reqwest::Client::new(), "http://127.0.0.1:1", &dependencies).await;
+        assert_eq!(report.vulnerable, vec![]);
+        assert_eq!(report.unchecked.len(), 3);
+    }
+}
@@ -0,0 +1,262 @@
+//! Searches for calls to APIs that a package has deprecated, from a list bundled with bloop.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    agent::{
+        exchange::{DeprecatedCall, SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+    indexes::reader::ContentDocument,
+};
+
+/// The maximum number of calls returned.
+const MAX_DEPRECATED_CALLS: usize = 100;
+
+const DEPRECATIONS: &str = include_str!("deprecations.toml");
+
+/// The deprecated APIs of a package.
+#[derive(Debug, Clone, Deserialize)]
+struct Package {
+    /// The languages of the files that use the package, lowercase.
+    langs: Vec<String>,
+    apis: Vec<DeprecatedApi>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct DeprecatedApi {
+    api: String,
+    /// How the API is called in code, like `.trim_left` for a method. Defaults to `api`.
+    call: Option<String>,
+    replacement: Option<String>,
+}
+
+impl Agent {
+    pub async fn deprecated_usage(&mut self, package: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::DeprecatedUsage {
+            package: package.to_owned(),
+            apis: 0,
+            calls: Vec::new(),
+        }))
+        .await?;
+
+        let packages = packages()?;
+        let (apis, calls) = match find_package(&packages, package) {
+            Some(found) => {
+                let branch = self.branch();
+                let apis = found.apis.len();
+                let found = found.clone();
+                let calls = self
+                    .app
+                    .indexes
+                    .file
+                    .scan_files(&self.repo_ref, branch.as_deref(), move |docs| {
+                        deprecated_calls(&found, docs)
+                    })
+                    .await?;
+
+                (apis, calls)
+            }
+            None => (0, Vec::new()),
+        };
+
+        let step = SearchStep::DeprecatedUsage {
+            package: package.to_owned(),
+            apis,
+            calls: calls.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("deprecated usage")
+                .with_payload("package", package)
+                .with_payload("apis", apis)
+                .with_payload("results", &calls)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+fn packages() -> Result<HashMap<String, Package>> {
+    toml::from_str(DEPRECATIONS).context("invalid list of deprecated APIs")
+}
+
+/// Look up a package by name, ignoring case.
+fn find_package<'a>(packages: &'a HashMap<String, Package>, name: &str) -> Option<&'a Package> {
+    let name = name.trim().to_lowercase();
+    packages
+        .iter()
+        .find(|(n, _)| n.to_lowercase() == name)
+        .map(|(_, package)| package)
+}
+
+/// Calls in `docs` to the deprecated APIs of `package`, ordered by path and line.
+///
+/// Only files in the package's languages are searched, and lines that start with a comment are
+/// skipped.
+fn deprecated_calls(
+    package: &Package,
+    docs: impl IntoIterator<Item = ContentDocument>,
+) -> Vec<DeprecatedCall> {
+    let patterns = package
+        .apis
+        .iter()
+        .filter_map(|api| Some((api, call_regex(api.call.as_deref().unwrap_or(&api.api))?)))
+        .collect::<Vec<_>>();
+
+    let mut calls = docs
+        .into_iter()
+        .filter(|doc| {
+            doc.lang
+                .as_deref()
+                .map_or(false, |lang| package.langs.contains(&lang.to_lowercase()))
+        })
+        .flat_map(|doc| {
+            let path = &doc.relative_path;
+            doc.content
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !is_comment(line))
+                .flat_map(|(i, line)| {
+                    patterns
+                        .iter()
+                        .filter(move |(_, regex)| regex.is_match(line))
+                        .map(move |(api, _)| DeprecatedCall {
+                            api: api.api.clone(),
+                            path: path.clone(),
+                            line: i + 1,
+                            replacement: api.replacement.clone(),
+                        })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    calls.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    calls.truncate(MAX_DEPRECATED_CALLS);
+    calls
+}
+
+/// A regex that matches a call as it is written in code, followed by its arguments.
+///
+/// Calls that start with a word must start at a word boundary, so that `url.parse` doesn't match
+/// `base_url.parse`. Calls that end with a word must be followed by `(`, so that `.ymd` doesn't
+/// match `.ymd_opt(`.
+fn call_regex(call: &str) -> Option<Regex> {
+    let boundary = if call.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+        r"\b"
+    } else {
+        ""
+    };
+
+    Regex::new(&format!(r"{boundary}{}\s*\(", regex::escape(call))).ok()
+}
+
+fn is_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    ["//", "/*", "*", "#"].iter().any(|c| line.starts_with(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn doc(path: &str, lang: &str, content: &str) -> ContentDocument {
+        ContentDocument {
+            relative_path: path.to_owned(),
+            lang: Some(lang.to_owned()),
+            content: content.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_packages() {
+        let packages = packages().unwrap();
+        assert!(find_package(&packages, " Chrono").is_some());
+        assert!(find_package(&packages, "left-pad").is_none());
+
+        for (name, package) in &packages {
+            assert!(!package.langs.is_empty(), "{name}");
+            for api in &package.apis {
+                let call = api.call.as_deref().unwrap_or(&api.api);
+                assert!(call_regex(call).is_some(), "{name}: {call}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_deprecated_calls() {
+        let packages = packages().unwrap();
+        let chrono = find_package(&packages, "chrono").unwrap();
+
+        let docs = [
+            doc(
+                "src/time.rs",
+                "Rust",
+                "use chrono::NaiveDateTime;\n\
+                 \n\
+                 fn parse(secs: i64) -> NaiveDateTime {\n\
+                 \x20   // NaiveDateTime::from_timestamp(secs, 0) panics on overflow\n\
+                 \x20   NaiveDateTime::from_timestamp_opt(secs, 0).unwrap_or_else(|| {\n\
+                 \x20       chrono::NaiveDateTime::from_timestamp (0, 0)\n\
+                 \x20   })\n\
+                 }\n\
+                 \n\
+                 fn today() -> Date<Utc> {\n\
+                 \x20   Utc::now().date().and_hms(0, 0, 0);\n\
+                 \x20   Utc.ymd(2023, 7, 1)\n\
+                 }\n",
+            ),
+            // Only files in the languages of the package are searched.
+            doc(
+                "client/time.js",
+                "JavaScript",
+                "NaiveDateTime::from_timestamp(0, 0)",
+            ),
+        ];
+
+        assert_eq!(
+            deprecated_calls(chrono, docs),
+            [
+                DeprecatedCall {
+                    api: "NaiveDateTime::from_timestamp".into(),
+                    path: "src/time.rs".into(),
+                    line: 6,
+                    replacement: Some("DateTime::from_timestamp".into()),
+                },
+                DeprecatedCall {
+                    api: "TimeZone::ymd".into(),
+                    path: "src/time.rs".into(),
+                    line: 12,
+                    replacement: Some("TimeZone::with_ymd_and_hms".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_call_regex() {
+        let regex = call_regex("url.parse").unwrap();
+        assert!(regex.is_match("const u = url.parse(input);"));
+        assert!(regex.is_match("const u = require('url').url.parse (input);"));
+        assert!(!regex.is_match("const u = base_url.parse(input);"));
+        assert!(!regex.is_match("const u = url.parseHost(input);"));
+
+        let regex = call_regex(".trim_left").unwrap();
+        assert!(regex.is_match("name.trim_left()"));
+        assert!(!regex.is_match("name.trim_left_matches('a')"));
+        assert!(!regex.is_match("trim_left(name)"));
+    }
+}
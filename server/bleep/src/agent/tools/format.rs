@@ -0,0 +1,528 @@
+use std::{path::Path, process::Stdio, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, Instrument};
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+};
+
+/// How long a formatter may run for, before it is killed.
+const FORMAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of unchanged lines shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+/// Changed regions larger than this, in lines of the original times lines of the formatted file,
+/// are shown as replaced wholesale, rather than aligned line by line.
+const MAX_ALIGNED_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Formatter {
+    Rustfmt,
+    Black,
+    Prettier,
+    Gofmt,
+}
+
+impl Formatter {
+    fn detect(path: &str) -> Option<Self> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+
+        Some(match extension.as_str() {
+            "rs" => Self::Rustfmt,
+            "py" | "pyi" => Self::Black,
+            "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" | "json" | "css" | "scss" | "less"
+            | "html" | "vue" | "md" | "yaml" | "yml" => Self::Prettier,
+            "go" => Self::Gofmt,
+            _ => return None,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Rustfmt => "rustfmt",
+            Self::Black => "black",
+            Self::Prettier => "prettier",
+            Self::Gofmt => "gofmt",
+        }
+    }
+
+    /// The command that formats a file read from stdin, and writes the result to stdout.
+    ///
+    /// Formatters never write to the file itself in this mode. They run in the empty directory
+    /// `dir`, and are only told the file's name, so that no configuration or plugins are loaded
+    /// from the repository, as some formatters execute them. rustfmt is given the `edition` of
+    /// the file's crate instead.
+    fn command(self, path: &str, dir: &Path, edition: Option<&str>) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(self.name());
+        command.current_dir(dir);
+
+        let file_name = Path::new(path).file_name().unwrap_or(path.as_ref());
+
+        match self {
+            Self::Rustfmt => {
+                if let Some(edition) = edition {
+                    command.arg("--edition").arg(edition);
+                }
+                command.args(["--emit", "stdout"])
+            }
+            Self::Black => command
+                .args(["--quiet", "--stdin-filename"])
+                .arg(file_name)
+                .arg("-"),
+            Self::Prettier => command
+                .args(["--no-config", "--stdin-filepath"])
+                .arg(file_name),
+            Self::Gofmt => &mut command,
+        };
+
+        command
+    }
+}
+
+/// The Rust edition of the crate that `file` belongs to, as set in its `Cargo.toml`.
+///
+/// Manifests are looked for in the directories of `file`, up to `root`. Editions inherited from a
+/// workspace are read from the workspace's manifest.
+fn cargo_edition(root: &Path, file: &Path) -> Option<String> {
+    let mut inherited = false;
+
+    for dir in file.ancestors().skip(1) {
+        if !dir.starts_with(root) {
+            break;
+        }
+
+        let Ok(manifest) = std::fs::read_to_string(dir.join("Cargo.toml")) else {
+            continue;
+        };
+        let Ok(manifest) = toml::from_str::<toml::Value>(&manifest) else {
+            continue;
+        };
+
+        if !inherited {
+            let edition = manifest
+                .get("package")
+                .map(|package| package.get("edition"));
+            match edition {
+                Some(Some(toml::Value::String(edition))) => return Some(edition.clone()),
+                // `edition.workspace = true`
+                Some(Some(_)) => inherited = true,
+                // The edition that Cargo defaults to.
+                Some(None) => return Some("2015".to_owned()),
+                None => continue,
+            }
+        }
+
+        let workspace = manifest.get("workspace").and_then(|w| w.get("package"));
+        if let Some(edition) = workspace.and_then(|p| p.get("edition")?.as_str()) {
+            return Some(edition.to_owned());
+        }
+    }
+
+    None
+}
+
+impl Agent {
+    pub async fn format_check(&mut self, path: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::Format {
+            path: path.to_owned(),
+            formatter: None,
+            diff: None,
+        }))
+        .await?;
+
+        let content = self
+            .get_file_content(path)
+            .await?
+            .with_context(|| format!("path does not exist in the index: {path}"))?
+            .content;
+
+        let checkout = self
+            .app
+            .repo_pool
+            .read(&self.repo_ref, |_, repo| repo.disk_path.clone());
+        let edition = checkout.and_then(|root| cargo_edition(&root, &root.join(path)));
+        let edition = edition.as_deref();
+
+        let (formatter, diff) = match Formatter::detect(path) {
+            Some(formatter) => match format_source(formatter, path, edition, &content).await? {
+                Some(formatted) => (
+                    Some(formatter.name().to_owned()),
+                    unified_diff(&content, &formatted),
+                ),
+                None => {
+                    debug!(formatter = formatter.name(), "formatter is not installed");
+                    (None, None)
+                }
+            },
+            None => (None, None),
+        };
+
+        let step = SearchStep::Format {
+            path: path.to_owned(),
+            formatter: formatter.clone(),
+            diff,
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("format")
+                .with_payload("path", path)
+                .with_payload("formatter", &formatter)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Run `formatter` over `source`, the content of `path`, returning the formatted source, or
+/// `None` if the formatter is not installed.
+async fn format_source(
+    formatter: Formatter,
+    path: &str,
+    edition: Option<&str>,
+    source: &str,
+) -> Result<Option<String>> {
+    let dir = std::env::temp_dir().join(format!("bleep-format-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir).context("failed to create formatter directory")?;
+
+    let formatted = run_formatter(formatter.command(path, &dir, edition), formatter, source).await;
+    _ = std::fs::remove_dir_all(&dir);
+
+    formatted
+}
+
+async fn run_formatter(
+    mut command: tokio::process::Command,
+    formatter: Formatter,
+    source: &str,
+) -> Result<Option<String>> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    #[cfg(windows)]
+    {
+        // CREATE_NO_WINDOW
+        command.creation_flags(0x08000000);
+    }
+
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("failed to run {}", formatter.name())),
+    };
+
+    // Write stdin while the output is read, so that large files don't fill both pipes.
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("formatter stdin was not piped")?;
+    let input = source.to_owned();
+    let writer =
+        tokio::spawn(async move { stdin.write_all(input.as_bytes()).await }.in_current_span());
+
+    let output = tokio::time::timeout(FORMAT_TIMEOUT, child.wait_with_output())
+        .await
+        .with_context(|| format!("{} timed out", formatter.name()))??;
+
+    if !output.status.success() {
+        bail!(
+            "{} exited with {}: {}",
+            formatter.name(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    writer.await??;
+
+    Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// A unified diff from `original` to `formatted`, or `None` if their lines are the same.
+fn unified_diff(original: &str, formatted: &str) -> Option<String> {
+    let old = original.lines().collect::<Vec<_>>();
+    let new = formatted.lines().collect::<Vec<_>>();
+
+    if old == new {
+        return None;
+    }
+
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut lines = old[..prefix]
+        .iter()
+        .copied()
+        .map(Line::Same)
+        .collect::<Vec<_>>();
+    lines.extend(align(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    ));
+    lines.extend(old[old.len() - suffix..].iter().copied().map(Line::Same));
+
+    Some(hunks(&lines))
+}
+
+/// Align two runs of lines along their longest common subsequence.
+fn align<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    if old.len() * new.len() > MAX_ALIGNED_CELLS {
+        return old
+            .iter()
+            .copied()
+            .map(Line::Removed)
+            .chain(new.iter().copied().map(Line::Added))
+            .collect();
+    }
+
+    // `lengths[i * width + j]` is the length of the longest common subsequence of `old[i..]` and
+    // `new[j..]`.
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::with_capacity(old.len() + new.len());
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(Line::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            lines.push(Line::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    lines.extend(old[i..].iter().copied().map(Line::Removed));
+    lines.extend(new[j..].iter().copied().map(Line::Added));
+    lines
+}
+
+/// Render aligned lines as hunks, each with up to `CONTEXT_LINES` of context around its changes.
+fn hunks(lines: &[Line<'_>]) -> String {
+    let is_old = |l: &&Line<'_>| !matches!(l, Line::Added(_));
+    let is_new = |l: &&Line<'_>| !matches!(l, Line::Removed(_));
+
+    // Changes are grouped into one hunk if their context would overlap.
+    let mut groups = Vec::<(usize, usize)>::new();
+    for (i, _) in lines
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| !matches!(l, Line::Same(_)))
+    {
+        match groups.last_mut() {
+            Some((_, last)) if i - *last <= 2 * CONTEXT_LINES + 1 => *last = i,
+            _ => groups.push((i, i)),
+        }
+    }
+
+    let mut out = String::new();
+    for (first, last) in groups {
+        let start = first.saturating_sub(CONTEXT_LINES);
+        let end = (last + CONTEXT_LINES + 1).min(lines.len());
+        let (before, hunk) = (&lines[..start], &lines[start..end]);
+
+        out += &format!(
+            "@@ -{},{} +{},{} @@\n",
+            before.iter().filter(is_old).count() + 1,
+            hunk.iter().filter(is_old).count(),
+            before.iter().filter(is_new).count() + 1,
+            hunk.iter().filter(is_new).count(),
+        );
+
+        for line in hunk {
+            let (marker, text) = match line {
+                Line::Same(text) => (' ', text),
+                Line::Removed(text) => ('-', text),
+                Line::Added(text) => ('+', text),
+            };
+
+            out.push(marker);
+            out.push_str(text);
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_detect_formatter() {
+        assert_eq!(Formatter::detect("src/main.rs"), Some(Formatter::Rustfmt));
+        assert_eq!(Formatter::detect("app/models.py"), Some(Formatter::Black));
+        assert_eq!(
+            Formatter::detect("client/src/App.TSX"),
+            Some(Formatter::Prettier)
+        );
+        assert_eq!(Formatter::detect("cmd/main.go"), Some(Formatter::Gofmt));
+        assert_eq!(Formatter::detect("Makefile"), None);
+        assert_eq!(Formatter::detect("src/lib.hs"), None);
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let original = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+        let formatted = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\nL\nl2\nm\n";
+
+        assert_eq!(
+            unified_diff(original, formatted).unwrap(),
+            "@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -9,5 +9,6 @@\n i\n j\n k\n-l\n+L\n+l2\n m\n"
+        );
+
+        assert_eq!(unified_diff(original, original), None);
+        assert_eq!(unified_diff("a\nb", "a\nb\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_rustfmt_diff() {
+        let path = "src/lib.rs";
+        let source = "fn add(a:i32,b:i32)->i32{a+b}\n\nfn double(x: i32) -> i32 {\n    x * 2\n}\n";
+
+        let Some(formatted) = format_source(Formatter::Rustfmt, path, None, source)
+            .await
+            .unwrap()
+        else {
+            // rustfmt is not installed.
+            return;
+        };
+
+        assert_eq!(
+            unified_diff(source, &formatted).unwrap(),
+            "@@ -1,4 +1,6 @@\n\
+             -fn add(a:i32,b:i32)->i32{a+b}\n\
+             +fn add(a: i32, b: i32) -> i32 {\n\
+             +    a + b\n\
+             +}\n\
+             \x20\n\
+             \x20fn double(x: i32) -> i32 {\n\
+             \x20    x * 2\n"
+        );
+
+        // Formatting is idempotent, so a formatted file has nothing to fix.
+        let reformatted = format_source(Formatter::Rustfmt, path, None, &formatted)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unified_diff(&formatted, &reformatted), None);
+
+        assert!(
+            format_source(Formatter::Rustfmt, path, None, "fn broken( {\n")
+                .await
+                .is_err()
+        );
+    }
+    #[test]
+    fn test_cargo_edition() {
+        let tmp = tempdir::TempDir::new("format").unwrap();
+        let root = tmp.path();
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+
+        write(
+            "Cargo.toml",
+            "[workspace]\nmembers = [\"*\"]\n\n[workspace.package]\nedition = \"2021\"\n",
+        );
+        write(
+            "old/Cargo.toml",
+            "[package]\nname = \"old\"\nedition = \"2018\"\n",
+        );
+        write(
+            "inherited/Cargo.toml",
+            "[package]\nname = \"inherited\"\nedition.workspace = true\n",
+        );
+        write("default/Cargo.toml", "[package]\nname = \"default\"\n");
+
+        let edition = |path: &str| cargo_edition(root, &root.join(path));
+        assert_eq!(edition("old/src/lib.rs").as_deref(), Some("2018"));
+        assert_eq!(edition("inherited/src/lib.rs").as_deref(), Some("2021"));
+        assert_eq!(edition("default/src/main.rs").as_deref(), Some("2015"));
+
+        // Files outside of a crate have no edition, and manifests above the root are ignored.
+        assert_eq!(edition("scripts/build.rs"), None);
+        assert_eq!(
+            cargo_edition(&root.join("old/src"), &root.join("old/src/lib.rs")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rustfmt_edition() {
+        // `dyn` is an identifier in Rust 2015, and a keyword since.
+        let source = "fn f() -> i32 {\n    let dyn = 1;\n    dyn\n}\n";
+
+        let Some(formatted) = format_source(Formatter::Rustfmt, "src/lib.rs", Some("2015"), source)
+            .await
+            .unwrap()
+        else {
+            // rustfmt is not installed.
+            return;
+        };
+        assert_eq!(formatted, source);
+
+        assert!(
+            format_source(Formatter::Rustfmt, "src/lib.rs", Some("2018"), source)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_formatter_isolated_from_repo() {
+        let dir = Path::new("/tmp/bleep-format");
+        let command = Formatter::Prettier.command("client/src/App.tsx", dir, None);
+        let command = command.as_std();
+
+        assert_eq!(command.get_current_dir(), Some(dir));
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            ["--no-config", "--stdin-filepath", "App.tsx"]
+        );
+    }
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+};
+
+impl Agent {
+    pub async fn list_files(&mut self, pattern: &String) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::ListFiles {
+            pattern: pattern.clone(),
+            paths: Vec::new(),
+        }))
+        .await?;
+
+        let paths = self.glob_path_search(pattern).await;
+        let response = paths.join("\n");
+
+        self.update(Update::ReplaceStep(SearchStep::ListFiles {
+            pattern: pattern.clone(),
+            paths: paths.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("list files")
+                .with_payload("pattern", pattern)
+                .with_payload("results", &paths)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
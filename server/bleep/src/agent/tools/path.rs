@@ -4,7 +4,7 @@ use anyhow::Result;
 
 use crate::{
     agent::{
-        exchange::{SearchStep, Update},
+        exchange::{ContextSource, SearchStep, Update},
         Agent,
     },
     analytics::EventData,
@@ -43,17 +43,7 @@ impl Agent {
             paths = semantic_paths;
         }
 
-        let mut paths = paths
-            .iter()
-            .map(|p| (self.get_path_alias(p), p.to_string()))
-            .collect::<Vec<_>>();
-        paths.sort_by(|a: &(usize, String), b| a.0.cmp(&b.0)); // Sort by alias
-
-        let response = paths
-            .iter()
-            .map(|(alias, path)| format!("{}: {}", alias, path))
-            .collect::<Vec<_>>()
-            .join("\n");
+        let (paths, response) = self.record_paths(&paths);
 
         self.update(Update::ReplaceStep(SearchStep::Path {
             query: query.clone(),
@@ -71,4 +61,27 @@ impl Agent {
 
         Ok(response)
     }
+
+    /// Add the paths found by a path search to the last exchange, returning them by alias with the
+    /// response that is sent to the model.
+    pub(crate) fn record_paths(&mut self, paths: &[String]) -> (Vec<(usize, String)>, String) {
+        let mut paths = paths
+            .iter()
+            .map(|p| (self.get_path_alias(p), p.to_string()))
+            .collect::<Vec<_>>();
+        paths.sort_by(|a: &(usize, String), b| a.0.cmp(&b.0)); // Sort by alias
+
+        for (_, path) in &paths {
+            self.last_exchange_mut()
+                .include_context(path, ContextSource::PathSearch, &[], false);
+        }
+
+        let response = paths
+            .iter()
+            .map(|(alias, path)| format!("{}: {}", alias, path))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        (paths, response)
+    }
 }
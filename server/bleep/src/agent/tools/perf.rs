@@ -0,0 +1,295 @@
+//! Heap allocations in a file, with how often each one is likely to run.
+
+use anyhow::{Context, Result};
+use tree_sitter::Node;
+
+use crate::{
+    agent::{
+        exchange::{AllocFrequency, AllocationSite, SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+    intelligence::TreeSitterFile,
+};
+
+/// The maximum number of allocation sites returned.
+const MAX_ALLOCATION_SITES: usize = 100;
+
+/// The longest that an expression is shown, in characters.
+const MAX_EXPRESSION_LEN: usize = 80;
+
+/// Functions that allocate, as the last two segments of their path.
+const ALLOCATING_FUNCTIONS: &[&str] = &[
+    "Box::new",
+    "Rc::new",
+    "Arc::new",
+    "Vec::new",
+    "Vec::with_capacity",
+    "String::new",
+    "String::from",
+    "String::with_capacity",
+    "HashMap::new",
+    "HashMap::with_capacity",
+    "HashSet::new",
+    "BTreeMap::new",
+    "BTreeSet::new",
+    "VecDeque::new",
+];
+
+/// Methods that allocate, mostly by copying borrowed data into owned data.
+const ALLOCATING_METHODS: &[&str] = &[
+    "clone",
+    "to_owned",
+    "to_string",
+    "to_vec",
+    "into_owned",
+    "collect",
+];
+
+const ALLOCATING_MACROS: &[&str] = &["vec", "format"];
+
+impl Agent {
+    pub async fn allocations(&mut self, path: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::Allocations {
+            path: path.to_owned(),
+            language: None,
+            sites: Vec::new(),
+        }))
+        .await?;
+
+        let doc = self
+            .get_file_content(path)
+            .await?
+            .with_context(|| format!("path does not exist in the index: {path}"))?;
+
+        // Only Rust allocations are known, as other languages allocate implicitly.
+        let (language, sites) = match doc.lang.as_deref() {
+            Some(lang) if lang.eq_ignore_ascii_case("rust") => {
+                (Some(lang.to_owned()), allocation_sites(&doc.content))
+            }
+            _ => (None, Vec::new()),
+        };
+
+        let step = SearchStep::Allocations {
+            path: path.to_owned(),
+            language: language.clone(),
+            sites: sites.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("allocations")
+                .with_payload("path", path)
+                .with_payload("language", &language)
+                .with_payload("results", &sites)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// The calls and macros in Rust `content` that allocate on the heap, ordered by position.
+fn allocation_sites(content: &str) -> Vec<AllocationSite> {
+    let Ok(file) = TreeSitterFile::try_build(content.as_bytes(), "Rust") else {
+        return Vec::new();
+    };
+
+    let mut sites = Vec::new();
+    let mut stack = vec![file.root_node()];
+    while let Some(node) = stack.pop() {
+        if allocates(node, content) {
+            sites.push((node.start_byte(), node));
+        }
+
+        let mut cursor = node.walk();
+        stack.extend(node.children(&mut cursor));
+    }
+
+    sites.sort_by_key(|(start, _)| *start);
+    sites
+        .into_iter()
+        .take(MAX_ALLOCATION_SITES)
+        .map(|(_, node)| AllocationSite {
+            line: node.start_position().row + 1,
+            expression: expression(&content[node.byte_range()]),
+            frequency: frequency(node),
+        })
+        .collect()
+}
+
+/// Whether `node` is a call or a macro invocation that allocates.
+fn allocates(node: Node<'_>, content: &str) -> bool {
+    let text = |n: Node<'_>| &content[n.byte_range()];
+
+    match node.kind() {
+        "call_expression" => {
+            let Some(mut function) = node.child_by_field_name("function") else {
+                return false;
+            };
+
+            // Calls with a turbofish, like `collect::<Vec<_>>()`.
+            if function.kind() == "generic_function" {
+                match function.child_by_field_name("function") {
+                    Some(f) => function = f,
+                    None => return false,
+                }
+            }
+
+            match function.kind() {
+                "field_expression" => function
+                    .child_by_field_name("field")
+                    .map_or(false, |f| ALLOCATING_METHODS.contains(&text(f))),
+                _ => ALLOCATING_FUNCTIONS.contains(&last_segments(text(function)).as_str()),
+            }
+        }
+        "macro_invocation" => node.child_by_field_name("macro").map_or(false, |m| {
+            let name = text(m).rsplit("::").next().unwrap_or_default();
+            ALLOCATING_MACROS.contains(&name)
+        }),
+        _ => false,
+    }
+}
+
+/// The last two segments of a path without generic arguments, like `Box::new` for
+/// `std::boxed::Box::<u8>::new`.
+fn last_segments(path: &str) -> String {
+    let mut depth = 0;
+    let stripped = path
+        .chars()
+        .filter(|c| match c {
+            '<' => {
+                depth += 1;
+                false
+            }
+            '>' => {
+                depth -= 1;
+                false
+            }
+            c => depth == 0 && !c.is_whitespace(),
+        })
+        .collect::<String>();
+
+    let segments = stripped
+        .split("::")
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    segments[segments.len().saturating_sub(2)..].join("::")
+}
+
+/// How often the allocation at `node` is likely to run, from the loops and closures around it in
+/// its function.
+///
+/// The iterator of a `for` loop is created once, so it is not in the loop.
+fn frequency(node: Node<'_>) -> AllocFrequency {
+    let mut frequency = AllocFrequency::Low;
+    let mut child = node;
+
+    while let Some(parent) = child.parent() {
+        match parent.kind() {
+            "for_expression"
+                if parent
+                    .child_by_field_name("value")
+                    .map_or(false, |v| v.id() == child.id()) => {}
+            "for_expression" | "while_expression" | "loop_expression" => {
+                return AllocFrequency::High
+            }
+            "closure_expression" => frequency = AllocFrequency::Medium,
+            "function_item" => break,
+            _ => {}
+        }
+
+        child = parent;
+    }
+
+    frequency
+}
+
+/// An expression on a single line, shortened to `MAX_EXPRESSION_LEN`.
+fn expression(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if text.chars().count() > MAX_EXPRESSION_LEN {
+        let shortened = text.chars().take(MAX_EXPRESSION_LEN).collect::<String>();
+        format!("{shortened}...")
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_allocation_sites() {
+        let content = r#"use std::collections::HashMap;
+
+fn names(users: &[User]) -> Vec<String> {
+    let mut names = Vec::new();
+    // names.push(String::from("admin"));
+    for user in users.to_vec() {
+        names.push(user.name.clone());
+    }
+
+    let count = names.len();
+    let labels = names
+        .iter()
+        .map(|n| format!("{n}: {count}"))
+        .collect::<Vec<_>>();
+
+    while names.len() < 10 {
+        names.push(String::from("guest"));
+    }
+
+    let boxed = Box::new(labels);
+    names
+}
+"#;
+
+        let sites = allocation_sites(content)
+            .into_iter()
+            .map(|s| (s.line, s.expression, s.frequency))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            sites,
+            [
+                (4, "Vec::new()".to_owned(), AllocFrequency::Low),
+                (6, "users.to_vec()".to_owned(), AllocFrequency::Low),
+                (7, "user.name.clone()".to_owned(), AllocFrequency::High),
+                (
+                    11,
+                    r#"names .iter() .map(|n| format!("{n}: {count}")) .collect::<Vec<_>>()"#
+                        .to_owned(),
+                    AllocFrequency::Low
+                ),
+                (
+                    13,
+                    r#"format!("{n}: {count}")"#.to_owned(),
+                    AllocFrequency::Medium
+                ),
+                (
+                    17,
+                    r#"String::from("guest")"#.to_owned(),
+                    AllocFrequency::High
+                ),
+                (20, "Box::new(labels)".to_owned(), AllocFrequency::Low),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_last_segments() {
+        assert_eq!(last_segments("std::boxed::Box::<u8>::new"), "Box::new");
+        assert_eq!(
+            last_segments("HashMap::<String, Vec<u8>>::new"),
+            "HashMap::new"
+        );
+        assert_eq!(last_segments("new"), "new");
+    }
+}
@@ -0,0 +1,282 @@
+//! Planning of complex tasks, which are broken down into function calls before any is made.
+
+use std::time::Instant;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use tracing::debug;
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Update},
+        prompts, Action, Agent,
+    },
+    analytics::EventData,
+    llm_gateway::api::{Function, FunctionCall, Message},
+};
+
+/// The model that writes plans.
+const PLAN_MODEL: &str = "gpt-4-0613";
+
+/// The most function calls that a plan can have.
+const MAX_STEPS: usize = 5;
+
+/// Functions that can't be planned: answering ends the search, `proc` reads files by the aliases
+/// of results that are not known yet, and plans are not nested.
+const UNPLANNED: &[&str] = &["none", "proc", "plan"];
+
+impl Agent {
+    /// Break `goal` down into the function calls that are made next, without asking the model
+    /// in between.
+    pub async fn plan(&mut self, goal: &str) -> Result<Vec<Action>> {
+        self.update(Update::StartStep(SearchStep::Plan {
+            goal: goal.to_owned(),
+            actions: Vec::new(),
+        }))
+        .await?;
+
+        let functions = self
+            .step_functions()
+            .into_iter()
+            .filter(|f| !UNPLANNED.contains(&f.name.as_str()))
+            .collect::<Vec<_>>();
+        let prompt = prompts::plan(goal, &serde_json::to_string_pretty(&functions)?, MAX_STEPS);
+        let messages = [Message::system(&prompt)];
+
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model(PLAN_MODEL)
+            .chat(&messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage("plan", PLAN_MODEL, &messages, &response, start.elapsed())
+            .await;
+
+        let actions = parse_plan(&response, &functions);
+        debug!(goal, steps = actions.len(), "planned the next steps");
+
+        self.update(Update::ReplaceStep(SearchStep::Plan {
+            goal: goal.to_owned(),
+            actions: actions.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("plan")
+                .with_payload("goal", goal)
+                .with_payload("actions", &actions)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(actions)
+    }
+}
+
+/// A function call in a plan, as the model writes it.
+#[derive(serde::Deserialize)]
+struct PlannedCall {
+    name: String,
+    #[serde(default)]
+    arguments: Option<serde_json::Value>,
+}
+
+/// Parse the calls of a plan, which is a JSON array that may be surrounded by other text.
+///
+/// Calls to functions that are not in `functions`, or with invalid arguments, are left out.
+fn parse_plan(response: &str, functions: &[Function]) -> Vec<Action> {
+    let json = match (response.find('['), response.rfind(']')) {
+        (Some(start), Some(end)) if start < end => &response[start..=end],
+        _ => return Vec::new(),
+    };
+
+    let calls = match serde_json::from_str::<Vec<PlannedCall>>(json) {
+        Ok(calls) => calls,
+        Err(err) => {
+            debug!(?err, "failed to parse plan");
+            return Vec::new();
+        }
+    };
+
+    calls
+        .into_iter()
+        .filter_map(|call| {
+            let call = FunctionCall {
+                name: Some(call.name),
+                arguments: call
+                    .arguments
+                    .unwrap_or_else(|| serde_json::json!({}))
+                    .to_string(),
+            };
+
+            Action::deserialize_offered(&call, functions)
+                .map_err(|err| debug!(?err, "dropping planned call"))
+                .ok()
+        })
+        .take(MAX_STEPS)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        agent::{builder, prompts::Capabilities},
+        llm_gateway::mock::{call, Gateway, Reply, Request, Script},
+        repo::{Backend, RepoRef},
+        Application, Environment,
+    };
+
+    const GOAL: &str = "add OAuth2 authentication to the webserver";
+
+    fn functions() -> Vec<Function> {
+        let capabilities = Capabilities {
+            semantic: true,
+            ..Default::default()
+        };
+        serde_json::from_value(prompts::functions(
+            true,
+            &capabilities,
+            crate::agent::exchange::QueryType::Other,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_plan() {
+        let response = r#"Here is the plan:
+[
+  {"name": "path", "arguments": {"query": "auth"}},
+  {"name": "changelog", "arguments": {}},
+  {"name": "code", "arguments": {"q": "missing query"}},
+  {"name": "dead_code"},
+  {"name": "code", "arguments": {"query": "session cookies"}}
+]"#;
+
+        let actions = parse_plan(response, &functions());
+        assert_eq!(
+            actions
+                .iter()
+                .map(|a| serde_json::to_value(a).unwrap())
+                .collect::<Vec<_>>(),
+            [
+                serde_json::json!({ "path": { "query": "auth" } }),
+                serde_json::json!({ "dead_code": {} }),
+                serde_json::json!({ "code": { "query": "session cookies" } }),
+            ]
+        );
+
+        assert!(parse_plan("I can't plan this.", &functions()).is_empty());
+        assert!(parse_plan("[{\"name\": ", &functions()).is_empty());
+
+        let long = serde_json::to_string(&vec![
+            serde_json::json!({ "name": "dead_code" });
+            MAX_STEPS + 2
+        ])
+        .unwrap();
+        assert_eq!(parse_plan(&long, &functions()).len(), MAX_STEPS);
+    }
+
+    /// Serve a mock gateway, which makes `calls` in order, writes `plan` when asked for one, and
+    /// answers every other request with `answer`.
+    fn serve(
+        calls: Vec<serde_json::Value>,
+        plan: serde_json::Value,
+        answer: &'static str,
+    ) -> Gateway {
+        let calls = Script::new(calls);
+        Gateway::serve(move |request| {
+            if request.is_function_call() {
+                calls.next()
+            } else if is_plan(request) {
+                Reply::text(plan.to_string())
+            } else {
+                Reply::text(answer)
+            }
+        })
+    }
+
+    fn is_plan(request: &Request) -> bool {
+        request.system().contains("break down this task")
+    }
+
+    #[tokio::test]
+    async fn test_plan() {
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+
+        let gateway = serve(
+            vec![
+                call("plan", serde_json::json!({ "goal": GOAL })),
+                call("none", serde_json::json!({ "paths": [] })),
+            ],
+            serde_json::json!([
+                { "name": "path", "arguments": { "query": "webserver auth" } },
+                { "name": "list_files", "arguments": { "pattern": "**/middleware.rs" } },
+                { "name": "path", "arguments": { "query": "session cookie" } },
+            ]),
+            "Add an OAuth2 middleware to the webserver.",
+        );
+
+        let config = serde_json::from_value(serde_json::json!({
+            "index_dir": index_dir.path(),
+            "answer_api_url": gateway.url,
+            "disable_background": true,
+            "disable_analytics": true,
+        }))
+        .unwrap();
+        let app = Application::initialize(Environment::insecure_local(), config, None, None)
+            .await
+            .unwrap();
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &repo_dir.path().to_string_lossy()).unwrap();
+        let mut driver = builder::builder(app).repo(repo_ref).build().unwrap();
+        let exchange = driver
+            .run("How do I add OAuth2 authentication?")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            exchange.answer.as_deref(),
+            Some("Add an OAuth2 middleware to the webserver.")
+        );
+
+        // The planned calls are made in order, right after the plan.
+        let search_steps = &exchange.search_steps;
+        assert_eq!(search_steps.len(), 4);
+        assert!(matches!(
+            &search_steps[0],
+            SearchStep::Plan { goal, actions, .. } if goal == GOAL && actions.len() == 3
+        ));
+        assert!(matches!(
+            &search_steps[1],
+            SearchStep::Path { query, .. } if query == "webserver auth"
+        ));
+        assert!(matches!(
+            &search_steps[2],
+            SearchStep::ListFiles { pattern, .. } if pattern == "**/middleware.rs"
+        ));
+        assert!(matches!(
+            &search_steps[3],
+            SearchStep::Path { query, .. } if query == "session cookie"
+        ));
+
+        // The model is only asked for the next call once the whole plan has been carried out.
+        assert_eq!(gateway.requests_where(Request::is_function_call).len(), 2);
+
+        // Plans can't call functions that need results of their own, or plan again.
+        let plans = gateway.requests_where(is_plan);
+        assert_eq!(plans.len(), 1);
+        let prompt = plans[0].system();
+        assert!(prompt.contains(GOAL));
+        assert!(prompt.contains("\"name\": \"path\""));
+        for name in UNPLANNED {
+            assert!(!prompt.contains(&format!("\"name\": \"{name}\"")));
+        }
+    }
+}
@@ -1,12 +1,22 @@
+use std::{
+    future::Future,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
 use anyhow::{anyhow, Context, Result};
 use futures::{stream, StreamExt, TryStreamExt};
-use tiktoken_rs::CoreBPE;
-use tracing::debug;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
 
 use crate::{
     agent::{
-        exchange::{CodeChunk, SearchStep, Update},
-        prompts, Agent,
+        context, deadline,
+        exchange::{CodeChunk, ContextSource, Exchange, SearchStep, Update},
+        file_budget, prompts,
+        relocation::Relocation,
+        tokens::Tokenizer,
+        Agent, FileTooLarge,
     },
     analytics::EventData,
     llm_gateway,
@@ -14,16 +24,20 @@ use crate::{
 
 impl Agent {
     pub async fn process_files(&mut self, query: &str, path_aliases: &[usize]) -> Result<String> {
-        const MAX_CHUNK_LINE_LENGTH: usize = 20;
-        const CHUNK_MERGE_DISTANCE: usize = 10;
         const MAX_TOKENS: usize = 15400;
+        let max_tokens = MAX_TOKENS.saturating_sub(self.app.config.token_safety_margin);
 
+        let all_paths = self.paths();
+        let repo_aliases = self.repo_aliases();
         let paths = path_aliases
             .iter()
             .copied()
-            .map(|i| self.paths().get(i).ok_or(i).cloned())
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|i| anyhow!("invalid path alias {i}"))?;
+            .map(|i| match all_paths.get(i) {
+                Some(path) if repo_aliases.contains(&i) => Ok(path.clone()),
+                Some(_) => Err(anyhow!("path alias {i} is in another repository")),
+                None => Err(anyhow!("invalid path alias {i}")),
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         debug!(?query, ?paths, "invoking proc");
 
@@ -31,154 +45,132 @@ impl Agent {
             query: query.to_string(),
             paths: paths.clone(),
             response: String::new(),
+            lines_read: Vec::new(),
+            timed_out: Vec::new(),
         }))
         .await?;
 
+        // Files that were renamed or deleted since they were added to the context can't be read,
+        // so we explain what happened to them instead.
+        let mut readable = Vec::new();
+        let mut notes = Vec::new();
+        for path in &paths {
+            match self.relocate_path(path).await? {
+                None => readable.push(path.clone()),
+                Some(Relocation::Moved(new_path)) => {
+                    let alias = self.get_path_alias(path);
+                    let new_alias = self.get_path_alias(&new_path);
+                    notes.push(format!(
+                        "{alias}: {path}\nThis file has been moved to {new_alias}: {new_path}"
+                    ));
+                }
+                Some(Relocation::Deleted) => {
+                    let alias = self.get_path_alias(path);
+                    notes.push(format!(
+                        "{alias}: {path}\nThis file no longer exists in the repository"
+                    ));
+                }
+            }
+        }
+
+        // Files that were already found irrelevant to this exchange are not read again.
+        let (readable, examined) = split_examined(self.last_exchange(), readable);
+        for (path, reason) in examined {
+            let alias = self.get_path_alias(&path);
+            notes.push(format!(
+                "{alias}: {path}\nThis file was already examined and is not relevant: {reason}"
+            ));
+        }
+
+        // Files beyond the query's file budget are not read, so the model has to make do with what
+        // it has already read.
+        let (readable, refused): (Vec<_>, Vec<_>) = readable
+            .into_iter()
+            .partition(|path| self.spend_file_budget(path));
+        for path in refused {
+            let alias = self.get_path_alias(&path);
+            notes.push(format!(
+                "{alias}: {path}\n{}",
+                file_budget::EXHAUSTED_MESSAGE
+            ));
+        }
+
         // Immutable reborrow of `self`, to copy freely to async closures.
         let self_ = &*self;
-        let chunks = stream::iter(paths.clone())
-            .map(|path| async move {
-                tracing::debug!(?path, "reading file");
-
-                let lines = self_
-                    .get_file_content(&path)
-                    .await?
-                    .with_context(|| format!("path does not exist in the index: {path}"))?
-                    .content
-                    .lines()
-                    .enumerate()
-                    .map(|(i, line)| format!("{} {line}", i + 1))
-                    .collect::<Vec<_>>();
-
-                let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo")?;
-
-                let iter =
-                    tokio::task::spawn_blocking(|| trim_lines_by_tokens(lines, bpe, MAX_TOKENS))
-                        .await
-                        .context("failed to split by token")?;
-
-                Result::<_>::Ok((iter, path.clone()))
-            })
-            // Buffer file loading to load multiple paths at once
-            .buffered(10)
-            .map(|result| async {
-                let (lines, path) = result?;
-
-                // The unwraps here should never fail, we generated this string above to always
-                // have the same format.
-                let start_line = lines[0]
-                    .split_once(' ')
-                    .unwrap()
-                    .0
-                    .parse::<usize>()
-                    .unwrap();
-
-                // We store the lines separately, so that we can reference them later to trim
-                // this snippet by line number.
-                let contents = lines.join("\n");
-                let prompt = prompts::file_explanation(query, &path, &contents);
-
-                debug!(?path, "calling chat API on file");
-
-                let json = self_
-                    .llm_gateway
-                    .clone()
-                    .model("gpt-3.5-turbo-16k-0613")
-                    // Set low frequency penalty to discourage long outputs.
-                    .frequency_penalty(0.2)
-                    .chat(&[llm_gateway::api::Message::system(&prompt)], None)
-                    .await?
-                    .try_collect::<String>()
-                    .await?;
-
-                #[derive(
-                    serde::Deserialize,
-                    serde::Serialize,
-                    PartialEq,
-                    Eq,
-                    PartialOrd,
-                    Ord,
-                    Copy,
-                    Clone,
-                    Debug,
-                )]
-                struct Range {
-                    start: usize,
-                    end: usize,
+        let extracted = extract_all(
+            readable,
+            &self.cancellation,
+            self.config.tool_timeout,
+            |path, token| async move {
+                // Files that are too large to read are reported, instead of being left out.
+                match self_.read_file(query, &path, max_tokens, &token).await {
+                    Ok(read) => Ok(Ok(read)),
+                    Err(err) => err.downcast::<FileTooLarge>().map(Err),
                 }
+            },
+        )
+        .await;
 
-                #[derive(serde::Serialize)]
-                struct RelevantChunk {
-                    #[serde(flatten)]
-                    range: Range,
-                    code: String,
+        // Files that took too long to read are given up on, and the model is told so.
+        let mut processed = Vec::new();
+        let mut timed_out = Vec::new();
+        for (path, extraction) in extracted {
+            match extraction {
+                Extraction::Done(Ok(read)) => processed.push((path, read)),
+                Extraction::Done(Err(FileTooLarge { size, limit, .. })) => {
+                    let alias = self.get_path_alias(&path);
+                    notes.push(format!(
+                        "{alias}: {path}\nThis file is too large to read: {size} bytes, over the \
+                         limit of {limit} bytes"
+                    ));
                 }
+                Extraction::TimedOut => {
+                    let alias = self.get_path_alias(&path);
+                    notes.push(format!(
+                        "{alias}: {path}\n{}",
+                        deadline::timed_out_message(&path)
+                    ));
+                    timed_out.push(path);
+                }
+            }
+        }
 
-                let mut line_ranges: Vec<Range> = serde_json::from_str::<Vec<Range>>(&json)?
-                    .into_iter()
-                    .filter(|r| r.start > 0 && r.end > 0)
-                    .map(|mut r| {
-                        r.end = r.end.min(r.start + MAX_CHUNK_LINE_LENGTH); // Cap relevant chunk size by line number
-                        r
-                    })
-                    .collect();
-
-                line_ranges.sort();
-                line_ranges.dedup();
-
-                let relevant_chunks = line_ranges
-                    .into_iter()
-                    .fold(Vec::<Range>::new(), |mut exps, next| {
-                        if let Some(prev) = exps.last_mut() {
-                            if prev.end + CHUNK_MERGE_DISTANCE >= next.start {
-                                prev.end = next.end;
-                                return exps;
-                            }
-                        }
-
-                        exps.push(next);
-                        exps
-                    })
-                    .into_iter()
-                    .filter_map(|range| {
-                        Some(RelevantChunk {
-                            range,
-                            code: lines
-                                .get(
-                                    range.start.saturating_sub(start_line)
-                                        ..range.end.saturating_sub(start_line),
-                                )?
-                                .iter()
-                                .map(|line| line.split_once(' ').unwrap().1)
-                                .collect::<Vec<_>>()
-                                .join("\n"),
-                        })
-                    })
-                    .collect::<Vec<_>>();
-
-                Ok::<_, anyhow::Error>((relevant_chunks, path))
-            });
+        for (path, read) in &processed {
+            let ranges = &read.lines_read;
+            let exchange = self.last_exchange_mut();
+            exchange.include_context(path, ContextSource::Proc, ranges, !ranges.is_empty());
 
-        let processed = chunks
-            // This box seems unnecessary, but it avoids a compiler bug:
-            // https://github.com/rust-lang/rust/issues/64552
-            .boxed()
-            .buffered(5)
-            .filter_map(|res| async { res.ok() })
-            .collect::<Vec<_>>()
-            .await;
+            if let Some(reason) = &read.irrelevant {
+                exchange.mark_irrelevant(path, reason);
+                let alias = self.get_path_alias(path);
+                notes.push(format!(
+                    "{alias}: {path}\nThis file is not relevant: {reason}"
+                ));
+            }
+        }
+
+        let lines_read = processed
+            .iter()
+            .flat_map(|(_, read)| read.lines_read.iter().cloned())
+            .collect::<Vec<_>>();
 
         let chunks = processed
             .into_iter()
-            .flat_map(|(relevant_chunks, path)| {
+            .flat_map(|(path, read)| {
                 let alias = self.get_path_alias(&path);
 
-                relevant_chunks.into_iter().map(move |c| CodeChunk {
+                read.chunks.into_iter().map(move |c| CodeChunk {
                     path: path.clone(),
                     alias,
                     snippet: c.code,
                     start_line: c.range.start,
                     end_line: c.range.end,
+                    moved_to: None,
+                    deleted: false,
+                    symbol_path: None,
+                    blob: None,
+                    revision: None,
+                    mapped: None,
                 })
             })
             .collect::<Vec<_>>();
@@ -195,6 +187,7 @@ impl Agent {
             .iter()
             .filter(|c| !c.is_empty())
             .map(|c| c.to_string())
+            .chain(notes)
             .collect::<Vec<_>>()
             .join("\n\n");
 
@@ -202,23 +195,474 @@ impl Agent {
             query: query.to_string(),
             paths,
             response: response.clone(),
+            lines_read,
+            timed_out: timed_out.clone(),
         }))
         .await?;
 
         self.track_query(
             EventData::input_stage("process file")
                 .with_payload("question", query)
-                .with_payload("chunks", &response),
+                .with_payload("chunks", &response)
+                .with_payload("timed_out", &timed_out),
         );
 
         Ok(response)
     }
+
+    /// Read `path`, and ask the model which of its lines are relevant to `query`.
+    ///
+    /// The file is read in parts of up to `max_tokens` each, which stops early if `token` is
+    /// cancelled.
+    async fn read_file(
+        &self,
+        query: &str,
+        path: &str,
+        max_tokens: usize,
+        token: &CancellationToken,
+    ) -> Result<ReadFile> {
+        debug!(?path, "reading file");
+
+        let lines = self
+            .get_file_content(path)
+            .await?
+            .with_context(|| format!("path does not exist in the index: {path}"))?
+            .content
+            .lines()
+            .enumerate()
+            .map(|(i, line)| format!("{} {line}", i + 1))
+            .collect::<Vec<_>>();
+
+        let tokenizer = self.tokenizer("gpt-3.5-turbo")?;
+        let token = token.clone();
+
+        let parts = context::spawn_blocking_in_ctx(&self.request_context(), move || {
+            split_lines_by_tokens(lines, &tokenizer, max_tokens, &token)
+        })
+        .await
+        .context("failed to split by token")?;
+
+        let mut chunks = Vec::new();
+        let mut lines_read = Vec::new();
+        let mut reason = None;
+        for lines in &parts {
+            let (relevant, irrelevant) = self.examine_part(query, path, lines).await?;
+            chunks.extend(relevant);
+            lines_read.extend(read_line_ranges(lines));
+            reason = reason.or(irrelevant);
+        }
+
+        // A file without relevant lines is as irrelevant as one the model rejected.
+        let irrelevant = chunks
+            .is_empty()
+            .then(|| reason.unwrap_or_else(|| "no relevant lines were found".to_owned()));
+
+        Ok(ReadFile {
+            chunks,
+            lines_read,
+            irrelevant,
+        })
+    }
+
+    /// Ask the model which of `lines`, a part of the file at `path`, are relevant to `query`.
+    ///
+    /// Returns the relevant chunks, and the reason the model gave if it found none.
+    async fn examine_part(
+        &self,
+        query: &str,
+        path: &str,
+        lines: &[String],
+    ) -> Result<(Vec<RelevantChunk>, Option<String>)> {
+        const MAX_CHUNK_LINE_LENGTH: usize = 20;
+        const CHUNK_MERGE_DISTANCE: usize = 10;
+
+        // The unwraps here should never fail, we generated this string above to always have the
+        // same format.
+        let start_line = lines[0]
+            .split_once(' ')
+            .unwrap()
+            .0
+            .parse::<usize>()
+            .unwrap();
+
+        // We store the lines separately, so that we can reference them later to trim this snippet
+        // by line number.
+        let contents = lines.join("\n");
+
+        debug!(?path, "calling chat API on file");
+
+        let (verdict, calls) = examine_file(
+            &self.llm_gateway,
+            self.config.use_structured_proc_output,
+            query,
+            path,
+            &contents,
+        )
+        .await?;
+
+        for call in calls {
+            self.track_usage(
+                "proc",
+                PROC_MODEL,
+                &call.messages,
+                &call.answer,
+                call.latency,
+            )
+            .await;
+        }
+
+        let mut line_ranges = match verdict? {
+            Verdict::RelevantRanges(ranges) => ranges,
+            Verdict::Irrelevant { reason } => return Ok((Vec::new(), Some(reason))),
+        }
+        .into_iter()
+        .filter(|r| r.start > 0 && r.end > 0)
+        .map(|mut r| {
+            // Cap relevant chunk size by line number
+            r.end = r.end.min(r.start + MAX_CHUNK_LINE_LENGTH);
+            r
+        })
+        .collect::<Vec<_>>();
+
+        line_ranges.sort();
+        line_ranges.dedup();
+
+        let chunks = line_ranges
+            .into_iter()
+            .fold(Vec::<LineRange>::new(), |mut exps, next| {
+                if let Some(prev) = exps.last_mut() {
+                    if prev.end + CHUNK_MERGE_DISTANCE >= next.start {
+                        prev.end = next.end;
+                        return exps;
+                    }
+                }
+
+                exps.push(next);
+                exps
+            })
+            .into_iter()
+            .filter_map(|range| {
+                Some(RelevantChunk {
+                    range,
+                    code: lines
+                        .get(
+                            range.start.saturating_sub(start_line)
+                                ..range.end.saturating_sub(start_line),
+                        )?
+                        .iter()
+                        .map(|line| line.split_once(' ').unwrap().1)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                })
+            })
+            .filter(|c| !c.code.trim().is_empty())
+            .collect::<Vec<_>>();
+
+        Ok((chunks, None))
+    }
+}
+
+/// The number of files that `proc` reads at once.
+const CONCURRENT_READS: usize = 5;
+
+/// The most parts of a file that `proc` reads, each with a call to the model. The rest of a file
+/// that is longer than this is not read.
+const MAX_FILE_PARTS: usize = 4;
+
+/// A relevant part of a file, as the model cited it.
+struct RelevantChunk {
+    range: LineRange,
+    code: String,
+}
+
+/// What `proc` found in a single file.
+struct ReadFile {
+    chunks: Vec<RelevantChunk>,
+    /// The 1-based, end-exclusive line ranges that were shown to the model.
+    lines_read: Vec<Range<usize>>,
+    /// Why the file is not relevant to the query, if it isn't.
+    irrelevant: Option<String>,
+}
+
+/// How reading a single file went.
+#[derive(Debug, PartialEq)]
+enum Extraction<T> {
+    Done(T),
+    /// The file took longer than its deadline, and was given up on.
+    TimedOut,
+}
+
+/// Read `paths` with `extract`, a few at a time, giving each file its own deadline of `timeout`
+/// and a child of `parent` to cancel.
+///
+/// A file that times out is given up on without holding up the others. Files that fail to be read
+/// are left out.
+async fn extract_all<T, F, Fut>(
+    paths: Vec<String>,
+    parent: &CancellationToken,
+    timeout: Duration,
+    extract: F,
+) -> Vec<(String, Extraction<T>)>
+where
+    T: Send,
+    F: Fn(String, CancellationToken) -> Fut + Sync,
+    Fut: Future<Output = Result<T>> + Send,
+{
+    let extract = &extract;
+
+    stream::iter(paths)
+        .map(|path| async move {
+            let result =
+                deadline::with_deadline(parent, timeout, |token| extract(path.clone(), token))
+                    .await;
+
+            match result {
+                Ok(Ok(read)) => Some((path, Extraction::Done(read))),
+                Ok(Err(err)) => {
+                    debug!(?path, ?err, "failed to read file");
+                    None
+                }
+                Err(err) => {
+                    warn!(?path, %err, "gave up reading file");
+                    Some((path, Extraction::TimedOut))
+                }
+            }
+        })
+        // This box seems unnecessary, but it avoids a compiler bug:
+        // https://github.com/rust-lang/rust/issues/64552
+        .boxed()
+        .buffered(CONCURRENT_READS)
+        .filter_map(|res| async { res })
+        .collect()
+        .await
+}
+
+/// The model that reads files for `proc`.
+const PROC_MODEL: &str = "gpt-3.5-turbo-16k-0613";
+
+/// A 1-based, end-exclusive range of lines, as the model cites them.
+#[derive(
+    serde::Deserialize, serde::Serialize, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug,
+)]
+struct LineRange {
+    start: usize,
+    end: usize,
+}
+
+/// What the model found when reading a file.
+#[derive(serde::Deserialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Verdict {
+    RelevantRanges(Vec<LineRange>),
+    Irrelevant { reason: String },
+}
+
+impl Verdict {
+    /// Models sometimes answer with a bare list of line ranges, which is read as relevant ranges.
+    fn parse(json: &str) -> Result<Self> {
+        let json = json.trim();
+
+        serde_json::from_str(json)
+            .or_else(|_| serde_json::from_str(json).map(Self::RelevantRanges))
+            .with_context(|| format!("invalid proc verdict: {json}"))
+    }
+}
+
+/// What the model found when reading a file with `prompts::proc_json`.
+#[derive(serde::Deserialize, PartialEq, Debug)]
+struct ProcResult {
+    relevant_lines: Vec<usize>,
+    summary: String,
+    confidence: f32,
+}
+
+impl ProcResult {
+    /// The relevant lines, as ranges of consecutive lines, or the summary if there are none.
+    fn into_verdict(self) -> Verdict {
+        if self.relevant_lines.is_empty() {
+            return Verdict::Irrelevant {
+                reason: self.summary,
+            };
+        }
+
+        let mut lines = self.relevant_lines;
+        lines.sort();
+        lines.dedup();
+
+        let ranges = lines
+            .into_iter()
+            .fold(Vec::<LineRange>::new(), |mut ranges, n| {
+                match ranges.last_mut() {
+                    Some(prev) if prev.end == n => prev.end = n + 1,
+                    _ => ranges.push(LineRange {
+                        start: n,
+                        end: n + 1,
+                    }),
+                }
+
+                ranges
+            });
+
+        Verdict::RelevantRanges(ranges)
+    }
+}
+
+/// A call to the model, for tracking its usage.
+struct ModelCall {
+    messages: Vec<llm_gateway::api::Message>,
+    answer: String,
+    latency: Duration,
+}
+
+/// Ask the model which lines of a file are relevant to `query`.
+///
+/// With `structured`, the model is asked for a `ProcResult` first, and the usual prompt is only
+/// used if its answer is not valid JSON. Returns the verdict, alongside every call to the model.
+async fn examine_file(
+    llm_gateway: &llm_gateway::Client,
+    structured: bool,
+    query: &str,
+    path: &str,
+    contents: &str,
+) -> Result<(Result<Verdict>, Vec<ModelCall>)> {
+    let mut calls = Vec::new();
+
+    if structured {
+        let messages = vec![llm_gateway::api::Message::system(&prompts::proc_json(
+            query, contents,
+        ))];
+
+        let start = Instant::now();
+        let answer = examine(llm_gateway, &messages).await?;
+        let parsed = serde_json::from_str::<ProcResult>(answer.trim());
+        calls.push(ModelCall {
+            messages,
+            answer,
+            latency: start.elapsed(),
+        });
+
+        match parsed {
+            Ok(result) => {
+                debug!(
+                    ?path,
+                    confidence = result.confidence,
+                    "read file with structured output"
+                );
+                return Ok((Ok(result.into_verdict()), calls));
+            }
+            Err(err) => {
+                warn!(
+                    ?path,
+                    ?err,
+                    "invalid structured proc output, falling back to line ranges"
+                )
+            }
+        }
+    }
+
+    let messages = vec![llm_gateway::api::Message::system(
+        &prompts::file_explanation(query, path, contents),
+    )];
+
+    let start = Instant::now();
+    let answer = examine(llm_gateway, &messages).await?;
+    let verdict = Verdict::parse(&answer);
+    calls.push(ModelCall {
+        messages,
+        answer,
+        latency: start.elapsed(),
+    });
+
+    Ok((verdict, calls))
+}
+
+/// Ask the model which lines of a file are relevant, returning its raw answer.
+async fn examine(
+    llm_gateway: &llm_gateway::Client,
+    messages: &[llm_gateway::api::Message],
+) -> Result<String> {
+    llm_gateway
+        .clone()
+        .model(PROC_MODEL)
+        // Set low frequency penalty to discourage long outputs.
+        .frequency_penalty(0.2)
+        .chat(messages, None)
+        .await?
+        .try_collect::<String>()
+        .await
+}
+
+/// Split `paths` into those to read, and those that were already found irrelevant to `exchange`,
+/// alongside the reason they were.
+fn split_examined(exchange: &Exchange, paths: Vec<String>) -> (Vec<String>, Vec<(String, String)>) {
+    let (examined, unread) = paths
+        .into_iter()
+        .partition::<Vec<_>, _>(|path| exchange.irrelevant_reason(path).is_some());
+
+    let examined = examined
+        .into_iter()
+        .map(|path| {
+            let reason = exchange
+                .irrelevant_reason(&path)
+                .unwrap_or_default()
+                .to_owned();
+            (path, reason)
+        })
+        .collect();
+
+    (unread, examined)
+}
+
+/// Take lines from the start of a file, until their total number of tokens is over `max_tokens`,
+/// and then trim their joined text to that budget.
+///
+/// If `token` is cancelled, the lines are returned as they are, as they won't be read anyway.
+fn fit_lines_to_tokens(
+    lines: Vec<String>,
+    tokenizer: &Tokenizer,
+    max_tokens: usize,
+    token: &CancellationToken,
+) -> Vec<String> {
+    let mut lines = trim_lines_by_tokens(lines, tokenizer, max_tokens);
+
+    // Lines are sent joined together, which neither counts per line nor stops at the budget.
+    while !token.is_cancelled()
+        && lines.len() > 1
+        && tokenizer.count(&lines.join("\n")) > max_tokens
+    {
+        lines.pop();
+    }
+
+    lines
 }
 
-fn trim_lines_by_tokens(lines: Vec<String>, bpe: CoreBPE, max_tokens: usize) -> Vec<String> {
+/// Split `lines` into consecutive parts, each fit to `max_tokens` as with `fit_lines_to_tokens`,
+/// up to `MAX_FILE_PARTS`.
+fn split_lines_by_tokens(
+    mut lines: Vec<String>,
+    tokenizer: &Tokenizer,
+    max_tokens: usize,
+    token: &CancellationToken,
+) -> Vec<Vec<String>> {
+    let mut parts = Vec::new();
+
+    while !lines.is_empty() && parts.len() < MAX_FILE_PARTS && !token.is_cancelled() {
+        let part = fit_lines_to_tokens(lines.clone(), tokenizer, max_tokens, token);
+        lines.drain(..part.len());
+        parts.push(part);
+    }
+
+    parts
+}
+
+fn trim_lines_by_tokens(
+    lines: Vec<String>,
+    tokenizer: &Tokenizer,
+    max_tokens: usize,
+) -> Vec<String> {
     let line_tokens = lines
         .iter()
-        .map(|line| bpe.encode_ordinary(line).len())
+        .map(|line| tokenizer.count(line))
         .collect::<Vec<_>>();
 
     let mut trimmed_lines = Vec::new();
@@ -235,13 +679,41 @@ fn trim_lines_by_tokens(lines: Vec<String>, bpe: CoreBPE, max_tokens: usize) ->
     trimmed_lines
 }
 
+/// Collapse a list of numbered lines (as generated in `process_files`) into contiguous, 1-based,
+/// end-exclusive line ranges.
+fn read_line_ranges(lines: &[String]) -> Vec<Range<usize>> {
+    lines
+        .iter()
+        .filter_map(|line| line.split_once(' ')?.0.parse::<usize>().ok())
+        .fold(Vec::<Range<usize>>::new(), |mut ranges, n| {
+            match ranges.last_mut() {
+                Some(prev) if prev.end == n => prev.end = n + 1,
+                _ => ranges.push(n..n + 1),
+            }
+
+            ranges
+        })
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
     use super::*;
+    use crate::{
+        agent::builder,
+        background::SyncHandle,
+        llm_gateway::mock::{call, Gateway, Reply, Script},
+        query::parser::SemanticQuery,
+        repo::{Backend, RepoRef, Repository},
+    };
 
     #[test]
     fn test_trim_lines_by_tokens() {
-        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo").unwrap();
+        let tokenizer = Tokenizer::new("gpt-3.5-turbo").unwrap();
 
         let lines = vec![
             "fn main() {".to_string(),
@@ -254,7 +726,7 @@ mod tests {
             "}".to_string(),
         ];
         assert_eq!(
-            trim_lines_by_tokens(lines, bpe.clone(), 15),
+            trim_lines_by_tokens(lines, &tokenizer, 15),
             vec![
                 "fn main() {".to_string(),
                 "    one();".to_string(),
@@ -266,11 +738,398 @@ mod tests {
 
         let lines = vec!["fn main() {".to_string(), "    one();".to_string()];
         assert_eq!(
-            trim_lines_by_tokens(lines, bpe.clone(), 15),
+            trim_lines_by_tokens(lines, &tokenizer, 15),
             vec!["fn main() {".to_string(), "    one();".to_string()]
         );
 
         let expected: Vec<String> = vec![];
-        assert_eq!(trim_lines_by_tokens(vec![], bpe, 15), expected);
+        assert_eq!(trim_lines_by_tokens(vec![], &tokenizer, 15), expected);
+    }
+
+    #[test]
+    fn test_fit_cjk_lines_to_tokens() {
+        let tokenizer = Tokenizer::new("gpt-3.5-turbo").unwrap();
+        let lines = include_str!("../fixtures/cjk_thread.txt")
+            .repeat(40)
+            .lines()
+            .enumerate()
+            .map(|(i, line)| format!("{} {line}", i + 1))
+            .collect::<Vec<_>>();
+
+        let token = CancellationToken::new();
+        let fitted = fit_lines_to_tokens(lines.clone(), &tokenizer, 15400, &token);
+        assert!(fitted.len() < lines.len());
+        assert!(tokenizer.count(&fitted.join("\n")) <= 15400);
+        assert_eq!(fitted[..], lines[..fitted.len()]);
+
+        // A single line is kept, even when it is over the budget on its own.
+        assert_eq!(
+            fit_lines_to_tokens(lines.clone(), &tokenizer, 1, &token),
+            lines[..1]
+        );
+    }
+
+    #[test]
+    fn test_read_line_ranges() {
+        let tokenizer = Tokenizer::new("gpt-3.5-turbo").unwrap();
+
+        let lines = [
+            "fn main() {",
+            "    one();",
+            "    two();",
+            "    three();",
+            "    four();",
+            "    five();",
+            "    six();",
+            "}",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, line)| format!("{} {line}", i + 1))
+        .collect::<Vec<_>>();
+
+        let chunk = trim_lines_by_tokens(lines.clone(), &tokenizer, 15400);
+        assert_eq!(read_line_ranges(&chunk), vec![1..9]);
+
+        assert_eq!(read_line_ranges(&[]), Vec::<Range<usize>>::new());
+    }
+
+    #[test]
+    fn test_split_lines_by_tokens() {
+        let tokenizer = Tokenizer::new("gpt-3.5-turbo").unwrap();
+        let token = CancellationToken::new();
+        let lines = (1..=100)
+            .map(|i| format!("{i} let x = {i};"))
+            .collect::<Vec<_>>();
+
+        // Parts follow each other, without overlapping or leaving lines out.
+        let parts = split_lines_by_tokens(lines.clone(), &tokenizer, 400, &token);
+        assert!(parts.len() > 1);
+        assert_eq!(parts.concat(), lines);
+        assert!(parts
+            .iter()
+            .all(|part| tokenizer.count(&part.join("\n")) <= 400));
+
+        // Files with more parts than `MAX_FILE_PARTS` are only read up to them.
+        let parts = split_lines_by_tokens(lines.clone(), &tokenizer, 10, &token);
+        assert_eq!(parts.len(), MAX_FILE_PARTS);
+        assert_eq!(parts.concat(), lines[..parts.concat().len()]);
+
+        assert!(split_lines_by_tokens(vec![], &tokenizer, 400, &token).is_empty());
+    }
+
+    /// Index a local repository of `files`, and run `proc` over all of them, with a model that
+    /// finds the first two lines of each part relevant.
+    async fn run_proc(files: &[(&str, String)]) -> (Exchange, Gateway) {
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+        let dir = crate::canonicalize(repo_dir.path()).unwrap();
+        for (path, content) in files {
+            std::fs::write(dir.join(path), content).unwrap();
+        }
+
+        let paths = (0..files.len()).collect::<Vec<_>>();
+        let script = Script::new(vec![
+            call(
+                "proc",
+                serde_json::json!({ "query": "where is x set", "paths": paths }),
+            ),
+            call("none", serde_json::json!({ "paths": [] })),
+        ]);
+        let gateway = Gateway::serve(move |request| {
+            if request.is_function_call() {
+                script.next()
+            } else if request.system().contains("Each line is numbered") {
+                Reply::text(r#"{"relevant_ranges": [[1, 3]]}"#)
+            } else {
+                Reply::text("x is set at the top of each file.")
+            }
+        });
+
+        let index_dir = tempdir::TempDir::new("bleep-agent").unwrap();
+        let config = serde_json::json!({ "answer_api_url": gateway.url });
+        let app = crate::webserver::tests::app(&index_dir, config).await;
+
+        let repo_ref = RepoRef::new(Backend::LocalDir, &dir.to_string_lossy()).unwrap();
+        let repo = Repository::local_from(&repo_ref);
+        app.repo_pool
+            .insert(repo_ref.clone(), repo.clone())
+            .unwrap();
+
+        let progress = tokio::sync::broadcast::channel(1).0;
+        let handle = SyncHandle::new(app.clone(), repo_ref.clone(), progress, None).await;
+        let writers = app.indexes.writers().await.unwrap();
+        writers.index(&handle, &repo).await.unwrap();
+        writers.commit().await.unwrap();
+
+        // An earlier exchange found the files, which the new one reads.
+        let mut found = Exchange::new(uuid::Uuid::new_v4(), SemanticQuery::default());
+        found.repo_ref = Some(repo_ref.clone());
+        found.paths = files.iter().map(|(path, _)| path.to_string()).collect();
+
+        let mut driver = builder::builder(app)
+            .repo(repo_ref)
+            .exchanges(vec![found])
+            .build()
+            .unwrap();
+        let exchange = driver.run("Where is x set?").await.unwrap();
+
+        (exchange, gateway)
+    }
+
+    /// The `proc` step of `exchange`, as its response and the lines read.
+    fn proc_step(exchange: &Exchange) -> (&str, &[Range<usize>]) {
+        let Some(SearchStep::Proc {
+            response,
+            lines_read,
+            ..
+        }) = exchange
+            .search_steps
+            .iter()
+            .find(|step| matches!(step, SearchStep::Proc { .. }))
+        else {
+            panic!("no proc step in {:?}", exchange.search_steps);
+        };
+
+        (response, lines_read)
+    }
+
+    #[tokio::test]
+    async fn test_lines_read() {
+        // `big.rs` is over the token limit of a single read, and `small.rs` is well under it.
+        let big = (1..=2000)
+            .map(|i| format!("let x = {i};\n"))
+            .collect::<String>();
+        let small = "fn small() {}\n".repeat(10);
+
+        let (exchange, gateway) = run_proc(&[("big.rs", big), ("small.rs", small)]).await;
+        let (_, lines_read) = proc_step(&exchange);
+
+        // The big file is read in two parts, which meet without overlapping, and the small file
+        // is read whole.
+        assert_eq!(lines_read.len(), 3, "{lines_read:?}");
+        assert_eq!(lines_read[0].start, 1);
+        assert_eq!(lines_read[0].end, lines_read[1].start);
+        assert_eq!(lines_read[1].end, 2001);
+        assert_eq!(lines_read[2], 1..11);
+
+        let reads = gateway.requests_where(|r| r.system().contains("Each line is numbered"));
+        assert_eq!(reads.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_files_are_reported() {
+        // `generated.rs` is indexed, but is over the size limit of files the agent reads.
+        let generated = "static TABLE: [u8; 4] = [0, 1, 2, 3];\n".repeat(4000);
+        assert!(generated.len() > crate::agent::DEFAULT_MAX_FILE_SIZE_BYTES);
+        let small = "fn small() {}\n".repeat(10);
+
+        let (exchange, gateway) =
+            run_proc(&[("generated.rs", generated), ("small.rs", small)]).await;
+        let (response, lines_read) = proc_step(&exchange);
+
+        // The model is told why the file was not read, rather than that it doesn't exist.
+        assert!(
+            response.contains("generated.rs\nThis file is too large to read"),
+            "{response}"
+        );
+        assert!(!response.contains("does not exist"), "{response}");
+        assert_eq!(lines_read, [1..11]);
+
+        let reads = gateway.requests_where(|r| r.system().contains("Each line is numbered"));
+        assert_eq!(reads.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_reads_time_out() {
+        let parent = CancellationToken::new();
+        let slow_token = Mutex::new(None);
+        let paths = [
+            "src/lib.rs",
+            "src/generated.rs",
+            "src/missing.rs",
+            "src/main.rs",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+
+        let extracted = extract_all(paths, &parent, Duration::from_millis(200), |path, token| {
+            let slow_token = &slow_token;
+            async move {
+                match path.as_str() {
+                    // A pathological file, whose extraction never finishes.
+                    "src/generated.rs" => {
+                        *slow_token.lock().unwrap() = Some(token);
+                        std::future::pending::<()>().await;
+                    }
+                    "src/missing.rs" => anyhow::bail!("path does not exist in the index: {path}"),
+                    _ => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+
+                Ok(path.len())
+            }
+        })
+        .await;
+
+        // The other files are read as usual, while the slow one is given up on.
+        assert_eq!(
+            extracted,
+            [
+                ("src/lib.rs".to_owned(), Extraction::Done(10)),
+                ("src/generated.rs".to_owned(), Extraction::TimedOut),
+                ("src/main.rs".to_owned(), Extraction::Done(11)),
+            ]
+        );
+
+        // Only the extraction that timed out is cancelled.
+        assert!(slow_token.lock().unwrap().as_ref().unwrap().is_cancelled());
+        assert!(!parent.is_cancelled());
+    }
+
+    #[test]
+    fn test_verdict_parse() {
+        assert_eq!(
+            Verdict::parse(r#"{"relevant_ranges": [[12, 15], [20, 22]]}"#).unwrap(),
+            Verdict::RelevantRanges(vec![
+                LineRange { start: 12, end: 15 },
+                LineRange { start: 20, end: 22 }
+            ])
+        );
+        assert_eq!(
+            Verdict::parse(r#" {"irrelevant": {"reason": "Only defines routes"}}"#).unwrap(),
+            Verdict::Irrelevant {
+                reason: "Only defines routes".into()
+            }
+        );
+        assert_eq!(
+            Verdict::parse("[[1,3]]\n").unwrap(),
+            Verdict::RelevantRanges(vec![LineRange { start: 1, end: 3 }])
+        );
+        assert_eq!(
+            Verdict::parse("[]").unwrap(),
+            Verdict::RelevantRanges(vec![])
+        );
+        assert!(Verdict::parse("Lines 1 to 3").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_irrelevant_files_are_not_read_again() {
+        let gateway = Gateway::serve(|_| {
+            Reply::text(r#"{"irrelevant": {"reason": "This file only defines routes"}}"#)
+        });
+        let client = gateway.client();
+
+        let mut exchange = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        let paths = vec!["src/routes.rs".to_owned(), "src/streams.rs".to_owned()];
+
+        // The first call reads both files, and the model finds one of them irrelevant.
+        let (unread, examined) = split_examined(&exchange, paths.clone());
+        assert_eq!(unread, paths);
+        assert!(examined.is_empty());
+
+        let messages = [llm_gateway::api::Message::system("find stream merging")];
+        let json = examine(&client, &messages).await.unwrap();
+        let Verdict::Irrelevant { reason } = Verdict::parse(&json).unwrap() else {
+            panic!("expected an irrelevant verdict, got {json}");
+        };
+        exchange.mark_irrelevant("src/routes.rs", &reason);
+        assert_eq!(gateway.requests().len(), 1);
+
+        // The next call reuses the verdict, without asking the model again.
+        let (unread, examined) = split_examined(&exchange, paths.clone());
+        assert_eq!(unread, ["src/streams.rs"]);
+        assert_eq!(
+            examined,
+            [(
+                "src/routes.rs".to_owned(),
+                "This file only defines routes".to_owned()
+            )]
+        );
+        assert_eq!(gateway.requests().len(), 1);
+
+        // A new exchange starts without any verdicts.
+        let next = Exchange::new(uuid::Uuid::nil(), SemanticQuery::default());
+        assert_eq!(split_examined(&next, paths.clone()).0, paths);
+    }
+
+    /// A mock gateway that gives `answers` in turn.
+    fn serve_answers(answers: Vec<&'static str>) -> Gateway {
+        let next = AtomicUsize::new(0);
+        Gateway::serve(move |_| Reply::text(answers[next.fetch_add(1, Ordering::SeqCst)]))
+    }
+
+    #[test]
+    fn test_proc_result_into_verdict() {
+        let result = serde_json::from_str::<ProcResult>(
+            r#"{"relevant_lines": [14, 12, 13, 20], "summary": "Reads the keys", "confidence": 0.8}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            result.into_verdict(),
+            Verdict::RelevantRanges(vec![
+                LineRange { start: 12, end: 15 },
+                LineRange { start: 20, end: 21 }
+            ])
+        );
+
+        let result = ProcResult {
+            relevant_lines: vec![],
+            summary: "Only defines routes".into(),
+            confidence: 0.9,
+        };
+        assert_eq!(
+            result.into_verdict(),
+            Verdict::Irrelevant {
+                reason: "Only defines routes".into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_examine_file_structured() {
+        let gateway = serve_answers(vec![
+            r#"{"relevant_lines": [2, 3], "summary": "Merges the streams", "confidence": 0.7}"#,
+        ]);
+
+        let (verdict, model_calls) = examine_file(
+            &gateway.client(),
+            true,
+            "merge streams",
+            "src/streams.rs",
+            "1 a\n2 b\n3 c",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            verdict.unwrap(),
+            Verdict::RelevantRanges(vec![LineRange { start: 2, end: 4 }])
+        );
+        assert_eq!(model_calls.len(), 1);
+        assert_eq!(gateway.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_examine_file_falls_back_to_line_ranges() {
+        let gateway = serve_answers(vec![
+            "Lines 2 and 3 merge the streams.",
+            r#"{"relevant_ranges": [[2, 4]]}"#,
+        ]);
+
+        let (verdict, model_calls) = examine_file(
+            &gateway.client(),
+            true,
+            "merge streams",
+            "src/streams.rs",
+            "1 a\n2 b\n3 c",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            verdict.unwrap(),
+            Verdict::RelevantRanges(vec![LineRange { start: 2, end: 4 }])
+        );
+        assert_eq!(model_calls.len(), 2);
+        assert_eq!(gateway.requests().len(), 2);
     }
 }
@@ -0,0 +1,242 @@
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use tracing::debug;
+
+use crate::{
+    agent::{
+        exchange::{PullRequestSummary, SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+    db::{PullRequest, PullRequests},
+};
+
+/// The maximum number of pull requests that a search returns.
+const MAX_RESULTS: usize = 5;
+
+/// Query terms shorter than this are ignored.
+const MIN_TERM_LEN: usize = 3;
+
+/// Terms and words of at least this length also match when one is a prefix of the other, so that
+/// "limiting" matches "limit".
+const MIN_PREFIX_LEN: usize = 4;
+
+const TITLE_WEIGHT: f32 = 2.0;
+const BODY_WEIGHT: f32 = 0.5;
+const FILE_WEIGHT: f32 = 1.0;
+
+/// The weight of each changed file that is already in the context of the conversation.
+const CONTEXT_WEIGHT: f32 = 1.0;
+
+impl Agent {
+    pub async fn pr_search(&mut self, query: &String) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::Prs {
+            query: query.clone(),
+            pull_requests: Vec::new(),
+        }))
+        .await?;
+
+        let open = PullRequests::new(&self.app.sql)
+            .for_repo(&self.repo_ref.to_string())
+            .await?;
+
+        let paths = self.paths();
+        let context = self
+            .repo_aliases()
+            .into_iter()
+            .map(|i| paths[i].clone())
+            .collect::<Vec<_>>();
+
+        let pull_requests = match_pull_requests(query, &context, &open);
+        debug!(
+            open = open.len(),
+            matches = pull_requests.len(),
+            "searched pull requests"
+        );
+
+        let step = SearchStep::Prs {
+            query: query.clone(),
+            pull_requests: pull_requests.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("pull request search")
+                .with_payload("query", query)
+                .with_payload("open", open.len())
+                .with_payload("results", &pull_requests)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Rank open pull requests by how well they match `query`, and by how many of the files in
+/// `context` they change.
+///
+/// Query terms are matched against the words of each pull request's title, body and changed file
+/// paths. Pull requests that match nothing are left out.
+fn match_pull_requests(
+    query: &str,
+    context: &[String],
+    open: &[PullRequest],
+) -> Vec<PullRequestSummary> {
+    let terms = words(query)
+        .filter(|t| t.chars().count() >= MIN_TERM_LEN)
+        .collect::<Vec<_>>();
+
+    let matches_any = |text: &str| {
+        let text = words(text).collect::<Vec<_>>();
+        terms
+            .iter()
+            .filter(|t| text.iter().any(|w| term_matches(t, w)))
+            .count()
+    };
+
+    let mut scored = open
+        .iter()
+        .filter_map(|pr| {
+            let mut score = TITLE_WEIGHT * matches_any(&pr.title) as f32
+                + BODY_WEIGHT * matches_any(&pr.body) as f32;
+
+            let mut matched_files = Vec::new();
+            for file in &pr.changed_files {
+                let in_context = context.contains(file);
+                let file_terms = matches_any(file);
+
+                if in_context || file_terms > 0 {
+                    matched_files.push(file.clone());
+                }
+
+                score += FILE_WEIGHT * file_terms.min(1) as f32;
+                if in_context {
+                    score += CONTEXT_WEIGHT;
+                }
+            }
+
+            (score > 0.0).then(|| {
+                let summary = PullRequestSummary {
+                    number: pr.number,
+                    title: pr.title.clone(),
+                    author: pr.author.clone(),
+                    url: pr.url.clone(),
+                    matched_files,
+                };
+
+                (score, summary)
+            })
+        })
+        .collect::<Vec<_>>();
+
+    scored.sort_by(|(a, pa), (b, pb)| {
+        b.partial_cmp(a)
+            .unwrap_or(Ordering::Equal)
+            .then(pb.number.cmp(&pa.number))
+    });
+
+    scored
+        .into_iter()
+        .take(MAX_RESULTS)
+        .map(|(_, summary)| summary)
+        .collect()
+}
+
+/// The lowercase alphanumeric words of `text`, splitting paths and identifiers on punctuation.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+fn term_matches(term: &str, word: &str) -> bool {
+    term == word
+        || (term.len().min(word.len()) >= MIN_PREFIX_LEN
+            && (word.starts_with(term) || term.starts_with(word)))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn pr(number: i64, title: &str, body: &str, changed_files: &[&str]) -> PullRequest {
+        PullRequest {
+            number,
+            title: title.to_owned(),
+            body: body.to_owned(),
+            author: "alice".to_owned(),
+            url: format!("https://github.com/bloopai/bloop/pull/{number}"),
+            changed_files: changed_files.iter().map(|f| f.to_string()).collect(),
+            updated_at: "2026-10-01T00:00:00Z".to_owned(),
+        }
+    }
+
+    fn numbers(matches: &[PullRequestSummary]) -> Vec<i64> {
+        matches.iter().map(|m| m.number).collect()
+    }
+
+    #[test]
+    fn test_match_pull_requests() {
+        let open = [
+            pr(
+                1,
+                "Add a rate limiter to the answer endpoint",
+                "",
+                &["server/bleep/src/webserver/answer.rs"],
+            ),
+            pr(
+                2,
+                "Fix typos",
+                "Mentions the rate of indexing.",
+                &["README.md"],
+            ),
+            pr(
+                3,
+                "Refactor middleware",
+                "",
+                &["server/bleep/src/webserver/rate_limit.rs"],
+            ),
+            pr(4, "Bump dependencies", "", &["Cargo.toml"]),
+        ];
+
+        // Title matches outrank changed files, which outrank the body.
+        let matches = match_pull_requests("rate limiting", &[], &open);
+        assert_eq!(numbers(&matches), [1, 3, 2]);
+        assert_eq!(matches[0].matched_files, Vec::<String>::new());
+        assert_eq!(
+            matches[1].matched_files,
+            ["server/bleep/src/webserver/rate_limit.rs"]
+        );
+
+        assert!(match_pull_requests("tantivy", &[], &open).is_empty());
+        assert!(match_pull_requests("a", &[], &open).is_empty());
+    }
+
+    #[test]
+    fn test_match_context_files() {
+        let open = [
+            pr(
+                1,
+                "Refactor middleware",
+                "",
+                &["server/bleep/src/webserver.rs"],
+            ),
+            pr(2, "Middleware tweaks", "", &["server/bleep/src/lib.rs"]),
+            pr(3, "Unrelated", "", &["client/src/App.tsx"]),
+        ];
+
+        // Pull requests that touch files in context rank higher, even without matching terms.
+        let context = ["server/bleep/src/webserver.rs".to_owned()];
+        let matches = match_pull_requests("middleware", &context, &open);
+        assert_eq!(numbers(&matches), [1, 2]);
+        assert_eq!(matches[0].matched_files, ["server/bleep/src/webserver.rs"]);
+
+        let matches = match_pull_requests("nothing", &context, &open);
+        assert_eq!(numbers(&matches), [1]);
+    }
+}
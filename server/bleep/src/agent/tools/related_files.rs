@@ -0,0 +1,508 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use lazy_regex::regex;
+
+use crate::{
+    agent::{
+        exchange::{ContextSource, SearchStep, Update},
+        Agent,
+    },
+    analytics::EventData,
+    indexes::reader::ContentDocument,
+};
+
+/// The maximum number of related files returned.
+const MAX_RELATED: usize = 20;
+
+/// The number of symbols of a file that are used to search for semantically similar files.
+const MAX_SYMBOLS: usize = 8;
+
+impl Agent {
+    pub async fn related_files(&mut self, paths: &[String]) -> Result<String> {
+        const SEMANTIC_LIMIT: u64 = 10;
+
+        self.update(Update::StartStep(SearchStep::RelatedFiles {
+            paths: paths.to_vec(),
+            related: Vec::new(),
+        }))
+        .await?;
+
+        let branch = self.branch();
+        let wanted = paths.to_vec();
+        let (graph, symbols) = self
+            .app
+            .indexes
+            .file
+            .scan_files(&self.repo_ref, branch.as_deref(), move |docs| {
+                let mut symbols = Vec::new();
+                let graph = ImportGraph::new(docs.map(|doc| {
+                    if wanted.contains(&doc.relative_path) {
+                        symbols.push(key_symbols(&doc));
+                    }
+
+                    let targets = imports(&doc.relative_path, &doc.content);
+                    (doc.relative_path, targets)
+                }));
+                (graph, symbols)
+            })
+            .await?;
+
+        let mut related = paths
+            .iter()
+            .flat_map(|path| graph.importers(path).chain(graph.imports(path)))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+
+        if self.app.semantic.is_some() {
+            for names in symbols {
+                let query = names.join(" ");
+                if query.is_empty() {
+                    continue;
+                }
+
+                let results = self
+                    .semantic_search((&query).into(), SEMANTIC_LIMIT, 0, 0.0, true)
+                    .await?;
+                related.extend(results.into_iter().map(|chunk| chunk.relative_path));
+            }
+        }
+
+        let related = dedup(related, paths);
+        for path in &related {
+            self.get_path_alias(path);
+            self.last_exchange_mut()
+                .include_context(path, ContextSource::RelatedFiles, &[], false);
+        }
+
+        let step = SearchStep::RelatedFiles {
+            paths: paths.to_vec(),
+            related: related.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("related files")
+                .with_payload("paths", paths)
+                .with_payload("results", &related)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// Keep the first occurrence of each path that is not one of `exclude`, up to `MAX_RELATED`.
+fn dedup(paths: Vec<String>, exclude: &[String]) -> Vec<String> {
+    let mut seen = exclude.iter().cloned().collect::<HashSet<_>>();
+    paths
+        .into_iter()
+        .filter(|path| seen.insert(path.clone()))
+        .take(MAX_RELATED)
+        .collect()
+}
+
+/// The names of the first few symbols defined in a file.
+fn key_symbols(doc: &ContentDocument) -> Vec<String> {
+    let mut names = Vec::<String>::new();
+
+    for symbol in doc.symbol_locations.list() {
+        let Some(name) = doc
+            .content
+            .get(symbol.range.start.byte..symbol.range.end.byte)
+        else {
+            continue;
+        };
+
+        // Very short names like `i` or `id` don't say much about what a file does.
+        if name.len() >= 3 && !names.iter().any(|n| n == name) {
+            names.push(name.to_owned());
+        }
+
+        if names.len() == MAX_SYMBOLS {
+            break;
+        }
+    }
+
+    names
+}
+
+/// Imports between the files of a repository, resolved to the paths of the imported files.
+///
+/// Imports are found with regular expressions rather than a full parse, and imports of external
+/// packages are ignored.
+struct ImportGraph {
+    /// `(importer, imported)` pairs.
+    edges: Vec<(String, String)>,
+}
+
+impl ImportGraph {
+    /// Resolve the imports of each file, as found by `imports`, among the paths of all files.
+    fn new(files: impl Iterator<Item = (String, Vec<Target>)>) -> Self {
+        let files = files.collect::<Vec<_>>();
+        let paths = files
+            .iter()
+            .map(|(path, _)| path.as_str())
+            .collect::<HashSet<_>>();
+
+        let mut edges = files
+            .iter()
+            .flat_map(|(path, targets)| {
+                let paths = &paths;
+                targets
+                    .iter()
+                    .filter_map(move |target| target.resolve(paths))
+                    .filter(move |imported| *imported != path.as_str())
+                    .map(move |imported| (path.clone(), imported.to_owned()))
+            })
+            .collect::<Vec<_>>();
+
+        edges.sort();
+        edges.dedup();
+
+        Self { edges }
+    }
+
+    /// Files that import `path`.
+    fn importers<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.edges
+            .iter()
+            .filter(move |(_, imported)| imported == path)
+            .map(|(importer, _)| importer.as_str())
+    }
+
+    /// Files that `path` imports.
+    fn imports<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.edges
+            .iter()
+            .filter(move |(importer, _)| importer == path)
+            .map(|(_, imported)| imported.as_str())
+    }
+}
+
+/// Candidate paths for an import, in order of preference.
+#[derive(Debug, PartialEq)]
+enum Target {
+    /// Paths relative to the repository root.
+    Exact(Vec<String>),
+    /// Trailing path components, for imports that are relative to an unknown source root.
+    Suffix(Vec<String>),
+}
+
+impl Target {
+    fn resolve<'a>(&self, paths: &HashSet<&'a str>) -> Option<&'a str> {
+        match self {
+            Self::Exact(candidates) => candidates
+                .iter()
+                .find_map(|c| paths.get(c.as_str()).copied()),
+            Self::Suffix(candidates) => candidates.iter().find_map(|c| {
+                let suffix = format!("/{c}");
+                let mut matches = paths
+                    .iter()
+                    .copied()
+                    .filter(|p| *p == c || p.ends_with(&suffix))
+                    .collect::<Vec<_>>();
+
+                // Prefer the shortest match, as it is closest to a source root.
+                matches.sort_by_key(|p| (p.len(), *p));
+                matches.first().copied()
+            }),
+        }
+    }
+}
+
+/// The imports of a file, based on its extension.
+fn imports(path: &str, content: &str) -> Vec<Target> {
+    let (dir, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+    let extension = file_name.rsplit_once('.').map_or("", |(_, ext)| ext);
+
+    match extension {
+        "js" | "jsx" | "ts" | "tsx" | "mjs" | "cjs" => javascript_imports(dir, content),
+        "py" => python_imports(dir, content),
+        "rs" => rust_imports(dir, file_name, content),
+        "java" | "kt" => jvm_imports(content),
+        _ => Vec::new(),
+    }
+}
+
+fn javascript_imports(dir: &str, content: &str) -> Vec<Target> {
+    const SUFFIXES: &[&str] = &[
+        "",
+        ".ts",
+        ".tsx",
+        ".js",
+        ".jsx",
+        ".mjs",
+        ".cjs",
+        "/index.ts",
+        "/index.tsx",
+        "/index.js",
+        "/index.jsx",
+    ];
+
+    regex!(
+        r#"\b(?:from|import)\s*['"]([^'"]+)['"]|\b(?:require|import)\s*\(\s*['"]([^'"]+)['"]\s*\)"#
+    )
+    .captures_iter(content)
+    .filter_map(|c| c.get(1).or_else(|| c.get(2)))
+    .map(|m| m.as_str())
+    // Other specifiers refer to packages, or to aliases configured in the bundler.
+    .filter(|specifier| specifier.starts_with('.'))
+    .map(|specifier| {
+        let base = join(dir, specifier);
+        Target::Exact(SUFFIXES.iter().map(|s| format!("{base}{s}")).collect())
+    })
+    .collect()
+}
+
+fn python_imports(dir: &str, content: &str) -> Vec<Target> {
+    let module_candidates = |module: &str| {
+        let module = module.replace('.', "/");
+        vec![format!("{module}.py"), format!("{module}/__init__.py")]
+    };
+
+    let mut targets = Vec::new();
+
+    for line in content.lines() {
+        if let Some(c) = regex!(r"^\s*from\s+(\.*)([\w.]*)\s+import\s+\(?([\w\s,]+)").captures(line)
+        {
+            let module = &c[2];
+            let names = c[3]
+                .split(',')
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .map(|n| n.split_whitespace().next().unwrap_or(n))
+                .collect::<Vec<_>>();
+
+            // Imported names may be submodules, so these are tried first.
+            let mut modules = names
+                .iter()
+                .map(|name| match module {
+                    "" => name.to_string(),
+                    module => format!("{module}.{name}"),
+                })
+                .collect::<Vec<_>>();
+            if !module.is_empty() {
+                modules.push(module.to_owned());
+            }
+
+            let dots = c[1].len();
+            for module in modules {
+                targets.push(if dots == 0 {
+                    Target::Suffix(module_candidates(&module))
+                } else {
+                    let base = (1..dots).fold(dir.to_owned(), |dir, _| join(&dir, ".."));
+                    Target::Exact(
+                        module_candidates(&module)
+                            .into_iter()
+                            .map(|m| join(&base, &m))
+                            .collect(),
+                    )
+                });
+            }
+        } else if let Some(c) = regex!(r"^\s*import\s+([\w.]+(?:\s*,\s*[\w.]+)*)").captures(line) {
+            targets.extend(
+                c[1].split(',')
+                    .map(|module| Target::Suffix(module_candidates(module.trim()))),
+            );
+        }
+    }
+
+    targets
+}
+
+fn rust_imports(dir: &str, file_name: &str, content: &str) -> Vec<Target> {
+    // Submodules of `foo.rs` live in `foo/`, while those of `mod.rs` and crate roots live next to
+    // them.
+    let module_dir = match file_name {
+        "lib.rs" | "main.rs" | "mod.rs" => dir.to_owned(),
+        name => join(dir, name.trim_end_matches(".rs")),
+    };
+
+    let mut targets = regex!(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?mod\s+(\w+)\s*;")
+        .captures_iter(content)
+        .map(|c| {
+            let module = join(&module_dir, &c[1]);
+            Target::Exact(vec![format!("{module}.rs"), format!("{module}/mod.rs")])
+        })
+        .collect::<Vec<_>>();
+
+    // Paths in `use` declarations are relative to the crate root, which isn't known. Longer
+    // module paths are tried first, as trailing segments may be items rather than modules.
+    targets.extend(
+        regex!(r"\buse\s+(?:crate|super|self)((?:::\w+)+)")
+            .captures_iter(content)
+            .map(|c| {
+                let segments = c[1]
+                    .trim_start_matches("::")
+                    .split("::")
+                    .collect::<Vec<_>>();
+                Target::Suffix(
+                    (1..=segments.len())
+                        .rev()
+                        .flat_map(|n| {
+                            let module = segments[..n].join("/");
+                            [format!("{module}.rs"), format!("{module}/mod.rs")]
+                        })
+                        .collect(),
+                )
+            }),
+    );
+
+    targets
+}
+
+fn jvm_imports(content: &str) -> Vec<Target> {
+    regex!(r"(?m)^\s*import\s+(?:static\s+)?([\w.]+)")
+        .captures_iter(content)
+        .map(|c| {
+            let segments = c[1].split('.').collect::<Vec<_>>();
+
+            // Static imports name a member of the imported class, so fall back to the parent.
+            Target::Suffix(
+                [segments.len(), segments.len() - 1]
+                    .into_iter()
+                    .filter(|n| *n > 0)
+                    .flat_map(|n| {
+                        let class = segments[..n].join("/");
+                        [format!("{class}.java"), format!("{class}.kt")]
+                    })
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Join a relative path onto a directory, resolving `.` and `..` components.
+fn join(dir: &str, relative: &str) -> String {
+    let mut components = dir.split('/').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+
+    for component in relative.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+
+    components.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn graph(files: &[(&str, &str)]) -> ImportGraph {
+        ImportGraph::new(
+            files
+                .iter()
+                .map(|(path, content)| (path.to_string(), imports(path, content))),
+        )
+    }
+
+    fn related(graph: &ImportGraph, path: &str) -> Vec<String> {
+        dedup(
+            graph
+                .importers(path)
+                .chain(graph.imports(path))
+                .map(str::to_owned)
+                .collect(),
+            &[path.to_owned()],
+        )
+    }
+
+    #[test]
+    fn test_mutual_imports() {
+        let files = [
+            (
+                "client/src/a.ts",
+                "import { b } from './b';\nexport const a = () => b();\n",
+            ),
+            (
+                "client/src/b.ts",
+                "import { a } from \"./a\";\nexport const b = () => a();\n",
+            ),
+            ("client/src/c.ts", "import React from 'react';\n"),
+        ];
+        let graph = graph(&files);
+
+        assert_eq!(related(&graph, "client/src/a.ts"), ["client/src/b.ts"]);
+        assert_eq!(related(&graph, "client/src/b.ts"), ["client/src/a.ts"]);
+        assert_eq!(related(&graph, "client/src/c.ts"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_resolution() {
+        let files = [
+            ("server/src/lib.rs", "pub mod agent;\nmod db;\n"),
+            (
+                "server/src/agent.rs",
+                "mod tools;\nuse crate::db::{SqlDb, init};\n",
+            ),
+            ("server/src/agent/tools.rs", ""),
+            ("server/src/db.rs", ""),
+            (
+                "app/main.py",
+                "from .models import User\nimport app.views\n",
+            ),
+            ("app/models.py", ""),
+            ("app/views/__init__.py", "from .. import main\n"),
+            (
+                "client/src/index.tsx",
+                "import App from './components';\nconst x = require('../lib/util');\n",
+            ),
+            ("client/src/components/index.tsx", ""),
+            ("client/lib/util.js", ""),
+            (
+                "src/main/java/com/example/Main.java",
+                "import com.example.util.Strings;\nimport static com.example.util.Strings.join;\n",
+            ),
+            ("src/main/java/com/example/util/Strings.java", ""),
+        ];
+        let graph = graph(&files);
+
+        let imports = |path: &'static str| graph.imports(path).collect::<Vec<_>>();
+
+        assert_eq!(
+            imports("server/src/lib.rs"),
+            ["server/src/agent.rs", "server/src/db.rs"]
+        );
+        assert_eq!(
+            imports("server/src/agent.rs"),
+            ["server/src/agent/tools.rs", "server/src/db.rs"]
+        );
+        assert_eq!(
+            imports("app/main.py"),
+            ["app/models.py", "app/views/__init__.py"]
+        );
+        assert_eq!(imports("app/views/__init__.py"), ["app/main.py"]);
+        assert_eq!(
+            imports("client/src/index.tsx"),
+            ["client/lib/util.js", "client/src/components/index.tsx"]
+        );
+        assert_eq!(
+            imports("src/main/java/com/example/Main.java"),
+            ["src/main/java/com/example/util/Strings.java"]
+        );
+
+        assert_eq!(
+            graph.importers("server/src/db.rs").collect::<Vec<_>>(),
+            ["server/src/agent.rs", "server/src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        let paths = ["a", "b", "a", "c"].map(str::to_owned).to_vec();
+        assert_eq!(dedup(paths, &["b".to_owned()]), ["a", "c"]);
+
+        let many = (0..30).map(|i| i.to_string()).collect();
+        assert_eq!(dedup(many, &[]).len(), MAX_RELATED);
+    }
+}
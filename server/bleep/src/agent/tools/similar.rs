@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    agent::{
+        exchange::{ContextSource, SearchStep, SimilarFile, Update},
+        Agent,
+    },
+    analytics::EventData,
+    query::parser::{self, SemanticQuery},
+    semantic::{store::VectorStore, Embedding},
+};
+
+/// The maximum number of similar files returned.
+const MAX_SIMILAR: usize = 10;
+
+/// The number of chunks that are searched for, before they are grouped by file.
+const CHUNK_LIMIT: u64 = 100;
+
+/// The number of characters at the start of a file that it is embedded by.
+///
+/// The embedding model truncates its input anyway, so embedding more of the file only costs time.
+const MAX_EMBEDDED_CHARS: usize = 4096;
+
+impl Agent {
+    pub async fn find_similar(&mut self, path: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::FindSimilar {
+            path: path.to_owned(),
+            similar: Vec::new(),
+        }))
+        .await?;
+
+        let semantic = self
+            .app
+            .semantic
+            .clone()
+            .context("semantic search is not available")?;
+
+        let content = self
+            .get_file_content(path)
+            .await?
+            .with_context(|| format!("path does not exist in the index: {path}"))?
+            .content;
+
+        let vector =
+            semantic.embed(&content.chars().take(MAX_EMBEDDED_CHARS).collect::<String>())?;
+        let query = self.semantic_query(parser::Literal::Plain(path.into()));
+        let similar = similar_files(semantic.store(), &query, vector, path).await?;
+
+        for file in &similar {
+            self.get_path_alias(&file.path);
+            self.last_exchange_mut().include_context(
+                &file.path,
+                ContextSource::RelatedFiles,
+                &[],
+                false,
+            );
+        }
+
+        let step = SearchStep::FindSimilar {
+            path: path.to_owned(),
+            similar: similar.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("find similar")
+                .with_payload("path", path)
+                .with_payload("results", &similar)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// The files whose chunks are closest to `vector`, other than `path` itself, most similar first.
+///
+/// A file is as similar as its most similar chunk, so that long files aren't favoured for having
+/// many chunks.
+async fn similar_files(
+    store: &dyn VectorStore,
+    query: &SemanticQuery<'_>,
+    vector: Embedding,
+    path: &str,
+) -> Result<Vec<SimilarFile>> {
+    let chunks = store.search(query, vector, CHUNK_LIMIT, 0, 0.0).await?;
+
+    let mut scores = HashMap::<String, f32>::new();
+    for chunk in chunks {
+        if chunk.relative_path == path {
+            continue;
+        }
+
+        let score = chunk.score.unwrap_or_default();
+        scores
+            .entry(chunk.relative_path)
+            .and_modify(|s| *s = s.max(score))
+            .or_insert(score);
+    }
+
+    let mut similar = scores
+        .into_iter()
+        .map(|(path, similarity)| SimilarFile { path, similarity })
+        .collect::<Vec<_>>();
+
+    similar.sort_by(|a, b| {
+        b.similarity
+            .total_cmp(&a.similarity)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    similar.truncate(MAX_SIMILAR);
+
+    Ok(similar)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::semantic::{store::Point, Payload};
+
+    /// A store that returns the same chunks for any search.
+    struct MockStore(Vec<(String, f32)>);
+
+    impl MockStore {
+        fn new<'a>(chunks: impl IntoIterator<Item = (&'a str, f32)>) -> Self {
+            Self(
+                chunks
+                    .into_iter()
+                    .map(|(path, score)| (path.to_owned(), score))
+                    .collect(),
+            )
+        }
+    }
+
+    #[async_trait]
+    impl VectorStore for MockStore {
+        async fn health_check(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn search(
+            &self,
+            _query: &SemanticQuery<'_>,
+            _vector: Embedding,
+            limit: u64,
+            _offset: u64,
+            _threshold: f32,
+        ) -> Result<Vec<Payload>> {
+            Ok(self
+                .0
+                .iter()
+                .take(limit as usize)
+                .map(|(path, score)| Payload {
+                    relative_path: path.clone(),
+                    score: Some(*score),
+                    ..Default::default()
+                })
+                .collect())
+        }
+
+        async fn upsert(&self, _points: Vec<Point>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn delete(&self, _ids: Vec<String>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn set_branches(&self, _ids: Vec<String>, _branches: Vec<String>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn delete_files(&self, _repo_ref: &str, _file_hashes: Vec<String>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_similar_files() {
+        let store = MockStore::new([
+            ("src/retry.rs", 0.99),
+            ("src/backoff.rs", 0.71),
+            ("src/retry.rs", 0.95),
+            ("src/queue.rs", 0.42),
+            ("src/backoff.rs", 0.83),
+            ("src/timer.rs", 0.83),
+        ]);
+
+        let similar = similar_files(
+            &store,
+            &SemanticQuery::default(),
+            vec![0.0; 4],
+            "src/retry.rs",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            similar,
+            [
+                SimilarFile {
+                    path: "src/backoff.rs".into(),
+                    similarity: 0.83,
+                },
+                SimilarFile {
+                    path: "src/timer.rs".into(),
+                    similarity: 0.83,
+                },
+                SimilarFile {
+                    path: "src/queue.rs".into(),
+                    similarity: 0.42,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_similar_files_limit() {
+        let paths = (0..20)
+            .map(|i| format!("src/{i:02}.rs"))
+            .collect::<Vec<_>>();
+        let store = MockStore::new(
+            paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| (path.as_str(), i as f32 / 20.0)),
+        );
+
+        let similar = similar_files(&store, &SemanticQuery::default(), vec![0.0; 4], "src/19.rs")
+            .await
+            .unwrap();
+
+        assert_eq!(similar.len(), MAX_SIMILAR);
+        assert_eq!(similar[0].path, "src/18.rs");
+        assert_eq!(similar[9].path, "src/09.rs");
+    }
+}
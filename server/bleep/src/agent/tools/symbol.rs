@@ -0,0 +1,434 @@
+//! Finds functions and types by name, across naming conventions and small typos.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use lazy_regex::regex;
+
+use super::dead_code::DEFINITION_KINDS;
+use crate::{
+    agent::{
+        exchange::{SearchStep, SymbolMatch, Update},
+        Agent,
+    },
+    analytics::EventData,
+    indexes::reader::ContentDocument,
+};
+
+/// The maximum number of symbols returned.
+const MAX_SYMBOLS: usize = 30;
+
+impl Agent {
+    pub async fn symbol_search(&mut self, query: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::Symbol {
+            query: query.to_owned(),
+            symbols: Vec::new(),
+        }))
+        .await?;
+
+        let branch = self.branch();
+        let symbols = {
+            let query = query.to_owned();
+            self.app
+                .indexes
+                .file
+                .scan_files(&self.repo_ref, branch.as_deref(), move |docs| {
+                    find_symbols(docs, &query)
+                })
+                .await?
+        };
+
+        let step = SearchStep::Symbol {
+            query: query.to_owned(),
+            symbols: symbols.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("symbol search")
+                .with_payload("query", query)
+                .with_payload("results", &symbols)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// How well a symbol matches a query, where lower ranks sort first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Rank {
+    /// Whether the query's subwords were found out of order or apart, rather than as a run.
+    scattered: bool,
+    /// The total edit distance between the query's subwords and the subwords they matched.
+    typos: usize,
+    private: bool,
+    /// The number of subwords in the symbol's name that the query didn't match.
+    extra: usize,
+}
+
+/// Functions and types in `docs` whose names contain every subword of `query`, allowing for
+/// typos.
+///
+/// Definitions that share a name are kept together, and names are ordered by their best match.
+fn find_symbols(docs: impl IntoIterator<Item = ContentDocument>, query: &str) -> Vec<SymbolMatch> {
+    let query = subwords(query);
+    if query.is_empty() {
+        return Vec::new();
+    }
+
+    let mut matches = Vec::new();
+    for doc in docs {
+        let lang = doc.lang.as_deref().unwrap_or_default();
+
+        for symbol in doc.symbol_locations.list() {
+            if !DEFINITION_KINDS.contains(&symbol.kind.as_str()) {
+                continue;
+            }
+
+            let start = symbol.range.start.byte;
+            let Some(name) = doc.content.get(start..symbol.range.end.byte) else {
+                continue;
+            };
+
+            let line_start = doc.content[..start].rfind('\n').map_or(0, |i| i + 1);
+            let public = is_public(lang, &doc.content[line_start..start], name);
+
+            if let Some(rank) = rank(&query, &subwords(name), public) {
+                matches.push((
+                    rank,
+                    SymbolMatch {
+                        name: name.to_owned(),
+                        kind: symbol.kind.clone(),
+                        path: doc.relative_path.clone(),
+                        line: symbol.range.start.line + 1,
+                        public,
+                    },
+                ));
+            }
+        }
+    }
+
+    let mut best = HashMap::<String, Rank>::new();
+    for (rank, symbol) in &matches {
+        best.entry(symbol.name.clone())
+            .and_modify(|best| *best = (*best).min(*rank))
+            .or_insert(*rank);
+    }
+
+    matches.sort_by_cached_key(|(rank, symbol)| {
+        let name = &symbol.name;
+        (
+            best[name],
+            name.clone(),
+            *rank,
+            symbol.path.clone(),
+            symbol.line,
+        )
+    });
+
+    matches
+        .into_iter()
+        .take(MAX_SYMBOLS)
+        .map(|(_, symbol)| symbol)
+        .collect()
+}
+
+/// Split an identifier into lowercase subwords, at underscores, dashes and other punctuation, and
+/// where its case changes, so that `getUserById`, `get_user_by_id` and `get-user-by-id` have the
+/// same subwords.
+///
+/// A run of capitals is one subword, so `HTTPServer` is `http` and `server`.
+pub fn subwords(ident: &str) -> Vec<String> {
+    let chars = ident.chars().collect::<Vec<_>>();
+    let mut words = Vec::new();
+    let mut word = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !word.is_empty() {
+                words.push(std::mem::take(&mut word));
+            }
+            continue;
+        }
+
+        let prev = i.checked_sub(1).map(|i| chars[i]);
+        let next = chars.get(i + 1);
+        let starts_word = c.is_uppercase()
+            && prev.map_or(false, |p| {
+                p.is_lowercase()
+                    || p.is_numeric()
+                    || (p.is_uppercase() && next.map_or(false, |n| n.is_lowercase()))
+            });
+
+        if starts_word && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+
+        word.extend(c.to_lowercase());
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Rank a symbol with subwords `name` against the subwords of a query, or `None` if one of the
+/// query's subwords matches none of the name's.
+///
+/// Each query subword takes the closest subword of the name that no earlier one took.
+fn rank(query: &[String], name: &[String], public: bool) -> Option<Rank> {
+    let mut taken = vec![false; name.len()];
+    let mut positions = Vec::with_capacity(query.len());
+    let mut typos = 0;
+
+    for word in query {
+        let (i, distance) = name
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !taken[*i])
+            .map(|(i, n)| (i, edit_distance(word, n)))
+            .filter(|(_, distance)| *distance <= max_typos(word))
+            .min_by_key(|(_, distance)| *distance)?;
+
+        taken[i] = true;
+        positions.push(i);
+        typos += distance;
+    }
+
+    Some(Rank {
+        scattered: positions.windows(2).any(|w| w[1] != w[0] + 1),
+        typos,
+        private: !public,
+        extra: name.len() - query.len(),
+    })
+}
+
+/// The number of typos that a subword may have: none in short words, where a single edit often
+/// makes another word.
+fn max_typos(word: &str) -> usize {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+/// The number of insertions, deletions, substitutions and swaps of adjacent characters that turn
+/// `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+
+    // `d[i][j]` is the distance between the first `i` characters of `a` and `j` of `b`.
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// Whether a symbol can be used outside of the module that defines it, judged by its name and the
+/// text before it on the line that defines it.
+fn is_public(lang: &str, prefix: &str, name: &str) -> bool {
+    match lang.to_lowercase().as_str() {
+        "rust" => regex!(r"\bpub\b").is_match(prefix),
+        "javascript" | "jsx" | "typescript" | "tsx" => regex!(r"\bexport\b").is_match(prefix),
+        "python" => !name.starts_with('_'),
+        "go" => name.starts_with(char::is_uppercase),
+        _ => !regex!(r"\b(private|protected|internal)\b").is_match(prefix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{intelligence::TreeSitterFile, symbol::SymbolLocations};
+
+    const FIXTURE: &[(&str, &str, &str)] = &[
+        (
+            "src/users.rs",
+            "Rust",
+            "pub struct User {\n\
+             \x20   pub id: u64,\n\
+             }\n\
+             \n\
+             pub fn get_user_by_id(id: u64) -> Option<User> {\n\
+             \x20   None\n\
+             }\n\
+             \n\
+             fn get_user_name_by_id(id: u64) -> String {\n\
+             \x20   String::new()\n\
+             }\n",
+        ),
+        (
+            "client/api.ts",
+            "TypeScript",
+            "export function getUserById(id: number) {\n\
+             \x20 return fetch(`/users/${id}`);\n\
+             }\n",
+        ),
+        (
+            "scripts/users.py",
+            "Python",
+            "def get_user_by_id(user_id):\n\
+             \x20   return db.users.find(user_id)\n\
+             \n\
+             \n\
+             class UserCache:\n\
+             \x20   def _get_user_by_id(self, user_id):\n\
+             \x20       return self.cache.get(user_id)\n",
+        ),
+    ];
+
+    fn doc(relative_path: &str, lang: &str, content: &str) -> ContentDocument {
+        let symbol_locations = TreeSitterFile::try_build(content.as_bytes(), lang)
+            .and_then(TreeSitterFile::scope_graph)
+            .map(SymbolLocations::TreeSitter)
+            .unwrap();
+
+        ContentDocument {
+            content: content.to_owned(),
+            lang: Some(lang.to_owned()),
+            relative_path: relative_path.to_owned(),
+            repo_name: "fixture".to_owned(),
+            repo_ref: "local//fixture".to_owned(),
+            line_end_indices: content.match_indices('\n').map(|(i, _)| i as u32).collect(),
+            symbol_locations,
+            branches: None,
+            lfs_pointer: false,
+        }
+    }
+
+    fn fixture() -> Vec<ContentDocument> {
+        FIXTURE
+            .iter()
+            .map(|(path, lang, content)| doc(path, lang, content))
+            .collect()
+    }
+
+    fn found(query: &str) -> Vec<String> {
+        find_symbols(fixture(), query)
+            .into_iter()
+            .map(|s| format!("{}:{} {}", s.path, s.line, s.name))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_symbols() {
+        let expected = [
+            "client/api.ts:1 getUserById",
+            "scripts/users.py:1 get_user_by_id",
+            "src/users.rs:5 get_user_by_id",
+            // Private symbols follow public ones.
+            "scripts/users.py:6 _get_user_by_id",
+            // Extra subwords in the middle of a name break up the query.
+            "src/users.rs:9 get_user_name_by_id",
+        ];
+
+        assert_eq!(found("getUserById"), expected);
+        assert_eq!(found("get_user_by_id"), expected);
+        assert_eq!(found("get-user-by-id"), expected);
+        assert_eq!(found("GetUserByID"), expected);
+
+        assert_eq!(
+            found("user"),
+            [
+                "src/users.rs:1 User",
+                "scripts/users.py:5 UserCache",
+                "client/api.ts:1 getUserById",
+                "scripts/users.py:1 get_user_by_id",
+                "src/users.rs:5 get_user_by_id",
+                "scripts/users.py:6 _get_user_by_id",
+                "src/users.rs:9 get_user_name_by_id",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_typos() {
+        assert_eq!(found("getUsreByID").len(), 5);
+        assert_eq!(found("get_users_by_id").len(), 5);
+        assert_eq!(found("UserCahce"), ["scripts/users.py:5 UserCache"]);
+
+        // Short subwords must match exactly.
+        assert!(found("getUsrById").is_empty());
+        assert!(found("set_user_by_id").is_empty());
+    }
+
+    #[test]
+    fn test_response() {
+        let step = SearchStep::Symbol {
+            query: "getUserById".to_owned(),
+            symbols: find_symbols(fixture(), "getUserById"),
+        };
+
+        assert_eq!(
+            step.get_response(),
+            "getUserById (function) client/api.ts:1\n\
+             get_user_by_id, defined 2 times:\n\
+             \x20 scripts/users.py:1 (function)\n\
+             \x20 src/users.rs:5 (function)\n\
+             _get_user_by_id (function) scripts/users.py:6\n\
+             get_user_name_by_id (function) src/users.rs:9"
+        );
+
+        let step = SearchStep::Symbol {
+            query: "fetchOrders".to_owned(),
+            symbols: find_symbols(fixture(), "fetchOrders"),
+        };
+
+        assert_eq!(
+            step.get_response(),
+            "No functions or types matching fetchOrders were found."
+        );
+    }
+
+    #[test]
+    fn test_subwords() {
+        let words = |ident| subwords(ident).join(" ");
+
+        assert_eq!(words("getUserById"), "get user by id");
+        assert_eq!(words("get_user_by_id"), "get user by id");
+        assert_eq!(words("GET_USER_BY_ID"), "get user by id");
+        assert_eq!(words("get-user-by-id"), "get user by id");
+        assert_eq!(words("HTTPServer"), "http server");
+        assert_eq!(words("parseV2Header"), "parse v2 header");
+        assert_eq!(words("__init__"), "init");
+        assert_eq!(
+            words("the getUserById function"),
+            "the get user by id function"
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("user", "user"), 0);
+        assert_eq!(edit_distance("usre", "user"), 1);
+        assert_eq!(edit_distance("usr", "user"), 1);
+        assert_eq!(edit_distance("cache", "cahce"), 1);
+        assert_eq!(edit_distance("get", "set"), 1);
+        assert_eq!(edit_distance("", "id"), 2);
+    }
+}
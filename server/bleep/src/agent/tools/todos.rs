@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use lazy_regex::regex;
+
+use crate::{
+    agent::{
+        exchange::{SearchStep, Todo, Update},
+        Agent,
+    },
+    analytics::EventData,
+};
+
+/// The tags of comments that are extracted, in the order they are grouped in.
+const TAGS: &[&str] = &["TODO", "FIXME", "HACK", "XXX"];
+
+/// The number of lines before and after a comment that are kept as its context.
+const CONTEXT_LINES: usize = 3;
+
+impl Agent {
+    pub async fn todos(&mut self, path: &str) -> Result<String> {
+        self.update(Update::StartStep(SearchStep::TODOs {
+            path: path.to_owned(),
+            todos: Vec::new(),
+        }))
+        .await?;
+
+        let content = self
+            .get_file_content(path)
+            .await?
+            .with_context(|| format!("path does not exist in the index: {path}"))?
+            .content;
+
+        let todos = todos(&content);
+
+        let step = SearchStep::TODOs {
+            path: path.to_owned(),
+            todos: todos.clone(),
+        };
+        let response = step.get_response();
+
+        self.update(Update::ReplaceStep(step)).await?;
+
+        self.track_query(
+            EventData::input_stage("todos")
+                .with_payload("path", path)
+                .with_payload("results", &todos)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+}
+
+/// The TODO, FIXME, HACK and XXX comments in `content`, grouped by tag and ordered by line.
+///
+/// Tags only count in comments, so that identifiers like `TODO_LIST` and strings that mention a
+/// tag are left out.
+fn todos(content: &str) -> Vec<Todo> {
+    let comment = regex!(
+        r"(?://|#|/\*|^\s*\*|--|<!--|;)[/*#!\s]*\b(TODO|FIXME|HACK|XXX)\b(?:\([^)]*\))?:?(.*)"
+    );
+
+    let lines = content.lines().collect::<Vec<_>>();
+    let mut todos = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let captures = comment.captures(line)?;
+            let message = captures[2]
+                .trim()
+                .trim_end_matches("*/")
+                .trim_end_matches("-->")
+                .trim();
+
+            let context = lines
+                [i.saturating_sub(CONTEXT_LINES)..(i + CONTEXT_LINES + 1).min(lines.len())]
+                .join("\n");
+
+            Some(Todo {
+                line: i + 1,
+                tag: captures[1].to_owned(),
+                message: message.to_owned(),
+                context,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    todos.sort_by_key(|t| (TAGS.iter().position(|tag| *tag == t.tag), t.line));
+    todos
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_todos() {
+        let content = r#"use std::time::Duration;
+
+// FIXME: retries never give up
+fn retry() {
+    let delay = Duration::from_secs(1);
+    /* HACK(alice): sleep instead of backing off */
+    std::thread::sleep(delay);
+}
+
+// TODO: make the delay configurable
+const TODO_LIST: &str = "TODO: not a comment";
+"#;
+
+        let todos = todos(content);
+        assert_eq!(
+            todos
+                .iter()
+                .map(|t| (t.tag.as_str(), t.line, t.message.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("TODO", 10, "make the delay configurable"),
+                ("FIXME", 3, "retries never give up"),
+                ("HACK", 6, "sleep instead of backing off"),
+            ]
+        );
+
+        assert_eq!(
+            todos[1].context,
+            "use std::time::Duration;\n\n// FIXME: retries never give up\nfn retry() {\n    \
+             let delay = Duration::from_secs(1);\n    /* HACK(alice): sleep instead of backing \
+             off */"
+        );
+        assert_eq!(
+            todos[0].context,
+            "    std::thread::sleep(delay);\n}\n\n// TODO: make the delay configurable\n\
+             const TODO_LIST: &str = \"TODO: not a comment\";"
+        );
+    }
+}
@@ -0,0 +1,679 @@
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use lazy_regex::regex;
+use semver::Version;
+use tracing::{debug, warn};
+
+use crate::{
+    agent::{
+        exchange::{BreakingChange, SearchStep, Update},
+        prompts, Agent,
+    },
+    analytics::EventData,
+    llm_gateway,
+};
+
+/// The model used to pick out the breaking changes from a changelog.
+const UPGRADE_MODEL: &str = "gpt-3.5-turbo-16k-0613";
+
+/// The maximum number of tokens of the changelog that are shown to the model.
+const MAX_CHANGELOG_TOKENS: usize = 8000;
+
+/// The maximum number of tokens of code from the conversation that are shown to the model.
+const MAX_CODE_TOKENS: usize = 4000;
+
+/// Manifests that we read from the repository root, with the registry of their dependencies.
+const MANIFESTS: &[(&str, Ecosystem)] = &[
+    ("Cargo.toml", Ecosystem::Crates),
+    ("package.json", Ecosystem::Npm),
+];
+
+/// Names of the changelog at the root of a dependency's repository, most common first.
+const CHANGELOG_NAMES: &[&str] = &["CHANGELOG.md", "CHANGES.md", "HISTORY.md"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ecosystem {
+    Crates,
+    Npm,
+}
+
+impl Ecosystem {
+    /// The version requirement of the dependency `name` in a manifest of this ecosystem.
+    fn requirement(self, manifest: &str, name: &str) -> Option<String> {
+        match self {
+            Self::Crates => cargo_requirement(manifest, name),
+            Self::Npm => npm_requirement(manifest, name),
+        }
+    }
+}
+
+/// The registries and code hosts that dependency releases are looked up in.
+struct Registries {
+    client: reqwest::Client,
+    crates_io: String,
+    npm: String,
+    /// The host of raw files in GitHub repositories, where changelogs are read from.
+    github_raw: String,
+}
+
+impl Default for Registries {
+    fn default() -> Self {
+        Self {
+            // crates.io rejects requests without a user agent.
+            client: reqwest::Client::builder()
+                .user_agent(concat!("bloop/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .expect("failed to build the registry client"),
+            crates_io: "https://crates.io".to_owned(),
+            npm: "https://registry.npmjs.org".to_owned(),
+            github_raw: "https://raw.githubusercontent.com".to_owned(),
+        }
+    }
+}
+
+/// The published releases of a package.
+#[derive(Debug, PartialEq, Eq)]
+struct Package {
+    versions: Vec<Version>,
+    /// The source repository, as given to the registry.
+    repository: Option<String>,
+}
+
+impl Agent {
+    pub async fn upgrade_suggestions(&mut self, dep_name: &str) -> Result<String> {
+        let dep_name = dep_name.trim();
+
+        self.update(Update::StartStep(SearchStep::UpgradeSuggestions {
+            dep_name: dep_name.to_owned(),
+            current_version: None,
+            target_version: None,
+            breaking_changes: Vec::new(),
+            response: String::new(),
+        }))
+        .await?;
+
+        let mut requirement = None;
+        for (path, ecosystem) in MANIFESTS {
+            let Some(doc) = self.get_file_content(path).await? else {
+                continue;
+            };
+
+            if let Some(req) = ecosystem.requirement(&doc.content, dep_name) {
+                requirement = Some((*path, *ecosystem, req));
+                break;
+            }
+        }
+
+        let registries = Registries::default();
+        let (current, target, changelog) = match requirement {
+            Some((path, ecosystem, req)) => {
+                let current = base_version(&req)
+                    .with_context(|| format!("{dep_name} has no version in {path}: {req}"))?;
+                let package = fetch_package(&registries, ecosystem, dep_name).await?;
+                let target = next_breaking(&current, &package.versions);
+
+                let changelog = match (&target, &package.repository) {
+                    (Some(target), Some(repository)) => fetch_changelog(&registries, repository)
+                        .await
+                        .map(|changelog| changelog_sections(&changelog, &current, target)),
+                    _ => None,
+                };
+
+                (Some(current), target, changelog)
+            }
+            None => (None, None, None),
+        };
+
+        debug!(
+            dep_name,
+            ?current,
+            ?target,
+            changelog = changelog.is_some(),
+            "suggesting upgrade"
+        );
+
+        let breaking_changes = match (&current, &target, &changelog) {
+            (Some(current), Some(target), Some(changelog)) if !changelog.trim().is_empty() => {
+                self.explain_breaking_changes(dep_name, current, target, changelog)
+                    .await?
+            }
+            _ => Vec::new(),
+        };
+
+        let response = render(
+            dep_name,
+            current.as_ref(),
+            target.as_ref(),
+            changelog.as_deref(),
+            &breaking_changes,
+        );
+
+        self.update(Update::ReplaceStep(SearchStep::UpgradeSuggestions {
+            dep_name: dep_name.to_owned(),
+            current_version: current.as_ref().map(Version::to_string),
+            target_version: target.as_ref().map(Version::to_string),
+            breaking_changes: breaking_changes.clone(),
+            response: response.clone(),
+        }))
+        .await?;
+
+        self.track_query(
+            EventData::input_stage("upgrade suggestions")
+                .with_payload("dep_name", dep_name)
+                .with_payload("current_version", current.map(|v| v.to_string()))
+                .with_payload("target_version", target.map(|v| v.to_string()))
+                .with_payload("breaking_changes", &breaking_changes)
+                .with_payload("raw_prompt", &response),
+        );
+
+        Ok(response)
+    }
+
+    /// Ask the model which of the changes in `changelog` affect the code in this conversation.
+    async fn explain_breaking_changes(
+        &self,
+        dep_name: &str,
+        current: &Version,
+        target: &Version,
+        changelog: &str,
+    ) -> Result<Vec<BreakingChange>> {
+        let tokenizer = self.tokenizer(UPGRADE_MODEL)?;
+        let fit = |text: &str, max_tokens: usize| {
+            let mut remaining = max_tokens;
+            text.lines()
+                .take_while(|line| {
+                    remaining = remaining.saturating_sub(tokenizer.count(line) + 1);
+                    remaining > 0
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        let code = self
+            .last_exchange()
+            .code_chunks
+            .iter()
+            .map(|c| format!("{}:{}-{}\n{}", c.path, c.start_line, c.end_line, c.snippet))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        let messages = [llm_gateway::api::Message::system(
+            &prompts::upgrade_suggestions(
+                dep_name,
+                &current.to_string(),
+                &target.to_string(),
+                &fit(changelog, MAX_CHANGELOG_TOKENS),
+                &fit(&code, MAX_CODE_TOKENS),
+            ),
+        )];
+
+        let start = Instant::now();
+        let response = self
+            .llm_gateway
+            .clone()
+            .model(UPGRADE_MODEL)
+            .chat(&messages, None)
+            .await?
+            .try_collect::<String>()
+            .await?;
+
+        self.track_usage(
+            "upgrade_suggestions",
+            UPGRADE_MODEL,
+            &messages,
+            &response,
+            start.elapsed(),
+        )
+        .await;
+
+        Ok(parse_breaking_changes(&response).unwrap_or_else(|err| {
+            warn!(?err, dep_name, "failed to parse upgrade suggestions");
+            Vec::new()
+        }))
+    }
+}
+
+/// The version requirement of `name` in a `Cargo.toml`, from any of its dependency tables.
+///
+/// Dependencies inherited from the workspace, or renamed with `package`, have no requirement.
+fn cargo_requirement(manifest: &str, name: &str) -> Option<String> {
+    let mut section = String::new();
+
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            section = line.trim_matches(&['[', ']'][..]).trim().to_owned();
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim().trim_matches('"'), value.trim());
+
+        let (table, dep) = section.rsplit_once('.').unwrap_or(("", section.as_str()));
+        if is_dependency_table(&section) && key == name {
+            // An inline table, like `{ version = "1.0", features = ["derive"] }`.
+            if value.starts_with('{') {
+                return regex!(r#"(?:^|[{,\s])version\s*=\s*"([^"]+)""#)
+                    .captures(value)
+                    .map(|c| c[1].to_owned());
+            }
+
+            return Some(value.trim_matches('"').to_owned());
+        } else if is_dependency_table(table) && dep.trim_matches('"') == name && key == "version" {
+            // A table of its own, like `[dependencies.serde]`.
+            return Some(value.trim_matches('"').to_owned());
+        }
+    }
+
+    None
+}
+
+fn is_dependency_table(section: &str) -> bool {
+    let last = section.rsplit('.').next().unwrap_or(section);
+    matches!(
+        last,
+        "dependencies" | "dev-dependencies" | "build-dependencies"
+    )
+}
+
+/// The version requirement of `name` in a `package.json`, from any of its dependency maps.
+fn npm_requirement(manifest: &str, name: &str) -> Option<String> {
+    let manifest = serde_json::from_str::<serde_json::Value>(manifest).ok()?;
+
+    [
+        "dependencies",
+        "devDependencies",
+        "peerDependencies",
+        "optionalDependencies",
+    ]
+    .iter()
+    .find_map(|key| manifest[key][name].as_str().map(str::to_owned))
+}
+
+/// The lowest version that a requirement like `^1.2`, `~0.4.1` or `>=2` allows.
+fn base_version(requirement: &str) -> Option<Version> {
+    let caps = regex!(r"(\d+)(?:\.(\d+))?(?:\.(\d+))?").captures(requirement)?;
+    let part = |i| caps.get(i).map_or(Ok(0), |m| m.as_str().parse::<u64>());
+
+    Some(Version::new(part(1).ok()?, part(2).ok()?, part(3).ok()?))
+}
+
+/// The releases that are compatible with a version, as with Cargo's and npm's caret requirements.
+///
+/// This is the major version, or the minor version of 0.x releases.
+fn compatibility(version: &Version) -> (u64, u64, u64) {
+    match (version.major, version.minor) {
+        (0, 0) => (0, 0, version.patch),
+        (0, minor) => (0, minor, 0),
+        (major, _) => (major, 0, 0),
+    }
+}
+
+/// The latest stable release of the first version that is incompatible with `current`.
+fn next_breaking(current: &Version, versions: &[Version]) -> Option<Version> {
+    let stable = || versions.iter().filter(|v| v.pre.is_empty());
+
+    let next = stable()
+        .map(compatibility)
+        .filter(|c| *c > compatibility(current))
+        .min()?;
+
+    stable().filter(|v| compatibility(v) == next).max().cloned()
+}
+
+async fn fetch_package(
+    registries: &Registries,
+    ecosystem: Ecosystem,
+    name: &str,
+) -> Result<Package> {
+    let url = match ecosystem {
+        Ecosystem::Crates => format!(
+            "{}/api/v1/crates/{name}",
+            registries.crates_io.trim_end_matches('/')
+        ),
+        // Scoped package names keep their `@`, but not their `/`.
+        Ecosystem::Npm => format!(
+            "{}/{}",
+            registries.npm.trim_end_matches('/'),
+            name.replace('/', "%2F")
+        ),
+    };
+
+    let response = registries
+        .client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await
+        .with_context(|| format!("failed to look up {name} in the registry"))?;
+
+    let package = match ecosystem {
+        Ecosystem::Crates => Package {
+            versions: response["versions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|v| v["yanked"] != true)
+                .filter_map(|v| Version::parse(v["num"].as_str()?).ok())
+                .collect(),
+            repository: response["crate"]["repository"].as_str().map(str::to_owned),
+        },
+        Ecosystem::Npm => Package {
+            versions: response["versions"]
+                .as_object()
+                .into_iter()
+                .flat_map(|versions| versions.keys())
+                .filter_map(|v| Version::parse(v).ok())
+                .collect(),
+            repository: response["repository"]["url"]
+                .as_str()
+                .or_else(|| response["repository"].as_str())
+                .map(str::to_owned),
+        },
+    };
+
+    Ok(package)
+}
+
+/// The changelog at the root of a GitHub repository, if there is one.
+async fn fetch_changelog(registries: &Registries, repository: &str) -> Option<String> {
+    let caps =
+        regex!(r"github\.com[/:]([^/]+)/([^/#?]+?)(?:\.git)?(?:[/#?]|$)").captures(repository)?;
+    let base = format!(
+        "{}/{}/{}/HEAD",
+        registries.github_raw.trim_end_matches('/'),
+        &caps[1],
+        &caps[2]
+    );
+
+    for name in CHANGELOG_NAMES {
+        let response = registries
+            .client
+            .get(format!("{base}/{name}"))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+
+        match response {
+            Ok(response) => return response.text().await.ok(),
+            Err(err) => debug!(?err, repository, name, "no changelog found"),
+        }
+    }
+
+    None
+}
+
+/// The sections of a Markdown changelog for the releases after `current`, up to `target`.
+///
+/// A section starts at a heading that names a version, and includes the headings below it that
+/// don't.
+fn changelog_sections(changelog: &str, current: &Version, target: &Version) -> String {
+    let mut sections = String::new();
+    let mut include = false;
+
+    for line in changelog.lines() {
+        if line.starts_with('#') {
+            let version = regex!(r"\bv?(\d+\.\d+\.\d+(?:-[0-9A-Za-z.-]+)?)\b")
+                .captures(line)
+                .and_then(|c| Version::parse(&c[1]).ok());
+
+            if let Some(version) = version {
+                include = *current < version && version <= *target;
+            }
+        }
+
+        if include {
+            sections += line;
+            sections.push('\n');
+        }
+    }
+
+    sections
+}
+
+/// Parse the breaking changes returned by the model, which may be wrapped in a code block.
+fn parse_breaking_changes(response: &str) -> Result<Vec<BreakingChange>> {
+    let json = response
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```");
+
+    Ok(serde_json::from_str(json.trim())?)
+}
+
+fn render(
+    dep_name: &str,
+    current: Option<&Version>,
+    target: Option<&Version>,
+    changelog: Option<&str>,
+    breaking_changes: &[BreakingChange],
+) -> String {
+    let (current, target) = match (current, target) {
+        (None, _) => {
+            return format!(
+                "{dep_name} is not a dependency in the Cargo.toml or package.json at the root of \
+                 the repository."
+            )
+        }
+        (Some(current), None) => {
+            return format!("{dep_name} {current} has no newer incompatible release.")
+        }
+        (Some(current), Some(target)) => (current, target),
+    };
+
+    match changelog {
+        None => format!(
+            "{dep_name} {target} is the next incompatible release after {current}, but no \
+             changelog was found for it."
+        ),
+        Some(_) if breaking_changes.is_empty() => format!(
+            "No breaking changes that affect this code were found in the changelog of \
+             {dep_name} from {current} to {target}."
+        ),
+        Some(_) => {
+            let mut s =
+                format!("Breaking changes when upgrading {dep_name} from {current} to {target}:\n");
+            for change in breaking_changes {
+                s += &format!("\n- {}", change.short_desc);
+                if let Some(hint) = &change.migration_hint {
+                    s += &format!("\n  Migration: {hint}");
+                }
+            }
+
+            s
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{extract::Path, routing::get, Json};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn versions(versions: &[&str]) -> Vec<Version> {
+        versions
+            .iter()
+            .map(|v| Version::parse(v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_manifest_requirements() {
+        let cargo_toml = r#"
+[package]
+name = "bleep"
+version = "0.4.13"
+
+[dependencies]
+tokio = { version = "1.29.1", features = ["macros"] }
+serde = "1.0.166"
+renamed = { package = "other", git = "https://github.com/example/other" }
+
+[target.'cfg(windows)'.dependencies]
+windows-sys = "0.48"
+
+[dev-dependencies.tempdir]
+version = "0.3.7"
+"#;
+
+        assert_eq!(cargo_requirement(cargo_toml, "tokio").unwrap(), "1.29.1");
+        assert_eq!(cargo_requirement(cargo_toml, "serde").unwrap(), "1.0.166");
+        assert_eq!(
+            cargo_requirement(cargo_toml, "windows-sys").unwrap(),
+            "0.48"
+        );
+        assert_eq!(cargo_requirement(cargo_toml, "tempdir").unwrap(), "0.3.7");
+        assert_eq!(cargo_requirement(cargo_toml, "renamed"), None);
+        assert_eq!(cargo_requirement(cargo_toml, "bleep"), None);
+
+        let package_json = r#"{
+            "name": "client",
+            "version": "0.4.13",
+            "dependencies": { "react": "^18.2.0" },
+            "devDependencies": { "@types/react": "~18.0.1" }
+        }"#;
+
+        assert_eq!(npm_requirement(package_json, "react").unwrap(), "^18.2.0");
+        assert_eq!(
+            npm_requirement(package_json, "@types/react").unwrap(),
+            "~18.0.1"
+        );
+        assert_eq!(npm_requirement(package_json, "client"), None);
+    }
+
+    #[test]
+    fn test_next_breaking() {
+        let published = versions(&[
+            "0.9.0",
+            "1.0.0",
+            "1.4.2",
+            "2.0.0-rc.1",
+            "2.0.0",
+            "2.3.1",
+            "3.0.0",
+        ]);
+
+        let next = |req| next_breaking(&base_version(req).unwrap(), &published);
+        assert_eq!(next("1.4"), Some(Version::new(2, 3, 1)));
+        assert_eq!(next("^2.0.0"), Some(Version::new(3, 0, 0)));
+        assert_eq!(next("3"), None);
+        assert_eq!(next("0.9"), Some(Version::new(1, 4, 2)));
+
+        let zero = versions(&["0.3.0", "0.3.5", "0.4.0", "0.4.2", "0.5.0"]);
+        assert_eq!(
+            next_breaking(&base_version("~0.3.1").unwrap(), &zero),
+            Some(Version::new(0, 4, 2))
+        );
+    }
+
+    #[test]
+    fn test_changelog_sections() {
+        let changelog = "\
+# Changelog
+
+## [3.0.0] - 2023-06-01
+### Breaking
+- Removed `Client::new`
+
+## v2.1.0
+- Added `Client::builder`
+
+## 2.0.0
+- Renamed `connect` to `open`
+
+## 1.4.2
+- Fixed reconnects
+";
+
+        assert_eq!(
+            changelog_sections(changelog, &Version::new(1, 4, 2), &Version::new(2, 1, 0)),
+            "## v2.1.0\n- Added `Client::builder`\n\n## 2.0.0\n- Renamed `connect` to `open`\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_registry() {
+        let registry = axum::Router::new()
+            .route(
+                "/api/v1/crates/:name",
+                get(|Path(name): Path<String>| async move {
+                    assert_eq!(name, "hyper");
+                    Json(serde_json::json!({
+                        "crate": { "repository": "https://github.com/hyperium/hyper" },
+                        "versions": [
+                            { "num": "1.0.0", "yanked": false },
+                            { "num": "0.14.27", "yanked": false },
+                            { "num": "0.14.26", "yanked": true },
+                        ]
+                    }))
+                }),
+            )
+            .route(
+                "/:name",
+                get(|Path(name): Path<String>| async move {
+                    assert_eq!(name, "@types/react");
+                    Json(serde_json::json!({
+                        "versions": { "18.0.1": {}, "18.2.0": {}, "not a version": {} },
+                        "repository": { "url": "git+https://github.com/DefinitelyTyped/DefinitelyTyped.git" }
+                    }))
+                }),
+            )
+            .route(
+                "/hyperium/hyper/HEAD/CHANGELOG.md",
+                get(|| async { "## v1.0.0 (2023-11-15)\n- Removed `Server`\n" }),
+            );
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(registry.into_make_service());
+        let base_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let registries = Registries {
+            client: reqwest::Client::new(),
+            crates_io: base_url.clone(),
+            npm: base_url.clone(),
+            github_raw: base_url,
+        };
+
+        let hyper = fetch_package(&registries, Ecosystem::Crates, "hyper")
+            .await
+            .unwrap();
+        assert_eq!(
+            hyper,
+            Package {
+                versions: versions(&["1.0.0", "0.14.27"]),
+                repository: Some("https://github.com/hyperium/hyper".to_owned()),
+            }
+        );
+
+        let mut types = fetch_package(&registries, Ecosystem::Npm, "@types/react")
+            .await
+            .unwrap();
+        types.versions.sort();
+        assert_eq!(types.versions, versions(&["18.0.1", "18.2.0"]));
+
+        let changelog = fetch_changelog(&registries, hyper.repository.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            changelog_sections(&changelog, &Version::new(0, 14, 27), &Version::new(1, 0, 0)),
+            "## v1.0.0 (2023-11-15)\n- Removed `Server`\n"
+        );
+
+        // Repositories without a changelog have none to show.
+        assert_eq!(
+            fetch_changelog(&registries, "https://github.com/example/none.git").await,
+            None
+        );
+    }
+}
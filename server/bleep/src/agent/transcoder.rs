@@ -11,14 +11,25 @@ use comrak::nodes::{NodeHtmlBlock, NodeValue};
 use lazy_regex::regex;
 use regex::Regex;
 use serde::Deserialize;
-use tiktoken_rs::CoreBPE;
+
+use crate::agent::{citations::CitationRegistry, tokens::Tokenizer};
 
 /// Decode an article.
 ///
 /// If successful, this returns a tuple of `(body, conclusion)`.
 pub fn decode(llm_message: &str) -> (String, Option<String>) {
+    decode_cited(llm_message, None)
+}
+
+/// Decode an article, tagging quoted code with the id of the citation it quotes.
+///
+/// Quoted code blocks that cover a registered citation gain a `citation:<id>` attribute.
+pub fn decode_cited(
+    llm_message: &str,
+    citations: Option<&CitationRegistry>,
+) -> (String, Option<String>) {
     let sanitized = sanitize(llm_message);
-    let markdown = xml_for_each(&sanitized, |code| xml_to_markdown(code).ok());
+    let markdown = xml_for_each(&sanitized, |code| xml_to_markdown(code, citations).ok());
 
     // The `comrak` crate has a very unusual API which makes this logic difficult to follow. It
     // favours arena allocation instead of a tree-based AST, and requires `Write`rs to regenerate
@@ -103,6 +114,48 @@ pub fn decode(llm_message: &str) -> (String, Option<String>) {
     (comrak_to_string(root), None)
 }
 
+/// Render a decoded article as plain text, without markdown.
+///
+/// Links are replaced by their text, and code blocks are indented instead of fenced.
+pub fn to_prose(markdown: &str) -> String {
+    let arena = comrak::Arena::new();
+    let options = comrak::ComrakOptions::default();
+    let root = comrak::parse_document(&arena, markdown, &options);
+
+    root.children()
+        .map(|block| match &block.data.borrow().value {
+            NodeValue::CodeBlock(code) => code
+                .literal
+                .lines()
+                .map(|line| format!("    {line}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            NodeValue::List(_) => block
+                .children()
+                .map(inline_text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => inline_text(block),
+        })
+        .filter(|text| !text.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The text of `node` and the nodes in it, without their formatting.
+fn inline_text<'a>(node: &'a comrak::nodes::AstNode<'a>) -> String {
+    node.descendants()
+        .filter_map(|child| match &child.data.borrow().value {
+            NodeValue::Text(text) => Some(text.clone()),
+            NodeValue::Code(code) => Some(code.literal.clone()),
+            NodeValue::SoftBreak | NodeValue::LineBreak => Some(" ".to_owned()),
+            _ => None,
+        })
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
 pub fn encode(markdown: &str, conclusion: Option<&str>) -> String {
     let arena = comrak::Arena::new();
     let mut options = comrak::ComrakOptions::default();
@@ -190,8 +243,8 @@ pub fn encode_summarized(markdown: &str, conclusion: Option<&str>, model: &str)
     let article = xml_for_each(&encode(markdown, conclusion), |xml| {
         try_trim_code_xml(xml).ok()
     });
-    let bpe = tiktoken_rs::get_bpe_from_model(model)?;
-    Ok(limit_tokens(&article, bpe, 500).to_owned())
+    let tokenizer = Tokenizer::new(model)?;
+    Ok(limit_tokens(&article, &tokenizer, 500).to_owned())
 }
 
 fn sanitize(article: &str) -> String {
@@ -281,11 +334,26 @@ fn fixup_xml_code(xml: &str) -> Cow<str> {
     }
 }
 
-fn xml_to_markdown(xml: &str) -> Result<String> {
+fn xml_to_markdown(xml: &str, citations: Option<&CitationRegistry>) -> Result<String> {
     let code_chunk =
         quick_xml::de::from_str::<CodeChunk>(xml).context("failed to deserialize code chunk")?;
 
-    Ok(code_chunk.to_markdown())
+    let citation = match (&code_chunk, citations) {
+        (
+            CodeChunk::QuotedCode {
+                path,
+                start_line: Some(start),
+                end_line: Some(end),
+                ..
+            },
+            Some(citations),
+        ) => citations
+            .find(path, *start as usize, *end as usize)
+            .map(|c| c.id.as_str()),
+        _ => None,
+    };
+
+    Ok(code_chunk.to_markdown(citation))
 }
 
 /// An XML code chunk that is generated by the LLM.
@@ -325,7 +393,7 @@ fn deserialize_lineno<'a, D: serde::Deserializer<'a>>(de: D) -> Result<Option<u3
 }
 
 impl CodeChunk {
-    fn to_markdown(&self) -> String {
+    fn to_markdown(&self, citation: Option<&str>) -> String {
         let (ty, code, lang, path, start, end) = match self {
             CodeChunk::QuotedCode {
                 code,
@@ -346,8 +414,13 @@ impl CodeChunk {
             }
         };
 
+        // The citation is placed before the path, as clients match `path:` and `lines:` greedily.
+        let citation = citation
+            .map(|id| format!("citation:{id},"))
+            .unwrap_or_default();
+
         format!(
-            "```type:{ty},lang:{lang},path:{path},lines:{}-{}\n{code}\n```",
+            "```type:{ty},lang:{lang},{citation}path:{path},lines:{}-{}\n{code}\n```",
             start.unwrap_or(0),
             end.unwrap_or(0)
         )
@@ -458,12 +531,12 @@ fn try_trim_code_xml(xml: &str) -> Result<String> {
     })
 }
 
-fn limit_tokens(text: &str, bpe: CoreBPE, max_tokens: usize) -> &str {
-    let mut tokens = bpe.encode_ordinary(text);
+fn limit_tokens<'a>(text: &'a str, tokenizer: &Tokenizer, max_tokens: usize) -> &'a str {
+    let mut tokens = tokenizer.encode(text);
     tokens.truncate(max_tokens);
 
     while !tokens.is_empty() {
-        if let Ok(s) = bpe.decode(tokens.clone()) {
+        if let Ok(s) = tokenizer.decode(tokens.clone()) {
             return &text[..s.len()];
         }
 
@@ -921,6 +994,64 @@ Hello again, world.
         );
     }
 
+    #[test]
+    fn test_decode_cited() {
+        let repo = crate::repo::RepoRef::from("github.com/BloopAI/bloop");
+        let mut citations = CitationRegistry::default();
+        let id = citations.register(
+            &repo,
+            &crate::agent::exchange::CodeChunk {
+                path: "src/main.rs".to_owned(),
+                alias: 0,
+                snippet: "fn main() {}".to_owned(),
+                start_line: 1,
+                end_line: 20,
+                moved_to: None,
+                deleted: false,
+                symbol_path: None,
+                blob: None,
+                revision: None,
+                mapped: None,
+            },
+        );
+
+        let input = "<QuotedCode>
+<Code>
+fn main() {}
+</Code>
+<Language>Rust</Language>
+<Path>src/main.rs</Path>
+<StartLine>2</StartLine>
+<EndLine>4</EndLine>
+</QuotedCode>
+
+<QuotedCode>
+<Code>
+fn lib() {}
+</Code>
+<Language>Rust</Language>
+<Path>src/lib.rs</Path>
+<StartLine>2</StartLine>
+<EndLine>4</EndLine>
+</QuotedCode>";
+
+        let expected = format!(
+            "``` type:Quoted,lang:Rust,citation:{id},path:src/main.rs,lines:2-4
+fn main() {{}}
+```
+
+``` type:Quoted,lang:Rust,path:src/lib.rs,lines:2-4
+fn lib() {{}}
+```"
+        );
+
+        let (body, _) = decode_cited(input, Some(&citations));
+        assert_eq!(expected, body);
+
+        // Encoding ignores the citation attribute.
+        assert!(!encode(&body, None).contains("citation"));
+    }
+
     #[test]
     fn test_encode() {
         let input = "Foo
@@ -1061,18 +1192,18 @@ quux";
 
     #[test]
     fn test_limit_tokens() {
-        let bpe = tiktoken_rs::get_bpe_from_model("gpt-3.5-turbo").unwrap();
-        assert_eq!(limit_tokens("fn 🚨() {}", bpe.clone(), 1), "fn");
+        let tokenizer = Tokenizer::new("gpt-3.5-turbo").unwrap();
+        assert_eq!(limit_tokens("fn 🚨() {}", &tokenizer, 1), "fn");
 
         // Note: the following calls return a string that does not split the emoji, despite the
         // tokenizer interpreting the tokens like that.
-        assert_eq!(limit_tokens("fn 🚨() {}", bpe.clone(), 2), "fn");
-        assert_eq!(limit_tokens("fn 🚨() {}", bpe.clone(), 3), "fn");
+        assert_eq!(limit_tokens("fn 🚨() {}", &tokenizer, 2), "fn");
+        assert_eq!(limit_tokens("fn 🚨() {}", &tokenizer, 3), "fn");
 
         // Now we have a sufficient number of input tokens to overcome the emoji.
-        assert_eq!(limit_tokens("fn 🚨() {}", bpe.clone(), 4), "fn 🚨");
-        assert_eq!(limit_tokens("fn 🚨() {}", bpe.clone(), 5), "fn 🚨()");
-        assert_eq!(limit_tokens("fn 🚨() {}", bpe, 6), "fn 🚨() {}");
+        assert_eq!(limit_tokens("fn 🚨() {}", &tokenizer, 4), "fn 🚨");
+        assert_eq!(limit_tokens("fn 🚨() {}", &tokenizer, 5), "fn 🚨()");
+        assert_eq!(limit_tokens("fn 🚨() {}", &tokenizer, 6), "fn 🚨() {}");
     }
 
     #[test]
@@ -1107,4 +1238,40 @@ Foo *bar* `[^summary]: allow this, it is in code quotes` quux.";
         assert_eq!(expected, body);
         assert_eq!("Baz fred **thud** corge.", conclusion.unwrap());
     }
+
+    #[test]
+    fn test_to_prose() {
+        let (article, _) = decode(
+            "# Retries
+
+Requests are retried by [`send`](src/client.rs#L10-L20), up to `MAX_RETRIES` times:
+
+- with **exponential** backoff
+- and a [jitter](src/backoff.rs#L5)
+
+<QuotedCode>
+<Code>
+const MAX_RETRIES: u32 = 3;
+</Code>
+<Language>Rust</Language>
+<Path>src/client.rs</Path>
+<StartLine>4</StartLine>
+<EndLine>4</EndLine>
+</QuotedCode>
+
+[^summary]: Requests are retried three times.",
+        );
+
+        assert_eq!(
+            to_prose(&article),
+            "Retries
+
+Requests are retried by send, up to MAX_RETRIES times:
+
+with exponential backoff
+and a jitter
+
+    const MAX_RETRIES: u32 = 3;"
+        );
+    }
 }
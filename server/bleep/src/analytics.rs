@@ -1,22 +1,49 @@
-use std::sync::Arc;
+//! Product analytics, sent to Rudderstack.
+//!
+//! Analytics can be compiled out by disabling the `analytics` feature, and turned off at runtime
+//! with `--disable-analytics`. In both cases, event payloads are never serialized.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use crate::{
     repo::RepoRef,
     state::{PersistedState, StateSource},
 };
 
+#[cfg(feature = "analytics")]
 use rudderanalytics::{
     client::RudderAnalytics,
-    message::{Message, Track},
+    message::{Identify, Message, Track},
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::Value;
+#[cfg(feature = "analytics")]
 use tracing::{info, warn};
 
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Whether analytics events are built and sent.
+///
+/// This is always `false` if the `analytics` feature is disabled.
+pub fn enabled() -> bool {
+    cfg!(feature = "analytics") && ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turn analytics on or off for the whole process.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
 #[derive(Debug, Clone)]
 pub struct QueryEvent {
     pub query_id: uuid::Uuid,
+    pub run_id: Option<uuid::Uuid>,
     pub thread_id: uuid::Uuid,
+    /// The browser session that the query was made in, which can span several threads.
+    pub session_id: Option<String>,
     pub repo_ref: Option<RepoRef>,
     pub data: EventData,
 }
@@ -51,7 +78,14 @@ impl EventData {
         }
     }
 
+    /// Attach a payload to this event.
+    ///
+    /// When analytics are disabled, the payload is dropped without being serialized.
     pub fn with_payload<T: Serialize>(mut self, name: &str, payload: T) -> Self {
+        if !enabled() {
+            return self;
+        }
+
         self.payload
             .push((name.to_string(), serde_json::to_value(payload).unwrap()));
         self
@@ -67,9 +101,11 @@ pub struct PackageMetadata {
 
 pub struct RudderHub {
     /// Rudderstack options
+    #[cfg(feature = "analytics")]
     options: Option<HubOptions>,
 
     /// Rudderstack client
+    #[cfg(feature = "analytics")]
     client: RudderAnalytics,
 
     /// User-specific store
@@ -95,6 +131,7 @@ pub struct UserState {
     pub tracking_id: String,
 }
 
+#[cfg(feature = "analytics")]
 impl RudderHub {
     pub fn new_with_options(
         state: &StateSource,
@@ -113,27 +150,6 @@ impl RudderHub {
         .into())
     }
 
-    pub fn device_id(&self) -> String {
-        self.device_id.0.trim().to_owned()
-    }
-
-    pub fn tracking_id(&self, username: Option<&str>) -> String {
-        match username {
-            Some(username) => {
-                let id = self
-                    .user_store
-                    .entry(username.to_owned())
-                    .or_default()
-                    .get()
-                    .tracking_id
-                    .clone();
-                _ = self.user_store.store();
-                id
-            }
-            None => self.device_id(),
-        }
-    }
-
     /// Send a message, logging an error if it occurs.
     ///
     /// This will internally `block_in_place`.
@@ -152,10 +168,12 @@ impl RudderHub {
                     self.send(Message::Track(Track {
                         user_id: Some(self.tracking_id(user.login())),
                         event: "openai query".to_owned(),
-                        properties: Some(json!({
+                        properties: Some(serde_json::json!({
                             "device_id": self.device_id(),
                             "query_id": ev.query_id,
+                            "run_id": ev.run_id,
                             "thread_id": ev.thread_id,
+                            "session_id": ev.session_id,
                             "repo_ref": ev.repo_ref.as_ref().map(ToString::to_string),
                             "data": ev.data,
                             "package_metadata": options.package_metadata,
@@ -167,6 +185,14 @@ impl RudderHub {
         }
     }
 
+    pub fn identify(&self, username: &str, traits: Value) {
+        self.send(Message::Identify(Identify {
+            user_id: Some(self.tracking_id(Some(username))),
+            traits: Some(traits),
+            ..Default::default()
+        }));
+    }
+
     pub fn track_synced_repos(
         &self,
         count: usize,
@@ -182,6 +208,55 @@ impl RudderHub {
     }
 }
 
+/// An inert hub, for builds without the `analytics` feature.
+#[cfg(not(feature = "analytics"))]
+impl RudderHub {
+    pub fn new_with_options(
+        _state: &StateSource,
+        _device_id: impl Into<Option<String>>,
+        _key: String,
+        _data_plane: String,
+        _options: impl Into<Option<HubOptions>>,
+    ) -> anyhow::Result<Arc<Self>> {
+        anyhow::bail!("analytics were disabled at compile time")
+    }
+
+    pub fn track_query(&self, _user: &crate::webserver::middleware::User, _event: QueryEvent) {}
+
+    pub fn identify(&self, _username: &str, _traits: Value) {}
+
+    pub fn track_synced_repos(
+        &self,
+        _count: usize,
+        _username: Option<&str>,
+        _org_name: Option<String>,
+    ) {
+    }
+}
+
+impl RudderHub {
+    pub fn device_id(&self) -> String {
+        self.device_id.0.trim().to_owned()
+    }
+
+    pub fn tracking_id(&self, username: Option<&str>) -> String {
+        match username {
+            Some(username) => {
+                let id = self
+                    .user_store
+                    .entry(username.to_owned())
+                    .or_default()
+                    .get()
+                    .tracking_id
+                    .clone();
+                _ = self.user_store.store();
+                id
+            }
+            None => self.device_id(),
+        }
+    }
+}
+
 impl From<Option<String>> for DeviceId {
     fn from(value: Option<String>) -> Self {
         match value {
@@ -203,3 +278,50 @@ impl Default for UserState {
         Self { tracking_id }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// A payload that counts how many times it was serialized.
+    struct Counted<'a>(&'a AtomicUsize);
+
+    impl Serialize for Counted<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            serializer.serialize_unit()
+        }
+    }
+
+    #[cfg(feature = "analytics")]
+    #[test]
+    fn test_disabled_at_runtime() {
+        let count = AtomicUsize::new(0);
+
+        set_enabled(false);
+        let data = EventData::input_stage("test").with_payload("counted", Counted(&count));
+        set_enabled(true);
+
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert!(data.payload.is_empty());
+
+        let data = EventData::input_stage("test").with_payload("counted", Counted(&count));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(data.payload.len(), 1);
+    }
+
+    #[cfg(not(feature = "analytics"))]
+    #[test]
+    fn test_disabled_at_compile_time() {
+        let count = AtomicUsize::new(0);
+
+        set_enabled(true);
+        assert!(!enabled());
+
+        let data = EventData::input_stage("test").with_payload("counted", Counted(&count));
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+        assert!(data.payload.is_empty());
+    }
+}
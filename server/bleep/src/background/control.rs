@@ -20,7 +20,7 @@ pub struct SyncPipes {
 }
 
 impl SyncPipes {
-    pub(super) fn new(
+    pub(crate) fn new(
         reporef: RepoRef,
         new_branch_filters: Option<crate::repo::BranchFilter>,
         progress: super::ProgressStream,
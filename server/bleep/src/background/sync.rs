@@ -118,6 +118,10 @@ impl SyncHandle {
                         last_commit_unix_secs: 0,
                         most_common_lang: None,
                         branch_filter: None,
+                        revision: None,
+                        branch_settings: Default::default(),
+                        lfs: Default::default(),
+                        index_diagnostics: Default::default(),
                     }
                 }
             });
@@ -173,6 +177,8 @@ impl SyncHandle {
                     repo.sync_done_with(self.new_branch_filters.as_ref(), state)
                 });
 
+                tokio::spawn(crate::warmup::repo(self.app.clone(), self.reporef.clone()));
+
                 // technically `sync_done_with` does this, but we want to send notifications
                 self.set_status(|_| SyncStatus::Done)
             }
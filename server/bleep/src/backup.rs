@@ -0,0 +1,439 @@
+//! Backups of the indexes and conversations, to an object store.
+//!
+//! A backup is stored as one blob per file, and a manifest that lists the files with their
+//! checksums. The manifest is written last, so a backup that failed halfway has no manifest and
+//! can't be restored.
+
+use std::{
+    path::{Component, Path},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{semantic::store::Backend, state::SCHEMA_VERSION, Application, Configuration};
+
+mod qdrant;
+mod store;
+
+pub use store::{open, open_in, BlobStore, FsStore, S3Store};
+
+const MANIFEST: &str = "manifest.json";
+
+/// Tantivy indexes, which are directories in the index directory.
+const INDEX_DIRS: [&str; 2] = ["repo", "content"];
+const DATABASE: &str = "bleep.db";
+const VECTORS: &str = "vectors.bin";
+const REPO_STATE: &str = "repo_state.json";
+const QDRANT_SNAPSHOT: &str = "qdrant/documents.snapshot";
+
+/// A downloaded backup waits in this directory of the index directory, until bloop next starts.
+const PENDING_RESTORE: &str = "restore";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub bloop_version: String,
+    /// The index schema version, which must match for the backup to be restored.
+    pub schema_version: String,
+    pub created_at: DateTime<Utc>,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ManifestFile {
+    /// The path of the file in the backup, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    /// The hex-encoded BLAKE3 hash of the file.
+    pub blake3: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("the backup has index schema version {found}, but this version of bloop needs {expected}")]
+pub struct IncompatibleSchema {
+    pub found: String,
+    pub expected: String,
+}
+
+fn blob_key(path: &str) -> String {
+    format!("files/{path}")
+}
+
+/// Back up the indexes and conversations of `app` to `store`.
+///
+/// Indexing is held off while the files are copied, but not while they are uploaded.
+pub async fn backup(app: &Application, store: &dyn BlobStore) -> Result<Manifest> {
+    let staging = app
+        .config
+        .index_dir
+        .join(format!("backup-{}", uuid::Uuid::new_v4()));
+
+    let result = async {
+        snapshot(app, &staging).await?;
+        upload(&staging, store).await
+    }
+    .await;
+
+    if let Err(err) = tokio::fs::remove_dir_all(&staging).await {
+        warn!(?err, ?staging, "failed to remove backup staging directory");
+    }
+
+    result
+}
+
+/// Copy everything that is backed up to `staging`.
+async fn snapshot(app: &Application, staging: &Path) -> Result<()> {
+    let _quiesced = app.indexes.quiesce().await;
+    let config = &app.config;
+
+    // The indexes can be gigabytes, which must not be copied on a worker of the runtime.
+    let (copied_config, dest) = (Arc::clone(config), staging.to_owned());
+    tokio::task::spawn_blocking(move || copy_files(&copied_config, &dest)).await??;
+
+    // A copy of the database file itself could catch it in the middle of a write.
+    let database = staging.join(DATABASE).to_string_lossy().into_owned();
+    sqlx::query!("VACUUM INTO ?", database)
+        .execute(&*app.sql)
+        .await
+        .context("failed to snapshot the database")?;
+
+    if let (Backend::Qdrant, Some(url)) = (config.semantic_backend, &config.qdrant_url) {
+        let data = qdrant::snapshot(url)
+            .await
+            .context("failed to snapshot qdrant")?;
+
+        let path = staging.join(QDRANT_SNAPSHOT);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(path, data).await?;
+    }
+
+    Ok(())
+}
+
+/// Copy the indexes, the vectors and the repository state to `staging`.
+fn copy_files(config: &Configuration, staging: &Path) -> Result<()> {
+    for dir in INDEX_DIRS {
+        copy_dir(&config.index_dir.join(dir), &staging.join(dir))?;
+    }
+
+    let vectors = config.index_dir.join(VECTORS);
+    if vectors.exists() {
+        std::fs::copy(vectors, staging.join(VECTORS))?;
+    }
+
+    if let Some(state) = config.source.state_file().filter(|path| path.exists()) {
+        std::fs::copy(state, staging.join(REPO_STATE))?;
+    }
+
+    Ok(())
+}
+
+/// Upload the files in `staging` to `store`, followed by their manifest.
+async fn upload(staging: &Path, store: &dyn BlobStore) -> Result<Manifest> {
+    let mut files = Vec::new();
+
+    for path in list_files(staging)? {
+        let data = tokio::fs::read(staging.join(&path)).await?;
+        files.push(ManifestFile {
+            size: data.len() as u64,
+            blake3: blake3::hash(&data).to_hex().to_string(),
+            path: path.clone(),
+        });
+
+        store.put(&blob_key(&path), data).await?;
+    }
+
+    let manifest = Manifest {
+        bloop_version: env!("CARGO_PKG_VERSION").to_owned(),
+        schema_version: SCHEMA_VERSION.to_owned(),
+        created_at: Utc::now(),
+        files,
+    };
+    store
+        .put(MANIFEST, serde_json::to_vec_pretty(&manifest)?)
+        .await?;
+
+    info!(files = manifest.files.len(), "backup complete");
+    Ok(manifest)
+}
+
+/// Download the backup in `store`, which replaces the local indexes and conversations the next
+/// time bloop starts.
+///
+/// Backups of another index schema version are refused, as they can't be opened. The error is
+/// then an [`IncompatibleSchema`].
+pub async fn restore(config: &Configuration, store: &dyn BlobStore) -> Result<Manifest> {
+    download(store, &config.index_dir.join(PENDING_RESTORE)).await
+}
+
+async fn download(store: &dyn BlobStore, dest: &Path) -> Result<Manifest> {
+    let raw_manifest = store.get(MANIFEST).await?;
+    let manifest: Manifest =
+        serde_json::from_slice(&raw_manifest).context("invalid backup manifest")?;
+
+    if manifest.schema_version != SCHEMA_VERSION {
+        return Err(IncompatibleSchema {
+            found: manifest.schema_version,
+            expected: SCHEMA_VERSION.to_owned(),
+        }
+        .into());
+    }
+
+    if dest.exists() {
+        tokio::fs::remove_dir_all(dest).await?;
+    }
+    tokio::fs::create_dir_all(dest).await?;
+
+    for file in &manifest.files {
+        let is_relative = Path::new(&file.path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if !is_relative {
+            bail!("invalid path `{}` in backup manifest", file.path);
+        }
+
+        let data = store.get(&blob_key(&file.path)).await?;
+        if data.len() as u64 != file.size || blake3::hash(&data).to_hex().as_str() != file.blake3 {
+            bail!("`{}` does not match the backup manifest", file.path);
+        }
+
+        let path = dest.join(&file.path);
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(path, data).await?;
+    }
+
+    // Only a backup that was downloaded completely has a manifest, and is applied.
+    tokio::fs::write(dest.join(MANIFEST), raw_manifest).await?;
+
+    info!(files = manifest.files.len(), "backup downloaded");
+    Ok(manifest)
+}
+
+/// Apply a backup that was downloaded since bloop last started.
+///
+/// This must run before the indexes and the database are opened.
+pub(crate) async fn apply_pending(config: &Configuration) -> Result<()> {
+    let pending = config.index_dir.join(PENDING_RESTORE);
+    let Ok(manifest) = tokio::fs::read(pending.join(MANIFEST)).await else {
+        return Ok(());
+    };
+    let manifest: Manifest = serde_json::from_slice(&manifest)?;
+
+    let (from, index_dir) = (pending.clone(), config.index_dir.clone());
+    let state = config.source.state_file().map(Path::to_owned);
+    tokio::task::spawn_blocking(move || move_files(&from, &index_dir, state.as_deref())).await??;
+
+    let snapshot = pending.join(QDRANT_SNAPSHOT);
+    if snapshot.exists() {
+        match &config.qdrant_url {
+            Some(url) => qdrant::recover(url, tokio::fs::read(snapshot).await?)
+                .await
+                .context("failed to restore qdrant")?,
+            None => warn!("not restoring the qdrant snapshot, as `qdrant_url` is not set"),
+        }
+    }
+
+    config.source.save_index_version()?;
+    tokio::fs::remove_dir_all(&pending).await?;
+
+    info!(created_at = %manifest.created_at, "restored backup");
+    Ok(())
+}
+
+/// Move the files of the backup in `pending` into `index_dir`, and to the `state` file.
+fn move_files(pending: &Path, index_dir: &Path, state: Option<&Path>) -> Result<()> {
+    for dir in INDEX_DIRS {
+        replace(&pending.join(dir), &index_dir.join(dir))?;
+    }
+
+    // The write-ahead log of the old database must not be replayed onto the new one.
+    let database = index_dir.join(DATABASE);
+    for extension in ["db-wal", "db-shm"] {
+        let path = database.with_extension(extension);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    replace(&pending.join(DATABASE), &database)?;
+
+    replace(&pending.join(VECTORS), &index_dir.join(VECTORS))?;
+    if let Some(state) = state {
+        replace(&pending.join(REPO_STATE), state)?;
+    }
+
+    Ok(())
+}
+
+/// Move `from` to `to`, replacing whatever is there. Nothing happens if `from` doesn't exist.
+fn replace(from: &Path, to: &Path) -> Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+
+    if to.is_dir() {
+        std::fs::remove_dir_all(to)?;
+    } else if to.exists() {
+        std::fs::remove_file(to)?;
+    }
+
+    // The state file can be on another file system, which files can't be renamed to.
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to).with_context(|| format!("failed to restore {}", to.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Copy a directory, leaving out the lock files of tantivy.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)?;
+
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_lock = name.to_string_lossy().ends_with(".lock");
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &to.join(&name))?;
+        } else if !is_lock {
+            std::fs::copy(entry.path(), to.join(&name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Paths of all files under `root`, relative to it and with `/` separators, in sorted order.
+fn list_files(root: &Path) -> Result<Vec<String>> {
+    fn walk(dir: &Path, prefix: &str, out: &mut Vec<String>) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = format!("{prefix}{}", entry.file_name().to_string_lossy());
+
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{path}/"), out)?;
+            } else {
+                out.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(root, "", &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use pretty_assertions::assert_eq;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    const FILES: &[(&str, &str)] = &[
+        ("bleep.db", "SQLite format 3"),
+        ("content/meta.json", r#"{"segments":[]}"#),
+        ("content/3f2a.store", "content segment"),
+        ("repo/meta.json", r#"{"segments":[]}"#),
+        ("repo_state.json", "{}"),
+    ];
+
+    fn staging(dir: &Path) -> PathBuf {
+        let staging = dir.join("staging");
+        for (path, content) in FILES {
+            let path = staging.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+
+        staging
+    }
+
+    #[tokio::test]
+    async fn test_backup_roundtrip() {
+        let dir = TempDir::new("test-backup-roundtrip").unwrap();
+        let store = FsStore::new(dir.path().join("store"));
+
+        let manifest = upload(&staging(dir.path()), &store).await.unwrap();
+        assert_eq!(manifest.schema_version, SCHEMA_VERSION);
+        assert_eq!(
+            manifest
+                .files
+                .iter()
+                .map(|f| (f.path.as_str(), f.size))
+                .collect::<Vec<_>>(),
+            [
+                ("bleep.db", 15),
+                ("content/3f2a.store", 15),
+                ("content/meta.json", 15),
+                ("repo/meta.json", 15),
+                ("repo_state.json", 2),
+            ]
+        );
+        assert_eq!(
+            manifest.files[4].blake3,
+            blake3::hash(b"{}").to_hex().as_str()
+        );
+
+        let stored: Manifest = serde_json::from_slice(&store.get(MANIFEST).await.unwrap()).unwrap();
+        assert_eq!(stored, manifest);
+
+        let dest = dir.path().join("restore");
+        assert_eq!(download(&store, &dest).await.unwrap(), manifest);
+
+        for (path, content) in FILES {
+            assert_eq!(&std::fs::read_to_string(dest.join(path)).unwrap(), content);
+        }
+        assert!(dest.join(MANIFEST).exists());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_backup_is_rejected() {
+        let dir = TempDir::new("test-tampered-backup").unwrap();
+        let store = FsStore::new(dir.path().join("store"));
+        upload(&staging(dir.path()), &store).await.unwrap();
+
+        store
+            .put(
+                &blob_key("content/meta.json"),
+                br#"{"segments":[1]}"#.to_vec(),
+            )
+            .await
+            .unwrap();
+
+        let dest = dir.path().join("restore");
+        let err = download(&store, &dest).await.unwrap_err();
+        assert!(err.to_string().contains("content/meta.json"), "{err}");
+
+        // Without a manifest, the partial download is never applied.
+        assert!(!dest.join(MANIFEST).exists());
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_schema_is_refused() {
+        let dir = TempDir::new("test-incompatible-backup").unwrap();
+        let store = FsStore::new(dir.path().join("store"));
+        let mut manifest = upload(&staging(dir.path()), &store).await.unwrap();
+
+        manifest.schema_version = "0".into();
+        store
+            .put(MANIFEST, serde_json::to_vec(&manifest).unwrap())
+            .await
+            .unwrap();
+
+        let dest = dir.path().join("restore");
+        let err = download(&store, &dest).await.unwrap_err();
+        let err = err.downcast::<IncompatibleSchema>().unwrap();
+        assert_eq!(err.found, "0");
+        assert_eq!(err.expected, SCHEMA_VERSION);
+        assert!(!dest.exists());
+    }
+}
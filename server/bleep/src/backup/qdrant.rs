@@ -0,0 +1,120 @@
+//! Snapshots of the Qdrant collection, through Qdrant's REST API.
+//!
+//! Search talks to Qdrant over gRPC, but snapshots can only be downloaded and uploaded over HTTP.
+
+use anyhow::{bail, Context, Result};
+
+use crate::semantic::COLLECTION_NAME;
+
+const GRPC_PORT: u16 = 6334;
+const REST_PORT: u16 = 6333;
+
+#[derive(serde::Deserialize)]
+struct Response<T> {
+    result: T,
+}
+
+#[derive(serde::Deserialize)]
+struct SnapshotDescription {
+    name: String,
+}
+
+/// The REST API of the Qdrant server whose gRPC API is at `url`.
+///
+/// This assumes Qdrant's default ports, so the REST API is only moved off the default gRPC port.
+fn rest_url(url: &str) -> Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(url).context("invalid qdrant url")?;
+    if url.port() == Some(GRPC_PORT) {
+        _ = url.set_port(Some(REST_PORT));
+    }
+
+    Ok(url)
+}
+
+async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if !status.is_success() {
+        bail!(
+            "qdrant request failed with {status}: {}",
+            response.text().await.unwrap_or_default()
+        );
+    }
+
+    Ok(response)
+}
+
+/// Snapshot the collection, returning the contents of the snapshot.
+pub(super) async fn snapshot(url: &str) -> Result<Vec<u8>> {
+    let base = rest_url(url)?;
+    let client = reqwest::Client::new();
+    let snapshots = base.join(&format!("collections/{COLLECTION_NAME}/snapshots/"))?;
+
+    let description = check(
+        client
+            .post(snapshots.as_str().trim_end_matches('/'))
+            .send()
+            .await?,
+    )
+    .await?
+    .json::<Response<SnapshotDescription>>()
+    .await?
+    .result;
+
+    let snapshot = snapshots.join(&description.name)?;
+    let data = check(client.get(snapshot.clone()).send().await?)
+        .await?
+        .bytes()
+        .await?;
+
+    // The snapshot is kept in the backup, so there is no need to keep it on the server too.
+    check(client.delete(snapshot).send().await?).await?;
+
+    Ok(data.to_vec())
+}
+
+/// Replace the collection with the contents of a snapshot.
+pub(super) async fn recover(url: &str, data: Vec<u8>) -> Result<()> {
+    let upload = rest_url(url)?.join(&format!(
+        "collections/{COLLECTION_NAME}/snapshots/upload?priority=snapshot"
+    ))?;
+
+    let boundary = format!("bloop-{}", uuid::Uuid::new_v4().simple());
+    let mut body = format!(
+        "--{boundary}\r\n\
+         Content-Disposition: form-data; name=\"snapshot\"; filename=\"{COLLECTION_NAME}.snapshot\"\r\n\
+         Content-Type: application/octet-stream\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend(data);
+    body.extend(format!("\r\n--{boundary}--\r\n").into_bytes());
+
+    let response = reqwest::Client::new()
+        .post(upload)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={boundary}"),
+        )
+        .body(body)
+        .send()
+        .await?;
+    check(response).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rest_url() {
+        assert_eq!(
+            rest_url("http://127.0.0.1:6334").unwrap().as_str(),
+            "http://127.0.0.1:6333/"
+        );
+        assert_eq!(
+            rest_url("https://qdrant.internal:8443").unwrap().as_str(),
+            "https://qdrant.internal:8443/"
+        );
+    }
+}
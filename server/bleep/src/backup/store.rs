@@ -0,0 +1,354 @@
+//! Object stores that snapshots are written to.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use ring::{digest, hmac};
+use secrecy::{ExposeSecret, SecretString};
+
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Write a blob, replacing any existing blob under the same key.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()>;
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>>;
+}
+
+/// Open the store at `url`.
+///
+/// This can be an `s3://bucket/prefix` or `gs://bucket/prefix` URL, or a local path, optionally
+/// as a `file://` URL. Object stores are authenticated with the `AWS_ACCESS_KEY_ID` and
+/// `AWS_SECRET_ACCESS_KEY` environment variables, which are HMAC keys for Google Cloud Storage.
+pub fn open(url: &str) -> Result<Box<dyn BlobStore>> {
+    Ok(if let Some(path) = url.strip_prefix("s3://") {
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned());
+        let endpoint = std::env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| format!("https://s3.{region}.amazonaws.com"));
+        Box::new(S3Store::from_env(&endpoint, &region, path)?)
+    } else if let Some(path) = url.strip_prefix("gs://") {
+        Box::new(S3Store::from_env(
+            "https://storage.googleapis.com",
+            "auto",
+            path,
+        )?)
+    } else {
+        Box::new(FsStore::new(url.strip_prefix("file://").unwrap_or(url)))
+    })
+}
+
+/// Open the store at `url` like `open`, but only allow local stores in `root`.
+///
+/// Local paths are taken to be relative to `root`, and absolute paths must be in it. If there is
+/// no `root`, only object stores can be opened.
+pub fn open_in(url: &str, root: Option<&Path>) -> Result<Box<dyn BlobStore>> {
+    if url.starts_with("s3://") || url.starts_with("gs://") {
+        return open(url);
+    }
+
+    let Some(root) = root else {
+        bail!("local backups are disabled, as no backup directory is configured");
+    };
+
+    let path = Path::new(url.strip_prefix("file://").unwrap_or(url));
+    let relative = if path.is_absolute() {
+        path.strip_prefix(root)
+            .map_err(|_| anyhow!("`{url}` is not in the backup directory"))?
+    } else {
+        path
+    };
+
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("`{url}` is not in the backup directory");
+    }
+
+    Ok(Box::new(FsStore::new(root.join(relative))))
+}
+
+/// A store in a local directory, where each key is a file path.
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        if key.split('/').any(|part| part.is_empty() || part == "..") {
+            bail!("invalid blob key `{key}`");
+        }
+
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let path = self.path(key)?;
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("failed to read {}", path.display()))
+    }
+}
+
+/// A bucket of an S3-compatible object store, addressed by path and signed with AWS Signature
+/// Version 4.
+pub struct S3Store {
+    client: reqwest::Client,
+    endpoint: reqwest::Url,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key: String,
+    secret_key: SecretString,
+}
+
+impl S3Store {
+    /// A store under `path`, as `bucket/prefix`, with credentials from the environment.
+    fn from_env(endpoint: &str, region: &str, path: &str) -> Result<Self> {
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            bail!("no bucket given in `{path}`");
+        }
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint: endpoint.parse().context("invalid object store endpoint")?,
+            region: region.to_owned(),
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+            access_key: std::env::var("AWS_ACCESS_KEY_ID")
+                .context("AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY")
+                .context("AWS_SECRET_ACCESS_KEY is not set")?
+                .into(),
+        })
+    }
+
+    async fn send(&self, method: reqwest::Method, key: &str, body: Vec<u8>) -> Result<Vec<u8>> {
+        let key = if self.prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}/{key}", self.prefix)
+        };
+        let path = format!("/{}/{}", self.bucket, uri_encode(&key));
+
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{port}", self.endpoint.host_str().unwrap_or_default()),
+            None => self.endpoint.host_str().unwrap_or_default().to_owned(),
+        };
+        let payload_hash = hex(digest::digest(&digest::SHA256, &body).as_ref());
+        let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+        let authorization = self.authorization(&Request {
+            method: method.as_str(),
+            path: &path,
+            host: &host,
+            payload_hash: &payload_hash,
+            timestamp: &timestamp,
+        });
+
+        let url = format!("{}{}", self.endpoint.as_str().trim_end_matches('/'), path);
+        let response = self
+            .client
+            .request(method, &url)
+            .header("host", host)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", timestamp)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let body = response.bytes().await?;
+        if !status.is_success() {
+            bail!(
+                "object store request for `{key}` failed with {status}: {}",
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        Ok(body.to_vec())
+    }
+
+    fn authorization(&self, request: &Request<'_>) -> String {
+        const SIGNED_HEADERS: &str = "host;x-amz-content-sha256;x-amz-date";
+
+        let date = &request.timestamp[..8];
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{SIGNED_HEADERS}\n{}",
+            request.method,
+            request.path,
+            request.host,
+            request.payload_hash,
+            request.timestamp,
+            request.payload_hash,
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{scope}\n{}",
+            request.timestamp,
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+
+        let key = signing_key(self.secret_key.expose_secret(), date, &self.region, "s3");
+        let signature = hex(hmac::sign(&key, string_to_sign.as_bytes()).as_ref());
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={SIGNED_HEADERS}, Signature={signature}",
+            self.access_key
+        )
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<()> {
+        self.send(reqwest::Method::PUT, key, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.send(reqwest::Method::GET, key, Vec::new()).await
+    }
+}
+
+/// The parts of a request that are signed.
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    host: &'a str,
+    payload_hash: &'a str,
+    timestamp: &'a str,
+}
+
+fn signing_key(secret: &str, date: &str, region: &str, service: &str) -> hmac::Key {
+    let mut key = hmac::Key::new(hmac::HMAC_SHA256, format!("AWS4{secret}").as_bytes());
+    for part in [date, region, service, "aws4_request"] {
+        key = hmac::Key::new(
+            hmac::HMAC_SHA256,
+            hmac::sign(&key, part.as_bytes()).as_ref(),
+        );
+    }
+
+    key
+}
+
+/// Percent-encode everything but unreserved characters and `/`, as S3 expects of object keys.
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_signing_key() {
+        // The example from the AWS documentation on deriving a signing key.
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+
+        // An HMAC key can't be read back, but signing with it shows whether it is the same key.
+        let expected = hmac::Key::new(
+            hmac::HMAC_SHA256,
+            &[
+                0xf4, 0x78, 0x0e, 0x2d, 0x9f, 0x65, 0xfa, 0x89, 0x5f, 0x9c, 0x67, 0xb3, 0x2c, 0xe1,
+                0xba, 0xf0, 0xb0, 0xd8, 0xa4, 0x35, 0x05, 0xa0, 0x00, 0xa1, 0xa9, 0xe0, 0x90, 0xd4,
+                0x14, 0xdb, 0x40, 0x4d,
+            ],
+        );
+        assert_eq!(
+            hmac::sign(&key, b"message").as_ref(),
+            hmac::sign(&expected, b"message").as_ref()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_in() {
+        let dir = TempDir::new("test-open-in").unwrap();
+        let root = dir.path().join("backups");
+
+        for url in ["daily", "./daily", "file://daily"] {
+            let store = open_in(url, Some(&root)).unwrap();
+            store.put("manifest.json", b"{}".to_vec()).await.unwrap();
+            assert!(root.join("daily/manifest.json").exists(), "{url}");
+        }
+
+        let absolute = root.join("weekly").to_string_lossy().into_owned();
+        let store = open_in(&absolute, Some(&root)).unwrap();
+        store.put("manifest.json", b"{}".to_vec()).await.unwrap();
+        assert!(root.join("weekly/manifest.json").exists());
+
+        // Paths that leave the backup directory are rejected.
+        for url in ["../daily", "daily/../../etc", "/etc", "file:///etc"] {
+            assert!(open_in(url, Some(&root)).is_err(), "{url}");
+        }
+
+        // Without a backup directory, only object stores can be opened.
+        assert!(open_in("daily", None).is_err());
+        assert!(open_in(&absolute, None).is_err());
+    }
+
+    #[test]
+    fn test_uri_encode() {
+        assert_eq!(
+            uri_encode("backups/2023 10/files/content/meta.json"),
+            "backups/2023%2010/files/content/meta.json"
+        );
+        assert_eq!(uri_encode("a+b=c~"), "a%2Bb%3Dc~");
+    }
+
+    #[tokio::test]
+    async fn test_fs_store() {
+        let dir = TempDir::new("test-fs-store").unwrap();
+        let store = open(&format!("file://{}", dir.path().display())).unwrap();
+
+        store
+            .put("files/repo/meta.json", b"{}".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(store.get("files/repo/meta.json").await.unwrap(), b"{}");
+        assert!(dir.path().join("files/repo/meta.json").exists());
+
+        assert!(store.get("files/missing").await.is_err());
+        assert!(store.put("../escape", Vec::new()).await.is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Compare the search quality of two configurations over the evaluation fixtures.
+//!
+//! Each configuration is a JSON file, as given to `bleep --config-file`, and defaults to the
+//! evaluation defaults if it is left out.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use bleep::{
+    eval::{self, Fixture, Harness, Mode},
+    Configuration,
+};
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {
+    /// The configuration to compare against
+    #[clap(long)]
+    baseline: Option<PathBuf>,
+
+    /// The configuration to evaluate
+    #[clap(long)]
+    candidate: Option<PathBuf>,
+
+    /// Only evaluate the fixture of this name
+    #[clap(long)]
+    fixture: Option<String>,
+
+    /// The directory of fixtures and their query sets
+    #[clap(long, default_value_os_t = eval::fixtures_dir())]
+    fixtures_dir: PathBuf,
+}
+
+fn config(path: Option<&PathBuf>) -> Result<Configuration> {
+    Ok(match path {
+        Some(path) => Configuration::merge(eval::default_config(), Configuration::read(path)?),
+        None => eval::default_config(),
+    })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let fixtures = Fixture::load_all(&args.fixtures_dir)?
+        .into_iter()
+        .filter(|f| args.fixture.as_ref().map_or(true, |name| &f.name == name))
+        .collect::<Vec<_>>();
+
+    let mut rows = Vec::new();
+    for fixture in fixtures {
+        let baseline = Harness::index(&fixture.root, config(args.baseline.as_ref())?).await?;
+        let candidate = Harness::index(&fixture.root, config(args.candidate.as_ref())?).await?;
+
+        for mode in Mode::ALL {
+            rows.push((
+                fixture.name.clone(),
+                mode,
+                baseline.evaluate(mode, &fixture.queries).await?,
+                candidate.evaluate(mode, &fixture.queries).await?,
+            ));
+        }
+    }
+
+    print!("{}", eval::comparison_table(&rows));
+    Ok(())
+}
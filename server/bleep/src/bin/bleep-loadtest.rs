@@ -0,0 +1,31 @@
+//! Load test the agent pipeline with generated queries, against a fake LLM gateway.
+//!
+//! See `bleep::loadtest` for what is measured.
+
+use anyhow::Result;
+use bleep::loadtest::{self, Options};
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {
+    #[clap(flatten)]
+    options: Options,
+
+    /// Print the report as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let report = loadtest::run(args.options).await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{report}");
+    }
+
+    Ok(())
+}
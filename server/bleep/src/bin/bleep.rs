@@ -1,13 +1,65 @@
 use anyhow::Result;
-use bleep::{Application, Configuration, Environment};
+use bleep::{backup, Application, Configuration, Environment};
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(flatten)]
+    config: Configuration,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Back up the indexes and conversations, and exit
+    Backup {
+        /// The store to back up to: an `s3://bucket/prefix` or `gs://bucket/prefix` URL, or a
+        /// local directory
+        #[clap(long)]
+        to: String,
+    },
+
+    /// Download a backup, which replaces the indexes and conversations the next time bloop
+    /// starts, and exit
+    Restore {
+        /// The store to restore from, as given to `backup --to`
+        #[clap(long)]
+        from: String,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = Configuration::cli_overriding_config_file()?;
+    let cli = Cli::parse();
+    let config = cli.config.overriding_config_file()?;
 
     Application::install_logging(&config);
-    let app = Application::initialize(Environment::server(), config, None, None).await?;
 
-    app.initialize_sentry();
-    app.run().await
+    match cli.command {
+        Some(Command::Backup { to }) => {
+            let store = backup::open(&to)?;
+            let app = Application::initialize(Environment::server(), config, None, None).await?;
+            let manifest = backup::backup(&app, &*store).await?;
+            println!("backed up {} files to {to}", manifest.files.len());
+            Ok(())
+        }
+        Some(Command::Restore { from }) => {
+            let store = backup::open(&from)?;
+            let manifest = backup::restore(&config, &*store).await?;
+            println!(
+                "downloaded the backup from {}, which will be restored when bloop next starts",
+                manifest.created_at
+            );
+            Ok(())
+        }
+        None => {
+            let app = Application::initialize(Environment::server(), config, None, None).await?;
+
+            app.initialize_sentry();
+            app.run().await
+        }
+    }
 }
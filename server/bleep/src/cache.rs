@@ -1,16 +1,15 @@
 use std::sync::{Arc, RwLock};
 
-use qdrant_client::{
-    prelude::QdrantClient,
-    qdrant::{PointId, PointStruct},
-};
 use sqlx::Sqlite;
 use tracing::trace;
 use uuid::Uuid;
 
 use crate::{
     repo::RepoRef,
-    semantic::{self, Embedding, Payload},
+    semantic::{
+        store::{Point, VectorStore},
+        Embedding, Payload,
+    },
 };
 
 use super::db::SqlDb;
@@ -154,7 +153,7 @@ impl<'a> FileCache<'a> {
     }
 }
 
-/// Manage both the SQL cache and the underlying qdrant database to
+/// Manage both the SQL cache and the underlying vector store to
 /// ensure consistency.
 ///
 /// Operates on a single file's level.
@@ -164,7 +163,7 @@ pub struct ChunkCache<'a> {
     file_cache_key: &'a str,
     cache: scc::HashMap<String, FreshValue<String>>,
     update: scc::HashMap<(Vec<String>, String), Vec<String>>,
-    new: RwLock<Vec<PointStruct>>,
+    new: RwLock<Vec<Point>>,
     new_sql: RwLock<Vec<(String, String)>>,
 }
 
@@ -228,10 +227,10 @@ impl<'a> ChunkCache<'a> {
                     .unwrap()
                     .push((vacant.key().to_owned(), branches_hash.clone()));
 
-                self.new.write().unwrap().push(PointStruct {
-                    id: Some(PointId::from(vacant.key().clone())),
-                    vectors: Some(embedder(data)?.into()),
-                    payload: payload.into_qdrant(),
+                self.new.write().unwrap().push(Point {
+                    id: vacant.key().clone(),
+                    vector: embedder(data)?,
+                    payload,
                 });
 
                 vacant.insert_entry(branches_hash.into());
@@ -241,39 +240,39 @@ impl<'a> ChunkCache<'a> {
         Ok(())
     }
 
-    /// Commit both qdrant and cache changes to the respective databases.
+    /// Commit both vector store and cache changes to the respective databases.
     ///
-    /// The SQLite operations mirror qdrant changes 1:1, so any
+    /// The SQLite operations mirror vector store changes 1:1, so any
     /// discrepancy between the 2 should be minimized.
     ///
     /// In addition, the SQLite cache is committed only AFTER all
-    /// qdrant writes have successfully completed, meaning they're in
-    /// qdrant's pipelines.
+    /// vector store writes have successfully completed, meaning
+    /// they're in qdrant's pipelines when using qdrant.
     ///
     /// Since qdrant changes are pipelined on their end, data written
-    /// here is not necessarily available for querying when the
+    /// there is not necessarily available for querying when the
     /// commit's completed.
-    pub async fn commit(self, qdrant: &QdrantClient) -> anyhow::Result<(usize, usize, usize)> {
+    pub async fn commit(self, store: &dyn VectorStore) -> anyhow::Result<(usize, usize, usize)> {
         let mut tx = self.sql.begin().await?;
 
-        let update_size = self.commit_branch_updates(&mut tx, qdrant).await?;
-        let delete_size = self.commit_deletes(&mut tx, qdrant).await?;
-        let new_size = self.commit_inserts(&mut tx, qdrant).await?;
+        let update_size = self.commit_branch_updates(&mut tx, store).await?;
+        let delete_size = self.commit_deletes(&mut tx, store).await?;
+        let new_size = self.commit_inserts(&mut tx, store).await?;
 
         tx.commit().await?;
 
         Ok((new_size, update_size, delete_size))
     }
 
-    /// Insert new additions to both qdrant and sqlite.
+    /// Insert new additions to both the vector store and sqlite.
     ///
-    /// The qdrant write uses `upsert`, because we simply want to
+    /// The vector store write uses `upsert`, because we simply want to
     /// express "these points should be in this state", without
     /// being pedantic.
     async fn commit_inserts(
         &self,
         tx: &mut sqlx::Transaction<'_, Sqlite>,
-        qdrant: &QdrantClient,
+        store: &dyn VectorStore,
     ) -> Result<usize, anyhow::Error> {
         let new: Vec<_> = std::mem::take(self.new.write().unwrap().as_mut());
         let new_sql = std::mem::take(&mut *self.new_sql.write().unwrap());
@@ -290,12 +289,7 @@ impl<'a> ChunkCache<'a> {
             .await?;
         }
 
-        // qdrant doesn't like empty payloads.
-        if !new.is_empty() {
-            qdrant
-                .upsert_points_blocking(semantic::COLLECTION_NAME, new, None)
-                .await?;
-        }
+        store.upsert(new).await?;
         Ok(new_size)
     }
 
@@ -303,7 +297,7 @@ impl<'a> ChunkCache<'a> {
     async fn commit_deletes(
         &self,
         tx: &mut sqlx::Transaction<'_, Sqlite>,
-        qdrant: &QdrantClient,
+        store: &dyn VectorStore,
     ) -> Result<usize, anyhow::Error> {
         let mut to_delete = vec![];
         self.cache
@@ -326,19 +320,7 @@ impl<'a> ChunkCache<'a> {
             .await?;
         }
 
-        if !to_delete.is_empty() {
-            qdrant
-                .delete_points(
-                    semantic::COLLECTION_NAME,
-                    &to_delete
-                        .into_iter()
-                        .map(PointId::from)
-                        .collect::<Vec<_>>()
-                        .into(),
-                    None,
-                )
-                .await?;
-        }
+        store.delete(to_delete).await?;
         Ok(delete_size)
     }
 
@@ -347,10 +329,10 @@ impl<'a> ChunkCache<'a> {
     async fn commit_branch_updates(
         &self,
         tx: &mut sqlx::Transaction<'_, Sqlite>,
-        qdrant: &QdrantClient,
+        store: &dyn VectorStore,
     ) -> Result<usize, anyhow::Error> {
         let mut update_size = 0;
-        let mut qdrant_updates = vec![];
+        let mut store_updates = vec![];
 
         let mut next = self.update.first_occupied_entry();
         while let Some(entry) = next {
@@ -369,22 +351,7 @@ impl<'a> ChunkCache<'a> {
                 .await?;
             }
 
-            let id = points
-                .iter()
-                .cloned()
-                .map(PointId::from)
-                .collect::<Vec<_>>()
-                .into();
-
-            let payload = qdrant_client::client::Payload::new_from_hashmap(
-                [("branches".to_string(), branches_list.to_owned().into())].into(),
-            );
-
-            qdrant_updates.push(async move {
-                qdrant
-                    .set_payload_blocking(semantic::COLLECTION_NAME, &id, payload, None)
-                    .await
-            });
+            store_updates.push(store.set_branches(points.clone(), branches_list.to_owned()));
             next = entry.next();
         }
 
@@ -393,7 +360,7 @@ impl<'a> ChunkCache<'a> {
         //
         // This should be fine since the number of updates would be
         // reasonably small.
-        futures::future::join_all(qdrant_updates.into_iter())
+        futures::future::join_all(store_updates.into_iter())
             .await
             .into_iter()
             .collect::<Result<Vec<_>, _>>()?;
@@ -1,10 +1,19 @@
-use crate::{semantic::chunk::OverlapStrategy, state::StateSource};
+use crate::{
+    agent::{canary::CanaryConfig, playbook::Playbook},
+    indexes::lfs::LfsPolicy,
+    llm_gateway,
+    semantic::{chunk::OverlapStrategy, store::Backend},
+    state::StateSource,
+};
 use anyhow::{Context, Result};
 use clap::Parser;
 
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize, Serializer};
-use std::path::{Path, PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 #[derive(Serialize, Deserialize, Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -41,6 +50,20 @@ pub struct Configuration {
     /// Disable system-native notification backends to detect new git commits immediately.
     pub disable_fsevents: bool,
 
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Disable analytics, even if an analytics key is configured.
+    ///
+    /// No analytics events are built or sent, including their prompt and response payloads.
+    pub disable_analytics: bool,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Repositories to pre-warm search state for, on startup and after they are indexed.
+    ///
+    /// Use `*` to warm up all repositories.
+    pub warmup_repos: Vec<String>,
+
     #[clap(long, default_value_t = false)]
     #[serde(default)]
     /// Avoid writing logs to files.
@@ -63,6 +86,14 @@ pub struct Configuration {
     /// Maximum number of parallel background threads
     pub max_threads: usize,
 
+    #[clap(long, value_enum, default_value_t = LfsPolicy::default())]
+    #[serde(default)]
+    /// How to index Git LFS pointer files.
+    ///
+    /// By default, the real object is indexed when it was fetched with `git lfs`, and the pointer
+    /// is indexed otherwise.
+    pub lfs: LfsPolicy,
+
     #[clap(long, default_value_t = default_host())]
     #[serde(default = "default_host")]
     /// Bind the webserver to `<port>`
@@ -73,6 +104,15 @@ pub struct Configuration {
     /// Bind the webserver to `<host>`
     pub port: u16,
 
+    #[clap(long)]
+    #[serde(default)]
+    /// Bind the editor JSON-RPC server to `127.0.0.1:<editor_port>`.
+    ///
+    /// The editor server is not authenticated, and answers as the user logged in to this
+    /// instance, so it only accepts connections from this machine. This is only used when bleep
+    /// is built with the `editor` feature.
+    pub editor_port: Option<u16>,
+
     //
     // External dependencies
     //
@@ -81,6 +121,158 @@ pub struct Configuration {
     /// URL for the answer-api
     pub answer_api_url: String,
 
+    #[clap(long, default_value_t = default_osv_api_url())]
+    #[serde(default = "default_osv_api_url")]
+    /// URL for the OSV (Open Source Vulnerabilities) API
+    pub osv_api_url: String,
+
+    #[clap(long, default_value_t = default_github_api_url())]
+    #[serde(default = "default_github_api_url")]
+    /// URL for the GitHub API, which issues and pull requests linked in queries are fetched from
+    pub github_api_url: String,
+
+    #[clap(long, default_values_t = default_external_context_hosts())]
+    #[serde(default = "default_external_context_hosts")]
+    /// Hosts that raw text and markdown pages linked in queries can be fetched from
+    pub external_context_hosts: Vec<String>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// LLM gateway endpoints to spread answer requests across, by weight, instead of sending
+    /// them all to `answer_api_url`.
+    ///
+    /// This can only be set in a config file, as endpoints may carry their own credentials.
+    pub llm_endpoints: Vec<llm_gateway::Endpoint>,
+
+    #[clap(long, default_value_t = default_llm_endpoint_cooldown_secs())]
+    #[serde(default = "default_llm_endpoint_cooldown_secs")]
+    /// How long an LLM endpoint receives no requests after repeatedly failing, in seconds
+    pub llm_endpoint_cooldown_secs: u64,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Interleave the function call instruction into the agent history as user messages.
+    ///
+    /// This is the framing used before the instruction was moved into a system message, and is
+    /// kept while the new framing rolls out.
+    pub legacy_function_call_framing: bool,
+
+    #[clap(long, default_value_t = default_call_graph_depth())]
+    #[serde(default = "default_call_graph_depth")]
+    /// How many levels of callees to follow when explaining a single function
+    pub call_graph_depth: usize,
+
+    #[clap(long, default_value_t = default_call_graph_fan_out())]
+    #[serde(default = "default_call_graph_fan_out")]
+    /// Maximum number of callees, and of callers, to include for each function that is explained
+    pub call_graph_fan_out: usize,
+
+    #[clap(long, default_value_t = default_token_safety_margin())]
+    #[serde(default = "default_token_safety_margin")]
+    /// Tokens to leave free in every prompt, on top of its measured size
+    pub token_safety_margin: usize,
+
+    #[clap(long, default_value_t = default_max_files_sent())]
+    #[serde(default = "default_max_files_sent")]
+    /// Maximum number of distinct files whose content is sent to the LLM while answering a query
+    pub max_files_sent: usize,
+
+    #[clap(long)]
+    /// Maximum number of tokens that the LLM can respond with, on every request made to answer a
+    /// query
+    pub max_response_tokens: Option<u32>,
+
+    #[clap(long, default_value_t = default_loop_similarity())]
+    #[serde(default = "default_loop_similarity")]
+    /// How similar the arguments of consecutive calls to a tool must be, between 0 and 1, for the
+    /// calls to count as an answer loop
+    pub loop_similarity: f32,
+
+    #[clap(long, default_value_t = default_loop_nudge_after())]
+    #[serde(default = "default_loop_nudge_after")]
+    /// After how many near-identical tool calls in a row the agent is reminded to use its results
+    pub loop_nudge_after: usize,
+
+    #[clap(long, default_value_t = default_loop_answer_after())]
+    #[serde(default = "default_loop_answer_after")]
+    /// After how many near-identical tool calls in a row the agent is made to answer
+    pub loop_answer_after: usize,
+
+    #[clap(long, default_value_t = default_index_warning_threshold())]
+    #[serde(default = "default_index_warning_threshold")]
+    /// The share of a repository's files, between 0 and 1, that must have failed to index for
+    /// answers from it to carry a caveat
+    pub index_warning_threshold: f32,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Playbooks to run in addition to the built-in ones, or in their place, if they have the same
+    /// name.
+    ///
+    /// Playbooks can also be stored as `.json` files in the `playbooks` directory of the index.
+    pub playbooks: Vec<Playbook>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// The canary query that `POST /admin/selftest` runs, to check that answering works end to end
+    pub canary: CanaryConfig,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// Groups that can be listed in repository ACLs, with the GitHub logins of their members
+    pub acl_groups: BTreeMap<String, Vec<String>>,
+
+    #[clap(skip)]
+    #[serde(default)]
+    /// GitHub logins of the users that can change repository ACLs.
+    ///
+    /// If this is empty, every user can.
+    pub acl_admins: Vec<String>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Directory that the admin API can write backups to and restore them from.
+    ///
+    /// Local backup paths given to the API are relative to this directory. If it is not set, the
+    /// API can only use object stores.
+    pub backup_dir: Option<PathBuf>,
+
+    #[clap(long, default_value_t = default_query_history_retention_days())]
+    #[serde(default = "default_query_history_retention_days")]
+    /// How many days queries are kept in the query history of users
+    pub query_history_retention_days: u64,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Extra secret patterns to redact from repository content and answers, as `kind=regex`.
+    ///
+    /// Matches are replaced with `[REDACTED:<kind>]`, alongside the built-in detectors.
+    pub secret_patterns: Vec<String>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Patterns of personal data, such as email addresses, to replace with `[REDACTED]` in stored
+    /// threads
+    pub anonymize_patterns: Vec<String>,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Repositories that secrets are not redacted for, such as the ones security teams audit
+    pub disable_secret_redaction_repos: Vec<String>,
+
+    #[clap(long, default_value_t = false)]
+    #[serde(default)]
+    /// Leave queries, tool calls and answers out of exported runs, keeping only their metadata
+    pub redact_exported_runs: bool,
+
+    #[clap(long)]
+    #[serde(default)]
+    /// Documents to show the agent when they are relevant to a query, such as documentation that
+    /// isn't in an indexed repository.
+    ///
+    /// Each document is titled with its file name.
+    pub knowledge_base: Vec<PathBuf>,
+
     #[clap(long)]
     /// Key for analytics backend
     pub analytics_key: Option<String>,
@@ -112,6 +304,12 @@ pub struct Configuration {
     /// URL for the qdrant server
     pub qdrant_url: Option<String>,
 
+    #[clap(long, value_enum, default_value_t = Backend::default())]
+    #[serde(default)]
+    /// Vector store for semantic search. The embedded store is meant for a handful of small
+    /// repositories, and doesn't need a qdrant server
+    pub semantic_backend: Backend,
+
     #[clap(long, default_value_os_t = default_model_dir())]
     #[serde(default = "default_model_dir")]
     /// Path to the embedding model directory
@@ -126,6 +324,17 @@ pub struct Configuration {
     /// Chunking strategy
     pub overlap: Option<OverlapStrategy>,
 
+    #[clap(long, default_value_t = default_bm25_weight())]
+    #[serde(default = "default_bm25_weight")]
+    /// Weight of the BM25 score when re-ranking semantic search results, between 0 and 1
+    pub bm25_weight: f32,
+
+    #[clap(long, default_value_t = default_mmr_lambda())]
+    #[serde(default = "default_mmr_lambda")]
+    /// Trade-off between relevance and diversity when ordering code search results, between 0
+    /// (most diverse) and 1 (most relevant)
+    pub mmr_lambda: f32,
+
     //
     // Installation-specific values
     //
@@ -199,17 +408,21 @@ impl Configuration {
     }
 
     pub fn cli_overriding_config_file() -> Result<Self> {
-        let cli = Self::from_cli()?;
-        let Ok(file) = cli
+        Self::from_cli()?.overriding_config_file()
+    }
+
+    /// Merge in the config file named by `config_file`, if there is one
+    pub fn overriding_config_file(self) -> Result<Self> {
+        let Ok(file) = self
             .config_file
             .as_ref()
             .context("no config file specified")
             .and_then(Self::read) else
         {
-            return Ok(cli);
+            return Ok(self);
         };
 
-        Ok(Self::merge(file, cli))
+        Ok(Self::merge(file, self))
     }
 
     /// Merge 2 configurations with values from `b` taking precedence
@@ -230,9 +443,12 @@ impl Configuration {
             index_only: b.index_only | a.index_only,
 
             disable_background: b.disable_background | a.disable_background,
+            disable_analytics: b.disable_analytics | a.disable_analytics,
 
             disable_fsevents: b.disable_fsevents | a.disable_fsevents,
 
+            warmup_repos: right_if_default!(b.warmup_repos, a.warmup_repos, Vec::<String>::new()),
+
             disable_log_write: b.disable_log_write | a.disable_log_write,
 
             buffer_size: right_if_default!(b.buffer_size, a.buffer_size, default_buffer_size()),
@@ -245,10 +461,14 @@ impl Configuration {
 
             max_threads: right_if_default!(b.max_threads, a.max_threads, default_parallelism()),
 
+            lfs: right_if_default!(b.lfs, a.lfs, LfsPolicy::default()),
+
             host: right_if_default!(b.host, a.host, default_host()),
 
             port: right_if_default!(b.port, a.port, default_port()),
 
+            editor_port: b.editor_port.or(a.editor_port),
+
             model_dir: right_if_default!(b.model_dir, a.model_dir, default_model_dir()),
 
             max_chunk_tokens: right_if_default!(
@@ -259,16 +479,159 @@ impl Configuration {
 
             overlap: b.overlap.or(a.overlap),
 
+            bm25_weight: right_if_default!(b.bm25_weight, a.bm25_weight, default_bm25_weight()),
+
+            mmr_lambda: right_if_default!(b.mmr_lambda, a.mmr_lambda, default_mmr_lambda()),
+
             frontend_dist: b.frontend_dist.or(a.frontend_dist),
 
             qdrant_url: b.qdrant_url.or(a.qdrant_url),
 
+            semantic_backend: right_if_default!(
+                b.semantic_backend,
+                a.semantic_backend,
+                Backend::default()
+            ),
+
             answer_api_url: right_if_default!(
                 b.answer_api_url,
                 a.answer_api_url,
                 default_answer_api_url()
             ),
 
+            osv_api_url: right_if_default!(b.osv_api_url, a.osv_api_url, default_osv_api_url()),
+
+            github_api_url: right_if_default!(
+                b.github_api_url,
+                a.github_api_url,
+                default_github_api_url()
+            ),
+
+            external_context_hosts: right_if_default!(
+                b.external_context_hosts,
+                a.external_context_hosts,
+                default_external_context_hosts()
+            ),
+
+            legacy_function_call_framing: b.legacy_function_call_framing
+                | a.legacy_function_call_framing,
+
+            call_graph_depth: right_if_default!(
+                b.call_graph_depth,
+                a.call_graph_depth,
+                default_call_graph_depth()
+            ),
+
+            llm_endpoints: if b.llm_endpoints.is_empty() {
+                a.llm_endpoints
+            } else {
+                b.llm_endpoints
+            },
+
+            llm_endpoint_cooldown_secs: right_if_default!(
+                b.llm_endpoint_cooldown_secs,
+                a.llm_endpoint_cooldown_secs,
+                default_llm_endpoint_cooldown_secs()
+            ),
+
+            call_graph_fan_out: right_if_default!(
+                b.call_graph_fan_out,
+                a.call_graph_fan_out,
+                default_call_graph_fan_out()
+            ),
+
+            token_safety_margin: right_if_default!(
+                b.token_safety_margin,
+                a.token_safety_margin,
+                default_token_safety_margin()
+            ),
+
+            max_files_sent: right_if_default!(
+                b.max_files_sent,
+                a.max_files_sent,
+                default_max_files_sent()
+            ),
+
+            max_response_tokens: b.max_response_tokens.or(a.max_response_tokens),
+
+            loop_similarity: right_if_default!(
+                b.loop_similarity,
+                a.loop_similarity,
+                default_loop_similarity()
+            ),
+
+            loop_nudge_after: right_if_default!(
+                b.loop_nudge_after,
+                a.loop_nudge_after,
+                default_loop_nudge_after()
+            ),
+
+            loop_answer_after: right_if_default!(
+                b.loop_answer_after,
+                a.loop_answer_after,
+                default_loop_answer_after()
+            ),
+
+            index_warning_threshold: right_if_default!(
+                b.index_warning_threshold,
+                a.index_warning_threshold,
+                default_index_warning_threshold()
+            ),
+
+            playbooks: if b.playbooks.is_empty() {
+                a.playbooks
+            } else {
+                b.playbooks
+            },
+
+            canary: right_if_default!(b.canary, a.canary, CanaryConfig::default()),
+
+            acl_groups: if b.acl_groups.is_empty() {
+                a.acl_groups
+            } else {
+                b.acl_groups
+            },
+
+            acl_admins: if b.acl_admins.is_empty() {
+                a.acl_admins
+            } else {
+                b.acl_admins
+            },
+
+            backup_dir: b.backup_dir.or(a.backup_dir),
+
+            query_history_retention_days: right_if_default!(
+                b.query_history_retention_days,
+                a.query_history_retention_days,
+                default_query_history_retention_days()
+            ),
+
+            secret_patterns: right_if_default!(
+                b.secret_patterns,
+                a.secret_patterns,
+                Vec::<String>::new()
+            ),
+
+            anonymize_patterns: right_if_default!(
+                b.anonymize_patterns,
+                a.anonymize_patterns,
+                Vec::<String>::new()
+            ),
+
+            disable_secret_redaction_repos: right_if_default!(
+                b.disable_secret_redaction_repos,
+                a.disable_secret_redaction_repos,
+                Vec::<String>::new()
+            ),
+
+            redact_exported_runs: b.redact_exported_runs | a.redact_exported_runs,
+
+            knowledge_base: right_if_default!(
+                b.knowledge_base,
+                a.knowledge_base,
+                Vec::<PathBuf>::new()
+            ),
+
             github_client_id: b.github_client_id.or(a.github_client_id),
 
             github_client_secret: b.github_client_secret.or(a.github_client_secret),
@@ -300,6 +663,11 @@ impl Configuration {
     pub fn log_dir(&self) -> PathBuf {
         self.index_dir.join("logs")
     }
+
+    /// Directory where playbooks are read from, one per `.json` file
+    pub fn playbooks_dir(&self) -> PathBuf {
+        self.index_dir.join("playbooks")
+    }
 }
 
 pub fn serialize_secret_opt_str<S>(
@@ -364,6 +732,68 @@ fn default_answer_api_url() -> String {
     String::from("http://127.0.0.1:7879")
 }
 
+fn default_osv_api_url() -> String {
+    String::from("https://api.osv.dev")
+}
+
+fn default_github_api_url() -> String {
+    String::from("https://api.github.com")
+}
+
+fn default_external_context_hosts() -> Vec<String> {
+    ["raw.githubusercontent.com", "gist.githubusercontent.com"]
+        .map(String::from)
+        .into()
+}
+
+const fn default_call_graph_depth() -> usize {
+    1
+}
+
+const fn default_call_graph_fan_out() -> usize {
+    5
+}
+
+const fn default_token_safety_margin() -> usize {
+    crate::agent::tokens::DEFAULT_SAFETY_MARGIN
+}
+
+const fn default_max_files_sent() -> usize {
+    15
+}
+
+fn default_loop_similarity() -> f32 {
+    crate::agent::loops::DEFAULT_SIMILARITY
+}
+
+const fn default_loop_nudge_after() -> usize {
+    crate::agent::loops::DEFAULT_NUDGE_AFTER
+}
+
+const fn default_loop_answer_after() -> usize {
+    crate::agent::loops::DEFAULT_ANSWER_AFTER
+}
+
+fn default_index_warning_threshold() -> f32 {
+    crate::indexes::diagnostics::DEFAULT_WARNING_THRESHOLD
+}
+
+const fn default_query_history_retention_days() -> u64 {
+    90
+}
+
+const fn default_llm_endpoint_cooldown_secs() -> u64 {
+    60
+}
+
 fn default_max_chunk_tokens() -> usize {
     256
 }
+
+fn default_bm25_weight() -> f32 {
+    crate::semantic::DEFAULT_BM25_WEIGHT
+}
+
+fn default_mmr_lambda() -> f32 {
+    crate::semantic::DEFAULT_MMR_LAMBDA
+}
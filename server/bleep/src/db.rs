@@ -6,8 +6,22 @@ use tracing::{debug, warn};
 
 use crate::Configuration;
 
+mod faq;
+mod prompt_examples;
+mod pull_requests;
+mod query_history;
 mod query_log;
+mod repo_acls;
+mod snippets;
+mod usage;
+pub use faq::{Faq, Faqs};
+pub use prompt_examples::{PromptExample, PromptExamples};
+pub use pull_requests::{PullRequest, PullRequests};
+pub use query_history::{Cursor, HistoryEntry, HistoryPage, QueryHistory, QueryStatus};
 pub use query_log::QueryLog;
+pub use repo_acls::{RepoAcl, RepoAcls};
+pub use snippets::{Snippet, SnippetId, SnippetStore};
+pub use usage::{Usage, UsageRecord};
 
 pub type SqlDb = Arc<SqlitePool>;
 
@@ -29,7 +43,7 @@ pub async fn init(config: &Configuration) -> Result<SqlitePool> {
     }
 }
 
-async fn connect(data_dir: &str) -> Result<SqlitePool> {
+pub(crate) async fn connect(data_dir: &str) -> Result<SqlitePool> {
     let url = format!("sqlite://{data_dir}/bleep.db?mode=rwc");
     debug!("loading db from {url}");
     let pool = SqlitePool::connect(&url).await?;
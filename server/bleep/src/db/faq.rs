@@ -0,0 +1,108 @@
+/// A canned answer, returned instead of running the agent when a query matches.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Faq {
+    pub id: i64,
+    /// Unix timestamp, in seconds.
+    pub created_at: i64,
+    pub question: String,
+    /// An optional regex, matched against the user query.
+    pub pattern: Option<String>,
+    /// Markdown text.
+    pub answer: String,
+    /// If set, this FAQ only applies to queries against this repository.
+    pub repo_ref: Option<String>,
+    #[serde(skip)]
+    pub embedding: Option<Vec<f32>>,
+}
+
+pub struct Faqs<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> Faqs<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<Faq>> {
+        let recs = sqlx::query!(
+            "SELECT id, created_at, question, pattern, answer, repo_ref, embedding \
+             FROM faqs \
+             ORDER BY id"
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        Ok(recs
+            .into_iter()
+            .map(|r| Faq {
+                id: r.id,
+                created_at: r.created_at,
+                question: r.question,
+                pattern: r.pattern,
+                answer: r.answer,
+                repo_ref: r.repo_ref,
+                embedding: r.embedding.as_deref().map(decode_embedding),
+            })
+            .collect())
+    }
+
+    /// Insert a new FAQ, returning its ID.
+    pub async fn insert(
+        &self,
+        question: &str,
+        pattern: Option<&str>,
+        answer: &str,
+        repo_ref: Option<&str>,
+        embedding: Option<&[f32]>,
+    ) -> anyhow::Result<i64> {
+        let embedding = embedding.map(encode_embedding);
+
+        let id = sqlx::query!(
+            "INSERT INTO faqs (created_at, question, pattern, answer, repo_ref, embedding) \
+             VALUES (strftime('%s', 'now'), ?, ?, ?, ?, ?) \
+             RETURNING id",
+            question,
+            pattern,
+            answer,
+            repo_ref,
+            embedding,
+        )
+        .fetch_one(self.db)
+        .await?
+        .id;
+
+        Ok(id)
+    }
+
+    /// Delete an FAQ, returning whether it existed.
+    pub async fn delete(&self, id: i64) -> anyhow::Result<bool> {
+        let result = sqlx::query!("DELETE FROM faqs WHERE id = ?", id)
+            .execute(self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn encode_embedding(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_roundtrip() {
+        let embedding = vec![0.0, 1.5, -2.25, f32::MIN_POSITIVE];
+        assert_eq!(decode_embedding(&encode_embedding(&embedding)), embedding);
+    }
+}
@@ -0,0 +1,137 @@
+/// A few-shot example of a tool call sequence, mined from a positively rated exchange.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct PromptExample {
+    pub id: i64,
+    /// Unix timestamp, in seconds.
+    pub created_at: i64,
+    pub repo_ref: String,
+    pub query_id: String,
+    pub example: String,
+    /// The number of tool calls in the example.
+    pub steps: i64,
+    pub pinned: bool,
+    pub selected: bool,
+}
+
+pub struct PromptExamples<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> PromptExamples<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// Insert a candidate example. Examples are unique by the exchange they were mined from.
+    pub async fn insert(
+        &self,
+        repo_ref: &str,
+        query_id: &str,
+        example: &str,
+        steps: i64,
+    ) -> anyhow::Result<()> {
+        sqlx::query!(
+            "INSERT INTO prompt_examples (created_at, repo_ref, query_id, example, steps) \
+             VALUES (strftime('%s', 'now'), ?, ?, ?, ?) \
+             ON CONFLICT (query_id) DO NOTHING",
+            repo_ref,
+            query_id,
+            example,
+            steps,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn for_repo(&self, repo_ref: &str) -> anyhow::Result<Vec<PromptExample>> {
+        let recs = sqlx::query!(
+            "SELECT id, created_at, repo_ref, query_id, example, steps, pinned, selected \
+             FROM prompt_examples \
+             WHERE repo_ref = ? \
+             ORDER BY id",
+            repo_ref,
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        Ok(recs
+            .into_iter()
+            .map(|r| PromptExample {
+                id: r.id,
+                created_at: r.created_at,
+                repo_ref: r.repo_ref,
+                query_id: r.query_id,
+                example: r.example,
+                steps: r.steps,
+                pinned: r.pinned,
+                selected: r.selected,
+            })
+            .collect())
+    }
+
+    pub async fn all(&self) -> anyhow::Result<Vec<PromptExample>> {
+        let recs = sqlx::query!(
+            "SELECT id, created_at, repo_ref, query_id, example, steps, pinned, selected \
+             FROM prompt_examples \
+             ORDER BY id"
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        Ok(recs
+            .into_iter()
+            .map(|r| PromptExample {
+                id: r.id,
+                created_at: r.created_at,
+                repo_ref: r.repo_ref,
+                query_id: r.query_id,
+                example: r.example,
+                steps: r.steps,
+                pinned: r.pinned,
+                selected: r.selected,
+            })
+            .collect())
+    }
+
+    /// Mark exactly the examples in `selected` as selected, out of `examples`.
+    pub async fn set_selected(
+        &self,
+        examples: &[PromptExample],
+        selected: &[i64],
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.db.begin().await?;
+
+        for example in examples {
+            let is_selected = selected.contains(&example.id);
+            if is_selected == example.selected {
+                continue;
+            }
+
+            sqlx::query!(
+                "UPDATE prompt_examples SET selected = ? WHERE id = ?",
+                is_selected,
+                example.id,
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Pin or unpin an example, returning its repository if it exists.
+    pub async fn set_pinned(&self, id: i64, pinned: bool) -> anyhow::Result<Option<String>> {
+        let rec = sqlx::query!(
+            "UPDATE prompt_examples SET pinned = ? WHERE id = ? RETURNING repo_ref",
+            pinned,
+            id,
+        )
+        .fetch_optional(self.db)
+        .await?;
+
+        Ok(rec.map(|r| r.repo_ref))
+    }
+}
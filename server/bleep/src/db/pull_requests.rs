@@ -0,0 +1,113 @@
+use anyhow::Context;
+
+/// An open pull request of a GitHub repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PullRequest {
+    pub number: i64,
+    pub title: String,
+    pub body: String,
+    /// The GitHub login of the author.
+    pub author: String,
+    pub url: String,
+    /// The paths that this pull request changes.
+    pub changed_files: Vec<String>,
+    /// When this pull request was last updated, as reported by GitHub.
+    pub updated_at: String,
+}
+
+pub struct PullRequests<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> PullRequests<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    /// The ETag of the last listing of a repository's open pull requests, if it has been synced.
+    pub async fn etag(&self, repo_ref: &str) -> anyhow::Result<Option<String>> {
+        let rec = sqlx::query!(
+            "SELECT etag FROM pull_request_syncs WHERE repo_ref = ?",
+            repo_ref,
+        )
+        .fetch_optional(self.db)
+        .await?;
+
+        Ok(rec.and_then(|r| r.etag))
+    }
+
+    /// The open pull requests of a repository, most recent first.
+    pub async fn for_repo(&self, repo_ref: &str) -> anyhow::Result<Vec<PullRequest>> {
+        let recs = sqlx::query!(
+            "SELECT number, title, body, author, url, changed_files, updated_at \
+             FROM pull_requests \
+             WHERE repo_ref = ? \
+             ORDER BY number DESC",
+            repo_ref,
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        recs.into_iter()
+            .map(|r| {
+                Ok(PullRequest {
+                    number: r.number,
+                    title: r.title,
+                    body: r.body,
+                    author: r.author,
+                    url: r.url,
+                    changed_files: serde_json::from_str(&r.changed_files)
+                        .context("invalid changed files")?,
+                    updated_at: r.updated_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Replace the open pull requests of a repository, recording the ETag of their listing.
+    pub async fn replace(
+        &self,
+        repo_ref: &str,
+        etag: Option<&str>,
+        pull_requests: &[PullRequest],
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.db.begin().await?;
+
+        sqlx::query!("DELETE FROM pull_requests WHERE repo_ref = ?", repo_ref)
+            .execute(&mut transaction)
+            .await?;
+
+        for pr in pull_requests {
+            let changed_files = serde_json::to_string(&pr.changed_files)?;
+
+            sqlx::query!(
+                "INSERT INTO pull_requests \
+                 (repo_ref, number, title, body, author, url, changed_files, updated_at) \
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                repo_ref,
+                pr.number,
+                pr.title,
+                pr.body,
+                pr.author,
+                pr.url,
+                changed_files,
+                pr.updated_at,
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        sqlx::query!(
+            "INSERT INTO pull_request_syncs (repo_ref, etag, synced_at) \
+             VALUES (?, ?, strftime('%s', 'now')) \
+             ON CONFLICT (repo_ref) DO UPDATE SET etag = excluded.etag, synced_at = excluded.synced_at",
+            repo_ref,
+            etag,
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+}
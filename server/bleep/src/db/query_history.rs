@@ -0,0 +1,411 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context};
+
+/// How answering a query ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryStatus {
+    /// The query is still being answered.
+    Pending,
+    Answered,
+    Errored,
+    /// The user stopped waiting for an answer.
+    Cancelled,
+}
+
+impl QueryStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Answered => "answered",
+            Self::Errored => "errored",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for QueryStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "pending" => Self::Pending,
+            "answered" => Self::Answered,
+            "errored" => Self::Errored,
+            "cancelled" => Self::Cancelled,
+            _ => bail!("unknown query status `{s}`"),
+        })
+    }
+}
+
+/// A query that a user asked.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct HistoryEntry {
+    /// Unix timestamp, in seconds.
+    pub created_at: i64,
+    pub thread_id: String,
+    pub exchange_id: String,
+    pub repo_ref: String,
+    pub query: String,
+    pub status: QueryStatus,
+}
+
+/// The position after which a page of history starts, as the last entry of the previous page.
+///
+/// This is serialized as an opaque `<created_at>.<id>` string, so that pages never need an
+/// offset scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    created_at: i64,
+    id: i64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.created_at, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (created_at, id) = s.split_once('.').context("invalid cursor")?;
+
+        Ok(Self {
+            created_at: created_at.parse().context("invalid cursor")?,
+            id: id.parse().context("invalid cursor")?,
+        })
+    }
+}
+
+/// A page of a user's query history, newest first.
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryPage {
+    pub queries: Vec<HistoryEntry>,
+    /// The cursor of the next page, if there are older entries.
+    pub next_page: Option<String>,
+}
+
+struct HistoryRow {
+    id: i64,
+    created_at: i64,
+    thread_id: String,
+    exchange_id: String,
+    repo_ref: String,
+    query: String,
+    status: String,
+}
+
+pub struct QueryHistory<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> QueryHistory<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    pub async fn insert(&self, user_id: &str, entry: &HistoryEntry) -> anyhow::Result<()> {
+        let status = entry.status.as_str();
+
+        sqlx::query!(
+            "INSERT INTO query_history \
+             (created_at, user_id, thread_id, exchange_id, repo_ref, query, status) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            entry.created_at,
+            user_id,
+            entry.thread_id,
+            entry.exchange_id,
+            entry.repo_ref,
+            entry.query,
+            status,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_status(&self, exchange_id: &str, status: QueryStatus) -> anyhow::Result<()> {
+        let status = status.as_str();
+
+        sqlx::query!(
+            "UPDATE query_history SET status = ? WHERE exchange_id = ?",
+            status,
+            exchange_id,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark a query as cancelled, unless answering it has already ended some other way.
+    pub async fn cancel(&self, exchange_id: &str) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE query_history SET status = 'cancelled' \
+             WHERE exchange_id = ? AND status = 'pending'",
+            exchange_id,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// A page of at most `per_page` queries of a user, newest first.
+    ///
+    /// Queries older than `since`, a unix timestamp, have expired and are left out. If `repo_ref`
+    /// is given, only queries about that repository are listed.
+    pub async fn page(
+        &self,
+        user_id: &str,
+        repo_ref: Option<&str>,
+        since: i64,
+        after: Option<Cursor>,
+        per_page: u32,
+    ) -> anyhow::Result<HistoryPage> {
+        let Cursor { created_at, id } = after.unwrap_or(Cursor {
+            created_at: i64::MAX,
+            id: i64::MAX,
+        });
+        // Fetch one more entry than requested, to know whether there is a next page.
+        let limit = i64::from(per_page) + 1;
+
+        let mut rows = if let Some(repo_ref) = repo_ref {
+            sqlx::query_as!(
+                HistoryRow,
+                "SELECT id, created_at, thread_id, exchange_id, repo_ref, query, status \
+                 FROM query_history \
+                 WHERE user_id = ? AND repo_ref = ? AND created_at >= ? \
+                 AND (created_at, id) < (?, ?) \
+                 ORDER BY created_at DESC, id DESC \
+                 LIMIT ?",
+                user_id,
+                repo_ref,
+                since,
+                created_at,
+                id,
+                limit,
+            )
+            .fetch_all(self.db)
+            .await?
+        } else {
+            sqlx::query_as!(
+                HistoryRow,
+                "SELECT id, created_at, thread_id, exchange_id, repo_ref, query, status \
+                 FROM query_history \
+                 WHERE user_id = ? AND created_at >= ? \
+                 AND (created_at, id) < (?, ?) \
+                 ORDER BY created_at DESC, id DESC \
+                 LIMIT ?",
+                user_id,
+                since,
+                created_at,
+                id,
+                limit,
+            )
+            .fetch_all(self.db)
+            .await?
+        };
+
+        let next_page = if rows.len() > per_page as usize {
+            rows.truncate(per_page as usize);
+            rows.last().map(|r| {
+                Cursor {
+                    created_at: r.created_at,
+                    id: r.id,
+                }
+                .to_string()
+            })
+        } else {
+            None
+        };
+
+        let queries = rows
+            .into_iter()
+            .map(|r| {
+                Ok(HistoryEntry {
+                    created_at: r.created_at,
+                    thread_id: r.thread_id,
+                    exchange_id: r.exchange_id,
+                    repo_ref: r.repo_ref,
+                    query: r.query,
+                    status: r.status.parse()?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(HistoryPage { queries, next_page })
+    }
+
+    /// Forget the queries of a deleted thread, returning how many there were.
+    pub async fn delete_thread(&self, user_id: &str, thread_id: &str) -> anyhow::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM query_history WHERE user_id = ? AND thread_id = ?",
+            user_id,
+            thread_id,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Delete queries older than `cutoff`, a unix timestamp.
+    pub async fn prune(&self, cutoff: i64) -> anyhow::Result<()> {
+        sqlx::query!("DELETE FROM query_history WHERE created_at < ?", cutoff)
+            .execute(self.db)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    const REPOS: [&str; 3] = [
+        "github.com/bloopai/bloop",
+        "github.com/bloopai/answer-api",
+        "local//tmp/notes",
+    ];
+
+    /// Page through the whole history of `alice`, returning the queries of every page.
+    async fn pages(
+        history: &QueryHistory<'_>,
+        repo_ref: Option<&str>,
+        since: i64,
+        per_page: u32,
+    ) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut after = None;
+
+        loop {
+            let page = history
+                .page("alice", repo_ref, since, after, per_page)
+                .await
+                .unwrap();
+            pages.push(page.queries.into_iter().map(|e| e.query).collect());
+
+            match page.next_page {
+                Some(cursor) => after = Some(cursor.parse().unwrap()),
+                None => break pages,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_history() {
+        let tmpdir = TempDir::new("test-query-history").unwrap();
+        let db = crate::db::connect(&tmpdir.path().to_string_lossy())
+            .await
+            .unwrap();
+        let history = QueryHistory::new(&db);
+
+        // 150 queries over 15 threads, two of them at every timestamp.
+        for i in 0..150 {
+            let entry = HistoryEntry {
+                created_at: 1_000 + i / 2,
+                thread_id: format!("thread-{}", i / 10),
+                exchange_id: format!("exchange-{i}"),
+                repo_ref: REPOS[i as usize % 3].to_owned(),
+                query: format!("query {i}"),
+                status: QueryStatus::Answered,
+            };
+            history.insert("alice", &entry).await.unwrap();
+        }
+
+        let bob = HistoryEntry {
+            created_at: 2_000,
+            thread_id: "thread-bob".into(),
+            exchange_id: "exchange-bob".into(),
+            repo_ref: REPOS[0].into(),
+            query: "bob's query".into(),
+            status: QueryStatus::Pending,
+        };
+        history.insert("bob", &bob).await.unwrap();
+
+        // Pages are newest first, and follow on from each other without gaps.
+        let all = pages(&history, None, 0, 20).await;
+        assert_eq!(
+            all.iter().map(Vec::len).collect::<Vec<_>>(),
+            [20, 20, 20, 20, 20, 20, 20, 10]
+        );
+        assert_eq!(
+            all.concat(),
+            (0..150)
+                .rev()
+                .map(|i| format!("query {i}"))
+                .collect::<Vec<_>>()
+        );
+
+        // A history that fills its last page exactly has no empty page after it.
+        assert_eq!(pages(&history, None, 0, 50).await.len(), 3);
+
+        let repo = pages(&history, Some(REPOS[1]), 0, 20).await.concat();
+        assert_eq!(
+            repo,
+            (0..150)
+                .rev()
+                .filter(|i| i % 3 == 1)
+                .map(|i| format!("query {i}"))
+                .collect::<Vec<_>>()
+        );
+
+        // Expired queries are left out.
+        let recent = pages(&history, None, 1_070, 20).await.concat();
+        assert_eq!(recent.len(), 10);
+        assert_eq!(recent.last().unwrap(), "query 140");
+
+        // Deleted threads are forgotten.
+        assert_eq!(
+            history.delete_thread("alice", "thread-14").await.unwrap(),
+            10
+        );
+        assert_eq!(
+            history.delete_thread("alice", "thread-bob").await.unwrap(),
+            0
+        );
+        let remaining = pages(&history, None, 1_070, 20).await.concat();
+        assert!(remaining.is_empty());
+        assert_eq!(pages(&history, None, 0, 20).await.concat().len(), 140);
+
+        // Queries that errored keep their status, while pending ones can be cancelled.
+        history
+            .set_status("exchange-0", QueryStatus::Errored)
+            .await
+            .unwrap();
+        history.cancel("exchange-0").await.unwrap();
+        history.cancel("exchange-bob").await.unwrap();
+
+        let page = history.page("alice", None, 0, None, 200).await.unwrap();
+        assert_eq!(page.queries.last().unwrap().status, QueryStatus::Errored);
+        let page = history.page("bob", None, 0, None, 20).await.unwrap();
+        assert_eq!(page.queries.len(), 1);
+        assert_eq!(page.queries[0].status, QueryStatus::Cancelled);
+        assert_eq!(page.next_page, None);
+
+        history.prune(1_010).await.unwrap();
+        assert_eq!(pages(&history, None, 0, 20).await.concat().len(), 120);
+
+        db.close().await;
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = Cursor {
+            created_at: 1_700_000_000,
+            id: 42,
+        };
+        assert_eq!(cursor.to_string().parse::<Cursor>().unwrap(), cursor);
+        assert!("42".parse::<Cursor>().is_err());
+        assert!("a.b".parse::<Cursor>().is_err());
+    }
+}
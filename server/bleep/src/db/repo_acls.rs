@@ -0,0 +1,66 @@
+use anyhow::Context;
+
+/// Who can query a repository.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RepoAcl {
+    pub repo_ref: String,
+    /// Public repositories can be queried by everyone, whoever is listed in `users` and `groups`.
+    #[serde(default)]
+    pub public: bool,
+    /// The GitHub logins that can query the repository.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// The groups, from the `acl_groups` configuration, that can query the repository.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+pub struct RepoAcls<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> RepoAcls<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<RepoAcl>> {
+        let recs = sqlx::query!(
+            "SELECT repo_ref, public, users, groups FROM repo_acls ORDER BY repo_ref"
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        recs.into_iter()
+            .map(|r| {
+                Ok(RepoAcl {
+                    repo_ref: r.repo_ref,
+                    public: r.public,
+                    users: serde_json::from_str(&r.users).context("invalid ACL users")?,
+                    groups: serde_json::from_str(&r.groups).context("invalid ACL groups")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Set the ACL of a repository, replacing the one it had.
+    pub async fn set(&self, acl: &RepoAcl) -> anyhow::Result<()> {
+        let users = serde_json::to_string(&acl.users)?;
+        let groups = serde_json::to_string(&acl.groups)?;
+
+        sqlx::query!(
+            "INSERT INTO repo_acls (repo_ref, public, users, groups, updated_at) \
+             VALUES (?, ?, ?, ?, strftime('%s', 'now')) \
+             ON CONFLICT (repo_ref) DO UPDATE SET public = excluded.public, users = excluded.users, \
+             groups = excluded.groups, updated_at = excluded.updated_at",
+            acl.repo_ref,
+            acl.public,
+            users,
+            groups,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+}
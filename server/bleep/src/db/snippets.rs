@@ -0,0 +1,196 @@
+use std::fmt;
+
+use anyhow::Context;
+
+/// The id of a saved answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SnippetId(pub uuid::Uuid);
+
+impl fmt::Display for SnippetId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An answer that a user saved for later reference.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Snippet {
+    pub id: SnippetId,
+    /// Unix timestamp, in seconds.
+    pub created_at: i64,
+    pub user_id: String,
+    pub repo_ref: String,
+    pub thread_id: String,
+    pub title: String,
+    /// The query that the answer was given to.
+    pub query: String,
+    pub answer: String,
+}
+
+pub struct SnippetStore<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> SnippetStore<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    pub async fn insert(
+        &self,
+        user_id: &str,
+        repo_ref: &str,
+        thread_id: &str,
+        title: &str,
+        query: &str,
+        answer: &str,
+    ) -> anyhow::Result<SnippetId> {
+        let id = SnippetId(uuid::Uuid::new_v4());
+        let id_str = id.to_string();
+
+        sqlx::query!(
+            "INSERT INTO snippets \
+             (id, created_at, user_id, repo_ref, thread_id, title, query, answer) \
+             VALUES (?, strftime('%s', 'now'), ?, ?, ?, ?, ?, ?)",
+            id_str,
+            user_id,
+            repo_ref,
+            thread_id,
+            title,
+            query,
+            answer,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(id)
+    }
+
+    pub async fn get(&self, id: SnippetId) -> anyhow::Result<Option<Snippet>> {
+        let id = id.to_string();
+        let rec = sqlx::query!(
+            "SELECT id, created_at, user_id, repo_ref, thread_id, title, query, answer \
+             FROM snippets \
+             WHERE id = ?",
+            id,
+        )
+        .fetch_optional(self.db)
+        .await?;
+
+        rec.map(|r| {
+            Ok(Snippet {
+                id: SnippetId(r.id.parse().context("invalid snippet id")?),
+                created_at: r.created_at,
+                user_id: r.user_id,
+                repo_ref: r.repo_ref,
+                thread_id: r.thread_id,
+                title: r.title,
+                query: r.query,
+                answer: r.answer,
+            })
+        })
+        .transpose()
+    }
+
+    /// All snippets saved by a user, most recent first.
+    pub async fn list_for_user(&self, user_id: &str) -> anyhow::Result<Vec<Snippet>> {
+        let recs = sqlx::query!(
+            "SELECT id, created_at, user_id, repo_ref, thread_id, title, query, answer \
+             FROM snippets \
+             WHERE user_id = ? \
+             ORDER BY created_at DESC, rowid DESC",
+            user_id,
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        recs.into_iter()
+            .map(|r| {
+                Ok(Snippet {
+                    id: SnippetId(r.id.parse().context("invalid snippet id")?),
+                    created_at: r.created_at,
+                    user_id: r.user_id,
+                    repo_ref: r.repo_ref,
+                    thread_id: r.thread_id,
+                    title: r.title,
+                    query: r.query,
+                    answer: r.answer,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snippet_store() {
+        let tmpdir = TempDir::new("test-snippets").unwrap();
+        let db = crate::db::connect(&tmpdir.path().to_string_lossy())
+            .await
+            .unwrap();
+        let store = SnippetStore::new(&db);
+
+        let repo_ref = "github.com/bloopai/bloop";
+        let first = store
+            .insert(
+                "alice",
+                repo_ref,
+                "thread-1",
+                "Query parsing",
+                "How are queries parsed?",
+                "Queries are parsed with pest.",
+            )
+            .await
+            .unwrap();
+        let second = store
+            .insert(
+                "alice",
+                repo_ref,
+                "thread-2",
+                "Indexing",
+                "Where are files indexed?",
+                "In `indexes/file.rs`.",
+            )
+            .await
+            .unwrap();
+        store
+            .insert("bob", repo_ref, "thread-3", "Other", "?", "!")
+            .await
+            .unwrap();
+
+        let snippet = store.get(first).await.unwrap().unwrap();
+        assert_eq!(snippet.id, first);
+        assert_eq!(snippet.user_id, "alice");
+        assert_eq!(snippet.thread_id, "thread-1");
+        assert_eq!(snippet.title, "Query parsing");
+        assert_eq!(snippet.query, "How are queries parsed?");
+        assert_eq!(snippet.answer, "Queries are parsed with pest.");
+
+        assert_eq!(
+            store.get(SnippetId(uuid::Uuid::new_v4())).await.unwrap(),
+            None
+        );
+
+        // Users only see their own snippets, most recent first.
+        let ids = store
+            .list_for_user("alice")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [second, first]);
+
+        assert_eq!(store.list_for_user("bob").await.unwrap().len(), 1);
+        assert!(store.list_for_user("carol").await.unwrap().is_empty());
+
+        db.close().await;
+    }
+}
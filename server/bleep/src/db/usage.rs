@@ -0,0 +1,120 @@
+/// A single LLM call made while answering a query.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct UsageRecord {
+    /// Unix timestamp, in seconds.
+    pub created_at: i64,
+    pub user_id: Option<String>,
+    pub repo_ref: String,
+    pub thread_id: String,
+    pub query_id: String,
+    /// The run that this call was part of, for records made before runs were tracked.
+    pub run_id: Option<String>,
+    pub stage: String,
+    pub model: String,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub latency_ms: i64,
+    /// The index of the LLM endpoint the call was sent to, if several are configured.
+    pub endpoint: Option<i64>,
+    /// How the query was answered, as named by `AnswerOutcome::as_str`. This is set on every
+    /// record of a run once its query is answered, or fails.
+    pub outcome: Option<String>,
+}
+
+pub struct Usage<'a> {
+    db: &'a super::SqlitePool,
+}
+
+impl<'a> Usage<'a> {
+    pub fn new(db: &'a super::SqlitePool) -> Self {
+        Self { db }
+    }
+
+    pub async fn insert(&self, record: &UsageRecord) -> anyhow::Result<()> {
+        sqlx::query!(
+            "INSERT INTO query_usage (\
+             created_at, user_id, repo_ref, thread_id, query_id, run_id, stage, model, \
+             prompt_tokens, completion_tokens, latency_ms, endpoint, outcome\
+             ) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            record.created_at,
+            record.user_id,
+            record.repo_ref,
+            record.thread_id,
+            record.query_id,
+            record.run_id,
+            record.stage,
+            record.model,
+            record.prompt_tokens,
+            record.completion_tokens,
+            record.latency_ms,
+            record.endpoint,
+            record.outcome,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Fetch all records created in the time range `[from, to)`, given as unix timestamps.
+    pub async fn between(&self, from: i64, to: i64) -> anyhow::Result<Vec<UsageRecord>> {
+        let recs = sqlx::query!(
+            "SELECT created_at, user_id, repo_ref, thread_id, query_id, run_id, stage, model, \
+             prompt_tokens, completion_tokens, latency_ms, endpoint, outcome \
+             FROM query_usage \
+             WHERE created_at >= ? AND created_at < ?",
+            from,
+            to,
+        )
+        .fetch_all(self.db)
+        .await?;
+
+        Ok(recs
+            .into_iter()
+            .map(|r| UsageRecord {
+                created_at: r.created_at,
+                user_id: r.user_id,
+                repo_ref: r.repo_ref,
+                thread_id: r.thread_id,
+                query_id: r.query_id,
+                run_id: r.run_id,
+                stage: r.stage,
+                model: r.model,
+                prompt_tokens: r.prompt_tokens,
+                completion_tokens: r.completion_tokens,
+                latency_ms: r.latency_ms,
+                endpoint: r.endpoint,
+                outcome: r.outcome,
+            })
+            .collect())
+    }
+
+    /// Fetch all records of the run `run_id`, oldest first.
+    pub async fn for_run(&self, run_id: &str) -> anyhow::Result<Vec<UsageRecord>> {
+        Ok(sqlx::query_as!(
+            UsageRecord,
+            "SELECT created_at, user_id, repo_ref, thread_id, query_id, run_id, stage, model, \
+             prompt_tokens, completion_tokens, latency_ms, endpoint, outcome \
+             FROM query_usage \
+             WHERE run_id = ? \
+             ORDER BY created_at, id",
+            run_id,
+        )
+        .fetch_all(self.db)
+        .await?)
+    }
+
+    /// Set the outcome of every record of the run `run_id`.
+    pub async fn set_outcome(&self, run_id: &str, outcome: &str) -> anyhow::Result<()> {
+        sqlx::query!(
+            "UPDATE query_usage SET outcome = ? WHERE run_id = ?",
+            outcome,
+            run_id,
+        )
+        .execute(self.db)
+        .await?;
+
+        Ok(())
+    }
+}
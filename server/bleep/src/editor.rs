@@ -0,0 +1,888 @@
+//! A minimal JSON-RPC server that answers "explain selection" requests from editors.
+//!
+//! Messages are framed like the Language Server Protocol, with a `Content-Length` header. An
+//! `explain` request streams the answer back as `$/progress` notifications, followed by a response
+//! with the final answer and the code it cites, as absolute local paths.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use lazy_regex::regex;
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpListener,
+    sync::mpsc,
+};
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::{
+        self,
+        exchange::{CodeChunk, ContextSource, Exchange, FocusedChunk},
+        Action,
+    },
+    llm_gateway,
+    query::parser,
+    repo::{Backend, RepoRef, RepoRemote, Repository},
+    webserver::middleware::User,
+    Application,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+const REPO_NOT_INDEXED: i64 = -32001;
+
+/// Listen for editor connections on `127.0.0.1:<port>`.
+///
+/// Connections are not authenticated, and are answered as the user logged in to this instance, so
+/// they are only accepted from this machine, whichever host the webserver is bound to.
+pub async fn start(app: Application, port: u16) -> Result<()> {
+    let bind = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+    let listener = TcpListener::bind(bind).await?;
+    info!(%bind, "starting editor server");
+
+    let explainer = Arc::new(AgentExplainer { app }) as Arc<dyn Explainer>;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        debug!(%peer, "editor connected");
+
+        let explainer = Arc::clone(&explainer);
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            if let Err(err) = serve(explainer, reader, writer).await {
+                error!(?err, %peer, "editor connection failed");
+            }
+        });
+    }
+}
+
+/// A request to explain a selection in a file checked out on the editor's machine.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ExplainParams {
+    pub repo_root: PathBuf,
+    /// The path of the file, either absolute or relative to `repo_root`.
+    pub path: PathBuf,
+    pub range: Range,
+    #[serde(default)]
+    pub question: Option<String>,
+}
+
+/// A range in a file, with 0-based positions as in the Language Server Protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Range {
+    pub start: Position,
+    /// The exclusive end of the range.
+    pub end: Position,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    #[serde(default)]
+    pub character: usize,
+}
+
+/// A selection to explain, relative to the root of an indexed repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub relative_path: String,
+    /// 1-based and inclusive.
+    pub line_start: usize,
+    /// 1-based and inclusive.
+    pub line_end: usize,
+    pub question: Option<String>,
+}
+
+impl Selection {
+    fn new(params: &ExplainParams) -> Result<Self, String> {
+        let path = if params.path.is_absolute() {
+            params.path.strip_prefix(&params.repo_root).map_err(|_| {
+                format!(
+                    "{} is not in {}",
+                    params.path.display(),
+                    params.repo_root.display()
+                )
+            })?
+        } else {
+            &params.path
+        };
+
+        let relative_path = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        let Range { start, end } = params.range;
+        if end.line < start.line {
+            return Err("the range ends before it starts".to_owned());
+        }
+
+        // Selecting whole lines puts the end at the start of the next line, which isn't selected.
+        let last_line = if end.character == 0 && end.line > start.line {
+            end.line - 1
+        } else {
+            end.line
+        };
+
+        Ok(Self {
+            relative_path,
+            line_start: start.line + 1,
+            line_end: last_line + 1,
+            question: params
+                .question
+                .clone()
+                .filter(|question| !question.trim().is_empty()),
+        })
+    }
+}
+
+/// A reference from an answer to code on the editor's machine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Citation {
+    pub path: PathBuf,
+    /// 1-based and inclusive, if the citation refers to specific lines.
+    pub start_line: Option<usize>,
+    pub end_line: Option<usize>,
+}
+
+/// Answers editor requests. This is separate from the RPC loop, so that the loop can be tested
+/// without an index or an LLM.
+#[async_trait]
+trait Explainer: Send + Sync {
+    /// Find the indexed repository that is checked out at `repo_root`, if the user can query it.
+    async fn resolve(&self, repo_root: &Path) -> Option<RepoRef>;
+
+    /// A shell command that indexes the repository checked out at `repo_root`.
+    fn index_command(&self, repo_root: &Path) -> String;
+
+    /// Answer a request, sending each update of the exchange to `exchange_tx`.
+    async fn explain(
+        &self,
+        repo_ref: RepoRef,
+        selection: Selection,
+        exchange_tx: mpsc::Sender<Exchange>,
+    ) -> Result<()>;
+}
+
+struct AgentExplainer {
+    app: Application,
+}
+
+#[async_trait]
+impl Explainer for AgentExplainer {
+    async fn resolve(&self, repo_root: &Path) -> Option<RepoRef> {
+        let root = repo_root
+            .canonicalize()
+            .unwrap_or_else(|_| repo_root.to_owned());
+
+        // Checkouts of remote repositories are matched by their remote, as they are indexed from
+        // a clone of their own.
+        let remote = match Repository::local_from(&RepoRef::from(&root)).remote {
+            RepoRemote::Git(remote) => Some((remote.host, remote.address)),
+            RepoRemote::None => None,
+        };
+
+        let mut local = None;
+        let mut remote_match = None;
+        self.app.repo_pool.scan(|reporef, repo| {
+            if repo.last_index_unix_secs == 0 {
+                return;
+            }
+
+            match (&reporef.backend, &repo.remote) {
+                (Backend::Local | Backend::LocalDir, _) if repo.disk_path == root => {
+                    local = Some(reporef.clone())
+                }
+                (Backend::Github, RepoRemote::Git(r))
+                    if remote.as_ref().map_or(false, |(host, address)| {
+                        *host == r.host && *address == r.address
+                    }) =>
+                {
+                    remote_match = Some(reporef.clone())
+                }
+                _ => {}
+            }
+        });
+
+        // Repositories that the user can't query are reported like the ones that aren't indexed.
+        let user = User::local(&self.app);
+        local
+            .or(remote_match)
+            .filter(|repo_ref| self.app.access.allows(&user, repo_ref))
+    }
+
+    fn index_command(&self, repo_root: &Path) -> String {
+        format!(
+            "curl 'http://{}:{}/api/repos/sync?repo={}'",
+            self.app.config.host,
+            self.app.config.port,
+            RepoRef::from(&repo_root)
+        )
+    }
+
+    async fn explain(
+        &self,
+        repo_ref: RepoRef,
+        selection: Selection,
+        exchange_tx: mpsc::Sender<Exchange>,
+    ) -> Result<()> {
+        let app = self.app.clone();
+        let query_id = uuid::Uuid::new_v4();
+        let thread_id = uuid::Uuid::new_v4();
+
+        let q = selection.question.clone().unwrap_or_else(|| {
+            format!(
+                "Explain lines {} - {} in {}",
+                selection.line_start, selection.line_end, selection.relative_path
+            )
+        });
+
+        let query = parser::parse_nl(&q)
+            .context("failed to parse query")?
+            .into_semantic()
+            .context("query was not a natural language query")?
+            .with_raw_target(&q)
+            .into_owned();
+
+        let file_content = app
+            .indexes
+            .file
+            .by_path(&repo_ref, &selection.relative_path, None)
+            .await
+            .context("file retrieval failed")?
+            .context("did not find requested file")?
+            .content;
+
+        let snippet = file_content
+            .lines()
+            .skip(selection.line_start - 1)
+            .take(selection.line_end + 1 - selection.line_start)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let mut exchange = Exchange::new(query_id, query);
+
+        exchange.focused_chunk = Some(FocusedChunk {
+            file_path: selection.relative_path.clone(),
+            start_line: selection.line_start,
+            end_line: selection.line_end,
+            ..Default::default()
+        });
+
+        exchange.include_context(
+            &selection.relative_path,
+            ContextSource::Pinned,
+            &[selection.line_start..selection.line_end + 1],
+            true,
+        );
+
+        exchange.paths.push(selection.relative_path.clone());
+        exchange.code_chunks.push(CodeChunk {
+            path: selection.relative_path.clone(),
+            alias: 0,
+            start_line: selection.line_start,
+            end_line: selection.line_end,
+            snippet,
+            moved_to: None,
+            deleted: false,
+            symbol_path: None,
+            blob: None,
+            revision: None,
+            mapped: None,
+        });
+
+        let gh_token = app.github_token()?.map(|s| s.expose_secret().clone());
+
+        let llm_gateway = llm_gateway::Client::new(&app.config.answer_api_url)
+            .temperature(0.0)
+            .bearer(gh_token)
+            .endpoints(app.llm_endpoints.clone())
+            .session_reference_id(thread_id.to_string());
+
+        let user = User::local(&app);
+        let mut driver = agent::builder::builder(app)
+            .repo(repo_ref)
+            .user(user)
+            .thread_id(thread_id)
+            .query_id(query_id)
+            .exchanges(vec![exchange])
+            .llm_gateway(llm_gateway)
+            .on_update(exchange_tx)
+            .build()?;
+
+        // Questions about the selection go through the agent loop, with the selection already
+        // in its context. Without a question, the selection is explained directly.
+        let action = match selection.question {
+            Some(question) => Action::Query(question),
+            None => Action::Answer { paths: vec![0] },
+        };
+
+        driver.run_action(action).await?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Request {
+    /// Notifications have no id, and get no response.
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+/// Handle requests from `reader` until the connection is closed, or an `exit` notification.
+async fn serve(
+    explainer: Arc<dyn Explainer>,
+    reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+
+    while let Some(body) = read_message(&mut reader).await? {
+        let request = match serde_json::from_slice::<Value>(&body) {
+            Ok(value) => serde_json::from_value::<Request>(value)
+                .map_err(|err| RpcError::new(INVALID_REQUEST, err.to_string())),
+            Err(err) => Err(RpcError::new(PARSE_ERROR, err.to_string())),
+        };
+
+        let request = match request {
+            Ok(request) => request,
+            Err(err) => {
+                write_message(&mut writer, &error_response(Value::Null, err)).await?;
+                continue;
+            }
+        };
+
+        let Some(id) = request.id else {
+            if request.method == "exit" {
+                break;
+            }
+
+            continue;
+        };
+
+        let response = match request.method.as_str() {
+            "explain" => match serde_json::from_value::<ExplainParams>(request.params) {
+                Ok(params) => explain(&*explainer, &id, params, &mut writer).await?,
+                Err(err) => Err(RpcError::new(INVALID_PARAMS, err.to_string())),
+            },
+            "shutdown" => Ok(Value::Null),
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method `{method}`"),
+            )),
+        };
+
+        let response = match response {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(err) => error_response(id, err),
+        };
+
+        write_message(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+/// Answer an `explain` request, sending the answer as it is written in `$/progress`
+/// notifications.
+///
+/// The outer error is an I/O error, and ends the connection.
+async fn explain(
+    explainer: &dyn Explainer,
+    id: &Value,
+    params: ExplainParams,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<Result<Value, RpcError>> {
+    if !params.repo_root.is_absolute() {
+        return Ok(Err(RpcError::new(
+            INVALID_PARAMS,
+            "repo_root must be an absolute path",
+        )));
+    }
+
+    let Some(repo_ref) = explainer.resolve(&params.repo_root).await else {
+        let command = explainer.index_command(&params.repo_root);
+        return Ok(Err(RpcError {
+            code: REPO_NOT_INDEXED,
+            message: format!(
+                "repo not indexed: {} is not indexed by bloop. Index it with `{command}`",
+                params.repo_root.display()
+            ),
+            data: Some(json!({ "command": command })),
+        }));
+    };
+
+    let selection = match Selection::new(&params) {
+        Ok(selection) => selection,
+        Err(message) => return Ok(Err(RpcError::new(INVALID_PARAMS, message))),
+    };
+
+    let (exchange_tx, mut exchange_rx) = mpsc::channel(10);
+    let run = explainer.explain(repo_ref, selection, exchange_tx);
+    tokio::pin!(run);
+
+    let mut last = None::<Exchange>;
+    let mut reported = String::new();
+
+    let result = loop {
+        let exchange = tokio::select! {
+            Some(exchange) = exchange_rx.recv() => exchange,
+            result = &mut run => break result,
+        };
+
+        report_progress(writer, id, &exchange, &mut reported).await?;
+        last = Some(exchange);
+    };
+
+    // Updates sent just before the run finished may still be queued.
+    while let Ok(exchange) = exchange_rx.try_recv() {
+        report_progress(writer, id, &exchange, &mut reported).await?;
+        last = Some(exchange);
+    }
+
+    let error = last.as_ref().and_then(|e| e.error.clone());
+    if let Err(err) = result {
+        return Ok(Err(RpcError::new(
+            INTERNAL_ERROR,
+            error.unwrap_or_else(|| err.to_string()),
+        )));
+    }
+
+    let answer = last
+        .as_ref()
+        .and_then(|e| e.answer())
+        .map(|(answer, _)| answer.to_owned())
+        .unwrap_or_default();
+
+    Ok(Ok(json!({
+        "answer": answer,
+        "citations": citations(&answer, &params.repo_root),
+    })))
+}
+
+/// Send the answer in `exchange`, if it has changed since it was last `reported`.
+async fn report_progress(
+    writer: &mut (impl AsyncWrite + Unpin),
+    id: &Value,
+    exchange: &Exchange,
+    reported: &mut String,
+) -> Result<()> {
+    let Some(answer) = exchange.answer.as_deref() else {
+        return Ok(());
+    };
+
+    if answer == reported {
+        return Ok(());
+    }
+
+    answer.clone_into(reported);
+
+    write_message(
+        writer,
+        &json!({
+            "jsonrpc": "2.0",
+            "method": "$/progress",
+            "params": {
+                "token": id,
+                "value": { "kind": "report", "message": answer },
+            },
+        }),
+    )
+    .await
+}
+
+/// The code cited by links in an answer, with paths resolved against `repo_root`.
+fn citations(answer: &str, repo_root: &Path) -> Vec<Citation> {
+    let mut citations = Vec::new();
+
+    for c in regex!(r"\]\(([^)\s#]+)(?:#L(\d+)(?:-L?(\d+))?)?\)").captures_iter(answer) {
+        let path = &c[1];
+        if path.contains("://") {
+            continue;
+        }
+
+        let start_line = c.get(2).and_then(|m| m.as_str().parse().ok());
+        let citation = Citation {
+            path: repo_root.join(path.trim_start_matches('/')),
+            start_line,
+            end_line: c
+                .get(3)
+                .and_then(|m| m.as_str().parse().ok())
+                .or(start_line),
+        };
+
+        if !citations.contains(&citation) {
+            citations.push(citation);
+        }
+    }
+
+    citations
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    let mut error = json!({ "code": err.code, "message": err.message });
+    if let Some(data) = err.data {
+        error["data"] = data;
+    }
+
+    json!({ "jsonrpc": "2.0", "id": id, "error": error })
+}
+
+/// Read one message, or `None` if the connection was closed.
+async fn read_message(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<Vec<u8>>> {
+    let mut content_length = None;
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            match content_length {
+                Some(_) => break,
+                None => continue,
+            }
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = Some(
+                    value
+                        .trim()
+                        .parse::<usize>()
+                        .context("bad Content-Length")?,
+                );
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length.unwrap()];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Some(body))
+}
+
+async fn write_message(writer: &mut (impl AsyncWrite + Unpin), message: &Value) -> Result<()> {
+    let body = serde_json::to_vec(message)?;
+
+    writer
+        .write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes())
+        .await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tokio::io::{duplex, DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+    use crate::agent::exchange::Update;
+
+    /// Replays canned LLM output as updates to an exchange.
+    struct MockExplainer {
+        indexed: PathBuf,
+        articles: Vec<&'static str>,
+    }
+
+    #[async_trait]
+    impl Explainer for MockExplainer {
+        async fn resolve(&self, repo_root: &Path) -> Option<RepoRef> {
+            (repo_root == self.indexed).then(|| RepoRef::from(&repo_root))
+        }
+
+        fn index_command(&self, repo_root: &Path) -> String {
+            format!("bleep index {}", repo_root.display())
+        }
+
+        async fn explain(
+            &self,
+            _repo_ref: RepoRef,
+            selection: Selection,
+            exchange_tx: mpsc::Sender<Exchange>,
+        ) -> Result<()> {
+            assert_eq!(
+                selection,
+                Selection {
+                    relative_path: "src/config.rs".into(),
+                    line_start: 11,
+                    line_end: 20,
+                    question: None,
+                }
+            );
+
+            let mut exchange = Exchange::new(uuid::Uuid::nil(), Default::default());
+            for article in &self.articles {
+                exchange.apply_update(Update::Article(article.to_string()));
+                exchange_tx.send(exchange.clone()).await?;
+            }
+
+            if self.articles.is_empty() {
+                exchange.apply_update(Update::Error("did not find requested file".into()));
+                exchange_tx.send(exchange.clone()).await?;
+                anyhow::bail!("did not find requested file");
+            }
+
+            exchange.apply_update(Update::Conclude("Anything else?".into()));
+            exchange_tx.send(exchange).await?;
+            Ok(())
+        }
+    }
+
+    struct Client {
+        reader: BufReader<ReadHalf<DuplexStream>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl Client {
+        fn connect(explainer: MockExplainer) -> Self {
+            let (client, server) = duplex(4096);
+            let (server_reader, server_writer) = tokio::io::split(server);
+            tokio::spawn(serve(Arc::new(explainer), server_reader, server_writer));
+
+            let (reader, writer) = tokio::io::split(client);
+            Self {
+                reader: BufReader::new(reader),
+                writer,
+            }
+        }
+
+        async fn send(&mut self, body: &str) {
+            let message = format!("Content-Length: {}\r\n\r\n{body}", body.len());
+            self.writer.write_all(message.as_bytes()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Value {
+            let body = read_message(&mut self.reader).await.unwrap().unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+    }
+
+    fn explain_request(repo_root: &str) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "explain",
+            "params": {
+                "repo_root": repo_root,
+                "path": format!("{repo_root}/src/config.rs"),
+                "range": {
+                    "start": { "line": 10, "character": 4 },
+                    "end": { "line": 20, "character": 0 },
+                },
+            },
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_explain() {
+        let mut client = Client::connect(MockExplainer {
+            indexed: "/home/user/bloop".into(),
+            articles: vec![
+                "The config is parsed",
+                "The config is parsed in [`load`](src/config.rs#L12-L18).",
+                "The config is parsed in [`load`](src/config.rs#L12-L18).",
+                "The config is parsed in [`load`](src/config.rs#L12-L18), \
+                 called from [`main`](/src/main.rs#L5).",
+            ],
+        });
+
+        client.send(&explain_request("/home/user/bloop")).await;
+
+        // Repeated updates of the same answer are only reported once.
+        for message in [
+            "The config is parsed",
+            "The config is parsed in [`load`](src/config.rs#L12-L18).",
+            "The config is parsed in [`load`](src/config.rs#L12-L18), \
+             called from [`main`](/src/main.rs#L5).",
+        ] {
+            assert_eq!(
+                client.recv().await,
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "$/progress",
+                    "params": { "token": 1, "value": { "kind": "report", "message": message } },
+                })
+            );
+        }
+
+        assert_eq!(
+            client.recv().await,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {
+                    "answer": "The config is parsed in [`load`](src/config.rs#L12-L18), \
+                               called from [`main`](/src/main.rs#L5).",
+                    "citations": [
+                        {
+                            "path": "/home/user/bloop/src/config.rs",
+                            "start_line": 12,
+                            "end_line": 18,
+                        },
+                        {
+                            "path": "/home/user/bloop/src/main.rs",
+                            "start_line": 5,
+                            "end_line": 5,
+                        },
+                    ],
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let mut client = Client::connect(MockExplainer {
+            indexed: "/home/user/bloop".into(),
+            articles: Vec::new(),
+        });
+
+        client.send(&explain_request("/home/user/other")).await;
+        assert_eq!(
+            client.recv().await,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": {
+                    "code": REPO_NOT_INDEXED,
+                    "message": "repo not indexed: /home/user/other is not indexed by bloop. \
+                                Index it with `bleep index /home/user/other`",
+                    "data": { "command": "bleep index /home/user/other" },
+                },
+            })
+        );
+
+        // The connection is still usable after a malformed message.
+        client.send("{\"jsonrpc\": ").await;
+        assert_eq!(client.recv().await["error"]["code"], PARSE_ERROR);
+
+        client
+            .send(r#"{"jsonrpc": "2.0", "id": 2, "method": "hover", "params": {}}"#)
+            .await;
+        assert_eq!(
+            client.recv().await["error"],
+            json!({ "code": METHOD_NOT_FOUND, "message": "unknown method `hover`" })
+        );
+
+        // Errors in the agent are reported as the response, after the updates up to the error.
+        client.send(&explain_request("/home/user/bloop")).await;
+        assert_eq!(
+            client.recv().await["error"],
+            json!({ "code": INTERNAL_ERROR, "message": "did not find requested file" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_denied() {
+        let index_dir = tempdir::TempDir::new("bleep-test").unwrap();
+        let repo_dir = tempdir::TempDir::new("bleep-repo").unwrap();
+        let app = crate::webserver::tests::app(&index_dir, json!({})).await;
+
+        let root = repo_dir.path().canonicalize().unwrap();
+        let repo_ref = RepoRef::from(&root);
+        let mut repo = Repository::local_from(&repo_ref);
+        repo.last_index_unix_secs = 1;
+        app.repo_pool.insert(repo_ref.clone(), repo).unwrap();
+
+        let explainer = AgentExplainer { app: app.clone() };
+        assert_eq!(explainer.resolve(&root).await, Some(repo_ref.clone()));
+
+        app.access
+            .set(
+                &app.sql,
+                crate::db::RepoAcl {
+                    repo_ref: repo_ref.to_string(),
+                    public: false,
+                    users: vec!["alice".to_owned()],
+                    groups: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+        // Repositories that the local user can't query are reported as not indexed, before the
+        // agent is started.
+        assert_eq!(explainer.resolve(&root).await, None);
+    }
+
+    #[test]
+    fn test_selection() {
+        let params = |path: &str, start: (usize, usize), end: (usize, usize)| ExplainParams {
+            repo_root: "/home/user/bloop".into(),
+            path: path.into(),
+            range: Range {
+                start: Position {
+                    line: start.0,
+                    character: start.1,
+                },
+                end: Position {
+                    line: end.0,
+                    character: end.1,
+                },
+            },
+            question: Some("  ".into()),
+        };
+
+        assert_eq!(
+            Selection::new(&params("server/src/lib.rs", (0, 0), (0, 12))),
+            Ok(Selection {
+                relative_path: "server/src/lib.rs".into(),
+                line_start: 1,
+                line_end: 1,
+                question: None,
+            })
+        );
+        assert_eq!(
+            Selection::new(&params("/home/user/bloop/src/lib.rs", (4, 2), (9, 0))).map(|s| (
+                s.relative_path,
+                s.line_start,
+                s.line_end
+            )),
+            Ok(("src/lib.rs".into(), 5, 9))
+        );
+        assert!(Selection::new(&params("/etc/passwd", (0, 0), (1, 0))).is_err());
+        assert!(Selection::new(&params("src/lib.rs", (4, 0), (3, 0))).is_err());
+    }
+}
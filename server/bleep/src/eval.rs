@@ -0,0 +1,496 @@
+//! Search quality evaluation against fixture repositories.
+//!
+//! Each fixture repository in `tests/eval` comes with a query set of the same name, which lists
+//! queries and the files, or lines of files, that are relevant to them. A [`Harness`] indexes a
+//! fixture with the regular indexing pipeline, and scores each search mode by recall@k and mean
+//! reciprocal rank. The scores are checked against the baselines committed in
+//! `tests/eval/baselines.yaml`, by `cargo test -p bleep --features eval`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    query::parser::{Literal, SemanticQuery},
+    repo::{Backend, RepoRef, SyncStatus},
+    Application, Configuration, Environment,
+};
+
+/// How far below its baseline a metric may fall, to allow for floating point noise.
+const TOLERANCE: f32 = 1e-3;
+
+/// The search modes that are evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Vector search, as returned by the semantic index.
+    Semantic,
+    /// Vector search, re-ranked by BM25 and for diversity, as the agent's code search does.
+    Hybrid,
+    /// Trigram search over file paths.
+    FuzzyPath,
+}
+
+impl Mode {
+    pub const ALL: [Self; 3] = [Self::Semantic, Self::Hybrid, Self::FuzzyPath];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Semantic => "semantic",
+            Self::Hybrid => "hybrid",
+            Self::FuzzyPath => "fuzzy_path",
+        }
+    }
+}
+
+/// Labeled queries against one fixture repository.
+#[derive(Debug, Deserialize)]
+pub struct QuerySet {
+    /// The number of top results that are scored.
+    pub k: usize,
+    /// Natural language queries, for the semantic and hybrid modes.
+    pub queries: Vec<Case>,
+    /// Partial paths, for the fuzzy path mode.
+    #[serde(default)]
+    pub path_queries: Vec<Case>,
+}
+
+impl QuerySet {
+    pub fn load(path: &Path) -> Result<Self> {
+        let yaml = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        serde_yaml::from_str(&yaml).with_context(|| format!("invalid query set {}", path.display()))
+    }
+
+    pub fn cases(&self, mode: Mode) -> &[Case] {
+        match mode {
+            Mode::Semantic | Mode::Hybrid => &self.queries,
+            Mode::FuzzyPath => &self.path_queries,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Case {
+    pub query: String,
+    pub relevant: Vec<Relevant>,
+}
+
+/// A file, or a region of a file, that is relevant to a query.
+#[derive(Debug, Deserialize)]
+pub struct Relevant {
+    pub path: String,
+    /// The 1-based, inclusive line range of the relevant region, or `None` for the whole file.
+    #[serde(default)]
+    pub lines: Option<(usize, usize)>,
+}
+
+impl Relevant {
+    /// Whether `hit` is from this file and, if both have line ranges, overlaps this region.
+    fn matches(&self, hit: &Hit) -> bool {
+        hit.path == self.path
+            && match (self.lines, hit.lines) {
+                (Some((start, end)), Some((hit_start, hit_end))) => {
+                    hit_start <= end && start <= hit_end
+                }
+                _ => true,
+            }
+    }
+}
+
+/// A search result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    pub path: String,
+    /// The 1-based, inclusive line range of the result, or `None` if it is a whole file.
+    pub lines: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    /// The fraction of relevant items found in the top `k` results, averaged over queries.
+    pub recall_at_k: f32,
+    /// The reciprocal rank of the first relevant result, averaged over queries.
+    pub mrr: f32,
+}
+
+impl Metrics {
+    /// Score the ranked results of each case, taking the top `k` of each.
+    pub fn score<'a>(ranked: impl IntoIterator<Item = (&'a Case, &'a [Hit])>, k: usize) -> Self {
+        let (mut recall, mut rr, mut n) = (0., 0., 0);
+
+        for (case, hits) in ranked {
+            let top = &hits[..hits.len().min(k)];
+
+            let found = case
+                .relevant
+                .iter()
+                .filter(|r| top.iter().any(|hit| r.matches(hit)))
+                .count();
+            recall += found as f32 / case.relevant.len().max(1) as f32;
+
+            rr += top
+                .iter()
+                .position(|hit| case.relevant.iter().any(|r| r.matches(hit)))
+                .map_or(0., |i| 1. / (i + 1) as f32);
+
+            n += 1;
+        }
+
+        if n == 0 {
+            return Self::default();
+        }
+
+        Self {
+            recall_at_k: recall / n as f32,
+            mrr: rr / n as f32,
+        }
+    }
+
+    /// Describe each metric that is below its `baseline`.
+    pub fn regressions(&self, baseline: &Metrics) -> Vec<String> {
+        [
+            ("recall@k", self.recall_at_k, baseline.recall_at_k),
+            ("MRR", self.mrr, baseline.mrr),
+        ]
+        .into_iter()
+        .filter(|(_, value, baseline)| *value + TOLERANCE < *baseline)
+        .map(|(name, value, baseline)| format!("{name} is {value:.3}, below {baseline:.3}"))
+        .collect()
+    }
+}
+
+/// The minimum metrics of each fixture, by search mode.
+pub type Baselines = BTreeMap<String, BTreeMap<Mode, Metrics>>;
+
+pub fn load_baselines(path: &Path) -> Result<Baselines> {
+    let yaml = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    serde_yaml::from_str(&yaml).with_context(|| format!("invalid baselines {}", path.display()))
+}
+
+pub fn save_baselines(path: &Path, baselines: &Baselines) -> Result<()> {
+    const HEADER: &str = "\
+# The minimum search quality of each fixture, by search mode, checked by
+# `cargo test -p bleep --features eval`.
+#
+# When a change improves search quality, update these with `BLEEP_EVAL_UPDATE_BASELINES=1`.
+";
+
+    std::fs::write(path, HEADER.to_owned() + &serde_yaml::to_string(baselines)?)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// The directory of the fixture repositories, their query sets, and `baselines.yaml`.
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/eval")
+}
+
+/// A fixture repository, and its query set.
+pub struct Fixture {
+    pub name: String,
+    pub root: PathBuf,
+    pub queries: QuerySet,
+}
+
+impl Fixture {
+    /// Load every fixture in `dir`, which is each directory that has a `<name>.yaml` query set
+    /// next to it, sorted by name.
+    pub fn load_all(dir: &Path) -> Result<Vec<Self>> {
+        let mut fixtures = Vec::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let root = entry?.path();
+            let queries = root.with_extension("yaml");
+            if !root.is_dir() || !queries.is_file() {
+                continue;
+            }
+
+            fixtures.push(Self {
+                name: root.file_name().unwrap().to_string_lossy().into_owned(),
+                queries: QuerySet::load(&queries)?,
+                root,
+            });
+        }
+
+        fixtures.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(fixtures)
+    }
+}
+
+/// The configuration that fixtures are evaluated with by default, which uses the embedded vector
+/// store so that no qdrant server is needed.
+pub fn default_config() -> Configuration {
+    serde_json::from_value(serde_json::json!({
+        "semantic_backend": "embedded",
+        "model_dir": Path::new(env!("CARGO_MANIFEST_DIR")).join("model"),
+    }))
+    .expect("invalid default configuration")
+}
+
+/// An application that has indexed one fixture repository, in a scratch index directory.
+pub struct Harness {
+    app: Application,
+    repo_ref: RepoRef,
+    index_dir: PathBuf,
+}
+
+impl Harness {
+    /// Index the repository at `root`, with `config`.
+    ///
+    /// The index directory of `config` is replaced with a scratch directory, which is removed when
+    /// the harness is dropped.
+    pub async fn index(root: &Path, mut config: Configuration) -> Result<Self> {
+        let index_dir = std::env::temp_dir().join(format!("bleep-eval-{}", uuid::Uuid::new_v4()));
+        config.index_dir = index_dir.clone();
+        config.disable_background = true;
+        config.disable_analytics = true;
+
+        let app =
+            Application::initialize(Environment::insecure_local(), config, None, None).await?;
+
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("fixture not found: {}", root.display()))?;
+        let repo_ref = RepoRef::new(Backend::LocalDir, &root.to_string_lossy())?;
+
+        match app
+            .write_index()
+            .block_until_synced(repo_ref.clone())
+            .await?
+        {
+            SyncStatus::Done => {}
+            status => bail!("failed to index {}: {status:?}", root.display()),
+        }
+
+        Ok(Self {
+            app,
+            repo_ref,
+            index_dir,
+        })
+    }
+
+    /// Search the fixture for `query`, returning the top `k` results.
+    pub async fn search(&self, mode: Mode, query: &str, k: usize) -> Result<Vec<Hit>> {
+        if mode == Mode::FuzzyPath {
+            return Ok(self
+                .app
+                .indexes
+                .file
+                .fuzzy_path_match(&self.repo_ref, query, None, k)
+                .await
+                .map(|doc| Hit {
+                    path: doc.relative_path,
+                    lines: None,
+                })
+                .collect());
+        }
+
+        let Some(semantic) = self.app.semantic.as_ref() else {
+            bail!("{} search needs a semantic index", mode.name());
+        };
+
+        let parsed_query = SemanticQuery {
+            target: Some(Literal::Plain(query.to_owned().into())),
+            repos: [Literal::Plain(self.repo_ref.display_name().into())].into(),
+            ..Default::default()
+        };
+
+        let mut results = semantic
+            .search(&parsed_query, k as u64, 0, 0.0, true)
+            .await?;
+        if mode == Mode::Hybrid {
+            results = semantic.hybrid_rerank(query, semantic.embed(query)?, results);
+        }
+
+        Ok(results
+            .into_iter()
+            .take(k)
+            .map(|payload| Hit {
+                path: payload.relative_path,
+                lines: Some((
+                    payload.start_line as usize + 1,
+                    payload.end_line as usize + 1,
+                )),
+            })
+            .collect())
+    }
+
+    /// Score `mode` against the cases of `queries` for it.
+    pub async fn evaluate(&self, mode: Mode, queries: &QuerySet) -> Result<Metrics> {
+        let mut ranked = Vec::new();
+        for case in queries.cases(mode) {
+            ranked.push((case, self.search(mode, &case.query, queries.k).await?));
+        }
+
+        Ok(Metrics::score(
+            ranked.iter().map(|(case, hits)| (*case, hits.as_slice())),
+            queries.k,
+        ))
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.index_dir);
+    }
+}
+
+/// Render a table comparing the metrics of two configurations, one row per metric.
+///
+/// Each row of `rows` is a fixture name, a search mode, and the metrics of the baseline and the
+/// candidate configuration.
+pub fn comparison_table(rows: &[(String, Mode, Metrics, Metrics)]) -> String {
+    let mut table = format!(
+        "{:<16} {:<12} {:<10} {:>9} {:>9} {:>8}\n",
+        "fixture", "mode", "metric", "baseline", "candidate", "change"
+    );
+
+    for (fixture, mode, baseline, candidate) in rows {
+        for (metric, a, b) in [
+            ("recall@k", baseline.recall_at_k, candidate.recall_at_k),
+            ("MRR", baseline.mrr, candidate.mrr),
+        ] {
+            _ = writeln!(
+                table,
+                "{fixture:<16} {:<12} {metric:<10} {a:>9.3} {b:>9.3} {:>+8.3}",
+                mode.name(),
+                b - a,
+            );
+        }
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn case(relevant: &[(&str, Option<(usize, usize)>)]) -> Case {
+        Case {
+            query: String::new(),
+            relevant: relevant
+                .iter()
+                .map(|(path, lines)| Relevant {
+                    path: path.to_string(),
+                    lines: *lines,
+                })
+                .collect(),
+        }
+    }
+
+    fn hit(path: &str, lines: Option<(usize, usize)>) -> Hit {
+        Hit {
+            path: path.into(),
+            lines,
+        }
+    }
+
+    #[test]
+    fn test_score() {
+        let auth = case(&[("auth.py", Some((10, 20))), ("app.py", None)]);
+        let cart = case(&[("cart.js", None)]);
+
+        let auth_hits = [
+            // Outside of the relevant lines.
+            hit("auth.py", Some((1, 9))),
+            hit("db.py", Some((1, 30))),
+            hit("auth.py", Some((18, 40))),
+        ];
+        let cart_hits = [hit("format.js", None), hit("api.js", None)];
+
+        // Half of the relevant items of `auth` are found, at rank 3, and nothing of `cart`.
+        let metrics = Metrics::score([(&auth, &auth_hits[..]), (&cart, &cart_hits[..])], 3);
+        assert_eq!(
+            metrics,
+            Metrics {
+                recall_at_k: 0.25,
+                mrr: 1. / 6.,
+            }
+        );
+
+        // Results past `k` don't count.
+        let metrics = Metrics::score([(&auth, &auth_hits[..])], 2);
+        assert_eq!(metrics, Metrics::default());
+    }
+
+    #[test]
+    fn test_regressions() {
+        let baseline = Metrics {
+            recall_at_k: 0.8,
+            mrr: 0.5,
+        };
+
+        let same = Metrics {
+            recall_at_k: 0.8,
+            mrr: 0.4999,
+        };
+        assert!(same.regressions(&baseline).is_empty());
+
+        let worse = Metrics {
+            recall_at_k: 0.7,
+            mrr: 0.6,
+        };
+        assert_eq!(
+            worse.regressions(&baseline),
+            ["recall@k is 0.700, below 0.800"]
+        );
+    }
+
+    #[test]
+    fn test_fixtures_have_baselines() {
+        let baselines = load_baselines(&fixtures_dir().join("baselines.yaml")).unwrap();
+        let fixtures = Fixture::load_all(&fixtures_dir()).unwrap();
+        assert!(!fixtures.is_empty());
+
+        for fixture in fixtures {
+            for mode in Mode::ALL {
+                assert!(
+                    !fixture.queries.cases(mode).is_empty(),
+                    "{} has no {} queries",
+                    fixture.name,
+                    mode.name()
+                );
+                assert!(
+                    baselines
+                        .get(&fixture.name)
+                        .map_or(false, |b| b.contains_key(&mode)),
+                    "{} has no {} baseline",
+                    fixture.name,
+                    mode.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_comparison_table() {
+        let rows = [(
+            "bookshelf".to_owned(),
+            Mode::Hybrid,
+            Metrics {
+                recall_at_k: 0.5,
+                mrr: 0.25,
+            },
+            Metrics {
+                recall_at_k: 0.75,
+                mrr: 0.2,
+            },
+        )];
+
+        assert_eq!(
+            comparison_table(&rows),
+            "fixture          mode         metric      baseline candidate   change\n\
+             bookshelf        hybrid       recall@k       0.500     0.750   +0.250\n\
+             bookshelf        hybrid       MRR            0.250     0.200   -0.050\n"
+        );
+    }
+}
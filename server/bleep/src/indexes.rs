@@ -1,4 +1,10 @@
-use std::{fs, ops::Deref, path::Path, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
@@ -7,18 +13,20 @@ use tantivy::{
     collector::{Collector, MultiFruit},
     schema::Schema,
     tokenizer::NgramTokenizer,
-    DocAddress, Document, IndexReader, IndexWriter, Score,
+    DocAddress, Document, IndexReader, IndexWriter, Score, Searcher,
 };
 use tokio::sync::RwLock;
 
+pub mod diagnostics;
 pub mod file;
+pub mod lfs;
 pub mod reader;
 pub mod repo;
 mod schema;
 
 pub use file::File;
 pub use repo::Repo;
-use tracing::debug;
+use tracing::{debug, error};
 
 use crate::{
     background::{SyncHandle, SyncPipes},
@@ -68,7 +76,7 @@ impl<'a> GlobalWriteHandle<'a> {
         sync_handle: &SyncHandle,
         repo: &Repository,
     ) -> Result<Arc<RepoMetadata>, RepoError> {
-        let metadata = repo.get_repo_metadata().await;
+        let metadata = repo.get_repo_metadata(&sync_handle.reporef).await;
 
         futures::future::join_all(self.handles.iter().map(|handle| {
             handle.index(&sync_handle.reporef, repo, &metadata, sync_handle.pipes())
@@ -116,16 +124,18 @@ impl Indexes {
         Ok(Self {
             repo: Indexer::create(
                 Repo::new(),
+                "repo",
                 config.index_path("repo").as_ref(),
                 config.repo_buffer_size,
                 config.max_threads,
-            )?,
+            ),
             file: Indexer::create(
-                File::new(sql, semantic),
+                File::new(sql, semantic, config.lfs),
+                "content",
                 config.index_path("content").as_ref(),
                 config.buffer_size,
                 config.max_threads,
-            )?,
+            ),
             write_mutex: Default::default(),
         })
     }
@@ -137,10 +147,42 @@ impl Indexes {
         debug!(id, "lock acquired");
 
         Ok(GlobalWriteHandle {
-            handles: vec![self.repo.write_handle()?, self.file.write_handle()?],
+            handles: vec![
+                self.repo.write_handle().await?,
+                self.file.write_handle().await?,
+            ],
             _write_lock,
         })
     }
+
+    /// Delete the indexes and start new, empty ones in their place.
+    ///
+    /// Every repository is left out of the new indexes until it is indexed again.
+    pub async fn rebuild(&self) -> Result<()> {
+        let _write_lock = self.write_mutex.lock().await;
+        self.repo.rebuild().await?;
+        self.file.rebuild().await?;
+
+        Ok(())
+    }
+
+    /// Check that the indexes can be read, opening them if they haven't been yet.
+    pub async fn health(&self) -> Vec<Result<(), IndexCorrupt>> {
+        vec![self.repo.health().await, self.file.health().await]
+    }
+
+    /// Check that the documents of `repo_ref` can be read from the indexes.
+    pub async fn repo_health(&self, repo_ref: &RepoRef) -> Vec<Result<(), IndexCorrupt>> {
+        vec![
+            self.repo.repo_health(repo_ref).await,
+            self.file.repo_health(repo_ref).await,
+        ]
+    }
+
+    /// Wait for indexing to finish, and hold off any more until the guard is dropped.
+    pub async fn quiesce(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.write_mutex.lock().await
+    }
 }
 
 #[async_trait]
@@ -185,14 +227,25 @@ pub trait DocumentRead: Send + Sync {
 
 pub struct IndexWriteHandle<'a> {
     source: &'a dyn Indexable,
-    index: &'a tantivy::Index,
-    reader: &'a RwLock<IndexReader>,
+    name: &'static str,
+    index: tantivy::Index,
+    state: &'a RwLock<IndexState>,
+    corrupt_repos: &'a std::sync::RwLock<HashMap<RepoRef, IndexCorrupt>>,
     writer: IndexWriter,
 }
 
 impl<'a> IndexWriteHandle<'a> {
     pub async fn refresh_reader(&self) -> Result<()> {
-        *self.reader.write().await = self.index.reader()?;
+        let reader = self.index.reader();
+        *self.state.write().await = match &reader {
+            Ok(reader) => IndexState::Open(OpenIndex {
+                index: self.index.clone(),
+                reader: reader.clone(),
+            }),
+            Err(err) => IndexState::Corrupt(IndexCorrupt::new(self.name, err)),
+        };
+
+        reader?;
         Ok(())
     }
 
@@ -209,7 +262,11 @@ impl<'a> IndexWriteHandle<'a> {
     ) -> Result<()> {
         self.source
             .index_repository(reporef, repo, metadata, &self.writer, progress)
-            .await
+            .await?;
+
+        // The documents that couldn't be read were replaced.
+        self.corrupt_repos.write().unwrap().remove(reporef);
+        Ok(())
     }
 
     pub async fn commit(&mut self) -> Result<()> {
@@ -225,29 +282,164 @@ impl<'a> IndexWriteHandle<'a> {
     }
 }
 
+/// The error that queries fail with when an index can't be read, such as when its files were
+/// left corrupt by a crash or a full disk.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("the {index} index is corrupt: {reason}")]
+pub struct IndexCorrupt {
+    /// The name of the index, like `content`.
+    pub index: &'static str,
+    /// The error that the index failed to open with.
+    pub reason: String,
+}
+
+impl IndexCorrupt {
+    fn new(index: &'static str, err: impl std::fmt::Display) -> Self {
+        error!(index, %err, "index is corrupt");
+        Self {
+            index,
+            reason: err.to_string(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct OpenIndex {
+    index: tantivy::Index,
+    reader: IndexReader,
+}
+
+/// An index is opened when it is first used, rather than at startup, so that an index that can't
+/// be read fails only the queries that need it.
+///
+/// This is the state of the index as a whole. An index that can't be opened fails the queries of
+/// every repository, while documents that can't be read only fail the queries of the repository
+/// they belong to.
+enum IndexState {
+    Closed,
+    Open(OpenIndex),
+    Corrupt(IndexCorrupt),
+}
+
+impl IndexState {
+    fn get(&self) -> Option<Result<OpenIndex, IndexCorrupt>> {
+        match self {
+            Self::Closed => None,
+            Self::Open(open) => Some(Ok(open.clone())),
+            Self::Corrupt(err) => Some(Err(err.clone())),
+        }
+    }
+}
+
 /// A wrapper around `tantivy::IndexReader`.
 ///
 /// This contains the schema, and also additional fields used to enable re-indexing.
 pub struct Indexer<T> {
     pub source: T,
-    pub index: tantivy::Index,
-    pub reader: RwLock<IndexReader>,
+    name: &'static str,
+    path: PathBuf,
+    state: RwLock<IndexState>,
+    /// The repositories with documents that couldn't be read, until they are indexed again.
+    corrupt_repos: std::sync::RwLock<HashMap<RepoRef, IndexCorrupt>>,
     pub reindex_buffer_size: usize,
     pub reindex_threads: usize,
 }
 
 impl<T: Indexable> Indexer<T> {
-    fn write_handle(&self) -> Result<IndexWriteHandle<'_>> {
+    async fn write_handle(&self) -> Result<IndexWriteHandle<'_>> {
+        let OpenIndex { index, .. } = self.open().await?;
+        let writer =
+            index.writer_with_num_threads(self.reindex_threads, self.reindex_buffer_size)?;
+
         Ok(IndexWriteHandle {
             source: &self.source,
-            index: &self.index,
-            reader: &self.reader,
-            writer: self
-                .index
-                .writer_with_num_threads(self.reindex_threads, self.reindex_buffer_size)?,
+            name: self.name,
+            index,
+            state: &self.state,
+            corrupt_repos: &self.corrupt_repos,
+            writer,
         })
     }
 
+    /// Open the index, unless it has already been opened or found to be corrupt.
+    async fn open(&self) -> Result<OpenIndex, IndexCorrupt> {
+        if let Some(open) = self.state.read().await.get() {
+            return open;
+        }
+
+        let mut state = self.state.write().await;
+        if let Some(open) = state.get() {
+            return open;
+        }
+
+        let open = self
+            .open_index()
+            .map_err(|err| IndexCorrupt::new(self.name, format!("{err:#}")));
+
+        *state = match &open {
+            Ok(open) => IndexState::Open(open.clone()),
+            Err(err) => IndexState::Corrupt(err.clone()),
+        };
+
+        open
+    }
+
+    fn open_index(&self) -> Result<OpenIndex> {
+        let index = Self::init_index(self.source.schema(), &self.path, self.reindex_threads)?;
+        let reader = index.reader()?;
+
+        Ok(OpenIndex { index, reader })
+    }
+
+    /// Delete the files of this index, and create a new, empty one in their place.
+    async fn rebuild(&self) -> Result<()> {
+        let mut state = self.state.write().await;
+        *state = IndexState::Closed;
+        self.corrupt_repos.write().unwrap().clear();
+
+        if self.path.exists() {
+            fs::remove_dir_all(&self.path).context("failed to remove index dir")?;
+        }
+
+        *state = IndexState::Open(self.open_index()?);
+        Ok(())
+    }
+
+    /// A searcher over the last commit of this index.
+    pub async fn searcher(&self) -> Result<Searcher, IndexCorrupt> {
+        Ok(self.open().await?.reader.searcher())
+    }
+
+    /// Check that this index can be read.
+    pub async fn health(&self) -> Result<(), IndexCorrupt> {
+        self.open().await.map(drop)
+    }
+
+    /// A searcher for the documents of `repo_ref`, unless some of them couldn't be read.
+    pub async fn repo_searcher(&self, repo_ref: &RepoRef) -> Result<Searcher, IndexCorrupt> {
+        let searcher = self.searcher().await?;
+        match self.corrupt_repos.read().unwrap().get(repo_ref) {
+            Some(err) => Err(err.clone()),
+            None => Ok(searcher),
+        }
+    }
+
+    /// Check that the documents of `repo_ref` can be read from this index.
+    pub async fn repo_health(&self, repo_ref: &RepoRef) -> Result<(), IndexCorrupt> {
+        self.repo_searcher(repo_ref).await.map(drop)
+    }
+
+    /// Fail the queries of `repo_ref` until it is indexed again, as some of its documents can't
+    /// be read, without affecting the other repositories in this index.
+    fn mark_corrupt(&self, repo_ref: &RepoRef, err: impl std::fmt::Display) -> IndexCorrupt {
+        let err = IndexCorrupt::new(self.name, format!("{repo_ref}: {err}"));
+        self.corrupt_repos
+            .write()
+            .unwrap()
+            .insert(repo_ref.clone(), err.clone());
+        err
+    }
+
     fn init_index(schema: Schema, path: &Path, threads: usize) -> Result<tantivy::Index> {
         fs::create_dir_all(path).context("failed to create index dir")?;
 
@@ -264,18 +456,24 @@ impl<T: Indexable> Indexer<T> {
     }
 
     /// Create an index using `source` at the specified path.
-    pub fn create(source: T, path: &Path, buffer_size: usize, threads: usize) -> Result<Self> {
-        let index = Self::init_index(source.schema(), path, threads)?;
-        let reader = index.reader()?.into();
-        let instance = Self {
-            reader,
-            index,
+    ///
+    /// The index is opened when it is first used.
+    pub fn create(
+        source: T,
+        name: &'static str,
+        path: &Path,
+        buffer_size: usize,
+        threads: usize,
+    ) -> Self {
+        Self {
             source,
+            name,
+            path: path.to_owned(),
+            state: RwLock::new(IndexState::Closed),
+            corrupt_repos: Default::default(),
             reindex_threads: threads,
             reindex_buffer_size: buffer_size,
-        };
-
-        Ok(instance)
+        }
     }
 
     pub async fn query<'a, R, I, C>(
@@ -289,12 +487,12 @@ impl<T: Indexable> Indexer<T> {
         C: Collector<Fruit = (Vec<(Score, DocAddress)>, MultiFruit)>,
         R: DocumentRead<Schema = T>,
     {
-        let searcher = self.reader.read().await.searcher();
+        let OpenIndex { index, reader } = self.open().await?;
+        let searcher = reader.searcher();
         let queries = queries
             .filter(|q| doc_reader.query_matches(q))
             .collect::<SmallVec<[_; 2]>>();
-        let compiled_query =
-            doc_reader.compile(&self.source, queries.iter().copied(), &self.index)?;
+        let compiled_query = doc_reader.compile(&self.source, queries.iter().copied(), &index)?;
 
         let (top_k, metadata) = searcher
             .search(&compiled_query, &collector)
@@ -316,3 +514,98 @@ pub struct SearchResults<'a, T> {
     pub docs: Box<dyn Iterator<Item = T> + Sync + Send + 'a>,
     pub metadata: MultiFruit,
 }
+
+#[cfg(test)]
+mod tests {
+    use tantivy::{
+        collector::{MultiCollector, TopDocs},
+        doc,
+    };
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::query::parser;
+
+    /// Create a repo index in `dir`, holding a single repository named `name`.
+    async fn repo_index(dir: &Path, name: &str) {
+        let indexer = Indexer::create(Repo::new(), "repo", dir, 15_000_000, 1);
+        let mut handle = indexer.write_handle().await.unwrap();
+        let repo = &indexer.source;
+
+        handle
+            .writer
+            .add_document(doc!(
+                repo.org => "",
+                repo.disk_path => format!("/tmp/{name}"),
+                repo.name => name,
+                repo.raw_name => name.as_bytes(),
+                repo.repo_ref => format!("local//tmp/{name}"),
+            ))
+            .unwrap();
+        handle.commit().await.unwrap();
+    }
+
+    async fn repo_names(indexer: &Indexer<Repo>, query: &str) -> Result<Vec<String>> {
+        let queries = parser::parse(query)?;
+        let collector = (TopDocs::with_limit(10), MultiCollector::new());
+        let results = indexer
+            .query(queries.iter(), &reader::RepoReader, collector)
+            .await?;
+
+        Ok(results.docs.map(|doc| doc.name).collect())
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_index_is_isolated() {
+        let tmpdir = TempDir::new("test-corrupt-index").unwrap();
+        let (corrupt_dir, healthy_dir) = (tmpdir.path().join("a"), tmpdir.path().join("b"));
+        repo_index(&corrupt_dir, "corrupt").await;
+        repo_index(&healthy_dir, "healthy").await;
+
+        // Indexes are opened lazily, so the corruption is only found when they are first used.
+        std::fs::write(corrupt_dir.join("meta.json"), "{ not json").unwrap();
+        let corrupt = Indexer::create(Repo::new(), "repo", &corrupt_dir, 15_000_000, 1);
+        let healthy = Indexer::create(Repo::new(), "repo", &healthy_dir, 15_000_000, 1);
+
+        let err = repo_names(&corrupt, "repo:corrupt").await.unwrap_err();
+        let err = err.downcast::<IndexCorrupt>().unwrap();
+        assert_eq!(err.index, "repo");
+        assert_eq!(corrupt.health().await, Err(err));
+        assert!(corrupt.write_handle().await.is_err());
+
+        assert_eq!(healthy.health().await, Ok(()));
+        assert_eq!(
+            repo_names(&healthy, "repo:healthy").await.unwrap(),
+            ["healthy"]
+        );
+
+        // Rebuilding the corrupt index leaves it empty, but readable again.
+        corrupt.rebuild().await.unwrap();
+        assert_eq!(corrupt.health().await, Ok(()));
+        assert!(repo_names(&corrupt, "repo:corrupt")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_repo_is_isolated() {
+        let tmpdir = TempDir::new("test-corrupt-repo").unwrap();
+        repo_index(tmpdir.path(), "healthy").await;
+        let indexer = Indexer::create(Repo::new(), "repo", tmpdir.path(), 15_000_000, 1);
+
+        let corrupt = RepoRef::from("local//tmp/corrupt");
+        let healthy = RepoRef::from("local//tmp/healthy");
+        let err = indexer.mark_corrupt(&corrupt, "failed to decompress block");
+
+        // Documents that can't be read fail the queries of their repository, and only those.
+        assert_eq!(indexer.repo_health(&corrupt).await, Err(err.clone()));
+        assert!(err.reason.starts_with("local//tmp/corrupt: "));
+        assert_eq!(indexer.repo_health(&healthy).await, Ok(()));
+        assert_eq!(indexer.health().await, Ok(()));
+
+        // Rebuilding the index forgets which repositories had unreadable documents.
+        indexer.rebuild().await.unwrap();
+        assert_eq!(indexer.repo_health(&corrupt).await, Ok(()));
+    }
+}
@@ -0,0 +1,258 @@
+//! Files that were left out of an index, or indexed without parts of what is usually extracted.
+//!
+//! Answers about a repository whose index is missing many files can be badly wrong, without the
+//! user ever learning why. Indexing records what went wrong with each file, so that the agent can
+//! caveat its answers and operators can see what to fix.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The share of files with diagnostics, from which answers carry a caveat.
+pub const DEFAULT_WARNING_THRESHOLD: f32 = 0.05;
+
+/// The most diagnostics that are kept for a repository. Counts are kept in full.
+const MAX_STORED: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    /// Files over the size or line limits, which were left out.
+    Oversized,
+    /// Files that could not be read, for example for lack of permissions.
+    Unreadable,
+    /// Files that were indexed, but could not be parsed for symbols.
+    ParseError,
+    /// Files that failed to be indexed for any other reason.
+    Failed,
+}
+
+impl Category {
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Oversized => "too large",
+            Self::Unreadable => "unreadable",
+            Self::ParseError => "not parsed",
+            Self::Failed => "failed to index",
+        }
+    }
+}
+
+/// What went wrong with a single file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub category: Category,
+    /// The path of the file, relative to the repository root.
+    pub path: String,
+    pub message: String,
+}
+
+/// The diagnostics of the last index of a repository.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct IndexDiagnostics {
+    /// The number of files that the index went through, including unchanged files.
+    pub files: usize,
+    /// The number of diagnostics in each category.
+    pub counts: BTreeMap<Category, usize>,
+    /// Diagnostics sorted by path, of which only the first `MAX_STORED` are kept.
+    pub entries: Vec<Diagnostic>,
+}
+
+/// A summary of the diagnostics of a repository, for the exchanges answered from it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IndexWarnings {
+    /// The number of files in the index.
+    pub files: usize,
+    /// The number of files with diagnostics.
+    pub affected: usize,
+    pub counts: BTreeMap<Category, usize>,
+}
+
+impl IndexDiagnostics {
+    /// The number of files with diagnostics.
+    pub fn affected(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// A summary of these diagnostics, if at least a `threshold` share of the files are affected.
+    pub fn warnings(&self, threshold: f32) -> Option<IndexWarnings> {
+        let affected = self.affected();
+        if affected == 0 || (affected as f32) < threshold * self.files as f32 {
+            return None;
+        }
+
+        Some(IndexWarnings {
+            files: self.files,
+            affected,
+            counts: self.counts.clone(),
+        })
+    }
+
+    /// The diagnostics in `category`, or in every category if there is none.
+    pub fn filter(&self, category: Option<Category>) -> impl Iterator<Item = &Diagnostic> + '_ {
+        self.entries
+            .iter()
+            .filter(move |d| category.is_none() || category == Some(d.category))
+    }
+}
+
+impl IndexWarnings {
+    /// A one-line instruction for the answer prompt, to caveat answers with.
+    pub fn caveat(&self) -> String {
+        let counts = self
+            .counts
+            .iter()
+            .map(|(category, n)| format!("{n} {}", category.describe()))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!(
+            "{} of the {} files in this repository were not fully indexed ({counts}), so mention \
+             that the answer may be missing information from them.",
+            self.affected, self.files
+        )
+    }
+}
+
+/// Diagnostics that are collected by indexing threads, to be turned into `IndexDiagnostics`.
+#[derive(Debug, Default)]
+pub struct DiagnosticsTally {
+    files: AtomicUsize,
+    entries: Mutex<Vec<Diagnostic>>,
+    /// The diagnostics of the previous index, by path, for files that are skipped as unchanged.
+    previous: HashMap<String, Vec<Diagnostic>>,
+}
+
+impl DiagnosticsTally {
+    /// Start collecting diagnostics for an index that follows one with `previous` diagnostics.
+    pub fn new(previous: &IndexDiagnostics) -> Self {
+        let mut by_path = HashMap::<_, Vec<_>>::new();
+        for diagnostic in &previous.entries {
+            by_path
+                .entry(diagnostic.path.clone())
+                .or_default()
+                .push(diagnostic.clone());
+        }
+
+        Self {
+            previous: by_path,
+            ..Default::default()
+        }
+    }
+
+    /// Count a file that the index went through.
+    pub fn file(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, category: Category, path: &str, message: impl Display) {
+        self.entries.lock().unwrap().push(Diagnostic {
+            category,
+            path: path.to_owned(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Keep the diagnostics that `path` had in the previous index, as it is unchanged since.
+    pub fn unchanged(&self, path: &str) {
+        if let Some(previous) = self.previous.get(path) {
+            self.entries
+                .lock()
+                .unwrap()
+                .extend(previous.iter().cloned());
+        }
+    }
+
+    pub fn finish(&self) -> IndexDiagnostics {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by(|a, b| (&a.path, a.category).cmp(&(&b.path, b.category)));
+        entries.dedup();
+
+        let mut counts = BTreeMap::new();
+        for diagnostic in &entries {
+            *counts.entry(diagnostic.category).or_default() += 1;
+        }
+
+        entries.truncate(MAX_STORED);
+
+        IndexDiagnostics {
+            files: self.files.load(Ordering::Relaxed),
+            counts,
+            entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_tally() {
+        let tally = DiagnosticsTally::default();
+        for _ in 0..40 {
+            tally.file();
+        }
+
+        tally.record(Category::Unreadable, "secrets/key.pem", "permission denied");
+        tally.record(
+            Category::Oversized,
+            "data/vocab.txt",
+            "more than 20000 lines",
+        );
+        tally.record(Category::ParseError, "src/lib.rs", "parse timeout");
+        tally.record(
+            Category::Oversized,
+            "data/vocab.txt",
+            "more than 20000 lines",
+        );
+
+        let diagnostics = tally.finish();
+        assert_eq!(diagnostics.files, 40);
+        assert_eq!(diagnostics.affected(), 3);
+        assert_eq!(
+            diagnostics
+                .entries
+                .iter()
+                .map(|d| d.path.as_str())
+                .collect::<Vec<_>>(),
+            ["data/vocab.txt", "secrets/key.pem", "src/lib.rs"]
+        );
+
+        // Unchanged files keep their diagnostics in the next index.
+        let next = DiagnosticsTally::new(&diagnostics);
+        next.file();
+        next.unchanged("data/vocab.txt");
+        next.unchanged("src/main.rs");
+        assert_eq!(next.finish().entries, diagnostics.entries[..1]);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let diagnostics = IndexDiagnostics {
+            files: 100,
+            counts: [(Category::Oversized, 3), (Category::Unreadable, 2)].into(),
+            entries: vec![],
+        };
+
+        assert_eq!(diagnostics.warnings(0.1), None);
+        let warnings = diagnostics.warnings(0.05).unwrap();
+        assert_eq!(warnings.affected, 5);
+        assert_eq!(
+            warnings.caveat(),
+            "5 of the 100 files in this repository were not fully indexed (3 too large, 2 \
+             unreadable), so mention that the answer may be missing information from them."
+        );
+
+        assert_eq!(IndexDiagnostics::default().warnings(0.0), None);
+    }
+}
@@ -9,10 +9,11 @@ use std::{
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
+use either::Either;
 use rayon::prelude::*;
 use scc::hash_map::Entry;
 use tantivy::{
-    collector::TopDocs,
+    collector::{DocSetCollector, TopDocs},
     doc,
     query::{BooleanQuery, Query, QueryParser, TermQuery},
     schema::{IndexRecordOption, Schema, Term},
@@ -28,13 +29,15 @@ pub use super::schema::File;
 use std::time::Instant;
 
 use super::{
+    diagnostics::Category,
+    lfs,
     reader::{ContentDocument, ContentReader, FileDocument, FileReader},
     DocumentRead, Indexable, Indexer,
 };
 use crate::{
     background::SyncPipes,
     cache::{FileCache, FileCacheSnapshot},
-    intelligence::TreeSitterFile,
+    intelligence::{TreeSitterFile, TreeSitterFileError},
     query::compiler::{case_permutations, trigrams},
     repo::{iterator::*, RepoMetadata, RepoRef, Repository},
     symbol::SymbolLocations,
@@ -73,6 +76,7 @@ impl Indexable for File {
                 pipes.index_percent(((completed as f32 / count as f32) * 100f32) as u8);
 
                 let entry_disk_path = dir_entry.path().unwrap_or_default().to_owned();
+                let is_file = matches!(dir_entry, RepoDirEntry::File(_));
                 let workload = Workload {
                     repo_disk_path: &repo.disk_path,
                     repo_ref: reporef.to_string(),
@@ -86,6 +90,16 @@ impl Indexable for File {
                 trace!(entry_disk_path, "queueing entry");
                 if let Err(err) = self.worker(workload, writer) {
                     warn!(%err, entry_disk_path, "indexing failed; skipping");
+                    if is_file {
+                        let relative_path = Path::new(&entry_disk_path)
+                            .strip_prefix(&repo.disk_path)
+                            .unwrap_or(Path::new(&entry_disk_path));
+                        repo_metadata.diagnostics.record(
+                            Category::Failed,
+                            &diagnostic_path(relative_path),
+                            err,
+                        );
+                    }
                 }
             }
         };
@@ -162,7 +176,7 @@ impl Indexer<File> {
     /// For example, the string `Cargo` can return documents whose path is `foo/Cargo.toml`,
     /// or `bar/Cargo.lock`. Constructs regexes that permit an edit-distance of 2.
     ///
-    /// If the regex filter fails to build, an empty list is returned.
+    /// If the regex filter fails to build, or the index can't be read, an empty list is returned.
     pub async fn fuzzy_path_match(
         &self,
         repo_ref: &RepoRef,
@@ -171,8 +185,6 @@ impl Indexer<File> {
         limit: usize,
     ) -> impl Iterator<Item = FileDocument> + '_ {
         // lifted from query::compiler
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
         let collector = TopDocs::with_limit(100);
         let file_source = &self.source;
 
@@ -189,6 +201,13 @@ impl Indexer<File> {
                     .collect::<Vec<_>>()
             })
             .map(BooleanQuery::intersection);
+        let searcher = match self.repo_searcher(repo_ref).await {
+            Ok(searcher) => searcher,
+            Err(err) => {
+                warn!(%err, "failed to match paths");
+                return Either::Left(std::iter::empty());
+            }
+        };
         let mut hits = trigrams(query_str)
             .flat_map(|s| case_permutations(s.as_str()))
             .map(|token| Term::from_field_text(self.source.relative_path, token.as_str()))
@@ -259,16 +278,141 @@ impl Indexer<File> {
 
         // if the regex filter fails to build for some reason, the filter defaults to returning
         // false and zero results are produced
-        hits.into_iter()
-            .map(|(doc, _)| doc)
-            .filter(move |doc| {
-                regex_filter
-                    .as_ref()
-                    .map(|f| f.is_match(&doc.relative_path))
-                    .unwrap_or_default()
+        Either::Right(
+            hits.into_iter()
+                .map(|(doc, _)| doc)
+                .filter(move |doc| {
+                    regex_filter
+                        .as_ref()
+                        .map(|f| f.is_match(&doc.relative_path))
+                        .unwrap_or_default()
+                })
+                .filter(|doc| !doc.relative_path.ends_with('/')) // omit directories
+                .take(limit),
+        )
+    }
+
+    /// Search this index for paths matching a glob pattern, like `**/*.sql` or `src/{a,b}/*.rs`.
+    ///
+    /// Results are sorted alphabetically. Patterns without a `/` match files in any directory.
+    ///
+    /// If the pattern is invalid, an empty list is returned.
+    pub async fn glob_path_match(
+        &self,
+        repo_ref: &RepoRef,
+        pattern: &str,
+        branch: Option<&str>,
+        limit: usize,
+    ) -> Vec<String> {
+        let Some(regex) = build_glob_regex(pattern) else {
+            return vec![];
+        };
+
+        let searcher = match self.repo_searcher(repo_ref).await {
+            Ok(searcher) => searcher,
+            Err(err) => {
+                warn!(%err, "failed to list files");
+                return Vec::new();
+            }
+        };
+
+        let query = self.repo_files_query(repo_ref, branch);
+        let paths = searcher
+            .search(&query, &DocSetCollector)
+            .expect("failed to search index")
+            .into_iter()
+            .map(|addr| {
+                let retrieved_doc = searcher
+                    .doc(addr)
+                    .expect("failed to get document by address");
+                FileReader
+                    .read_document(&self.source, retrieved_doc)
+                    .relative_path
+            });
+
+        filter_glob_matches(&regex, paths, limit)
+    }
+
+    /// Produce every file in a repo, without a limit.
+    ///
+    /// Directories are omitted. This fails with [`IndexCorrupt`](super::IndexCorrupt) if the
+    /// files of the repo can't be read.
+    ///
+    /// This holds every file in memory at once. Prefer [`Self::scan_files`] to compute something
+    /// from the files.
+    pub async fn all_files(
+        &self,
+        repo_ref: &RepoRef,
+        branch: Option<&str>,
+    ) -> Result<Vec<ContentDocument>> {
+        self.scan_files(repo_ref, branch, |docs| docs.collect())
+            .await
+    }
+
+    /// Run `scan` over every file in a repo, on a blocking thread.
+    ///
+    /// Files are read one at a time as `scan` iterates, so only what it keeps of them is held in
+    /// memory. Directories are omitted. This fails with [`IndexCorrupt`](super::IndexCorrupt) if
+    /// the files of the repo can't be read, whatever `scan` returned.
+    pub async fn scan_files<T: Send + 'static>(
+        &self,
+        repo_ref: &RepoRef,
+        branch: Option<&str>,
+        scan: impl FnOnce(&mut dyn Iterator<Item = ContentDocument>) -> T + Send + 'static,
+    ) -> Result<T> {
+        let searcher = self.repo_searcher(repo_ref).await?;
+        let query = self.repo_files_query(repo_ref, branch);
+        let source = self.source.clone();
+
+        let scanned = tokio::task::spawn_blocking(move || {
+            let addrs = searcher.search(&query, &DocSetCollector)?;
+
+            let mut error = None;
+            let mut docs = addrs
+                .into_iter()
+                .map_while(|addr| match searcher.doc(addr) {
+                    Ok(doc) => Some(doc),
+                    Err(err) => {
+                        error = Some(err);
+                        None
+                    }
+                })
+                .map(|doc| ContentReader.read_document(&source, doc))
+                .filter(|doc| !doc.relative_path.ends_with('/'));
+
+            let scanned = scan(&mut docs);
+            match error {
+                Some(err) => Err(err),
+                None => Ok(scanned),
+            }
+        })
+        .await?;
+
+        scanned.map_err(|err| self.mark_corrupt(repo_ref, err).into())
+    }
+
+    /// A query for the files of a repo, on `branch` if one is given.
+    fn repo_files_query(&self, repo_ref: &RepoRef, branch: Option<&str>) -> BooleanQuery {
+        let mut query = vec![Box::new(TermQuery::new(
+            Term::from_field_text(self.source.repo_ref, &repo_ref.to_string()),
+            IndexRecordOption::Basic,
+        )) as Box<dyn Query>];
+
+        let branch_term = branch
+            .map(|b| {
+                trigrams(b)
+                    .map(|token| Term::from_field_text(self.source.branches, token.as_str()))
+                    .map(|term| TermQuery::new(term, IndexRecordOption::Basic))
+                    .map(Box::new)
+                    .map(|q| q as Box<dyn Query>)
+                    .collect::<Vec<_>>()
             })
-            .filter(|doc| !doc.relative_path.ends_with('/')) // omit directories
-            .take(limit)
+            .map(BooleanQuery::intersection);
+        if let Some(b) = branch_term {
+            query.push(Box::new(b) as Box<dyn Query>);
+        };
+
+        BooleanQuery::intersection(query)
     }
 
     pub async fn by_path(
@@ -277,8 +421,7 @@ impl Indexer<File> {
         relative_path: &str,
         branch: Option<&str>,
     ) -> Result<Option<ContentDocument>> {
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
+        let searcher = self.repo_searcher(repo_ref).await?;
 
         let file_index = searcher.index();
 
@@ -350,8 +493,13 @@ impl Indexer<File> {
         langs: impl Iterator<Item = S>,
         branch: Option<&str>,
     ) -> Vec<ContentDocument> {
-        let reader = self.reader.read().await;
-        let searcher = reader.searcher();
+        let searcher = match self.repo_searcher(repo_ref).await {
+            Ok(searcher) => searcher,
+            Err(err) => {
+                warn!(%err, "failed to list files");
+                return Vec::new();
+            }
+        };
 
         let mut query = vec![];
 
@@ -416,7 +564,7 @@ impl File {
             repo_metadata,
             file_cache,
             cache_snapshot,
-            dir_entry,
+            mut dir_entry,
         } = workload;
 
         #[cfg(feature = "debug")]
@@ -434,28 +582,46 @@ impl File {
         };
         let entry_pathbuf = repo_disk_path.join(&relative_path);
 
-        let semantic_hash = {
-            let mut hash = blake3::Hasher::new();
-            hash.update(crate::state::SCHEMA_VERSION.as_bytes());
-            hash.update(relative_path.to_string_lossy().as_ref().as_ref());
-            hash.update(repo_ref.as_bytes());
-            hash.update(dir_entry.buffer().unwrap_or_default().as_bytes());
-            hash.finalize().to_hex().to_string()
-        };
+        if let RepoDirEntry::File(_) | RepoDirEntry::Skipped(_) = &dir_entry {
+            repo_metadata.diagnostics.file();
+        }
 
-        let tantivy_hash = {
-            let branch_list = dir_entry.branches().unwrap_or_default();
-            let mut hash = blake3::Hasher::new();
-            hash.update(semantic_hash.as_ref());
-            hash.update(branch_list.join("\n").as_bytes());
-            hash.finalize().to_hex().to_string()
-        };
+        if let RepoDirEntry::Skipped(skipped) = &dir_entry {
+            trace!(reason = skipped.reason, "skipped before indexing");
+            repo_metadata.diagnostics.record(
+                skipped.category,
+                &diagnostic_path(&relative_path),
+                &skipped.reason,
+            );
+            return Ok(());
+        }
+
+        // LFS objects are swapped in before the cache keys are taken, so that pointers are
+        // re-indexed once their object is fetched.
+        let mut lfs_pointer = false;
+        if let RepoDirEntry::File(file) = &mut dir_entry {
+            if let Some(resolved) = self.lfs.resolve(repo_disk_path, &file.buffer) {
+                repo_metadata.lfs.record(&resolved);
+                match resolved {
+                    lfs::Resolved::Skip => {
+                        trace!("LFS pointer; skipping");
+                        return Ok(());
+                    }
+                    lfs::Resolved::Object(object) => file.buffer = object,
+                    lfs::Resolved::Pointer => lfs_pointer = true,
+                }
+            }
+        }
 
+        let (semantic_hash, tantivy_hash) = cache_keys(&repo_ref, &relative_path, &dir_entry);
         let last_commit = repo_metadata.last_commit_unix_secs.unwrap_or(0);
 
         match dir_entry {
             _ if is_cache_fresh(cache_snapshot, &tantivy_hash, &entry_pathbuf) => {
                 info!("fresh; skipping");
+                repo_metadata
+                    .diagnostics
+                    .unchanged(&diagnostic_path(&relative_path));
                 return Ok(());
             }
             RepoDirEntry::Dir(dir) => {
@@ -474,26 +640,30 @@ impl File {
             }
             RepoDirEntry::File(file) => {
                 trace!("writing file document");
-                let doc = file
-                    .build_document(
-                        self,
-                        repo_name,
-                        relative_path.as_path(),
-                        repo_disk_path,
-                        semantic_hash,
-                        tantivy_hash,
-                        entry_pathbuf.as_path(),
-                        repo_ref.as_str(),
-                        last_commit,
-                        repo_metadata,
-                        file_cache,
-                    )
-                    .ok_or(anyhow::anyhow!("failed to build document"))?;
+                // Files that are too long to index are recorded in the diagnostics.
+                let Some(doc) = file.build_document(
+                    self,
+                    repo_name,
+                    relative_path.as_path(),
+                    repo_disk_path,
+                    semantic_hash,
+                    tantivy_hash,
+                    entry_pathbuf.as_path(),
+                    repo_ref.as_str(),
+                    last_commit,
+                    repo_metadata,
+                    file_cache,
+                    lfs_pointer,
+                ) else {
+                    return Ok(());
+                };
                 writer.add_document(doc)?;
 
                 trace!("file document written");
             }
-            RepoDirEntry::Other => anyhow::bail!("dir entry was neither a file nor a directory"),
+            RepoDirEntry::Skipped(_) | RepoDirEntry::Other => {
+                anyhow::bail!("dir entry was neither a file nor a directory")
+            }
         }
 
         #[cfg(feature = "debug")]
@@ -580,6 +750,7 @@ impl RepoFile {
         last_commit: u64,
         repo_metadata: &RepoMetadata,
         file_cache: &FileCache,
+        lfs_pointer: bool,
     ) -> Option<tantivy::schema::Document> {
         let relative_path_str = relative_path.to_string_lossy().to_string();
         #[cfg(windows)]
@@ -603,7 +774,15 @@ impl RepoFile {
                 // we have a graph, use that
                 Ok(graph) => SymbolLocations::TreeSitter(graph),
                 // no graph, it's empty
-                Err(_) => SymbolLocations::Empty,
+                Err(TreeSitterFileError::UnsupportedLanguage) => SymbolLocations::Empty,
+                Err(err) => {
+                    repo_metadata.diagnostics.record(
+                        Category::ParseError,
+                        &relative_path_str,
+                        format!("{err:?}"),
+                    );
+                    SymbolLocations::Empty
+                }
             }
         };
 
@@ -631,6 +810,11 @@ impl RepoFile {
         // Skip files that are too long. This is not necessarily caught in the filesize check, e.g.
         // for a file like `vocab.txt` which has thousands of very short lines.
         if line_end_indices.len() > MAX_LINE_COUNT as usize {
+            repo_metadata.diagnostics.record(
+                Category::Oversized,
+                &relative_path_str,
+                format!("more than {MAX_LINE_COUNT} lines"),
+            );
             return None;
         }
 
@@ -647,6 +831,7 @@ impl RepoFile {
                             &self.buffer,
                             lang_str,
                             &self.branches,
+                            lfs_pointer,
                             file_cache.chunks_for_file(&semantic_cache_key).await,
                         )
                         .await
@@ -672,11 +857,42 @@ impl RepoFile {
             schema.symbols => symbols,
             schema.branches => branches,
             schema.is_directory => false,
+            schema.is_lfs_pointer => lfs_pointer,
         ))
     }
 }
 
 #[tracing::instrument(skip(cache))]
+/// The content-addressed cache keys of an entry, as `(semantic_hash, tantivy_hash)`.
+///
+/// Both keys change whenever the contents of the entry change, which is what makes re-indexing
+/// incremental: entries with a key that is already in the cache are skipped.
+/// The path of a file in diagnostics, which uses `/` as the separator on every platform.
+fn diagnostic_path(relative_path: &Path) -> String {
+    relative_path.to_string_lossy().replace('\\', "/")
+}
+
+fn cache_keys(repo_ref: &str, relative_path: &Path, dir_entry: &RepoDirEntry) -> (String, String) {
+    let semantic_hash = {
+        let mut hash = blake3::Hasher::new();
+        hash.update(crate::state::SCHEMA_VERSION.as_bytes());
+        hash.update(relative_path.to_string_lossy().as_ref().as_ref());
+        hash.update(repo_ref.as_bytes());
+        hash.update(dir_entry.buffer().unwrap_or_default().as_bytes());
+        hash.finalize().to_hex().to_string()
+    };
+
+    let tantivy_hash = {
+        let branch_list = dir_entry.branches().unwrap_or_default();
+        let mut hash = blake3::Hasher::new();
+        hash.update(semantic_hash.as_ref());
+        hash.update(branch_list.join("\n").as_bytes());
+        hash.finalize().to_hex().to_string()
+    };
+
+    (semantic_hash, tantivy_hash)
+}
+
 fn is_cache_fresh(cache: &FileCacheSnapshot, unique_hash: &str, entry_pathbuf: &PathBuf) -> bool {
     match cache.entry(unique_hash.into()) {
         Entry::Occupied(mut val) => {
@@ -757,9 +973,400 @@ fn build_fuzzy_regex_filter(query_str: &str) -> Option<regex::RegexSet> {
         .ok()
 }
 
+/// Translate a glob pattern into an anchored regex over relative paths.
+///
+/// This supports `*` and `?` (which do not cross directory boundaries), `**` (which does), character
+/// classes like `[a-z]` or `[!a-z]`, and alternations like `{rs,toml}`. Patterns that do not
+/// contain a `/` are matched against the file name, in any directory.
+fn build_glob_regex(pattern: &str) -> Option<regex::Regex> {
+    let pattern = pattern.trim_start_matches("./").trim_start_matches('/');
+    let mut chars = pattern.chars().peekable();
+    let mut in_alternation = false;
+
+    let mut regex = String::from("^");
+    if !pattern.contains('/') {
+        regex.push_str("(?:.*/)?");
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.next_if_eq(&'/').is_some() {
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+
+                // An unterminated class makes the whole pattern invalid.
+                loop {
+                    match chars.next()? {
+                        ']' => break,
+                        c @ ('\\' | '[' | '&' | '~') => {
+                            regex.push('\\');
+                            regex.push(c);
+                        }
+                        c => regex.push(c),
+                    }
+                }
+
+                regex.push(']');
+            }
+            '{' if !in_alternation => {
+                in_alternation = true;
+                regex.push_str("(?:");
+            }
+            '}' if in_alternation => {
+                in_alternation = false;
+                regex.push(')');
+            }
+            ',' if in_alternation => regex.push('|'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    if in_alternation {
+        return None;
+    }
+
+    regex.push('$');
+    regex::Regex::new(&regex).ok()
+}
+
+/// Filter a list of paths with a glob regex, omitting directories and sorting them alphabetically.
+fn filter_glob_matches(
+    regex: &regex::Regex,
+    paths: impl IntoIterator<Item = String>,
+    limit: usize,
+) -> Vec<String> {
+    let mut paths = paths
+        .into_iter()
+        .filter(|path| !path.ends_with('/'))
+        .filter(|path| regex.is_match(path))
+        .collect::<Vec<_>>();
+
+    paths.sort();
+    paths.dedup();
+    paths.truncate(limit);
+    paths
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::Mutex;
+
+    use tempdir::TempDir;
+
     use super::*;
+    use crate::{background::SyncPipes, cache::FreshValue, repo::Backend};
+
+    fn copy_dir(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let path = entry.unwrap().path();
+            let target = to.join(path.file_name().unwrap());
+            if path.is_dir() {
+                copy_dir(&path, &target);
+            } else {
+                std::fs::copy(&path, &target).unwrap();
+            }
+        }
+    }
+
+    fn fixture_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/indexes/fixtures")
+            .join(name)
+    }
+
+    /// Copy the `local_dir` fixture into a temporary directory that tests can modify.
+    fn local_dir_fixture() -> (TempDir, PathBuf) {
+        let tmpdir = TempDir::new("test-local-dir").unwrap();
+        let root = crate::canonicalize(tmpdir.path()).unwrap();
+        copy_dir(&fixture_path("local_dir"), &root);
+
+        (tmpdir, root)
+    }
+
+    /// Copy the `lfs` fixture into a temporary directory, with the objects in `lfs_objects`
+    /// fetched into its `.git/lfs` store.
+    ///
+    /// The fixture has a pointer to a fetched object, `data/latency.csv`, and a pointer to an
+    /// object that isn't fetched, `models/ranker.onnx`.
+    fn lfs_fixture() -> (TempDir, PathBuf) {
+        let tmpdir = TempDir::new("test-lfs").unwrap();
+        let root = crate::canonicalize(tmpdir.path()).unwrap();
+        copy_dir(&fixture_path("lfs"), &root);
+
+        for entry in std::fs::read_dir(fixture_path("lfs_objects")).unwrap() {
+            let path = entry.unwrap().path();
+            let oid = path.file_name().unwrap().to_string_lossy();
+            let target = root
+                .join(".git/lfs/objects")
+                .join(&oid[..2])
+                .join(&oid[2..4])
+                .join(&*oid);
+            std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+            std::fs::copy(&path, &target).unwrap();
+        }
+
+        (tmpdir, root)
+    }
+
+    /// Walk a plain directory like `File::index_repository` does, returning the relative paths
+    /// of files that were processed, as opposed to skipped because they were fresh in `cache`.
+    ///
+    /// The cache is left as it would be persisted at the end of indexing.
+    fn index_files(reporef: &RepoRef, root: &Path, cache: &FileCacheSnapshot) -> Vec<String> {
+        let pipes = SyncPipes::new(reporef.clone(), None, tokio::sync::broadcast::channel(1).0);
+        let processed = Mutex::new(vec![]);
+
+        FileWalker::index_directory(root).for_each(&pipes, |entry| {
+            let path = PathBuf::from(entry.path().unwrap());
+            let relative_path = path.strip_prefix(root).unwrap().to_owned();
+            let (_, tantivy_hash) = cache_keys(&reporef.to_string(), &relative_path, &entry);
+
+            if !is_cache_fresh(cache, &tantivy_hash, &path) && path.is_file() {
+                let relative_path = relative_path.to_string_lossy().replace('\\', "/");
+                processed.lock().unwrap().push(relative_path);
+            }
+        });
+
+        cache.retain(|_, v| v.fresh);
+
+        let mut processed = processed.into_inner().unwrap();
+        processed.sort();
+        processed
+    }
+
+    /// Load a persisted cache back, as `FileCache::retrieve` does, with every entry stale.
+    fn reload(cache: &FileCacheSnapshot) -> FileCacheSnapshot {
+        let reloaded = FileCacheSnapshot::default();
+        cache.scan(|k, _| {
+            _ = reloaded.insert(
+                k.clone(),
+                FreshValue {
+                    fresh: false,
+                    value: (),
+                },
+            );
+        });
+
+        reloaded
+    }
+
+    #[test]
+    fn local_dir_reindexes_changed_files_only() {
+        let (_tmpdir, root) = local_dir_fixture();
+        let reporef = RepoRef::new(Backend::LocalDir, &root.to_string_lossy()).unwrap();
+
+        let cache = FileCacheSnapshot::default();
+        assert_eq!(
+            index_files(&reporef, &root, &cache),
+            ["api/reference.md", "build/manifest.json", "guide.md"]
+        );
+        let revision = FileWalker::revision(&root);
+
+        // Nothing changed, so nothing is processed, and the revision stays the same.
+        let cache = reload(&cache);
+        assert!(index_files(&reporef, &root, &cache).is_empty());
+        assert_eq!(FileWalker::revision(&root), revision);
+
+        // Only the modified and the new file are processed on the next run.
+        std::fs::write(
+            root.join("guide.md"),
+            "# Getting started\n\nIt's changed.\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("build/notes.txt"), "Built from a clean tree.\n").unwrap();
+
+        let cache = reload(&cache);
+        assert_eq!(
+            index_files(&reporef, &root, &cache),
+            ["build/notes.txt", "guide.md"]
+        );
+
+        let changed = FileWalker::revision(&root);
+        assert_ne!(changed, revision);
+
+        // Removing a file drops it from the cache, and changes the revision again.
+        std::fs::remove_file(root.join("build/notes.txt")).unwrap();
+
+        let previous = cache.len();
+        let cache = reload(&cache);
+        assert!(index_files(&reporef, &root, &cache).is_empty());
+        assert_eq!(cache.len(), previous - 1);
+        assert_ne!(FileWalker::revision(&root), changed);
+    }
+
+    /// Resolve every file of the `lfs` fixture like `File::worker` does, returning the relative
+    /// paths that would be indexed with their contents, and whether they are indexed as pointers.
+    fn resolve_lfs(policy: lfs::LfsPolicy) -> (Vec<(String, String, bool)>, lfs::LfsCounts) {
+        let (_tmpdir, root) = lfs_fixture();
+        let reporef = RepoRef::new(Backend::LocalDir, &root.to_string_lossy()).unwrap();
+        let pipes = SyncPipes::new(reporef, None, tokio::sync::broadcast::channel(1).0);
+        let tally = lfs::LfsTally::default();
+        let indexed = Mutex::new(vec![]);
+
+        FileWalker::index_directory(&root).for_each(&pipes, |entry| {
+            let RepoDirEntry::File(file) = entry else {
+                return;
+            };
+
+            let relative_path = PathBuf::from(&file.path)
+                .strip_prefix(&root)
+                .unwrap()
+                .to_string_lossy()
+                .replace('\\', "/");
+
+            let (buffer, pointer) = match policy.resolve(&root, &file.buffer) {
+                Some(resolved) => {
+                    tally.record(&resolved);
+                    match resolved {
+                        lfs::Resolved::Skip => return,
+                        lfs::Resolved::Object(object) => (object, false),
+                        lfs::Resolved::Pointer => (file.buffer, true),
+                    }
+                }
+                None => (file.buffer, false),
+            };
+
+            indexed
+                .lock()
+                .unwrap()
+                .push((relative_path, buffer, pointer));
+        });
+
+        let mut indexed = indexed.into_inner().unwrap();
+        indexed.sort();
+        (indexed, tally.counts())
+    }
+
+    fn lfs_paths(indexed: &[(String, String, bool)]) -> Vec<(&str, bool)> {
+        indexed
+            .iter()
+            .map(|(path, _, pointer)| (path.as_str(), *pointer))
+            .collect()
+    }
+
+    #[test]
+    fn lfs_pointers_are_skipped() {
+        let (indexed, counts) = resolve_lfs(lfs::LfsPolicy::Skip);
+
+        assert_eq!(lfs_paths(&indexed), [("README.md", false)]);
+        assert_eq!(
+            counts,
+            lfs::LfsCounts {
+                skipped: 2,
+                unfetched: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn lfs_objects_are_fetched() {
+        let (indexed, counts) = resolve_lfs(lfs::LfsPolicy::Fetch);
+
+        assert_eq!(
+            lfs_paths(&indexed),
+            [
+                ("README.md", false),
+                ("data/latency.csv", false),
+                ("models/ranker.onnx", true),
+            ]
+        );
+        assert_eq!(indexed[1].1, "name,latency_ms\nsearch,12\nindex,340\n");
+        assert!(lfs::Pointer::parse(&indexed[2].1).is_some());
+        assert_eq!(
+            counts,
+            lfs::LfsCounts {
+                skipped: 0,
+                unfetched: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn lfs_pointers_are_indexed() {
+        let (indexed, counts) = resolve_lfs(lfs::LfsPolicy::Index);
+
+        assert_eq!(
+            lfs_paths(&indexed),
+            [
+                ("README.md", false),
+                ("data/latency.csv", true),
+                ("models/ranker.onnx", true),
+            ]
+        );
+        assert_eq!(
+            counts,
+            lfs::LfsCounts {
+                skipped: 0,
+                unfetched: 2,
+            }
+        );
+    }
+
+    fn glob(pattern: &str, paths: &[&str], limit: usize) -> Vec<String> {
+        let regex = build_glob_regex(pattern).unwrap();
+        filter_glob_matches(&regex, paths.iter().map(|p| p.to_string()), limit)
+    }
+
+    #[test]
+    fn glob_path_matches() {
+        let paths = [
+            "migrations/",
+            "migrations/20230101_init.sql",
+            "migrations/20230202_users.sql",
+            "README.md",
+            "schema.sql",
+            "server/src/db.rs",
+            "server/src/db/queries.sql",
+            "server/src/lib.rs",
+            "server/Cargo.toml",
+            "web/src/index.ts",
+        ];
+
+        assert_eq!(
+            glob("**/*.sql", &paths, 200),
+            vec![
+                "migrations/20230101_init.sql",
+                "migrations/20230202_users.sql",
+                "schema.sql",
+                "server/src/db/queries.sql",
+            ]
+        );
+
+        assert_eq!(glob("*.sql", &paths, 200), glob("**/*.sql", &paths, 200));
+        assert_eq!(glob("**/*.sql", &paths, 2).len(), 2);
+        assert_eq!(
+            glob("server/src/*.rs", &paths, 200),
+            vec!["server/src/db.rs", "server/src/lib.rs"]
+        );
+        assert_eq!(
+            glob("server/**/*.{rs,toml}", &paths, 200),
+            vec!["server/Cargo.toml", "server/src/db.rs", "server/src/lib.rs"]
+        );
+        assert_eq!(
+            glob("migrations/2023020?_*", &paths, 200),
+            vec!["migrations/20230202_users.sql"]
+        );
+        assert_eq!(glob("[!s]*.md", &paths, 200), vec!["README.md"]);
+    }
+
+    #[test]
+    fn invalid_globs_do_not_compile() {
+        assert!(build_glob_regex("src/[a-z").is_none());
+        assert!(build_glob_regex("src/*.{rs,toml").is_none());
+    }
 
     #[test]
     fn fuzzy_multibyte_should_compile() {
@@ -0,0 +1,217 @@
+//! Git LFS pointer files.
+//!
+//! Repositories that use Git LFS commit a small pointer file in place of every large file, and
+//! `git lfs` fetches the real objects into `.git/lfs/objects` separately. Indexing the pointer
+//! text as if it were the file would lead the model to believe that the file is empty, so pointers
+//! are detected during indexing and handled according to an `LfsPolicy`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::repo::iterator::MAX_FILE_LEN;
+
+/// The first line of every pointer file.
+const VERSION_LINE: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// Pointer files are a few lines long; anything larger is a regular file.
+const MAX_POINTER_LEN: usize = 1024;
+
+/// What to index in place of an LFS pointer file.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LfsPolicy {
+    /// Leave pointer files out of the index
+    Skip,
+    /// Index the real object if it was fetched with `git lfs`, otherwise index the pointer
+    #[default]
+    Fetch,
+    /// Index the pointer, even if the real object was fetched
+    Index,
+}
+
+/// A parsed LFS pointer file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pointer {
+    /// The SHA-256 of the object, in hex.
+    pub oid: String,
+    /// The size of the object, in bytes.
+    pub size: u64,
+}
+
+impl Pointer {
+    /// Parse `content` as a pointer file, returning `None` if it isn't one.
+    pub fn parse(content: &str) -> Option<Self> {
+        if content.len() > MAX_POINTER_LEN {
+            return None;
+        }
+
+        let mut lines = content.lines();
+        if lines.next()?.trim_end() != VERSION_LINE {
+            return None;
+        }
+
+        let (mut oid, mut size) = (None, None);
+        for line in lines {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "oid" => {
+                    let hash = value.strip_prefix("sha256:")?;
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return None;
+                    }
+                    oid = Some(hash.to_ascii_lowercase());
+                }
+                "size" => size = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            oid: oid?,
+            size: size?,
+        })
+    }
+
+    /// Where `git lfs` stores the object of this pointer, in a checkout at `repo_disk_path`.
+    pub fn object_path(&self, repo_disk_path: &Path) -> PathBuf {
+        repo_disk_path
+            .join(".git/lfs/objects")
+            .join(&self.oid[..2])
+            .join(&self.oid[2..4])
+            .join(&self.oid)
+    }
+
+    /// Read the object of this pointer, if it was fetched and can be indexed as text.
+    pub fn read_object(&self, repo_disk_path: &Path) -> Option<String> {
+        if self.size > MAX_FILE_LEN {
+            return None;
+        }
+
+        let object = std::fs::read(self.object_path(repo_disk_path)).ok()?;
+        if object.len() as u64 != self.size {
+            return None;
+        }
+
+        String::from_utf8(object).ok()
+    }
+}
+
+/// The contents that a file is indexed with, once LFS pointers are handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resolved {
+    /// The file is a pointer which isn't indexed at all.
+    Skip,
+    /// The file is a pointer, whose object is indexed in its place.
+    Object(String),
+    /// The file is a pointer, which is indexed as it is.
+    Pointer,
+}
+
+impl LfsPolicy {
+    /// Decide what to index for a file with `content`, or `None` if it isn't a pointer file.
+    pub fn resolve(self, repo_disk_path: &Path, content: &str) -> Option<Resolved> {
+        let pointer = Pointer::parse(content)?;
+
+        Some(match self {
+            Self::Skip => Resolved::Skip,
+            Self::Index => Resolved::Pointer,
+            Self::Fetch => pointer
+                .read_object(repo_disk_path)
+                .map_or(Resolved::Pointer, Resolved::Object),
+        })
+    }
+}
+
+/// The text that pointer files are read as, in place of their contents.
+pub fn not_fetched_message(path: &str, content: &str) -> String {
+    match Pointer::parse(content) {
+        Some(pointer) => format!(
+            "LFS object not fetched: `{path}` is stored with Git LFS, and its {} byte object is \
+             not available to read.",
+            pointer.size
+        ),
+        None => format!("LFS object not fetched: `{path}` is stored with Git LFS."),
+    }
+}
+
+/// The number of pointer files that were not indexed with their objects, in the last index of a
+/// repository.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LfsCounts {
+    /// Pointers that were left out of the index.
+    pub skipped: usize,
+    /// Pointers that were indexed without their objects.
+    pub unfetched: usize,
+}
+
+/// `LfsCounts` that are shared between indexing threads.
+#[derive(Debug, Default)]
+pub struct LfsTally {
+    skipped: AtomicUsize,
+    unfetched: AtomicUsize,
+}
+
+impl LfsTally {
+    pub fn record(&self, resolved: &Resolved) {
+        let counter = match resolved {
+            Resolved::Skip => &self.skipped,
+            Resolved::Pointer => &self.unfetched,
+            Resolved::Object(_) => return,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> LfsCounts {
+        LfsCounts {
+            skipped: self.skipped.load(Ordering::Relaxed),
+            unfetched: self.unfetched.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+    fn pointer(oid: &str, size: usize) -> String {
+        format!("{VERSION_LINE}\noid sha256:{oid}\nsize {size}\n")
+    }
+
+    #[test]
+    fn parse_pointer() {
+        assert_eq!(
+            Pointer::parse(&pointer(OID, 12345)),
+            Some(Pointer {
+                oid: OID.to_owned(),
+                size: 12345,
+            })
+        );
+
+        // Extension keys are allowed, and `\r\n` line endings from checkouts on Windows.
+        let extended =
+            format!("{VERSION_LINE}\r\next-0-foo sha256:{OID}\r\noid sha256:{OID}\r\nsize 3\r\n");
+        assert_eq!(Pointer::parse(&extended).unwrap().size, 3);
+
+        assert_eq!(Pointer::parse("# Getting started\n"), None);
+        assert_eq!(Pointer::parse(&pointer("not-a-hash", 3)), None);
+        assert_eq!(Pointer::parse(&format!("{VERSION_LINE}\nsize 3\n")), None);
+        assert_eq!(
+            Pointer::parse(&(pointer(OID, 3) + &"x".repeat(MAX_POINTER_LEN))),
+            None
+        );
+    }
+
+    #[test]
+    fn not_fetched() {
+        let message = not_fetched_message("models/ranker.onnx", &pointer(OID, 48103375));
+        assert!(message.starts_with("LFS object not fetched: `models/ranker.onnx`"));
+        assert!(message.contains("48103375 byte object"));
+    }
+}
@@ -12,6 +12,7 @@ use crate::{
         compiler::Compiler,
         parser::{self, Query, Target},
     },
+    semantic,
     symbol::SymbolLocations,
     text_range::TextRange,
 };
@@ -26,6 +27,8 @@ pub struct ContentDocument {
     pub line_end_indices: Vec<u32>,
     pub symbol_locations: SymbolLocations,
     pub branches: Option<String>,
+    /// Whether this file is a Git LFS pointer, which was indexed without its object.
+    pub lfs_pointer: bool,
 }
 
 impl ContentDocument {
@@ -34,6 +37,33 @@ impl ContentDocument {
             .and_then(TreeSitterFile::hoverable_ranges)
             .ok()
     }
+
+    /// A semantic payload that covers the whole document as a single chunk.
+    ///
+    /// The payload has no content hash or symbol path, which are only known while indexing.
+    pub fn to_semantic_payload(&self) -> semantic::Payload {
+        semantic::Payload {
+            lang: self
+                .lang
+                .as_deref()
+                .map(str::to_ascii_lowercase)
+                .unwrap_or_default(),
+            repo_name: self.repo_name.clone(),
+            repo_ref: self.repo_ref.clone(),
+            relative_path: self.relative_path.clone(),
+            text: self.content.clone(),
+            end_line: self.line_end_indices.len() as u64,
+            end_byte: self.content.len() as u64,
+            branches: self
+                .branches
+                .iter()
+                .flat_map(|branches| branches.split('\n'))
+                .map(str::to_owned)
+                .collect(),
+            lfs_pointer: self.lfs_pointer,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -118,6 +148,11 @@ impl DocumentRead for ContentReader {
         )
         .unwrap_or_default();
 
+        let lfs_pointer = doc
+            .get_first(schema.is_lfs_pointer)
+            .and_then(|v| v.as_bool())
+            .unwrap_or_default();
+
         ContentDocument {
             relative_path,
             repo_name,
@@ -127,6 +162,7 @@ impl DocumentRead for ContentReader {
             line_end_indices,
             lang,
             branches,
+            lfs_pointer,
         }
     }
 }
@@ -3,6 +3,7 @@ use tantivy::schema::{
     FAST, STORED, STRING,
 };
 
+use super::lfs::LfsPolicy;
 use crate::{db::SqlDb, semantic::Semantic};
 
 #[cfg(feature = "debug")]
@@ -18,6 +19,7 @@ pub struct File {
     pub(super) schema: Schema,
     pub(super) semantic: Option<Semantic>,
     pub(super) sql: SqlDb,
+    pub(super) lfs: LfsPolicy,
 
     #[cfg(feature = "debug")]
     pub histogram: Arc<RwLock<Histogram>>,
@@ -63,10 +65,13 @@ pub struct File {
 
     /// Whether this entry is a file or a directory
     pub is_directory: Field,
+
+    /// Whether this file is a Git LFS pointer, which was indexed without its object
+    pub is_lfs_pointer: Field,
 }
 
 impl File {
-    pub fn new(sql: SqlDb, semantic: Option<Semantic>) -> Self {
+    pub fn new(sql: SqlDb, semantic: Option<Semantic>, lfs: LfsPolicy) -> Self {
         let mut builder = tantivy::schema::SchemaBuilder::new();
         let trigram = TextOptions::default().set_stored().set_indexing_options(
             TextFieldIndexing::default()
@@ -103,6 +108,7 @@ impl File {
         let raw_relative_path = builder.add_bytes_field("raw_relative_path", FAST);
 
         let is_directory = builder.add_bool_field("is_directory", FAST);
+        let is_lfs_pointer = builder.add_bool_field("is_lfs_pointer", STORED);
 
         Self {
             repo_disk_path,
@@ -124,7 +130,9 @@ impl File {
             raw_relative_path,
             branches,
             is_directory,
+            is_lfs_pointer,
             sql,
+            lfs,
 
             #[cfg(feature = "debug")]
             histogram: Arc::new(Histogram::builder().build().unwrap().into()),
@@ -65,6 +65,11 @@ impl<'a> TreeSitterFile<'a> {
         })
     }
 
+    /// The root node of this file's syntax tree, for walks that no query covers.
+    pub fn root_node(&self) -> tree_sitter::Node<'_> {
+        self.tree.root_node()
+    }
+
     pub fn hoverable_ranges(
         self,
     ) -> Result<Vec<crate::text_range::TextRange>, TreeSitterFileError> {
@@ -82,6 +87,128 @@ impl<'a> TreeSitterFile<'a> {
             .collect::<Vec<_>>())
     }
 
+    /// The names of functions called within a byte range of this file, alongside the byte range
+    /// of each name, in source order.
+    pub fn calls(
+        &self,
+        range: std::ops::Range<usize>,
+    ) -> Result<Vec<(&'a str, std::ops::Range<usize>)>, TreeSitterFileError> {
+        let query = self
+            .language
+            .call_query
+            .query(self.language.grammar)
+            .map_err(TreeSitterFileError::QueryError)?;
+        let src: &'a [u8] = self.src;
+        let root_node = self.tree.root_node();
+        let mut cursor = tree_sitter::QueryCursor::new();
+        cursor.set_byte_range(range.clone());
+
+        let mut calls = cursor
+            .matches(query, root_node, src)
+            .flat_map(|m| m.captures)
+            .filter(|c| range.contains(&c.node.start_byte()))
+            .filter_map(|c| {
+                let name = std::str::from_utf8(&src[c.node.byte_range()]).ok()?;
+                Some((name, c.node.byte_range()))
+            })
+            .collect::<Vec<_>>();
+
+        calls.sort_by_key(|(_, range)| range.start);
+        Ok(calls)
+    }
+
+    /// The byte range of the innermost function or method that contains a byte range.
+    pub fn enclosing_function(
+        &self,
+        range: std::ops::Range<usize>,
+    ) -> Option<std::ops::Range<usize>> {
+        self.enclosing_function_node(range).map(|n| n.byte_range())
+    }
+
+    /// The byte range of the function or method whose name is at a byte range.
+    ///
+    /// This returns `None` if the name is inside the body of its enclosing function, as is the
+    /// case for local variables.
+    pub fn function_named_at(
+        &self,
+        name: std::ops::Range<usize>,
+    ) -> Option<std::ops::Range<usize>> {
+        let node = self.enclosing_function_node(name.clone())?;
+        let body_start = node
+            .child_by_field_name("body")
+            .map_or(node.end_byte(), |body| body.start_byte());
+
+        (name.start < body_start).then(|| node.byte_range())
+    }
+
+    fn enclosing_function_node(
+        &self,
+        range: std::ops::Range<usize>,
+    ) -> Option<tree_sitter::Node<'_>> {
+        let is_function = |kind: &str| {
+            (kind.contains("function")
+                || kind.contains("method")
+                || kind == "constructor_declaration")
+                && !kind.contains("call")
+                && !kind.contains("invocation")
+                && !kind.contains("type")
+        };
+
+        let mut node = self
+            .tree
+            .root_node()
+            .descendant_for_byte_range(range.start, range.end);
+
+        while let Some(n) = node {
+            if is_function(n.kind()) {
+                return Some(n);
+            }
+            node = n.parent();
+        }
+
+        None
+    }
+
+    /// The names of the symbols that enclose a byte range, from the outermost to the innermost.
+    ///
+    /// These are the modules, types, `impl` blocks and functions that contain the range. Anonymous
+    /// symbols, like closures, are left out.
+    pub fn symbol_path(&self, range: std::ops::Range<usize>) -> Vec<&'a str> {
+        let src: &'a [u8] = self.src;
+        let mut path = Vec::new();
+
+        let mut node = self
+            .tree
+            .root_node()
+            .descendant_for_byte_range(range.start, range.end);
+
+        while let Some(n) = node {
+            let name = match n.kind() {
+                // `impl Trait for Type` is named after `Type`.
+                "impl_item" => n.child_by_field_name("type"),
+                kind if is_symbol(kind) => symbol_name(n),
+                _ => None,
+            };
+
+            if let Some(name) = name.and_then(|n| std::str::from_utf8(&src[n.byte_range()]).ok()) {
+                path.push(name);
+            }
+
+            node = n.parent();
+        }
+
+        path.reverse();
+        path
+    }
+
+    /// The separator between the names of a symbol path in this file's language.
+    pub fn symbol_separator(&self) -> &'static str {
+        match self.language.language_ids {
+            ["Rust"] | ["C++"] => "::",
+            _ => ".",
+        }
+    }
+
     /// Produce a lexical scope-graph for this TreeSitterFile.
     pub fn scope_graph(self) -> Result<ScopeGraph, TreeSitterFileError> {
         let query = self
@@ -94,3 +221,137 @@ impl<'a> TreeSitterFile<'a> {
         Ok(ResolutionMethod::Generic.build_scope(query, root_node, self.src, self.language))
     }
 }
+
+/// Whether a node of this kind is a named symbol, that can be part of a symbol path.
+fn is_symbol(kind: &str) -> bool {
+    let is_function = (kind.contains("function") || kind.contains("method"))
+        && !kind.contains("call")
+        && !kind.contains("invocation")
+        && !kind.contains("type");
+
+    is_function
+        || matches!(
+            kind,
+            "mod_item"
+                | "struct_item"
+                | "enum_item"
+                | "union_item"
+                | "trait_item"
+                | "class"
+                | "module"
+                | "class_declaration"
+                | "abstract_class_declaration"
+                | "class_definition"
+                | "class_specifier"
+                | "struct_specifier"
+                | "interface_declaration"
+                | "enum_declaration"
+                | "constructor_declaration"
+                | "namespace_declaration"
+                | "namespace_definition"
+                | "internal_module"
+        )
+}
+
+/// The node that names a symbol.
+///
+/// C and C++ functions have no name field, and are named by the innermost of their declarators
+/// instead.
+fn symbol_name(node: tree_sitter::Node<'_>) -> Option<tree_sitter::Node<'_>> {
+    if let Some(name) = node.child_by_field_name("name") {
+        return Some(name);
+    }
+
+    let mut declarator = node.child_by_field_name("declarator")?;
+    while let Some(inner) = declarator.child_by_field_name("declarator") {
+        declarator = inner;
+    }
+
+    declarator
+        .kind()
+        .ends_with("identifier")
+        .then_some(declarator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol_path<'a>(file: &TreeSitterFile<'a>, src: &str, needle: &str) -> Vec<&'a str> {
+        let start = src.find(needle).unwrap();
+        file.symbol_path(start..start + needle.len())
+    }
+
+    #[test]
+    fn test_symbol_path_nested_impl() {
+        let src = r#"
+use std::fmt;
+
+mod outer {
+    mod inner {
+        struct Foo;
+
+        impl Foo {
+            fn bar(&self) -> usize {
+                let local = 1;
+                local
+            }
+        }
+
+        impl fmt::Display for Foo {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "foo")
+            }
+        }
+
+        fn make() {
+            impl Foo {
+                fn baz() {
+                    let nested = || 2;
+                }
+            }
+        }
+    }
+}
+"#;
+        let file = TreeSitterFile::try_build(src.as_bytes(), "Rust").unwrap();
+
+        assert_eq!(
+            symbol_path(&file, src, "let local = 1;"),
+            ["outer", "inner", "Foo", "bar"]
+        );
+        assert_eq!(
+            symbol_path(&file, src, "write!(f, \"foo\")"),
+            ["outer", "inner", "Foo", "fmt"]
+        );
+        assert_eq!(
+            symbol_path(&file, src, "let nested = || 2;"),
+            ["outer", "inner", "make", "Foo", "baz"]
+        );
+        assert_eq!(
+            symbol_path(&file, src, "struct Foo;"),
+            ["outer", "inner", "Foo"]
+        );
+        assert!(symbol_path(&file, src, "use std::fmt;").is_empty());
+        assert_eq!(file.symbol_separator(), "::");
+    }
+
+    #[test]
+    fn test_symbol_path_class_method() {
+        let src = r#"
+import os
+
+class Loader:
+    def load(self, path):
+        return open(path).read()
+"#;
+        let file = TreeSitterFile::try_build(src.as_bytes(), "Python").unwrap();
+
+        assert_eq!(
+            symbol_path(&file, src, "return open(path)"),
+            ["Loader", "load"]
+        );
+        assert!(symbol_path(&file, src, "import os").is_empty());
+        assert_eq!(file.symbol_separator(), ".");
+    }
+}
@@ -64,6 +64,9 @@ pub struct TSLanguageConfig {
     /// Compiled tree-sitter hoverables query
     pub hoverable_query: MemoizedQuery,
 
+    /// Compiled tree-sitter query for the names of called functions, captured as `@call`
+    pub call_query: MemoizedQuery,
+
     /// Namespaces defined by this language,
     /// E.g.: type namespace, variable namespace, function namespace
     pub namespaces: NameSpaces,
@@ -147,6 +150,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn verify_all_call_queries() {
+        for language in ALL_LANGUAGES {
+            let query = language.call_query.query(language.grammar).unwrap();
+            assert_eq!(
+                query.capture_names(),
+                ["call"],
+                "{:?}",
+                language.language_ids
+            );
+        }
+    }
+
     fn has_valid_symbol_kinds(query: &Query, kinds: Vec<&str>) -> bool {
         let query_file_symbol_names = query
             .capture_names()
@@ -13,6 +13,13 @@ pub static C: TSLanguageConfig = TSLanguageConfig {
         (type_identifier)] @hoverable
         ",
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call_expression
+         function: [(identifier) @call
+                    (field_expression field: (field_identifier) @call)])
+        "#,
+    ),
     namespaces: &[&[
         // imports
         "header",
@@ -10,6 +10,13 @@ pub static C_SHARP: TSLanguageConfig = TSLanguageConfig {
         (identifier) @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (invocation_expression . (identifier) @call)
+        (invocation_expression
+         . (member_access_expression (identifier) @call .))
+        "#,
+    ),
     namespaces: &[&[
         // variables, functions
         "local",
@@ -15,6 +15,14 @@ pub static CPP: TSLanguageConfig = TSLanguageConfig {
         (namespace_identifier)] @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call_expression
+         function: [(identifier) @call
+                    (field_expression field: (field_identifier) @call)
+                    (qualified_identifier name: (identifier) @call)])
+        "#,
+    ),
     namespaces: &[&[
         // imports
         "header",
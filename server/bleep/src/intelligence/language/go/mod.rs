@@ -13,6 +13,13 @@ pub static GO: TSLanguageConfig = TSLanguageConfig {
          (field_identifier)] @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call_expression
+         function: [(identifier) @call
+                    (selector_expression field: (field_identifier) @call)])
+        "#,
+    ),
     namespaces: &[
         // variables
         &["const", "var", "func", "module"],
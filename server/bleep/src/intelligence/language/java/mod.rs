@@ -11,6 +11,12 @@ pub static JAVA: TSLanguageConfig = TSLanguageConfig {
          (type_identifier)] @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (method_invocation name: (identifier) @call)
+        (object_creation_expression type: (type_identifier) @call)
+        "#,
+    ),
     namespaces: &[&[
         // variables
         "local",
@@ -15,6 +15,14 @@ pub static JAVASCRIPT: TSLanguageConfig = TSLanguageConfig {
          (statement_identifier)] @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call_expression
+         function: [(identifier) @call
+                    (member_expression property: (property_identifier) @call)])
+        (new_expression constructor: (identifier) @call)
+        "#,
+    ),
     namespaces: &[&[
         //variables
         "constant",
@@ -10,6 +10,12 @@ pub static PHP: TSLanguageConfig = TSLanguageConfig {
         (name) @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (function_call_expression . (name) @call)
+        (member_call_expression name: (name) @call)
+        "#,
+    ),
     namespaces: &[&[
         // variables
         "constant",
@@ -10,6 +10,13 @@ pub static PYTHON: TSLanguageConfig = TSLanguageConfig {
         (identifier) @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call
+         function: [(identifier) @call
+                    (attribute attribute: (identifier) @call)])
+        "#,
+    ),
     namespaces: &[&["class", "function", "parameter", "variable"]],
 };
 
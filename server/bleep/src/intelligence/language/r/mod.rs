@@ -10,6 +10,11 @@ pub static R: TSLanguageConfig = TSLanguageConfig {
         (identifier) @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call . (identifier) @call)
+        "#,
+    ),
     namespaces: &[&[
         // variables
         "variable",
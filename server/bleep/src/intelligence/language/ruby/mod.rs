@@ -15,6 +15,11 @@ pub static RUBY: TSLanguageConfig = TSLanguageConfig {
         (hash_key_symbol)] @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call method: (identifier) @call)
+        "#,
+    ),
     namespaces: &[
         // everything is an object
         &["variable", "constant", "class", "method", "module"],
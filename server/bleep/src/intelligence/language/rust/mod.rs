@@ -13,6 +13,14 @@ pub static RUST: TSLanguageConfig = TSLanguageConfig {
          (type_identifier)] @hoverable
         "#,
     ),
+    call_query: MemoizedQuery::new(
+        r#"
+        (call_expression
+         function: [(identifier) @call
+                    (field_expression field: (field_identifier) @call)
+                    (scoped_identifier name: (identifier) @call)])
+        "#,
+    ),
     namespaces: &[&[
         // variables
         "const",
@@ -25,8 +25,16 @@ use state::PersistedState;
 use std::fs::canonicalize;
 use user::UserProfile;
 
-use crate::{background::SyncQueue, indexes::Indexes, semantic::Semantic, state::RepositoryPool};
-use anyhow::{bail, Result};
+use crate::{
+    background::SyncQueue,
+    indexes::Indexes,
+    semantic::{
+        store::{self, VectorStore},
+        Semantic,
+    },
+    state::RepositoryPool,
+};
+use anyhow::{bail, Context, Result};
 use axum::extract::FromRef;
 
 use once_cell::sync::OnceCell;
@@ -41,6 +49,7 @@ use tracing_subscriber::{
     EnvFilter,
 };
 
+mod acl;
 mod agent;
 mod background;
 mod cache;
@@ -51,12 +60,23 @@ mod env;
 mod llm_gateway;
 mod remotes;
 mod repo;
+mod warmup;
 mod webserver;
 
 #[cfg(feature = "ee")]
 mod ee;
 
+#[cfg(feature = "editor")]
+mod editor;
+
+#[cfg(feature = "eval")]
+pub mod eval;
+
+#[cfg(feature = "loadtest")]
+pub mod loadtest;
+
 pub mod analytics;
+pub mod backup;
 pub mod indexes;
 pub mod intelligence;
 pub mod periodic;
@@ -71,6 +91,19 @@ pub mod user;
 pub use config::{default_parallelism, minimum_parallelism, Configuration};
 pub use env::Environment;
 
+/// Answer queries with the agent, without running the webserver.
+pub mod embed {
+    pub use crate::{
+        agent::{
+            builder::{builder, AgentBuilder, Driver, DEFAULT_TIMEOUT},
+            exchange::Exchange,
+            Action, OutputFormat,
+        },
+        repo::{Backend, RepoRef},
+        webserver::middleware::User,
+    };
+}
+
 const LOG_ENV_VAR: &str = "BLOOP_LOG";
 static LOGGER_INSTALLED: OnceCell<bool> = OnceCell::new();
 static SENTRY_GUARD: OnceCell<sentry::ClientInitGuard> = OnceCell::new();
@@ -110,8 +143,26 @@ pub struct Application {
     /// SQL database for persistent storage
     pub sql: SqlDb,
 
+    /// LLM gateway endpoints that answer requests are spread across, if configured
+    llm_endpoints: Option<Arc<llm_gateway::EndpointPool>>,
+
+    /// Redacts secrets from repository content before it reaches the LLM, and from answers
+    secret_scanner: Arc<agent::secrets::SecretScanner>,
+
+    /// Personal data to remove from threads before they are stored
+    anonymize_patterns: Arc<Vec<regex::Regex>>,
+
+    /// Documents that every agent is given, from `Configuration::knowledge_base`
+    knowledge_base: Arc<Vec<agent::knowledge::KnowledgeDoc>>,
+
     /// Analytics backend -- may be unintialized
     pub analytics: Option<Arc<analytics::RudderHub>>,
+
+    /// Progress of pre-warming search state for hot repositories
+    warmup: Arc<warmup::Warmup>,
+
+    /// Which users can query which repositories
+    access: Arc<acl::AccessControl>,
 }
 
 impl Application {
@@ -128,26 +179,49 @@ impl Application {
         config.buffer_size = config.buffer_size.max(threads * 3_000_000);
         config.repo_buffer_size = config.repo_buffer_size.max(threads * 3_000_000);
         config.source.set_default_dir(&config.index_dir);
+        backup::apply_pending(&config).await?;
 
         let config = Arc::new(config);
         debug!(?config, "effective configuration");
 
         let sqlite = Arc::new(db::init(&config).await?);
 
-        // Initialise Semantic index if `qdrant_url` set in config
-        let semantic = match config.qdrant_url {
-            Some(ref url) => {
-                match Semantic::initialize(&config.model_dir, url, Arc::clone(&config)).await {
+        if let Err(err) = webserver::answer::conversations::backfill_citations(&sqlite).await {
+            warn!(?err, "failed to backfill conversation citations");
+        }
+
+        let access = acl::AccessControl::load(&sqlite, &config).await?.into();
+
+        // Initialise Semantic index if `qdrant_url` set in config, or if using the embedded store
+        let backend = config.semantic_backend;
+        let store: Option<Arc<dyn VectorStore>> = match (backend, &config.qdrant_url) {
+            (store::Backend::Qdrant, Some(url)) => match store::Qdrant::connect(url).await {
+                Ok(qdrant) => Some(Arc::new(qdrant)),
+                Err(e) => {
+                    bail!("Qdrant initialization failed: {}", e);
+                }
+            },
+            (store::Backend::Qdrant, None) => {
+                warn!("Semantic search disabled because `qdrant_url` is not provided. Starting without.");
+                None
+            }
+            (store::Backend::Embedded, _) => {
+                info!("Using the embedded vector store");
+                let path = config.index_path("vectors.bin");
+                Some(Arc::new(store::Embedded::open(path)?))
+            }
+        };
+
+        let semantic = match store {
+            Some(store) => {
+                match Semantic::initialize(&config.model_dir, store, Arc::clone(&config)).await {
                     Ok(semantic) => Some(semantic),
                     Err(e) => {
-                        bail!("Qdrant initialization failed: {}", e);
+                        bail!("Semantic initialization failed: {}", e);
                     }
                 }
             }
-            None => {
-                warn!("Semantic search disabled because `qdrant_url` is not provided. Starting without.");
-                None
-            }
+            None => None,
         };
 
         let env = if config.github_app_id.is_some() {
@@ -157,15 +231,41 @@ impl Application {
             env
         };
 
-        let analytics = match initialize_analytics(&config, tracking_seed, analytics_options) {
-            Ok(analytics) => Some(analytics),
-            Err(err) => {
-                warn!(?err, "failed to initialize analytics");
-                None
+        let analytics = if config.disable_analytics {
+            info!("analytics disabled");
+            None
+        } else {
+            match initialize_analytics(&config, tracking_seed, analytics_options) {
+                Ok(analytics) => Some(analytics),
+                Err(err) => {
+                    warn!(?err, "failed to initialize analytics");
+                    None
+                }
             }
         };
 
+        // Without a backend to send them to, events are not even built.
+        analytics::set_enabled(analytics.is_some());
+
+        let llm_endpoints = llm_gateway::EndpointPool::new(
+            config.llm_endpoints.clone(),
+            std::time::Duration::from_secs(config.llm_endpoint_cooldown_secs),
+        );
+
+        let secret_scanner = agent::secrets::SecretScanner::new(&config.secret_patterns)?.into();
+        let anonymize_patterns = config
+            .anonymize_patterns
+            .iter()
+            .map(|pattern| {
+                regex::Regex::new(pattern)
+                    .with_context(|| format!("invalid anonymize pattern `{pattern}`"))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into();
+        let knowledge_base = agent::knowledge::KnowledgeDoc::load(&config.knowledge_base)?.into();
+
         let repo_pool = config.source.initialize_pool()?;
+        let warmup = warmup::Warmup::new(!config.warmup_repos.is_empty()).into();
 
         Ok(Self {
             indexes: Indexes::new(
@@ -181,9 +281,15 @@ impl Application {
             credentials: config.source.initialize_credentials()?.into(),
             user_profiles: config.source.load_or_default("user_profiles")?,
             sql: sqlite,
+            llm_endpoints,
+            secret_scanner,
+            anonymize_patterns,
+            knowledge_base,
             repo_pool,
             analytics,
             semantic,
+            warmup,
+            access,
             config,
             env,
         })
@@ -258,12 +364,20 @@ impl Application {
                 tokio::spawn(periodic::sync_github_status(self.clone()));
                 tokio::spawn(periodic::check_repo_updates(self.clone()));
                 tokio::spawn(periodic::log_and_branch_rotate(self.clone()));
+                tokio::spawn(periodic::sync_pull_requests(self.clone()));
 
                 if !self.env.is_cloud_instance() {
                     tokio::spawn(periodic::clear_disk_logs(self.clone()));
                 }
             }
 
+            tokio::spawn(warmup::startup(self.clone()));
+
+            #[cfg(feature = "editor")]
+            if let Some(port) = self.config.editor_port {
+                joins.spawn(editor::start(self.clone(), port));
+            }
+
             joins.spawn(webserver::start(self));
         }
 
@@ -291,6 +405,10 @@ impl Application {
     }
 
     fn track_query(&self, user: &webserver::middleware::User, event: &analytics::QueryEvent) {
+        if !analytics::enabled() {
+            return;
+        }
+
         if let Some(analytics) = self.analytics.as_ref() {
             analytics.track_query(user, event.clone());
         }
@@ -1,11 +1,18 @@
 //! A Rust-friendly interface to Bloop's LLM Gateway service.
 
-use std::time::Duration;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, bail};
 use axum::http::StatusCode;
 use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
 use reqwest_eventsource::EventSource;
+use secrecy::{ExposeSecret, SecretString};
 use tracing::{debug, error, warn};
 
 use self::api::FunctionCall;
@@ -80,6 +87,7 @@ pub mod api {
         pub messages: Messages,
         pub functions: Option<Functions>,
         pub provider: Provider,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         pub max_tokens: Option<u32>,
         pub temperature: Option<f32>,
         pub presence_penalty: Option<f32>,
@@ -114,6 +122,17 @@ pub mod api {
     }
 
     pub type Result = std::result::Result<String, Error>;
+
+    /// A single event of a response stream.
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(untagged)]
+    pub enum Chunk {
+        /// The fingerprint of the backend configuration that the model ran with.
+        Fingerprint {
+            system_fingerprint: String,
+        },
+        Result(Result),
+    }
 }
 
 impl api::Message {
@@ -151,6 +170,38 @@ impl api::Message {
             content: content.to_string(),
         }
     }
+
+    /// The number of tokens that this message takes up in a prompt to `model`.
+    ///
+    /// This doesn't include the few tokens that prime the reply, which are only counted once for
+    /// a whole prompt.
+    pub fn count_tokens(&self, model: &str) -> anyhow::Result<usize> {
+        let tokenizer = crate::agent::tokens::Tokenizer::new(model)?;
+        Ok(tokenizer.count_message(&self.into()))
+    }
+}
+
+impl api::FunctionCall {
+    /// Render this call as `name(arguments)`, with the arguments pretty-printed on their own
+    /// lines, for logging.
+    ///
+    /// Arguments that aren't valid JSON, such as those of a truncated response, are kept as-is.
+    pub fn pretty_print(&self) -> String {
+        let arguments = serde_json::from_str::<serde_json::Value>(&self.arguments)
+            .and_then(|args| serde_json::to_string_pretty(&args))
+            .unwrap_or_else(|_| self.arguments.clone());
+
+        let arguments = arguments
+            .lines()
+            .map(|line| format!("  {line}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        format!(
+            "{}(\n{arguments}\n)",
+            self.name.as_deref().unwrap_or_default()
+        )
+    }
 }
 
 impl From<&api::Message> for tiktoken_rs::ChatCompletionRequestMessage {
@@ -185,12 +236,200 @@ impl From<&api::Message> for tiktoken_rs::ChatCompletionRequestMessage {
     }
 }
 
+/// The context window and pricing of a model, for operators to budget with.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct LlmModelInfo {
+    pub name: String,
+    /// The most tokens that a prompt and its completion can take up together.
+    pub context_window: usize,
+    /// The cost of prompt tokens, in USD per 1K tokens.
+    pub input_cost_per_1k: f64,
+    /// The cost of completion tokens, in USD per 1K tokens.
+    pub output_cost_per_1k: f64,
+}
+
+/// The models that `LlmModelInfo` knows about, with their context window and their input and
+/// output costs per 1K tokens.
+static MODELS: Lazy<HashMap<&'static str, (usize, f64, f64)>> = Lazy::new(|| {
+    HashMap::from([
+        ("gpt-4-0613", (8192, 0.03, 0.06)),
+        ("gpt-4-turbo-preview", (128_000, 0.01, 0.03)),
+        ("gpt-3.5-turbo", (16_385, 0.0005, 0.0015)),
+        ("gpt-4o", (128_000, 0.005, 0.015)),
+    ])
+});
+
+impl LlmModelInfo {
+    /// The metadata of `model`, or the default "unknown" model if it isn't a known model.
+    pub fn for_model(model: &str) -> Self {
+        match MODELS.get_key_value(model) {
+            Some((name, &(context_window, input_cost_per_1k, output_cost_per_1k))) => Self {
+                name: name.to_string(),
+                context_window,
+                input_cost_per_1k,
+                output_cost_per_1k,
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+impl Default for LlmModelInfo {
+    fn default() -> Self {
+        Self {
+            name: "unknown".to_owned(),
+            context_window: 0,
+            input_cost_per_1k: 0.0,
+            output_cost_per_1k: 0.0,
+        }
+    }
+}
+
+/// What to do when the `system_fingerprint` of a response differs from the one first seen.
+///
+/// A changed fingerprint means that the model may have been updated mid-thread, which can change
+/// its behaviour.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FingerprintValidation {
+    Warn,
+    Error,
+}
+
 enum ChatError {
     BadRequest,
     TooManyRequests,
+    Unauthorized,
     Other(anyhow::Error),
 }
 
+/// An LLM gateway endpoint, with the credentials used to call it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Endpoint {
+    pub url: String,
+
+    /// The bearer token to call this endpoint with. If this is not set, the client's own bearer
+    /// token is used.
+    #[serde(serialize_with = "crate::config::serialize_secret_opt_str", default)]
+    pub bearer_token: Option<SecretString>,
+
+    /// The relative share of requests sent to this endpoint. This is at least 1.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+const fn default_weight() -> u32 {
+    1
+}
+
+/// A set of endpoints that requests are spread across, with smooth weighted round-robin.
+///
+/// Endpoints that repeatedly respond with `429 Too Many Requests` or `401 Unauthorized` are
+/// circuit-broken, and receive no requests until a cooldown has passed. A pool is shared between
+/// all clients of an application, so that this state outlives single requests.
+pub struct EndpointPool {
+    endpoints: Vec<Endpoint>,
+    cooldown: Duration,
+    state: Mutex<Vec<EndpointState>>,
+}
+
+#[derive(Default)]
+struct EndpointState {
+    /// The running counter of smooth weighted round-robin.
+    current: i64,
+    /// Consecutive rate-limited or unauthorized responses.
+    failures: u32,
+    broken_until: Option<Instant>,
+}
+
+impl EndpointPool {
+    /// Consecutive failures after which an endpoint is circuit-broken.
+    const FAILURE_THRESHOLD: u32 = 3;
+
+    /// Create a pool, if any endpoints are given.
+    pub fn new(endpoints: Vec<Endpoint>, cooldown: Duration) -> Option<Arc<Self>> {
+        if endpoints.is_empty() {
+            return None;
+        }
+
+        let state = endpoints.iter().map(|_| EndpointState::default()).collect();
+
+        Some(Arc::new(Self {
+            endpoints,
+            cooldown,
+            state: Mutex::new(state),
+        }))
+    }
+
+    /// Choose the endpoint index for the next request, preferring one other than `avoid`.
+    fn select(&self, avoid: Option<usize>) -> usize {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+
+        for endpoint in state.iter_mut() {
+            if endpoint.broken_until.map_or(false, |until| until <= now) {
+                endpoint.broken_until = None;
+                endpoint.failures = 0;
+            }
+        }
+
+        let healthy = (0..state.len())
+            .filter(|&i| state[i].broken_until.is_none())
+            .collect::<Vec<_>>();
+
+        let mut candidates = healthy
+            .iter()
+            .copied()
+            .filter(|&i| Some(i) != avoid)
+            .collect::<Vec<_>>();
+
+        if candidates.is_empty() {
+            candidates = healthy;
+        }
+
+        // When all endpoints are broken, try the one that recovers first.
+        if candidates.is_empty() {
+            return (0..state.len())
+                .min_by_key(|&i| state[i].broken_until)
+                .unwrap_or_default();
+        }
+
+        let weight = |i: usize| i64::from(self.endpoints[i].weight.max(1));
+        let total = candidates.iter().map(|&i| weight(i)).sum::<i64>();
+
+        for &i in &candidates {
+            state[i].current += weight(i);
+        }
+
+        let chosen = candidates
+            .iter()
+            .copied()
+            .max_by_key(|&i| (state[i].current, Reverse(i)))
+            .expect("candidates were not empty");
+
+        state[chosen].current -= total;
+        chosen
+    }
+
+    fn record_success(&self, index: usize) {
+        self.state.lock().unwrap()[index].failures = 0;
+    }
+
+    fn record_failure(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        let endpoint = &mut state[index];
+
+        endpoint.failures += 1;
+        if endpoint.failures >= Self::FAILURE_THRESHOLD && endpoint.broken_until.is_none() {
+            warn!(
+                endpoint = index,
+                cooldown = ?self.cooldown,
+                "LLM endpoint keeps failing, circuit-breaking it"
+            );
+            endpoint.broken_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     http: reqwest::Client,
@@ -205,6 +444,20 @@ pub struct Client {
     pub provider: api::Provider,
     pub model: Option<String>,
     pub session_reference_id: Option<String>,
+    pub fingerprint_validation: Option<FingerprintValidation>,
+
+    /// Extra headers sent with every request, such as the authentication headers of a proxy.
+    headers: Vec<(String, String)>,
+
+    /// Endpoints to spread requests across. If this is `None`, requests go to `base_url`.
+    endpoints: Option<Arc<EndpointPool>>,
+
+    /// The index of the endpoint in `endpoints` that the last request went to. This is shared
+    /// between clones of a client.
+    last_endpoint: Arc<Mutex<Option<usize>>>,
+
+    /// The first fingerprint seen. This is shared between clones of a client.
+    system_fingerprint: Arc<Mutex<Option<String>>>,
 }
 
 impl Client {
@@ -222,6 +475,11 @@ impl Client {
             frequency_penalty: None,
             model: None,
             session_reference_id: None,
+            fingerprint_validation: None,
+            headers: Vec::new(),
+            endpoints: None,
+            last_endpoint: Arc::default(),
+            system_fingerprint: Arc::default(),
         }
     }
 
@@ -267,12 +525,74 @@ impl Client {
         self
     }
 
+    /// Send a header with every request, replacing any header of the same name set before.
+    pub fn set_header(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let key = key.into();
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        self.headers.push((key, value.into()));
+        self
+    }
+
+    /// Spread requests across the endpoints of a pool, instead of sending them to `base_url`.
+    pub fn endpoints(mut self, endpoints: impl Into<Option<Arc<EndpointPool>>>) -> Self {
+        self.endpoints = endpoints.into();
+        self
+    }
+
+    /// The index of the endpoint that the last request was sent to, if an endpoint pool is used.
+    ///
+    /// This never exposes the endpoint's credentials.
+    pub fn last_endpoint(&self) -> Option<usize> {
+        *self.last_endpoint.lock().unwrap()
+    }
+
+    /// Check that the `system_fingerprint` of responses stays the same across requests.
+    pub fn with_system_fingerprint_validation(mut self, validation: FingerprintValidation) -> Self {
+        self.fingerprint_validation = Some(validation);
+        self
+    }
+
+    /// Forget the first-seen fingerprint, so that the next one seen is accepted.
+    pub fn reset_fingerprint(&self) {
+        *self.system_fingerprint.lock().unwrap() = None;
+    }
+
+    /// Compare a fingerprint against the first one seen, storing it if there is none yet.
+    fn validate_fingerprint(&self, fingerprint: String) -> anyhow::Result<()> {
+        let Some(validation) = self.fingerprint_validation else {
+            return Ok(());
+        };
+
+        let mut first_seen = self.system_fingerprint.lock().unwrap();
+        match first_seen.as_deref() {
+            None => *first_seen = Some(fingerprint),
+            Some(first) if first == fingerprint => {}
+            Some(first) => match validation {
+                FingerprintValidation::Warn => {
+                    warn!(first, fingerprint, "LLM fingerprint changed mid-thread");
+                }
+                FingerprintValidation::Error => {
+                    bail!("LLM system fingerprint changed mid-thread from {first} to {fingerprint}")
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn with_headers(&self, mut builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        for (key, value) in &self.headers {
+            builder = builder.header(key, value);
+        }
+
+        builder
+    }
+
     pub async fn is_compatible(
         &self,
         version: semver::Version,
     ) -> Result<reqwest::Response, reqwest::Error> {
-        self.http
-            .get(format!("{}/v1/compatibility", self.base_url))
+        self.with_headers(self.http.get(format!("{}/v1/compatibility", self.base_url)))
             .query(&[("version", version)])
             .send()
             .await
@@ -287,13 +607,42 @@ impl Client {
         const SCALE_FACTOR: f32 = 1.5;
 
         let mut delay = INITIAL_DELAY;
+        let mut previous = None;
         for _ in 0..self.max_retries {
-            match self.chat_oneshot(messages, functions).await {
+            // Retries prefer a different endpoint than the one that just failed.
+            let endpoint = self.endpoints.as_ref().map(|pool| pool.select(previous));
+            *self.last_endpoint.lock().unwrap() = endpoint;
+            previous = endpoint;
+
+            let result = self.chat_oneshot(endpoint, messages, functions).await;
+
+            if let (Some(pool), Some(index)) = (&self.endpoints, endpoint) {
+                match &result {
+                    Ok(_) => pool.record_success(index),
+                    Err(ChatError::TooManyRequests | ChatError::Unauthorized) => {
+                        pool.record_failure(index)
+                    }
+                    Err(_) => {}
+                }
+            }
+
+            match result {
                 Err(ChatError::TooManyRequests) => {
-                    warn!(?delay, "too many LLM requests, retrying with delay...");
+                    warn!(
+                        ?delay,
+                        ?endpoint,
+                        "too many LLM requests, retrying with delay..."
+                    );
                     tokio::time::sleep(delay).await;
                     delay = Duration::from_millis((delay.as_millis() as f32 * SCALE_FACTOR) as u64);
                 }
+                Err(ChatError::Unauthorized) if endpoint.is_some() => {
+                    warn!(?endpoint, "unauthorized LLM request, retrying...");
+                }
+                Err(ChatError::Unauthorized) => {
+                    error!("LLM request was unauthorized");
+                    bail!("unauthorized LLM request");
+                }
                 Err(ChatError::BadRequest) => {
                     // We log the messages in a separate `debug!` statement so that they can be
                     // filtered out, due to their verbosity.
@@ -318,14 +667,27 @@ impl Client {
     /// Like `chat`, but without exponential backoff.
     async fn chat_oneshot(
         &self,
+        endpoint: Option<usize>,
         messages: &[api::Message],
         functions: Option<&[api::Function]>,
     ) -> Result<impl Stream<Item = anyhow::Result<String>>, ChatError> {
+        let endpoint = self
+            .endpoints
+            .as_ref()
+            .zip(endpoint)
+            .map(|(pool, i)| &pool.endpoints[i]);
+
+        let base_url = endpoint.map_or(&self.base_url, |e| &e.url);
+        let bearer = endpoint
+            .and_then(|e| e.bearer_token.as_ref())
+            .map(|token| token.expose_secret().as_str())
+            .or(self.bearer_token.as_deref());
+
         let mut event_source = Box::pin(
             EventSource::new({
-                let mut builder = self.http.post(format!("{}/v1/q", self.base_url));
+                let mut builder = self.with_headers(self.http.post(format!("{base_url}/v1/q")));
 
-                if let Some(bearer) = &self.bearer_token {
+                if let Some(bearer) = bearer {
                     builder = builder.bearer_auth(bearer);
                 }
 
@@ -370,6 +732,12 @@ impl Client {
                 warn!("too many requests to LLM");
                 return Err(ChatError::TooManyRequests);
             }
+            Some(Err(reqwest_eventsource::Error::InvalidStatusCode(status)))
+                if status == StatusCode::UNAUTHORIZED =>
+            {
+                warn!("unauthorized request to LLM");
+                return Err(ChatError::Unauthorized);
+            }
             Some(Err(e)) => {
                 return Err(ChatError::Other(anyhow!("event source error: {:?}", e)));
             }
@@ -378,6 +746,8 @@ impl Client {
             }
         }
 
+        let client = self.clone();
+
         Ok(event_source
             .filter_map(|result| async move {
                 match result {
@@ -387,9 +757,312 @@ impl Client {
                     Err(e) => Some(Err(e)),
                 }
             })
-            .map(|result| match result {
-                Ok(s) => Ok(serde_json::from_str::<api::Result>(&s)??),
-                Err(e) => bail!("event source error {e:?}"),
+            .filter_map(move |result| {
+                let result = match result {
+                    Ok(s) => match serde_json::from_str::<api::Chunk>(&s) {
+                        Ok(api::Chunk::Fingerprint { system_fingerprint }) => client
+                            .validate_fingerprint(system_fingerprint)
+                            .err()
+                            .map(Err),
+                        Ok(api::Chunk::Result(result)) => Some(result.map_err(Into::into)),
+                        Err(e) => Some(Err(e.into())),
+                    },
+                    Err(e) => Some(Err(anyhow!("event source error {e:?}"))),
+                };
+
+                futures::future::ready(result)
             }))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod mock;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use futures::TryStreamExt;
+
+    use super::{
+        mock::{Gateway, Reply, Request},
+        *,
+    };
+
+    #[test]
+    fn test_function_call_pretty_print() {
+        let call = FunctionCall {
+            name: Some("proc".to_owned()),
+            arguments: r#"{"paths":[0,2],"query":"where is the config parsed"}"#.to_owned(),
+        };
+
+        assert_eq!(
+            call.pretty_print(),
+            r#"proc(
+  {
+    "paths": [
+      0,
+      2
+    ],
+    "query": "where is the config parsed"
+  }
+)"#
+        );
+
+        let truncated = FunctionCall {
+            name: Some("code".to_owned()),
+            arguments: r#"{"query":"pars"#.to_owned(),
+        };
+        assert_eq!(truncated.pretty_print(), "code(\n  {\"query\":\"pars\n)");
+    }
+
+    #[test]
+    fn test_gpt_4_model_info() {
+        assert_eq!(
+            LlmModelInfo::for_model("gpt-4-0613"),
+            LlmModelInfo {
+                name: "gpt-4-0613".into(),
+                context_window: 8192,
+                input_cost_per_1k: 0.03,
+                output_cost_per_1k: 0.06,
+            }
+        );
+    }
+
+    #[test]
+    fn test_gpt_4_turbo_model_info() {
+        assert_eq!(
+            LlmModelInfo::for_model("gpt-4-turbo-preview"),
+            LlmModelInfo {
+                name: "gpt-4-turbo-preview".into(),
+                context_window: 128_000,
+                input_cost_per_1k: 0.01,
+                output_cost_per_1k: 0.03,
+            }
+        );
+    }
+
+    #[test]
+    fn test_gpt_3_5_turbo_model_info() {
+        assert_eq!(
+            LlmModelInfo::for_model("gpt-3.5-turbo"),
+            LlmModelInfo {
+                name: "gpt-3.5-turbo".into(),
+                context_window: 16_385,
+                input_cost_per_1k: 0.0005,
+                output_cost_per_1k: 0.0015,
+            }
+        );
+    }
+
+    #[test]
+    fn test_gpt_4o_model_info() {
+        assert_eq!(
+            LlmModelInfo::for_model("gpt-4o"),
+            LlmModelInfo {
+                name: "gpt-4o".into(),
+                context_window: 128_000,
+                input_cost_per_1k: 0.005,
+                output_cost_per_1k: 0.015,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_model_info() {
+        for model in ["", "gpt-5", "gpt-4o-mini"] {
+            assert_eq!(LlmModelInfo::for_model(model), LlmModelInfo::default());
+        }
+        assert_eq!(LlmModelInfo::default().name, "unknown");
+    }
+
+    #[test]
+    fn test_message_count_tokens() {
+        const MODEL: &str = "gpt-4-0613";
+        // Every prompt is primed for the reply with a few tokens, which aren't any message's.
+        const REPLY_PRIMING: usize = 3;
+
+        let expected = |m: &api::Message| {
+            tiktoken_rs::num_tokens_from_messages(MODEL, &[m.into()]).unwrap() - REPLY_PRIMING
+        };
+
+        // 3 tokens to frame the message, and one for the role.
+        let text = api::Message::user("Hello world");
+        assert_eq!(text.count_tokens(MODEL).unwrap(), 6);
+        assert_eq!(text.count_tokens(MODEL).unwrap(), expected(&text));
+
+        // The name of a function return takes up another token, after its own.
+        let function_return = api::Message::function_return("code", "Hello world");
+        assert_eq!(function_return.count_tokens(MODEL).unwrap(), 8);
+        assert_eq!(
+            function_return.count_tokens(MODEL).unwrap(),
+            expected(&function_return)
+        );
+
+        // Function calls are counted as their JSON encoding.
+        let function_call = api::Message::function_call(&FunctionCall {
+            name: Some("code".to_owned()),
+            arguments: r#"{"query":"where are retries scheduled"}"#.to_owned(),
+        });
+        assert_eq!(
+            function_call.count_tokens(MODEL).unwrap(),
+            expected(&function_call)
+        );
+
+        assert!(api::Message::user("").count_tokens("not-a-model").is_err());
+    }
+
+    /// Serve a mock gateway, which answers each request with the next fingerprint.
+    fn serve(fingerprints: &'static [&'static str]) -> Gateway {
+        let next = AtomicUsize::new(0);
+        Gateway::serve(move |_| {
+            let fingerprint = fingerprints[next.fetch_add(1, Ordering::SeqCst)];
+            Reply::Events(vec![
+                serde_json::json!({ "system_fingerprint": fingerprint }),
+                serde_json::json!({ "Ok": "hello" }),
+            ])
+        })
+    }
+
+    async fn chat(client: &Client) -> anyhow::Result<String> {
+        client
+            .chat(&[api::Message::user("hi")], None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_fingerprint_validation() {
+        let client = serve(&["fp_a", "fp_a", "fp_b", "fp_b"])
+            .client()
+            .with_system_fingerprint_validation(FingerprintValidation::Error);
+
+        assert_eq!(chat(&client).await.unwrap(), "hello");
+        assert_eq!(chat(&client.clone()).await.unwrap(), "hello");
+        assert!(chat(&client).await.is_err());
+
+        client.reset_fingerprint();
+        assert_eq!(chat(&client).await.unwrap(), "hello");
+
+        let client = serve(&["fp_a", "fp_b"])
+            .client()
+            .with_system_fingerprint_validation(FingerprintValidation::Warn);
+
+        assert_eq!(chat(&client).await.unwrap(), "hello");
+        assert_eq!(chat(&client).await.unwrap(), "hello");
+    }
+
+    /// Serve a mock gateway that accepts two keys, `key-0` and `key-1`, the first of which can be
+    /// revoked.
+    fn serve_keys(revoked: Arc<AtomicBool>) -> Gateway {
+        Gateway::serve(move |request| {
+            if key(request) == 0 && revoked.load(Ordering::SeqCst) {
+                Reply::Status(StatusCode::UNAUTHORIZED)
+            } else {
+                Reply::text("hello")
+            }
+        })
+    }
+
+    /// The key a request to `serve_keys` was made with.
+    fn key(request: &Request) -> usize {
+        let auth = request.headers.get("authorization");
+        if auth.and_then(|h| h.to_str().ok()) == Some("Bearer key-0") {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_tokens() {
+        let gateway = Gateway::serve(|_| Reply::text("hello"));
+        let client = gateway.client();
+
+        assert_eq!(chat(&client).await.unwrap(), "hello");
+        assert_eq!(chat(&client.clone().max_tokens(10)).await.unwrap(), "hello");
+
+        let requests = gateway.requests();
+        assert!(requests[0].body.get("max_tokens").is_none());
+        assert_eq!(requests[1].body["max_tokens"], 10);
+    }
+
+    #[tokio::test]
+    async fn test_custom_header() {
+        let gateway = Gateway::serve(|request| {
+            let auth = request.headers.get("x-proxy-auth");
+            if auth.and_then(|h| h.to_str().ok()) == Some("secret") {
+                Reply::text("hello")
+            } else {
+                Reply::Status(StatusCode::UNAUTHORIZED)
+            }
+        });
+        let mut client = gateway.client();
+
+        assert!(chat(&client).await.is_err());
+
+        client.set_header("X-Proxy-Auth", "wrong");
+        assert!(chat(&client).await.is_err());
+
+        // Setting a header again replaces it, whatever the case of its name.
+        client.set_header("x-proxy-auth", "secret");
+        assert_eq!(chat(&client).await.unwrap(), "hello");
+        assert_eq!(chat(&client.clone()).await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_failover() {
+        const COOLDOWN: Duration = Duration::from_millis(200);
+
+        let revoked = Arc::new(AtomicBool::new(true));
+        let gateway = serve_keys(revoked.clone());
+        let endpoint = |key: &str| Endpoint {
+            url: gateway.url.clone(),
+            bearer_token: Some(SecretString::new(key.to_owned())),
+            weight: 1,
+        };
+
+        let pool = EndpointPool::new(vec![endpoint("key-0"), endpoint("key-1")], COOLDOWN);
+        let client = Client::new("http://unused").endpoints(pool);
+        let hits = |i| gateway.requests_where(|request| key(request) == i).len();
+
+        // Every request succeeds, by retrying with the valid key.
+        for _ in 0..8 {
+            assert_eq!(chat(&client).await.unwrap(), "hello");
+            assert_eq!(client.last_endpoint(), Some(1));
+        }
+
+        // The revoked key is circuit-broken after repeated failures, and traffic shifts away.
+        assert_eq!(hits(0), EndpointPool::FAILURE_THRESHOLD as usize);
+        assert_eq!(hits(1), 8);
+
+        // Once the key works again, it receives traffic after the cooldown.
+        revoked.store(false, Ordering::SeqCst);
+        tokio::time::sleep(COOLDOWN).await;
+
+        for _ in 0..4 {
+            assert_eq!(chat(&client).await.unwrap(), "hello");
+        }
+
+        assert_eq!(hits(0), EndpointPool::FAILURE_THRESHOLD as usize + 2);
+        assert_eq!(hits(1), 8 + 2);
+    }
+
+    #[test]
+    fn test_weighted_selection() {
+        let endpoint = |weight| Endpoint {
+            url: String::new(),
+            bearer_token: None,
+            weight,
+        };
+
+        let pool = EndpointPool::new(vec![endpoint(3), endpoint(1)], Duration::ZERO).unwrap();
+        let picks = (0..8).map(|_| pool.select(None)).collect::<Vec<_>>();
+        assert_eq!(picks, [0, 0, 1, 0, 0, 0, 1, 0]);
+
+        // Retries avoid the endpoint that just failed.
+        assert_eq!(pool.select(Some(0)), 1);
+        assert!(EndpointPool::new(vec![], Duration::ZERO).is_none());
+    }
+}
@@ -0,0 +1,179 @@
+//! A mock LLM gateway, for tests of code that talks to a model.
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, Sse},
+        IntoResponse, Response,
+    },
+    routing::post,
+    Json, Router,
+};
+
+use super::Client;
+
+/// A request made to the mock gateway.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub headers: HeaderMap,
+    pub body: serde_json::Value,
+}
+
+impl Request {
+    /// Whether the model was offered functions to call, as it is on every step of the agent.
+    pub fn is_function_call(&self) -> bool {
+        !self.body["functions"].is_null()
+    }
+
+    /// The content of the first message, which is the system prompt of most requests.
+    pub fn system(&self) -> &str {
+        self.body["messages"]["messages"][0]["content"]
+            .as_str()
+            .unwrap_or_default()
+    }
+
+    /// The content of the last message.
+    pub fn last(&self) -> &str {
+        self.body["messages"]["messages"]
+            .as_array()
+            .and_then(|messages| messages.last())
+            .and_then(|message| message["content"].as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// What the mock gateway responds to a request with.
+#[derive(Debug)]
+pub enum Reply {
+    /// Stream these events, one server-sent event each.
+    Events(Vec<serde_json::Value>),
+    /// Fail with this status, without streaming anything.
+    Status(StatusCode),
+}
+
+impl Reply {
+    /// Stream `text` as the whole response of the model.
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Events(vec![serde_json::json!({ "Ok": text.into() })])
+    }
+
+    /// Stream `call`, as made with [`call`], as the function call of the model.
+    pub fn call(call: &serde_json::Value) -> Self {
+        Self::text(call.to_string())
+    }
+}
+
+impl IntoResponse for Reply {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Events(events) => {
+                let events = events
+                    .into_iter()
+                    .map(|data| Ok::<_, Infallible>(Event::default().data(data.to_string())));
+
+                Sse::new(futures::stream::iter(events)).into_response()
+            }
+            Self::Status(status) => status.into_response(),
+        }
+    }
+}
+
+/// A call of the function `name` with `arguments`, as the model makes it.
+pub fn call(name: &str, arguments: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({ "name": name, "arguments": arguments.to_string() })
+}
+
+/// Function calls that the model makes in order. The last call is repeated once all have been
+/// made.
+pub struct Script {
+    calls: Vec<serde_json::Value>,
+    next: AtomicUsize,
+}
+
+impl Script {
+    pub fn new(calls: Vec<serde_json::Value>) -> Self {
+        assert!(!calls.is_empty(), "a script needs at least one call");
+        Self {
+            calls,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reply with the next call.
+    pub fn next(&self) -> Reply {
+        let i = self.next.fetch_add(1, Ordering::SeqCst);
+        Reply::call(&self.calls[i.min(self.calls.len() - 1)])
+    }
+}
+
+/// A mock gateway, which records every request made to it.
+#[derive(Clone)]
+pub struct Gateway {
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Gateway {
+    /// Serve a gateway that responds to each request with `reply`.
+    pub fn serve(reply: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> Self {
+        Self::serve_with(Router::new(), reply)
+    }
+
+    /// Like [`Gateway::serve`], with `routes` served alongside the gateway, such as pages that
+    /// are fetched by the code under test.
+    pub fn serve_with(
+        routes: Router,
+        reply: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+    ) -> Self {
+        let requests = Arc::<Mutex<Vec<Request>>>::default();
+        let reply = Arc::new(reply);
+
+        let router = routes.route(
+            "/v1/q",
+            post({
+                let requests = requests.clone();
+                move |headers: HeaderMap, Json(body): Json<serde_json::Value>| {
+                    let request = Request { headers, body };
+                    let reply = reply(&request);
+                    requests.lock().unwrap().push(request);
+
+                    async move { reply }
+                }
+            }),
+        );
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(router.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        Self { url, requests }
+    }
+
+    /// A client of this gateway.
+    pub fn client(&self) -> Client {
+        Client::new(&self.url)
+    }
+
+    /// The requests made so far, in order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The requests made so far that match `filter`, in order.
+    pub fn requests_where(&self, filter: impl Fn(&Request) -> bool) -> Vec<Request> {
+        self.requests()
+            .into_iter()
+            .filter(|request| filter(request))
+            .collect()
+    }
+}
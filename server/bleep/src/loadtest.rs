@@ -0,0 +1,680 @@
+//! Load tests of the agent pipeline, against generated fixture repositories.
+//!
+//! A load test indexes a set of generated repositories, then answers a deterministic mix of
+//! queries against them, several at a time. Queries go through the real agent loop, stream their
+//! exchanges to a simulated client, are stored as conversations, and send their analytics events
+//! to a local sink. Only the LLM is faked, by a gateway that makes scripted function calls and
+//! streams its answers token by token, with configurable latencies. Semantic search is turned off,
+//! so that nothing is embedded either.
+//!
+//! Run with `cargo run -p bleep --features loadtest --bin bleep-loadtest`. A small configuration
+//! is run by `cargo test -p bleep --features loadtest`.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    http::StatusCode,
+    response::sse::{Event, Sse},
+    routing::post,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::{
+    agent::{builder, quick},
+    analytics,
+    repo::{Backend, RepoRef, SyncStatus},
+    webserver::answer::conversations::{self, ConversationId, Window},
+    Application, Configuration, Environment,
+};
+
+/// The user that load test conversations are stored for.
+const USER_ID: &str = "bleep-loadtest";
+
+/// The topics of the files in each fixture repository, and of the queries that are asked.
+const TOPICS: &[&str] = &[
+    "auth", "billing", "sessions", "search", "storage", "webhooks",
+];
+
+/// The answer that the fake gateway streams, one word at a time.
+const ANSWER: &str = "The request is handled in [`handle`](src/auth.rs#L3-L12), which \
+    checks the session before anything else. Expired sessions are refreshed once, and then \
+    rejected with a `401`. Every other error is logged and retried with a backoff, so that \
+    transient failures of the store don't reach the user.";
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Options {
+    /// The number of queries to answer
+    #[clap(long, default_value_t = 200)]
+    pub queries: usize,
+
+    /// The most queries that are answered at the same time
+    #[clap(long, default_value_t = 16)]
+    pub concurrency: usize,
+
+    /// The number of fixture repositories to generate
+    #[clap(long, default_value_t = 4)]
+    pub repos: usize,
+
+    /// The relative weight of quick queries in the mix
+    #[clap(long, default_value_t = 2)]
+    pub quick_weight: u32,
+
+    /// The relative weight of full queries in the mix
+    #[clap(long, default_value_t = 3)]
+    pub full_weight: u32,
+
+    /// The relative weight of queries across several repositories in the mix
+    #[clap(long, default_value_t = 1)]
+    pub multi_repo_weight: u32,
+
+    /// How long the fake gateway waits before its first token, in milliseconds
+    #[clap(long, default_value_t = 200)]
+    pub first_token_ms: u64,
+
+    /// How long the fake gateway waits between tokens, in milliseconds
+    #[clap(long, default_value_t = 10)]
+    pub token_ms: u64,
+
+    /// How long the simulated client takes to handle each update, in milliseconds
+    #[clap(long, default_value_t = 1)]
+    pub client_ms: u64,
+
+    /// The number of updates that can be queued for the simulated client
+    #[clap(long, default_value_t = 8)]
+    pub update_buffer: usize,
+
+    /// The seed that the query mix is generated from
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
+}
+
+impl Options {
+    /// A configuration that is small and fast enough to run in CI.
+    pub fn smoke() -> Self {
+        Self {
+            queries: 12,
+            concurrency: 4,
+            repos: 2,
+            quick_weight: 1,
+            full_weight: 1,
+            multi_repo_weight: 1,
+            first_token_ms: 5,
+            token_ms: 1,
+            client_ms: 2,
+            update_buffer: 2,
+            seed: 0,
+        }
+    }
+
+    fn first_token(&self) -> Duration {
+        Duration::from_millis(self.first_token_ms)
+    }
+
+    fn token_interval(&self) -> Duration {
+        Duration::from_millis(self.token_ms)
+    }
+}
+
+/// The kinds of query in the mix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// A query in the quick mode, answered from search snippets.
+    Quick,
+    /// A query in the normal mode, which reads files before it is answered.
+    Full,
+    /// A full query that names several repositories with `repo:` filters.
+    MultiRepo,
+}
+
+/// A generated query, against the fixture repository of index `repo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    pub mode: Mode,
+    pub repo: usize,
+    pub text: String,
+}
+
+/// Generate the queries of `options`, which are the same for the same seed.
+pub fn generate(options: &Options, repo_names: &[String]) -> Vec<Query> {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let weights = [
+        (Mode::Quick, options.quick_weight),
+        (Mode::Full, options.full_weight),
+        (Mode::MultiRepo, options.multi_repo_weight),
+    ];
+    let total = weights.iter().map(|(_, w)| w).sum::<u32>().max(1);
+
+    (0..options.queries)
+        .map(|_| {
+            let mut pick = rng.gen_range(0..total);
+            let mode = weights
+                .iter()
+                .find(|(_, weight)| {
+                    let found = pick < *weight;
+                    pick = pick.saturating_sub(*weight);
+                    found
+                })
+                .map_or(Mode::Full, |(mode, _)| *mode);
+
+            let repo = rng.gen_range(0..repo_names.len().max(1));
+            let topic = TOPICS[rng.gen_range(0..TOPICS.len())];
+            let mut text = format!("How does {topic} handle errors?");
+
+            if mode == Mode::MultiRepo {
+                let other = (repo + 1) % repo_names.len().max(1);
+                let filters = [repo, other]
+                    .map(|i| format!("repo:{}", repo_names[i]))
+                    .join(" ");
+                text = format!("{filters} {text}");
+            }
+
+            Query { mode, repo, text }
+        })
+        .collect()
+}
+
+/// Write `count` fixture repositories to `dir`, returning their paths.
+fn write_repos(dir: &Path, count: usize) -> Result<Vec<PathBuf>> {
+    (0..count)
+        .map(|i| {
+            let root = dir.join(format!("fixture-{i}"));
+            std::fs::create_dir_all(root.join("src"))?;
+            std::fs::write(
+                root.join("README.md"),
+                format!("# fixture-{i}\n\nA service with {}.\n", TOPICS.join(", ")),
+            )?;
+
+            for topic in TOPICS {
+                let code = (0..20)
+                    .map(|n| {
+                        format!(
+                            "pub fn {topic}_{n}(input: &str) -> Result<String, String> {{\n    \
+                             if input.is_empty() {{\n        \
+                             return Err(\"empty {topic} request\".to_owned());\n    }}\n    \
+                             Ok(format!(\"{topic} {n} {{input}}\"))\n}}\n"
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                std::fs::write(root.join("src").join(format!("{topic}.rs")), code)?;
+            }
+
+            Ok(root)
+        })
+        .collect()
+}
+
+/// What the fake gateway and the analytics sink have received.
+#[derive(Default)]
+struct Counters {
+    llm_requests: AtomicUsize,
+    analytics_requests: AtomicUsize,
+    analytics_events: AtomicUsize,
+}
+
+/// Serve a fake LLM gateway, which also accepts analytics events on every other route.
+///
+/// Function call requests are answered by a script: a path search, then a `proc` of its first
+/// result if that is offered, and then an answer. Requests to read files cite their first lines,
+/// and every other request is answered with `ANSWER`. Each response streams its first chunk after
+/// `first_token`, and every other chunk `token_interval` after the one before it.
+fn serve(options: &Options, counters: Arc<Counters>) -> String {
+    let (first_token, token_interval) = (options.first_token(), options.token_interval());
+
+    let gateway = axum::Router::new()
+        .route(
+            "/v1/q",
+            post({
+                let counters = counters.clone();
+                move |axum::Json(body): axum::Json<serde_json::Value>| {
+                    counters.llm_requests.fetch_add(1, Ordering::Relaxed);
+                    let chunks = script(&body);
+
+                    async move {
+                        Sse::new(async_stream::stream! {
+                            tokio::time::sleep(first_token).await;
+                            for (i, chunk) in chunks.into_iter().enumerate() {
+                                if i > 0 {
+                                    tokio::time::sleep(token_interval).await;
+                                }
+
+                                let data = serde_json::json!({ "Ok": chunk });
+                                yield Ok::<_, Infallible>(Event::default().data(data.to_string()));
+                            }
+                        })
+                    }
+                }
+            }),
+        )
+        .fallback(move || {
+            counters.analytics_requests.fetch_add(1, Ordering::Relaxed);
+            async { StatusCode::OK }
+        });
+
+    let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(gateway.into_make_service());
+    let base_url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    base_url
+}
+
+/// The chunks of the scripted response to a gateway request.
+fn script(body: &serde_json::Value) -> Vec<String> {
+    let messages = body["messages"]["messages"].as_array();
+
+    if let Some(functions) = body["functions"]["functions"].as_array() {
+        let offered = |name: &str| functions.iter().any(|f| f["name"] == name);
+        let steps = messages.map_or(0, |m| m.iter().filter(|m| m["role"] == "function").count());
+
+        let (name, arguments) = match steps {
+            0 => ("path", serde_json::json!({ "query": "src" })),
+            1 if offered("proc") => (
+                "proc",
+                serde_json::json!({ "query": "error handling", "paths": [0] }),
+            ),
+            _ => ("none", serde_json::json!({ "paths": [0] })),
+        };
+
+        // Like the real gateway, the name comes first, and the arguments are streamed after it.
+        let arguments = arguments.to_string().chars().collect::<Vec<_>>();
+        return std::iter::once(serde_json::json!({ "name": name, "arguments": "" }))
+            .chain(
+                arguments.chunks(8).map(
+                    |chunk| serde_json::json!({ "arguments": chunk.iter().collect::<String>() }),
+                ),
+            )
+            .map(|delta| delta.to_string())
+            .collect();
+    }
+
+    let reads_file = messages
+        .and_then(|m| m.first())
+        .and_then(|m| m["content"].as_str())
+        .map_or(false, |system| system.contains("relevant_ranges"));
+    if reads_file {
+        return vec![r#"{"relevant_ranges": [[1, 12]]}"#.to_owned()];
+    }
+
+    ANSWER.split_inclusive(' ').map(str::to_owned).collect()
+}
+
+/// The measurements of a single query.
+struct Outcome {
+    mode: Mode,
+    latency: Duration,
+    answered: bool,
+    stored: bool,
+    updates: usize,
+    backpressure_events: usize,
+}
+
+/// Answer `query`, streaming its updates to a client that takes `options.client_ms` to handle
+/// each one, and store its thread.
+async fn run_query(
+    app: Application,
+    repo_ref: RepoRef,
+    query: Query,
+    options: Arc<Options>,
+) -> Result<Outcome> {
+    let (tx, mut rx) = mpsc::channel(options.update_buffer.max(1));
+
+    // The client sees a full channel when updates are sent faster than it can handle them, which
+    // holds up the agent until there is room again.
+    let probe = tx.downgrade();
+    let client_delay = Duration::from_millis(options.client_ms);
+    let client = tokio::spawn(async move {
+        let (mut updates, mut backpressure_events) = (0, 0);
+        loop {
+            let full = probe.upgrade().map_or(false, |tx| tx.capacity() == 0);
+            if rx.recv().await.is_none() {
+                break;
+            }
+
+            updates += 1;
+            backpressure_events += usize::from(full);
+            tokio::time::sleep(client_delay).await;
+        }
+
+        (updates, backpressure_events)
+    });
+
+    let mode = match query.mode {
+        Mode::Quick => quick::Mode::Quick,
+        Mode::Full | Mode::MultiRepo => quick::Mode::Normal,
+    };
+    let thread_id = uuid::Uuid::new_v4();
+    let mut driver = builder::builder(app.clone())
+        .repo(repo_ref.clone())
+        .thread_id(thread_id)
+        .mode(mode)
+        .on_update(tx)
+        .build()?;
+
+    let start = Instant::now();
+    let result = driver.run(&query.text).await;
+    let latency = start.elapsed();
+
+    let agent = driver.into_agent();
+    let window = Window::new(repo_ref, agent.exchanges.clone());
+    let conversation_id = ConversationId {
+        thread_id,
+        user_id: USER_ID.to_owned(),
+    };
+    let stored = conversations::store(&app.sql, conversation_id, window, None).await;
+    drop(agent);
+
+    let (updates, backpressure_events) = client.await?;
+
+    Ok(Outcome {
+        mode: query.mode,
+        latency,
+        answered: result.map_or(false, |exchange| exchange.answer.is_some()),
+        stored: stored.is_ok(),
+        updates,
+        backpressure_events,
+    })
+}
+
+/// The results of a load test.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    /// The number of queries of each mode that were asked.
+    pub queries: BTreeMap<Mode, usize>,
+    /// The number of queries that failed, or ended without an answer.
+    pub failures: usize,
+    /// The number of queries that were stored as conversations.
+    pub stored: usize,
+    pub elapsed: Duration,
+    /// Queries answered per second.
+    pub throughput: f64,
+    /// The median time to answer a query, from the start of the query to its last update.
+    pub p50: Duration,
+    /// The 95th percentile of the time to answer a query.
+    pub p95: Duration,
+    /// The number of updates that the client received.
+    pub updates: usize,
+    /// The number of times that the client found its update channel full.
+    pub backpressure_events: usize,
+    /// The number of requests made to the fake LLM gateway.
+    pub llm_requests: usize,
+    /// The number of analytics events that were tracked, and the requests they were sent in.
+    pub analytics_events: usize,
+    pub analytics_requests: usize,
+    /// The peak resident memory of the process, including indexing, in kB. This is only known on
+    /// Linux.
+    pub peak_memory_kb: Option<u64>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.queries.values().sum::<usize>();
+        let mix = self
+            .queries
+            .iter()
+            .map(|(mode, n)| format!("{mode:?} {n}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        writeln!(f, "queries              {total} ({mix})")?;
+        writeln!(f, "failures             {}", self.failures)?;
+        writeln!(f, "stored               {}", self.stored)?;
+        writeln!(f, "elapsed              {:.2?}", self.elapsed)?;
+        writeln!(f, "throughput           {:.2} queries/s", self.throughput)?;
+        writeln!(f, "latency p50          {:.2?}", self.p50)?;
+        writeln!(f, "latency p95          {:.2?}", self.p95)?;
+        writeln!(f, "updates              {}", self.updates)?;
+        writeln!(f, "backpressure events  {}", self.backpressure_events)?;
+        writeln!(f, "llm requests         {}", self.llm_requests)?;
+        writeln!(
+            f,
+            "analytics events     {} ({} requests)",
+            self.analytics_events, self.analytics_requests
+        )?;
+        match self.peak_memory_kb {
+            Some(kb) => writeln!(f, "peak memory          {:.1} MiB", kb as f64 / 1024.),
+            None => writeln!(f, "peak memory          unknown"),
+        }
+    }
+}
+
+/// The `p`th percentile of `sorted`, by the nearest rank.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let rank = ((p / 100.) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// The peak resident memory of this process, in kB.
+fn peak_memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Run a load test with `options`, in a scratch directory that is removed afterwards.
+pub async fn run(options: Options) -> Result<Report> {
+    let dir = std::env::temp_dir().join(format!("bleep-loadtest-{}", uuid::Uuid::new_v4()));
+    let report = run_in(&dir, options).await;
+    _ = std::fs::remove_dir_all(&dir);
+    report
+}
+
+async fn run_in(dir: &Path, options: Options) -> Result<Report> {
+    if options.repos == 0 {
+        bail!("a load test needs at least one repository");
+    }
+
+    let roots = write_repos(&dir.join("repos"), options.repos)?;
+    let counters = Arc::new(Counters::default());
+    let url = serve(&options, counters.clone());
+
+    let config: Configuration = serde_json::from_value(serde_json::json!({
+        "index_dir": dir.join("index"),
+        "answer_api_url": url,
+        "analytics_key": USER_ID,
+        "analytics_data_plane": url,
+        "disable_background": true,
+    }))
+    .context("invalid load test configuration")?;
+
+    let hub_options = analytics::HubOptions {
+        event_filter: Some(Arc::new({
+            let counters = counters.clone();
+            move |event: analytics::QueryEvent| {
+                counters.analytics_events.fetch_add(1, Ordering::Relaxed);
+                Some(event)
+            }
+        })),
+        package_metadata: None,
+    };
+    let app =
+        Application::initialize(Environment::insecure_local(), config, None, hub_options).await?;
+
+    let mut repo_refs = Vec::new();
+    for root in roots {
+        let root = root.canonicalize()?;
+        let repo_ref = RepoRef::new(Backend::LocalDir, &root.to_string_lossy())?;
+        match app
+            .write_index()
+            .block_until_synced(repo_ref.clone())
+            .await?
+        {
+            SyncStatus::Done => repo_refs.push(repo_ref),
+            status => bail!("failed to index {}: {status:?}", root.display()),
+        }
+    }
+
+    let repo_names = repo_refs
+        .iter()
+        .map(RepoRef::display_name)
+        .collect::<Vec<_>>();
+    let queries = generate(&options, &repo_names);
+
+    let options = Arc::new(options);
+    let permits = Arc::new(Semaphore::new(options.concurrency.max(1)));
+    let start = Instant::now();
+
+    let tasks = queries
+        .into_iter()
+        .map(|query| {
+            let (app, options, permits) = (app.clone(), options.clone(), permits.clone());
+            let repo_ref = repo_refs[query.repo].clone();
+            tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await?;
+                run_query(app, repo_ref, query, options).await
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut outcomes = Vec::new();
+    for task in tasks {
+        outcomes.push(task.await??);
+    }
+
+    let elapsed = start.elapsed();
+
+    let mut latencies = outcomes.iter().map(|o| o.latency).collect::<Vec<_>>();
+    latencies.sort();
+
+    let mut modes = BTreeMap::new();
+    for outcome in &outcomes {
+        *modes.entry(outcome.mode).or_default() += 1;
+    }
+
+    Ok(Report {
+        queries: modes,
+        failures: outcomes.iter().filter(|o| !o.answered).count(),
+        stored: outcomes.iter().filter(|o| o.stored).count(),
+        elapsed,
+        throughput: outcomes.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        p50: percentile(&latencies, 50.),
+        p95: percentile(&latencies, 95.),
+        updates: outcomes.iter().map(|o| o.updates).sum(),
+        backpressure_events: outcomes.iter().map(|o| o.backpressure_events).sum(),
+        llm_requests: counters.llm_requests.load(Ordering::Relaxed),
+        analytics_events: counters.analytics_events.load(Ordering::Relaxed),
+        analytics_requests: counters.analytics_requests.load(Ordering::Relaxed),
+        peak_memory_kb: peak_memory_kb(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn names() -> Vec<String> {
+        vec!["fixture-0".to_owned(), "fixture-1".to_owned()]
+    }
+
+    #[test]
+    fn test_generate() {
+        let options = Options {
+            queries: 300,
+            ..Options::smoke()
+        };
+
+        // The same seed makes the same queries, and another seed makes others.
+        let queries = generate(&options, &names());
+        assert_eq!(queries, generate(&options, &names()));
+        let reseeded = Options {
+            seed: 1,
+            ..options.clone()
+        };
+        assert_ne!(queries, generate(&reseeded, &names()));
+
+        for mode in [Mode::Quick, Mode::Full, Mode::MultiRepo] {
+            assert!(queries.iter().any(|q| q.mode == mode));
+        }
+
+        for query in &queries {
+            assert!(query.repo < 2);
+            assert_eq!(
+                query.text.contains("repo:fixture-0 ") && query.text.contains("repo:fixture-1 "),
+                query.mode == Mode::MultiRepo
+            );
+        }
+
+        // Modes without weight are left out of the mix.
+        let quick_only = Options {
+            full_weight: 0,
+            multi_repo_weight: 0,
+            ..options
+        };
+        assert!(generate(&quick_only, &names())
+            .iter()
+            .all(|q| q.mode == Mode::Quick));
+    }
+
+    #[test]
+    fn test_script() {
+        let functions = serde_json::json!({
+            "functions": [{ "name": "path" }, { "name": "proc" }, { "name": "none" }]
+        });
+        let called = |body: serde_json::Value| {
+            script(&body)
+                .iter()
+                .map(|chunk| serde_json::from_str::<serde_json::Value>(chunk).unwrap())
+                .fold((String::new(), String::new()), |(name, args), delta| {
+                    (
+                        name + delta["name"].as_str().unwrap_or_default(),
+                        args + delta["arguments"].as_str().unwrap(),
+                    )
+                })
+        };
+
+        let (name, arguments) = called(serde_json::json!({
+            "messages": { "messages": [{ "role": "system", "content": "" }] },
+            "functions": functions,
+        }));
+        assert_eq!(name, "path");
+        assert_eq!(arguments, r#"{"query":"src"}"#);
+
+        let (name, _) = called(serde_json::json!({
+            "messages": { "messages": [{ "role": "function", "name": "path", "content": "" }] },
+            "functions": functions,
+        }));
+        assert_eq!(name, "proc");
+
+        let answer = script(&serde_json::json!({
+            "messages": { "messages": [{ "role": "system", "content": "Answer the question" }] },
+        }));
+        assert!(answer.len() > 10);
+        assert_eq!(answer.concat(), ANSWER);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted = (1..=20).map(Duration::from_millis).collect::<Vec<_>>();
+        assert_eq!(percentile(&sorted, 50.), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 95.), Duration::from_millis(19));
+        assert_eq!(percentile(&sorted, 100.), Duration::from_millis(20));
+        assert_eq!(percentile(&[], 95.), Duration::ZERO);
+    }
+}
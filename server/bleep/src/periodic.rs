@@ -1,5 +1,7 @@
 mod logrotate;
+mod pull_requests;
 mod remotes;
 
 pub(crate) use logrotate::*;
+pub(crate) use pull_requests::*;
 pub(crate) use remotes::*;
@@ -13,6 +13,7 @@ use crate::{
 
 pub(crate) async fn log_and_branch_rotate(app: crate::Application) {
     let log = crate::db::QueryLog::new(&app.sql);
+    let history = crate::db::QueryHistory::new(&app.sql);
     loop {
         let jitter = thread_rng().sample(distributions::Uniform::new(100, 300));
         tokio::time::sleep(
@@ -31,6 +32,11 @@ pub(crate) async fn log_and_branch_rotate(app: crate::Application) {
         if let Err(err) = log.prune(cutoff).await {
             error!(?err, "failed to prune old log entries");
         };
+
+        let retention = Duration::days(app.config.query_history_retention_days as i64);
+        if let Err(err) = history.prune((Utc::now() - retention).timestamp()).await {
+            error!(?err, "failed to prune expired query history");
+        };
     }
 }
 
@@ -84,7 +90,11 @@ fn update_branch_filters(
         };
 
         repo_pool.update(&reporef, |_, v| {
-            let effective = std::mem::take(branches).into_iter().collect();
+            // Branches that are used in queries are indexed alongside the ones that are
+            // configured, which picks up new branches that match the configured patterns.
+            let mut effective = std::mem::take(branches).into_iter().collect::<Vec<_>>();
+            effective.extend(v.indexed_branches(&reporef));
+
             let new_filter = Some(BranchFilter::Select(effective));
             if new_filter != v.branch_filter {
                 v.branch_filter = new_filter;
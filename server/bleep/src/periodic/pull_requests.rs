@@ -0,0 +1,366 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use reqwest::{
+    header::{ACCEPT, ETAG, IF_NONE_MATCH, USER_AGENT},
+    StatusCode,
+};
+use secrecy::{ExposeSecret, SecretString};
+use sqlx::SqlitePool;
+use tracing::{debug, warn};
+
+use crate::{
+    db::{PullRequest, PullRequests},
+    repo::{Backend, RepoRef},
+    Application,
+};
+
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// How often the open pull requests of every GitHub repository are synced.
+const POLL_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// The number of pull requests, and of changed files per pull request, that are fetched.
+///
+/// Only the first page of each listing is read, to keep a sync to a handful of requests.
+const PAGE_SIZE: &str = "100";
+
+/// Periodically sync the open pull requests of GitHub repositories, so that the agent can tell
+/// what is already being worked on.
+pub(crate) async fn sync_pull_requests(app: Application) {
+    let mut interval = tokio::time::interval(POLL_PERIOD);
+
+    loop {
+        interval.tick().await;
+
+        let Some(github) = app.credentials.github() else {
+            continue;
+        };
+
+        let client = PullRequestClient::new(GITHUB_API_URL, github.auth.api_token().clone());
+
+        let mut repos = vec![];
+        app.repo_pool
+            .scan_async(|k, _| {
+                if k.backend() == Backend::Github {
+                    repos.push(k.clone());
+                }
+            })
+            .await;
+
+        for repo_ref in repos {
+            match client.sync_repo(&app.sql, &repo_ref).await {
+                Ok(changed) => debug!(%repo_ref, changed, "synced pull requests"),
+                Err(err) if err.is::<RateLimited>() => {
+                    warn!(%repo_ref, "GitHub rate limit reached, postponing pull request sync");
+                    break;
+                }
+                Err(err) => warn!(?err, %repo_ref, "failed to sync pull requests"),
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("GitHub API rate limit exceeded")]
+struct RateLimited;
+
+/// A pull request, as listed by the GitHub API.
+#[derive(serde::Deserialize)]
+struct GithubPull {
+    number: i64,
+    title: String,
+    body: Option<String>,
+    user: GithubUser,
+    html_url: String,
+    updated_at: String,
+}
+
+#[derive(serde::Deserialize)]
+struct GithubUser {
+    login: String,
+}
+
+#[derive(serde::Deserialize)]
+struct GithubFile {
+    filename: String,
+}
+
+enum Listing {
+    /// The open pull requests have not changed since the listing with the given ETag.
+    Unchanged,
+    Changed {
+        etag: Option<String>,
+        pulls: Vec<GithubPull>,
+    },
+}
+
+struct PullRequestClient {
+    http: reqwest::Client,
+    base_url: String,
+    token: SecretString,
+}
+
+impl PullRequestClient {
+    fn new(base_url: &str, token: SecretString) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            token,
+        }
+    }
+
+    /// Sync the open pull requests of a repository, returning whether they changed.
+    ///
+    /// Listings are conditional on the ETag of the last sync, and GitHub does not count unchanged
+    /// listings against the rate limit. The changed files of a pull request are only fetched again
+    /// if it was updated.
+    async fn sync_repo(&self, db: &SqlitePool, repo_ref: &RepoRef) -> Result<bool> {
+        let store = PullRequests::new(db);
+        let key = repo_ref.to_string();
+
+        let etag = store.etag(&key).await?;
+        let (etag, pulls) = match self.list_open(repo_ref.name(), etag.as_deref()).await? {
+            Listing::Unchanged => return Ok(false),
+            Listing::Changed { etag, pulls } => (etag, pulls),
+        };
+
+        let known = store
+            .for_repo(&key)
+            .await?
+            .into_iter()
+            .map(|pr| (pr.number, pr))
+            .collect::<HashMap<_, _>>();
+
+        let mut pull_requests = Vec::with_capacity(pulls.len());
+        for pull in pulls {
+            let changed_files = match known.get(&pull.number) {
+                Some(pr) if pr.updated_at == pull.updated_at => pr.changed_files.clone(),
+                _ => self.changed_files(repo_ref.name(), pull.number).await?,
+            };
+
+            pull_requests.push(PullRequest {
+                number: pull.number,
+                title: pull.title,
+                body: pull.body.unwrap_or_default(),
+                author: pull.user.login,
+                url: pull.html_url,
+                changed_files,
+                updated_at: pull.updated_at,
+            });
+        }
+
+        store.replace(&key, etag.as_deref(), &pull_requests).await?;
+
+        Ok(true)
+    }
+
+    async fn list_open(&self, repo: &str, etag: Option<&str>) -> Result<Listing> {
+        let mut request = self
+            .get(&format!("/repos/{repo}/pulls"))
+            .query(&[("state", "open"), ("per_page", PAGE_SIZE)]);
+
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Listing::Unchanged);
+        }
+
+        let response = check_rate_limit(response)?.error_for_status()?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned);
+
+        Ok(Listing::Changed {
+            etag,
+            pulls: response.json().await?,
+        })
+    }
+
+    async fn changed_files(&self, repo: &str, number: i64) -> Result<Vec<String>> {
+        let response = self
+            .get(&format!("/repos/{repo}/pulls/{number}/files"))
+            .query(&[("per_page", PAGE_SIZE)])
+            .send()
+            .await?;
+
+        let files = check_rate_limit(response)?
+            .error_for_status()?
+            .json::<Vec<GithubFile>>()
+            .await?;
+
+        Ok(files.into_iter().map(|f| f.filename).collect())
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .get(format!("{}{path}", self.base_url))
+            .bearer_auth(self.token.expose_secret())
+            .header(ACCEPT, "application/vnd.github+json")
+            .header(USER_AGENT, "bloop")
+    }
+}
+
+/// GitHub signals an exhausted rate limit with a 429, or a 403 with no remaining requests.
+fn check_rate_limit(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    let exhausted = response
+        .headers()
+        .get("x-ratelimit-remaining")
+        .map_or(false, |v| v == "0");
+
+    if status == StatusCode::TOO_MANY_REQUESTS || (status == StatusCode::FORBIDDEN && exhausted) {
+        Err(RateLimited.into())
+    } else {
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use axum::{
+        extract::{Path, State},
+        http::HeaderMap,
+        response::IntoResponse,
+        routing::get,
+        Json,
+    };
+    use pretty_assertions::assert_eq;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// A GitHub API with two open pull requests, of which #2 is updated with every revision.
+    #[derive(Default)]
+    struct MockGithub {
+        revision: AtomicUsize,
+        file_requests: AtomicUsize,
+    }
+
+    async fn list_pulls(
+        State(mock): State<Arc<MockGithub>>,
+        headers: HeaderMap,
+    ) -> impl IntoResponse {
+        let revision = mock.revision.load(Ordering::SeqCst);
+        let etag = format!("\"rev-{revision}\"");
+
+        if headers
+            .get(IF_NONE_MATCH)
+            .map_or(false, |v| v == etag.as_str())
+        {
+            return StatusCode::NOT_MODIFIED.into_response();
+        }
+
+        let pulls = serde_json::json!([
+            {
+                "number": 2,
+                "title": "Rate limit the answer endpoint",
+                "body": null,
+                "user": { "login": "alice" },
+                "html_url": "https://github.com/bloopai/bloop/pull/2",
+                "updated_at": format!("2026-10-0{}T00:00:00Z", revision + 1),
+            },
+            {
+                "number": 1,
+                "title": "Fix typo",
+                "body": "Fixes the README.",
+                "user": { "login": "bob" },
+                "html_url": "https://github.com/bloopai/bloop/pull/1",
+                "updated_at": "2026-09-01T00:00:00Z",
+            },
+        ]);
+
+        ([(ETAG, etag)], Json(pulls)).into_response()
+    }
+
+    async fn list_files(
+        State(mock): State<Arc<MockGithub>>,
+        Path(number): Path<i64>,
+    ) -> Json<serde_json::Value> {
+        mock.file_requests.fetch_add(1, Ordering::SeqCst);
+
+        let files = match number {
+            2 => serde_json::json!([
+                { "filename": "server/bleep/src/webserver/answer.rs" },
+                { "filename": "server/bleep/src/webserver/middleware.rs" },
+            ]),
+            _ => serde_json::json!([{ "filename": "README.md" }]),
+        };
+
+        Json(files)
+    }
+
+    #[tokio::test]
+    async fn test_sync_skips_unchanged() {
+        let mock = Arc::new(MockGithub::default());
+        let github = axum::Router::new()
+            .route("/repos/bloopai/bloop/pulls", get(list_pulls))
+            .route("/repos/bloopai/bloop/pulls/:number/files", get(list_files))
+            .with_state(mock.clone());
+
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(github.into_make_service());
+        let base_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let tmpdir = TempDir::new("test-pull-requests").unwrap();
+        let db = crate::db::connect(&tmpdir.path().to_string_lossy())
+            .await
+            .unwrap();
+
+        let client = PullRequestClient::new(&base_url, "token".to_owned().into());
+        let repo_ref = RepoRef::new(Backend::Github, "bloopai/bloop").unwrap();
+
+        assert!(client.sync_repo(&db, &repo_ref).await.unwrap());
+        assert_eq!(mock.file_requests.load(Ordering::SeqCst), 2);
+
+        let stored = PullRequests::new(&db)
+            .for_repo(&repo_ref.to_string())
+            .await
+            .unwrap();
+        assert_eq!(
+            stored.iter().map(|pr| pr.number).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        assert_eq!(stored[0].author, "alice");
+        assert_eq!(stored[0].body, "");
+        assert_eq!(
+            stored[0].changed_files,
+            [
+                "server/bleep/src/webserver/answer.rs",
+                "server/bleep/src/webserver/middleware.rs"
+            ]
+        );
+
+        // Nothing changed, so the listing is not modified and no files are fetched.
+        assert!(!client.sync_repo(&db, &repo_ref).await.unwrap());
+        assert_eq!(mock.file_requests.load(Ordering::SeqCst), 2);
+
+        // Only the files of the updated pull request are fetched again.
+        mock.revision.fetch_add(1, Ordering::SeqCst);
+        assert!(client.sync_repo(&db, &repo_ref).await.unwrap());
+        assert_eq!(mock.file_requests.load(Ordering::SeqCst), 3);
+
+        let stored = PullRequests::new(&db)
+            .for_repo(&repo_ref.to_string())
+            .await
+            .unwrap();
+        assert_eq!(stored[0].updated_at, "2026-10-02T00:00:00Z");
+        assert_eq!(stored[1].changed_files, ["README.md"]);
+
+        db.close().await;
+    }
+}
@@ -244,10 +244,14 @@ impl Poller {
         let (tx, rx) = flume::bounded(10);
 
         let mut _debouncer = None;
-        if app.config.disable_fsevents.not() && reporef.backend() == Backend::Local {
+        if app.config.disable_fsevents.not() && reporef.is_local() {
+            // Plain directories have no `.git` to watch, so any change to their files counts.
             let git_path = app
                 .repo_pool
-                .read(reporef, |_, v| v.disk_path.join(".git"))?;
+                .read(reporef, |_, v| match reporef.backend() {
+                    Backend::LocalDir => v.disk_path.clone(),
+                    _ => v.disk_path.join(".git"),
+                })?;
 
             let mut debouncer = debounced_events(tx);
             debouncer
@@ -312,9 +316,12 @@ impl Poller {
     }
 }
 
-fn check_repo(app: &Application, reporef: &RepoRef) -> Option<(u64, SyncStatus)> {
+fn check_repo(app: &Application, reporef: &RepoRef) -> Option<((u64, Option<String>), SyncStatus)> {
     app.repo_pool.read(reporef, |_, repo| {
-        (repo.last_commit_unix_secs, repo.sync_status.clone())
+        (
+            (repo.last_commit_unix_secs, repo.revision.clone()),
+            repo.sync_status.clone(),
+        )
     })
 }
 
@@ -10,6 +10,7 @@ use crate::{
         reader::{base_name, ContentReader, FileReader, OpenReader, RepoReader},
         DocumentRead, File, Indexable, Indexer, Indexes, Repo,
     },
+    repo::RepoRef,
     snippet::{HighlightedString, SnippedFile, Snipper},
 };
 
@@ -84,6 +85,47 @@ pub struct QueryResponse {
 
 impl crate::webserver::ApiResponse for QueryResponse {}
 
+impl QueryResponse {
+    /// Remove the results from the repositories in `repos`, as if they had never been indexed.
+    pub fn without_repos(mut self, repos: &[RepoRef]) -> Self {
+        if repos.is_empty() {
+            return self;
+        }
+
+        let repo_refs = repos.iter().map(RepoRef::to_string).collect::<HashSet<_>>();
+        let repo_names = repos
+            .iter()
+            .map(RepoRef::indexed_name)
+            .collect::<HashSet<_>>();
+
+        self.data.retain(|result| {
+            result
+                .repo_ref()
+                .map_or(true, |repo_ref| !repo_refs.contains(repo_ref))
+        });
+        self.count = self.data.len();
+
+        let removed = self
+            .stats
+            .repo
+            .iter()
+            .filter(|(name, _)| repo_names.contains(*name))
+            .map(|(_, count)| count)
+            .sum::<usize>();
+        self.stats.repo.retain(|name, _| !repo_names.contains(name));
+
+        if let Some(total_count) = self.metadata.total_count {
+            self.metadata = PagingMetadata::new(
+                self.metadata.page,
+                self.metadata.page_size,
+                Some(total_count.saturating_sub(removed)),
+            );
+        }
+
+        self
+    }
+}
+
 /// Metadata pertaining to the query response, such as paging info
 #[derive(Default, Serialize)]
 #[non_exhaustive]
@@ -135,6 +177,20 @@ pub enum QueryResult {
     Lang(String),
 }
 
+impl QueryResult {
+    /// The repository of this result, unless it is an autocompletion.
+    fn repo_ref(&self) -> Option<&str> {
+        match self {
+            Self::Snippets(file) => Some(&file.repo_ref),
+            Self::RepositoryResult(repo) => Some(&repo.repo_ref),
+            Self::FileResult(file) => Some(&file.repo_ref),
+            Self::File(file) => Some(&file.repo_ref),
+            Self::Directory(dir) => Some(&dir.repo_ref),
+            Self::Flag(_) | Self::Lang(_) => None,
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct RepositoryResultData {
     name: HighlightedString,
@@ -815,6 +871,7 @@ mod tests {
                     line_range: 49..51,
                     highlights: vec![51..56],
                     symbols: vec![],
+                    symbol_path: None,
                 }],
             })],
             metadata: PagingMetadata {
@@ -829,4 +886,48 @@ mod tests {
 
         assert_eq!(expected, observed);
     }
+
+    #[test]
+    fn without_repos() {
+        let snippets = |repo: &str| {
+            QueryResult::Snippets(SnippedFile {
+                relative_path: "src/lib.rs".into(),
+                repo_name: format!("github.com/acme/{repo}"),
+                repo_ref: format!("github.com/acme/{repo}"),
+                lang: Some("Rust".into()),
+                snippets: vec![],
+            })
+        };
+
+        let response = QueryResponse {
+            count: 3,
+            data: vec![snippets("payments"), snippets("web"), snippets("payments")],
+            metadata: PagingMetadata::new(0, 3, Some(5)),
+            stats: ResultStats {
+                repo: HashMap::from([
+                    ("github.com/acme/payments".into(), 4),
+                    ("github.com/acme/web".into(), 1),
+                ]),
+                lang: HashMap::from([("Rust".into(), 5)]),
+            },
+        };
+
+        let response = response.without_repos(&[RepoRef::from("github.com/acme/payments")]);
+
+        assert_eq!(response.count, 1);
+        assert_eq!(
+            response
+                .data
+                .iter()
+                .filter_map(QueryResult::repo_ref)
+                .collect::<Vec<_>>(),
+            ["github.com/acme/web"]
+        );
+        assert_eq!(
+            response.stats.repo,
+            HashMap::from([("github.com/acme/web".into(), 1)])
+        );
+        assert_eq!(response.metadata.total_count, Some(1));
+        assert_eq!(response.metadata.page_count, Some(1));
+    }
 }
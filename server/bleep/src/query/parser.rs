@@ -46,6 +46,19 @@ pub struct SemanticQuery<'a> {
     pub langs: HashSet<Cow<'a, str>>,
     pub branch: HashSet<Literal<'a>>,
     pub target: Option<Literal<'a>>,
+
+    /// Repositories that results must not be in, written like `-repo:foo`.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub exclude_repos: HashSet<Literal<'a>>,
+    /// Paths that results must not be in, written like `-path:tests`.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub exclude_paths: HashSet<Literal<'a>>,
+    /// Languages that results must not be written in, written like `-lang:go`.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub exclude_langs: HashSet<Cow<'a, str>>,
+    /// Quoted phrases, which results must contain exactly. They are also part of the target.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub phrases: HashSet<Literal<'a>>,
 }
 
 impl<'a> SemanticQuery<'a> {
@@ -69,6 +82,22 @@ impl<'a> SemanticQuery<'a> {
         self.branch.iter().filter_map(|t| t.as_plain())
     }
 
+    pub fn exclude_repos(&'a self) -> impl Iterator<Item = Cow<'a, str>> {
+        self.exclude_repos.iter().filter_map(|t| t.as_plain())
+    }
+
+    pub fn exclude_paths(&'a self) -> impl Iterator<Item = Cow<'a, str>> {
+        self.exclude_paths.iter().filter_map(|t| t.as_plain())
+    }
+
+    pub fn exclude_langs(&'a self) -> impl Iterator<Item = Cow<'a, str>> {
+        self.exclude_langs.iter().cloned()
+    }
+
+    pub fn phrases(&'a self) -> impl Iterator<Item = Cow<'a, str>> {
+        self.phrases.iter().filter_map(|t| t.as_plain())
+    }
+
     // TODO (@calyptobai): This is a quirk of the current conversation logic. We take only the
     // first branch because the UX operates on a single "current" branch. We can likely update
     // `SemanticQuery` to remove multiple branches altogether.
@@ -76,6 +105,41 @@ impl<'a> SemanticQuery<'a> {
         self.branch.iter().next().map(|t| t.clone().unwrap())
     }
 
+    /// Use `raw`, the input this query was parsed from, as its target if the parser found none.
+    ///
+    /// Queries that only consist of filters, like `lang:rust repo:foo`, have no target otherwise.
+    /// Blank input is left without a target.
+    pub fn with_raw_target(mut self, raw: &'a str) -> Self {
+        if self.target.is_none() && !raw.trim().is_empty() {
+            self.target = Some(Literal::Plain(raw.trim().into()));
+        }
+
+        self
+    }
+
+    /// The filters of this query, rendered as they are written, like `lang:rust`, in a stable
+    /// order.
+    pub fn filters(&self) -> Vec<String> {
+        let plain = |literals: &HashSet<Literal<'a>>, name: &str| {
+            literals
+                .iter()
+                .filter_map(|l| l.as_plain())
+                .map(|l| format!("{name}:{l}"))
+                .collect::<Vec<_>>()
+        };
+
+        let mut filters = plain(&self.repos, "repo");
+        filters.extend(plain(&self.paths, "path"));
+        filters.extend(self.langs.iter().map(|l| format!("lang:{l}")));
+        filters.extend(plain(&self.branch, "branch"));
+        filters.extend(plain(&self.exclude_repos, "-repo"));
+        filters.extend(plain(&self.exclude_paths, "-path"));
+        filters.extend(self.exclude_langs.iter().map(|l| format!("-lang:{l}")));
+
+        filters.sort();
+        filters
+    }
+
     pub fn from_str(query: String, repo_ref: String) -> Self {
         Self {
             target: Some(Literal::Plain(Cow::Owned(query))),
@@ -95,6 +159,22 @@ impl<'a> SemanticQuery<'a> {
                 .collect(),
             branch: self.branch.into_iter().map(Literal::into_owned).collect(),
             target: self.target.map(Literal::into_owned),
+            exclude_repos: self
+                .exclude_repos
+                .into_iter()
+                .map(Literal::into_owned)
+                .collect(),
+            exclude_paths: self
+                .exclude_paths
+                .into_iter()
+                .map(Literal::into_owned)
+                .collect(),
+            exclude_langs: self
+                .exclude_langs
+                .into_iter()
+                .map(|c| c.into_owned().into())
+                .collect(),
+            phrases: self.phrases.into_iter().map(Literal::into_owned).collect(),
         }
     }
 }
@@ -517,10 +597,42 @@ pub fn parse_nl(query: &str) -> Result<ParsedQuery<'_>, ParseError> {
     let mut paths = HashSet::new();
     let mut langs = HashSet::new();
     let mut branch = HashSet::new();
+    let mut exclude_repos = HashSet::new();
+    let mut exclude_paths = HashSet::new();
+    let mut exclude_langs = HashSet::new();
+    let mut phrases = HashSet::new();
     let mut target: Option<Literal> = None;
     let mut force_parsing_as = None;
     for pair in pairs {
         match pair.as_rule() {
+            Rule::negated => {
+                let filter = pair.into_inner().next().unwrap();
+                match filter.as_rule() {
+                    Rule::repo => {
+                        let item = Literal::from(filter.into_inner().next().unwrap());
+                        let _ = exclude_repos.insert(item);
+                    }
+                    Rule::path => {
+                        let item = Literal::from(filter.into_inner().next().unwrap());
+                        let _ = exclude_paths.insert(item);
+                    }
+                    Rule::lang => {
+                        let item =
+                            super::languages::parse_alias(filter.into_inner().as_str().into());
+                        let _ = exclude_langs.insert(item);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Rule::phrase => {
+                let rhs = Literal::from(pair.into_inner().next().unwrap());
+                let _ = phrases.insert(rhs.clone());
+                if let Some(t) = target {
+                    target = t.join_as_plain(rhs);
+                } else {
+                    target = Some(rhs);
+                }
+            }
             Rule::repo => {
                 let item = Literal::from(pair.into_inner().next().unwrap());
                 let _ = repos.insert(item);
@@ -569,6 +681,10 @@ pub fn parse_nl(query: &str) -> Result<ParsedQuery<'_>, ParseError> {
             langs,
             branch,
             target,
+            exclude_repos,
+            exclude_paths,
+            exclude_langs,
+            phrases,
         })),
     }
 }
@@ -1176,7 +1292,8 @@ mod tests {
                 langs: ["tsx".into()].into(),
                 repos: [Literal::Plain("bloop".into())].into(),
                 paths: [].into(),
-                branch: [].into()
+                branch: [].into(),
+                ..Default::default()
             }),
         );
     }
@@ -1184,7 +1301,8 @@ mod tests {
     #[test]
     fn nl_parse_dedup_similar_filters() {
         let ParsedQuery::Semantic(q) =
-            parse_nl("what is background color? lang:tsx repo:bloop repo:bloop").unwrap() else {
+            parse_nl("what is background color? lang:tsx repo:bloop repo:bloop").unwrap()
+        else {
             panic!("down with this sorta thing")
         };
         assert_eq!(q.repos().count(), 1);
@@ -1206,6 +1324,7 @@ mod tests {
                 ]
                 .into(),
                 paths: [Literal::Plain("server/bleep".into())].into(),
+                ..Default::default()
             })
         );
     }
@@ -1223,6 +1342,7 @@ mod tests {
                 repos: [Literal::Plain("bloop".into())].into(),
                 paths: [].into(),
                 branch: [].into(),
+                ..Default::default()
             })
         );
 
@@ -1238,6 +1358,120 @@ mod tests {
         );
     }
 
+    #[test]
+    fn nl_parse_without_target() {
+        let parse = |q| parse_nl(q).unwrap().into_semantic().unwrap();
+
+        // Filter-only queries fall back to the whole input as their target.
+        let q = parse("lang:rust repo:foo");
+        assert_eq!(q.target, None);
+        assert_eq!(q.filters(), ["lang:rust", "repo:foo"]);
+        let q = q.with_raw_target("lang:rust repo:foo");
+        assert_eq!(q.target(), Some("lang:rust repo:foo".into()));
+        assert_eq!(q.filters(), ["lang:rust", "repo:foo"]);
+
+        // Emoji are plain text, which the parser takes as the target.
+        let q = parse("🦀 🔥");
+        assert_eq!(q.target(), Some("🦀 🔥".into()));
+        assert_eq!(q.clone().with_raw_target("🦀 🔥"), q);
+
+        // Blank input has nothing to fall back to.
+        for blank in ["", "   ", "\t\n"] {
+            let q = parse(blank).with_raw_target(blank);
+            assert_eq!(q.target, None);
+            assert!(q.filters().is_empty());
+        }
+
+        // Targets found by the parser are kept.
+        let q = parse("what is this? lang:rust").with_raw_target("what is this? lang:rust");
+        assert_eq!(q.target(), Some("what is this?".into()));
+    }
+
+    #[test]
+    fn nl_parse_negated_filters() {
+        assert_eq!(
+            parse_nl(r#""connection pool" -path:tests lang:go"#).unwrap(),
+            ParsedQuery::Semantic(SemanticQuery {
+                target: Some(Literal::Plain("connection pool".into())),
+                langs: ["go".into()].into(),
+                exclude_paths: [Literal::Plain("tests".into())].into(),
+                phrases: [Literal::Plain("connection pool".into())].into(),
+                ..Default::default()
+            })
+        );
+
+        // Adjacent negations are separate filters, and languages are normalised like `lang:`.
+        let q = parse_nl("how are retries done -path:tests -path:benches -lang:py -repo:bloop")
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        assert_eq!(q.target(), Some("how are retries done".into()));
+        assert_eq!(
+            q.filters(),
+            [
+                "-lang:python",
+                "-path:benches",
+                "-path:tests",
+                "-repo:bloop"
+            ]
+        );
+        assert!(q.repos.is_empty() && q.paths.is_empty() && q.langs.is_empty());
+
+        // A dash inside a word, or before text that isn't a filter, is part of the target.
+        let q = parse_nl("what is a no-op -foo x-path:bar")
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        assert_eq!(q.target(), Some("what is a no-op -foo x-path:bar".into()));
+        assert!(q.filters().is_empty());
+    }
+
+    #[test]
+    fn nl_parse_negated_filters_with_branch() {
+        let q = parse_nl("where is auth handled branch:main -path:tests")
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        assert_eq!(q.first_branch(), Some("main".into()));
+        assert_eq!(q.filters(), ["-path:tests", "branch:main"]);
+
+        // Branches can't be negated, so the negation is left in the target.
+        let q = parse_nl("where is auth handled -branch:main")
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        assert!(q.branch.is_empty());
+        assert_eq!(
+            q.target(),
+            Some("where is auth handled -branch:main".into())
+        );
+    }
+
+    #[test]
+    fn nl_parse_phrases() {
+        let parse = |q| parse_nl(q).unwrap().into_semantic().unwrap();
+
+        // Phrases are part of the target, in the order they are written.
+        let q = parse(r#"where is "retry_after" "max backoff" set"#);
+        assert_eq!(
+            q.target(),
+            Some("where is retry_after max backoff set".into())
+        );
+        let mut phrases = q.phrases().collect::<Vec<_>>();
+        phrases.sort();
+        assert_eq!(phrases, ["max backoff", "retry_after"]);
+
+        // Quotes can be escaped inside a phrase.
+        let q = parse(r#"who logs "say \"hi\"" lang:rust"#);
+        assert_eq!(q.phrases().collect::<Vec<_>>(), [r#"say "hi""#]);
+        assert_eq!(q.target(), Some(r#"who logs say "hi""#.into()));
+
+        // An unterminated quote is plain text.
+        let q = parse(r#"what does "foo do"#);
+        assert!(q.phrases.is_empty());
+        assert_eq!(q.target(), Some(r#"what does "foo do"#.into()));
+    }
+
     // NL queries should permit arbitrary text in the `target` field, such as `(` and `|`
     #[test]
     fn nl_parse_arbitrary_text() {
@@ -164,6 +164,14 @@ impl Auth {
         }
     }
 
+    /// The token to authenticate GitHub API requests with.
+    pub(crate) fn api_token(&self) -> &SecretString {
+        match self {
+            Auth::OAuth { access_token, .. } => access_token,
+            Auth::App { token, .. } => token,
+        }
+    }
+
     fn git_cred(&self) -> GitCreds {
         use Auth::*;
         match self {
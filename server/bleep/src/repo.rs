@@ -11,7 +11,13 @@ use std::{
 };
 use tracing::debug;
 
-use crate::state::get_relative_path;
+use crate::{
+    indexes::{
+        diagnostics::{DiagnosticsTally, IndexDiagnostics},
+        lfs::{LfsCounts, LfsTally},
+    },
+    state::get_relative_path,
+};
 
 pub(crate) mod iterator;
 use iterator::language;
@@ -21,6 +27,8 @@ use iterator::language;
 #[serde(rename_all = "snake_case")]
 pub enum Backend {
     Local,
+    /// A local directory that is not a git repository.
+    LocalDir,
     Github,
 }
 
@@ -40,7 +48,7 @@ impl RepoRef {
                 backend,
                 name: name.as_ref().to_owned(),
             }),
-            Local => {
+            Local | LocalDir => {
                 let path = Path::new(name.as_ref());
 
                 if !path.is_absolute() {
@@ -68,6 +76,10 @@ impl RepoRef {
         let pathstr = match refstr.trim_start_matches('/').split_once('/') {
             Some(("github.com", name)) => return RepoRef::new(Backend::Github, name),
             Some(("local", name)) => name,
+            Some(("localdir", name)) => {
+                let local_path = get_relative_path(Path::new(name), root);
+                return Self::new(Backend::LocalDir, &local_path.to_string_lossy());
+            }
             _ => &refstr,
         };
 
@@ -84,18 +96,26 @@ impl RepoRef {
     }
 
     pub fn is_local(&self) -> bool {
-        self.backend == Backend::Local
+        matches!(self.backend, Backend::Local | Backend::LocalDir)
     }
 
     pub fn is_remote(&self) -> bool {
-        self.backend != Backend::Local
+        !self.is_local()
+    }
+
+    /// Whether this repository has git branches and commits.
+    ///
+    /// Plain local directories don't, so branch filters don't apply to them, and their revision
+    /// is a hash of their file manifest instead of a commit SHA.
+    pub fn has_branches(&self) -> bool {
+        self.backend != Backend::LocalDir
     }
 
     pub fn indexed_name(&self) -> String {
         // Local repos indexed as: dirname
         // Github repos indexed as: github.com/org/repo
         match self.backend {
-            Backend::Local => Path::new(&self.name)
+            Backend::Local | Backend::LocalDir => Path::new(&self.name)
                 .file_name()
                 .expect("last component is `..`")
                 .to_string_lossy()
@@ -109,13 +129,13 @@ impl RepoRef {
             // org_name/repo_name
             Backend::Github => self.name.to_owned(),
             // repo_name
-            Backend::Local => self.indexed_name(),
+            Backend::Local | Backend::LocalDir => self.indexed_name(),
         }
     }
 
     pub fn local_path(&self) -> Option<PathBuf> {
         match self.backend {
-            Backend::Local => Some(PathBuf::from(&self.name)),
+            Backend::Local | Backend::LocalDir => Some(PathBuf::from(&self.name)),
             _ => None,
         }
     }
@@ -152,6 +172,8 @@ impl FromStr for RepoRef {
             Some(("github.com", name)) => RepoRef::new(Backend::Github, name),
             // local/...
             Some(("local", name)) => RepoRef::new(Backend::Local, name),
+            // localdir/...
+            Some(("localdir", name)) => RepoRef::new(Backend::LocalDir, name),
             _ => Err(RepoError::InvalidBackend),
         }
     }
@@ -162,6 +184,7 @@ impl Display for RepoRef {
         match self.backend() {
             Backend::Github => write!(f, "github.com/{}", self.name()),
             Backend::Local => write!(f, "local/{}", self.name()),
+            Backend::LocalDir => write!(f, "localdir/{}", self.name()),
         }
     }
 }
@@ -227,6 +250,77 @@ impl From<&BranchFilter> for iterator::BranchFilter {
     }
 }
 
+/// The most branches that `BranchSettings::branches_to_index` expands to.
+///
+/// Every branch adds its own files to the indexes, so a broad pattern in a repository with
+/// hundreds of branches is cut down to the most recently committed to.
+pub const MAX_INDEXED_BRANCHES: usize = 10;
+
+/// The branches of a repository that are indexed besides HEAD, and the one that queries use.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct BranchSettings {
+    /// Glob patterns of the branches to index, like `develop` or `release/*`.
+    ///
+    /// Remote branches are matched without their `origin/` prefix.
+    #[serde(default)]
+    pub branches_to_index: Vec<String>,
+
+    /// The branch that queries are restricted to, if they name none.
+    #[serde(default)]
+    pub default_query_branch: Option<String>,
+}
+
+impl BranchSettings {
+    /// The branches that match `branches_to_index`, out of `branches` ordered newest first.
+    ///
+    /// At most `MAX_INDEXED_BRANCHES` of the matches are kept.
+    pub(crate) fn expand(&self, branches: &[String]) -> Result<Vec<String>, regex::Error> {
+        let patterns = RegexSet::new(self.branches_to_index.iter().map(|p| glob_to_regex(p)))?;
+
+        Ok(branches
+            .iter()
+            .filter(|b| patterns.is_match(b.strip_prefix("origin/").unwrap_or(b)))
+            .take(MAX_INDEXED_BRANCHES)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Translate a glob pattern of branch names to an anchored regex.
+///
+/// `*` matches within a single segment of the name, `**` matches across segments, and `?` matches
+/// any one character but `/`.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// The entry of a branch in a `BranchFilter::Select`, which only matches that branch.
+fn exact_branch(branch: &str) -> String {
+    format!("^{}$", regex::escape(branch))
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct Branch {
+    pub last_commit_unix_secs: u64,
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Repository {
     pub disk_path: PathBuf,
@@ -236,6 +330,24 @@ pub struct Repository {
     pub last_index_unix_secs: u64,
     pub most_common_lang: Option<String>,
     pub branch_filter: Option<BranchFilter>,
+
+    /// The revision that was last indexed.
+    ///
+    /// For git repositories this is the SHA of the HEAD commit, for plain directories it is a
+    /// hash of the file manifest.
+    #[serde(default)]
+    pub revision: Option<String>,
+
+    #[serde(default)]
+    pub branch_settings: BranchSettings,
+
+    /// LFS pointer files that were not indexed with their objects, in the last index.
+    #[serde(default)]
+    pub lfs: LfsCounts,
+
+    /// Files that were left out of the last index, or could not be fully indexed.
+    #[serde(default)]
+    pub index_diagnostics: IndexDiagnostics,
 }
 
 impl Repository {
@@ -251,6 +363,10 @@ impl Repository {
         let remote = gix::open(&disk_path)
             .map_err(anyhow::Error::from)
             .and_then(|git| {
+                if !reporef.has_branches() {
+                    anyhow::bail!("plain directories have no git remote");
+                }
+
                 let origin = git
                     .find_default_remote(Direction::Fetch)
                     .context("no git remote")??;
@@ -274,22 +390,162 @@ impl Repository {
             remote,
             most_common_lang: None,
             branch_filter: None,
+            revision: None,
+            branch_settings: Default::default(),
+            lfs: Default::default(),
+            index_diagnostics: Default::default(),
         }
     }
 
+    /// The name of the HEAD branch, and the remote branches of the repository, oldest first.
+    pub(crate) fn branches(&self, reporef: &RepoRef) -> (String, Vec<Branch>) {
+        let default = ("HEAD".to_string(), vec![]);
+        if !reporef.has_branches() {
+            return default;
+        }
+
+        let Ok(git) = gix::open(&self.disk_path) else {
+            return default;
+        };
+
+        let head = git
+            .head()
+            .ok()
+            .and_then(|head| head.try_into_referent())
+            .map(|r| {
+                if reporef.is_local() {
+                    r.name().shorten().to_string()
+                } else {
+                    format!("origin/{}", r.name().shorten())
+                }
+            })
+            .unwrap_or_else(|| default.0.clone());
+
+        let Ok(refs) = git.references() else {
+            return default;
+        };
+
+        let Ok(refs) = refs.all() else {
+            return default;
+        };
+
+        use gix::bstr::ByteSlice;
+        let mut branches = refs
+            .filter_map(Result::ok)
+            .filter_map(|mut r| {
+                let name = r.name().shorten().to_str_lossy().to_string();
+                let last_commit_unix_secs = r
+                    .peel_to_id_in_place()
+                    .ok()?
+                    .object()
+                    .ok()?
+                    .try_into_commit()
+                    .ok()?
+                    .time()
+                    .ok()?
+                    .seconds;
+
+                Some(Branch {
+                    name,
+                    last_commit_unix_secs,
+                })
+            })
+            .filter(|b| b.name != "origin/HEAD" && b.name.starts_with("origin/"))
+            .collect::<Vec<_>>();
+
+        branches.sort_by_key(|b| b.last_commit_unix_secs);
+        (head, branches)
+    }
+
+    /// The names of the branches of the repository, newest first.
+    pub(crate) fn branch_names(&self, reporef: &RepoRef) -> Vec<String> {
+        let (_, branches) = self.branches(reporef);
+        branches.into_iter().rev().map(|b| b.name).collect()
+    }
+
+    /// The entries of the branch filter for the branches that the branch settings select.
+    pub(crate) fn indexed_branches(&self, reporef: &RepoRef) -> Vec<String> {
+        self.branch_settings
+            .expand(&self.branch_names(reporef))
+            .unwrap_or_default()
+            .iter()
+            .map(|b| exact_branch(b))
+            .collect()
+    }
+
+    /// Replace the branch settings, given the branches of the repository, newest first.
+    ///
+    /// Unlike `BranchFilter::patch`, this drops the branches that the old settings selected but
+    /// the new ones don't from the branch filter, so that the next sync removes their files and
+    /// embeddings from the indexes. Returns the dropped branches.
+    pub(crate) fn set_branch_settings(
+        &mut self,
+        settings: BranchSettings,
+        branches: &[String],
+    ) -> Result<Vec<String>, regex::Error> {
+        let old = self.branch_settings.expand(branches)?;
+        let new = settings.expand(branches)?;
+        self.branch_settings = settings;
+
+        let dropped = old
+            .into_iter()
+            .filter(|b| !new.contains(b))
+            .collect::<Vec<_>>();
+
+        let mut selected = match &self.branch_filter {
+            // Every branch is indexed regardless of the settings.
+            Some(BranchFilter::All) => return Ok(vec![]),
+            Some(BranchFilter::Select(list)) => list.clone(),
+            Some(BranchFilter::Head) | None => vec![],
+        };
+
+        selected.retain(|s| !dropped.iter().any(|b| *s == exact_branch(b) || s == b));
+        for branch in new.iter().map(|b| exact_branch(b)) {
+            if !selected.contains(&branch) {
+                selected.push(branch);
+            }
+        }
+
+        self.branch_filter = Some(BranchFilter::Select(selected));
+        Ok(dropped)
+    }
+
     /// Pre-scan the repository to provide supporting metadata for a
     /// new indexing operation
-    pub async fn get_repo_metadata(&self) -> Arc<RepoMetadata> {
-        let last_commit_unix_secs = gix::open(&self.disk_path)
+    pub async fn get_repo_metadata(&self, reporef: &RepoRef) -> Arc<RepoMetadata> {
+        let head = gix::open(&self.disk_path)
             .context("failed to open git repo")
-            .and_then(|repo| Ok(repo.head()?.peel_to_commit_in_place()?.time()?.seconds))
+            .and_then(|repo| {
+                if !reporef.has_branches() {
+                    anyhow::bail!("plain directories are not indexed as git repos");
+                }
+
+                let commit = repo.head()?.peel_to_commit_in_place()?;
+                Ok((commit.time()?.seconds, commit.id.to_string()))
+            })
             .ok();
 
+        let (last_commit_unix_secs, revision) = match head {
+            Some((secs, sha)) => (Some(secs), Some(sha)),
+            None => {
+                let disk_path = self.disk_path.clone();
+                let revision =
+                    tokio::task::spawn_blocking(move || iterator::FileWalker::revision(disk_path))
+                        .await
+                        .ok();
+
+                (None, revision)
+            }
+        };
+
         let langs = Default::default();
 
         RepoMetadata {
             last_commit_unix_secs,
+            revision,
             langs,
+            lfs: Default::default(),
+            diagnostics: DiagnosticsTally::new(&self.index_diagnostics),
         }
         .into()
     }
@@ -313,6 +569,9 @@ impl Repository {
     ) {
         self.last_index_unix_secs = get_unix_time(SystemTime::now());
         self.last_commit_unix_secs = metadata.last_commit_unix_secs.unwrap_or(0);
+        self.revision = metadata.revision.clone();
+        self.lfs = metadata.lfs.counts();
+        self.index_diagnostics = metadata.diagnostics.finish();
         self.most_common_lang = metadata
             .langs
             .most_common_lang()
@@ -336,7 +595,13 @@ fn get_unix_time(time: SystemTime) -> u64 {
 #[derive(Debug)]
 pub struct RepoMetadata {
     pub last_commit_unix_secs: Option<u64>,
+    /// The HEAD commit SHA, or the file manifest hash of plain directories.
+    pub revision: Option<String>,
     pub langs: language::LanguageInfo,
+    /// LFS pointer files found while indexing.
+    pub lfs: LfsTally,
+    /// Files that could not be fully indexed.
+    pub diagnostics: DiagnosticsTally,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Hash)]
@@ -417,7 +682,7 @@ impl<T: AsRef<RepoRef>> From<T> for RepoRemote {
                 address: name.to_owned(),
             }),
             RepoRef {
-                backend: Backend::Local,
+                backend: Backend::Local | Backend::LocalDir,
                 name: _name,
             } => RepoRemote::None,
         }
@@ -516,6 +781,17 @@ mod test {
             "local//tmp/repository".parse::<RepoRef>().unwrap(),
             RepoRef::new(Backend::Local, "/tmp/repository").unwrap()
         );
+        assert_eq!(
+            "localdir//tmp/docs".parse::<RepoRef>().unwrap(),
+            RepoRef::new(Backend::LocalDir, "/tmp/docs").unwrap()
+        );
+        assert!(!"localdir//tmp/docs"
+            .parse::<RepoRef>()
+            .unwrap()
+            .has_branches());
+        if "localdir/docs".parse::<RepoRef>().is_ok() {
+            panic!("non-absolute local directory allowed")
+        }
         if "repository".parse::<RepoRef>().is_ok() {
             panic!("non-absolute local allowed")
         }
@@ -531,6 +807,10 @@ mod test {
             r#""local//org/repo""#,
             &serde_json::to_string(&RepoRef::new(Backend::Local, "/org/repo").unwrap()).unwrap()
         );
+        assert_eq!(
+            r#""localdir//srv/docs""#,
+            &serde_json::to_string(&RepoRef::new(Backend::LocalDir, "/srv/docs").unwrap()).unwrap()
+        );
     }
 
     #[test]
@@ -556,4 +836,105 @@ mod test {
         assert_eq!(ssh, "git@github.com:org/repo.git/".parse().unwrap());
         assert_eq!(ssh, "git@github.com:/org/repo.git/".parse().unwrap());
     }
+
+    fn branch_settings(patterns: &[&str]) -> BranchSettings {
+        BranchSettings {
+            branches_to_index: patterns.iter().map(|p| p.to_string()).collect(),
+            default_query_branch: None,
+        }
+    }
+
+    #[test]
+    fn expand_branch_patterns() {
+        let branches = [
+            "origin/release/2.0",
+            "origin/develop",
+            "origin/release/1.0",
+            "origin/release/1.0/hotfix",
+            "origin/main",
+            "origin/feature/develop",
+        ]
+        .map(String::from);
+
+        assert_eq!(
+            branch_settings(&["develop", "release/*"])
+                .expand(&branches)
+                .unwrap(),
+            ["origin/release/2.0", "origin/develop", "origin/release/1.0"]
+        );
+        assert_eq!(
+            branch_settings(&["release/**"]).expand(&branches).unwrap(),
+            [
+                "origin/release/2.0",
+                "origin/release/1.0",
+                "origin/release/1.0/hotfix"
+            ]
+        );
+        assert_eq!(
+            branch_settings(&["release/?.0"]).expand(&branches).unwrap(),
+            ["origin/release/2.0", "origin/release/1.0"]
+        );
+        assert!(branch_settings(&[]).expand(&branches).unwrap().is_empty());
+
+        // Only the newest branches are kept.
+        let many = (0..20)
+            .map(|i| format!("origin/release/{i}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            branch_settings(&["release/*"]).expand(&many).unwrap(),
+            many[..MAX_INDEXED_BRANCHES]
+        );
+    }
+
+    #[test]
+    fn dropped_branches_are_cleaned_up() {
+        let branches = ["origin/develop", "origin/release/1.0", "origin/main"].map(String::from);
+        let mut repo = Repository {
+            disk_path: "/repo".into(),
+            remote: RepoRemote::None,
+            sync_status: SyncStatus::Done,
+            last_commit_unix_secs: 0,
+            last_index_unix_secs: 0,
+            most_common_lang: None,
+            branch_filter: Some(BranchFilter::Select(vec!["origin/main".into()])),
+            revision: None,
+            branch_settings: Default::default(),
+            lfs: Default::default(),
+            index_diagnostics: Default::default(),
+        };
+
+        let dropped = repo
+            .set_branch_settings(branch_settings(&["develop", "release/*"]), &branches)
+            .unwrap();
+        assert!(dropped.is_empty());
+        assert_eq!(
+            repo.branch_filter,
+            Some(BranchFilter::Select(vec![
+                "origin/main".into(),
+                "^origin/develop$".into(),
+                "^origin/release/1\\.0$".into(),
+            ]))
+        );
+
+        // Branches that were used in queries stay in the filter.
+        let dropped = repo
+            .set_branch_settings(branch_settings(&["release/*"]), &branches)
+            .unwrap();
+        assert_eq!(dropped, ["origin/develop"]);
+        assert_eq!(
+            repo.branch_filter,
+            Some(BranchFilter::Select(vec![
+                "origin/main".into(),
+                "^origin/release/1\\.0$".into(),
+            ]))
+        );
+
+        // Nothing is dropped from a repository that indexes every branch.
+        repo.branch_filter = Some(BranchFilter::All);
+        let dropped = repo
+            .set_branch_settings(branch_settings(&[]), &branches)
+            .unwrap();
+        assert!(dropped.is_empty());
+        assert_eq!(repo.branch_filter, Some(BranchFilter::All));
+    }
 }
@@ -12,7 +12,7 @@ pub(super) mod language;
 pub use fs::FileWalker;
 pub use git::{BranchFilter, GitWalker};
 
-use crate::background::SyncPipes;
+use crate::{background::SyncPipes, indexes::diagnostics::Category};
 
 // Empirically calculated using:
 //     cat **/*.rs | awk '{SUM+=length;N+=1}END{print SUM/N}'
@@ -28,6 +28,8 @@ pub trait FileSource {
 pub enum RepoDirEntry {
     Dir(RepoDir),
     File(RepoFile),
+    /// A file that was left out before its contents were indexed.
+    Skipped(SkippedFile),
     Other,
 }
 
@@ -36,6 +38,7 @@ impl RepoDirEntry {
         match self {
             Self::File(file) => Some(file.path.as_str()),
             Self::Dir(dir) => Some(dir.path.as_str()),
+            Self::Skipped(skipped) => Some(skipped.path.as_str()),
             Self::Other => None,
         }
     }
@@ -51,7 +54,7 @@ impl RepoDirEntry {
         match self {
             RepoDirEntry::Dir(d) => Some(&d.branches),
             RepoDirEntry::File(f) => Some(&f.branches),
-            RepoDirEntry::Other => None,
+            RepoDirEntry::Skipped(_) | RepoDirEntry::Other => None,
         }
     }
 }
@@ -67,6 +70,12 @@ pub struct RepoFile {
     pub branches: Vec<String>,
 }
 
+pub struct SkippedFile {
+    pub path: String,
+    pub category: Category,
+    pub reason: String,
+}
+
 #[derive(Hash, Eq, PartialEq)]
 pub enum FileType {
     File,
@@ -10,6 +10,41 @@ pub struct FileWalker {
 
 impl FileWalker {
     pub fn index_directory(dir: impl AsRef<Path>) -> impl FileSource {
+        Self::walk(dir)
+    }
+
+    /// A synthetic revision for directories that are not git repositories.
+    ///
+    /// This is a hash of the file manifest: the relative path and content hash of every file that
+    /// would be indexed, so it changes whenever any indexed file is added, removed or modified.
+    pub fn revision(dir: impl AsRef<Path>) -> String {
+        let root = crate::canonicalize(dir.as_ref()).unwrap_or_else(|_| dir.as_ref().to_owned());
+        let mut file_list = Self::walk(&root).file_list;
+        file_list.sort();
+
+        let mut manifest = blake3::Hasher::new();
+        let indexed = file_list.iter().filter(
+            |p| matches!(p.metadata(), Ok(meta) if meta.is_file() && meta.len() < MAX_FILE_LEN),
+        );
+        for path in indexed {
+            let content = match std::fs::read(path) {
+                Ok(content) => content,
+                Err(err) => {
+                    warn!(%err, ?path, "read failed; skipping");
+                    continue;
+                }
+            };
+
+            let relative_path = path.strip_prefix(&root).unwrap_or(path);
+            manifest.update(relative_path.to_string_lossy().as_bytes());
+            manifest.update(b"\0");
+            manifest.update(blake3::hash(&content).as_bytes());
+        }
+
+        manifest.finalize().to_hex().to_string()
+    }
+
+    fn walk(dir: impl AsRef<Path>) -> Self {
         // note: this WILL observe .gitignore files for the respective repos.
         let walker = ignore::WalkBuilder::new(&dir)
             .standard_filters(true)
@@ -25,8 +60,6 @@ impl FileWalker {
                     None
                 }
             })
-            // Preliminarily ignore files that are very large, without reading the contents.
-            .filter(|de| matches!(de.metadata(), Ok(meta) if meta.len() < MAX_FILE_LEN))
             .filter_map(|de| crate::canonicalize(de.into_path()).ok())
             .collect();
 
@@ -46,22 +79,39 @@ impl FileSource for FileWalker {
         self.file_list
             .into_par_iter()
             .filter_map(|entry_disk_path| {
+                let path = entry_disk_path.to_string_lossy().to_string();
                 if entry_disk_path.is_file() {
+                    // Preliminarily skip files that are very large, without reading the contents.
+                    let len = entry_disk_path.metadata().map_or(0, |meta| meta.len());
+                    if len >= MAX_FILE_LEN {
+                        return Some(RepoDirEntry::Skipped(SkippedFile {
+                            path,
+                            category: Category::Oversized,
+                            reason: format!("{len} bytes, over the limit of {MAX_FILE_LEN}"),
+                        }));
+                    }
+
                     let buffer = match std::fs::read_to_string(&entry_disk_path) {
+                        // Binary files are not indexed, and are not worth a diagnostic.
+                        Err(err) if err.kind() == std::io::ErrorKind::InvalidData => return None,
                         Err(err) => {
                             warn!(%err, ?entry_disk_path, "read failed; skipping");
-                            return None;
+                            return Some(RepoDirEntry::Skipped(SkippedFile {
+                                path,
+                                category: Category::Unreadable,
+                                reason: err.to_string(),
+                            }));
                         }
                         Ok(buffer) => buffer,
                     };
                     Some(RepoDirEntry::File(RepoFile {
                         buffer,
-                        path: entry_disk_path.to_string_lossy().to_string(),
+                        path,
                         branches: vec![HEAD.into()],
                     }))
                 } else if entry_disk_path.is_dir() {
                     Some(RepoDirEntry::Dir(RepoDir {
-                        path: entry_disk_path.to_string_lossy().to_string(),
+                        path,
                         branches: vec![HEAD.into()],
                     }))
                 } else {
@@ -187,11 +187,22 @@ impl FileSource for GitWalker {
                 let git = self.git.to_thread_local();
                 let Ok(Some(object)) = git.try_find_object(oid) else {
                     error!(?path, ?branches, "can't find object for file");
-                    return None;
+                    return Some(RepoDirEntry::Skipped(SkippedFile {
+                        path,
+                        category: Category::Unreadable,
+                        reason: format!("git object {oid} not found"),
+                    }));
                 };
 
                 if object.data.len() as u64 > MAX_FILE_LEN {
-                    return None;
+                    return Some(RepoDirEntry::Skipped(SkippedFile {
+                        path,
+                        category: Category::Oversized,
+                        reason: format!(
+                            "{} bytes, over the limit of {MAX_FILE_LEN}",
+                            object.data.len()
+                        ),
+                    }));
                 }
 
                 let entry = match kind {
@@ -1,21 +1,19 @@
 use std::{borrow::Cow, collections::HashMap, env, path::Path, sync::Arc};
 
-use crate::{query::parser::SemanticQuery, Configuration};
+use crate::{
+    indexes::reader::ContentDocument, intelligence::TreeSitterFile, query::parser::SemanticQuery,
+    Configuration,
+};
 
 use ndarray::Axis;
 use ort::{
     tensor::{FromArray, InputTensor, OrtOwnedTensor},
     Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, SessionBuilder,
 };
-use qdrant_client::{
-    prelude::{QdrantClient, QdrantClientConfig},
-    qdrant::{
-        point_id::PointIdOptions, r#match::MatchValue, vectors::VectorsOptions, vectors_config,
-        with_payload_selector, with_vectors_selector, CollectionOperationResponse,
-        CreateCollection, Distance, FieldCondition, FieldType, Filter, Match, PointId,
-        RetrievedPoint, ScoredPoint, SearchPoints, Value, VectorParams, Vectors, VectorsConfig,
-        WithPayloadSelector, WithVectorsSelector,
-    },
+use qdrant_client::qdrant::{
+    point_id::PointIdOptions, r#match::MatchValue, vectors::VectorsOptions, vectors_config,
+    CreateCollection, Distance, FieldCondition, Filter, Match, PointId, RetrievedPoint,
+    ScoredPoint, Value, VectorParams, Vectors, VectorsConfig,
 };
 
 use futures::{stream, StreamExt, TryStreamExt};
@@ -26,12 +24,17 @@ use tracing::{debug, info, trace, warn};
 pub mod chunk;
 pub mod execute;
 mod schema;
+pub mod store;
 
 pub use schema::{Embedding, Payload};
+use store::VectorStore;
 
 pub(crate) const COLLECTION_NAME: &str = "documents";
 pub(crate) const EMBEDDING_DIM: usize = 384;
 
+/// The symbol path of chunks that are outside any symbol, like imports and file-level comments.
+pub const FILE_SCOPE: &str = "<file scope>";
+
 #[derive(Error, Debug)]
 pub enum SemanticError {
     /// Represents failure to initialize Qdrant client
@@ -53,7 +56,7 @@ pub enum SemanticError {
 
 #[derive(Clone)]
 pub struct Semantic {
-    qdrant: Arc<QdrantClient>,
+    store: Arc<dyn VectorStore>,
     tokenizer: Arc<tokenizers::Tokenizer>,
     session: Arc<ort::Session>,
     config: Arc<Configuration>,
@@ -92,7 +95,7 @@ impl Payload {
     }
 
     pub(crate) fn into_qdrant(self) -> HashMap<String, Value> {
-        HashMap::from([
+        let mut payload = HashMap::from([
             ("lang".into(), self.lang.to_ascii_lowercase().into()),
             ("repo_name".into(), self.repo_name.into()),
             ("repo_ref".into(), self.repo_ref.into()),
@@ -104,7 +107,38 @@ impl Payload {
             ("start_byte".into(), self.start_byte.to_string().into()),
             ("end_byte".into(), self.end_byte.to_string().into()),
             ("branches".into(), self.branches.into()),
-        ])
+        ]);
+
+        if let Some(symbol_path) = self.symbol_path {
+            payload.insert("symbol_path".into(), symbol_path.into());
+        }
+
+        if self.lfs_pointer {
+            payload.insert("lfs_pointer".into(), true.into());
+        }
+
+        payload
+    }
+
+    /// A document with the text of this chunk as its content.
+    ///
+    /// Chunks carry no symbols, and their language is lowercase, as it was stored.
+    pub fn to_content_document(&self) -> ContentDocument {
+        ContentDocument {
+            content: self.text.clone(),
+            lang: (!self.lang.is_empty()).then(|| self.lang.clone()),
+            relative_path: self.relative_path.clone(),
+            repo_name: self.repo_name.clone(),
+            repo_ref: self.repo_ref.clone(),
+            line_end_indices: self
+                .text
+                .match_indices('\n')
+                .map(|(i, _)| i as u32)
+                .collect(),
+            symbol_locations: Default::default(),
+            branches: (!self.branches.is_empty()).then(|| self.branches.join("\n")),
+            lfs_pointer: self.lfs_pointer,
+        }
     }
 }
 
@@ -149,6 +183,14 @@ fn parse_payload(
         end_line: val_parse_str!(converted, "end_line"),
         start_byte: val_parse_str!(converted, "start_byte"),
         end_byte: val_parse_str!(converted, "end_byte"),
+        // Chunks indexed before symbol paths were recorded have none.
+        symbol_path: converted
+            .remove("symbol_path")
+            .and_then(|v| serde_json::from_value(v).ok()),
+        lfs_pointer: converted
+            .remove("lfs_pointer")
+            .and_then(|v| v.as_bool())
+            .unwrap_or_default(),
 
         id: Some(id),
         score: Some(score),
@@ -194,50 +236,9 @@ fn collection_config() -> CreateCollection {
 impl Semantic {
     pub async fn initialize(
         model_dir: &Path,
-        qdrant_url: &str,
+        store: Arc<dyn VectorStore>,
         config: Arc<Configuration>,
     ) -> Result<Self, SemanticError> {
-        let qdrant = QdrantClient::new(Some(QdrantClientConfig::from_url(qdrant_url))).unwrap();
-
-        match qdrant.has_collection(COLLECTION_NAME).await {
-            Ok(false) => {
-                let CollectionOperationResponse { result, time } = qdrant
-                    .create_collection(&collection_config())
-                    .await
-                    .unwrap();
-
-                debug!(
-                    time,
-                    created = result,
-                    name = COLLECTION_NAME,
-                    "created qdrant collection"
-                );
-
-                assert!(result);
-            }
-            Ok(true) => {}
-            Err(_) => return Err(SemanticError::QdrantInitializationError),
-        }
-
-        qdrant
-            .create_field_index(COLLECTION_NAME, "repo_ref", FieldType::Text, None, None)
-            .await?;
-        qdrant
-            .create_field_index(COLLECTION_NAME, "content_hash", FieldType::Text, None, None)
-            .await?;
-        qdrant
-            .create_field_index(COLLECTION_NAME, "branches", FieldType::Text, None, None)
-            .await?;
-        qdrant
-            .create_field_index(
-                COLLECTION_NAME,
-                "relative_path",
-                FieldType::Text,
-                None,
-                None,
-            )
-            .await?;
-
         if let Some(dylib_dir) = config.dylib_dir.as_ref() {
             init_ort_dylib(dylib_dir);
         }
@@ -258,7 +259,7 @@ impl Semantic {
         };
 
         Ok(Self {
-            qdrant: qdrant.into(),
+            store,
             tokenizer: tokenizers::Tokenizer::from_file(model_dir.join("tokenizer.json"))
                 .unwrap()
                 .into(),
@@ -271,9 +272,12 @@ impl Semantic {
         })
     }
 
+    pub(crate) fn store(&self) -> &dyn VectorStore {
+        &*self.store
+    }
+
     pub async fn health_check(&self) -> anyhow::Result<()> {
-        self.qdrant.health_check().await?;
-        Ok(())
+        self.store.health_check().await
     }
 
     pub fn embed(&self, sequence: &str) -> anyhow::Result<Embedding> {
@@ -319,30 +323,10 @@ impl Semantic {
         limit: u64,
         offset: u64,
         threshold: f32,
-    ) -> anyhow::Result<Vec<ScoredPoint>> {
-        let response = self
-            .qdrant
-            .search_points(&SearchPoints {
-                limit,
-                vector,
-                collection_name: COLLECTION_NAME.to_string(),
-                offset: Some(offset),
-                score_threshold: Some(threshold),
-                with_payload: Some(WithPayloadSelector {
-                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-                }),
-                filter: Some(Filter {
-                    must: build_conditions(parsed_query),
-                    ..Default::default()
-                }),
-                with_vectors: Some(WithVectorsSelector {
-                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
-                }),
-                ..Default::default()
-            })
-            .await?;
-
-        Ok(response.result)
+    ) -> anyhow::Result<Vec<Payload>> {
+        self.store
+            .search(parsed_query, vector, limit, offset, threshold)
+            .await
     }
 
     pub async fn batch_search_with<'a>(
@@ -352,7 +336,7 @@ impl Semantic {
         limit: u64,
         offset: u64,
         threshold: f32,
-    ) -> anyhow::Result<Vec<ScoredPoint>> {
+    ) -> anyhow::Result<Vec<Payload>> {
         // FIXME: This method uses `search_points` internally, and not `search_batch_points`. It's
         // not clear why, but it seems that the `batch` variant of the `qdrant` calls leads to
         // HTTP2 errors on some deployment configurations. A typical example error:
@@ -366,40 +350,17 @@ impl Semantic {
 
         // Queries should contain the same filters, so we get the first one
         let parsed_query = parsed_queries.first().unwrap();
-        let filters = &build_conditions(parsed_query);
 
         let responses = stream::iter(vectors.into_iter())
-            .map(|vector| async move {
-                let points = SearchPoints {
-                    limit,
-                    vector,
-                    collection_name: COLLECTION_NAME.to_string(),
-                    offset: Some(offset),
-                    score_threshold: Some(threshold),
-                    with_payload: Some(WithPayloadSelector {
-                        selector_options: Some(with_payload_selector::SelectorOptions::Enable(
-                            true,
-                        )),
-                    }),
-                    filter: Some(Filter {
-                        must: filters.clone(),
-                        ..Default::default()
-                    }),
-                    with_vectors: Some(WithVectorsSelector {
-                        selector_options: Some(with_vectors_selector::SelectorOptions::Enable(
-                            true,
-                        )),
-                    }),
-                    ..Default::default()
-                };
-
-                self.qdrant.search_points(&points).await
+            .map(|vector| {
+                self.store
+                    .search(parsed_query, vector, limit, offset, threshold)
             })
             .buffered(10)
             .try_collect::<Vec<_>>()
             .await?;
 
-        Ok(responses.into_iter().flat_map(|r| r.result).collect())
+        Ok(responses.into_iter().flatten().collect())
     }
 
     pub async fn search<'a>(
@@ -426,15 +387,22 @@ impl Semantic {
                 offset,
                 threshold,
             )
-            .await
-            .map(|raw| {
-                raw.into_iter()
-                    .map(Payload::from_qdrant)
-                    .collect::<Vec<_>>()
-            })?;
+            .await?;
         Ok(deduplicate_snippets(results, vector, limit))
     }
 
+    /// Re-rank the results of a search for `query`, by their BM25 score against it and then for
+    /// diversity, with the configured weights.
+    pub fn hybrid_rerank(
+        &self,
+        query: &str,
+        query_embedding: Embedding,
+        results: Vec<Payload>,
+    ) -> Vec<Payload> {
+        let results = rerank_weighted(query, results, self.config.bm25_weight);
+        mmr_rerank(query_embedding, results, self.config.mmr_lambda)
+    }
+
     pub async fn batch_search<'a>(
         &self,
         parsed_queries: &[&SemanticQuery<'a>],
@@ -466,10 +434,7 @@ impl Semantic {
 
         tracing::trace!(?result, "qdrant batch search returned");
 
-        let results = result?
-            .into_iter()
-            .map(Payload::from_qdrant)
-            .collect::<Vec<_>>();
+        let results = result?;
 
         // deduplicate with mmr with respect to the mean of query vectors
         // TODO: implement a more robust multi-vector deduplication strategy
@@ -487,6 +452,7 @@ impl Semantic {
         buffer: &str,
         lang_str: &str,
         branches: &[String],
+        lfs_pointer: bool,
         chunk_cache: crate::cache::ChunkCache<'_>,
     ) {
         let chunks = chunk::by_tokens(
@@ -500,11 +466,23 @@ impl Semantic {
         );
         debug!(chunk_count = chunks.len(), "found chunks");
 
+        let chunks = {
+            let file = TreeSitterFile::try_build(buffer.as_bytes(), lang_str).ok();
+            chunks
+                .into_iter()
+                .map(|chunk| {
+                    let range = chunk.range.start.byte..chunk.range.end.byte;
+                    let symbol_path = file.as_ref().map(|file| symbol_path(file, range));
+                    (chunk, symbol_path)
+                })
+                .collect::<Vec<_>>()
+        };
+
         let embedder = |c: &str| {
             debug!("generating embedding");
             self.embed(c)
         };
-        chunks.par_iter().for_each(|chunk| {
+        chunks.par_iter().for_each(|(chunk, symbol_path)| {
             let data = format!("{repo_name}\t{relative_path}\n{}", chunk.data,);
             let payload = Payload {
                 repo_name: repo_name.to_owned(),
@@ -518,6 +496,8 @@ impl Semantic {
                 end_line: chunk.range.end.line as u64,
                 start_byte: chunk.range.start.byte as u64,
                 end_byte: chunk.range.end.byte as u64,
+                symbol_path: symbol_path.clone(),
+                lfs_pointer,
                 ..Default::default()
             };
 
@@ -527,7 +507,7 @@ impl Semantic {
             }
         });
 
-        match chunk_cache.commit(&self.qdrant).await {
+        match chunk_cache.commit(&*self.store).await {
             Ok((new, updated, deleted)) => {
                 info!(
                     repo_name,
@@ -545,22 +525,9 @@ impl Semantic {
         repo_ref: &str,
         paths: impl Iterator<Item = String>,
     ) {
-        let repo_filter = make_kv_keyword_filter("repo_ref", repo_ref).into();
-        let file_filter = paths
-            .map(|p| make_kv_keyword_filter("content_hash", &p).into())
-            .collect::<Vec<_>>();
-
-        let selector = Filter {
-            must: vec![repo_filter],
-            should: file_filter,
-            ..Default::default()
+        if let Err(err) = self.store.delete_files(repo_ref, paths.collect()).await {
+            warn!(repo_ref, ?err, "failed to delete vectors");
         }
-        .into();
-
-        let _ = self
-            .qdrant
-            .delete_points(COLLECTION_NAME, &selector, None)
-            .await;
     }
 
     pub fn overlap_strategy(&self) -> chunk::OverlapStrategy {
@@ -619,13 +586,7 @@ fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Con
     let repo_filter = {
         let conditions = query
             .repos()
-            .map(|r| {
-                if r.contains('/') && !r.starts_with("github.com/") {
-                    format!("github.com/{r}")
-                } else {
-                    r.to_string()
-                }
-            })
+            .map(|r| qualified_repo_name(&r))
             .map(|r| make_kv_keyword_filter("repo_name", r.as_ref()).into())
             .collect::<Vec<_>>();
         // one of the above repos should match
@@ -695,6 +656,93 @@ fn build_conditions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Con
     filters
 }
 
+/// Conditions that exclude the points matching the negated filters of a query, like `-path:tests`.
+///
+/// These go in the `must_not` clause of a filter, so a point matching any of them is excluded.
+fn build_exclusions(query: &SemanticQuery<'_>) -> Vec<qdrant_client::qdrant::Condition> {
+    query
+        .exclude_repos()
+        .map(|r| make_kv_keyword_filter("repo_name", &qualified_repo_name(&r)).into())
+        .chain(
+            query
+                .exclude_paths()
+                .map(|p| make_kv_text_filter("relative_path", p.as_ref()).into()),
+        )
+        .chain(
+            query
+                .exclude_langs()
+                .map(|l| make_kv_keyword_filter("lang", l.as_ref()).into()),
+        )
+        .collect()
+}
+
+/// Whether a payload matches the filters of a query, like the conditions of `build_conditions`.
+fn matches_conditions(query: &SemanticQuery<'_>, payload: &Payload) -> bool {
+    fn any_or_empty<'a>(
+        mut values: impl Iterator<Item = Cow<'a, str>>,
+        mut matches: impl FnMut(&str) -> bool,
+    ) -> bool {
+        let mut empty = true;
+        values.any(|v| {
+            empty = false;
+            matches(&v)
+        }) || empty
+    }
+
+    let excluded = query
+        .exclude_repos()
+        .any(|r| qualified_repo_name(&r) == payload.repo_name)
+        || query
+            .exclude_paths()
+            .any(|p| payload.relative_path.contains(p.as_ref()))
+        || query.exclude_langs().any(|l| l == payload.lang);
+
+    any_or_empty(query.repos(), |r| qualified_repo_name(r) == payload.repo_name)
+        && any_or_empty(query.paths(), |p| payload.relative_path.contains(p))
+        && any_or_empty(query.langs(), |l| l == payload.lang)
+        && any_or_empty(query.branch(), |b| payload.branches.iter().any(|pb| pb == b))
+        && !excluded
+}
+
+/// Keep the results that contain every quoted phrase of a query, ignoring case.
+///
+/// This is the lexical half of hybrid search: the vector search can't match text exactly, so
+/// phrases are checked against the results it returns.
+pub fn retain_phrases(query: &SemanticQuery<'_>, mut results: Vec<Payload>) -> Vec<Payload> {
+    let phrases = query
+        .phrases()
+        .map(|p| p.to_lowercase())
+        .collect::<Vec<_>>();
+
+    if !phrases.is_empty() {
+        results.retain(|r| {
+            let text = r.text.to_lowercase();
+            phrases.iter().all(|p| text.contains(p))
+        });
+    }
+
+    results
+}
+
+/// Repository names in the index are qualified by their host.
+/// The path of the symbols that enclose a range of a file, like `Agent::code_search`.
+fn symbol_path(file: &TreeSitterFile<'_>, range: std::ops::Range<usize>) -> String {
+    let path = file.symbol_path(range);
+    if path.is_empty() {
+        FILE_SCOPE.to_owned()
+    } else {
+        path.join(file.symbol_separator())
+    }
+}
+
+fn qualified_repo_name(repo: &str) -> String {
+    if repo.contains('/') && !repo.starts_with("github.com/") {
+        format!("github.com/{repo}")
+    } else {
+        repo.to_string()
+    }
+}
+
 fn dot(a: &[f32], b: &[f32]) -> f32 {
     a.iter().zip(b.iter()).map(|(ai, bi)| ai * bi).sum()
 }
@@ -863,3 +911,422 @@ pub fn deduplicate_snippets(
         })
         .collect()
 }
+
+/// The default weight of the BM25 score when re-ranking search results.
+pub const DEFAULT_BM25_WEIGHT: f32 = 0.3;
+
+/// Re-rank search results by a combination of their similarity score and a BM25 score against the
+/// query, using the default weight.
+pub fn rerank(query: &str, results: Vec<Payload>) -> Vec<Payload> {
+    rerank_weighted(query, results, DEFAULT_BM25_WEIGHT)
+}
+
+/// Re-rank search results by a combination of their similarity score and a BM25 score against the
+/// query.
+///
+/// BM25 document statistics are taken from the results themselves. The BM25 scores are normalized
+/// to `[0, 1]` and combined linearly with the similarity score, as `(1 - weight) * similarity +
+/// weight * bm25`. The combined score replaces each result's `score`, and results are returned in
+/// descending order of it.
+pub fn rerank_weighted(query: &str, mut results: Vec<Payload>, weight: f32) -> Vec<Payload> {
+    let weight = weight.clamp(0., 1.);
+    let bm25 = bm25_scores(query, results.iter().map(|r| r.text.as_str()));
+
+    if bm25.is_empty() {
+        return results;
+    }
+
+    let max_bm25 = bm25.iter().copied().fold(0., f32::max);
+
+    for (result, bm25) in results.iter_mut().zip(bm25) {
+        let lexical = if max_bm25 > 0. { bm25 / max_bm25 } else { 0. };
+        let similarity = result.score.unwrap_or_default();
+
+        result.score = Some((1. - weight) * similarity + weight * lexical);
+    }
+
+    results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    results
+}
+
+/// The default trade-off between relevance and diversity in [`mmr_rerank`].
+pub const DEFAULT_MMR_LAMBDA: f32 = 0.5;
+
+/// Order search results by Maximal Marginal Relevance, so that near-duplicates of higher ranked
+/// results are pushed down.
+///
+/// Results are picked one at a time, by `lambda * relevance - (1 - lambda) * redundancy`. The
+/// relevance of a result is its score, or its similarity to the query if it has no score, and its
+/// redundancy is its highest similarity to any result picked before it. Results without an
+/// embedding can't be compared, and keep their order after all others.
+pub fn mmr_rerank(query_embedding: Vec<f32>, results: Vec<Payload>, lambda: f32) -> Vec<Payload> {
+    let lambda = lambda.clamp(0., 1.);
+    let (candidates, unembedded): (Vec<_>, Vec<_>) =
+        results.into_iter().partition(|r| r.embedding.is_some());
+
+    let order = {
+        let embeddings = candidates
+            .iter()
+            .map(|r| r.embedding.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        let relevance = candidates
+            .iter()
+            .zip(&embeddings)
+            .map(|(r, emb)| {
+                r.score
+                    .unwrap_or_else(|| cosine_similarity(&query_embedding, emb))
+            })
+            .collect::<Vec<_>>();
+
+        let mut redundancy = vec![0.; candidates.len()];
+        let mut remaining = (0..candidates.len()).collect::<Vec<_>>();
+        let mut order = Vec::with_capacity(candidates.len());
+
+        while !remaining.is_empty() {
+            let (pos, _) = remaining
+                .iter()
+                .map(|&i| lambda * relevance[i] - (1. - lambda) * redundancy[i])
+                .enumerate()
+                .fold((0, f32::NEG_INFINITY), |best, (pos, score)| {
+                    if score > best.1 {
+                        (pos, score)
+                    } else {
+                        best
+                    }
+                });
+
+            let picked = remaining.remove(pos);
+            for &i in &remaining {
+                redundancy[i] =
+                    redundancy[i].max(cosine_similarity(embeddings[i], embeddings[picked]));
+            }
+
+            order.push(picked);
+        }
+
+        order
+    };
+
+    let mut candidates = candidates.into_iter().map(Some).collect::<Vec<_>>();
+    order
+        .into_iter()
+        .map(|i| candidates[i].take().unwrap())
+        .chain(unembedded)
+        .collect()
+}
+
+/// The BM25 score of each of `documents` against `query`, in the same order.
+///
+/// Document statistics are taken from `documents` themselves. This is empty if there are no
+/// documents, or if the query has no terms.
+pub fn bm25_scores<'a>(query: &str, documents: impl IntoIterator<Item = &'a str>) -> Vec<f32> {
+    const K1: f32 = 1.2;
+    const B: f32 = 0.75;
+
+    let mut query_terms = bm25_tokens(query);
+    query_terms.sort();
+    query_terms.dedup();
+
+    let documents = documents.into_iter().map(bm25_tokens).collect::<Vec<_>>();
+
+    if documents.is_empty() || query_terms.is_empty() {
+        return Vec::new();
+    }
+
+    let n = documents.len() as f32;
+    let avg_len = documents.iter().map(Vec::len).sum::<usize>() as f32 / n;
+
+    let idf = query_terms
+        .iter()
+        .map(|term| {
+            let df = documents.iter().filter(|d| d.contains(term)).count() as f32;
+            (1. + (n - df + 0.5) / (df + 0.5)).ln()
+        })
+        .collect::<Vec<_>>();
+
+    documents
+        .iter()
+        .map(|doc| {
+            let len_norm = 1. - B + B * doc.len() as f32 / avg_len.max(1.);
+
+            query_terms
+                .iter()
+                .zip(&idf)
+                .map(|(term, idf)| {
+                    let tf = doc.iter().filter(|t| *t == term).count() as f32;
+                    idf * tf * (K1 + 1.) / (tf + K1 * len_norm)
+                })
+                .sum::<f32>()
+        })
+        .collect()
+}
+
+/// Split text into lowercase alphanumeric terms, also splitting `camelCase` and `snake_case`
+/// identifiers.
+fn bm25_tokens(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let mut start = 0;
+        let mut prev_lower = false;
+
+        for (i, c) in word.char_indices() {
+            if c.is_uppercase() && prev_lower {
+                tokens.push(word[start..i].to_lowercase());
+                start = i;
+            }
+
+            prev_lower = c.is_lowercase() || c.is_numeric();
+        }
+
+        if start < word.len() {
+            tokens.push(word[start..].to_lowercase());
+        }
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(relative_path: &str, text: &str, score: f32) -> Payload {
+        Payload {
+            relative_path: relative_path.to_owned(),
+            text: text.to_owned(),
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    fn point_id() -> Option<PointId> {
+        Some(PointId {
+            point_id_options: Some(PointIdOptions::Uuid(uuid::Uuid::new_v4().to_string())),
+        })
+    }
+
+    #[test]
+    fn test_content_document_round_trip() {
+        let payload = Payload {
+            lang: "rust".to_owned(),
+            repo_name: "github.com/bloopai/bloop".to_owned(),
+            repo_ref: "github.com/bloopai/bloop".to_owned(),
+            relative_path: "src/lib.rs".to_owned(),
+            text: "fn main() {\n    run();\n}\n".to_owned(),
+            end_line: 3,
+            end_byte: 25,
+            branches: vec!["main".to_owned(), "dev".to_owned()],
+            lfs_pointer: true,
+            ..Default::default()
+        };
+
+        let doc = payload.to_content_document();
+        assert_eq!(doc.content, payload.text);
+        assert_eq!(doc.lang.as_deref(), Some("rust"));
+        assert_eq!(doc.relative_path, payload.relative_path);
+        assert_eq!(doc.repo_name, payload.repo_name);
+        assert_eq!(doc.repo_ref, payload.repo_ref);
+        assert_eq!(doc.line_end_indices, [11, 22, 24]);
+        assert_eq!(doc.branches.as_deref(), Some("main\ndev"));
+        assert!(doc.lfs_pointer);
+
+        assert_eq!(doc.to_semantic_payload(), payload);
+
+        // Documents that aren't in any language or branch round-trip as well.
+        let doc = ContentDocument {
+            content: "MIT".to_owned(),
+            relative_path: "LICENSE".to_owned(),
+            ..Default::default()
+        };
+        let payload = doc.to_semantic_payload();
+        assert_eq!(payload.lang, "");
+        assert!(payload.branches.is_empty());
+
+        let round_trip = payload.to_content_document();
+        assert_eq!(round_trip.lang, None);
+        assert_eq!(round_trip.branches, None);
+        assert_eq!(round_trip.content, doc.content);
+        assert_eq!(round_trip.line_end_indices, doc.line_end_indices);
+    }
+
+    #[test]
+    fn test_symbol_path_round_trip() {
+        let payload = Payload {
+            lang: "rust".to_owned(),
+            relative_path: "src/agent.rs".to_owned(),
+            text: "fn code_search() {}".to_owned(),
+            branches: vec!["main".to_owned()],
+            symbol_path: Some("Agent::code_search".to_owned()),
+            ..Default::default()
+        };
+
+        let parsed = parse_payload(point_id(), None, payload.clone().into_qdrant(), 0.5);
+        assert_eq!(parsed, payload);
+        assert_eq!(parsed.symbol_path.as_deref(), Some("Agent::code_search"));
+    }
+
+    #[test]
+    fn test_lfs_pointer_round_trip() {
+        let payload = Payload {
+            relative_path: "assets/model.onnx".to_owned(),
+            lfs_pointer: true,
+            ..Default::default()
+        };
+
+        let parsed = parse_payload(point_id(), None, payload.clone().into_qdrant(), 0.5);
+        assert_eq!(parsed, payload);
+        assert!(parsed.lfs_pointer);
+
+        let regular = Payload::default().into_qdrant();
+        assert!(!regular.contains_key("lfs_pointer"));
+        assert!(!parse_payload(point_id(), None, regular, 0.5).lfs_pointer);
+    }
+
+    #[test]
+    fn test_old_payload_without_symbol_path() {
+        let mut stored = Payload {
+            lang: "rust".to_owned(),
+            relative_path: "src/agent.rs".to_owned(),
+            branches: vec!["main".to_owned()],
+            ..Default::default()
+        }
+        .into_qdrant();
+        stored.remove("symbol_path");
+
+        let parsed = parse_payload(point_id(), None, stored, 0.5);
+        assert_eq!(parsed.relative_path, "src/agent.rs");
+        assert_eq!(parsed.symbol_path, None);
+
+        let json = serde_json::json!({
+            "lang": "rust",
+            "repo_name": "bloop",
+            "repo_ref": "local//bloop",
+            "relative_path": "src/agent.rs",
+            "content_hash": "",
+            "text": "",
+            "start_line": 0,
+            "end_line": 1,
+            "start_byte": 0,
+            "end_byte": 10,
+            "branches": ["main"],
+        });
+        let payload = serde_json::from_value::<Payload>(json).unwrap();
+        assert_eq!(payload.symbol_path, None);
+    }
+
+    #[test]
+    fn test_file_scope_symbol_path() {
+        let src = "use std::fmt;\n\nimpl Foo {\n    fn bar() {}\n}\n";
+        let file = TreeSitterFile::try_build(src.as_bytes(), "Rust").unwrap();
+
+        assert_eq!(symbol_path(&file, 0..13), FILE_SCOPE);
+        let bar = src.find("fn bar").unwrap();
+        assert_eq!(symbol_path(&file, bar..bar + 11), "Foo::bar");
+    }
+
+    #[test]
+    fn test_bm25_tokens() {
+        assert_eq!(
+            bm25_tokens("fn parseQuery(raw_input: &str) -> HTTPResult"),
+            ["fn", "parse", "query", "raw", "input", "str", "httpresult"]
+        );
+    }
+
+    #[test]
+    fn test_rerank() {
+        let results = vec![
+            payload("src/auth.rs", "fn login(user: &User) -> Session", 0.82),
+            payload("src/token.rs", "fn refresh_token(t: Token) -> Token", 0.80),
+            payload("src/config.rs", "struct Config { port: u16 }", 0.60),
+        ];
+
+        // The lexical match overtakes the slightly more similar result.
+        let reranked = rerank("refresh token", results.clone());
+        assert_eq!(reranked[0].relative_path, "src/token.rs");
+        assert_eq!(reranked[1].relative_path, "src/auth.rs");
+
+        // A weight of 0 keeps the original similarity ordering and scores.
+        let unchanged = rerank_weighted("refresh token", results.clone(), 0.);
+        assert_eq!(unchanged, results);
+        assert_eq!(unchanged[0].score, Some(0.82));
+
+        // A query without terms leaves results as they are.
+        assert_eq!(rerank("  ", results.clone()), results);
+    }
+
+    #[test]
+    fn test_retain_phrases() {
+        let results = vec![
+            payload("src/pool.rs", "struct ConnectionPool; // a Connection Pool", 0.9),
+            payload("src/conn.rs", "fn connection() -> Pool", 0.8),
+            payload("src/idle.rs", "// idle connection pool, max 10", 0.7),
+        ];
+
+        let query = crate::query::parser::parse_nl(r#""connection pool" "max 10""#)
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        let retained = retain_phrases(&query, results.clone());
+        assert_eq!(retained, [results[2].clone()]);
+
+        // Phrases ignore case.
+        let query = crate::query::parser::parse_nl(r#"pools "CONNECTION POOL""#)
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        let retained = retain_phrases(&query, results.clone());
+        assert_eq!(retained, [results[0].clone(), results[2].clone()]);
+
+        // Queries without phrases keep every result.
+        let query = crate::query::parser::parse_nl("connection pool")
+            .unwrap()
+            .into_semantic()
+            .unwrap();
+        assert_eq!(retain_phrases(&query, results.clone()), results);
+    }
+
+    #[test]
+    fn test_mmr_rerank() {
+        let embedded = |relative_path: &str, score: f32, embedding: Vec<f32>| Payload {
+            embedding: Some(embedding),
+            ..payload(relative_path, relative_path, score)
+        };
+
+        let results = vec![
+            embedded("src/a.rs", 0.90, vec![1., 0., 0.]),
+            // The next chunk of the same file, with nearly the same content.
+            embedded("src/a.rs", 0.89, vec![0.99, 0.14, 0.]),
+            embedded("src/b.rs", 0.60, vec![0., 1., 0.]),
+            payload("src/c.rs", "", 0.95),
+        ];
+        let paths = |results: &[Payload]| {
+            results
+                .iter()
+                .map(|r| (r.relative_path.clone(), r.score.unwrap()))
+                .collect::<Vec<_>>()
+        };
+
+        // The less relevant result from another file is preferred over the duplicate.
+        let reranked = mmr_rerank(vec![1., 0., 0.], results.clone(), DEFAULT_MMR_LAMBDA);
+        assert_eq!(
+            paths(&reranked),
+            [
+                ("src/a.rs".to_owned(), 0.90),
+                ("src/b.rs".to_owned(), 0.60),
+                ("src/a.rs".to_owned(), 0.89),
+                ("src/c.rs".to_owned(), 0.95),
+            ]
+        );
+
+        // Without any weight on diversity, results are ordered by relevance alone.
+        let reranked = mmr_rerank(vec![1., 0., 0.], results, 1.);
+        assert_eq!(
+            paths(&reranked)
+                .into_iter()
+                .map(|(_, score)| score)
+                .collect::<Vec<_>>(),
+            [0.90, 0.89, 0.60, 0.95]
+        );
+    }
+}
@@ -42,6 +42,7 @@ pub async fn execute(
                 line_range: payload.start_line as usize..payload.end_line as usize,
                 highlights: vec![],
                 symbols: vec![],
+                symbol_path: payload.symbol_path,
             });
 
             acc
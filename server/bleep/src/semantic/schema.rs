@@ -13,6 +13,15 @@ pub struct Payload {
     pub start_byte: u64,
     pub end_byte: u64,
     pub branches: Vec<String>,
+    /// The symbols that enclose this chunk, like `Agent::code_search`.
+    ///
+    /// This is `FILE_SCOPE` for chunks outside any symbol, and `None` for chunks indexed before
+    /// symbol paths were recorded, or in unsupported languages.
+    #[serde(default)]
+    pub symbol_path: Option<String>,
+    /// Whether this chunk is from a Git LFS pointer, which was indexed without its object.
+    #[serde(default)]
+    pub lfs_pointer: bool,
 
     #[serde(skip)]
     pub id: Option<String>,
@@ -35,6 +44,8 @@ impl PartialEq for Payload {
             && self.start_byte == other.start_byte
             && self.end_byte == other.end_byte
             && self.branches == other.branches
+            && self.symbol_path == other.symbol_path
+            && self.lfs_pointer == other.lfs_pointer
 
         // ignoring deserialized fields that will not exist on a newly
         // created payload
@@ -0,0 +1,652 @@
+//! Vector storage backends.
+//!
+//! Qdrant is used by default. Small deployments can instead use the embedded store, which keeps
+//! all vectors in memory and searches them exhaustively.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use qdrant_client::{
+    prelude::{QdrantClient, QdrantClientConfig},
+    qdrant::{
+        with_payload_selector, with_vectors_selector, CollectionOperationResponse, FieldType,
+        Filter, PointId, PointStruct, SearchPoints, WithPayloadSelector, WithVectorsSelector,
+    },
+};
+use tracing::{debug, warn};
+
+use super::{
+    build_conditions, build_exclusions, collection_config, make_kv_keyword_filter,
+    matches_conditions, Embedding, Payload, SemanticError, COLLECTION_NAME,
+};
+use crate::query::parser::SemanticQuery;
+
+/// The number of points in a single repository above which the embedded store gets slow.
+const COMFORTABLE_SIZE: usize = 50_000;
+
+#[derive(
+    Debug,
+    Default,
+    Copy,
+    Clone,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// A Qdrant server, running at `qdrant_url`
+    #[default]
+    Qdrant,
+    /// A file-backed store in the index directory
+    Embedded,
+}
+
+/// An embedded chunk, with its payload.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Point {
+    pub id: String,
+    pub vector: Embedding,
+    pub payload: Payload,
+}
+
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    async fn health_check(&self) -> Result<()>;
+
+    /// Find the points closest to `vector` that match the filters of `query`, best first.
+    ///
+    /// Returned payloads include their ID, score and embedding.
+    async fn search(
+        &self,
+        query: &SemanticQuery<'_>,
+        vector: Embedding,
+        limit: u64,
+        offset: u64,
+        threshold: f32,
+    ) -> Result<Vec<Payload>>;
+
+    /// Insert points, replacing any existing points with the same ID.
+    async fn upsert(&self, points: Vec<Point>) -> Result<()>;
+
+    async fn delete(&self, ids: Vec<String>) -> Result<()>;
+
+    /// Replace the list of branches in which points are searchable.
+    async fn set_branches(&self, ids: Vec<String>, branches: Vec<String>) -> Result<()>;
+
+    /// Delete the points of a repository with one of `file_hashes`, or all of its points if
+    /// `file_hashes` is empty.
+    async fn delete_files(&self, repo_ref: &str, file_hashes: Vec<String>) -> Result<()>;
+}
+
+pub struct Qdrant {
+    client: QdrantClient,
+}
+
+impl Qdrant {
+    /// Connect to a Qdrant server, creating the collection and its indexes if needed.
+    pub async fn connect(url: &str) -> Result<Self, SemanticError> {
+        let client = QdrantClient::new(Some(QdrantClientConfig::from_url(url))).unwrap();
+
+        match client.has_collection(COLLECTION_NAME).await {
+            Ok(false) => {
+                let CollectionOperationResponse { result, time } = client
+                    .create_collection(&collection_config())
+                    .await
+                    .unwrap();
+
+                debug!(
+                    time,
+                    created = result,
+                    name = COLLECTION_NAME,
+                    "created qdrant collection"
+                );
+
+                assert!(result);
+            }
+            Ok(true) => {}
+            Err(_) => return Err(SemanticError::QdrantInitializationError),
+        }
+
+        for field in ["repo_ref", "content_hash", "branches", "relative_path"] {
+            client
+                .create_field_index(COLLECTION_NAME, field, FieldType::Text, None, None)
+                .await?;
+        }
+
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl VectorStore for Qdrant {
+    async fn health_check(&self) -> Result<()> {
+        self.client.health_check().await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        query: &SemanticQuery<'_>,
+        vector: Embedding,
+        limit: u64,
+        offset: u64,
+        threshold: f32,
+    ) -> Result<Vec<Payload>> {
+        let response = self
+            .client
+            .search_points(&SearchPoints {
+                limit,
+                vector,
+                collection_name: COLLECTION_NAME.to_string(),
+                offset: Some(offset),
+                score_threshold: Some(threshold),
+                with_payload: Some(WithPayloadSelector {
+                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
+                }),
+                filter: Some(Filter {
+                    must: build_conditions(query),
+                    must_not: build_exclusions(query),
+                    ..Default::default()
+                }),
+                with_vectors: Some(WithVectorsSelector {
+                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
+                }),
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(response
+            .result
+            .into_iter()
+            .map(Payload::from_qdrant)
+            .collect())
+    }
+
+    async fn upsert(&self, points: Vec<Point>) -> Result<()> {
+        // qdrant doesn't like empty payloads.
+        if points.is_empty() {
+            return Ok(());
+        }
+
+        let points = points
+            .into_iter()
+            .map(|point| PointStruct {
+                id: Some(PointId::from(point.id)),
+                vectors: Some(point.vector.into()),
+                payload: point.payload.into_qdrant(),
+            })
+            .collect();
+
+        self.client
+            .upsert_points_blocking(COLLECTION_NAME, points, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, ids: Vec<String>) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        self.client
+            .delete_points(
+                COLLECTION_NAME,
+                &ids.into_iter()
+                    .map(PointId::from)
+                    .collect::<Vec<_>>()
+                    .into(),
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_branches(&self, ids: Vec<String>, branches: Vec<String>) -> Result<()> {
+        let ids = ids
+            .into_iter()
+            .map(PointId::from)
+            .collect::<Vec<_>>()
+            .into();
+
+        let payload = qdrant_client::client::Payload::new_from_hashmap(
+            [("branches".to_string(), branches.into())].into(),
+        );
+
+        self.client
+            .set_payload_blocking(COLLECTION_NAME, &ids, payload, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_files(&self, repo_ref: &str, file_hashes: Vec<String>) -> Result<()> {
+        let repo_filter = make_kv_keyword_filter("repo_ref", repo_ref).into();
+        let file_filter = file_hashes
+            .iter()
+            .map(|hash| make_kv_keyword_filter("content_hash", hash).into())
+            .collect::<Vec<_>>();
+
+        let selector = Filter {
+            must: vec![repo_filter],
+            should: file_filter,
+            ..Default::default()
+        }
+        .into();
+
+        self.client
+            .delete_points(COLLECTION_NAME, &selector, None)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// A change to the embedded store, as recorded in its log.
+#[derive(serde::Serialize, serde::Deserialize)]
+enum Op {
+    Upsert(Vec<Point>),
+    Delete(Vec<String>),
+    SetBranches(Vec<String>, Vec<String>),
+    DeleteFiles(String, Vec<String>),
+}
+
+/// A vector store that searches all points exhaustively.
+///
+/// Points are kept in memory, and changes are appended to a log file which is compacted on
+/// startup. This is fast enough for repositories of up to tens of thousands of chunks.
+pub struct Embedded {
+    points: RwLock<HashMap<String, Point>>,
+    log: Mutex<BufWriter<File>>,
+    comfortable_size: usize,
+    oversized: Mutex<HashSet<String>>,
+}
+
+impl Embedded {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_size(path, COMFORTABLE_SIZE)
+    }
+
+    fn open_with_size(path: impl AsRef<Path>, comfortable_size: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let mut points = HashMap::new();
+
+        if path.exists() {
+            let mut reader = BufReader::new(File::open(path)?);
+            while !reader.fill_buf()?.is_empty() {
+                match bincode::deserialize_from(&mut reader) {
+                    Ok(op) => apply(&mut points, op),
+                    Err(err) => {
+                        warn!(?err, "truncated vector store log, discarding the rest");
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Rewrite the log as a single snapshot, which also drops a truncated trailing entry.
+        let snapshot = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&snapshot)?);
+            bincode::serialize_into(&mut writer, &Op::Upsert(points.values().cloned().collect()))?;
+            writer.flush()?;
+        }
+        std::fs::rename(&snapshot, path)?;
+
+        debug!(points = points.len(), ?path, "opened embedded vector store");
+
+        Ok(Self {
+            points: RwLock::new(points),
+            log: Mutex::new(BufWriter::new(OpenOptions::new().append(true).open(path)?)),
+            comfortable_size,
+            oversized: Mutex::default(),
+        })
+    }
+
+    /// Log a change, and then apply it.
+    fn write(&self, op: Op) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        bincode::serialize_into(&mut *log, &op)?;
+        log.flush()?;
+
+        apply(&mut self.points.write().unwrap(), op);
+        Ok(())
+    }
+
+    /// Warn once when a repository grows past the size that this store handles well.
+    ///
+    /// This returns whether a warning was logged.
+    fn check_size(&self, repo_ref: &str) -> bool {
+        let points = self.points.read().unwrap();
+
+        // A repository is never larger than the whole store, so this skips counting points in
+        // the common case.
+        if points.len() <= self.comfortable_size {
+            return false;
+        }
+
+        let size = points
+            .values()
+            .filter(|p| p.payload.repo_ref == repo_ref)
+            .count();
+
+        if size <= self.comfortable_size || !self.oversized.lock().unwrap().insert(repo_ref.into())
+        {
+            return false;
+        }
+
+        warn!(
+            repo_ref,
+            size,
+            limit = self.comfortable_size,
+            "repository is large for the embedded vector store, consider using Qdrant instead"
+        );
+
+        true
+    }
+}
+
+fn apply(points: &mut HashMap<String, Point>, op: Op) {
+    match op {
+        Op::Upsert(new) => points.extend(new.into_iter().map(|p| (p.id.clone(), p))),
+        Op::Delete(ids) => {
+            for id in ids {
+                points.remove(&id);
+            }
+        }
+        Op::SetBranches(ids, branches) => {
+            for id in ids {
+                if let Some(point) = points.get_mut(&id) {
+                    point.payload.branches = branches.clone();
+                }
+            }
+        }
+        Op::DeleteFiles(repo_ref, file_hashes) => points.retain(|_, p| {
+            p.payload.repo_ref != repo_ref
+                || !(file_hashes.is_empty() || file_hashes.contains(&p.payload.content_hash))
+        }),
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    match norm(a) * norm(b) {
+        n if n == 0.0 => 0.0,
+        n => dot / n,
+    }
+}
+
+#[async_trait]
+impl VectorStore for Embedded {
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        query: &SemanticQuery<'_>,
+        vector: Embedding,
+        limit: u64,
+        offset: u64,
+        threshold: f32,
+    ) -> Result<Vec<Payload>> {
+        let points = self.points.read().unwrap();
+
+        let mut scored = points
+            .values()
+            .filter(|p| matches_conditions(query, &p.payload))
+            .filter_map(|p| {
+                let score = cosine_similarity(&vector, &p.vector);
+                (score >= threshold).then_some((p, score))
+            })
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        Ok(scored
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(p, score)| Payload {
+                id: Some(p.id.clone()),
+                embedding: Some(p.vector.clone()),
+                score: Some(score),
+                ..p.payload.clone()
+            })
+            .collect())
+    }
+
+    async fn upsert(&self, points: Vec<Point>) -> Result<()> {
+        let repos = points
+            .iter()
+            .map(|p| p.payload.repo_ref.clone())
+            .collect::<HashSet<_>>();
+
+        self.write(Op::Upsert(points))?;
+
+        for repo_ref in repos {
+            self.check_size(&repo_ref);
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, ids: Vec<String>) -> Result<()> {
+        self.write(Op::Delete(ids))
+    }
+
+    async fn set_branches(&self, ids: Vec<String>, branches: Vec<String>) -> Result<()> {
+        self.write(Op::SetBranches(ids, branches))
+    }
+
+    async fn delete_files(&self, repo_ref: &str, file_hashes: Vec<String>) -> Result<()> {
+        self.write(Op::DeleteFiles(repo_ref.to_owned(), file_hashes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::query::parser::Literal;
+
+    fn point(id: &str, vector: [f32; 2], repo: &str, path: &str, lang: &str) -> Point {
+        Point {
+            id: id.to_owned(),
+            vector: vector.to_vec(),
+            payload: Payload {
+                lang: lang.to_owned(),
+                repo_name: format!("github.com/{repo}"),
+                repo_ref: format!("github.com/{repo}"),
+                relative_path: path.to_owned(),
+                content_hash: format!("hash-{path}"),
+                branches: vec!["main".to_owned()],
+                ..Default::default()
+            },
+        }
+    }
+
+    fn query<'a>(repos: &[&'a str], paths: &[&'a str], langs: &[&'a str]) -> SemanticQuery<'a> {
+        SemanticQuery {
+            repos: repos.iter().map(|r| Literal::Plain((*r).into())).collect(),
+            paths: paths.iter().map(|p| Literal::Plain((*p).into())).collect(),
+            langs: langs.iter().map(|l| (*l).into()).collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn search(store: &Embedded, query: &SemanticQuery<'_>) -> Vec<String> {
+        store
+            .search(query, vec![1.0, 0.0], 10, 0, 0.0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|p| p.id.unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_embedded_store() {
+        let dir = TempDir::new("test-embedded-store").unwrap();
+        let path = dir.path().join("vectors.bin");
+        let store = Embedded::open(&path).unwrap();
+
+        store
+            .upsert(vec![
+                point("a", [1.0, 0.0], "bloopai/bloop", "server/lib.rs", "rust"),
+                point("b", [0.8, 0.6], "bloopai/bloop", "client/App.tsx", "tsx"),
+                point("c", [0.6, 0.8], "bloopai/other", "src/lib.rs", "rust"),
+                point("d", [-1.0, 0.0], "bloopai/bloop", "server/main.rs", "rust"),
+            ])
+            .await
+            .unwrap();
+
+        // Results are ordered by similarity, and can be paged and thresholded.
+        assert_eq!(search(&store, &query(&[], &[], &[])).await, ["a", "b", "c"]);
+        let page = store
+            .search(&query(&[], &[], &[]), vec![1.0, 0.0], 1, 1, 0.0)
+            .await
+            .unwrap();
+        assert_eq!(page[0].id.as_deref(), Some("b"));
+        assert!((page[0].score.unwrap() - 0.8).abs() < 1e-6);
+        assert_eq!(page[0].embedding.as_deref(), Some(&[0.8, 0.6][..]));
+
+        // Filters by repository, path and language.
+        assert_eq!(
+            search(&store, &query(&["bloopai/bloop"], &[], &[])).await,
+            ["a", "b"]
+        );
+        assert_eq!(
+            search(&store, &query(&[], &["lib.rs"], &[])).await,
+            ["a", "c"]
+        );
+        assert_eq!(search(&store, &query(&[], &[], &["tsx"])).await, ["b"]);
+
+        // Filters by branch.
+        store
+            .set_branches(vec!["b".into()], vec!["dev".into()])
+            .await
+            .unwrap();
+        let dev = SemanticQuery {
+            branch: [Literal::Plain("dev".into())].into(),
+            ..Default::default()
+        };
+        assert_eq!(search(&store, &dev).await, ["b"]);
+
+        // Deletes by ID, and by file.
+        store.delete(vec!["c".into()]).await.unwrap();
+        store
+            .delete_files(
+                "github.com/bloopai/bloop",
+                vec!["hash-client/App.tsx".into()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(search(&store, &query(&[], &[], &[])).await, ["a"]);
+
+        // Changes persist across restarts.
+        drop(store);
+        let store = Embedded::open(&path).unwrap();
+        assert_eq!(search(&store, &query(&[], &[], &[])).await, ["a"]);
+        assert_eq!(search(&store, &dev).await, Vec::<String>::new());
+
+        // Deleting without file hashes deletes the whole repository.
+        store
+            .delete_files("github.com/bloopai/bloop", vec![])
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .search(&query(&[], &[], &[]), vec![-1.0, 0.0], 10, 0, -1.0)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_embedded_store_exclusions() {
+        let dir = TempDir::new("test-embedded-store-exclusions").unwrap();
+        let store = Embedded::open(dir.path().join("vectors.bin")).unwrap();
+
+        store
+            .upsert(vec![
+                point("a", [1.0, 0.0], "bloopai/bloop", "server/pool.rs", "rust"),
+                point(
+                    "b",
+                    [0.8, 0.6],
+                    "bloopai/bloop",
+                    "server/tests/pool.rs",
+                    "rust",
+                ),
+                point("c", [0.6, 0.8], "bloopai/bloop", "client/pool.go", "go"),
+                point("d", [0.0, 1.0], "bloopai/other", "src/pool.rs", "rust"),
+            ])
+            .await
+            .unwrap();
+
+        let parse = |q| {
+            crate::query::parser::parse_nl(q)
+                .unwrap()
+                .into_semantic()
+                .unwrap()
+        };
+
+        assert_eq!(
+            search(&store, &parse("connection pool")).await,
+            ["a", "b", "c", "d"]
+        );
+        assert_eq!(
+            search(&store, &parse("connection pool -path:tests")).await,
+            ["a", "c", "d"]
+        );
+        assert_eq!(
+            search(&store, &parse("connection pool -path:tests -lang:go")).await,
+            ["a", "d"]
+        );
+        assert_eq!(
+            search(
+                &store,
+                &parse("connection pool -repo:bloopai/other lang:rust")
+            )
+            .await,
+            ["a", "b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_size_warning() {
+        let dir = TempDir::new("test-embedded-store-size").unwrap();
+        let store = Embedded::open_with_size(dir.path().join("vectors.bin"), 2).unwrap();
+
+        let points = ["a", "b", "c"].map(|id| point(id, [1.0, 0.0], "bloopai/bloop", id, "rust"));
+        store.upsert(points[..2].to_vec()).await.unwrap();
+        assert!(!store.check_size("github.com/bloopai/bloop"));
+
+        store.upsert(points[2..].to_vec()).await.unwrap();
+        assert!(store
+            .oversized
+            .lock()
+            .unwrap()
+            .contains("github.com/bloopai/bloop"));
+
+        // The warning is only logged once.
+        assert!(!store.check_size("github.com/bloopai/bloop"));
+    }
+}
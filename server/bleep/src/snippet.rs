@@ -21,6 +21,9 @@ pub struct Snippet {
     pub highlights: Vec<Range<usize>>,
     pub symbols: Vec<Symbol>,
     pub line_range: Range<usize>,
+    /// The symbols that enclose this snippet, for snippets found by semantic search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol_path: Option<String>,
 }
 
 /// A marker indicating a subset of some source text, with a list of highlighted ranges.
@@ -86,6 +89,7 @@ impl Location {
                     sym
                 })
                 .collect(),
+            symbol_path: None,
         }
     }
 
@@ -354,6 +358,7 @@ mod tests {
                 line_range: 0..0,
                 highlights: vec![0..3],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -371,6 +376,7 @@ mod tests {
                 line_range: 2..4,
                 highlights: vec![4..7],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -388,6 +394,7 @@ mod tests {
                 line_range: 0..2,
                 highlights: vec![5..8],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -405,6 +412,7 @@ mod tests {
                 line_range: 0..2,
                 highlights: vec![4..7],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -422,6 +430,7 @@ mod tests {
                 line_range: 1..2,
                 highlights: vec![4..7],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -440,6 +449,7 @@ mod tests {
                 line_range: 0..0,
                 highlights: vec![0..3],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -457,6 +467,7 @@ mod tests {
                 line_range: 2..3,
                 highlights: vec![0..3],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
@@ -475,6 +486,7 @@ mod tests {
                 line_range: 2..3,
                 highlights: vec![0..3],
                 symbols: vec![],
+                symbol_path: None,
             }
         );
     }
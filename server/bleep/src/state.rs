@@ -137,6 +137,10 @@ impl StateSource {
         Ok(val)
     }
 
+    pub(crate) fn state_file(&self) -> Option<&Path> {
+        self.state_file.as_deref()
+    }
+
     pub(crate) fn repo_dir(&self) -> Option<PathBuf> {
         self.directory.clone()
     }
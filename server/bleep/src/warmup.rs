@@ -0,0 +1,264 @@
+//! Pre-warming of search state for frequently used repositories.
+//!
+//! The first query against a repository pays for paging in tantivy index segments, running the
+//! embedding model for the first time, and establishing the Qdrant connection. Warming up "hot"
+//! repositories ahead of time moves that cost out of the request path.
+
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{query::parser, repo::RepoRef, Application};
+
+/// The maximum number of repositories that are warmed up concurrently.
+const PARALLELISM: usize = 4;
+
+/// Progress of the warmup routine.
+pub struct Warmup {
+    /// The number of repositories that are still being warmed up.
+    pending: AtomicUsize,
+
+    /// Set once the startup warmup has finished.
+    ready: AtomicBool,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq, Eq)]
+pub struct WarmupStatus {
+    pub ready: bool,
+    pub pending: usize,
+}
+
+impl Warmup {
+    /// Create a new progress tracker.
+    ///
+    /// If warmup is disabled, this reports that we are ready from the start.
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            pending: AtomicUsize::new(0),
+            ready: AtomicBool::new(!enabled),
+        }
+    }
+
+    pub fn status(&self) -> WarmupStatus {
+        WarmupStatus {
+            ready: self.ready.load(Ordering::SeqCst),
+            pending: self.pending.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Warm up all configured repositories, and report readiness once done.
+pub async fn startup(app: Application) {
+    let mut repos = vec![];
+    app.repo_pool.scan_async(|k, _| repos.push(k.clone())).await;
+    repos.retain(|repo_ref| is_hot(&app.config.warmup_repos, repo_ref));
+
+    if !repos.is_empty() {
+        let start = Instant::now();
+        let count = repos.len();
+        warm_all(&app, &app.warmup, repos, true).await;
+        info!(count, elapsed = ?start.elapsed(), "finished warming up repositories");
+    }
+
+    app.warmup.ready.store(true, Ordering::SeqCst);
+}
+
+/// Warm up a single repository after it has been indexed, if it is configured as hot.
+pub async fn repo(app: Application, repo_ref: RepoRef) {
+    if is_hot(&app.config.warmup_repos, &repo_ref) {
+        warm_all(&app, &app.warmup, vec![repo_ref], false).await;
+    }
+}
+
+/// Whether a repository is selected by the configured list of hot repositories.
+///
+/// `*` selects all repositories.
+fn is_hot(patterns: &[String], repo_ref: &RepoRef) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| pattern == "*" || *pattern == repo_ref.to_string())
+}
+
+/// Search state that can be warmed up ahead of the first query.
+#[async_trait]
+trait Warmable: Sync {
+    /// Warm up state that is shared between repositories.
+    async fn warm_shared(&self) -> Result<()>;
+
+    /// Warm up the state of a single repository.
+    async fn warm_repo(&self, repo_ref: &RepoRef) -> Result<()>;
+}
+
+#[async_trait]
+impl Warmable for Application {
+    async fn warm_shared(&self) -> Result<()> {
+        let Some(semantic) = self.semantic.as_ref() else {
+            return Ok(());
+        };
+
+        // The first run of the embedder is significantly slower than subsequent ones.
+        tokio::task::spawn_blocking({
+            let semantic = semantic.clone();
+            move || semantic.embed("warmup")
+        })
+        .await??;
+
+        semantic.health_check().await
+    }
+
+    async fn warm_repo(&self, repo_ref: &RepoRef) -> Result<()> {
+        // Page in the file index segments for this repository.
+        let _ = self
+            .indexes
+            .file
+            .fuzzy_path_match(repo_ref, "readme", None, 1)
+            .await
+            .count();
+
+        if let Some(semantic) = self.semantic.as_ref() {
+            let query = parser::SemanticQuery {
+                target: Some(parser::Literal::Plain("main".into())),
+                repos: [parser::Literal::Plain(repo_ref.display_name().into())].into(),
+                ..Default::default()
+            };
+
+            semantic.search(&query, 1, 0, 0.0, false).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn warm_all(target: &impl Warmable, warmup: &Warmup, repos: Vec<RepoRef>, shared: bool) {
+    warmup.pending.fetch_add(repos.len(), Ordering::SeqCst);
+
+    if shared {
+        if let Err(err) = target.warm_shared().await {
+            warn!(?err, "failed to warm up shared search state");
+        }
+    }
+
+    futures::stream::iter(repos)
+        .for_each_concurrent(PARALLELISM, |repo_ref| async move {
+            debug!(%repo_ref, "warming up repository");
+            if let Err(err) = target.warm_repo(&repo_ref).await {
+                warn!(?err, %repo_ref, "failed to warm up repository");
+            }
+
+            warmup.pending.fetch_sub(1, Ordering::SeqCst);
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Mutex, time::Duration};
+
+    use super::*;
+
+    const COLD_OPEN: Duration = Duration::from_millis(50);
+
+    /// An index that is slow to query the first time a repository is touched.
+    #[derive(Default)]
+    struct MockIndex {
+        open: Mutex<HashSet<RepoRef>>,
+        opened: AtomicUsize,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    impl MockIndex {
+        async fn query(&self, repo_ref: &RepoRef) {
+            let cold = self.open.lock().unwrap().insert(repo_ref.clone());
+            if cold {
+                tokio::time::sleep(COLD_OPEN).await;
+                self.opened.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Warmable for MockIndex {
+        async fn warm_shared(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn warm_repo(&self, repo_ref: &RepoRef) -> Result<()> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+
+            self.query(repo_ref).await;
+
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_is_hot() {
+        let repo_ref = "github.com/bloopai/bloop".parse::<RepoRef>().unwrap();
+
+        assert!(is_hot(&["*".to_owned()], &repo_ref));
+        assert!(is_hot(&["github.com/bloopai/bloop".to_owned()], &repo_ref));
+        assert!(!is_hot(&["github.com/bloopai/other".to_owned()], &repo_ref));
+        assert!(!is_hot(&[], &repo_ref));
+    }
+
+    #[tokio::test]
+    async fn test_warm_first_query_skips_cold_open() {
+        let index = MockIndex::default();
+        let warmup = Warmup::new(true);
+        let hot = "github.com/bloopai/hot".parse::<RepoRef>().unwrap();
+        let cold = "github.com/bloopai/cold".parse::<RepoRef>().unwrap();
+
+        assert!(!warmup.status().ready);
+        warm_all(&index, &warmup, vec![hot.clone()], true).await;
+        assert_eq!(warmup.status().pending, 0);
+
+        let start = Instant::now();
+        index.query(&cold).await;
+        let cold_latency = start.elapsed();
+
+        let start = Instant::now();
+        index.query(&hot).await;
+        let warm_latency = start.elapsed();
+
+        assert!(cold_latency >= COLD_OPEN);
+        assert!(warm_latency < COLD_OPEN);
+
+        // One open for the hot repository during warmup, and one for the cold repository.
+        assert_eq!(index.opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_parallelism_is_bounded() {
+        let index = MockIndex::default();
+        let warmup = Warmup::new(true);
+        let repos = (0..10)
+            .map(|i| format!("github.com/bloopai/repo{i}").parse().unwrap())
+            .collect::<Vec<RepoRef>>();
+
+        warm_all(&index, &warmup, repos, false).await;
+
+        assert_eq!(index.opened.load(Ordering::SeqCst), 10);
+        assert!(index.max_running.load(Ordering::SeqCst) <= PARALLELISM);
+        assert_eq!(warmup.status().pending, 0);
+    }
+
+    #[test]
+    fn test_disabled_warmup_is_ready() {
+        assert_eq!(
+            Warmup::new(false).status(),
+            WarmupStatus {
+                ready: true,
+                pending: 0
+            }
+        );
+    }
+}
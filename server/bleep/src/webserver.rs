@@ -1,4 +1,4 @@
-use crate::{env::Feature, Application};
+use crate::{env::Feature, indexes::IndexCorrupt, repo::RepoRef, Application};
 
 use axum::{
     http::StatusCode,
@@ -13,6 +13,7 @@ use tower_http::{catch_panic::CatchPanicLayer, cors::CorsLayer};
 use tracing::info;
 
 mod aaa;
+mod admin;
 pub mod answer;
 mod autocomplete;
 mod config;
@@ -22,9 +23,12 @@ mod hoverable;
 mod index;
 mod intelligence;
 pub mod middleware;
+mod openai;
+mod playbooks;
 mod query;
 pub mod repos;
 mod semantic;
+mod users;
 
 pub type Router<S = Application> = axum::Router<S>;
 
@@ -66,7 +70,20 @@ pub async fn start(app: Application) -> anyhow::Result<()> {
             "/answer/conversations/:thread_id",
             get(answer::conversations::thread),
         )
-        .route("/answer/vote", post(answer::vote));
+        .route("/threads/:thread_id/title", get(answer::conversations::title))
+        .route(
+            "/threads/:thread_id/export",
+            get(answer::conversations::export),
+        )
+        .route("/answer/snippets", get(answer::snippets::list))
+        .route("/answer/snippets/:snippet_id", get(answer::snippets::get))
+        .route("/answer/vote", post(answer::vote))
+        .route("/users/me/queries", get(users::queries))
+        // OpenAI-compatible clients
+        .route("/v1/chat/completions", post(openai::chat_completions))
+        .route("/playbooks/:name/run", post(playbooks::run))
+        // administration
+        .nest("/admin", admin::router(app.clone()));
 
     if app.env.allow(Feature::AnyPathScan) {
         api = api.route("/repos/scan", get(repos::scan_local));
@@ -132,6 +149,19 @@ where
     Json(Response::from(val))
 }
 
+/// Fail like a repository that was never indexed would, if `user` can't query `repo_ref`.
+fn check_repo_access(
+    app: &Application,
+    user: &middleware::User,
+    repo_ref: &RepoRef,
+) -> Result<()> {
+    if app.access.allows(user, repo_ref) {
+        Ok(())
+    } else {
+        Err(Error::new(ErrorKind::NotFound, "Can't find repository"))
+    }
+}
+
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 pub struct Error {
@@ -149,6 +179,7 @@ impl Error {
             | ErrorKind::Custom => StatusCode::INTERNAL_SERVER_ERROR,
             ErrorKind::User => StatusCode::BAD_REQUEST,
             ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::IndexCorrupt => StatusCode::SERVICE_UNAVAILABLE,
         };
 
         let body = Json(Response::from(EndpointError {
@@ -194,7 +225,19 @@ impl Error {
 
 impl From<anyhow::Error> for Error {
     fn from(value: anyhow::Error) -> Self {
-        Error::internal(value.to_string())
+        match value.downcast::<IndexCorrupt>() {
+            Ok(err) => err.into(),
+            Err(value) => Error::internal(value.to_string()),
+        }
+    }
+}
+
+impl From<IndexCorrupt> for Error {
+    fn from(value: IndexCorrupt) -> Self {
+        Error::new(
+            ErrorKind::IndexCorrupt,
+            format!("{value}. Rebuild it with `POST /repos/reindex?repo=<ref>&force=true`"),
+        )
     }
 }
 
@@ -226,6 +269,8 @@ pub enum ErrorKind {
     Configuration,
     UpstreamService,
     Internal,
+    /// An index can't be read, and has to be rebuilt.
+    IndexCorrupt,
 
     // TODO: allow construction of detailed custom kinds
     #[doc(hidden)]
@@ -256,10 +301,75 @@ impl<'a> From<EndpointError<'a>> for Response<'a> {
     }
 }
 
-async fn health(Extension(app): Extension<Application>) {
+/// Report whether the server is ready to accept traffic.
+///
+/// This responds with `503 Service Unavailable` until hot repositories have been warmed up.
+async fn health(Extension(app): Extension<Application>) -> impl IntoResponse {
     if let Some(ref semantic) = app.semantic {
         // panic is fine here, we don't need exact reporting of
         // subsystem checks at this stage
         semantic.health_check().await.unwrap()
     }
+
+    let warmup = app.warmup.status();
+    let status = if warmup.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(serde_json::json!({ "warmup": warmup })))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::Environment;
+
+    /// An application without background tasks or analytics, with the settings in `config`.
+    pub(crate) async fn app(
+        index_dir: &tempdir::TempDir,
+        config: serde_json::Value,
+    ) -> Application {
+        let mut defaults = serde_json::json!({
+            "index_dir": index_dir.path(),
+            "disable_background": true,
+            "disable_analytics": true,
+        });
+        if let (Some(defaults), serde_json::Value::Object(config)) =
+            (defaults.as_object_mut(), config)
+        {
+            defaults.extend(config);
+        }
+
+        Application::initialize(
+            Environment::insecure_local(),
+            serde_json::from_value(defaults).unwrap(),
+            None,
+            None,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// The status that `router` responds to `request` with, when it is made by `user`.
+    pub(crate) async fn status(
+        router: Router,
+        app: &Application,
+        user: middleware::User,
+        request: Request<Body>,
+    ) -> StatusCode {
+        router
+            .layer(Extension(user))
+            .layer(Extension(app.indexes.clone()))
+            .layer(Extension(app.clone()))
+            .with_state(app.clone())
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status()
+    }
 }
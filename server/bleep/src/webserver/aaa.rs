@@ -201,17 +201,15 @@ pub(super) async fn authorized(
         .expect("can't retrieve user name");
 
     app.with_analytics(|analytics| {
-        use rudderanalytics::message::{Identify, Message};
-        analytics.send(Message::Identify(Identify {
-            user_id: Some(analytics.tracking_id(Some(&user_name))),
-            traits: Some(serde_json::json!({
+        analytics.identify(
+            &user_name,
+            serde_json::json!({
                 "org_name": app.org_name(),
                 "device_id": analytics.device_id(),
                 "is_self_serve": app.env.is_cloud_instance(),
                 "github_username": user_name,
-            })),
-            ..Default::default()
-        }));
+            }),
+        );
     });
 
     (
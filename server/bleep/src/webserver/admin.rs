@@ -0,0 +1,867 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, Request},
+    middleware::{from_fn_with_state, Next},
+    response::Response,
+    routing::{delete, get, post, put},
+    Json,
+};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use secrecy::ExposeSecret;
+
+use super::{middleware::User, prelude::*};
+use crate::{
+    agent::{
+        canary::{self, CanaryReport},
+        exchange::AnswerOutcome,
+        few_shot,
+    },
+    db::{Faq, Faqs, PromptExample, PromptExamples, Usage, UsageRecord},
+    llm_gateway,
+    repo::RepoRef,
+    Application,
+};
+
+mod backup;
+mod runs;
+
+/// The admin API. Every route is only served to admins.
+pub(super) fn router(app: Application) -> Router {
+    Router::new()
+        .route("/usage", get(usage))
+        .route("/runs/export", get(runs::export))
+        .route("/backup", post(backup::backup))
+        .route("/restore", post(backup::restore))
+        .route("/faqs", get(list_faqs).post(create_faq))
+        .route("/faqs/:id", delete(delete_faq))
+        .route("/prompt-examples", get(list_prompt_examples))
+        .route(
+            "/prompt-examples/:id/pinned",
+            put(set_prompt_example_pinned),
+        )
+        .route("/selftest", post(selftest))
+        .route_layer(from_fn_with_state(app, require_admin))
+}
+
+async fn require_admin<B>(
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    if !app.access.is_admin(&user) {
+        return Err(
+            Error::user("only admins can use the admin API").with_status(StatusCode::FORBIDDEN)
+        );
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+pub(super) struct UsageParams {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    group_by: GroupBy,
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum GroupBy {
+    #[default]
+    User,
+    Repo,
+    Model,
+    Outcome,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Aggregated usage for a single group, on a single day.
+#[derive(Serialize, Debug, PartialEq)]
+struct UsageRow {
+    day: String,
+    key: String,
+    queries: usize,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    cost_usd: f64,
+    /// The mean latency of the group's LLM requests.
+    latency_mean_ms: i64,
+    latency_p95_ms: i64,
+    /// The number of queries by how they were answered. Queries that are still running, or
+    /// that were made before outcomes were recorded, are counted as `unknown`.
+    outcomes: BTreeMap<String, usize>,
+}
+
+/// The outcomes that are reported as columns of CSV reports.
+const OUTCOMES: &[AnswerOutcome] = &[
+    AnswerOutcome::Answered,
+    AnswerOutcome::Partial,
+    AnswerOutcome::NotFound,
+    AnswerOutcome::Clarification,
+    AnswerOutcome::Error,
+];
+
+/// A histogram bucket, counting LLM calls with at most `le` prompt tokens.
+///
+/// The last bucket has no upper bound.
+#[derive(Serialize, Debug, PartialEq)]
+struct Bucket {
+    le: Option<i64>,
+    count: usize,
+}
+
+#[derive(Serialize)]
+pub(super) struct UsageResponse {
+    rows: Vec<UsageRow>,
+    /// Prompt sizes of LLM calls, by stage.
+    histograms: BTreeMap<String, Vec<Bucket>>,
+}
+
+impl super::ApiResponse for UsageResponse {}
+
+/// Report token usage, estimated cost and latency, grouped by day and by a selected dimension.
+///
+/// This defaults to reporting the last 24 hours.
+pub(super) async fn usage(
+    State(app): State<Application>,
+    Query(params): Query<UsageParams>,
+) -> Result<Response> {
+    let to = params.to.unwrap_or_else(Utc::now);
+    let from = params.from.unwrap_or(to - Duration::days(1));
+
+    if from >= to {
+        return Err(Error::user("`from` must be earlier than `to`"));
+    }
+
+    let records = Usage::new(&app.sql)
+        .between(from.timestamp(), to.timestamp())
+        .await?;
+
+    let rows = aggregate(&records, params.group_by);
+
+    Ok(match params.format {
+        Format::Json => json(UsageResponse {
+            rows,
+            histograms: histograms(&records),
+        })
+        .into_response(),
+        Format::Csv => (
+            [(header::CONTENT_TYPE, "text/csv")],
+            to_csv(&rows, params.group_by),
+        )
+            .into_response(),
+    })
+}
+
+#[derive(Serialize)]
+pub(super) struct FaqList {
+    faqs: Vec<Faq>,
+}
+
+impl super::ApiResponse for FaqList {}
+
+pub(super) async fn list_faqs(State(app): State<Application>) -> Result<impl IntoResponse> {
+    let faqs = Faqs::new(&app.sql).list().await?;
+    Ok(json(FaqList { faqs }))
+}
+
+#[derive(Deserialize)]
+pub(super) struct NewFaq {
+    question: String,
+    /// A regex matched against user queries. If this is not set, queries are matched by the
+    /// similarity of their embedding to the question.
+    pattern: Option<String>,
+    /// Markdown text.
+    answer: String,
+    repo_ref: Option<RepoRef>,
+}
+
+#[derive(Serialize)]
+pub(super) struct FaqCreated {
+    id: i64,
+}
+
+impl super::ApiResponse for FaqCreated {}
+
+/// Create a canned answer, which is returned instead of running the agent on matching queries.
+pub(super) async fn create_faq(
+    State(app): State<Application>,
+    Json(faq): Json<NewFaq>,
+) -> Result<impl IntoResponse> {
+    if faq.question.trim().is_empty() || faq.answer.trim().is_empty() {
+        return Err(Error::user("`question` and `answer` must not be empty"));
+    }
+
+    if let Some(pattern) = &faq.pattern {
+        regex::Regex::new(pattern).map_err(|e| Error::user(format!("invalid pattern: {e}")))?;
+    }
+
+    let embedding = match app.semantic.clone() {
+        Some(semantic) => {
+            let question = faq.question.clone();
+            Some(
+                tokio::task::spawn_blocking(move || semantic.embed(&question))
+                    .await
+                    .map_err(Error::internal)??,
+            )
+        }
+        None => None,
+    };
+
+    let repo_ref = faq.repo_ref.as_ref().map(RepoRef::to_string);
+    let id = Faqs::new(&app.sql)
+        .insert(
+            &faq.question,
+            faq.pattern.as_deref(),
+            &faq.answer,
+            repo_ref.as_deref(),
+            embedding.as_deref(),
+        )
+        .await?;
+
+    Ok(json(FaqCreated { id }))
+}
+
+pub(super) async fn delete_faq(
+    State(app): State<Application>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode> {
+    if Faqs::new(&app.sql).delete(id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(Error::new(ErrorKind::NotFound, "FAQ not found"))
+    }
+}
+
+#[derive(Deserialize)]
+pub(super) struct PromptExampleParams {
+    repo_ref: Option<RepoRef>,
+}
+
+#[derive(Serialize)]
+pub(super) struct PromptExampleList {
+    examples: Vec<PromptExample>,
+}
+
+impl super::ApiResponse for PromptExampleList {}
+
+/// List few-shot prompt examples mined from upvoted exchanges, optionally for one repository.
+pub(super) async fn list_prompt_examples(
+    State(app): State<Application>,
+    Query(params): Query<PromptExampleParams>,
+) -> Result<impl IntoResponse> {
+    let store = PromptExamples::new(&app.sql);
+    let examples = match params.repo_ref {
+        Some(repo_ref) => store.for_repo(&repo_ref.to_string()).await?,
+        None => store.all().await?,
+    };
+
+    Ok(json(PromptExampleList { examples }))
+}
+
+#[derive(Deserialize)]
+pub(super) struct Pinned {
+    pinned: bool,
+}
+
+/// Pin or unpin a prompt example. Pinned examples are always shown to the model.
+pub(super) async fn set_prompt_example_pinned(
+    State(app): State<Application>,
+    Path(id): Path<i64>,
+    Json(Pinned { pinned }): Json<Pinned>,
+) -> Result<StatusCode> {
+    let repo_ref = PromptExamples::new(&app.sql)
+        .set_pinned(id, pinned)
+        .await?
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "prompt example not found"))?;
+
+    let repo_ref = repo_ref
+        .parse::<RepoRef>()
+        .map_err(|e| Error::internal(format!("invalid repo ref `{repo_ref}`: {e}")))?;
+    few_shot::select(&app.sql, &repo_ref).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl super::ApiResponse for CanaryReport {}
+
+/// Ask the canary question of `Configuration::canary`, reporting how each subsystem responded.
+///
+/// This responds with `503 Service Unavailable` if any subsystem failed or was too slow.
+pub(super) async fn selftest(State(app): State<Application>) -> Result<impl IntoResponse> {
+    let gh_token = app
+        .github_token()
+        .map_err(|e| Error::user(e).with_status(StatusCode::UNAUTHORIZED))?
+        .map(|s| s.expose_secret().clone());
+    let llm_gateway = llm_gateway::Client::new(&app.config.answer_api_url)
+        .temperature(0.0)
+        .bearer(gh_token)
+        .endpoints(app.llm_endpoints.clone());
+
+    let report = canary::run(&app, &app.config.canary, llm_gateway).await;
+    let status = if report.passed {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Ok((status, json(report)))
+}
+
+fn aggregate(records: &[UsageRecord], group_by: GroupBy) -> Vec<UsageRow> {
+    #[derive(Default)]
+    struct Acc {
+        queries: HashSet<String>,
+        latencies: Vec<i64>,
+        outcome_by_query: HashMap<String, String>,
+        prompt_tokens: i64,
+        completion_tokens: i64,
+        cost_usd: f64,
+    }
+
+    let mut groups = BTreeMap::<(String, String), Acc>::new();
+
+    for record in records {
+        let key = match group_by {
+            GroupBy::User => record.user_id.as_deref().unwrap_or("unknown"),
+            GroupBy::Repo => record.repo_ref.as_str(),
+            GroupBy::Model => record.model.as_str(),
+            GroupBy::Outcome => record.outcome.as_deref().unwrap_or("unknown"),
+        };
+
+        let acc = groups
+            .entry((day(record.created_at), key.to_owned()))
+            .or_default();
+
+        acc.queries.insert(record.query_id.clone());
+        acc.latencies.push(record.latency_ms);
+        if let Some(outcome) = &record.outcome {
+            acc.outcome_by_query
+                .insert(record.query_id.clone(), outcome.clone());
+        }
+        acc.prompt_tokens += record.prompt_tokens;
+        acc.completion_tokens += record.completion_tokens;
+        acc.cost_usd += cost_usd(
+            &record.model,
+            record.prompt_tokens,
+            record.completion_tokens,
+        );
+    }
+
+    groups
+        .into_iter()
+        .map(|((day, key), acc)| {
+            let mut outcomes = BTreeMap::<String, usize>::new();
+            for query_id in &acc.queries {
+                let outcome = acc.outcome_by_query.get(query_id).map(String::as_str);
+                *outcomes
+                    .entry(outcome.unwrap_or("unknown").to_owned())
+                    .or_default() += 1;
+            }
+
+            let mut latencies = acc.latencies;
+            latencies.sort_unstable();
+
+            UsageRow {
+                day,
+                key,
+                queries: acc.queries.len(),
+                prompt_tokens: acc.prompt_tokens,
+                completion_tokens: acc.completion_tokens,
+                cost_usd: acc.cost_usd,
+                latency_mean_ms: mean(&latencies),
+                latency_p95_ms: percentile(&latencies, 0.95),
+                outcomes,
+            }
+        })
+        .collect()
+}
+
+fn histograms(records: &[UsageRecord]) -> BTreeMap<String, Vec<Bucket>> {
+    const BOUNDS: &[i64] = &[512, 1024, 2048, 4096, 8192, 16384];
+
+    let mut histograms = BTreeMap::<String, Vec<Bucket>>::new();
+
+    for record in records {
+        let buckets = histograms.entry(record.stage.clone()).or_insert_with(|| {
+            BOUNDS
+                .iter()
+                .map(|le| Some(*le))
+                .chain([None])
+                .map(|le| Bucket { le, count: 0 })
+                .collect()
+        });
+
+        let idx = BOUNDS
+            .iter()
+            .position(|le| record.prompt_tokens <= *le)
+            .unwrap_or(BOUNDS.len());
+
+        buckets[idx].count += 1;
+    }
+
+    histograms
+}
+
+fn to_csv(rows: &[UsageRow], group_by: GroupBy) -> String {
+    let key = match group_by {
+        GroupBy::User => "user",
+        GroupBy::Repo => "repo",
+        GroupBy::Model => "model",
+        GroupBy::Outcome => "outcome",
+    };
+
+    let outcome_columns = OUTCOMES
+        .iter()
+        .map(|o| o.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut csv = format!(
+        "day,{key},queries,prompt_tokens,completion_tokens,cost_usd,latency_mean_ms,latency_p95_ms,\
+         {outcome_columns}\n"
+    );
+
+    for row in rows {
+        let outcome_counts = OUTCOMES
+            .iter()
+            .map(|o| {
+                row.outcomes
+                    .get(o.as_str())
+                    .copied()
+                    .unwrap_or(0)
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        csv += &format!(
+            "{},{},{},{},{},{:.4},{},{},{outcome_counts}\n",
+            csv_escape(&row.day),
+            csv_escape(&row.key),
+            row.queries,
+            row.prompt_tokens,
+            row.completion_tokens,
+            row.cost_usd,
+            row.latency_mean_ms,
+            row.latency_p95_ms,
+        );
+    }
+
+    csv
+}
+
+fn csv_escape(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\"")).into()
+    } else {
+        field.into()
+    }
+}
+
+/// The mean of a list, rounded down.
+fn mean(values: &[i64]) -> i64 {
+    if values.is_empty() {
+        return 0;
+    }
+
+    values.iter().sum::<i64>() / values.len() as i64
+}
+
+/// Nearest-rank percentile of a sorted list.
+fn percentile(sorted: &[i64], p: f64) -> i64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Estimated cost in USD, based on public per-1K-token pricing.
+fn cost_usd(model: &str, prompt_tokens: i64, completion_tokens: i64) -> f64 {
+    let (prompt, completion) = if model.starts_with("gpt-4-32k") {
+        (0.06, 0.12)
+    } else if model.starts_with("gpt-4") {
+        (0.03, 0.06)
+    } else if model.starts_with("gpt-3.5-turbo-16k") {
+        (0.003, 0.004)
+    } else if model.starts_with("gpt-3.5-turbo") {
+        (0.0015, 0.002)
+    } else {
+        (0.0, 0.0)
+    };
+
+    (prompt * prompt_tokens as f64 + completion * completion_tokens as f64) / 1000.0
+}
+
+fn day(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|dt| dt.date().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{acl::tests::user, webserver::tests::status};
+
+    /// An application whose only admin is `alice`.
+    async fn app(index_dir: &tempdir::TempDir) -> Application {
+        crate::webserver::tests::app(index_dir, serde_json::json!({ "acl_admins": ["alice"] }))
+            .await
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn with_json(method: &str, uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_usage_requires_admin() {
+        let index_dir = tempdir::TempDir::new("bleep-admin").unwrap();
+        let app = app(&index_dir).await;
+
+        let usage = |user| status(router(app.clone()), &app, user, get("/usage"));
+        assert_eq!(usage(user("bob")).await, StatusCode::FORBIDDEN);
+        assert_eq!(usage(User::Unknown).await, StatusCode::FORBIDDEN);
+        assert_eq!(usage(user("alice")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_faqs_require_admin() {
+        let index_dir = tempdir::TempDir::new("bleep-admin").unwrap();
+        let app = app(&index_dir).await;
+
+        let faq = serde_json::json!({
+            "question": "How do I get access?",
+            "answer": "Ask in #help.",
+        });
+        let faqs = |user, request| status(router(app.clone()), &app, user, request);
+
+        for user in [user("bob"), User::Unknown] {
+            let create = with_json("POST", "/faqs", faq.clone());
+            assert_eq!(faqs(user.clone(), create).await, StatusCode::FORBIDDEN);
+            assert_eq!(
+                faqs(user.clone(), get("/faqs")).await,
+                StatusCode::FORBIDDEN
+            );
+
+            let delete = Request::delete("/faqs/1").body(Body::empty()).unwrap();
+            assert_eq!(faqs(user, delete).await, StatusCode::FORBIDDEN);
+        }
+
+        let create = with_json("POST", "/faqs", faq);
+        assert_eq!(faqs(user("alice"), create).await, StatusCode::OK);
+        assert_eq!(faqs(user("alice"), get("/faqs")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_prompt_examples_require_admin() {
+        let index_dir = tempdir::TempDir::new("bleep-admin").unwrap();
+        let app = app(&index_dir).await;
+
+        let examples = |user, request| status(router(app.clone()), &app, user, request);
+        let pin = || {
+            with_json(
+                "PUT",
+                "/prompt-examples/1/pinned",
+                serde_json::json!({ "pinned": true }),
+            )
+        };
+
+        for user in [user("bob"), User::Unknown] {
+            let list = get("/prompt-examples");
+            assert_eq!(examples(user.clone(), list).await, StatusCode::FORBIDDEN);
+            assert_eq!(examples(user, pin()).await, StatusCode::FORBIDDEN);
+        }
+
+        let list = get("/prompt-examples");
+        assert_eq!(examples(user("alice"), list).await, StatusCode::OK);
+        // There are no examples yet, so admins get past the check to find none.
+        assert_eq!(examples(user("alice"), pin()).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_runs_export_requires_admin() {
+        let index_dir = tempdir::TempDir::new("bleep-admin").unwrap();
+        let app = app(&index_dir).await;
+
+        let export = |user| status(router(app.clone()), &app, user, get("/runs/export"));
+        assert_eq!(export(user("bob")).await, StatusCode::FORBIDDEN);
+        assert_eq!(export(User::Unknown).await, StatusCode::FORBIDDEN);
+        assert_eq!(export(user("alice")).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_backups_require_configured_admin() {
+        let index_dir = tempdir::TempDir::new("bleep-admin").unwrap();
+        let backup_dir = tempdir::TempDir::new("bleep-backups").unwrap();
+
+        // Without configured admins every user is an admin, but no one can back up or restore.
+        let app = crate::webserver::tests::app(
+            &index_dir,
+            serde_json::json!({ "backup_dir": backup_dir.path() }),
+        )
+        .await;
+        let backups = |user, request| status(router(app.clone()), &app, user, request);
+
+        let backup = with_json("POST", "/backup", serde_json::json!({ "to": "daily" }));
+        assert_eq!(backups(user("bob"), backup).await, StatusCode::FORBIDDEN);
+        let restore = with_json("POST", "/restore", serde_json::json!({ "from": "daily" }));
+        assert_eq!(backups(User::Unknown, restore).await, StatusCode::FORBIDDEN);
+
+        // Configured admins can only use the backup directory.
+        let index_dir = tempdir::TempDir::new("bleep-admin").unwrap();
+        let app = crate::webserver::tests::app(
+            &index_dir,
+            serde_json::json!({ "acl_admins": ["alice"], "backup_dir": backup_dir.path() }),
+        )
+        .await;
+        let backups = |user, request| status(router(app.clone()), &app, user, request);
+
+        for to in ["../daily", "/tmp/daily"] {
+            let backup = with_json("POST", "/backup", serde_json::json!({ "to": to }));
+            assert_eq!(
+                backups(user("alice"), backup).await,
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    // 2023-10-02T00:00:00Z
+    const DAY: i64 = 1_696_204_800;
+
+    fn record(
+        created_at: i64,
+        user_id: &str,
+        query_id: &str,
+        model: &str,
+        tokens: (i64, i64),
+        latency_ms: i64,
+    ) -> UsageRecord {
+        UsageRecord {
+            created_at,
+            user_id: Some(user_id.to_owned()),
+            repo_ref: "github.com/bloopai/bloop".to_owned(),
+            thread_id: "thread".to_owned(),
+            query_id: query_id.to_owned(),
+            run_id: None,
+            stage: if model.starts_with("gpt-4") {
+                "answer"
+            } else {
+                "proc"
+            }
+            .to_owned(),
+            model: model.to_owned(),
+            prompt_tokens: tokens.0,
+            completion_tokens: tokens.1,
+            latency_ms,
+            endpoint: None,
+            outcome: None,
+        }
+    }
+
+    fn fixture() -> Vec<UsageRecord> {
+        vec![
+            record(DAY + 10, "alice", "q1", "gpt-4-0613", (1000, 500), 100),
+            record(
+                DAY + 20,
+                "alice",
+                "q1",
+                "gpt-3.5-turbo-16k-0613",
+                (2000, 0),
+                50,
+            ),
+            record(DAY + 30, "alice", "q2", "gpt-4-0613", (3000, 1000), 300),
+            record(
+                DAY + 40,
+                "bob, \"the builder\"",
+                "q3",
+                "gpt-4-0613",
+                (100, 100),
+                10,
+            ),
+            record(DAY + 86_400, "alice", "q4", "gpt-4-0613", (10_000, 0), 20),
+        ]
+    }
+
+    #[test]
+    fn test_aggregate_by_user() {
+        let rows = aggregate(&fixture(), GroupBy::User);
+
+        assert_eq!(
+            rows.iter()
+                .map(|r| (
+                    r.day.as_str(),
+                    r.key.as_str(),
+                    r.queries,
+                    r.prompt_tokens,
+                    r.completion_tokens,
+                    r.latency_mean_ms,
+                    r.latency_p95_ms
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("2023-10-02", "alice", 2, 6000, 1500, 150, 300),
+                ("2023-10-02", "bob, \"the builder\"", 1, 100, 100, 10, 10),
+                ("2023-10-03", "alice", 1, 10_000, 0, 20, 20),
+            ]
+        );
+
+        let costs = rows.iter().map(|r| r.cost_usd).collect::<Vec<_>>();
+        for (cost, expected) in costs.into_iter().zip([0.216, 0.009, 0.3]) {
+            assert!((cost - expected).abs() < 1e-9, "{cost} != {expected}");
+        }
+    }
+
+    #[test]
+    fn test_aggregate_by_model() {
+        let rows = aggregate(&fixture(), GroupBy::Model)
+            .into_iter()
+            .map(|r| (r.day, r.key, r.queries, r.prompt_tokens))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    "2023-10-02".to_owned(),
+                    "gpt-3.5-turbo-16k-0613".to_owned(),
+                    1,
+                    2000
+                ),
+                ("2023-10-02".to_owned(), "gpt-4-0613".to_owned(), 3, 4100),
+                ("2023-10-03".to_owned(), "gpt-4-0613".to_owned(), 1, 10_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregate_outcomes() {
+        let mut records = fixture();
+        let outcomes = [
+            Some("answered"),
+            Some("answered"),
+            Some("not_found"),
+            None,
+            Some("error"),
+        ];
+        for (record, outcome) in records.iter_mut().zip(outcomes) {
+            record.outcome = outcome.map(str::to_owned);
+        }
+
+        // Queries are counted once per outcome, however many LLM calls they made.
+        let by_repo = aggregate(&records, GroupBy::Repo)
+            .into_iter()
+            .map(|r| (r.day, r.queries, r.outcomes))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            by_repo,
+            vec![
+                (
+                    "2023-10-02".to_owned(),
+                    3,
+                    [
+                        ("answered".to_owned(), 1),
+                        ("not_found".to_owned(), 1),
+                        ("unknown".to_owned(), 1)
+                    ]
+                    .into()
+                ),
+                ("2023-10-03".to_owned(), 1, [("error".to_owned(), 1)].into()),
+            ]
+        );
+
+        let by_outcome = aggregate(&records, GroupBy::Outcome)
+            .into_iter()
+            .map(|r| (r.day, r.key, r.queries))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            by_outcome,
+            vec![
+                ("2023-10-02".to_owned(), "answered".to_owned(), 1),
+                ("2023-10-02".to_owned(), "not_found".to_owned(), 1),
+                ("2023-10-02".to_owned(), "unknown".to_owned(), 1),
+                ("2023-10-03".to_owned(), "error".to_owned(), 1),
+            ]
+        );
+
+        let csv = to_csv(&aggregate(&records, GroupBy::Repo), GroupBy::Repo);
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert!(lines[0].ends_with(",answered,partial,not_found,clarification,error"));
+        assert!(lines[1].ends_with(",1,0,1,0,0"));
+        assert!(lines[2].ends_with(",0,0,0,0,1"));
+    }
+
+    #[test]
+    fn test_csv_escaping() {
+        let rows = aggregate(&fixture()[3..4], GroupBy::User);
+
+        assert_eq!(
+            to_csv(&rows, GroupBy::User),
+            "day,user,queries,prompt_tokens,completion_tokens,cost_usd,latency_mean_ms,latency_p95_ms,\
+             answered,partial,not_found,clarification,error\n\
+             2023-10-02,\"bob, \"\"the builder\"\"\",1,100,100,0.0090,10,10,0,0,0,0,0\n"
+        );
+    }
+
+    #[test]
+    fn test_histograms() {
+        let histograms = histograms(&fixture());
+
+        let counts = |stage: &str| {
+            histograms[stage]
+                .iter()
+                .map(|b| b.count)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(counts("answer"), vec![1, 1, 0, 1, 0, 1, 0]);
+        assert_eq!(counts("proc"), vec![0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut records = fixture();
+        records[0].latency_ms = 1_000;
+
+        // Latencies are those of single LLM requests, not summed per query.
+        let rows = aggregate(&records[..3], GroupBy::User);
+        assert_eq!(rows[0].latency_mean_ms, 450);
+        assert_eq!(rows[0].latency_p95_ms, 1_000);
+    }
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(percentile(&[], 0.5), 0);
+        assert_eq!(percentile(&[1, 2, 3, 4], 0.5), 2);
+        assert_eq!(percentile(&[1, 2, 3, 4], 0.95), 4);
+    }
+}
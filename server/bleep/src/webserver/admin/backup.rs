@@ -0,0 +1,73 @@
+//! Backups of the indexes and conversations, to an object store.
+//!
+//! Backups copy every conversation and index, and restores replace them, so these are only served
+//! to the admins in `Configuration::acl_admins`, even when every user is an admin because none are
+//! configured.
+
+use axum::{extract::State, Json};
+
+use super::super::prelude::*;
+use crate::{
+    backup::{self, BlobStore, IncompatibleSchema, Manifest},
+    webserver::middleware::User,
+    Application,
+};
+
+#[derive(Deserialize)]
+pub(super) struct BackupParams {
+    /// An `s3://bucket/prefix` or `gs://bucket/prefix` URL, or a directory in
+    /// `Configuration::backup_dir`.
+    to: String,
+}
+
+#[derive(Deserialize)]
+pub(super) struct RestoreParams {
+    from: String,
+}
+
+#[derive(Serialize)]
+pub(super) struct BackupResponse {
+    manifest: Manifest,
+}
+
+impl super::super::ApiResponse for BackupResponse {}
+
+pub(super) async fn backup(
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+    Json(params): Json<BackupParams>,
+) -> Result<impl IntoResponse> {
+    let store = open(&app, &user, &params.to)?;
+    let manifest = backup::backup(&app, &*store).await?;
+
+    Ok(json(BackupResponse { manifest }))
+}
+
+/// Download a backup, which replaces the indexes and conversations when bloop is next restarted.
+pub(super) async fn restore(
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+    Json(params): Json<RestoreParams>,
+) -> Result<impl IntoResponse> {
+    let store = open(&app, &user, &params.from)?;
+    let manifest = backup::restore(&app.config, &*store).await.map_err(|err| {
+        match err.downcast_ref::<IncompatibleSchema>() {
+            Some(_) => Error::user(err),
+            None => Error::from(err),
+        }
+    })?;
+
+    Ok(json(BackupResponse { manifest }))
+}
+
+/// Open the store at `url` for `user`, if they are a configured admin.
+fn open(app: &Application, user: &User, url: &str) -> Result<Box<dyn BlobStore>> {
+    if !app.access.is_configured_admin(user) {
+        return Err(
+            Error::user("only the admins in `acl_admins` can back up and restore")
+                .with_status(StatusCode::FORBIDDEN),
+        );
+    }
+
+    backup::open_in(url, app.config.backup_dir.as_deref()).map_err(Error::user)
+}
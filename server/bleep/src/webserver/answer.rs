@@ -1,5 +1,5 @@
 use secrecy::ExposeSecret;
-use std::{panic::AssertUnwindSafe, time::Duration};
+use std::panic::AssertUnwindSafe;
 
 use anyhow::{anyhow, Context, Result};
 use axum::{
@@ -10,22 +10,23 @@ use axum::{
     },
     Extension, Json,
 };
-use futures::{future::Either, stream, StreamExt};
+use axum_extra::extract::cookie::CookieJar;
+use futures::{stream, StreamExt};
 use reqwest::StatusCode;
 use serde_json::json;
-use tracing::warn;
+use tracing::{debug, trace, warn, Instrument};
 
-use self::conversations::ConversationId;
+use self::conversations::{ConversationId, Window};
 
 use super::middleware::User;
 use crate::{
     agent::{
         self,
-        exchange::{CodeChunk, Exchange, FocusedChunk},
-        Action, Agent,
+        exchange::{AnswerSource, CodeChunk, ContextSource, Exchange, FocusedChunk, Update},
+        Action,
     },
     analytics::{EventData, QueryEvent},
-    db::QueryLog,
+    db::{Faq, Faqs, HistoryEntry, PromptExamples, QueryHistory, QueryLog, QueryStatus},
     llm_gateway,
     query::parser::{self, Literal},
     repo::RepoRef,
@@ -33,8 +34,8 @@ use crate::{
 };
 
 pub mod conversations;
-
-const TIMEOUT_SECS: u64 = 60;
+mod faq;
+pub mod snippets;
 
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Vote {
@@ -54,40 +55,131 @@ pub enum VoteFeedback {
 pub(super) async fn vote(
     Extension(app): Extension<Application>,
     Extension(user): Extension<User>,
+    jar: CookieJar,
     Json(params): Json<Vote>,
 ) {
+    if let (VoteFeedback::Positive, Some(user_id)) = (&params.feedback, user.login()) {
+        let conversation_id = ConversationId {
+            user_id: user_id.to_owned(),
+            thread_id: params.thread_id,
+        };
+
+        let ctx = agent::context::RequestContext {
+            user: Some(user_id.to_owned()),
+            thread_id: params.thread_id,
+            query_id: params.query_id,
+            run_id: None,
+        };
+
+        agent::context::spawn_in_ctx(
+            &ctx,
+            record_example(app.clone(), conversation_id, params.query_id),
+        );
+    }
+
     app.track_query(
         &user,
         &QueryEvent {
             query_id: params.query_id,
+            run_id: None,
             thread_id: params.thread_id,
+            session_id: session_id(&jar),
             repo_ref: params.repo_ref,
             data: EventData::output_stage("vote").with_payload("feedback", params.feedback),
         },
     );
 }
 
+/// Store the tool calls of an upvoted exchange as a few-shot example for its repository.
+async fn record_example(app: Application, conversation_id: ConversationId, query_id: uuid::Uuid) {
+    let result = async {
+        let Some((repo_ref, exchanges)) = conversations::load(&app.sql, &conversation_id).await?
+        else {
+            return Ok(());
+        };
+
+        let Some(example) = exchanges
+            .iter()
+            .find(|e| e.id == query_id)
+            .and_then(agent::few_shot::extract)
+        else {
+            return Ok(());
+        };
+
+        PromptExamples::new(&app.sql)
+            .insert(
+                &repo_ref.to_string(),
+                &query_id.to_string(),
+                &example.text,
+                example.steps as i64,
+            )
+            .await?;
+
+        agent::few_shot::select(&app.sql, &repo_ref).await
+    };
+
+    if let Err(err) = result.await {
+        warn!(?err, %query_id, "failed to record prompt example");
+    }
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct Answer {
     pub q: String,
+    /// The repository that this query searches.
+    ///
+    /// This can differ from the repository of earlier queries in the thread, whose questions and
+    /// answers the agent still sees.
     pub repo_ref: RepoRef,
     #[serde(default = "default_thread_id")]
     pub thread_id: uuid::Uuid,
     /// Optional id of the parent of the exchange to overwrite
     /// If this UUID is nil, then overwrite the first exchange in the thread
     pub parent_exchange_id: Option<uuid::Uuid>,
+    /// Save the answer as a snippet with this title, once it is complete
+    #[serde(default)]
+    pub snippet_title: Option<String>,
+    /// Only search code written in this language, given by name or file extension
+    #[serde(default)]
+    pub language: Option<String>,
+    /// When to send updates of the answer as it is generated
+    #[serde(default)]
+    pub flush: agent::flush::FlushMode,
+    /// How code blocks are sent, if updates are only sent at the end of sentences
+    #[serde(default)]
+    pub code_blocks: agent::flush::CodeBlocks,
+    /// Whether to answer in full, or quickly from search snippets
+    #[serde(default)]
+    pub mode: agent::quick::Mode,
+    /// Optional id of a quick exchange to answer again in full, which is replaced in the thread
+    #[serde(default)]
+    pub full_analysis_of: Option<uuid::Uuid>,
+    /// Read files into JSON summaries of their relevant lines, rather than prose
+    #[serde(default)]
+    pub structured_proc_output: bool,
+    /// The browser session of the request, which is read from the `session_id` cookie
+    #[serde(skip)]
+    pub session_id: Option<String>,
 }
 
 fn default_thread_id() -> uuid::Uuid {
     uuid::Uuid::new_v4()
 }
 
+/// The browser session of a request, which groups the analytics events of several threads.
+fn session_id(jar: &CookieJar) -> Option<String> {
+    jar.get("session_id")
+        .map(|cookie| cookie.value().to_owned())
+}
+
 pub(super) async fn answer(
-    Query(params): Query<Answer>,
+    Query(mut params): Query<Answer>,
     Extension(app): Extension<Application>,
     Extension(user): Extension<User>,
+    jar: CookieJar,
 ) -> super::Result<impl IntoResponse> {
     let query_id = uuid::Uuid::new_v4();
+    params.session_id = session_id(&jar);
 
     let conversation_id = ConversationId {
         user_id: user
@@ -97,36 +189,83 @@ pub(super) async fn answer(
         thread_id: params.thread_id,
     };
 
-    let (_, mut exchanges) = conversations::load(&app.sql, &conversation_id)
+    super::check_repo_access(&app, &user, &params.repo_ref)?;
+
+    let mut window = conversations::load_window(&app.sql, &conversation_id)
         .await?
-        .unwrap_or_else(|| (params.repo_ref.clone(), Vec::new()));
+        .unwrap_or_else(|| Window::new(params.repo_ref.clone(), Vec::new()));
 
     let Answer {
         parent_exchange_id,
+        full_analysis_of,
         q,
         ..
     } = &params;
 
+    // A full analysis re-runs the question of its quick exchange, which may have been archived.
+    if let Some(id) = full_analysis_of {
+        if !window.exchanges.iter().any(|e| e.id == *id) {
+            if let Some((repo_ref, exchanges)) =
+                conversations::load(&app.sql, &conversation_id).await?
+            {
+                window = Window::new(repo_ref, exchanges);
+            }
+        }
+    }
+
     if let Some(parent_exchange_id) = parent_exchange_id {
+        let in_window = if parent_exchange_id.is_nil() {
+            window.archived == 0
+        } else {
+            window.exchanges.iter().any(|e| e.id == *parent_exchange_id)
+        };
+
+        // Overwriting an archived exchange rewrites the thread from there, so it is loaded whole.
+        if !in_window {
+            if let Some((repo_ref, exchanges)) =
+                conversations::load(&app.sql, &conversation_id).await?
+            {
+                window = Window::new(repo_ref, exchanges);
+            }
+        }
+
         let truncate_from_index = if parent_exchange_id.is_nil() {
             0
         } else {
-            exchanges
+            window
+                .exchanges
                 .iter()
                 .position(|e| e.id == *parent_exchange_id)
                 .ok_or_else(|| super::Error::user("parent query id not found in exchanges"))?
                 + 1
         };
 
-        exchanges.truncate(truncate_from_index);
+        window.exchanges.truncate(truncate_from_index);
     }
 
-    let query = parser::parse_nl(q)
-        .context("parse error")?
-        .into_semantic()
-        .context("got a 'Grep' query")?
-        .into_owned();
-    let query_target = query
+    let mut exchange = match full_analysis_of {
+        Some(id) => agent::quick::full_analysis(&mut window.exchanges, *id, query_id)
+            .map_err(super::Error::user)?,
+        None => {
+            let query = parser::parse_nl(q)
+                .context("parse error")?
+                .into_semantic()
+                .context("got a 'Grep' query")?
+                .with_raw_target(q)
+                .into_owned();
+
+            Exchange::new(query_id, query)
+        }
+    };
+
+    // Repositories that the user can't query are dropped from the query, as if they didn't exist.
+    let omitted = app.access.strip_denied(&user, &mut exchange.query);
+    if omitted > 0 {
+        exchange.omitted_repos = Some(omitted);
+    }
+
+    let query_target = exchange
+        .query
         .target
         .as_ref()
         .context("query was empty")?
@@ -135,8 +274,33 @@ pub(super) async fn answer(
         .clone()
         .into_owned();
 
+    let entry = HistoryEntry {
+        created_at: chrono::Utc::now().timestamp(),
+        thread_id: conversation_id.thread_id.to_string(),
+        exchange_id: query_id.to_string(),
+        repo_ref: params.repo_ref.to_string(),
+        query: query_target.clone(),
+        status: QueryStatus::Pending,
+    };
+    QueryHistory::new(&app.sql)
+        .insert(&conversation_id.user_id, &entry)
+        .await?;
+
+    // Questions that are analysed in full already went past the canned answers.
+    if full_analysis_of.is_none() {
+        let faqs = Faqs::new(&app.sql).list().await?;
+        let embedding = faq::embed(&app, &faqs, &query_target).await;
+        match faq::find(&faqs, &params.repo_ref, &query_target, embedding.as_deref()) {
+            faq::Match::Hit(hit) => {
+                window.exchanges.push(exchange);
+                return answer_faq(params, app, user, query_id, conversation_id, window, hit).await;
+            }
+            faq::Match::Suggestions(suggestions) => exchange.suggestions = suggestions,
+        }
+    }
+
     let action = Action::Query(query_target);
-    exchanges.push(Exchange::new(query_id, query));
+    window.exchanges.push(exchange);
 
     execute_agent(
         params.clone(),
@@ -144,12 +308,86 @@ pub(super) async fn answer(
         user.clone(),
         query_id,
         conversation_id,
-        exchanges,
+        window,
         action,
     )
     .await
 }
 
+/// Answer a query with a canned answer, without running the agent.
+async fn answer_faq(
+    params: Answer,
+    app: Application,
+    user: User,
+    query_id: uuid::Uuid,
+    conversation_id: ConversationId,
+    mut window: Window,
+    faq: &Faq,
+) -> super::Result<
+    Sse<std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<sse::Event>> + Send>>>,
+> {
+    QueryLog::new(&app.sql).insert(&params.q).await?;
+
+    let exchange = window
+        .exchanges
+        .last_mut()
+        .context("no exchange to answer")?;
+    let run_id = exchange.run_id;
+    exchange.source = Some(AnswerSource::Faq);
+    exchange.apply_update(Update::Article(faq.answer.clone()));
+    exchange.apply_update(Update::Conclude(
+        "Is there anything else I can help you with?".into(),
+    ));
+    let exchange = exchange.compressed();
+
+    conversations::save(&app, conversation_id, window).await?;
+    set_status(&app, query_id, QueryStatus::Answered).await;
+
+    for data in [
+        EventData::input_stage("query").with_payload("q", &params.q),
+        EventData::output_stage("faq_answer").with_payload("faq_id", faq.id),
+    ] {
+        app.track_query(
+            &user,
+            &QueryEvent {
+                query_id,
+                run_id,
+                thread_id: params.thread_id,
+                session_id: params.session_id.clone(),
+                repo_ref: Some(params.repo_ref.clone()),
+                data,
+            },
+        );
+    }
+
+    let events = [
+        sse::Event::default().json_data(json!({
+            "thread_id": params.thread_id.to_string(),
+            "query_id": query_id
+        })),
+        sse::Event::default().json_data(Ok::<_, String>(exchange)),
+    ]
+    .into_iter()
+    .map(|event| event.map_err(anyhow::Error::new))
+    .chain([Ok(sse::Event::default().data("[DONE]"))])
+    .collect::<Vec<_>>();
+
+    Ok(Sse::new(Box::pin(stream::iter(events))))
+}
+
+/// Record how answering a query ended in the user's query history.
+///
+/// Failing to do so is only logged, as it shouldn't fail the query itself.
+async fn set_status(app: &Application, query_id: uuid::Uuid, status: QueryStatus) {
+    let result = QueryHistory::new(&app.sql)
+        .set_status(&query_id.to_string(), status)
+        .await;
+
+    if let Err(err) = result {
+        warn!(?err, %query_id, "failed to update query history");
+    }
+}
+
 /// Like `try_execute_agent`, but additionally logs errors in our analytics.
 async fn execute_agent(
     params: Answer,
@@ -157,28 +395,32 @@ async fn execute_agent(
     user: User,
     query_id: uuid::Uuid,
     conversation_id: ConversationId,
-    exchanges: Vec<Exchange>,
+    window: Window,
     action: Action,
 ) -> super::Result<
     Sse<std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<sse::Event>> + Send>>>,
 > {
+    let run_id = window.exchanges.last().and_then(|e| e.run_id);
     let response = try_execute_agent(
         params.clone(),
         app.clone(),
         user.clone(),
         query_id,
         conversation_id,
-        exchanges,
+        window,
         action,
     )
     .await;
 
     if let Err(err) = response.as_ref() {
+        set_status(&app, query_id, QueryStatus::Errored).await;
         app.track_query(
             &user,
             &QueryEvent {
                 query_id,
+                run_id,
                 thread_id: params.thread_id,
+                session_id: params.session_id,
                 repo_ref: Some(params.repo_ref),
                 data: EventData::output_stage("error")
                     .with_payload("status", err.status.as_u16())
@@ -196,8 +438,8 @@ async fn try_execute_agent(
     user: User,
     query_id: uuid::Uuid,
     conversation_id: ConversationId,
-    exchanges: Vec<Exchange>,
-    mut action: Action,
+    window: Window,
+    action: Action,
 ) -> super::Result<
     Sse<std::pin::Pin<Box<dyn tokio_stream::Stream<Item = Result<sse::Event>> + Send>>>,
 > {
@@ -211,7 +453,9 @@ async fn try_execute_agent(
     let llm_gateway = llm_gateway::Client::new(&app.config.answer_api_url)
         .temperature(0.0)
         .bearer(gh_token)
-        .session_reference_id(conversation_id.to_string());
+        .endpoints(app.llm_endpoints.clone())
+        .session_reference_id(conversation_id.to_string())
+        .with_system_fingerprint_validation(llm_gateway::FingerprintValidation::Warn);
 
     // confirm client compatibility with answer-api
     match llm_gateway
@@ -242,70 +486,73 @@ async fn try_execute_agent(
     let Answer {
         thread_id,
         repo_ref,
+        snippet_title,
+        language,
+        flush,
+        code_blocks,
+        mode,
+        full_analysis_of,
+        structured_proc_output,
+        session_id,
         ..
     } = params.clone();
-    let stream = async_stream::try_stream! {
-        let (exchange_tx, exchange_rx) = tokio::sync::mpsc::channel(10);
-
-        let mut agent = Agent {
-            app,
-            repo_ref,
-            exchanges,
-            exchange_tx,
-            llm_gateway,
-            user,
-            thread_id,
-            query_id,
-            complete: false,
-        };
 
-        let mut exchange_rx = tokio_stream::wrappers::ReceiverStream::new(exchange_rx);
-
-        let result = 'outer: loop {
-            // The main loop. Here, we create two streams that operate simultaneously; the update
-            // stream, which sends updates back to the HTTP event stream response, and the action
-            // stream, which returns a single item when there is a new action available to execute.
-            // Both of these operate together, and we repeat the process for every new action.
-
-            use futures::future::FutureExt;
-
-            let left_stream = (&mut exchange_rx).map(Either::Left);
-            let right_stream = agent
-                .step(action)
-                .into_stream()
-                .map(Either::Right);
-
-            let timeout = Duration::from_secs(TIMEOUT_SECS);
-
-            let mut next = None;
-            for await item in tokio_stream::StreamExt::timeout(
-                stream::select(left_stream, right_stream),
-                timeout,
-            ) {
-                match item {
-                    Ok(Either::Left(exchange)) => yield exchange.compressed(),
-                    Ok(Either::Right(next_action)) => match next_action {
-                        Ok(n) => break next = n,
-                        Err(e) => break 'outer Err(agent::Error::Processing(e)),
-                    },
-                    Err(_) => break 'outer Err(agent::Error::Timeout(timeout)),
-                }
-            }
+    // Full analyses are never quick, whatever the mode of the request.
+    let mode = match full_analysis_of {
+        Some(_) => agent::quick::Mode::Normal,
+        None => mode,
+    };
 
-            // NB: Sending updates after all other `await` points in the final `step` call will
-            // likely not return a pending future due to the internal receiver queue. So, the call
-            // stack usually continues onwards, ultimately resulting in a `Poll::Ready`, backing out
-            // of the above loop without ever processing the final message. Here, we empty the
-            // queue.
-            while let Some(Some(exchange)) = exchange_rx.next().now_or_never() {
-                yield exchange.compressed();
-            }
+    let Window {
+        archived,
+        archived_paths,
+        exchanges,
+        ..
+    } = window;
 
-            match next {
-                Some(a) => action = a,
-                None => break Ok(()),
+    let tool_examples = agent::few_shot::load(&app.sql, &repo_ref)
+        .await
+        .unwrap_or_else(|err| {
+            warn!(?err, "failed to load prompt examples");
+            Vec::new()
+        });
+    let stream = async_stream::try_stream! {
+        let mut driver = agent::builder::builder(app)
+            .repo(repo_ref)
+            .user(user)
+            .thread_id(thread_id)
+            .query_id(query_id)
+            .session_id(session_id)
+            .exchanges(exchanges)
+            .archived_paths(archived_paths)
+            .llm_gateway(llm_gateway)
+            .tool_examples(tool_examples)
+            .flush(agent::flush::Flush { mode: flush, code_blocks })
+            .language_hint(language)
+            .structured_proc_output(structured_proc_output)
+            .mode(mode)
+            .with_span(true)
+            .build()?;
+
+        let mut result = Ok(());
+        for await update in driver.drive_instrumented(action) {
+            match update {
+                // Updates are streamed in the span of the step that made them, so that logs of the
+                // response are tied to the thread.
+                Ok(update) => yield update.span.in_scope(|| {
+                    trace!("streaming exchange update");
+                    update.exchange.compressed()
+                }),
+                Err(err) => result = Err(err),
             }
-        };
+        }
+
+        let mut agent = driver.into_agent();
+        let span = agent.request_context().span();
+
+        if let Some(exchange) = agent.exchanges.last() {
+            debug!(steps = %exchange.serialize_search_steps_as_table(), "search steps");
+        }
 
         match result {
             Ok(_) => {}
@@ -315,6 +562,7 @@ async fn try_execute_agent(
                     EventData::output_stage("error")
                         .with_payload("timeout", duration.as_secs()),
                 );
+                set_status(&agent.app, query_id, QueryStatus::Errored).await;
                 Err(anyhow!("reached timeout of {duration:?}"))?;
             }
             Err(agent::Error::Processing(e)) => {
@@ -322,12 +570,40 @@ async fn try_execute_agent(
                     EventData::output_stage("error")
                         .with_payload("message", e.to_string()),
                 );
+                set_status(&agent.app, query_id, QueryStatus::Errored).await;
                 Err(e)?;
             }
         }
 
+        // The thread is anonymized in place, so that its title and snippet are too.
+        let anonymize_patterns = agent.app.anonymize_patterns.clone();
+        agent.anonymize_exchanges(&anonymize_patterns);
+
         // Storing the conversation here allows us to make subsequent requests.
-        conversations::store(&agent.app.sql, conversation_id, (agent.repo_ref.clone(), agent.exchanges.clone())).await?;
+        let window = Window {
+            repo_ref: agent.repo_ref.clone(),
+            archived,
+            archived_paths: agent.archived_paths.clone(),
+            exchanges: agent.exchanges.clone(),
+        };
+        conversations::save(&agent.app, conversation_id.clone(), window).await?;
+
+        // New threads are titled with a summary of their first query.
+        if archived == 0 && agent.exchanges.len() == 1 {
+            match agent.conversation_title().instrument(span.clone()).await {
+                Ok(title) => conversations::set_title(&agent.app.sql, &conversation_id, &title).await?,
+                Err(err) => warn!(?err, "failed to generate conversation title"),
+            }
+        }
+
+        if let Some(title) = &snippet_title {
+            match agent.save_answer_as_snippet(title).instrument(span.clone()).await {
+                Ok(id) => debug!(%id, "saved answer as snippet"),
+                Err(err) => warn!(?err, "failed to save answer as snippet"),
+            }
+        }
+
+        set_status(&agent.app, query_id, QueryStatus::Answered).await;
         agent.complete();
     };
 
@@ -373,7 +649,9 @@ pub async fn explain(
     Query(params): Query<Explain>,
     Extension(app): Extension<Application>,
     Extension(user): Extension<User>,
+    jar: CookieJar,
 ) -> super::Result<impl IntoResponse> {
+    super::check_repo_access(&app, &user, &params.repo_ref)?;
     let query_id = uuid::Uuid::new_v4();
 
     // We synthesize a virtual `/answer` request.
@@ -385,6 +663,14 @@ pub async fn explain(
         repo_ref: params.repo_ref,
         thread_id: params.thread_id,
         parent_exchange_id: None,
+        snippet_title: None,
+        language: None,
+        flush: Default::default(),
+        code_blocks: Default::default(),
+        mode: Default::default(),
+        full_analysis_of: None,
+        structured_proc_output: false,
+        session_id: session_id(&jar),
     };
 
     let conversation_id = ConversationId {
@@ -430,8 +716,16 @@ pub async fn explain(
         file_path: params.relative_path.clone(),
         start_line: params.line_start,
         end_line: params.line_end,
+        ..Default::default()
     });
 
+    exchange.include_context(
+        &params.relative_path,
+        ContextSource::Pinned,
+        &[params.line_start..params.line_end + 1],
+        true,
+    );
+
     exchange.paths.push(params.relative_path.clone());
     exchange.code_chunks.push(CodeChunk {
         path: params.relative_path.clone(),
@@ -439,9 +733,16 @@ pub async fn explain(
         start_line: params.line_start,
         end_line: params.line_end,
         snippet,
+        moved_to: None,
+        deleted: false,
+        symbol_path: None,
+        blob: None,
+        revision: None,
+        mapped: None,
     });
 
     let action = Action::Answer { paths: vec![0] };
+    let window = Window::new(virtual_req.repo_ref.clone(), vec![exchange]);
 
     execute_agent(
         virtual_req,
@@ -449,7 +750,7 @@ pub async fn explain(
         user,
         query_id,
         conversation_id,
-        vec![exchange],
+        window,
         action,
     )
     .await
@@ -1,16 +1,20 @@
 use anyhow::{Context, Result};
 use axum::{
     extract::{Path, Query, State},
+    http::header,
     response::IntoResponse,
     Extension, Json,
 };
 use reqwest::StatusCode;
+use secrecy::ExposeSecret;
+use serde_json::Value;
 use std::{fmt, str::FromStr};
 use tracing::info;
 
 use crate::{
-    agent::exchange::Exchange,
-    db::SqlDb,
+    agent::{self, citations::CitationRegistry, exchange::Exchange, line_map, page, relocation},
+    db::{QueryHistory, SqlDb},
+    llm_gateway,
     repo::RepoRef,
     webserver::{self, middleware::User, Error, ErrorKind},
     Application,
@@ -18,6 +22,34 @@ use crate::{
 
 type Conversation = (RepoRef, Vec<Exchange>);
 
+/// The number of most recent exchanges that a stored thread keeps at hand. Older exchanges are
+/// archived, as answering a new query only looks at the last few.
+const WINDOW_SIZE: usize = 20;
+
+/// The most recent exchanges of a thread, as loaded to answer a new query.
+pub struct Window {
+    pub repo_ref: RepoRef,
+    /// The number of older exchanges, which are archived.
+    pub archived: usize,
+    /// The paths of the archived exchanges.
+    ///
+    /// These are the first path aliases of the thread, which `exchanges` continue.
+    pub archived_paths: Vec<String>,
+    pub exchanges: Vec<Exchange>,
+}
+
+impl Window {
+    /// A window over a whole thread, with nothing archived.
+    pub fn new(repo_ref: RepoRef, exchanges: Vec<Exchange>) -> Self {
+        Self {
+            repo_ref,
+            archived: 0,
+            archived_paths: Vec::new(),
+            exchanges,
+        }
+    }
+}
+
 #[derive(Hash, PartialEq, Eq, Clone)]
 pub struct ConversationId {
     pub thread_id: uuid::Uuid,
@@ -102,15 +134,38 @@ pub(in crate::webserver) async fn delete(
     .await
     .map_err(Error::internal)?;
 
-    if result.rows_affected() == 0 {
+    // Queries that failed before their thread was stored only exist in the query history.
+    let forgotten = QueryHistory::new(db)
+        .delete_thread(user_id, &params.thread_id)
+        .await
+        .map_err(Error::internal)?;
+
+    if result.rows_affected() == 0 && forgotten == 0 {
         return Err(Error::user("conversation not found").with_status(StatusCode::NOT_FOUND));
     }
 
+    sqlx::query! {
+        "DELETE FROM conversation_archive WHERE user_id = ? AND thread_id = ?",
+        user_id,
+        params.thread_id,
+    }
+    .execute(db)
+    .await
+    .map_err(Error::internal)?;
+
     Ok(())
 }
 
+#[derive(serde::Deserialize)]
+pub(in crate::webserver) struct ThreadParams {
+    /// The `next_cursor` of the previous page, or nothing for the first page.
+    cursor: Option<String>,
+    page_size: Option<usize>,
+}
+
 pub(in crate::webserver) async fn thread(
     Path(thread_id): Path<uuid::Uuid>,
+    Query(params): Query<ThreadParams>,
     Extension(user): Extension<User>,
     State(app): State<Application>,
 ) -> webserver::Result<impl IntoResponse> {
@@ -119,24 +174,164 @@ pub(in crate::webserver) async fn thread(
         .ok_or_else(|| Error::user("missing user ID"))?
         .to_owned();
 
-    let (.., exchanges) = load(&app.sql, &ConversationId { thread_id, user_id })
+    let (repo_ref, exchanges) = load(&app.sql, &ConversationId { thread_id, user_id })
         .await?
         .ok_or_else(|| Error::new(ErrorKind::NotFound, "thread was not found"))?;
 
-    let exchanges = exchanges
+    let agent = agent::builder::builder(app.clone())
+        .repo(repo_ref.clone())
+        .user(user)
+        .thread_id(thread_id)
+        .exchanges(exchanges)
+        .build()
+        .map_err(|e| Error::new(ErrorKind::NotFound, e.to_string()))?
+        .into_agent();
+    let page = agent.exchanges_page(
+        params.cursor.as_deref(),
+        params.page_size.unwrap_or(page::DEFAULT_PAGE_SIZE),
+    );
+    agent.complete();
+    let mut page = page.map_err(Error::user)?;
+
+    // Files may have been renamed, deleted or changed since this thread was stored.
+    relocation::annotate(&app, &repo_ref, &mut page.exchanges).await;
+    line_map::remap(&app, &repo_ref, &mut page.exchanges).await;
+
+    page.exchanges = page
+        .exchanges
         .into_iter()
         .map(|ex| ex.compressed())
-        .collect::<Vec<_>>();
+        .collect();
+
+    Ok(Json(page))
+}
+
+#[derive(serde::Serialize)]
+pub(in crate::webserver) struct Title {
+    title: String,
+}
+
+/// Generate a short title for a thread from its first query, and store it for the thread list.
+pub(in crate::webserver) async fn title(
+    Path(thread_id): Path<uuid::Uuid>,
+    Extension(user): Extension<User>,
+    State(app): State<Application>,
+) -> webserver::Result<impl IntoResponse> {
+    let user_id = user
+        .login()
+        .ok_or_else(|| Error::user("missing user ID"))?
+        .to_owned();
+
+    let id = ConversationId { thread_id, user_id };
+    let (_, exchanges) = load(&app.sql, &id)
+        .await?
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "thread was not found"))?;
+
+    let query = exchanges
+        .first()
+        .and_then(|e| e.query())
+        .ok_or_else(|| Error::user("thread has no query"))?;
+
+    let gh_token = app
+        .github_token()
+        .map_err(|e| Error::user(e).with_status(StatusCode::UNAUTHORIZED))?
+        .map(|s| s.expose_secret().clone());
+
+    let llm_gateway = llm_gateway::Client::new(&app.config.answer_api_url)
+        .temperature(0.0)
+        .bearer(gh_token)
+        .endpoints(app.llm_endpoints.clone());
+
+    let title = agent::title::generate(&llm_gateway, &query)
+        .await
+        .map_err(Error::internal)?;
+    set_title(&app.sql, &id, &title).await?;
+
+    Ok(Json(Title { title }))
+}
+
+/// Export a thread as a fine-tuning record in OpenAI's format, on a single JSONL line.
+///
+/// Threads with an unanswered exchange can't be exported.
+pub(in crate::webserver) async fn export(
+    Path(thread_id): Path<uuid::Uuid>,
+    Extension(user): Extension<User>,
+    State(app): State<Application>,
+) -> webserver::Result<impl IntoResponse> {
+    let user_id = user
+        .login()
+        .ok_or_else(|| Error::user("missing user ID"))?
+        .to_owned();
+
+    let (repo_ref, exchanges) = load(&app.sql, &ConversationId { thread_id, user_id })
+        .await?
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "thread was not found"))?;
 
-    Ok(Json(exchanges))
+    let agent = agent::builder::builder(app)
+        .repo(repo_ref)
+        .user(user)
+        .thread_id(thread_id)
+        .exchanges(exchanges)
+        .build()
+        .map_err(|e| Error::new(ErrorKind::NotFound, e.to_string()))?
+        .into_agent();
+    let record = agent.export_openai_format();
+    agent.complete();
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        record.map_err(Error::user)? + "\n",
+    ))
 }
 
-pub async fn store(db: &SqlDb, id: ConversationId, conversation: Conversation) -> Result<()> {
+/// Store a conversation at the current revision of its repository, after stamping its citations
+/// with the blobs they were read from and removing the personal data that `anonymize_patterns`
+/// match.
+pub async fn save(app: &Application, id: ConversationId, mut window: Window) -> Result<()> {
+    let revision = app
+        .repo_pool
+        .read(&window.repo_ref, |_, repo| repo.revision.clone())
+        .flatten();
+    line_map::stamp_blobs(
+        app,
+        &window.repo_ref,
+        revision.as_deref(),
+        &mut window.exchanges,
+    )
+    .await;
+
+    for exchange in &mut window.exchanges {
+        exchange.anonymize(&app.anonymize_patterns);
+    }
+
+    store(&app.sql, id, window, revision).await
+}
+
+/// Store a conversation, stamping its citations with `revision`, the revision of the repository
+/// that the conversation is about.
+///
+/// Exchanges beyond the last `WINDOW_SIZE` are moved to the archive. Archived exchanges from
+/// `window.archived` onwards are replaced, so storing a whole thread rewrites its archive.
+pub async fn store(
+    db: &SqlDb,
+    id: ConversationId,
+    window: Window,
+    revision: Option<String>,
+) -> Result<()> {
     info!("writing conversation {}-{}", id.user_id, id.thread_id);
     let mut transaction = db.begin().await?;
 
-    // Delete the old conversation for simplicity. This also deletes all its messages.
+    // Keep the title of a thread that was already stored, which may have been generated.
     let (user_id, thread_id) = (id.user_id.clone(), id.thread_id.to_string());
+    let stored_title = sqlx::query_scalar! {
+        "SELECT title FROM conversations WHERE user_id = ? AND thread_id = ?",
+        user_id,
+        thread_id,
+    }
+    .fetch_optional(&mut transaction)
+    .await?;
+
+    // Delete the old conversation for simplicity. This also deletes all its messages.
     sqlx::query! {
         "DELETE FROM conversations \
             WHERE user_id = ? AND thread_id = ?",
@@ -146,25 +341,77 @@ pub async fn store(db: &SqlDb, id: ConversationId, conversation: Conversation) -
     .execute(&mut transaction)
     .await?;
 
-    let (repo_ref, exchanges) = conversation;
-    let repo_ref = repo_ref.to_string();
-    let title = exchanges
-        .first()
-        .and_then(|list| list.query())
-        .and_then(|q| q.split('\n').next().map(|s| s.to_string()))
-        .context("couldn't find conversation title")?;
+    let Window {
+        repo_ref,
+        mut archived,
+        mut archived_paths,
+        mut exchanges,
+    } = window;
+
+    let title = match stored_title {
+        Some(title) => title,
+        None => exchanges
+            .first()
+            .and_then(|list| list.query())
+            .and_then(|q| q.split('\n').next().map(|s| s.to_string()))
+            .context("couldn't find conversation title")?,
+    };
 
-    let exchanges = serde_json::to_string(&exchanges)?;
+    let first_replaced = archived as i64;
+    sqlx::query! {
+        "DELETE FROM conversation_archive \
+         WHERE user_id = ? AND thread_id = ? AND position >= ?",
+        user_id,
+        thread_id,
+        first_replaced,
+    }
+    .execute(&mut transaction)
+    .await?;
+
+    let overflow = exchanges.len().saturating_sub(WINDOW_SIZE);
+    for exchange in exchanges.drain(..overflow) {
+        let mut citations = CitationRegistry::default().at_revision(revision.clone());
+        let stored =
+            citations.normalize(&repo_ref, std::slice::from_ref(&exchange))?[0].to_string();
+        let citations = serde_json::to_string(&citations)?;
+        let position = archived as i64;
+
+        sqlx::query! {
+            "INSERT INTO conversation_archive (user_id, thread_id, position, exchange, citations) \
+             VALUES (?, ?, ?, ?, ?)",
+            user_id,
+            thread_id,
+            position,
+            stored,
+            citations,
+        }
+        .execute(&mut transaction)
+        .await?;
+
+        archived += 1;
+        archived_paths.extend(exchange.paths);
+    }
+
+    let mut citations = CitationRegistry::default().at_revision(revision);
+    let exchanges = citations.normalize(&repo_ref, &exchanges)?.to_string();
+    let citations = serde_json::to_string(&citations)?;
+    let repo_ref = repo_ref.to_string();
+    let archived = archived as i64;
+    let archived_paths = serde_json::to_string(&archived_paths)?;
     sqlx::query! {
         "INSERT INTO conversations (\
-            user_id, thread_id, repo_ref, title, exchanges, created_at\
+            user_id, thread_id, repo_ref, title, exchanges, citations, archived, archived_paths, \
+            created_at\
             ) \
-            VALUES (?, ?, ?, ?, ?, strftime('%s', 'now'))",
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, strftime('%s', 'now'))",
         user_id,
         thread_id,
         repo_ref,
         title,
         exchanges,
+        citations,
+        archived,
+        archived_paths,
     }
     .execute(&mut transaction)
     .await?;
@@ -174,11 +421,43 @@ pub async fn store(db: &SqlDb, id: ConversationId, conversation: Conversation) -
     Ok(())
 }
 
+pub async fn set_title(db: &SqlDb, id: &ConversationId, title: &str) -> Result<()> {
+    let (user_id, thread_id) = (id.user_id.clone(), id.thread_id.to_string());
+
+    sqlx::query! {
+        "UPDATE conversations SET title = ? WHERE user_id = ? AND thread_id = ?",
+        title,
+        user_id,
+        thread_id,
+    }
+    .execute(db.as_ref())
+    .await?;
+
+    Ok(())
+}
+
+/// Load a whole thread, including its archived exchanges.
 pub async fn load(db: &SqlDb, id: &ConversationId) -> Result<Option<Conversation>> {
+    let Some(window) = load_window(db, id).await? else {
+        return Ok(None);
+    };
+
+    let mut exchanges = if window.archived > 0 {
+        load_archive(db, &id.user_id, &id.thread_id.to_string(), &window.repo_ref).await?
+    } else {
+        Vec::new()
+    };
+    exchanges.extend(window.exchanges);
+
+    Ok(Some((window.repo_ref, exchanges)))
+}
+
+/// Load the most recent exchanges of a thread, leaving archived exchanges in the database.
+pub async fn load_window(db: &SqlDb, id: &ConversationId) -> Result<Option<Window>> {
     let (user_id, thread_id) = (id.user_id.clone(), id.thread_id.to_string());
 
     let row = sqlx::query! {
-        "SELECT repo_ref, exchanges FROM conversations \
+        "SELECT repo_ref, exchanges, citations, archived, archived_paths FROM conversations \
          WHERE user_id = ? AND thread_id = ?",
         user_id,
         thread_id,
@@ -192,7 +471,452 @@ pub async fn load(db: &SqlDb, id: &ConversationId) -> Result<Option<Conversation
     };
 
     let repo_ref = RepoRef::from_str(&row.repo_ref).context("failed to parse repo ref")?;
-    let exchanges = serde_json::from_str(&row.exchanges)?;
+    let (exchanges, _) = resolve(&repo_ref, &row.exchanges, row.citations.as_deref())?;
+
+    Ok(Some(Window {
+        repo_ref,
+        archived: row.archived as usize,
+        archived_paths: serde_json::from_str(&row.archived_paths)
+            .context("invalid archived paths")?,
+        exchanges,
+    }))
+}
+
+/// Load the archived exchanges of a thread, in order.
+async fn load_archive(
+    db: &SqlDb,
+    user_id: &str,
+    thread_id: &str,
+    repo_ref: &RepoRef,
+) -> Result<Vec<Exchange>> {
+    let rows = sqlx::query! {
+        "SELECT exchange, citations FROM conversation_archive \
+         WHERE user_id = ? AND thread_id = ? \
+         ORDER BY position",
+        user_id,
+        thread_id,
+    }
+    .fetch_all(db.as_ref())
+    .await?;
+
+    let mut exchanges = Vec::with_capacity(rows.len());
+    for row in rows {
+        let mut registry = serde_json::from_str::<CitationRegistry>(&row.citations)?;
+        let stored = serde_json::from_str(&row.exchange)?;
+        let (resolved, _) = registry.resolve(repo_ref, Value::Array(vec![stored]))?;
+        exchanges.extend(resolved);
+    }
+
+    Ok(exchanges)
+}
+
+/// A stored thread, along with the user it belongs to.
+pub struct StoredThread {
+    pub user_id: String,
+    pub thread_id: String,
+    pub repo_ref: RepoRef,
+    pub exchanges: Vec<Exchange>,
+}
+
+/// Load every thread that was last stored at or after `since`, a unix timestamp.
+pub async fn load_since(db: &SqlDb, since: i64) -> Result<Vec<StoredThread>> {
+    let rows = sqlx::query! {
+        "SELECT user_id, thread_id, repo_ref, exchanges, citations, archived FROM conversations \
+         WHERE created_at >= ? \
+         ORDER BY created_at",
+        since,
+    }
+    .fetch_all(db.as_ref())
+    .await?;
+
+    let mut threads = Vec::with_capacity(rows.len());
+    for row in rows {
+        let repo_ref = RepoRef::from_str(&row.repo_ref).context("failed to parse repo ref")?;
+        let (recent, _) = resolve(&repo_ref, &row.exchanges, row.citations.as_deref())?;
+
+        let mut exchanges = if row.archived > 0 {
+            load_archive(db, &row.user_id, &row.thread_id, &repo_ref).await?
+        } else {
+            Vec::new()
+        };
+        exchanges.extend(recent);
+
+        threads.push(StoredThread {
+            user_id: row.user_id,
+            thread_id: row.thread_id,
+            repo_ref,
+            exchanges,
+        });
+    }
+
+    Ok(threads)
+}
+
+/// Rebuild stored exchanges, returning whether they were stored in the legacy format.
+fn resolve(
+    repo_ref: &RepoRef,
+    exchanges: &str,
+    citations: Option<&str>,
+) -> Result<(Vec<Exchange>, bool)> {
+    let mut registry = match citations {
+        Some(c) => serde_json::from_str::<CitationRegistry>(c)?,
+        None => CitationRegistry::default(),
+    };
+
+    registry.resolve(repo_ref, serde_json::from_str(exchanges)?)
+}
+
+/// Move the code chunks of conversations stored before citations were registered into a registry.
+pub async fn backfill_citations(db: &SqlDb) -> Result<()> {
+    let rows = sqlx::query! {
+        "SELECT id, repo_ref, exchanges FROM conversations WHERE citations IS NULL"
+    }
+    .fetch_all(db.as_ref())
+    .await?;
+
+    if rows.is_empty() {
+        return Ok(());
+    }
+
+    info!(count = rows.len(), "backfilling conversation citations");
+    let mut transaction = db.begin().await?;
+
+    for row in rows {
+        let repo_ref = RepoRef::from_str(&row.repo_ref).context("failed to parse repo ref")?;
+        let (exchanges, _) = resolve(&repo_ref, &row.exchanges, None)
+            .with_context(|| format!("failed to read conversation {}", row.id))?;
+
+        let mut citations = CitationRegistry::default();
+        let exchanges = citations.normalize(&repo_ref, &exchanges)?.to_string();
+        let citations = serde_json::to_string(&citations)?;
+
+        sqlx::query! {
+            "UPDATE conversations SET exchanges = ?, citations = ? WHERE id = ?",
+            exchanges,
+            citations,
+            row.id,
+        }
+        .execute(&mut transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
 
-    Ok(Some((repo_ref, exchanges)))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backfill_legacy_thread() {
+        let repo_ref = RepoRef::from("github.com/BloopAI/bloop");
+        let legacy = include_str!("../../agent/fixtures/legacy_thread.json");
+
+        let (exchanges, was_legacy) = resolve(&repo_ref, legacy, None).unwrap();
+        assert!(was_legacy);
+
+        let mut citations = CitationRegistry::default();
+        let stored = citations
+            .normalize(&repo_ref, &exchanges)
+            .unwrap()
+            .to_string();
+        let citations = serde_json::to_string(&citations).unwrap();
+
+        let (migrated, was_legacy) = resolve(&repo_ref, &stored, Some(&citations)).unwrap();
+        assert!(!was_legacy);
+        assert_eq!(
+            serde_json::to_value(&exchanges).unwrap(),
+            serde_json::to_value(&migrated).unwrap()
+        );
+    }
+
+    fn exchange(i: usize) -> Exchange {
+        let query = crate::query::parser::SemanticQuery {
+            target: Some(crate::query::parser::Literal::Plain(
+                format!("query {i}").into(),
+            )),
+            ..Default::default()
+        };
+
+        let mut exchange = Exchange::new(uuid::Uuid::new_v4(), query);
+        exchange.paths.push(format!("src/file_{i}.rs"));
+        exchange
+    }
+
+    fn paths(exchanges: &[Exchange]) -> Vec<String> {
+        exchanges.iter().flat_map(|e| e.paths.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_long_thread_window() {
+        let tmpdir = tempdir::TempDir::new("conversations").unwrap();
+        let db: SqlDb = crate::db::connect(&tmpdir.path().to_string_lossy())
+            .await
+            .unwrap()
+            .into();
+
+        let repo_ref = RepoRef::from("github.com/BloopAI/bloop");
+        let id = ConversationId {
+            thread_id: uuid::Uuid::new_v4(),
+            user_id: "alice".to_owned(),
+        };
+
+        // Short threads are stored whole.
+        let short = (0..3).map(exchange).collect::<Vec<_>>();
+        store(&db, id.clone(), Window::new(repo_ref.clone(), short), None)
+            .await
+            .unwrap();
+        let window = load_window(&db, &id).await.unwrap().unwrap();
+        assert_eq!(window.archived, 0);
+        assert_eq!(window.exchanges.len(), 3);
+
+        let thread = (0..500).map(exchange).collect::<Vec<_>>();
+        store(
+            &db,
+            id.clone(),
+            Window::new(repo_ref.clone(), thread.clone()),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // Resuming the thread only loads its last exchanges, and aliases the same paths.
+        let mut window = load_window(&db, &id).await.unwrap().unwrap();
+        assert_eq!(window.archived, 500 - WINDOW_SIZE);
+        assert_eq!(window.exchanges.len(), WINDOW_SIZE);
+        assert_eq!(window.archived_paths.len(), 500 - WINDOW_SIZE);
+
+        let aliases = window
+            .archived_paths
+            .iter()
+            .cloned()
+            .chain(paths(&window.exchanges))
+            .collect::<Vec<_>>();
+        assert_eq!(aliases, paths(&thread));
+
+        window.exchanges.push(exchange(500));
+        store(&db, id.clone(), window, None).await.unwrap();
+
+        let window = load_window(&db, &id).await.unwrap().unwrap();
+        assert_eq!(window.archived, 501 - WINDOW_SIZE);
+        assert_eq!(window.exchanges.len(), WINDOW_SIZE);
+
+        // Loading the whole thread reads back the archive in order.
+        let (_, exchanges) = load(&db, &id).await.unwrap().unwrap();
+        assert_eq!(exchanges.len(), 501);
+        assert_eq!(
+            exchanges.iter().map(|e| e.id).collect::<Vec<_>>()[..500],
+            thread.iter().map(|e| e.id).collect::<Vec<_>>()[..]
+        );
+
+        // Storing a whole thread again rewrites the archive.
+        store(
+            &db,
+            id.clone(),
+            Window::new(repo_ref, exchanges[..10].to_vec()),
+            None,
+        )
+        .await
+        .unwrap();
+        let (_, exchanges) = load(&db, &id).await.unwrap().unwrap();
+        assert_eq!(exchanges.len(), 10);
+
+        db.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_save_anonymizes_thread() {
+        let index_dir = tempdir::TempDir::new("conversations").unwrap();
+        let app = webserver::tests::app(
+            &index_dir,
+            serde_json::json!({ "anonymize_patterns": [r"[\w.+-]+@[\w-]+(\.[\w-]+)+"] }),
+        )
+        .await;
+
+        let id = ConversationId {
+            thread_id: uuid::Uuid::new_v4(),
+            user_id: "alice".to_owned(),
+        };
+        let mut exchange = Exchange::new(
+            uuid::Uuid::new_v4(),
+            crate::query::parser::SemanticQuery {
+                target: Some(crate::query::parser::Literal::Plain(
+                    "why does jane.doe@example.com get no emails?".into(),
+                )),
+                ..Default::default()
+            },
+        );
+        exchange.answer = Some("Mail to jane.doe@example.com bounces.".into());
+
+        let repo_ref = RepoRef::from("github.com/BloopAI/bloop");
+        save(&app, id.clone(), Window::new(repo_ref, vec![exchange]))
+            .await
+            .unwrap();
+
+        let (_, exchanges) = load(&app.sql, &id).await.unwrap().unwrap();
+        let json = serde_json::to_string(&exchanges).unwrap();
+        assert!(!json.contains('@'), "{json}");
+        assert_eq!(
+            exchanges[0].answer.as_deref(),
+            Some("Mail to [REDACTED] bounces.")
+        );
+
+        let title: String = sqlx::query_scalar("SELECT title FROM conversations WHERE user_id = ?")
+            .bind("alice")
+            .fetch_one(app.sql.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(title, "why does [REDACTED] get no emails?");
+    }
+    #[tokio::test]
+    async fn test_export() {
+        use axum::{
+            body::{Body, HttpBody},
+            http::Request,
+            routing::get,
+            Router,
+        };
+        use tower::ServiceExt;
+
+        use crate::{acl::tests::user, agent::exchange::Update};
+
+        let index_dir = tempdir::TempDir::new("conversations").unwrap();
+        let app = webserver::tests::app(&index_dir, serde_json::json!({})).await;
+
+        let repo_ref = RepoRef::new(
+            crate::repo::Backend::LocalDir,
+            &index_dir.path().to_string_lossy(),
+        )
+        .unwrap();
+        let (answered, unanswered) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+        for thread_id in [answered, unanswered] {
+            let mut exchange = exchange(0);
+            if thread_id == answered {
+                exchange.apply_update(Update::Article("It is retried.".into()));
+                exchange.apply_update(Update::Conclude("Anything else?".into()));
+            }
+
+            let id = ConversationId {
+                thread_id,
+                user_id: "alice".to_owned(),
+            };
+            let window = Window::new(repo_ref.clone(), vec![exchange]);
+            store(&app.sql, id, window, None).await.unwrap();
+        }
+
+        let request = |login, thread_id| {
+            Router::new()
+                .route("/threads/:thread_id/export", get(export))
+                .layer(Extension(user(login)))
+                .with_state(app.clone())
+                .oneshot(
+                    Request::get(format!("/threads/{thread_id}/export"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+        };
+
+        let mut response = request("alice", answered).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.body_mut().data().await.unwrap().unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body.lines().count(), 1);
+
+        let record = serde_json::from_str::<Value>(body).unwrap();
+        let messages = record["messages"].as_array().unwrap();
+        assert_eq!(messages.last().unwrap()["role"], "assistant");
+
+        // Threads must be answered to be exported, and other users' threads are not found.
+        let response = request("alice", unanswered).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = request("bob", answered).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_thread_pages() {
+        use axum::{
+            body::{Body, HttpBody},
+            http::Request,
+            routing::get,
+            Router,
+        };
+        use tower::ServiceExt;
+
+        use crate::acl::tests::user;
+
+        let index_dir = tempdir::TempDir::new("conversations").unwrap();
+        let app = webserver::tests::app(&index_dir, serde_json::json!({})).await;
+
+        let repo_ref = RepoRef::new(
+            crate::repo::Backend::LocalDir,
+            &index_dir.path().to_string_lossy(),
+        )
+        .unwrap();
+        let thread_id = uuid::Uuid::new_v4();
+        let exchanges = (0..5).map(exchange).collect::<Vec<_>>();
+        let ids = exchanges
+            .iter()
+            .map(|e| e.id.to_string())
+            .collect::<Vec<_>>();
+
+        let id = ConversationId {
+            thread_id,
+            user_id: "alice".to_owned(),
+        };
+        store(&app.sql, id, Window::new(repo_ref, exchanges), None)
+            .await
+            .unwrap();
+
+        let app = &app;
+        let request = |query: String| async move {
+            let mut response = Router::new()
+                .route("/answer/conversations/:thread_id", get(thread))
+                .layer(Extension(user("alice")))
+                .with_state(app.clone())
+                .oneshot(
+                    Request::get(format!("/answer/conversations/{thread_id}?{query}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            let body = response.body_mut().data().await.unwrap().unwrap();
+            (
+                response.status(),
+                serde_json::from_slice::<Value>(&body).unwrap(),
+            )
+        };
+        let page_ids = |page: &Value| {
+            page["exchanges"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|e| e["id"].as_str().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let (status, first) = request("page_size=2".to_owned()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page_ids(&first), ids[..2]);
+
+        let cursor = first["next_cursor"].as_str().unwrap();
+        let (status, next) = request(format!("page_size=2&cursor={cursor}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page_ids(&next), ids[2..4]);
+
+        let cursor = next["next_cursor"].as_str().unwrap();
+        let (_, last) = request(format!("page_size=2&cursor={cursor}")).await;
+        assert_eq!(page_ids(&last), ids[4..]);
+        assert_eq!(last["next_cursor"], Value::Null);
+
+        // A cursor that was moved to another exchange no longer matches its signature.
+        let (_, tag) = cursor.split_once('.').unwrap();
+        let (status, _) = request(format!("page_size=2&cursor=1.{tag}")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 }
@@ -0,0 +1,263 @@
+//! Canned answers, which are matched against a query before the agent runs.
+
+use regex::Regex;
+use tracing::warn;
+
+use crate::{
+    agent::exchange::Suggestion, db::Faq, repo::RepoRef, semantic::Embedding, Application,
+};
+
+/// The minimum similarity for an FAQ to be returned instead of running the agent.
+const HIT_THRESHOLD: f32 = 0.92;
+
+/// The minimum similarity for an FAQ to be suggested alongside the agent's answer.
+const SUGGESTION_THRESHOLD: f32 = 0.8;
+
+const MAX_SUGGESTIONS: usize = 3;
+
+#[derive(Debug, PartialEq)]
+pub enum Match<'a> {
+    /// The query is answered by this FAQ.
+    Hit(&'a Faq),
+    /// The query should be answered by the agent, and these FAQs suggested alongside.
+    Suggestions(Vec<Suggestion>),
+}
+
+/// Match a query against a list of FAQs.
+///
+/// FAQs scoped to another repository are ignored. A pattern match takes precedence over an
+/// embedding match, and among equally good matches repository-scoped FAQs take precedence over
+/// global ones.
+pub fn find<'a>(
+    faqs: &'a [Faq],
+    repo_ref: &RepoRef,
+    query: &str,
+    embedding: Option<&[f32]>,
+) -> Match<'a> {
+    let repo_ref = repo_ref.to_string();
+
+    let mut candidates = faqs
+        .iter()
+        .filter(|faq| faq.repo_ref.as_ref().map_or(true, |r| *r == repo_ref))
+        .collect::<Vec<_>>();
+
+    // This is a stable sort, so FAQs otherwise stay in order of creation.
+    candidates.sort_by_key(|faq| faq.repo_ref.is_none());
+
+    if let Some(faq) = candidates.iter().find(|faq| matches_text(faq, query)) {
+        return Match::Hit(faq);
+    }
+
+    let Some(embedding) = embedding else {
+        return Match::Suggestions(vec![]);
+    };
+
+    let mut scored = candidates
+        .into_iter()
+        .filter_map(|faq| {
+            let score = cosine_similarity(faq.embedding.as_deref()?, embedding)?;
+            (score >= SUGGESTION_THRESHOLD).then_some((faq, score))
+        })
+        .collect::<Vec<_>>();
+
+    scored.sort_by(|(a, a_score), (b, b_score)| {
+        let hit = |score: f32| score >= HIT_THRESHOLD;
+
+        hit(*b_score)
+            .cmp(&hit(*a_score))
+            .then_with(|| a.repo_ref.is_none().cmp(&b.repo_ref.is_none()))
+            .then_with(|| b_score.total_cmp(a_score))
+    });
+
+    match scored.first() {
+        Some((faq, score)) if *score >= HIT_THRESHOLD => Match::Hit(faq),
+        _ => Match::Suggestions(
+            scored
+                .into_iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|(faq, _)| Suggestion {
+                    faq_id: faq.id,
+                    question: faq.question.clone(),
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Embed a query for matching, if any of the FAQs have an embedding.
+///
+/// Failures are logged, and fall back to matching by pattern only.
+pub async fn embed(app: &Application, faqs: &[Faq], query: &str) -> Option<Embedding> {
+    let semantic = app.semantic.clone()?;
+
+    if faqs.iter().all(|faq| faq.embedding.is_none()) {
+        return None;
+    }
+
+    let query = query.to_owned();
+    match tokio::task::spawn_blocking(move || semantic.embed(&query)).await {
+        Ok(Ok(embedding)) => Some(embedding),
+        Ok(Err(err)) => {
+            warn!(?err, "failed to embed query for FAQ matching");
+            None
+        }
+        Err(err) => {
+            warn!(?err, "FAQ embedding task failed");
+            None
+        }
+    }
+}
+
+/// Whether the query matches the FAQ pattern or, if there is none, the FAQ question itself.
+fn matches_text(faq: &Faq, query: &str) -> bool {
+    match &faq.pattern {
+        Some(pattern) => match Regex::new(pattern) {
+            Ok(re) => re.is_match(query),
+            Err(err) => {
+                warn!(?err, faq_id = faq.id, "invalid FAQ pattern");
+                false
+            }
+        },
+        None => normalize(&faq.question) == normalize(query),
+    }
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_end_matches('?')
+        .to_lowercase()
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() || a.is_empty() {
+        return None;
+    }
+
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    match norm(a) * norm(b) {
+        n if n == 0.0 => None,
+        n => Some(dot / n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn faq(id: i64, pattern: Option<&str>, repo_ref: Option<&str>, embedding: &[f32]) -> Faq {
+        Faq {
+            id,
+            created_at: 0,
+            question: format!("question {id}"),
+            pattern: pattern.map(str::to_owned),
+            answer: format!("answer {id}"),
+            repo_ref: repo_ref.map(str::to_owned),
+            embedding: (!embedding.is_empty()).then(|| embedding.to_vec()),
+        }
+    }
+
+    fn repo() -> RepoRef {
+        "github.com/bloopai/bloop".parse().unwrap()
+    }
+
+    fn hit_id(m: Match) -> Option<i64> {
+        match m {
+            Match::Hit(faq) => Some(faq.id),
+            Match::Suggestions(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_matching_precedence() {
+        let faqs = vec![
+            faq(1, None, None, &[1.0, 0.0]),
+            faq(2, Some("(?i)deploy"), None, &[]),
+            faq(3, Some("(?i)deploy"), Some("github.com/bloopai/bloop"), &[]),
+        ];
+
+        // A pattern match beats an exact embedding match, and a repository-scoped FAQ beats a
+        // global one.
+        assert_eq!(
+            hit_id(find(&faqs, &repo(), "How do I Deploy?", Some(&[1.0, 0.0]))),
+            Some(3)
+        );
+
+        // Without a pattern match, fall back to embeddings.
+        assert_eq!(
+            hit_id(find(&faqs, &repo(), "something else", Some(&[1.0, 0.01]))),
+            Some(1)
+        );
+
+        // FAQs without a pattern match their question verbatim.
+        assert_eq!(
+            hit_id(find(&faqs, &repo(), "  Question   1?", None)),
+            Some(1)
+        );
+
+        // Invalid patterns never match.
+        let faqs = vec![faq(4, Some("(unclosed"), None, &[])];
+        assert_eq!(
+            find(&faqs, &repo(), "(unclosed", None),
+            Match::Suggestions(vec![])
+        );
+    }
+
+    #[test]
+    fn test_repo_scoping() {
+        let faqs = vec![
+            faq(
+                1,
+                Some("deploy"),
+                Some("github.com/bloopai/other"),
+                &[1.0, 0.0],
+            ),
+            faq(2, None, None, &[0.0, 1.0]),
+            faq(3, None, Some("github.com/bloopai/bloop"), &[0.0, 1.0]),
+        ];
+
+        assert_eq!(
+            hit_id(find(&faqs, &repo(), "deploy", Some(&[1.0, 0.0]))),
+            None
+        );
+        assert_eq!(
+            hit_id(find(&faqs, &repo(), "other", Some(&[0.0, 1.0]))),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let faqs = vec![
+            faq(1, None, None, &[0.6, 0.8]),
+            faq(2, None, None, &[0.85, 0.527]),
+            faq(3, None, None, &[0.9, 0.436]),
+            faq(4, None, None, &[0.0, 1.0]),
+        ];
+
+        // No match is close enough to replace the agent, but the near-misses are suggested, best
+        // first.
+        let query = [1.0, 0.0];
+        assert_eq!(
+            find(&faqs, &repo(), "query", Some(&query)),
+            Match::Suggestions(vec![
+                Suggestion {
+                    faq_id: 3,
+                    question: "question 3".into(),
+                },
+                Suggestion {
+                    faq_id: 2,
+                    question: "question 2".into(),
+                },
+            ])
+        );
+
+        assert_eq!(
+            find(&faqs, &repo(), "query", None),
+            Match::Suggestions(vec![])
+        );
+    }
+}
@@ -0,0 +1,44 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+
+use crate::{
+    db::{SnippetId, SnippetStore},
+    webserver::{self, middleware::User, Error, ErrorKind},
+    Application,
+};
+
+/// List the answers that the user saved as snippets, most recent first.
+pub(in crate::webserver) async fn list(
+    Extension(user): Extension<User>,
+    State(app): State<Application>,
+) -> webserver::Result<impl IntoResponse> {
+    let user_id = user.login().ok_or_else(|| Error::user("missing user ID"))?;
+
+    let snippets = SnippetStore::new(&app.sql)
+        .list_for_user(user_id)
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(Json(snippets))
+}
+
+pub(in crate::webserver) async fn get(
+    Path(id): Path<uuid::Uuid>,
+    Extension(user): Extension<User>,
+    State(app): State<Application>,
+) -> webserver::Result<impl IntoResponse> {
+    let user_id = user.login().ok_or_else(|| Error::user("missing user ID"))?;
+
+    let snippet = SnippetStore::new(&app.sql)
+        .get(SnippetId(id))
+        .await
+        .map_err(Error::internal)?
+        // Snippets of other users are treated as non-existent.
+        .filter(|s| s.user_id == user_id)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "snippet was not found"))?;
+
+    Ok(Json(snippet))
+}
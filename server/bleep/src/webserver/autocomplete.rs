@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use super::prelude::*;
+use super::{middleware::User, prelude::*};
 use crate::{
     indexes::{
         reader::{ContentReader, FileReader, RepoReader},
@@ -11,21 +11,28 @@ use crate::{
         parser,
         parser::{Literal, Target},
     },
+    Application,
 };
 
-use axum::{extract::Query, response::IntoResponse as IntoAxumResponse, Extension};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse as IntoAxumResponse,
+    Extension,
+};
 use futures::{stream, StreamExt, TryStreamExt};
 use serde::Serialize;
 
 pub(super) async fn handle(
     Query(mut api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
 ) -> Result<impl IntoAxumResponse> {
     // Override page_size and set to low value
     api_params.page = 0;
     api_params.page_size = 3;
 
-    let queries = parser::parse(&api_params.q).map_err(Error::user)?;
+    let mut queries = parser::parse(&api_params.q).map_err(Error::user)?;
     let mut autocomplete_results = vec![];
 
     // Only execute prefix search on flag names if there is a non-regex content target.
@@ -58,6 +65,16 @@ pub(super) async fn handle(
 
     // If no flags completion, run a search with full query
     if autocomplete_results.is_empty() {
+        // Queries filtered to repositories the user can't query are dropped before they run, and
+        // results from the other queries are filtered as in `/q`.
+        if app.access.strip_denied_queries(&user, &mut queries) > 0 && queries.is_empty() {
+            return Ok(json(AutocompleteResponse {
+                count: 0,
+                data: vec![],
+            }));
+        }
+        let denied = app.access.denied(&user);
+
         let contents = ContentReader.execute(&indexes.file, &queries, &api_params);
         let repos = RepoReader.execute(&indexes.repo, &queries, &api_params);
         let files = FileReader.execute(&indexes.file, &queries, &api_params);
@@ -67,7 +84,7 @@ pub(super) async fn handle(
             // simply an upper bound.
             .buffered(10)
             .try_fold(Vec::new(), |mut a, e| async {
-                a.extend(e.data.into_iter());
+                a.extend(e.without_repos(&denied).data.into_iter());
                 Ok(a)
             })
             .await
@@ -181,3 +198,54 @@ const COMMON_LANGUAGES: &[&str] = &[
     "objective-c++",
     "actionscript",
 ];
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, HttpBody},
+        http::Request,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{acl::tests::user, db::RepoAcl, webserver};
+
+    #[tokio::test]
+    async fn test_denied() {
+        let index_dir = tempdir::TempDir::new("bleep-test").unwrap();
+        let app = webserver::tests::app(&index_dir, serde_json::json!({})).await;
+        app.access
+            .set(
+                &app.sql,
+                RepoAcl {
+                    repo_ref: "github.com/acme/payments".to_owned(),
+                    public: false,
+                    users: vec!["alice".to_owned()],
+                    groups: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut response = axum::Router::new()
+            .route("/autocomplete", axum::routing::get(handle))
+            .layer(Extension(user("carol")))
+            .layer(Extension(app.indexes.clone()))
+            .with_state(app.clone())
+            .oneshot(
+                Request::get("/autocomplete?q=repo:acme/payments%20refund")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Queries of unauthorized repositories complete to nothing, without running.
+        let body = response.body_mut().data().await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "count": 0, "data": [] })
+        );
+    }
+}
@@ -55,9 +55,20 @@ pub(super) async fn get(
         .and_then(|login| app.user_profiles.read(login, |_, v| v.clone()))
         .unwrap_or_default();
 
+    // The frontend only sends analytics if it is given a key.
+    let analytics_allowed = cfg!(feature = "analytics") && !app.config.disable_analytics;
+
     json(ConfigResponse {
-        analytics_data_plane: app.config.analytics_data_plane.clone(),
-        analytics_key_fe: app.config.analytics_key_fe.clone(),
+        analytics_data_plane: app
+            .config
+            .analytics_data_plane
+            .clone()
+            .filter(|_| analytics_allowed),
+        analytics_key_fe: app
+            .config
+            .analytics_key_fe
+            .clone()
+            .filter(|_| analytics_allowed),
         sentry_dsn_fe: app.config.sentry_dsn_fe.clone(),
         user_login: user.login().map(str::to_owned),
         schema_version: crate::state::SCHEMA_VERSION.into(),
@@ -3,9 +3,9 @@ use std::{path::PathBuf, sync::Arc};
 use anyhow::Context;
 use axum::{extract::Query, Extension, Json};
 
-use crate::repo::RepoRef;
+use crate::{repo::RepoRef, Application};
 
-use super::prelude::*;
+use super::{middleware::User, prelude::*};
 
 #[derive(Debug, serde::Deserialize)]
 pub(super) struct Params {
@@ -31,7 +31,11 @@ impl super::ApiResponse for FileResponse {}
 pub(super) async fn handle<'a>(
     Query(params): Query<Params>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
 ) -> Result<Json<super::Response<'a>>, Error> {
+    super::check_repo_access(&app, &user, &params.repo_ref)?;
+
     let doc = indexes
         .file
         .by_path(
@@ -39,8 +43,7 @@ pub(super) async fn handle<'a>(
             params.path.to_str().context("invalid file path")?,
             params.branch.as_deref(),
         )
-        .await
-        .map_err(Error::internal)?
+        .await?
         .ok_or_else(|| Error::user("file not found").with_status(StatusCode::NOT_FOUND))?;
 
     Ok(json(FileResponse {
@@ -47,17 +47,15 @@ pub(super) async fn status(Extension(app): Extension<Application>) -> impl IntoR
             .login;
 
         app.with_analytics(|analytics| {
-            use rudderanalytics::message::{Identify, Message};
-            analytics.send(Message::Identify(Identify {
-                user_id: Some(analytics.tracking_id(Some(&username))),
-                traits: Some(serde_json::json!({
+            analytics.identify(
+                &username,
+                serde_json::json!({
                     "org_name": app.org_name(),
                     "device_id": analytics.device_id(),
                     "is_self_serve": app.env.is_cloud_instance(),
                     "github_username": username,
-                })),
-                ..Default::default()
-            }));
+                }),
+            );
         });
     }
 
@@ -1,9 +1,16 @@
 use std::sync::Arc;
 
 use super::prelude::*;
-use crate::{indexes::Indexes, repo::RepoRef, text_range::TextRange};
+use crate::{
+    indexes::Indexes, repo::RepoRef, text_range::TextRange, webserver::middleware::User,
+    Application,
+};
 
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension,
+};
 use serde::{Deserialize, Serialize};
 
 /// The request made to the `hoverable` endpoint.
@@ -28,10 +35,13 @@ pub(super) struct HoverableResponse {
 impl super::ApiResponse for HoverableResponse {}
 
 pub(super) async fn handle(
+    State(app): State<Application>,
     Query(payload): Query<HoverableRequest>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(user): Extension<User>,
 ) -> impl IntoResponse {
     let repo_ref = &payload.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
+    super::check_repo_access(&app, &user, repo_ref)?;
 
     let document = match indexes
         .file
@@ -52,8 +62,10 @@ pub(super) async fn handle(
 
 #[cfg(test)]
 mod tests {
+    use axum::{body::Body, http::Request};
+
     use super::*;
-    use crate::text_range::Point;
+    use crate::{acl::tests::user, db::RepoAcl, text_range::Point, webserver};
 
     #[test]
     fn serialize_response() {
@@ -82,4 +94,34 @@ mod tests {
 
         assert_eq!(expected, observed)
     }
+
+    #[tokio::test]
+    async fn test_denied() {
+        let index_dir = tempdir::TempDir::new("bleep-test").unwrap();
+        let app = webserver::tests::app(&index_dir, serde_json::json!({})).await;
+        app.access
+            .set(
+                &app.sql,
+                RepoAcl {
+                    repo_ref: "github.com/acme/payments".to_owned(),
+                    public: false,
+                    users: vec!["alice".to_owned()],
+                    groups: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+        let router = axum::Router::new().route("/hoverable", axum::routing::get(handle));
+        let request =
+            Request::get("/hoverable?repo_ref=github.com/acme/payments&relative_path=src/lib.rs")
+                .body(Body::empty())
+                .unwrap();
+
+        // Unauthorized users are told that the repository doesn't exist.
+        assert_eq!(
+            webserver::tests::status(router, &app, user("carol"), request).await,
+            StatusCode::NOT_FOUND
+        );
+    }
 }
@@ -10,9 +10,15 @@ use crate::{
     repo::RepoRef,
     snippet::Snipper,
     text_range::TextRange,
+    webserver::middleware::User,
+    Application,
 };
 
-use axum::{extract::Query, response::IntoResponse, Extension};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension,
+};
 use serde::{Deserialize, Serialize};
 
 /// The request made to the `local-intel` endpoint.
@@ -47,10 +53,13 @@ impl TokenInfoResponse {
 impl super::ApiResponse for TokenInfoResponse {}
 
 pub(super) async fn handle(
+    State(app): State<Application>,
     Query(payload): Query<TokenInfoRequest>,
     Extension(indexes): Extension<Arc<Indexes>>,
+    Extension(user): Extension<User>,
 ) -> Result<impl IntoResponse> {
     let repo_ref = payload.repo_ref.parse::<RepoRef>().map_err(Error::user)?;
+    super::check_repo_access(&app, &user, &repo_ref)?;
 
     let token = Token {
         relative_path: payload.relative_path.as_str(),
@@ -188,8 +197,7 @@ async fn search_nav(
         BooleanQuery::intersection(terms)
     };
     let collector = TopDocs::with_limit(500);
-    let reader = indexes.file.reader.read().await;
-    let searcher = reader.searcher();
+    let searcher = indexes.file.searcher().await?;
     let results = searcher
         .search(&query, &collector)
         .expect("failed to search index");
@@ -270,8 +278,10 @@ async fn search_nav(
 
 #[cfg(test)]
 mod tests {
+    use axum::{body::Body, http::Request};
+
     use super::*;
-    use crate::{snippet::Snippet, text_range::Point};
+    use crate::{acl::tests::user, db::RepoAcl, snippet::Snippet, text_range::Point, webserver};
 
     #[test]
     fn serialize_response() {
@@ -337,6 +347,7 @@ mod tests {
                             .to_owned(),
                         highlights: vec![12..19],
                         symbols: vec![],
+                        symbol_path: None,
                     },
                 }],
                 },
@@ -361,6 +372,7 @@ mod tests {
                             data: "            indexes.reindex().await?;\n".to_owned(),
                             highlights: vec![12..19],
                             symbols: vec![],
+                            symbol_path: None,
                         },
                     }],
                 },
@@ -370,4 +382,35 @@ mod tests {
 
         pretty_assertions::assert_eq!(expected, observed)
     }
+
+    #[tokio::test]
+    async fn test_denied() {
+        let index_dir = tempdir::TempDir::new("bleep-test").unwrap();
+        let app = webserver::tests::app(&index_dir, serde_json::json!({})).await;
+        app.access
+            .set(
+                &app.sql,
+                RepoAcl {
+                    repo_ref: "github.com/acme/payments".to_owned(),
+                    public: false,
+                    users: vec!["alice".to_owned()],
+                    groups: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+        let router = axum::Router::new().route("/token-info", axum::routing::get(handle));
+        let request = Request::get(
+            "/token-info?repo_ref=github.com/acme/payments&relative_path=src/lib.rs&start=0&end=4",
+        )
+        .body(Body::empty())
+        .unwrap();
+
+        // Unauthorized users are told that the repository doesn't exist.
+        assert_eq!(
+            webserver::tests::status(router, &app, user("carol"), request).await,
+            StatusCode::NOT_FOUND
+        );
+    }
 }
@@ -30,6 +30,21 @@ impl User {
         Some(login)
     }
 
+    /// The user that is logged in to this instance, if any.
+    pub(crate) fn local(app: &Application) -> Self {
+        let app = app.clone();
+        app.credentials
+            .user()
+            .map(|user| User::Authenticated {
+                login: user,
+                crab: Arc::new(move || {
+                    let gh = app.credentials.github().context("no github")?;
+                    Ok(gh.client()?)
+                }),
+            })
+            .unwrap_or_else(|| User::Unknown)
+    }
+
     pub(crate) fn github(&self) -> Option<octocrab::Octocrab> {
         let User::Authenticated { crab, .. } = self
 	else {
@@ -71,19 +86,7 @@ async fn local_user_mw<B>(
     mut request: Request<B>,
     next: Next<B>,
 ) -> Response {
-    request.extensions_mut().insert(
-        app.clone()
-            .credentials
-            .user()
-            .map(|user| User::Authenticated {
-                login: user,
-                crab: Arc::new(move || {
-                    let gh = app.credentials.github().context("no github")?;
-                    Ok(gh.client()?)
-                }),
-            })
-            .unwrap_or_else(|| User::Unknown),
-    );
+    request.extensions_mut().insert(User::local(&app));
 
     next.run(request).await
 }
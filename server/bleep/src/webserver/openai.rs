@@ -0,0 +1,846 @@
+//! An endpoint that speaks OpenAI's chat completions API, so that existing OpenAI clients can ask
+//! the agent about a repository.
+//!
+//! The repository is picked by the `model` of a request, named like `bleep/github.com/org/repo`,
+//! or by the `x-bleep-repo` header, which takes precedence. Every request starts a new thread, so
+//! only the last user message is answered.
+
+use std::{panic::AssertUnwindSafe, str::FromStr};
+
+use anyhow::anyhow;
+use axum::{
+    extract::rejection::JsonRejection,
+    http::HeaderMap,
+    response::{
+        sse::{self, Sse},
+        Response,
+    },
+    Json,
+};
+use futures::{stream, StreamExt};
+use lazy_regex::regex;
+use secrecy::ExposeSecret;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::{middleware::User, prelude::*};
+use crate::{
+    agent::{self, exchange::Exchange, Action},
+    llm_gateway,
+    query::parser,
+    repo::RepoRef,
+    Application,
+};
+
+/// The prefix of model names that pick a repository.
+const MODEL_PREFIX: &str = "bleep/";
+
+/// The header that picks a repository, whatever the model.
+const REPO_HEADER: &str = "x-bleep-repo";
+
+#[derive(Debug, Deserialize)]
+pub(super) struct Request {
+    model: String,
+    messages: Vec<RequestMessage>,
+    #[serde(default)]
+    stream: bool,
+
+    // The agent has its own tools and sampling, so these can't be honoured.
+    tools: Option<Value>,
+    tool_choice: Option<Value>,
+    functions: Option<Value>,
+    function_call: Option<Value>,
+    logprobs: Option<bool>,
+    top_logprobs: Option<u32>,
+    n: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestMessage {
+    role: String,
+    #[serde(default)]
+    content: Option<Content>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: Option<String>,
+}
+
+impl Request {
+    /// The text of the last user message, which the agent answers.
+    fn query(&self) -> Result<String, OpenAiError> {
+        let unsupported = [
+            ("tools", self.tools.is_some()),
+            ("tool_choice", self.tool_choice.is_some()),
+            ("functions", self.functions.is_some()),
+            ("function_call", self.function_call.is_some()),
+            ("logprobs", self.logprobs == Some(true)),
+            ("top_logprobs", self.top_logprobs.is_some()),
+            ("n", matches!(self.n, Some(n) if n > 1)),
+        ];
+
+        if let Some((param, _)) = unsupported.into_iter().find(|(_, set)| *set) {
+            return Err(OpenAiError::unsupported(param));
+        }
+
+        let message = self
+            .messages
+            .iter()
+            .rev()
+            .find(|m| m.role == "user")
+            .ok_or_else(|| OpenAiError::invalid("messages", "there is no user message"))?;
+
+        let text = match &message.content {
+            Some(Content::Text(text)) => text.clone(),
+            Some(Content::Parts(parts)) => {
+                let mut text = Vec::new();
+                for part in parts {
+                    match (part.kind.as_str(), &part.text) {
+                        ("text", Some(t)) => text.push(t.as_str()),
+                        _ => {
+                            return Err(OpenAiError::invalid(
+                                "messages",
+                                "only text content is supported",
+                            ))
+                        }
+                    }
+                }
+                text.join("\n")
+            }
+            None => String::new(),
+        };
+
+        if text.trim().is_empty() {
+            return Err(OpenAiError::invalid(
+                "messages",
+                "the last user message is empty",
+            ));
+        }
+
+        Ok(text)
+    }
+
+    /// The repository that this request is about, from the repository header or the model name.
+    fn repo_ref(&self, headers: &HeaderMap) -> Result<RepoRef, OpenAiError> {
+        let name = match headers.get(REPO_HEADER) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| OpenAiError::invalid("model", "the repository header is not text"))?,
+            None => self
+                .model
+                .strip_prefix(MODEL_PREFIX)
+                .ok_or_else(|| OpenAiError::model_not_found(&self.model))?,
+        };
+
+        RepoRef::from_str(name).map_err(|_| OpenAiError::model_not_found(&self.model))
+    }
+}
+
+/// An error, in the shape of OpenAI's API errors.
+#[derive(Debug, Serialize)]
+pub(super) struct OpenAiError {
+    #[serde(skip)]
+    status: StatusCode,
+    message: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    param: Option<&'static str>,
+    code: Option<&'static str>,
+}
+
+impl OpenAiError {
+    fn invalid(param: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            kind: "invalid_request_error",
+            param: Some(param),
+            code: None,
+        }
+    }
+
+    fn unsupported(param: &'static str) -> Self {
+        Self {
+            code: Some("unsupported_parameter"),
+            ..Self::invalid(param, format!("`{param}` is not supported by bleep"))
+        }
+    }
+
+    fn model_not_found(model: &str) -> Self {
+        Self {
+            status: StatusCode::NOT_FOUND,
+            code: Some("model_not_found"),
+            ..Self::invalid(
+                "model",
+                format!(
+                    "the model `{model}` does not exist; name an indexed repository like \
+                     `{MODEL_PREFIX}github.com/org/repo`, or with the `{REPO_HEADER}` header"
+                ),
+            )
+        }
+    }
+
+    fn server(message: impl std::fmt::Display) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.to_string(),
+            kind: "server_error",
+            param: None,
+            code: None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        serde_json::json!({ "error": self })
+    }
+}
+
+impl IntoResponse for OpenAiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.to_json())).into_response()
+    }
+}
+
+pub(super) async fn chat_completions(
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
+    request: Result<Json<Request>, JsonRejection>,
+) -> Result<Response, OpenAiError> {
+    let Json(request) =
+        request.map_err(|rejection| OpenAiError::invalid("body", rejection.body_text()))?;
+
+    let q = request.query()?;
+    let repo_ref = request.repo_ref(&headers)?;
+    if app.repo_pool.read(&repo_ref, |_, _| ()).is_none() || !app.access.allows(&user, &repo_ref) {
+        return Err(OpenAiError::model_not_found(&request.model));
+    }
+
+    let not_a_question =
+        || OpenAiError::invalid("messages", "the last user message is not a question");
+
+    let query = parser::parse_nl(&q)
+        .ok()
+        .and_then(|query| query.into_semantic())
+        .ok_or_else(not_a_question)?
+        .with_raw_target(&q)
+        .into_owned();
+    let target = query
+        .target
+        .as_ref()
+        .and_then(|target| target.as_plain())
+        .map(|target| target.clone().into_owned())
+        .ok_or_else(not_a_question)?;
+
+    let gh_token = app
+        .github_token()
+        .map_err(|err| OpenAiError {
+            status: StatusCode::UNAUTHORIZED,
+            ..OpenAiError::server(err)
+        })?
+        .map(|s| s.expose_secret().clone());
+
+    let query_id = uuid::Uuid::new_v4();
+    let thread_id = uuid::Uuid::new_v4();
+    let llm_gateway = llm_gateway::Client::new(&app.config.answer_api_url)
+        .temperature(0.0)
+        .bearer(gh_token)
+        .endpoints(app.llm_endpoints.clone())
+        .session_reference_id(thread_id.to_string())
+        .with_system_fingerprint_validation(llm_gateway::FingerprintValidation::Warn);
+
+    let header = Header {
+        id: format!("chatcmpl-{}", query_id.simple()),
+        created: chrono::Utc::now().timestamp(),
+        model: request.model.clone(),
+    };
+
+    let updates = async_stream::try_stream! {
+        let mut driver = agent::builder::builder(app)
+            .repo(repo_ref)
+            .user(user)
+            .thread_id(thread_id)
+            .query_id(query_id)
+            .exchanges(vec![Exchange::new(query_id, query)])
+            .llm_gateway(llm_gateway)
+            // Answers are only extended by whole sentences and code blocks, which are never
+            // rewritten, so that they can be streamed as deltas.
+            .flush(agent::flush::Flush {
+                mode: agent::flush::FlushMode::Sentence,
+                code_blocks: agent::flush::CodeBlocks::Whole,
+            })
+            .build()?;
+
+        let mut result = Ok(());
+        for await update in driver.drive(Action::Query(target)) {
+            match update {
+                Ok(exchange) => yield exchange.answer.unwrap_or_default(),
+                Err(err) => result = Err(err),
+            }
+        }
+
+        let agent = driver.into_agent();
+        match result {
+            Ok(()) => agent.complete(),
+            Err(agent::Error::Timeout(duration)) => {
+                Err(anyhow!("reached timeout of {duration:?}"))?;
+            }
+            Err(agent::Error::Processing(err)) => Err(err)?,
+        }
+    };
+
+    // We know the stream is unwind safe as it doesn't use synchronization primitives like locks.
+    let updates = AssertUnwindSafe(updates)
+        .catch_unwind()
+        .map(|res| res.unwrap_or_else(|_| Err(anyhow!("stream panicked"))));
+
+    if !request.stream {
+        let mut answer = String::new();
+        futures::pin_mut!(updates);
+        while let Some(update) = updates.next().await {
+            answer = update.map_err(OpenAiError::server)?;
+        }
+
+        return Ok(Json(header.completion(&answer)).into_response());
+    }
+
+    let mut deltas = Deltas::new(header);
+    let mut failed = false;
+    let first = deltas.first();
+    let events = stream::once(async move { Ok(first) })
+        .chain(
+            updates
+                .map(Some)
+                .chain(stream::once(async { None }))
+                .flat_map(move |update| {
+                    let chunks = match update {
+                        Some(Ok(answer)) => Ok(deltas.push(&answer).into_iter().collect()),
+                        Some(Err(err)) => {
+                            failed = true;
+                            Err(OpenAiError::server(err))
+                        }
+                        // A response that failed is never finished.
+                        None if failed => Ok(vec![]),
+                        None => Ok(deltas.finish()),
+                    };
+
+                    stream::iter(match chunks {
+                        Ok(chunks) => chunks.into_iter().map(Ok).collect::<Vec<_>>(),
+                        Err(err) => {
+                            warn!(?err, "failed to answer a chat completion");
+                            vec![Err(err)]
+                        }
+                    })
+                }),
+        )
+        .map(|chunk| match chunk {
+            Ok(chunk) => sse::Event::default().json_data(chunk),
+            Err(err) => sse::Event::default().json_data(err.to_json()),
+        })
+        .chain(stream::once(async {
+            Ok(sse::Event::default().data("[DONE]"))
+        }));
+
+    Ok(Sse::new(events).into_response())
+}
+
+/// The fields that every completion and chunk of a response share.
+struct Header {
+    id: String,
+    created: i64,
+    model: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Completion {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionChoice {
+    index: usize,
+    message: CompletionMessage,
+    finish_reason: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct CompletionMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Chunk {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChunkChoice {
+    index: usize,
+    delta: Delta,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+impl Header {
+    /// A whole completion, with `answer` and its citations.
+    fn completion(&self, answer: &str) -> Completion {
+        Completion {
+            id: self.id.clone(),
+            object: "chat.completion",
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![CompletionChoice {
+                index: 0,
+                message: CompletionMessage {
+                    role: "assistant",
+                    content: format!("{answer}{}", citations_footer(answer)),
+                },
+                finish_reason: "stop",
+            }],
+        }
+    }
+
+    fn chunk(&self, delta: Delta, finish_reason: Option<&'static str>) -> Chunk {
+        Chunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk",
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![ChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+            }],
+        }
+    }
+}
+
+/// Turns the answer of a streamed exchange into the chunks of a streamed completion.
+struct Deltas {
+    header: Header,
+    /// The answer so far, as it was last sent.
+    sent: String,
+}
+
+impl Deltas {
+    fn new(header: Header) -> Self {
+        Self {
+            header,
+            sent: String::new(),
+        }
+    }
+
+    /// The first chunk of a response, which only has the role of the message.
+    fn first(&self) -> Chunk {
+        let delta = Delta {
+            role: Some("assistant"),
+            content: None,
+        };
+
+        self.header.chunk(delta, None)
+    }
+
+    /// A chunk with the text that `answer` adds to what was sent, if any.
+    ///
+    /// Text that was already sent can't be taken back, so an answer which rewrites it is skipped.
+    fn push(&mut self, answer: &str) -> Option<Chunk> {
+        let Some(added) = answer.strip_prefix(self.sent.as_str()) else {
+            debug!("answer was rewritten; skipping update");
+            return None;
+        };
+
+        if added.is_empty() {
+            return None;
+        }
+
+        let delta = Delta {
+            role: None,
+            content: Some(added.to_owned()),
+        };
+
+        self.sent = answer.to_owned();
+        Some(self.header.chunk(delta, None))
+    }
+
+    /// The last chunks of a response, with the citations of the answer and the finish reason.
+    fn finish(&mut self) -> Vec<Chunk> {
+        let footer = citations_footer(&self.sent);
+        let mut chunks = Vec::new();
+
+        if !footer.is_empty() {
+            let delta = Delta {
+                role: None,
+                content: Some(footer),
+            };
+            chunks.push(self.header.chunk(delta, None));
+        }
+
+        chunks.push(self.header.chunk(Delta::default(), Some("stop")));
+        chunks
+    }
+}
+
+/// A footer that lists the code quoted by an answer, or an empty string if no code was quoted.
+fn citations_footer(answer: &str) -> String {
+    let mut citations = Vec::new();
+    for captures in
+        regex!(r"```type:Quoted,[^\n]*?path:([^,\n]+),lines:(\d+)-(\d+)").captures_iter(answer)
+    {
+        let citation = format!(
+            "- `{}` (lines {}-{})",
+            &captures[1], &captures[2], &captures[3]
+        );
+        if !citations.contains(&citation) {
+            citations.push(citation);
+        }
+    }
+
+    if citations.is_empty() {
+        String::new()
+    } else {
+        format!("\n\n---\nSources:\n{}\n", citations.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// The parts of OpenAI's chat completion response schema that these responses implement.
+    fn completion_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["id", "object", "created", "model", "choices"],
+            "properties": {
+                "id": { "type": "string" },
+                "object": { "type": "string", "enum": ["chat.completion"] },
+                "created": { "type": "integer" },
+                "model": { "type": "string" },
+                "choices": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["index", "message", "finish_reason"],
+                        "properties": {
+                            "index": { "type": "integer" },
+                            "finish_reason": {
+                                "type": "string",
+                                "enum": ["stop", "length", "tool_calls", "content_filter"]
+                            },
+                            "message": {
+                                "type": "object",
+                                "required": ["role", "content"],
+                                "properties": {
+                                    "role": { "type": "string", "enum": ["assistant"] },
+                                    "content": { "type": "string", "nullable": true }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// The parts of OpenAI's chat completion chunk schema that these responses implement.
+    fn chunk_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["id", "object", "created", "model", "choices"],
+            "properties": {
+                "id": { "type": "string" },
+                "object": { "type": "string", "enum": ["chat.completion.chunk"] },
+                "created": { "type": "integer" },
+                "model": { "type": "string" },
+                "choices": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["index", "delta", "finish_reason"],
+                        "properties": {
+                            "index": { "type": "integer" },
+                            "finish_reason": {
+                                "type": "string",
+                                "nullable": true,
+                                "enum": ["stop", "length", "tool_calls", "content_filter"]
+                            },
+                            "delta": {
+                                "type": "object",
+                                "properties": {
+                                    "role": { "type": "string", "enum": ["assistant"] },
+                                    "content": { "type": "string", "nullable": true }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    /// Check `value` against the subset of the OpenAPI schema language used by OpenAI's spec.
+    fn validate(value: &Value, schema: &Value, path: &str) {
+        if value.is_null() {
+            assert_eq!(schema["nullable"], true, "{path} is not nullable");
+            return;
+        }
+
+        let ty = schema["type"].as_str().unwrap();
+        let matches = match ty {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            _ => panic!("unknown type {ty}"),
+        };
+        assert!(matches, "{path} is not of type {ty}: {value}");
+
+        if let Some(variants) = schema["enum"].as_array() {
+            assert!(
+                variants.contains(value),
+                "{path} is not one of {variants:?}"
+            );
+        }
+
+        for field in schema["required"].as_array().into_iter().flatten() {
+            let field = field.as_str().unwrap();
+            assert!(value.get(field).is_some(), "{path}.{field} is missing");
+        }
+
+        if let Some(properties) = schema["properties"].as_object() {
+            for (field, value) in value.as_object().unwrap() {
+                let schema = properties
+                    .get(field)
+                    .unwrap_or_else(|| panic!("{path}.{field} is not in the schema"));
+                validate(value, schema, &format!("{path}.{field}"));
+            }
+        }
+
+        if let Some(items) = schema.get("items") {
+            for (i, item) in value.as_array().unwrap().iter().enumerate() {
+                validate(item, items, &format!("{path}[{i}]"));
+            }
+        }
+    }
+
+    fn header() -> Header {
+        Header {
+            id: "chatcmpl-1".to_owned(),
+            created: 1700000000,
+            model: "bleep/github.com/bloopai/bloop".to_owned(),
+        }
+    }
+
+    const ANSWER: &str = concat!(
+        "Retries are scheduled with a backoff.\n\n",
+        "```type:Quoted,lang:Rust,path:src/retry.rs,lines:10-12\n",
+        "fn retry() {\n    backoff();\n}\n```\n\n",
+        "The delay doubles every time, in the same function:\n\n",
+        "```type:Quoted,lang:Rust,path:src/retry.rs,lines:10-12\n",
+        "fn retry() {\n    backoff();\n}\n```",
+    );
+
+    fn request(value: Value) -> Request {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_completion_shape() {
+        let completion = serde_json::to_value(header().completion(ANSWER)).unwrap();
+        validate(&completion, &completion_schema(), "completion");
+
+        let content = completion["choices"][0]["message"]["content"]
+            .as_str()
+            .unwrap();
+        assert!(content.starts_with(ANSWER));
+        assert!(content.ends_with("\n\n---\nSources:\n- `src/retry.rs` (lines 10-12)\n"));
+    }
+
+    #[test]
+    fn test_chunk_shapes() {
+        let mut deltas = Deltas::new(header());
+        let mut chunks = vec![deltas.first()];
+
+        // The answer grows by whole sentences and code blocks, with repeated updates in between.
+        let mut end = 0;
+        for (i, _) in ANSWER.match_indices("\n\n") {
+            chunks.extend(deltas.push(&ANSWER[..i]));
+            chunks.extend(deltas.push(&ANSWER[..i]));
+            end = i;
+        }
+        assert!(end > 0);
+        chunks.extend(deltas.push(ANSWER));
+        chunks.extend(deltas.finish());
+
+        let chunks = chunks
+            .into_iter()
+            .map(|c| serde_json::to_value(c).unwrap())
+            .collect::<Vec<_>>();
+        for (i, chunk) in chunks.iter().enumerate() {
+            validate(chunk, &chunk_schema(), &format!("chunk {i}"));
+        }
+
+        assert_eq!(
+            chunks[0]["choices"][0]["delta"],
+            json!({ "role": "assistant" })
+        );
+        let last = chunks.last().unwrap();
+        assert_eq!(last["choices"][0]["delta"], json!({}));
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|c| c["choices"][0]["finish_reason"].is_null()));
+
+        // The deltas add up to the content of a non-streamed completion.
+        let streamed = chunks
+            .iter()
+            .filter_map(|c| c["choices"][0]["delta"]["content"].as_str())
+            .collect::<String>();
+        let completion = header().completion(ANSWER);
+        assert_eq!(streamed, completion.choices[0].message.content);
+    }
+
+    #[test]
+    fn test_rewritten_answers_are_skipped() {
+        let mut deltas = Deltas::new(header());
+        assert!(deltas.push("Retries are scheduled.").is_some());
+        assert!(deltas.push("Retries are queued.").is_none());
+        assert_eq!(
+            deltas
+                .push("Retries are scheduled. They back off.")
+                .unwrap()
+                .choices[0]
+                .delta
+                .content
+                .as_deref(),
+            Some(" They back off.")
+        );
+    }
+
+    #[test]
+    fn test_request_query() {
+        let query = request(json!({
+            "model": "bleep/github.com/bloopai/bloop",
+            "messages": [
+                { "role": "system", "content": "You are helpful." },
+                { "role": "user", "content": "What is bloop?" },
+                { "role": "assistant", "content": "A code search engine." },
+                {
+                    "role": "user",
+                    "content": [{ "type": "text", "text": "How are retries scheduled?" }],
+                },
+            ],
+            "temperature": 0.2,
+        }))
+        .query()
+        .unwrap();
+        assert_eq!(query, "How are retries scheduled?");
+
+        let err = request(json!({
+            "model": "bleep/github.com/bloopai/bloop",
+            "messages": [{ "role": "system", "content": "You are helpful." }],
+        }))
+        .query()
+        .unwrap_err();
+        assert_eq!(err.status, StatusCode::BAD_REQUEST);
+        assert_eq!(err.param, Some("messages"));
+    }
+
+    #[test]
+    fn test_unsupported_fields_are_rejected() {
+        let messages = json!([{ "role": "user", "content": "How are retries scheduled?" }]);
+
+        for (field, value) in [
+            (
+                "tools",
+                json!([{ "type": "function", "function": { "name": "f" } }]),
+            ),
+            ("tool_choice", json!("auto")),
+            ("functions", json!([])),
+            ("logprobs", json!(true)),
+            ("top_logprobs", json!(2)),
+            ("n", json!(2)),
+        ] {
+            let mut body =
+                json!({ "model": "bleep/github.com/bloopai/bloop", "messages": messages });
+            body[field] = value;
+
+            let err = request(body).query().unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST);
+            assert_eq!(
+                err.to_json(),
+                json!({
+                    "error": {
+                        "message": format!("`{field}` is not supported by bleep"),
+                        "type": "invalid_request_error",
+                        "param": field,
+                        "code": "unsupported_parameter",
+                    }
+                })
+            );
+        }
+
+        // Values that are the same as the defaults are fine.
+        let body = json!({
+            "model": "bleep/github.com/bloopai/bloop",
+            "messages": messages,
+            "logprobs": false,
+            "n": 1,
+        });
+        assert!(request(body).query().is_ok());
+    }
+
+    #[test]
+    fn test_request_repo_ref() {
+        let req = request(json!({
+            "model": "bleep/github.com/bloopai/bloop",
+            "messages": [],
+        }));
+        assert_eq!(
+            req.repo_ref(&HeaderMap::new()).unwrap(),
+            RepoRef::from_str("github.com/bloopai/bloop").unwrap()
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(REPO_HEADER, "github.com/bloopai/other".parse().unwrap());
+        assert_eq!(
+            req.repo_ref(&headers).unwrap(),
+            RepoRef::from_str("github.com/bloopai/other").unwrap()
+        );
+
+        let err = request(json!({ "model": "gpt-4", "messages": [] }))
+            .repo_ref(&HeaderMap::new())
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+        assert_eq!(err.code, Some("model_not_found"));
+    }
+}
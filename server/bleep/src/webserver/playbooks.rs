@@ -0,0 +1,79 @@
+//! Runs of playbooks, which ask a series of templated questions in a new thread.
+
+use std::collections::HashMap;
+
+use axum::{extract::Path, Json};
+use secrecy::ExposeSecret;
+use tracing::info;
+
+use super::{
+    answer::conversations::{self, ConversationId, Window},
+    middleware::User,
+    prelude::*,
+};
+use crate::{
+    agent::{
+        self,
+        playbook::{self, PlaybookRun},
+    },
+    llm_gateway,
+    repo::RepoRef,
+    Application,
+};
+
+#[derive(Deserialize)]
+pub(super) struct RunParams {
+    repo_ref: RepoRef,
+    #[serde(default)]
+    params: HashMap<String, String>,
+}
+
+/// Run the playbook called `name` against a repository, storing its exchanges as a new thread.
+pub(super) async fn run(
+    Path(name): Path<String>,
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
+    Json(params): Json<RunParams>,
+) -> Result<Json<PlaybookRun>> {
+    let user_id = user
+        .login()
+        .ok_or_else(|| Error::user("didn't have user ID"))?
+        .to_owned();
+    super::check_repo_access(&app, &user, &params.repo_ref)?;
+
+    let playbook = playbook::load(&app.config)
+        .remove(&name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "unknown playbook"))?
+        .expand(&params.params)
+        .map_err(Error::user)?;
+
+    let gh_token = app
+        .github_token()
+        .map_err(|e| Error::user(e).with_status(StatusCode::UNAUTHORIZED))?
+        .map(|s| s.expose_secret().clone());
+
+    let thread_id = uuid::Uuid::new_v4();
+    let conversation_id = ConversationId { thread_id, user_id };
+    let llm_gateway = llm_gateway::Client::new(&app.config.answer_api_url)
+        .temperature(0.0)
+        .bearer(gh_token)
+        .endpoints(app.llm_endpoints.clone())
+        .session_reference_id(conversation_id.to_string())
+        .with_system_fingerprint_validation(llm_gateway::FingerprintValidation::Warn);
+
+    info!(playbook = name, repo = %params.repo_ref, "running playbook");
+    let driver = agent::builder::builder(app.clone())
+        .repo(params.repo_ref.clone())
+        .user(user)
+        .thread_id(thread_id)
+        .llm_gateway(llm_gateway)
+        .build()?;
+
+    let (agent, run) = playbook::run(driver, &playbook).await?;
+
+    let window = Window::new(params.repo_ref.clone(), agent.exchanges.clone());
+    conversations::save(&app, conversation_id, window).await?;
+    agent.complete();
+
+    Ok(Json(run))
+}
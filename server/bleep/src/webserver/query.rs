@@ -1,18 +1,20 @@
 use axum::extract::State;
 
-use super::prelude::*;
+use super::{middleware::User, prelude::*};
 use crate::{db::QueryLog, query::execute::ApiQuery, Application};
 
 pub(super) async fn handle(
     Query(api_params): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
     State(app): State<Application>,
+    Extension(user): Extension<User>,
 ) -> impl IntoResponse {
     QueryLog::new(&app.sql).insert(&api_params.q).await?;
 
+    let denied = app.access.denied(&user);
     Arc::new(api_params)
         .query(indexes)
         .await
-        .map(json)
+        .map(|response| json(response.without_repos(&denied)))
         .map_err(super::Error::from)
 }
@@ -1,8 +1,18 @@
-use std::{collections::HashSet, hash::Hash, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    hash::Hash,
+    time::Duration,
+};
 
 use crate::{
     background::QueuedRepoStatus,
-    repo::{Backend, BranchFilter, RepoRef, Repository, SyncStatus},
+    cache::FileCache,
+    db::RepoAcl,
+    indexes::{
+        diagnostics::{Category, Diagnostic},
+        lfs::LfsCounts,
+    },
+    repo::{Backend, Branch, BranchFilter, BranchSettings, RepoRef, Repository, SyncStatus},
     state::RepositoryPool,
     Application,
 };
@@ -14,15 +24,10 @@ use axum::{
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
+use tracing::info;
 
 use super::{middleware::User, prelude::*};
 
-#[derive(Serialize, Debug, PartialEq, Eq)]
-pub(crate) struct Branch {
-    last_commit_unix_secs: u64,
-    name: String,
-}
-
 #[derive(Serialize, Debug, Eq)]
 pub(crate) struct Repo {
     pub(super) provider: Backend,
@@ -35,69 +40,15 @@ pub(crate) struct Repo {
     pub(super) last_index: Option<DateTime<Utc>>,
     pub(super) most_common_lang: Option<String>,
     pub(super) branch_filter: BranchFilter,
+    pub(super) branch_settings: BranchSettings,
     pub(super) branches: Vec<Branch>,
+    pub(super) lfs: LfsCounts,
 }
 
 impl From<(&RepoRef, &Repository)> for Repo {
     fn from((key, repo): (&RepoRef, &Repository)) -> Self {
         use crate::repo::BranchFilter::*;
-        let (head, branches) = 'branch_list: {
-            let default = ("HEAD".to_string(), vec![]);
-            let Ok(git) = gix::open(&repo.disk_path)
-            else {
-                break 'branch_list default;
-            };
-
-            let head = git
-                .head()
-                .ok()
-                .and_then(|head| head.try_into_referent())
-                .map(|r| {
-                    if key.is_local() {
-                        r.name().shorten().to_string()
-                    } else {
-                        format!("origin/{}", r.name().shorten())
-                    }
-                })
-                .unwrap_or_else(|| default.0.clone());
-
-            let Ok(refs) = git.references()
-            else {
-                break 'branch_list default;
-            };
-
-            let Ok(refs) = refs.all()
-            else {
-                break 'branch_list default;
-            };
-
-            use gix::bstr::ByteSlice;
-            let mut branches = refs
-                .filter_map(Result::ok)
-                .filter_map(|mut r| {
-                    let name = r.name().shorten().to_str_lossy().to_string();
-                    let last_commit_unix_secs = r
-                        .peel_to_id_in_place()
-                        .ok()?
-                        .object()
-                        .ok()?
-                        .try_into_commit()
-                        .ok()?
-                        .time()
-                        .ok()?
-                        .seconds;
-
-                    Some(Branch {
-                        name,
-                        last_commit_unix_secs,
-                    })
-                })
-                .filter(|b| b.name != "origin/HEAD" && b.name.starts_with("origin/"))
-                .collect::<Vec<_>>();
-
-            branches.sort_by_key(|b| b.last_commit_unix_secs);
-            (head, branches)
-        };
+        let (head, branches) = repo.branches(key);
 
         let branch_filter = match repo.branch_filter.clone() {
             Some(All) => Select(vec![".*".to_string()]),
@@ -133,7 +84,9 @@ impl From<(&RepoRef, &Repository)> for Repo {
             },
             most_common_lang: repo.most_common_lang.clone(),
             branch_filter,
+            branch_settings: repo.branch_settings.clone(),
             branches,
+            lfs: repo.lfs,
         }
     }
 }
@@ -154,7 +107,9 @@ impl Repo {
             last_index: None,
             most_common_lang: None,
             branch_filter: crate::repo::BranchFilter::Select(vec![]),
+            branch_settings: Default::default(),
             branches: vec![],
+            lfs: Default::default(),
         }
     }
 }
@@ -179,6 +134,9 @@ pub(crate) enum ReposResponse {
     SyncQueue(Vec<QueuedRepoStatus>),
     SyncQueued,
     Deleted,
+    Diagnostics(DiagnosticsPage),
+    Acl(RepoAcl),
+    Health(RepoHealth),
 }
 
 impl super::ApiResponse for ReposResponse {}
@@ -200,15 +158,31 @@ pub(super) fn router() -> Router {
         .route("/status", get(index_status))
         .route("/indexed", indexed)
         .route("/sync", get(sync).delete(delete_sync))
+        .route("/reindex", post(reindex))
+        .route("/branch_settings", put(set_branch_settings))
+        .route("/diagnostics", get(diagnostics))
+        .route("/acl", get(acl).put(set_acl))
 }
 
 /// Get a stream of status notifications about the indexing of each repository
 /// This endpoint opens an SSE stream
+///
+/// If a repository is given, get whether it can be queried instead
 //
-pub(super) async fn index_status(Extension(app): Extension<Application>) -> impl IntoResponse {
+pub(super) async fn index_status(
+    Query(IndexedParams { repo }): Query<IndexedParams>,
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
+) -> Result<axum::response::Response> {
+    if let Some(repo) = repo {
+        super::check_repo_access(&app, &user, &repo)?;
+        let health = repo_health(&app, &repo).await?;
+        return Ok(json(ReposResponse::Health(health)).into_response());
+    }
+
     let mut receiver = app.sync_queue.subscribe();
 
-    Sse::new(async_stream::stream! {
+    let stream = Sse::new(async_stream::stream! {
         while let Ok(event) = receiver.recv().await {
             yield sse::Event::default().json_data(event).map_err(|err| {
                 <_ as Into<Box<dyn std::error::Error + Send + Sync>>>::into(err)
@@ -219,7 +193,38 @@ pub(super) async fn index_status(Extension(app): Extension<Application>) -> impl
         sse::KeepAlive::new()
             .interval(Duration::from_secs(5))
             .event(sse::Event::default().event("heartbeat")),
-    )
+    );
+
+    Ok(stream.into_response())
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct RepoHealth {
+    /// Whether the repository can be queried.
+    healthy: bool,
+    /// Why the documents of the repository can't be read from the indexes.
+    errors: Vec<String>,
+}
+
+async fn repo_health(app: &Application, repo: &RepoRef) -> Result<RepoHealth> {
+    app.repo_pool
+        .read_async(repo, |_, _| ())
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))?;
+
+    let errors = app
+        .indexes
+        .repo_health(repo)
+        .await
+        .into_iter()
+        .filter_map(Result::err)
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>();
+
+    Ok(RepoHealth {
+        healthy: errors.is_empty(),
+        errors,
+    })
 }
 
 #[derive(Deserialize)]
@@ -323,6 +328,194 @@ pub(super) async fn delete_sync(
     Ok(json(ReposResponse::SyncQueued))
 }
 
+#[derive(Deserialize)]
+pub(super) struct ReindexParams {
+    repo: RepoRef,
+    /// Rebuild the indexes from scratch, which also indexes every other repository again.
+    #[serde(default)]
+    force: bool,
+}
+
+/// Index a repository again from scratch, or rebuild the indexes that it is in when `force` is set
+///
+/// The indexes are shared by all repositories, so a forced reindex is how indexes that can't be
+/// read anymore are recovered.
+pub(super) async fn reindex(
+    Query(ReindexParams { repo, force }): Query<ReindexParams>,
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse> {
+    if !app.access.is_admin(&user) {
+        return Err(
+            Error::user("only admins can reindex repositories").with_status(StatusCode::FORBIDDEN)
+        );
+    }
+
+    let repository = app
+        .repo_pool
+        .read_async(&repo, |_, v| v.clone())
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))?;
+
+    let repos = if force {
+        info!(%repo, "rebuilding indexes");
+        app.indexes.rebuild().await?;
+
+        let mut repos = vec![];
+        app.repo_pool.scan_async(|k, _| repos.push(k.clone())).await;
+        repos
+    } else {
+        info!(%repo, "reindexing repository");
+        let writers = app.indexes.writers().await?;
+        for handle in writers.iter() {
+            handle.delete(&repository);
+        }
+        writers.commit().await?;
+
+        vec![repo]
+    };
+
+    // Without a file cache, every file is indexed again.
+    for reporef in &repos {
+        FileCache::for_repo(&app.sql, reporef).delete().await?;
+    }
+
+    app.write_index().enqueue_sync(repos).await;
+    Ok(json(ReposResponse::SyncQueued))
+}
+
+/// The most diagnostics that a page can have.
+const MAX_DIAGNOSTICS_PER_PAGE: usize = 100;
+
+const fn default_diagnostics_per_page() -> usize {
+    20
+}
+
+#[derive(Deserialize)]
+pub(super) struct DiagnosticsParams {
+    repo: RepoRef,
+    /// Only list diagnostics in this category.
+    category: Option<Category>,
+    /// The page to list, starting from 0.
+    #[serde(default)]
+    page: usize,
+    #[serde(default = "default_diagnostics_per_page")]
+    per_page: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct DiagnosticsPage {
+    /// The number of files that the last index went through.
+    files: usize,
+    /// The number of diagnostics in each category, regardless of the filter.
+    counts: BTreeMap<Category, usize>,
+    diagnostics: Vec<Diagnostic>,
+    next_page: Option<usize>,
+}
+
+/// List the files that the last index of a repository left out, or could not fully index
+pub(super) async fn diagnostics(
+    Query(params): Query<DiagnosticsParams>,
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse> {
+    super::check_repo_access(&app, &user, &params.repo)?;
+    Ok(json(ReposResponse::Diagnostics(
+        diagnostics_page(&app.repo_pool, params).await?,
+    )))
+}
+
+async fn diagnostics_page(
+    repo_pool: &RepositoryPool,
+    params: DiagnosticsParams,
+) -> Result<DiagnosticsPage> {
+    if params.per_page == 0 || params.per_page > MAX_DIAGNOSTICS_PER_PAGE {
+        return Err(Error::user(format!(
+            "per_page must be between 1 and {MAX_DIAGNOSTICS_PER_PAGE}"
+        )));
+    }
+
+    let DiagnosticsParams {
+        repo,
+        category,
+        page,
+        per_page,
+    } = params;
+
+    repo_pool
+        .read_async(&repo, |_, repo| {
+            let diagnostics = &repo.index_diagnostics;
+            let mut matching = diagnostics.filter(category).skip(page * per_page);
+            let entries = matching.by_ref().take(per_page).cloned().collect();
+
+            DiagnosticsPage {
+                files: diagnostics.files,
+                counts: diagnostics.counts.clone(),
+                diagnostics: entries,
+                next_page: matching.next().map(|_| page + 1),
+            }
+        })
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))
+}
+
+#[derive(Deserialize)]
+pub(super) struct AclParams {
+    #[serde(default)]
+    public: bool,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+/// Get the ACL of a repository. Repositories without one can be queried by everyone.
+pub(super) async fn acl(
+    Query(RepoParams { repo }): Query<RepoParams>,
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse> {
+    if !app.access.is_admin(&user) {
+        return Err(Error::user("only admins can manage ACLs").with_status(StatusCode::FORBIDDEN));
+    }
+
+    app.access
+        .get(&repo)
+        .map(|acl| json(ReposResponse::Acl(acl)))
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Repository has no ACL"))
+}
+
+/// Set who can query a repository, replacing its ACL
+pub(super) async fn set_acl(
+    Query(RepoParams { repo }): Query<RepoParams>,
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+    Json(params): Json<AclParams>,
+) -> Result<impl IntoResponse> {
+    if !app.access.is_admin(&user) {
+        return Err(Error::user("only admins can manage ACLs").with_status(StatusCode::FORBIDDEN));
+    }
+
+    if let Some(group) = params.groups.iter().find(|g| !app.access.has_group(g)) {
+        return Err(Error::user(format!("unknown group `{group}`")));
+    }
+
+    let acl = RepoAcl {
+        repo_ref: repo.to_string(),
+        public: params.public,
+        users: params.users,
+        groups: params.groups,
+    };
+
+    info!(%repo, public = acl.public, "setting repository ACL");
+    app.access
+        .set(&app.sql, acl.clone())
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(json(ReposResponse::Acl(acl)))
+}
+
 /// List all repositories that are either indexed, or available for indexing
 //
 pub(super) async fn available(State(app): State<Application>) -> impl IntoResponse {
@@ -400,6 +593,73 @@ pub(super) async fn set_indexed(
     json(ReposResponse::SyncQueued)
 }
 
+/// Update the branches of a repository that are indexed, and the branch that queries default to.
+/// This will trigger a sync if the indexed branches changed, which also drops the files and
+/// embeddings of the branches that are no longer selected.
+///
+/// Only admins that can query the repository can change its settings.
+//
+pub(super) async fn set_branch_settings(
+    Query(RepoParams { repo }): Query<RepoParams>,
+    State(app): State<Application>,
+    Extension(user): Extension<User>,
+    Json(mut settings): Json<BranchSettings>,
+) -> Result<impl IntoResponse> {
+    super::check_repo_access(&app, &user, &repo)?;
+    if !app.access.is_admin(&user) {
+        return Err(Error::user("only admins can change branch settings")
+            .with_status(StatusCode::FORBIDDEN));
+    }
+
+    let branches = app
+        .repo_pool
+        .read_async(&repo, |k, v| v.branch_names(k))
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))?;
+
+    if let Some(default) = settings.default_query_branch.take() {
+        let remote = format!("origin/{default}");
+        let branch = branches
+            .iter()
+            .find(|b| **b == default || **b == remote)
+            .ok_or_else(|| Error::user(format!("unknown branch: {default}")))?;
+
+        settings.default_query_branch = Some(branch.clone());
+    }
+
+    let (changed, dropped) = app
+        .repo_pool
+        .update_async(&repo, |_, v| {
+            let old_filter = v.branch_filter.clone();
+            let dropped = v.set_branch_settings(settings, &branches)?;
+            Ok::<_, regex::Error>((old_filter != v.branch_filter, dropped))
+        })
+        .await
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))?
+        .map_err(Error::user)?;
+
+    if !changed {
+        app.config
+            .source
+            .save_pool(app.repo_pool.clone())
+            .map_err(Error::internal)?;
+
+        return Ok(json(ReposResponse::Item(
+            app.repo_pool
+                .read_async(&repo, |k, v| Repo::from((k, v)))
+                .await
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "Can't find repository"))?,
+        )));
+    }
+
+    if !dropped.is_empty() {
+        info!(%repo, ?dropped, "scheduling cleanup of unselected branches");
+    }
+
+    app.write_index().enqueue_sync(vec![repo]).await;
+    Ok(json(ReposResponse::SyncQueued))
+}
+
 #[derive(Deserialize)]
 pub(super) struct ScanRequest {
     /// The path to scan
@@ -451,9 +711,12 @@ async fn list_unique_repos(repo_pool: RepositoryPool, other: HashSet<Repo>) -> V
 mod test {
     use std::collections::HashSet;
 
-    use crate::repo::{GitProtocol, GitRemote, RepoRef, RepoRemote::Git, Repository, SyncStatus};
+    use crate::{
+        indexes::diagnostics::{Category, DiagnosticsTally},
+        repo::{GitProtocol, GitRemote, RepoRef, RepoRemote::Git, Repository, SyncStatus},
+    };
 
-    use super::{list_unique_repos, Repo, RepositoryPool};
+    use super::{diagnostics_page, list_unique_repos, DiagnosticsParams, Repo, RepositoryPool};
 
     #[tokio::test]
     async fn unique_repos_only() {
@@ -473,6 +736,10 @@ mod test {
                     last_index_unix_secs: 123456,
                     most_common_lang: None,
                     branch_filter: Default::default(),
+                    revision: None,
+                    branch_settings: Default::default(),
+                    lfs: Default::default(),
+                    index_diagnostics: Default::default(),
                 },
             )
             .unwrap();
@@ -491,6 +758,10 @@ mod test {
                     last_index_unix_secs: 123456,
                     most_common_lang: None,
                     branch_filter: Default::default(),
+                    revision: None,
+                    branch_settings: Default::default(),
+                    lfs: Default::default(),
+                    index_diagnostics: Default::default(),
                 },
             )
             .unwrap();
@@ -511,6 +782,10 @@ mod test {
                     last_index_unix_secs: 0,
                     most_common_lang: None,
                     branch_filter: Default::default(),
+                    revision: None,
+                    branch_settings: Default::default(),
+                    lfs: Default::default(),
+                    index_diagnostics: Default::default(),
                 },
             )
                 .into(),
@@ -530,6 +805,10 @@ mod test {
                 last_index_unix_secs: 0,
                 most_common_lang: None,
                 branch_filter: Default::default(),
+                revision: None,
+                branch_settings: Default::default(),
+                lfs: Default::default(),
+                index_diagnostics: Default::default(),
             },
         )
             .into();
@@ -551,4 +830,126 @@ mod test {
             unique
         );
     }
+    #[tokio::test]
+    async fn diagnostics_by_category() {
+        let tally = DiagnosticsTally::default();
+        for i in 0..5 {
+            tally.file();
+            tally.record(Category::Oversized, &format!("data/{i}.csv"), "too large");
+        }
+        tally.record(Category::Unreadable, "secrets/key.pem", "permission denied");
+
+        let repo_ref = RepoRef::try_from("github.com/test/test").unwrap();
+        let repo_pool = RepositoryPool::default();
+        repo_pool
+            .insert(
+                repo_ref.clone(),
+                Repository {
+                    disk_path: "/repo".into(),
+                    remote: Git(GitRemote {
+                        protocol: GitProtocol::Https,
+                        host: "github.com".into(),
+                        address: "test/test".into(),
+                    }),
+                    sync_status: SyncStatus::Done,
+                    last_commit_unix_secs: 123456,
+                    last_index_unix_secs: 123456,
+                    most_common_lang: None,
+                    branch_filter: Default::default(),
+                    revision: None,
+                    branch_settings: Default::default(),
+                    lfs: Default::default(),
+                    index_diagnostics: tally.finish(),
+                },
+            )
+            .unwrap();
+
+        let params = |category, page| DiagnosticsParams {
+            repo: repo_ref.clone(),
+            category,
+            page,
+            per_page: 2,
+        };
+        let paths = |page: &super::DiagnosticsPage| {
+            page.diagnostics
+                .iter()
+                .map(|d| d.path.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let page = diagnostics_page(&repo_pool, params(Some(Category::Oversized), 0))
+            .await
+            .unwrap();
+        assert_eq!(paths(&page), ["data/0.csv", "data/1.csv"]);
+        assert_eq!(page.next_page, Some(1));
+        assert_eq!(page.counts[&Category::Oversized], 5);
+        assert_eq!(page.counts[&Category::Unreadable], 1);
+
+        let page = diagnostics_page(&repo_pool, params(Some(Category::Oversized), 2))
+            .await
+            .unwrap();
+        assert_eq!(paths(&page), ["data/4.csv"]);
+        assert_eq!(page.next_page, None);
+
+        let page = diagnostics_page(&repo_pool, params(Some(Category::Unreadable), 0))
+            .await
+            .unwrap();
+        assert_eq!(paths(&page), ["secrets/key.pem"]);
+
+        let page = diagnostics_page(&repo_pool, params(None, 2)).await.unwrap();
+        assert_eq!(paths(&page), ["secrets/key.pem"]);
+        assert_eq!(page.next_page, None);
+
+        let missing = DiagnosticsParams {
+            repo: RepoRef::try_from("github.com/test/missing").unwrap(),
+            ..params(None, 0)
+        };
+        assert!(diagnostics_page(&repo_pool, missing).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+
+    use super::*;
+    use crate::{acl::tests::user, webserver};
+
+    #[tokio::test]
+    async fn test_branch_settings_require_access() {
+        let index_dir = tempdir::TempDir::new("bleep-repos").unwrap();
+        let app =
+            webserver::tests::app(&index_dir, serde_json::json!({ "acl_admins": ["alice"] })).await;
+        app.access
+            .set(
+                &app.sql,
+                RepoAcl {
+                    repo_ref: "github.com/acme/payments".to_owned(),
+                    public: false,
+                    users: vec!["alice".to_owned(), "bob".to_owned()],
+                    groups: vec![],
+                },
+            )
+            .await
+            .unwrap();
+
+        let set = |login| {
+            let request = Request::put("/branch_settings?repo=github.com/acme/payments")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{ "branches_to_index": ["release/*"] }"#))
+                .unwrap();
+            webserver::tests::status(router(), &app, user(login), request)
+        };
+
+        // Users that can't query the repository are told that it doesn't exist, and users that
+        // can are refused unless they are admins.
+        assert_eq!(set("carol").await, StatusCode::NOT_FOUND);
+        assert_eq!(set("bob").await, StatusCode::FORBIDDEN);
+
+        // Admins get through to the repository pool, where it was never added.
+        assert_eq!(set("alice").await, StatusCode::NOT_FOUND);
+    }
 }
@@ -1,10 +1,11 @@
-use super::prelude::*;
+use super::{middleware::User, prelude::*};
 use crate::{
     query::{
         execute::ApiQuery,
         parser::{self, ParsedQuery},
     },
     semantic::{self, Semantic},
+    Application,
 };
 use tracing::error;
 
@@ -12,6 +13,8 @@ pub(super) async fn complex_search(
     Query(args): Query<ApiQuery>,
     Extension(indexes): Extension<Arc<Indexes>>,
     Extension(semantic): Extension<Option<Semantic>>,
+    Extension(app): Extension<Application>,
+    Extension(user): Extension<User>,
 ) -> impl IntoResponse {
     let Some(semantic) = semantic else {
         return Err(Error::new(
@@ -20,15 +23,16 @@ pub(super) async fn complex_search(
         ));
     };
 
+    let denied = app.access.denied(&user);
     match parser::parse_nl(&args.q.clone()) {
         Ok(ParsedQuery::Semantic(q)) => semantic::execute::execute(semantic, q, args)
             .await
-            .map(json)
+            .map(|response| json(response.without_repos(&denied)))
             .map_err(super::Error::from),
         Ok(ParsedQuery::Grep(q)) => Arc::new(args)
             .query_with(indexes, q)
             .await
-            .map(json)
+            .map(|response| json(response.without_repos(&denied)))
             .map_err(super::Error::from),
         Err(err) => {
             error!(?err, "qdrant query failed");
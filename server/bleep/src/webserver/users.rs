@@ -0,0 +1,63 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
+use chrono::Utc;
+
+use crate::{
+    db::{Cursor, QueryHistory},
+    repo::RepoRef,
+    webserver::{self, middleware::User, Error},
+    Application,
+};
+
+/// The most queries that a page of history can have.
+const MAX_PER_PAGE: u32 = 100;
+
+const fn default_per_page() -> u32 {
+    20
+}
+
+#[derive(serde::Deserialize)]
+pub(in crate::webserver) struct Queries {
+    /// Only list queries about this repository.
+    repo: Option<RepoRef>,
+    /// The `next_page` cursor of the previous page, if this isn't the first page.
+    page: Option<String>,
+    #[serde(default = "default_per_page")]
+    per_page: u32,
+}
+
+/// List the queries that the user asked across all their threads, newest first.
+pub(in crate::webserver) async fn queries(
+    Extension(user): Extension<User>,
+    Query(params): Query<Queries>,
+    State(app): State<Application>,
+) -> webserver::Result<impl IntoResponse> {
+    let user_id = user.login().ok_or_else(|| Error::user("missing user ID"))?;
+
+    let after = params
+        .page
+        .as_deref()
+        .map(str::parse::<Cursor>)
+        .transpose()
+        .map_err(Error::user)?;
+
+    if params.per_page == 0 || params.per_page > MAX_PER_PAGE {
+        return Err(Error::user(format!(
+            "per_page must be between 1 and {MAX_PER_PAGE}"
+        )));
+    }
+
+    let retention = chrono::Duration::days(app.config.query_history_retention_days as i64);
+    let since = (Utc::now() - retention).timestamp();
+    let repo_ref = params.repo.map(|r| r.to_string());
+
+    let page = QueryHistory::new(&app.sql)
+        .page(user_id, repo_ref.as_deref(), since, after, params.per_page)
+        .await
+        .map_err(Error::internal)?;
+
+    Ok(Json(page))
+}
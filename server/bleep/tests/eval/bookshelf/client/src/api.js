@@ -0,0 +1,44 @@
+const BASE_URL = '/api';
+const MAX_RETRIES = 3;
+
+const sleep = (ms) => new Promise((resolve) => setTimeout(resolve, ms));
+
+// Fetch a JSON endpoint, retrying server errors with exponential backoff.
+export async function request(path, options = {}) {
+  const token = localStorage.getItem('token');
+  const headers = {
+    'Content-Type': 'application/json',
+    ...(token ? { Authorization: `Bearer ${token}` } : {}),
+  };
+
+  for (let attempt = 0; ; attempt++) {
+    const response = await fetch(`${BASE_URL}${path}`, { ...options, headers });
+
+    if (response.status >= 500 && attempt < MAX_RETRIES) {
+      await sleep(2 ** attempt * 250);
+      continue;
+    }
+
+    if (response.status === 401) {
+      localStorage.removeItem('token');
+      window.location.assign('/login');
+    }
+
+    if (!response.ok) {
+      throw new Error(`request to ${path} failed with ${response.status}`);
+    }
+
+    return response.json();
+  }
+}
+
+export async function login(email, password) {
+  const { token } = await request('/login', {
+    method: 'POST',
+    body: JSON.stringify({ email, password }),
+  });
+  localStorage.setItem('token', token);
+}
+
+export const searchBooks = (query) =>
+  request(`/books/search?q=${encodeURIComponent(query)}`);
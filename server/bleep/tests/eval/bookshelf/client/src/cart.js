@@ -0,0 +1,42 @@
+// Bulk discounts, by the minimum number of books in the cart.
+const DISCOUNTS = [
+  { minBooks: 10, percent: 15 },
+  { minBooks: 5, percent: 10 },
+  { minBooks: 3, percent: 5 },
+];
+
+export class Cart {
+  constructor() {
+    this.items = new Map();
+  }
+
+  add(book, quantity = 1) {
+    const current = this.items.get(book.isbn)?.quantity ?? 0;
+    this.items.set(book.isbn, { book, quantity: current + quantity });
+  }
+
+  remove(isbn) {
+    this.items.delete(isbn);
+  }
+
+  get count() {
+    let count = 0;
+    for (const { quantity } of this.items.values()) {
+      count += quantity;
+    }
+    return count;
+  }
+
+  // The total price in cents, after the largest bulk discount that applies.
+  get totalCents() {
+    let subtotal = 0;
+    for (const { book, quantity } of this.items.values()) {
+      subtotal += book.price_cents * quantity;
+    }
+
+    const discount = DISCOUNTS.find((d) => this.count >= d.minBooks);
+    return discount
+      ? Math.round(subtotal * (1 - discount.percent / 100))
+      : subtotal;
+  }
+}
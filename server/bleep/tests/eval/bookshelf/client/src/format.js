@@ -0,0 +1,16 @@
+const currency = new Intl.NumberFormat('en-GB', {
+  style: 'currency',
+  currency: 'GBP',
+});
+
+export const formatPrice = (cents) => currency.format(cents / 100);
+
+export function formatAuthors(authors) {
+  if (authors.length <= 2) {
+    return authors.join(' & ');
+  }
+
+  return `${authors.slice(0, -1).join(', ')} & ${authors[authors.length - 1]}`;
+}
+
+export const formatYear = (year) => (year ? String(year) : 'Unknown year');
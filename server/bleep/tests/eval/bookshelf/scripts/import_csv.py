@@ -0,0 +1,30 @@
+"""Import books from a CSV export, with `isbn,title,author,price,published` columns."""
+
+import csv
+import sys
+
+from server import db
+from server.models import Book
+
+
+def import_books(path):
+    imported, skipped = 0, 0
+
+    with open(path, newline="", encoding="utf-8") as f:
+        for row in csv.DictReader(f):
+            try:
+                book = Book.from_dict(row)
+            except (KeyError, ValueError) as e:
+                print(f"skipping row {row}: {e}", file=sys.stderr)
+                skipped += 1
+                continue
+
+            db.insert_book(book)
+            imported += 1
+
+    print(f"imported {imported} books, skipped {skipped}")
+
+
+if __name__ == "__main__":
+    db.migrate()
+    import_books(sys.argv[1])
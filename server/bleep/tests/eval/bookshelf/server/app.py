@@ -0,0 +1,42 @@
+from flask import Flask, jsonify, request
+
+from server import auth, db, search
+from server.models import Book
+
+app = Flask(__name__)
+
+
+@app.route("/login", methods=["POST"])
+def login():
+    body = request.get_json()
+    user = db.find_user(body["email"])
+    if user is None or not auth.verify_password(body["password"], user.password_hash):
+        return jsonify({"error": "invalid email or password"}), 401
+
+    return jsonify({"token": auth.issue_token(user)})
+
+
+@app.route("/books")
+def list_books():
+    page = int(request.args.get("page", 1))
+    books = db.list_books(offset=(page - 1) * 20, limit=20)
+    return jsonify([book.to_dict() for book in books])
+
+
+@app.route("/books/search")
+def search_books():
+    query = request.args.get("q", "")
+    return jsonify([book.to_dict() for book in search.find_books(query)])
+
+
+@app.route("/books", methods=["POST"])
+@auth.require_admin
+def add_book():
+    book = Book.from_dict(request.get_json())
+    db.insert_book(book)
+    return jsonify(book.to_dict()), 201
+
+
+if __name__ == "__main__":
+    db.migrate()
+    app.run(port=8080)
@@ -0,0 +1,60 @@
+import functools
+import hashlib
+import hmac
+import os
+import time
+
+import jwt
+from flask import abort, request
+
+SECRET_KEY = os.environ.get("BOOKSHELF_SECRET", "development")
+TOKEN_LIFETIME_SECONDS = 60 * 60 * 24
+
+
+def hash_password(password, salt=None):
+    """Hash a password with PBKDF2, returning `salt$hash`."""
+    salt = salt or os.urandom(16).hex()
+    digest = hashlib.pbkdf2_hmac("sha256", password.encode(), salt.encode(), 100_000)
+    return f"{salt}${digest.hex()}"
+
+
+def verify_password(password, stored):
+    salt, _ = stored.split("$", 1)
+    return hmac.compare_digest(hash_password(password, salt), stored)
+
+
+def issue_token(user):
+    """Issue a signed JWT for `user`, which expires after a day."""
+    claims = {
+        "sub": user.id,
+        "admin": user.is_admin,
+        "exp": int(time.time()) + TOKEN_LIFETIME_SECONDS,
+    }
+    return jwt.encode(claims, SECRET_KEY, algorithm="HS256")
+
+
+def decode_token(token):
+    try:
+        return jwt.decode(token, SECRET_KEY, algorithms=["HS256"])
+    except jwt.ExpiredSignatureError:
+        abort(401, "token expired")
+    except jwt.InvalidTokenError:
+        abort(401, "invalid token")
+
+
+def require_admin(view):
+    """Reject requests whose bearer token doesn't belong to an administrator."""
+
+    @functools.wraps(view)
+    def wrapper(*args, **kwargs):
+        header = request.headers.get("Authorization", "")
+        if not header.startswith("Bearer "):
+            abort(401)
+
+        claims = decode_token(header.removeprefix("Bearer "))
+        if not claims.get("admin"):
+            abort(403)
+
+        return view(*args, **kwargs)
+
+    return wrapper
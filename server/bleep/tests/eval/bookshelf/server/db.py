@@ -0,0 +1,64 @@
+import sqlite3
+from pathlib import Path
+
+from server.models import Book, User
+
+DATABASE = Path(__file__).parent.parent / "bookshelf.db"
+
+MIGRATIONS = [
+    """
+    CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        email TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        is_admin INTEGER NOT NULL DEFAULT 0
+    )
+    """,
+    """
+    CREATE TABLE books (
+        isbn TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        author TEXT NOT NULL,
+        price_cents INTEGER NOT NULL,
+        published INTEGER
+    )
+    """,
+    "CREATE INDEX books_author ON books (author)",
+]
+
+
+def connect():
+    connection = sqlite3.connect(DATABASE)
+    connection.row_factory = sqlite3.Row
+    return connection
+
+
+def migrate():
+    """Apply the migrations that have not been applied yet, tracked by `user_version`."""
+    with connect() as connection:
+        version = connection.execute("PRAGMA user_version").fetchone()[0]
+        for i, migration in enumerate(MIGRATIONS[version:], start=version + 1):
+            connection.execute(migration)
+            connection.execute(f"PRAGMA user_version = {i}")
+
+
+def find_user(email):
+    with connect() as connection:
+        row = connection.execute("SELECT * FROM users WHERE email = ?", (email,)).fetchone()
+        return User(**row) if row else None
+
+
+def list_books(offset, limit):
+    with connect() as connection:
+        rows = connection.execute(
+            "SELECT * FROM books ORDER BY title LIMIT ? OFFSET ?", (limit, offset)
+        )
+        return [Book(**row) for row in rows]
+
+
+def insert_book(book):
+    with connect() as connection:
+        connection.execute(
+            "INSERT INTO books VALUES (?, ?, ?, ?, ?)",
+            (book.isbn, book.title, book.author, book.price_cents, book.published),
+        )
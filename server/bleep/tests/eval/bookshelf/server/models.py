@@ -0,0 +1,36 @@
+from dataclasses import asdict, dataclass
+from typing import Optional
+
+
+@dataclass
+class User:
+    id: int
+    email: str
+    password_hash: str
+    is_admin: bool = False
+
+
+@dataclass
+class Book:
+    isbn: str
+    title: str
+    author: str
+    price_cents: int
+    published: Optional[int] = None
+
+    @classmethod
+    def from_dict(cls, data):
+        isbn = data["isbn"].replace("-", "")
+        if len(isbn) not in (10, 13):
+            raise ValueError(f"invalid ISBN: {data['isbn']}")
+
+        return cls(
+            isbn=isbn,
+            title=data["title"].strip(),
+            author=data["author"].strip(),
+            price_cents=round(float(data["price"]) * 100),
+            published=data.get("published"),
+        )
+
+    def to_dict(self):
+        return asdict(self)
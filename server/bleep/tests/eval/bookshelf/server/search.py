@@ -0,0 +1,33 @@
+import re
+
+from server import db
+from server.models import Book
+
+STOP_WORDS = {"a", "an", "and", "of", "the"}
+
+
+def terms(text):
+    """Split text into lowercase words, leaving out stop words."""
+    return [w for w in re.findall(r"\w+", text.lower()) if w not in STOP_WORDS]
+
+
+def find_books(query, limit=20):
+    """Rank books by how many query terms appear in their title or author."""
+    wanted = set(terms(query))
+    if not wanted:
+        return []
+
+    with db.connect() as connection:
+        books = [Book(**row) for row in connection.execute("SELECT * FROM books")]
+
+    scored = []
+    for book in books:
+        title_terms = set(terms(book.title))
+        author_terms = set(terms(book.author))
+        # Title matches count double, as readers mostly search by title.
+        score = 2 * len(wanted & title_terms) + len(wanted & author_terms)
+        if score:
+            scored.append((score, book))
+
+    scored.sort(key=lambda pair: (-pair[0], pair[1].title))
+    return [book for _, book in scored[:limit]]
@@ -0,0 +1,29 @@
+//! A load test of the agent pipeline that is small enough to run in CI.
+//!
+//! Run with `cargo test -p bleep --features loadtest`.
+
+use bleep::loadtest::{self, Mode, Options};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn smoke() {
+    let options = Options::smoke();
+    let report = loadtest::run(options.clone()).await.unwrap();
+    println!("{report}");
+
+    // Every kind of query is asked, answered, and stored.
+    assert_eq!(report.queries.values().sum::<usize>(), options.queries);
+    for mode in [Mode::Quick, Mode::Full, Mode::MultiRepo] {
+        assert!(report.queries.contains_key(&mode), "no {mode:?} queries");
+    }
+    assert_eq!(report.failures, 0);
+    assert_eq!(report.stored, options.queries);
+
+    assert!(report.updates >= options.queries);
+    assert!(report.llm_requests >= options.queries);
+    assert!(report.p50 <= report.p95);
+    assert!(report.throughput > 0.);
+
+    if cfg!(feature = "analytics") {
+        assert!(report.analytics_events > 0);
+    }
+}
@@ -0,0 +1,64 @@
+//! Search quality regression tests, over the fixture repositories in `tests/eval`.
+//!
+//! Run with `cargo test -p bleep --features eval`. This indexes each fixture with the embedding
+//! model in `model/`, so it is not part of the default test run.
+//!
+//! Set `BLEEP_EVAL_UPDATE_BASELINES=1` to write the measured metrics to `baselines.yaml` instead
+//! of checking them.
+
+use bleep::eval::{self, Fixture, Harness, Mode};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn search_quality() {
+    let dir = eval::fixtures_dir();
+    let baselines_path = dir.join("baselines.yaml");
+    let mut baselines = eval::load_baselines(&baselines_path).unwrap();
+    let update = std::env::var_os("BLEEP_EVAL_UPDATE_BASELINES").is_some();
+
+    let mut failures = Vec::new();
+    for fixture in Fixture::load_all(&dir).unwrap() {
+        let harness = Harness::index(&fixture.root, eval::default_config())
+            .await
+            .unwrap();
+
+        for mode in Mode::ALL {
+            let metrics = harness.evaluate(mode, &fixture.queries).await.unwrap();
+            println!(
+                "{} {}: recall@{} {:.3}, MRR {:.3}",
+                fixture.name,
+                mode.name(),
+                fixture.queries.k,
+                metrics.recall_at_k,
+                metrics.mrr
+            );
+
+            if update {
+                baselines
+                    .entry(fixture.name.clone())
+                    .or_default()
+                    .insert(mode, metrics);
+                continue;
+            }
+
+            match baselines.get(&fixture.name).and_then(|b| b.get(&mode)) {
+                Some(baseline) => failures.extend(
+                    metrics
+                        .regressions(baseline)
+                        .into_iter()
+                        .map(|r| format!("{} {}: {r}", fixture.name, mode.name())),
+                ),
+                None => failures.push(format!("{} {}: no baseline", fixture.name, mode.name())),
+            }
+        }
+    }
+
+    if update {
+        eval::save_baselines(&baselines_path, &baselines).unwrap();
+    }
+
+    assert!(
+        failures.is_empty(),
+        "search quality regressed:\n{}",
+        failures.join("\n")
+    );
+}